@@ -107,7 +107,8 @@ int32_t krun_set_data_disk(uint32_t ctx_id, const char *disk_path);
 int32_t krun_set_mapped_volumes(uint32_t ctx_id, const char *const mapped_volumes[]);
 
 /**
- * Adds an independent virtio-fs device pointing to a host's directory with a tag.
+ * Adds an independent virtio-fs device pointing to a host's directory with a tag. It can be called
+ * several times to expose several directories, each with its own tag.
  *
  * Arguments:
  *  "ctx_id"         - the configuration context ID.
@@ -115,12 +116,56 @@ int32_t krun_set_mapped_volumes(uint32_t ctx_id, const char *const mapped_volume
  *  "c_path"         - full path to the directory in the host to be exposed to the guest.
  *
  * Returns:
- *  Zero on success or a negative error number on failure.
+ *  Zero on success or a negative error number on failure, -EEXIST if another virtio-fs device
+ *  already has this tag.
  */
 int32_t krun_add_virtiofs(uint32_t ctx_id,
                           const char *c_tag,
                           const char *c_path);
 
+/**
+ * Adds an independent virtio-fs device like krun_add_virtiofs, with a DAX window the guest can map
+ * the shared files into instead of reading and writing them through the device. The windows are
+ * taken from the shared memory region of the VM, which is 8 GiB large.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "c_tag"          - tag to identify the filesystem in the guest.
+ *  "c_path"         - full path to the directory in the host to be exposed to the guest.
+ *  "shm_size"       - size of the DAX window in bytes, a multiple of 2 MiB, or zero to disable DAX.
+ *
+ * Notes:
+ * The guest only uses the window if the filesystem is mounted with the "dax" option.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EEXIST if another virtio-fs device
+ *  already has this tag. Starting the VM fails if the windows don't fit in the shared memory
+ *  region.
+ */
+int32_t krun_add_virtiofs2(uint32_t ctx_id,
+                           const char *c_tag,
+                           const char *c_path,
+                           uint64_t shm_size);
+
+/**
+ * Enables or disables announcing the host mount points inside the directory of a virtio-fs device
+ * to the guest, which then gives each of them a submount of its own with a distinct st_dev, rather
+ * than seeing a single filesystem. Disabled by default.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "c_tag"          - tag of a device added by krun_add_virtiofs, or "/dev/root" for the one
+ *                     added by krun_set_root.
+ *  "enable"         - boolean indicating whether the submounts should be announced.
+ *
+ * Notes:
+ * Only Linux hosts can announce submounts, and only to guest kernels supporting them.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -ENOENT if no device has this tag.
+ */
+int32_t krun_set_virtiofs_submounts(uint32_t ctx_id, const char *c_tag, bool enable);
+
 /**
  * Configures the networking to use passt.
  * Call to this function disables TSI backend to use passt instead.
@@ -168,6 +213,35 @@ int32_t krun_set_gvproxy_path(uint32_t ctx_id, char* c_path);
  */
 int32_t krun_set_net_mac(uint32_t ctx_id, uint8_t *const c_mac);
 
+/**
+ * Derives the MAC address of the virtio-net device from "seed" instead of using the default one,
+ * so it's reproducible across boots without picking an address. Ignored if "krun_set_net_mac" is
+ * called too.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "seed"           - the seed to generate the MAC address from.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_net_mac_seed(uint32_t ctx_id, uint64_t seed);
+
+/**
+ * Registers the device that would get the id "c_default_id" (e.g. "eth0" for the virtio-net
+ * device) with "c_id" instead, so its id stays the same whatever other devices are configured.
+ * Starting the microVM fails if two devices end up with the same id.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "c_default_id"   - a null-terminated string with the id the device would get by default.
+ *  "c_id"           - a null-terminated string with the id to register the device with.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_device_id(uint32_t ctx_id, const char *c_default_id, const char *c_id);
+
 /**
  * Configures a map of host to guest TCP ports for the microVM.
  *
@@ -244,14 +318,16 @@ int32_t krun_set_snd_device(uint32_t ctx_id, bool enable);
 int32_t krun_set_rlimits(uint32_t ctx_id, const char *const rlimits[]);
 
 /**
- * Sets the SMBIOS OEM Strings.
+ * Sets the SMBIOS OEM Strings. There can be up to 255 of them, each one between 1 and 64 bytes
+ * long.
  *
  * Arguments:
  *  "ctx_id"      - the configuration context ID.
  *  "oem_strings" - an array of string pointers. Must be terminated with an additional NULL pointer.
  *
  * Returns:
- *  Zero on success or a negative error number on failure.
+ *  Zero on success, -EINVAL if a string isn't valid UTF-8 or the limits above aren't met, or
+ *  another negative error number on failure.
  */
 int32_t krun_set_smbios_oem_strings(uint32_t ctx_id, const char *const oem_strings[]);
 
@@ -316,7 +392,10 @@ int32_t krun_set_env(uint32_t ctx_id, const char *const envp[]);
 int32_t krun_set_tee_config_file(uint32_t ctx_id, const char *filepath);
 
 /**
- * Adds a port-path pairing for guest IPC with a process in the host.
+ * Adds a port-path pairing for guest IPC with a process in the host. Each guest connection to
+ * "port" gets its own connection to the UNIX socket, which a process in the host must be
+ * listening on. Guest connections to ports without a pairing, or to a socket nobody listens
+ * on, are reset.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
@@ -350,6 +429,20 @@ int32_t krun_get_shutdown_eventfd(uint32_t ctx_id);
  */
 int32_t krun_set_console_output(uint32_t ctx_id, const char *c_filepath);
 
+/**
+ * Starts a gdb server on "port" of the loopback interface once the microVM is running, for
+ * "target remote :port" to debug the guest kernel. The vCPUs are paused while gdb is attached.
+ * Only available on Linux, in libkrun built with GDB=1.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "port"   - the TCP port to listen on, other than zero.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_gdb_port(uint32_t ctx_id, uint16_t port);
+
 /**
  * Starts and enters the microVM with the configured parameters. The VMM will attempt to take over
  * stdin/stdout to manage them on behalf of the process running inside the isolated environment,
@@ -9,11 +9,13 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::{io, result};
 
+use super::super::BootEntropy;
+use super::super::CpuTopology;
 use super::super::DeviceType;
 use super::super::InitrdConfig;
 use super::get_fdt_addr;
 use super::gic::GICDevice;
-use super::layout::{GTIMER_HYP, GTIMER_PHYS, GTIMER_SEC, GTIMER_VIRT};
+use super::layout::{GTIMER_HYP, GTIMER_PHYS, GTIMER_SEC, GTIMER_VIRT, PMU_PPI};
 use crate::ArchMemoryInfo;
 use vm_fdt::{Error as FdtError, FdtWriter};
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
@@ -24,6 +26,8 @@ const GIC_PHANDLE: u32 = 1;
 const CLOCK_PHANDLE: u32 = 2;
 // This is a value for uniquely identifying the FDT node containing the gpio controller.
 const GPIO_PHANDLE: u32 = 4;
+// The FDT nodes of the vCPUs take the phandles from this one on, in vCPU order.
+const FIRST_CPU_PHANDLE: u32 = 0x100;
 // Read the documentation specified when appending the root node to the FDT.
 const ADDRESS_CELLS: u32 = 0x2;
 const SIZE_CELLS: u32 = 0x2;
@@ -41,6 +45,9 @@ const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
 const IRQ_TYPE_EDGE_RISING: u32 = 1;
 const IRQ_TYPE_LEVEL_HI: u32 = 4;
 
+// Size of the "rng-seed" property, matching what QEMU passes.
+const RNG_SEED_LEN: usize = 32;
+
 /// Trait for devices to be added to the Flattened Device Tree.
 pub trait DeviceInfoForFDT {
     /// Returns the address where this device will be loaded.
@@ -74,10 +81,13 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     guest_mem: &GuestMemoryMmap,
     arch_memory_info: &ArchMemoryInfo,
     vcpu_mpidr: Vec<u64>,
+    topology: &CpuTopology,
     cmdline: &str,
     device_info: &HashMap<(DeviceType, String), T>,
     gic_device: &Box<dyn GICDevice>,
     initrd: &Option<InitrdConfig>,
+    entropy: &BootEntropy,
+    pmu: bool,
 ) -> Result<Vec<u8>> {
     // Alocate stuff necessary for the holding the blob.
     let mut fdt = FdtWriter::new()?;
@@ -96,11 +106,15 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     // This is not mandatory but we use it to point the root node to the node
     // containing description of the interrupt controller for this VM.
     fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
-    create_cpu_nodes(&mut fdt, &vcpu_mpidr)?;
+    create_cpu_nodes(&mut fdt, &vcpu_mpidr, topology)?;
     create_memory_node(&mut fdt, guest_mem, arch_memory_info)?;
-    create_chosen_node(&mut fdt, cmdline, initrd)?;
+    create_reserved_memory_node(&mut fdt, arch_memory_info)?;
+    create_chosen_node(&mut fdt, cmdline, initrd, entropy)?;
     create_gic_node(&mut fdt, gic_device)?;
     create_timer_node(&mut fdt)?;
+    if pmu {
+        create_pmu_node(&mut fdt)?;
+    }
     create_clock_node(&mut fdt)?;
     create_psci_node(&mut fdt)?;
     create_devices_node(&mut fdt, device_info)?;
@@ -146,7 +160,7 @@ fn generate_prop64(cells: &[u64]) -> Vec<u8> {
 }
 
 // Following are the auxiliary function for creating the different nodes that we append to our FDT.
-fn create_cpu_nodes(fdt: &mut FdtWriter, vcpu_mpidr: &[u64]) -> Result<()> {
+fn create_cpu_nodes(fdt: &mut FdtWriter, vcpu_mpidr: &[u64], topology: &CpuTopology) -> Result<()> {
     // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/arm/cpus.yaml.
     let cpu_node = fdt.begin_node("cpus")?;
     // As per documentation, on ARM v8 64-bit systems value should be set to 2.
@@ -166,12 +180,45 @@ fn create_cpu_nodes(fdt: &mut FdtWriter, vcpu_mpidr: &[u64]) -> Result<()> {
         // Set the field to first 24 bits of the MPIDR - Multiprocessor Affinity Register.
         // See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0488c/BABHBJCI.html.
         fdt.property_u64("reg", mpidr & 0x7FFFFF)?;
+        fdt.property_u32("phandle", FIRST_CPU_PHANDLE + index as u32)?;
         fdt.end_node(cpu_name_node)?;
     }
+    create_cpu_map_node(fdt, topology)?;
     fdt.end_node(cpu_node)?;
     Ok(())
 }
 
+// Describes the topology in a cpu-map node, which the guest prefers to what the MPIDRs say. See
+// https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/cpu/cpu-topology.txt.
+fn create_cpu_map_node(fdt: &mut FdtWriter, topology: &CpuTopology) -> Result<()> {
+    let cpu_map_node = fdt.begin_node("cpu-map")?;
+    let mut phandle = FIRST_CPU_PHANDLE;
+    for socket in 0..topology.sockets {
+        let socket_node = fdt.begin_node(&format!("socket{socket}"))?;
+        // Each socket has a single cluster, holding all its cores.
+        let cluster_node = fdt.begin_node("cluster0")?;
+        for core in 0..topology.cores_per_socket {
+            let core_node = fdt.begin_node(&format!("core{core}"))?;
+            if topology.threads_per_core > 1 {
+                for thread in 0..topology.threads_per_core {
+                    let thread_node = fdt.begin_node(&format!("thread{thread}"))?;
+                    fdt.property_u32("cpu", phandle)?;
+                    fdt.end_node(thread_node)?;
+                    phandle += 1;
+                }
+            } else {
+                fdt.property_u32("cpu", phandle)?;
+                phandle += 1;
+            }
+            fdt.end_node(core_node)?;
+        }
+        fdt.end_node(cluster_node)?;
+        fdt.end_node(socket_node)?;
+    }
+    fdt.end_node(cpu_map_node)?;
+    Ok(())
+}
+
 fn create_memory_node(
     fdt: &mut FdtWriter,
     _guest_mem: &GuestMemoryMmap,
@@ -180,7 +227,15 @@ fn create_memory_node(
     let mem_size = arch_memory_info.ram_last_addr - super::layout::DRAM_MEM_START;
     // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/booting-without-of.txt#L960
     // for an explanation of this.
-    let mem_reg_prop = generate_prop64(&[super::layout::DRAM_MEM_START, mem_size]);
+    let mem_ranges = crate::subtract_ranges(
+        &[(super::layout::DRAM_MEM_START, mem_size)],
+        &arch_memory_info.reserved_ranges,
+    );
+    let mem_reg: Vec<u64> = mem_ranges
+        .iter()
+        .flat_map(|&(start, size)| [start, size])
+        .collect();
+    let mem_reg_prop = generate_prop64(&mem_reg);
 
     let mem_node = fdt.begin_node("memory")?;
     fdt.property_string("device_type", "memory")?;
@@ -189,10 +244,34 @@ fn create_memory_node(
     Ok(())
 }
 
+// See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/reserved-memory/reserved-memory.txt
+fn create_reserved_memory_node(
+    fdt: &mut FdtWriter,
+    arch_memory_info: &ArchMemoryInfo,
+) -> Result<()> {
+    if arch_memory_info.firmware_ranges.is_empty() {
+        return Ok(());
+    }
+
+    let reserved_node = fdt.begin_node("reserved-memory")?;
+    fdt.property_u32("#address-cells", ADDRESS_CELLS)?;
+    fdt.property_u32("#size-cells", SIZE_CELLS)?;
+    fdt.property_null("ranges")?;
+    for &(start, size) in &arch_memory_info.firmware_ranges {
+        let region_node = fdt.begin_node(&format!("firmware@{start:x}"))?;
+        fdt.property("reg", &generate_prop64(&[start, size]))?;
+        fdt.property_null("no-map")?;
+        fdt.end_node(region_node)?;
+    }
+    fdt.end_node(reserved_node)?;
+    Ok(())
+}
+
 fn create_chosen_node(
     fdt: &mut FdtWriter,
     cmdline: &str,
     initrd: &Option<InitrdConfig>,
+    entropy: &BootEntropy,
 ) -> Result<()> {
     let chosen_node = fdt.begin_node("chosen")?;
     fdt.property_string("bootargs", cmdline)?;
@@ -205,6 +284,15 @@ fn create_chosen_node(
         )?;
     }
 
+    if let Some(seed) = entropy.seed_bytes(RNG_SEED_LEN + 8) {
+        let (kaslr_seed, rng_seed) = seed.split_at(8);
+        fdt.property_u64(
+            "kaslr-seed",
+            u64::from_le_bytes(kaslr_seed.try_into().unwrap()),
+        )?;
+        fdt.property("rng-seed", rng_seed)?;
+    }
+
     fdt.end_node(chosen_node)?;
 
     Ok(())
@@ -278,6 +366,20 @@ fn create_timer_node(fdt: &mut FdtWriter) -> Result<()> {
     Ok(())
 }
 
+fn create_pmu_node(fdt: &mut FdtWriter) -> Result<()> {
+    // See
+    // https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/arm/pmu.yaml
+    let compatible = "arm,armv8-pmuv3";
+    let irqs = [GIC_FDT_IRQ_TYPE_PPI, PMU_PPI, IRQ_TYPE_LEVEL_HI];
+
+    let node = fdt.begin_node("pmu")?;
+    fdt.property_string("compatible", compatible)?;
+    fdt.property("interrupts", &generate_prop32(&irqs))?;
+    fdt.end_node(node)?;
+
+    Ok(())
+}
+
 fn create_psci_node(fdt: &mut FdtWriter) -> Result<()> {
     let compatible = "arm,psci-0.2";
     let node = fdt.begin_node("psci")?;
@@ -355,6 +457,19 @@ fn create_rtc_node<T: DeviceInfoForFDT + Clone + Debug>(
     Ok(())
 }
 
+fn create_pvpanic_node<T: DeviceInfoForFDT + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> Result<()> {
+    let pvpanic_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
+    let pvpanic_node = fdt.begin_node(&format!("pvpanic@{:x}", dev_info.addr()))?;
+    fdt.property_string("compatible", "qemu,pvpanic-mmio")?;
+    fdt.property("reg", &pvpanic_reg_prop)?;
+    fdt.end_node(pvpanic_node)?;
+
+    Ok(())
+}
+
 fn create_gpio_node<T: DeviceInfoForFDT + Clone + Debug>(
     fdt: &mut FdtWriter,
     dev_info: &T,
@@ -407,6 +522,7 @@ fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug>(
         match device_type {
             DeviceType::Gpio => create_gpio_node(fdt, info)?,
             DeviceType::RTC => create_rtc_node(fdt, info)?,
+            DeviceType::PvPanic => create_pvpanic_node(fdt, info)?,
             DeviceType::Serial => create_serial_node(fdt, info)?,
             DeviceType::Virtio(_) => {
                 ordered_virtio_device.push(info);
@@ -485,10 +601,13 @@ mod tests {
             &mem,
             &mem_info,
             vec![0],
+            &CpuTopology::single_socket(1, false),
             "console=tty0",
             &dev_info,
             &gic,
             &None,
+            &BootEntropy::Random,
+            true,
         )
         .is_ok())
     }
@@ -84,6 +84,9 @@ pub const GTIMER_HYP: u32 = 14;
 pub const GTIMER_VIRT: u32 = 11;
 pub const GTIMER_PHYS: u32 = 12;
 
+/// PMU overflow interrupt.
+pub const PMU_PPI: u32 = 7;
+
 /// Below this address will reside the GIC, above this address will reside the MMIO devices.
 #[cfg(not(feature = "efi"))]
 pub const MAPPED_IO_START: u64 = 1 << 30; // 1 GB
@@ -92,3 +95,6 @@ pub const MAPPED_IO_START: u64 = 0x0a00_0000;
 
 #[cfg(feature = "efi")]
 pub const SMBIOS_START: u64 = 0x4000_F000;
+/// Space available for the SMBIOS entry point and tables, up to the end of their page.
+#[cfg(feature = "efi")]
+pub const SMBIOS_MAX_SIZE: u64 = 0x1000;
@@ -58,6 +58,8 @@ pub fn arch_memory_regions(size: usize) -> (ArchMemoryInfo, Vec<(GuestAddress, u
         ram_last_addr,
         shm_start_addr,
         shm_size: MMIO_SHM_SIZE,
+        reserved_ranges: Vec::new(),
+        firmware_ranges: Vec::new(),
     };
     let regions = if cfg!(feature = "efi") {
         vec![
@@ -84,6 +86,7 @@ pub fn arch_memory_regions(size: usize) -> (ArchMemoryInfo, Vec<(GuestAddress, u
 /// * `guest_mem` - The memory to be used by the guest.
 /// * `cmdline_cstring` - The kernel commandline.
 /// * `vcpu_mpidr` - Array of MPIDR register values per vcpu.
+/// * `topology` - How the vcpus are grouped, described in the FDT `cpu-map`.
 /// * `device_info` - A hashmap containing the attached devices for building FDT device nodes.
 /// * `gic_device` - The GIC device.
 /// * `initrd` - Information about an optional initrd.
@@ -93,25 +96,36 @@ pub fn configure_system<T: DeviceInfoForFDT + Clone + Debug>(
     arch_memory_info: &ArchMemoryInfo,
     cmdline_cstring: &str,
     vcpu_mpidr: Vec<u64>,
+    topology: &super::CpuTopology,
     device_info: &HashMap<(DeviceType, String), T>,
     gic_device: &Box<dyn GICDevice>,
     initrd: &Option<super::InitrdConfig>,
     _smbios_oem_strings: &Option<Vec<String>>,
+    entropy: &super::BootEntropy,
+    pmu: bool,
 ) -> super::Result<()> {
     fdt::create_fdt(
         guest_mem,
         arch_memory_info,
         vcpu_mpidr,
+        topology,
         cmdline_cstring,
         device_info,
         gic_device,
         initrd,
+        entropy,
+        pmu,
     )
     .map_err(Error::SetupFDT)?;
 
     #[cfg(feature = "efi")]
-    smbios::setup_smbios(guest_mem, layout::SMBIOS_START, _smbios_oem_strings)
-        .map_err(Error::Smbios)?;
+    smbios::setup_smbios(
+        guest_mem,
+        layout::SMBIOS_START,
+        layout::SMBIOS_MAX_SIZE,
+        _smbios_oem_strings,
+    )
+    .map_err(Error::Smbios)?;
 
     Ok(())
 }
@@ -7,11 +7,61 @@
 use std::fmt;
 use std::result;
 
-#[derive(Default)]
+use vm_memory::GuestAddress;
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct ArchMemoryInfo {
     pub ram_last_addr: u64,
     pub shm_start_addr: u64,
     pub shm_size: u64,
+    /// Guest physical ranges left out of RAM, as `(start, size)` pairs.
+    pub reserved_ranges: Vec<(u64, u64)>,
+    /// Guest RAM ranges set aside for the firmware, as `(start, size)` pairs. They stay mapped,
+    /// but the guest is told not to allocate from them.
+    pub firmware_ranges: Vec<(u64, u64)>,
+}
+
+/// Removes `holes` from `ranges`, both given as `(start, size)` pairs, splitting the ranges a
+/// hole falls in the middle of.
+pub fn subtract_ranges(ranges: &[(u64, u64)], holes: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut pieces: Vec<(u64, u64)> = ranges
+        .iter()
+        .map(|&(start, size)| (start, start + size))
+        .collect();
+    for &(hole_start, hole_size) in holes {
+        let hole_end = hole_start + hole_size;
+        pieces = pieces
+            .into_iter()
+            .flat_map(|(start, end)| {
+                let before = (start, hole_start.min(end));
+                let after = (hole_end.max(start), end);
+                [before, after].into_iter().filter(|(s, e)| s < e)
+            })
+            .collect();
+    }
+    pieces
+        .into_iter()
+        .map(|(start, end)| (start, end - start))
+        .collect()
+}
+
+/// Carves the `reserved` ranges out of the guest memory `regions` returned by
+/// `arch_memory_regions`, and records them in `info` so that `configure_system` reports them as
+/// holes to the guest.
+pub fn reserve_memory_ranges(
+    info: &mut ArchMemoryInfo,
+    regions: &[(GuestAddress, usize)],
+    reserved: &[(u64, u64)],
+) -> Vec<(GuestAddress, usize)> {
+    let ranges: Vec<(u64, u64)> = regions
+        .iter()
+        .map(|&(addr, size)| (addr.0, size as u64))
+        .collect();
+    info.reserved_ranges = reserved.to_vec();
+    subtract_ranges(&ranges, reserved)
+        .into_iter()
+        .map(|(start, size)| (GuestAddress(start), size as usize))
+        .collect()
 }
 
 /// Module for aarch64 related functionality.
@@ -53,6 +103,9 @@ pub enum DeviceType {
     /// Device Type: RTC.
     #[cfg(target_arch = "aarch64")]
     RTC,
+    /// Device Type: pvpanic.
+    #[cfg(target_arch = "aarch64")]
+    PvPanic,
 }
 
 /// Type for passing information about the initrd in the guest memory.
@@ -63,6 +116,65 @@ pub struct InitrdConfig {
     pub size: usize,
 }
 
+/// Source of the early-boot randomness handed to the guest kernel.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum BootEntropy {
+    /// Don't seed the guest, letting it gather its own randomness.
+    #[default]
+    Random,
+    /// Seed the guest with bytes derived from this value, making early-boot randomness
+    /// (including KASLR) reproducible across boots. Meant for testing and debugging only.
+    Fixed(u64),
+}
+
+impl BootEntropy {
+    /// Returns `len` seed bytes for `Fixed` entropy, or `None` for `Random`.
+    pub fn seed_bytes(&self, len: usize) -> Option<Vec<u8>> {
+        let BootEntropy::Fixed(seed) = *self else {
+            return None;
+        };
+
+        // Expand the seed with SplitMix64 so every output byte depends on all of its bits.
+        let mut state = seed;
+        let mut bytes = Vec::with_capacity(len + 8);
+        while bytes.len() < len {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            bytes.extend_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+        }
+        bytes.truncate(len);
+        Some(bytes)
+    }
+}
+
+/// How the vCPUs are grouped into sockets, cores and threads. vCPUs are numbered thread first, so
+/// consecutive vCPUs are the threads of a core, and consecutive cores share a socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CpuTopology {
+    pub sockets: u8,
+    pub cores_per_socket: u8,
+    pub threads_per_core: u8,
+}
+
+impl CpuTopology {
+    /// Puts the `vcpu_count` vCPUs in a single socket, made of two-thread cores if `ht_enabled`.
+    pub fn single_socket(vcpu_count: u8, ht_enabled: bool) -> Self {
+        let threads_per_core = if ht_enabled && vcpu_count > 1 { 2 } else { 1 };
+        CpuTopology {
+            sockets: 1,
+            cores_per_socket: vcpu_count / threads_per_core,
+            threads_per_core,
+        }
+    }
+
+    /// Number of vCPUs in the topology.
+    pub fn vcpu_count(&self) -> usize {
+        self.sockets as usize * self.cores_per_socket as usize * self.threads_per_core as usize
+    }
+}
+
 /// Default (smallest) memory page size for the supported architectures.
 pub const PAGE_SIZE: usize = 4096;
 
@@ -71,3 +183,43 @@ impl fmt::Display for DeviceType {
         write!(f, "{self:?}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subtract_ranges() {
+        let ranges = [(0x0, 0x1000), (0x2000, 0x3000)];
+        assert_eq!(subtract_ranges(&ranges, &[]), ranges);
+        assert_eq!(
+            subtract_ranges(&ranges, &[(0x2800, 0x800)]),
+            [(0x0, 0x1000), (0x2000, 0x800), (0x3000, 0x2000)]
+        );
+        assert_eq!(
+            subtract_ranges(&ranges, &[(0x800, 0x2000), (0x4000, 0x2000)]),
+            [(0x0, 0x800), (0x2800, 0x1800)]
+        );
+        assert_eq!(subtract_ranges(&ranges, &[(0x0, 0x5000)]), []);
+    }
+
+    #[test]
+    fn test_cpu_topology() {
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 3,
+            threads_per_core: 2,
+        };
+        assert_eq!(topology.vcpu_count(), 12);
+
+        assert_eq!(
+            CpuTopology::single_socket(4, true),
+            CpuTopology {
+                sockets: 1,
+                cores_per_socket: 2,
+                threads_per_core: 2,
+            }
+        );
+        assert_eq!(CpuTopology::single_socket(1, true).threads_per_core, 1);
+    }
+}
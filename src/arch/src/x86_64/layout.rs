@@ -19,6 +19,12 @@ pub const CMDLINE_SEV_SIZE: usize = 0x200;
 /// Initrd start address on SEV.
 pub const INITRD_SEV_START: u64 = 0xa00000;
 
+/// Address of the SMBIOS entry point, the tables follow it. The kernel scans the BIOS area,
+/// 0xf0000 to 0xfffff, for it.
+pub const SMBIOS_START: u64 = 0xf0000;
+/// Space available for the SMBIOS entry point and tables.
+pub const SMBIOS_MAX_SIZE: u64 = 0x10000;
+
 /// Start of the high memory.
 pub const HIMEM_START: u64 = 0x0010_0000; //1 MB.
 
@@ -34,6 +40,9 @@ pub const KVM_TSS_ADDRESS: u64 = 0xfffb_d000;
 /// The 'zero page', a.k.a linux kernel bootparams.
 pub const ZERO_PAGE_START: u64 = 0x7000;
 
+/// Start of the `setup_data` list linked from the zero page.
+pub const SETUP_DATA_START: u64 = 0x30000;
+
 /// SNP: space for the initial LIDT
 pub const SNP_LIDT_START: u64 = 0x0;
 /// SNP: Secrets page.
@@ -18,8 +18,10 @@ pub mod msr;
 pub mod regs;
 
 use crate::ArchMemoryInfo;
+use crate::BootEntropy;
+use crate::CpuTopology;
 use crate::InitrdConfig;
-use arch_gen::x86::bootparam::{boot_params, E820_RAM};
+use arch_gen::x86::bootparam::{boot_params, E820_RAM, E820_RESERVED};
 use vm_memory::Bytes;
 use vm_memory::{
     Address, ByteValued, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
@@ -36,6 +38,19 @@ struct BootParamsWrapper(boot_params);
 // It is safe to initialize BootParamsWrap which is a wrapper over `boot_params` (a series of ints).
 unsafe impl ByteValued for BootParamsWrapper {}
 
+// Fixed-size header of `setup_data`, the bindings type can't be `Copy` because of its trailing
+// flexible array.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SetupDataHeader {
+    next: u64,
+    type_: u32,
+    len: u32,
+}
+
+// It is safe to initialize SetupDataHeader which is a series of ints.
+unsafe impl ByteValued for SetupDataHeader {}
+
 /// Errors thrown while configuring x86_64 system.
 #[derive(Debug, Eq, PartialEq)]
 pub enum Error {
@@ -46,18 +61,28 @@ pub enum Error {
     MpTableSetup(mptable::Error),
     /// Error writing the zero page of guest memory.
     ZeroPageSetup,
+    /// Error writing the setup_data list to guest memory.
+    SetupDataSetup,
     /// Failed to compute initrd address.
     InitrdAddress,
+    /// Error writing the SMBIOS tables to memory.
+    #[cfg(not(feature = "tee"))]
+    Smbios(smbios::Error),
 }
 
 // Where BIOS/VGA magic would live on a real PC.
 const EBDA_START: u64 = 0x9fc00;
+// `setup_data` type carrying a seed for the kernel RNG, not in our bootparam bindings yet.
+const SETUP_RNG_SEED: u32 = 9;
+// Same size as the seed the kernel's own EFI stub passes.
+const RNG_SEED_LEN: usize = 32;
 pub const RESET_VECTOR: u64 = 0xfff0;
 pub const RESET_VECTOR_SEV_AP: u64 = 0xfff3;
 pub const BIOS_START: u64 = 0xffff_0000;
 pub const BIOS_SIZE: usize = 65536;
 const FIRST_ADDR_PAST_32BITS: u64 = 1 << 32;
-const MEM_32BIT_GAP_SIZE: u64 = 768 << 20;
+/// The size of the hole below 4 GiB left out of RAM for the MMIO devices.
+pub const MEM_32BIT_GAP_SIZE: u64 = 768 << 20;
 /// The start of the memory area reserved for MMIO devices.
 pub const MMIO_MEM_START: u64 = FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE;
 /// The size of the MMIO shared memory area used by virtio-fs DAX.
@@ -117,6 +142,8 @@ pub fn arch_memory_regions(
         ram_last_addr,
         shm_start_addr,
         shm_size: MMIO_SHM_SIZE,
+        reserved_ranges: Vec::new(),
+        firmware_ranges: Vec::new(),
     };
     (info, regions)
 }
@@ -171,6 +198,8 @@ pub fn arch_memory_regions(
         ram_last_addr,
         shm_start_addr,
         shm_size: 0,
+        reserved_ranges: Vec::new(),
+        firmware_ranges: Vec::new(),
     };
     (info, regions)
 }
@@ -204,15 +233,19 @@ pub fn initrd_load_addr(guest_mem: &GuestMemoryMmap, initrd_size: usize) -> supe
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `initrd` - Information about where the ramdisk image was loaded in the `guest_mem`.
-/// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `topology` - How the virtual CPUs the guest will have are grouped.
+/// * `smbios_oem_strings` - Strings of the SMBIOS OEM Strings structure, if any.
 #[allow(unused_variables)]
+#[allow(clippy::too_many_arguments)]
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
     arch_memory_info: &ArchMemoryInfo,
     cmdline_addr: GuestAddress,
     cmdline_size: usize,
     initrd: &Option<InitrdConfig>,
-    num_cpus: u8,
+    topology: &CpuTopology,
+    smbios_oem_strings: &Option<Vec<String>>,
+    entropy: &BootEntropy,
 ) -> super::Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
@@ -223,10 +256,22 @@ pub fn configure_system(
 
     let himem_start = GuestAddress(layout::HIMEM_START);
 
+    let num_cpus = topology.vcpu_count() as u8;
+
     // Note that this puts the mptable at the last 1k of Linux's 640k base RAM
     #[cfg(not(feature = "tee"))]
     mptable::setup_mptable(guest_mem, num_cpus).map_err(Error::MpTableSetup)?;
 
+    // Without EFI, the kernel looks for the SMBIOS entry point in the BIOS area.
+    #[cfg(not(feature = "tee"))]
+    smbios::setup_smbios(
+        guest_mem,
+        layout::SMBIOS_START,
+        layout::SMBIOS_MAX_SIZE,
+        smbios_oem_strings,
+    )
+    .map_err(Error::Smbios)?;
+
     let mut params: BootParamsWrapper = BootParamsWrapper(boot_params::default());
 
     params.0.hdr.type_of_loader = KERNEL_LOADER_OTHER;
@@ -246,40 +291,67 @@ pub fn configure_system(
         params.0.hdr.syssize = num_cpus as u32;
     }
 
-    add_e820_entry(&mut params.0, 0, EBDA_START, E820_RAM)?;
+    let mut ram = vec![(0, EBDA_START)];
 
     let last_addr = GuestAddress(arch_memory_info.ram_last_addr);
     if last_addr < end_32bit_gap_start {
-        add_e820_entry(
-            &mut params.0,
+        ram.push((
             himem_start.raw_value(),
             // it's safe to use unchecked_offset_from because
             // mem_end > himem_start
             last_addr.unchecked_offset_from(himem_start) + 1,
-            E820_RAM,
-        )?;
+        ));
     } else {
-        add_e820_entry(
-            &mut params.0,
+        ram.push((
             himem_start.raw_value(),
             // it's safe to use unchecked_offset_from because
             // end_32bit_gap_start > himem_start
             end_32bit_gap_start.unchecked_offset_from(himem_start),
-            E820_RAM,
-        )?;
+        ));
 
         if last_addr > first_addr_past_32bits {
-            add_e820_entry(
-                &mut params.0,
+            ram.push((
                 first_addr_past_32bits.raw_value(),
                 // it's safe to use unchecked_offset_from because
                 // mem_end > first_addr_past_32bits
                 last_addr.unchecked_offset_from(first_addr_past_32bits) + 1,
-                E820_RAM,
-            )?;
+            ));
         }
     }
 
+    let reserved: Vec<(u64, u64)> = arch_memory_info
+        .reserved_ranges
+        .iter()
+        .chain(&arch_memory_info.firmware_ranges)
+        .copied()
+        .collect();
+    for (addr, size) in crate::subtract_ranges(&ram, &reserved) {
+        add_e820_entry(&mut params.0, addr, size, E820_RAM)?;
+    }
+    // Also keep the guest from placing its own MMIO resources in the reserved ranges.
+    for &(addr, size) in &reserved {
+        add_e820_entry(&mut params.0, addr, size, E820_RESERVED)?;
+    }
+
+    if let Some(seed) = entropy.seed_bytes(RNG_SEED_LEN) {
+        let setup_data_addr = GuestAddress(layout::SETUP_DATA_START);
+        let header = SetupDataHeader {
+            next: 0,
+            type_: SETUP_RNG_SEED,
+            len: seed.len() as u32,
+        };
+        guest_mem
+            .write_obj(header, setup_data_addr)
+            .and_then(|_| {
+                guest_mem.write_slice(
+                    &seed,
+                    setup_data_addr.unchecked_add(std::mem::size_of::<SetupDataHeader>() as u64),
+                )
+            })
+            .map_err(|_| Error::SetupDataSetup)?;
+        params.0.hdr.setup_data = setup_data_addr.raw_value();
+    }
+
     let zero_page_addr = GuestAddress(layout::ZERO_PAGE_START);
     guest_mem
         .write_obj(params, zero_page_addr)
@@ -348,7 +420,16 @@ mod tests {
         let no_vcpus = 4;
         let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let info = ArchMemoryInfo::default();
-        let config_err = configure_system(&gm, &info, GuestAddress(0), 0, &None, 1);
+        let config_err = configure_system(
+            &gm,
+            &info,
+            GuestAddress(0),
+            0,
+            &None,
+            &CpuTopology::single_socket(1, false),
+            &None,
+            &BootEntropy::Random,
+        );
         assert!(config_err.is_err());
         #[cfg(not(feature = "tee"))]
         assert_eq!(
@@ -361,21 +442,174 @@ mod tests {
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            &CpuTopology::single_socket(no_vcpus, false),
+            &Some(vec!["foo".to_string()]),
+            &BootEntropy::Random,
+        )
+        .unwrap();
+        let mut anchor = [0u8; 5];
+        gm.read_slice(&mut anchor, GuestAddress(layout::SMBIOS_START))
+            .unwrap();
+        assert_eq!(&anchor, b"_SM3_");
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            &CpuTopology::single_socket(no_vcpus, false),
+            &None,
+            &BootEntropy::Random,
+        )
+        .unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            &CpuTopology::single_socket(no_vcpus, false),
+            &None,
+            &BootEntropy::Random,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_system_configuration_reserved_ranges() {
+        let (mut arch_mem_info, arch_mem_regions) =
+            arch_memory_regions(128 << 20, KERNEL_LOAD_ADDR, KERNEL_SIZE);
+        let hole = (0x0400_0000, 0x0010_0000);
+        let regions = crate::reserve_memory_ranges(&mut arch_mem_info, &arch_mem_regions, &[hole]);
+        let gm = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        assert!(gm.find_region(GuestAddress(hole.0)).is_none());
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            &CpuTopology::single_socket(1, false),
+            &None,
+            &BootEntropy::Random,
+        )
+        .unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(GuestAddress(layout::ZERO_PAGE_START)).unwrap();
+        let entries: Vec<(u64, u64, u32)> = params.0.e820_map[..params.0.e820_entries as usize]
+            .iter()
+            .map(|e| (e.addr, e.size, e.type_))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (0, EBDA_START, E820_RAM),
+                (layout::HIMEM_START, hole.0 - layout::HIMEM_START, E820_RAM),
+                (
+                    hole.0 + hole.1,
+                    arch_mem_info.ram_last_addr + 1 - (hole.0 + hole.1),
+                    E820_RAM
+                ),
+                (hole.0, hole.1, E820_RESERVED),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_system_configuration_firmware_ranges() {
+        let (mut arch_mem_info, arch_mem_regions) =
+            arch_memory_regions(128 << 20, KERNEL_LOAD_ADDR, KERNEL_SIZE);
+        let firmware = (0x0600_0000, 0x0020_0000);
+        arch_mem_info.firmware_ranges.push(firmware);
+        let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            &CpuTopology::single_socket(1, false),
+            &None,
+            &BootEntropy::Random,
+        )
+        .unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(GuestAddress(layout::ZERO_PAGE_START)).unwrap();
+        let entries: Vec<(u64, u64, u32)> = params.0.e820_map[..params.0.e820_entries as usize]
+            .iter()
+            .map(|e| (e.addr, e.size, e.type_))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (0, EBDA_START, E820_RAM),
+                (
+                    layout::HIMEM_START,
+                    firmware.0 - layout::HIMEM_START,
+                    E820_RAM
+                ),
+                (
+                    firmware.0 + firmware.1,
+                    arch_mem_info.ram_last_addr + 1 - (firmware.0 + firmware.1),
+                    E820_RAM
+                ),
+                (firmware.0, firmware.1, E820_RESERVED),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_system_configuration_fixed_entropy() {
+        let (arch_mem_info, arch_mem_regions) =
+            arch_memory_regions(128 << 20, KERNEL_LOAD_ADDR, KERNEL_SIZE);
+        let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
+        let entropy = BootEntropy::Fixed(42);
+        let topology = CpuTopology::single_socket(1, false);
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            &topology,
+            &None,
+            &entropy,
+        )
+        .unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(GuestAddress(layout::ZERO_PAGE_START)).unwrap();
+        let setup_data_addr = params.0.hdr.setup_data;
+        assert_eq!(setup_data_addr, layout::SETUP_DATA_START);
+
+        let header: SetupDataHeader = gm.read_obj(GuestAddress(layout::SETUP_DATA_START)).unwrap();
+        assert_eq!(header.type_, SETUP_RNG_SEED);
+        assert_eq!(header.len as usize, RNG_SEED_LEN);
+
+        let mut seed = [0u8; RNG_SEED_LEN];
+        gm.read_slice(
+            &mut seed,
+            GuestAddress(layout::SETUP_DATA_START + std::mem::size_of::<SetupDataHeader>() as u64),
+        )
+        .unwrap();
+        assert_eq!(seed.to_vec(), entropy.seed_bytes(RNG_SEED_LEN).unwrap());
     }
 
     #[test]
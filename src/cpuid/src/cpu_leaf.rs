@@ -1,6 +1,11 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+// Highest basic leaf and vendor id
+pub mod leaf_0x0 {
+    pub const LEAF_NUM: u32 = 0x0;
+}
+
 // Basic CPUID Information
 pub mod leaf_0x1 {
     pub const LEAF_NUM: u32 = 0x1;
@@ -34,7 +39,8 @@ pub mod leaf_0x1 {
         pub const MONITOR_BITINDEX: u32 = 3;
         // CPL Qualified Debug Store
         pub const DS_CPL_SHIFT: u32 = 4;
-        // 5 = VMX (Virtual Machine Extensions)
+        // VMX = Virtual Machine Extensions
+        pub const VMX_BITINDEX: u32 = 5;
         // 6 = SMX (Safer Mode Extensions)
         // 7 = EIST (Enhanced Intel SpeedStep® technology)
         // TM2 = Thermal Monitor 2
@@ -200,6 +206,16 @@ pub mod leaf_0xa {
     pub const LEAF_NUM: u32 = 0xa;
 }
 
+// Time Stamp Counter and Nominal Core Crystal Clock Information Leaf
+pub mod leaf_0x15 {
+    pub const LEAF_NUM: u32 = 0x15;
+}
+
+// Processor Frequency Information Leaf
+pub mod leaf_0x16 {
+    pub const LEAF_NUM: u32 = 0x16;
+}
+
 // Extended Topology Leaf
 pub mod leaf_0xb {
     pub const LEAF_NUM: u32 = 0xb;
@@ -269,6 +285,7 @@ pub mod leaf_0x80000001 {
 
     pub mod ecx {
         pub const TOPOEXT_INDEX: u32 = 22;
+        pub const SVM_BITINDEX: u32 = 2; // Secure Virtual Machine
         pub const PREFETCH_BITINDEX: u32 = 8; // 3DNow! PREFETCH/PREFETCHW instructions
         pub const LZCNT_BITINDEX: u32 = 5; // advanced bit manipulation
     }
@@ -278,6 +295,15 @@ pub mod leaf_0x80000001 {
     }
 }
 
+// Advanced Power Management Information
+pub mod leaf_0x80000007 {
+    pub const LEAF_NUM: u32 = 0x8000_0007;
+
+    pub mod edx {
+        pub const INVARIANT_TSC_BITINDEX: u32 = 8; // The TSC rate is constant in all states.
+    }
+}
+
 pub mod leaf_0x80000008 {
     pub const LEAF_NUM: u32 = 0x8000_0008;
 
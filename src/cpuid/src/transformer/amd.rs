@@ -51,13 +51,17 @@ pub fn update_largest_extended_fn_entry(
 
 pub fn update_extended_feature_info_entry(
     entry: &mut kvm_cpuid_entry2,
-    _vm_spec: &VmSpec,
+    vm_spec: &VmSpec,
 ) -> Result<(), Error> {
     use crate::cpu_leaf::leaf_0x80000001::*;
 
     // set the Topology Extension bit since we use the Extended Cache Topology leaf
     entry.ecx.write_bit(ecx::TOPOEXT_INDEX, true);
 
+    // KVM only reports SVM if the host allows nesting, keep it only if nesting was asked for.
+    let svm = vm_spec.nested && entry.ecx.read_bit(ecx::SVM_BITINDEX);
+    entry.ecx.write_bit(ecx::SVM_BITINDEX, svm);
+
     Ok(())
 }
 
@@ -68,11 +72,18 @@ pub fn update_amd_features_entry(
     use crate::cpu_leaf::leaf_0x80000008::*;
 
     // We don't support more then 64 threads right now.
-    // It's safe to put them all on the same processor.
+    // Without a topology, it's safe to put them all on the same processor.
+    let thread_id_size = match vm_spec.topology {
+        Some((threads, cores)) => apic_id_bits(threads) + apic_id_bits(cores),
+        None => THREAD_ID_MAX_SIZE,
+    };
     entry
         .ecx
-        .write_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE, THREAD_ID_MAX_SIZE)
-        .write_bits_in_range(&ecx::NUM_THREADS_BITRANGE, u32::from(vm_spec.cpu_count - 1));
+        .write_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE, thread_id_size)
+        .write_bits_in_range(
+            &ecx::NUM_THREADS_BITRANGE,
+            u32::from(vm_spec.cpus_per_package() - 1),
+        );
 
     Ok(())
 }
@@ -93,14 +104,21 @@ pub fn update_extended_apic_id_entry(
     use crate::cpu_leaf::leaf_0x8000001e::*;
 
     let mut core_id = u32::from(vm_spec.cpu_id);
-    // When hyper-threading is enabled each pair of 2 consecutive logical CPUs
-    // will have the same core id since they represent 2 threads in the same core.
-    // For Example:
-    // logical CPU 0 -> core id: 0
-    // logical CPU 1 -> core id: 0
-    // logical CPU 2 -> core id: 1
-    // logical CPU 3 -> core id: 1
-    if vm_spec.ht_enabled {
+    let mut threads_per_core = u32::from(vm_spec.ht_enabled);
+    let mut node_id = 0;
+    if let Some((threads, cores)) = vm_spec.topology {
+        // Consecutive logical CPUs are the threads of a core, and each socket is a node.
+        core_id /= u32::from(threads);
+        threads_per_core = u32::from(threads - 1);
+        node_id = u32::from(vm_spec.cpu_id / (threads * cores));
+    } else if vm_spec.ht_enabled {
+        // When hyper-threading is enabled each pair of 2 consecutive logical CPUs
+        // will have the same core id since they represent 2 threads in the same core.
+        // For Example:
+        // logical CPU 0 -> core id: 0
+        // logical CPU 1 -> core id: 0
+        // logical CPU 2 -> core id: 1
+        // logical CPU 3 -> core id: 1
         core_id /= 2;
     }
 
@@ -112,16 +130,13 @@ pub fn update_extended_apic_id_entry(
     entry
         .ebx
         .write_bits_in_range(&ebx::CORE_ID_BITRANGE, core_id)
-        .write_bits_in_range(
-            &ebx::THREADS_PER_CORE_BITRANGE,
-            u32::from(vm_spec.ht_enabled),
-        );
+        .write_bits_in_range(&ebx::THREADS_PER_CORE_BITRANGE, threads_per_core);
 
     entry
         .ecx
         .write_bits_in_range(&ecx::NODES_PER_PROCESSOR_BITRANGE, NODES_PER_PROCESSOR)
-        // Put all the cpus in the same node.
-        .write_bits_in_range(&ecx::NODE_ID_BITRANGE, 0);
+        // Without a topology, put all the cpus in the same node.
+        .write_bits_in_range(&ecx::NODE_ID_BITRANGE, node_id);
 
     Ok(())
 }
@@ -132,6 +147,11 @@ impl CpuidTransformer for AmdCpuidTransformer {
     fn process_cpuid(&self, cpuid: &mut CpuId, vm_spec: &VmSpec) -> Result<(), Error> {
         use_host_cpuid_function(cpuid, leaf_0x8000001d::LEAF_NUM, false)?;
         use_host_cpuid_function(cpuid, leaf_0x8000001d::LEAF_NUM, true)?;
+        // AMD CPUs have no leaf describing the TSC frequency, guests read it from kvmclock
+        // instead, but they can still be told the TSC is invariant.
+        if vm_spec.tsc_khz.is_some() {
+            common::set_invariant_tsc(cpuid);
+        }
         self.process_entries(cpuid, vm_spec)
     }
 
@@ -227,6 +247,31 @@ mod tests {
         assert!(entry.ecx.read_bit(ecx::TOPOEXT_INDEX));
     }
 
+    #[test]
+    fn test_update_extended_feature_info_entry_nested() {
+        use crate::cpu_leaf::leaf_0x80000001::*;
+
+        let mut vm_spec = VmSpec::new(0, 1, false).expect("Error creating vm_spec");
+        let mut entry = kvm_cpuid_entry2 {
+            function: LEAF_NUM,
+            ecx: 1 << ecx::SVM_BITINDEX,
+            ..Default::default()
+        };
+
+        assert!(update_extended_feature_info_entry(&mut entry, &vm_spec).is_ok());
+        assert!(!entry.ecx.read_bit(ecx::SVM_BITINDEX));
+
+        vm_spec.set_nested(true);
+        entry.ecx.write_bit(ecx::SVM_BITINDEX, true);
+        assert!(update_extended_feature_info_entry(&mut entry, &vm_spec).is_ok());
+        assert!(entry.ecx.read_bit(ecx::SVM_BITINDEX));
+
+        // SVM can't be exposed if the host doesn't support it.
+        entry.ecx.write_bit(ecx::SVM_BITINDEX, false);
+        assert!(update_extended_feature_info_entry(&mut entry, &vm_spec).is_ok());
+        assert!(!entry.ecx.read_bit(ecx::SVM_BITINDEX));
+    }
+
     fn check_update_amd_features_entry(cpu_count: u8, ht_enabled: bool) {
         use crate::cpu_leaf::leaf_0x80000008::*;
 
@@ -352,4 +397,42 @@ mod tests {
         check_update_extended_apic_id_entry(0, 2, true, 0, 1);
         check_update_extended_apic_id_entry(1, 2, true, 0, 1);
     }
+
+    #[test]
+    fn test_topology() {
+        // 2 sockets of 2 cores of 2 threads, cpu 5 is thread 1 of core 0 of socket 1.
+        let mut vm_spec = VmSpec::new(5, 8, true).expect("Error creating vm_spec");
+        vm_spec.set_topology(2, 2);
+
+        let mut entry = kvm_cpuid_entry2 {
+            function: leaf_0x80000008::LEAF_NUM,
+            ..Default::default()
+        };
+        assert!(update_amd_features_entry(&mut entry, &vm_spec).is_ok());
+        {
+            use crate::cpu_leaf::leaf_0x80000008::*;
+            assert_eq!(entry.ecx.read_bits_in_range(&ecx::NUM_THREADS_BITRANGE), 3);
+            assert_eq!(
+                entry.ecx.read_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE),
+                2
+            );
+        }
+
+        let mut entry = kvm_cpuid_entry2 {
+            function: leaf_0x8000001e::LEAF_NUM,
+            ..Default::default()
+        };
+        assert!(update_extended_apic_id_entry(&mut entry, &vm_spec).is_ok());
+        {
+            use crate::cpu_leaf::leaf_0x8000001e::*;
+            assert_eq!(entry.ebx.read_bits_in_range(&ebx::CORE_ID_BITRANGE), 2);
+            assert_eq!(
+                entry
+                    .ebx
+                    .read_bits_in_range(&ebx::THREADS_PER_CORE_BITRANGE),
+                1
+            );
+            assert_eq!(entry.ecx.read_bits_in_range(&ecx::NODE_ID_BITRANGE), 1);
+        }
+    }
 }
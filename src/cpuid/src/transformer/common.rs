@@ -12,6 +12,9 @@ use kvm_bindings::{kvm_cpuid_entry2, CpuId};
 // CPUID bits in ebx, ecx, and edx.
 const EBX_CLFLUSH_CACHELINE: u32 = 8; // Flush a cache line size.
 
+// The core crystal clock we advertise, the rate of the APIC timer KVM emulates (1ns per tick).
+const CRYSTAL_CLOCK_KHZ: u32 = 1_000_000;
+
 /// The maximum number of logical processors per package is computed as the closest power of 2
 /// higher or equal to the CPU count configured by the user.
 fn get_max_cpus_per_package(cpu_count: u8) -> Result<u8, Error> {
@@ -33,11 +36,17 @@ pub fn update_feature_info_entry(
 ) -> Result<(), Error> {
     use crate::cpu_leaf::leaf_0x1::*;
 
-    let max_cpus_per_package = u32::from(common::get_max_cpus_per_package(vm_spec.cpu_count)?);
+    let max_cpus_per_package = u32::from(common::get_max_cpus_per_package(
+        vm_spec.cpus_per_package(),
+    )?);
 
     // X86 hypervisor feature
     entry.ecx.write_bit(ecx::HYPERVISOR_BITINDEX, true);
 
+    // KVM only reports VMX if the host allows nesting, keep it only if nesting was asked for.
+    let vmx = vm_spec.nested && entry.ecx.read_bit(ecx::VMX_BITINDEX);
+    entry.ecx.write_bit(ecx::VMX_BITINDEX, vmx);
+
     entry
         .ebx
         .write_bits_in_range(&ebx::APICID_BITRANGE, u32::from(vm_spec.cpu_id))
@@ -49,7 +58,7 @@ pub fn update_feature_info_entry(
     // is valid for the package
     entry
         .edx
-        .write_bit(edx::HTT_BITINDEX, vm_spec.cpu_count > 1);
+        .write_bit(edx::HTT_BITINDEX, vm_spec.cpus_per_package() > 1);
 
     Ok(())
 }
@@ -73,6 +82,19 @@ pub fn update_cache_parameters_entry(
 ) -> Result<(), Error> {
     use crate::cpu_leaf::leaf_cache_parameters::*;
 
+    if let Some((threads, cores)) = vm_spec.topology {
+        // L1 & L2 are private to a core, and L3 is shared by the whole package.
+        let sharing_id_bits = match entry.eax.read_bits_in_range(&eax::CACHE_LEVEL_BITRANGE) {
+            1 | 2 => apic_id_bits(threads),
+            3 => apic_id_bits(threads) + apic_id_bits(cores),
+            _ => return Ok(()),
+        };
+        entry
+            .eax
+            .write_bits_in_range(&eax::MAX_CPUS_PER_CORE_BITRANGE, (1 << sharing_id_bits) - 1);
+        return Ok(());
+    }
+
     match entry.eax.read_bits_in_range(&eax::CACHE_LEVEL_BITRANGE) {
         // L1 & L2 Cache
         1 | 2 => {
@@ -96,6 +118,89 @@ pub fn update_cache_parameters_entry(
     Ok(())
 }
 
+/// Returns the fraction closest to `num / den` whose numerator is at most `max_num`, from the
+/// convergents of its continued fraction.
+fn approximate_ratio(num: u64, den: u64, max_num: u64) -> (u64, u64) {
+    let (mut p0, mut q0, mut p1, mut q1) = (0, 1, 1, 0);
+    let (mut n, mut d) = (num, den);
+    while d != 0 {
+        let a = n / d;
+        let (p2, q2) = (a * p1 + p0, a * q1 + q0);
+        if p2 > max_num {
+            break;
+        }
+        (p0, q0, p1, q1) = (p1, q1, p2, q2);
+        (n, d) = (d, n - a * d);
+    }
+    (p1, q1)
+}
+
+/// Advertises an invariant TSC, which KVM keeps at the same rate whatever the host does.
+pub fn set_invariant_tsc(cpuid: &mut CpuId) {
+    use crate::cpu_leaf::leaf_0x80000007;
+
+    for entry in cpuid.as_mut_slice() {
+        if entry.function == leaf_0x80000007::LEAF_NUM {
+            entry
+                .edx
+                .write_bit(leaf_0x80000007::edx::INVARIANT_TSC_BITINDEX, true);
+        }
+    }
+}
+
+/// Advertises an invariant TSC running at `tsc_khz` and the core crystal clock it derives from in
+/// leaves 0x15 and 0x16, which lets Linux skip calibrating both the TSC and the APIC timer (which
+/// runs at the crystal clock rate). These leaves only exist on Intel CPUs.
+pub fn update_tsc_frequency_entries(cpuid: &mut CpuId, tsc_khz: u32) -> Result<(), Error> {
+    use crate::cpu_leaf::{leaf_0x0, leaf_0x15, leaf_0x16};
+
+    cpuid.retain(|entry| {
+        entry.function != leaf_0x15::LEAF_NUM && entry.function != leaf_0x16::LEAF_NUM
+    });
+
+    // The TSC runs at crystal * ebx / eax. Linux computes crystal_khz * ebx in 32 bits, so the
+    // ratio has to be approximated with a small enough numerator.
+    let (ebx, eax) = approximate_ratio(
+        u64::from(tsc_khz),
+        u64::from(CRYSTAL_CLOCK_KHZ),
+        u64::from(u32::MAX / CRYSTAL_CLOCK_KHZ),
+    );
+    cpuid
+        .push(kvm_cpuid_entry2 {
+            function: leaf_0x15::LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: eax as u32,
+            ebx: ebx as u32,
+            ecx: CRYSTAL_CLOCK_KHZ * 1000,
+            edx: 0,
+            padding: [0, 0, 0],
+        })
+        .map_err(FamError)?;
+    // Base, maximum and bus frequencies, in MHz.
+    cpuid
+        .push(kvm_cpuid_entry2 {
+            function: leaf_0x16::LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: tsc_khz / 1000,
+            ebx: tsc_khz / 1000,
+            ecx: CRYSTAL_CLOCK_KHZ / 1000,
+            edx: 0,
+            padding: [0, 0, 0],
+        })
+        .map_err(FamError)?;
+
+    for entry in cpuid.as_mut_slice() {
+        if entry.function == leaf_0x0::LEAF_NUM {
+            entry.eax = entry.eax.max(leaf_0x16::LEAF_NUM);
+        }
+    }
+    set_invariant_tsc(cpuid);
+
+    Ok(())
+}
+
 /// Replaces the `cpuid` entries corresponding to `function` with the entries from the host's cpuid.
 pub fn use_host_cpuid_function(
     cpuid: &mut CpuId,
@@ -250,6 +355,37 @@ mod tests {
         check_update_cache_parameters_entry(2, true, 3, 1);
     }
 
+    #[test]
+    fn test_update_tsc_frequency_entries() {
+        let mut cpuid = CpuId::new(2).unwrap();
+        cpuid.as_mut_slice()[0].eax = 0xd;
+        cpuid.as_mut_slice()[1].function = 0x8000_0007;
+
+        let tsc_khz = 2_995_213;
+        update_tsc_frequency_entries(&mut cpuid, tsc_khz).unwrap();
+        let entries = cpuid.as_slice();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].eax, 0x16);
+        assert_eq!(entries[1].edx, 1 << 8);
+
+        let leaf_0x15 = &entries[2];
+        assert_eq!(leaf_0x15.function, 0x15);
+        // What Linux computes from the leaf, overflows included.
+        let crystal_khz = leaf_0x15.ecx / 1000;
+        let guest_tsc_khz = crystal_khz.checked_mul(leaf_0x15.ebx).unwrap() / leaf_0x15.eax;
+        assert!(guest_tsc_khz.abs_diff(tsc_khz) <= 3);
+
+        let leaf_0x16 = &entries[3];
+        assert_eq!(leaf_0x16.function, 0x16);
+        assert_eq!((leaf_0x16.eax, leaf_0x16.ecx), (2995, 1000));
+
+        // The leaves are replaced rather than duplicated.
+        update_tsc_frequency_entries(&mut cpuid, 1_000_000).unwrap();
+        let entries = cpuid.as_slice();
+        assert_eq!(entries.len(), 4);
+        assert_eq!((entries[2].eax, entries[2].ebx), (1, 1));
+    }
+
     #[test]
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     fn test_use_host_cpuid_function_with_count() {
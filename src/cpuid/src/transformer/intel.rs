@@ -30,11 +30,14 @@ fn update_deterministic_cache_entry(
 
     common::update_cache_parameters_entry(entry, vm_spec)?;
 
-    // Put all the cores in the same socket
-    entry.eax.write_bits_in_range(
-        &eax::MAX_CORES_PER_PACKAGE_BITRANGE,
-        u32::from(vm_spec.cpu_count - 1),
-    );
+    let max_cores_per_package = match vm_spec.topology {
+        Some((_, cores)) => (1 << apic_id_bits(cores)) - 1,
+        // Put all the cores in the same socket
+        None => u32::from(vm_spec.cpu_count - 1),
+    };
+    entry
+        .eax
+        .write_bits_in_range(&eax::MAX_CORES_PER_PACKAGE_BITRANGE, max_cores_per_package);
 
     Ok(())
 }
@@ -76,6 +79,34 @@ fn update_extended_cache_topology_entry(
     // EDX bits 31..0 contain x2APIC ID of current logical processor
     // x2APIC increases the size of the APIC ID from 8 bits to 32 bits
     entry.edx = u32::from(vm_spec.cpu_id);
+
+    if let Some((threads, cores)) = vm_spec.topology {
+        let (level_type, apicid_shift, num_logical_processors) = match entry.index {
+            0 => (LEVEL_TYPE_THREAD, apic_id_bits(threads), threads),
+            1 => (
+                LEVEL_TYPE_CORE,
+                apic_id_bits(threads) + apic_id_bits(cores),
+                threads * cores,
+            ),
+            level => {
+                entry.ecx = level;
+                return Ok(());
+            }
+        };
+        entry
+            .eax
+            .write_bits_in_range(&eax::APICID_BITRANGE, apicid_shift);
+        entry.ebx.write_bits_in_range(
+            &ebx::NUM_LOGICAL_PROCESSORS_BITRANGE,
+            u32::from(num_logical_processors),
+        );
+        entry
+            .ecx
+            .write_bits_in_range(&ecx::LEVEL_NUMBER_BITRANGE, entry.index)
+            .write_bits_in_range(&ecx::LEVEL_TYPE_BITRANGE, level_type);
+        return Ok(());
+    }
+
     match entry.index {
         // Thread Level Topology; index = 0
         0 => {
@@ -139,6 +170,14 @@ fn update_extended_cache_topology_entry(
 pub struct IntelCpuidTransformer {}
 
 impl CpuidTransformer for IntelCpuidTransformer {
+    fn process_cpuid(&self, cpuid: &mut CpuId, vm_spec: &VmSpec) -> Result<(), Error> {
+        // Only Intel CPUs describe their TSC frequency in CPUID.
+        if let Some(tsc_khz) = vm_spec.tsc_khz {
+            common::update_tsc_frequency_entries(cpuid, tsc_khz)?;
+        }
+        self.process_entries(cpuid, vm_spec)
+    }
+
     fn entry_transformer_fn(&self, entry: &mut kvm_cpuid_entry2) -> Option<EntryTransformerFn> {
         match entry.function {
             leaf_0x1::LEAF_NUM => Some(intel::update_feature_info_entry),
@@ -182,6 +221,26 @@ mod tests {
         assert!(entry.ecx.read_bit(ecx::TSC_DEADLINE_TIMER_BITINDEX));
     }
 
+    #[test]
+    fn test_update_feature_info_entry_nested() {
+        use crate::cpu_leaf::leaf_0x1::*;
+
+        let mut vm_spec = VmSpec::new(0, 1, false).expect("Error creating vm_spec");
+        let mut entry = kvm_cpuid_entry2 {
+            function: leaf_0x1::LEAF_NUM,
+            ecx: 1 << ecx::VMX_BITINDEX,
+            ..Default::default()
+        };
+
+        assert!(update_feature_info_entry(&mut entry, &vm_spec).is_ok());
+        assert!(!entry.ecx.read_bit(ecx::VMX_BITINDEX));
+
+        vm_spec.set_nested(true);
+        entry.ecx.write_bit(ecx::VMX_BITINDEX, true);
+        assert!(update_feature_info_entry(&mut entry, &vm_spec).is_ok());
+        assert!(entry.ecx.read_bit(ecx::VMX_BITINDEX));
+    }
+
     #[test]
     fn test_update_perf_mon_entry() {
         let vm_spec = VmSpec::new(0, 1, false).expect("Error creating vm_spec");
@@ -364,4 +423,59 @@ mod tests {
             LEVEL_TYPE_CORE,
         );
     }
+
+    #[test]
+    fn test_topology() {
+        use crate::cpu_leaf::leaf_0xb::*;
+
+        // 2 sockets of 4 cores of 2 threads.
+        let mut vm_spec = VmSpec::new(9, 16, true).expect("Error creating vm_spec");
+        vm_spec.set_topology(2, 4);
+
+        let expected = [(1, 2, LEVEL_TYPE_THREAD), (3, 8, LEVEL_TYPE_CORE)];
+        for (index, (apicid_shift, num_logical_processors, level_type)) in
+            expected.into_iter().enumerate()
+        {
+            let mut entry = kvm_cpuid_entry2 {
+                function: leaf_0xb::LEAF_NUM,
+                index: index as u32,
+                ..Default::default()
+            };
+            assert!(update_extended_cache_topology_entry(&mut entry, &vm_spec).is_ok());
+            assert_eq!(
+                entry.eax.read_bits_in_range(&eax::APICID_BITRANGE),
+                apicid_shift
+            );
+            assert_eq!(
+                entry
+                    .ebx
+                    .read_bits_in_range(&ebx::NUM_LOGICAL_PROCESSORS_BITRANGE),
+                num_logical_processors
+            );
+            assert_eq!(
+                entry.ecx.read_bits_in_range(&ecx::LEVEL_TYPE_BITRANGE),
+                level_type
+            );
+            assert_eq!(entry.edx, 9);
+        }
+
+        let mut entry = kvm_cpuid_entry2 {
+            function: leaf_0x4::LEAF_NUM,
+            eax: *(0_u32).write_bits_in_range(&leaf_0x4::eax::CACHE_LEVEL_BITRANGE, 3),
+            ..Default::default()
+        };
+        assert!(update_deterministic_cache_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(
+            entry
+                .eax
+                .read_bits_in_range(&leaf_0x4::eax::MAX_CORES_PER_PACKAGE_BITRANGE),
+            3
+        );
+        assert_eq!(
+            entry
+                .eax
+                .read_bits_in_range(&leaf_0x4::eax::MAX_CPUS_PER_CORE_BITRANGE),
+            7
+        );
+    }
 }
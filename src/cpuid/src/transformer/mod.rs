@@ -23,6 +23,12 @@ pub struct VmSpec {
     ht_enabled: bool,
     /// The desired brand string for the guest.
     brand_string: BrandString,
+    /// Specifies whether the guest may use hardware virtualization itself.
+    nested: bool,
+    /// The TSC frequency advertised to the guest, in kHz.
+    tsc_khz: Option<u32>,
+    /// The threads per core and cores per socket, when set by `set_topology`.
+    topology: Option<(u8, u8)>,
 }
 
 impl VmSpec {
@@ -37,9 +43,36 @@ impl VmSpec {
             cpu_count,
             ht_enabled,
             brand_string: BrandString::from_vendor_id(&cpu_vendor_id),
+            nested: false,
+            tsc_khz: None,
+            topology: None,
         })
     }
 
+    /// Exposes VMX/SVM to the guest, provided the host supports nested virtualization.
+    pub fn set_nested(&mut self, nested: bool) {
+        self.nested = nested;
+    }
+
+    /// Advertises a TSC running at `tsc_khz` so the guest doesn't have to calibrate it.
+    pub fn set_tsc_khz(&mut self, tsc_khz: u32) {
+        self.tsc_khz = Some(tsc_khz);
+    }
+
+    /// Groups the logical cpus into cores of `threads_per_core` threads and packages of
+    /// `cores_per_socket` cores, numbered thread first. Both must be powers of 2 for the APIC IDs,
+    /// which are the logical cpu ids, to break down into that topology. Without a topology, all
+    /// the cpus are in one package, and `ht_enabled` pairs them in cores.
+    pub fn set_topology(&mut self, threads_per_core: u8, cores_per_socket: u8) {
+        self.topology = Some((threads_per_core, cores_per_socket));
+    }
+
+    /// Returns the number of logical cpus in each package.
+    fn cpus_per_package(&self) -> u8 {
+        self.topology
+            .map_or(self.cpu_count, |(threads, cores)| threads * cores)
+    }
+
     /// Returns an immutable reference to cpu_vendor_id
     pub fn cpu_vendor_id(&self) -> &[u8; 12] {
         &self.cpu_vendor_id
@@ -57,6 +90,11 @@ pub enum Error {
     VcpuCountOverflow,
 }
 
+/// Returns the number of bits of the APIC ID needed to tell `count` siblings apart.
+fn apic_id_bits(count: u8) -> u32 {
+    u8::BITS - count.saturating_sub(1).leading_zeros()
+}
+
 pub type EntryTransformerFn =
     fn(entry: &mut kvm_cpuid_entry2, vm_spec: &VmSpec) -> Result<(), Error>;
 
@@ -32,6 +32,55 @@ pub trait BusDevice: AsAny + Send {
     }
 }
 
+/// Direction of an access to an address on the bus.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BusAccess {
+    Read,
+    Write,
+}
+
+/// Trait for objects that want to be notified about accesses to addresses which are not claimed
+/// by any device on the bus.
+pub trait UnhandledAccessObserver: Send + Sync {
+    /// Called with the address, size and direction of the unclaimed access.
+    fn on_unhandled_access(&self, vcpuid: u64, addr: u64, len: usize, access: BusAccess);
+}
+
+/// Trait for objects handling guest accesses to a range of addresses on behalf of the embedder,
+/// so it can emulate devices of its own. `addr` is the absolute address of the access. The methods
+/// are called on the vCPU thread making the access, under its seccomp filter if it has one.
+pub trait BusAccessHandler: Send + Sync {
+    /// Called when the guest reads `data.len()` bytes at `addr`. Whatever is left in `data`,
+    /// which starts zeroed, is returned to the guest.
+    fn read(&self, vcpuid: u64, addr: u64, data: &mut [u8]);
+    /// Called when the guest writes `data` at `addr`.
+    fn write(&self, vcpuid: u64, addr: u64, data: &[u8]);
+}
+
+/// Bus device forwarding all the accesses to its range to a `BusAccessHandler`.
+pub struct HandlerDevice {
+    base: u64,
+    handler: Arc<dyn BusAccessHandler>,
+}
+
+impl HandlerDevice {
+    /// Creates a device for a range starting at `base`.
+    pub fn new(base: u64, handler: Arc<dyn BusAccessHandler>) -> Self {
+        HandlerDevice { base, handler }
+    }
+}
+
+impl BusDevice for HandlerDevice {
+    fn read(&mut self, vcpuid: u64, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        self.handler.read(vcpuid, self.base + offset, data);
+    }
+
+    fn write(&mut self, vcpuid: u64, offset: u64, data: &[u8]) {
+        self.handler.write(vcpuid, self.base + offset, data);
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// The insertion failed because the new device overlapped with an old device.
@@ -73,6 +122,18 @@ impl PartialOrd for BusRange {
     }
 }
 
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.devices
+                    .keys()
+                    .map(|BusRange(base, len)| format!("{base:#x}+{len:#x}")),
+            )
+            .finish()
+    }
+}
+
 /// A device container for routing reads and writes over some address space.
 ///
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
@@ -80,6 +141,7 @@ impl PartialOrd for BusRange {
 #[derive(Clone, Default)]
 pub struct Bus {
     devices: BTreeMap<BusRange, Arc<Mutex<dyn BusDevice>>>,
+    unhandled_access_observer: Option<Arc<dyn UnhandledAccessObserver>>,
 }
 
 impl Bus {
@@ -87,6 +149,20 @@ impl Bus {
     pub fn new() -> Bus {
         Bus {
             devices: BTreeMap::new(),
+            unhandled_access_observer: None,
+        }
+    }
+
+    /// Sets the observer to be notified about accesses that no device handles.
+    ///
+    /// Clones of this bus made before calling this method won't see the observer.
+    pub fn set_unhandled_access_observer(&mut self, observer: Arc<dyn UnhandledAccessObserver>) {
+        self.unhandled_access_observer = Some(observer);
+    }
+
+    fn notify_unhandled_access(&self, vcpuid: u64, addr: u64, len: usize, access: BusAccess) {
+        if let Some(observer) = &self.unhandled_access_observer {
+            observer.on_unhandled_access(vcpuid, addr, len, access);
         }
     }
 
@@ -140,6 +216,13 @@ impl Bus {
         Ok(())
     }
 
+    /// Removes the device whose range starts at `base`, returning it.
+    ///
+    /// Clones of this bus made before calling this method still route accesses to it.
+    pub fn remove(&mut self, base: u64) -> Option<Arc<Mutex<dyn BusDevice>>> {
+        self.devices.remove(&BusRange(base, 0))
+    }
+
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
     ///
     /// Returns true on success, otherwise `data` is untouched.
@@ -151,6 +234,7 @@ impl Bus {
                 .read(vcpuid, offset, data);
             true
         } else {
+            self.notify_unhandled_access(vcpuid, addr, data.len(), BusAccess::Read);
             false
         }
     }
@@ -166,6 +250,7 @@ impl Bus {
                 .write(vcpuid, offset, data);
             true
         } else {
+            self.notify_unhandled_access(vcpuid, addr, data.len(), BusAccess::Write);
             false
         }
     }
@@ -221,6 +306,27 @@ mod tests {
         assert!(bus.insert(dummy, 0x0, 0x10).is_ok());
     }
 
+    #[test]
+    fn bus_remove() {
+        let mut bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.insert(dummy.clone(), 0x20, 0x10).is_ok());
+        let clone = bus.clone();
+
+        // Only the start of a range identifies a device.
+        assert!(bus.remove(0x18).is_none());
+        assert!(bus.remove(0x10).is_some());
+        assert!(bus.remove(0x10).is_none());
+        assert!(!bus.read(0, 0x10, &mut [0, 0, 0, 0]));
+        assert!(bus.read(0, 0x20, &mut [0, 0, 0, 0]));
+        assert!(clone.read(0, 0x10, &mut [0, 0, 0, 0]));
+        assert_eq!(format!("{bus:?}"), "[\"0x20+0x10\"]");
+
+        // The range can be reused.
+        assert!(bus.insert(dummy, 0x10, 0x10).is_ok());
+    }
+
     #[test]
     fn bus_read_write() {
         let mut bus = Bus::new();
@@ -253,6 +359,69 @@ mod tests {
         assert!(bus.write(0, 0x15, &values));
     }
 
+    #[test]
+    fn bus_unhandled_access_observer() {
+        #[derive(Default)]
+        struct RecordingObserver {
+            accesses: Mutex<Vec<(u64, usize, BusAccess)>>,
+        }
+        impl UnhandledAccessObserver for RecordingObserver {
+            fn on_unhandled_access(&self, _vcpuid: u64, addr: u64, len: usize, access: BusAccess) {
+                self.accesses.lock().unwrap().push((addr, len, access));
+            }
+        }
+
+        let mut bus = Bus::new();
+        let observer = Arc::new(RecordingObserver::default());
+        bus.set_unhandled_access_observer(observer.clone());
+        assert!(bus
+            .insert(Arc::new(Mutex::new(DummyDevice)), 0x10, 0x10)
+            .is_ok());
+
+        assert!(bus.read(0, 0x10, &mut [0, 0, 0, 0]));
+        assert!(bus.write(0, 0x1f, &[0]));
+        assert!(observer.accesses.lock().unwrap().is_empty());
+
+        assert!(!bus.read(0, 0x20, &mut [0, 0, 0, 0]));
+        assert!(!bus.write(0, 0x06, &[0, 0]));
+        assert_eq!(
+            *observer.accesses.lock().unwrap(),
+            vec![(0x20, 4, BusAccess::Read), (0x06, 2, BusAccess::Write)]
+        );
+    }
+
+    #[test]
+    fn bus_handler_device() {
+        #[derive(Default)]
+        struct RecordingHandler {
+            writes: Mutex<Vec<(u64, Vec<u8>)>>,
+        }
+        impl BusAccessHandler for RecordingHandler {
+            fn read(&self, _vcpuid: u64, addr: u64, data: &mut [u8]) {
+                data[0] = addr as u8;
+            }
+            fn write(&self, _vcpuid: u64, addr: u64, data: &[u8]) {
+                self.writes.lock().unwrap().push((addr, data.to_vec()));
+            }
+        }
+
+        let mut bus = Bus::new();
+        let handler = Arc::new(RecordingHandler::default());
+        assert!(bus
+            .insert(
+                Arc::new(Mutex::new(HandlerDevice::new(0x510, handler.clone()))),
+                0x510,
+                0x2
+            )
+            .is_ok());
+
+        let mut values = [0xff, 0xff];
+        assert!(bus.read(0, 0x511, &mut values));
+        assert_eq!(values, [0x11, 0]);
+        assert!(bus.write(0, 0x510, &[1, 2]));
+        assert_eq!(*handler.writes.lock().unwrap(), vec![(0x510, vec![1, 2])]);
+    }
+
     #[test]
     fn busrange_cmp_and_clone() {
         assert_eq!(BusRange(0x10, 2), BusRange(0x10, 3));
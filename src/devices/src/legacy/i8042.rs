@@ -155,6 +155,24 @@ impl I8042Device {
         Ok(())
     }
 
+    /// Queues raw `scancodes` for the guest, all of them or none if they don't fit in the buffer.
+    pub fn trigger_scancodes(&mut self, scancodes: &[u8]) -> Result<()> {
+        if scancodes.is_empty() {
+            return Ok(());
+        }
+        if BUF_SIZE - self.buf_len() < scancodes.len() {
+            return Err(Error::InternalBufferFull);
+        }
+        for &byte in scancodes {
+            self.push_byte(byte)?;
+        }
+
+        match self.trigger_kbd_interrupt() {
+            Ok(_) | Err(Error::KbdInterruptDisabled) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
     #[inline]
     fn push_byte(&mut self, byte: u8) -> Result<()> {
         self.status |= SB_OUT_DATA_AVAIL;
@@ -473,6 +491,18 @@ mod tests {
             Error::InternalBufferFull
         );
 
+        // Test raw scancodes trigger, and its failure.
+        i8042.flush_buf();
+        i8042.trigger_scancodes(&[0x5a, 0xf0, 0x5a]).unwrap();
+        assert_eq!(i8042.pop_byte(), Some(0x5a));
+        assert_eq!(i8042.pop_byte(), Some(0xf0));
+        assert_eq!(i8042.pop_byte(), Some(0x5a));
+        assert_eq!(
+            i8042.trigger_scancodes(&[0; BUF_SIZE + 1]).unwrap_err(),
+            Error::InternalBufferFull
+        );
+        assert_eq!(i8042.buf_len(), 0);
+
         // Test kbd interrupt disable.
         let mut data = [1];
         data[0] = CMD_WRITE_CTR;
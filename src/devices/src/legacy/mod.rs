@@ -9,11 +9,15 @@
 #[allow(non_camel_case_types)]
 mod gic;
 mod i8042;
+mod pvpanic;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
+mod serial_lines;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 #[cfg(target_arch = "x86_64")]
+use x86_64::cmos;
+#[cfg(target_arch = "x86_64")]
 use x86_64::serial;
 #[cfg(target_arch = "aarch64")]
 mod aarch64;
@@ -22,15 +26,19 @@ use aarch64::gpio;
 #[cfg(target_arch = "aarch64")]
 use aarch64::serial;
 
+#[cfg(target_arch = "x86_64")]
+pub use self::cmos::Cmos;
 #[cfg(target_os = "macos")]
 pub use self::gic::Gic;
 #[cfg(target_arch = "aarch64")]
 pub use self::gpio::Gpio;
 pub use self::i8042::Error as I8042DeviceError;
 pub use self::i8042::I8042Device;
+pub use self::pvpanic::{PvPanic, PVPANIC_CRASH_LOADED, PVPANIC_PANICKED};
 #[cfg(target_arch = "aarch64")]
 pub use self::rtc_pl031::RTC;
 pub use self::serial::Serial;
+pub use self::serial_lines::SerialLineBuffer;
 
 // Cannot use multiple types as bounds for a trait object, so we define our own trait
 // which is a composition of the desired bounds. In this case, io::Read and AsRawFd.
@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! QEMU pvpanic device, a single byte register the guest kernel writes to when it panics.
+//!
+//! Reading it returns the events the device supports, writing it raises the events set in the
+//! value. The guest finds it through a `qemu,pvpanic-mmio` FDT node, so it's only provided on
+//! aarch64: an x86_64 guest kernel would need ACPI to discover it.
+
+use utils::eventfd::EventFd;
+
+use crate::BusDevice;
+
+/// The guest kernel panicked.
+pub const PVPANIC_PANICKED: u8 = 1 << 0;
+/// The guest kernel panicked and loaded a crash kernel to capture the dump.
+pub const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+const PVPANIC_EVENTS: u8 = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+
+pub struct PvPanic {
+    // Events raised by the guest since the last `take_events`.
+    events: u8,
+    panic_evt: EventFd,
+}
+
+impl PvPanic {
+    /// Creates the device, writing to `panic_evt` whenever the guest raises an event.
+    pub fn new(panic_evt: EventFd) -> Self {
+        PvPanic {
+            events: 0,
+            panic_evt,
+        }
+    }
+
+    /// Returns the `PVPANIC_*` events raised since the last call, clearing them.
+    pub fn take_events(&mut self) -> u8 {
+        std::mem::take(&mut self.events)
+    }
+}
+
+impl BusDevice for PvPanic {
+    fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        if offset != 0 || data.len() != 1 {
+            return;
+        }
+        data[0] = PVPANIC_EVENTS;
+    }
+
+    fn write(&mut self, _vcpuid: u64, offset: u64, data: &[u8]) {
+        if offset != 0 || data.len() != 1 {
+            return;
+        }
+        let events = data[0] & PVPANIC_EVENTS;
+        if events == 0 {
+            return;
+        }
+        self.events |= events;
+        if let Err(e) = self.panic_evt.write(1) {
+            error!("Failed to signal the guest panic: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::eventfd::EFD_NONBLOCK;
+
+    #[test]
+    fn test_pvpanic() {
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut pvpanic = PvPanic::new(evt.try_clone().unwrap());
+
+        let mut data = [0u8];
+        pvpanic.read(0, 0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+
+        // Unsupported events are ignored.
+        pvpanic.write(0, 0, &[1 << 4]);
+        assert!(evt.read().is_err());
+        assert_eq!(pvpanic.take_events(), 0);
+
+        pvpanic.write(0, 0, &[PVPANIC_CRASH_LOADED]);
+        assert_eq!(evt.read().unwrap(), 1);
+        assert_eq!(pvpanic.take_events(), PVPANIC_CRASH_LOADED);
+        assert_eq!(pvpanic.take_events(), 0);
+    }
+}
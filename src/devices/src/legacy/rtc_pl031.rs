@@ -68,12 +68,13 @@ pub struct RTC {
 }
 
 impl RTC {
-    /// Constructs an AMBA PL031 RTC device.
-    pub fn new(interrupt_evt: EventFd) -> RTC {
+    /// Constructs an AMBA PL031 RTC device that starts counting from `tick_offset`
+    /// nanoseconds since the Unix epoch.
+    pub fn new(interrupt_evt: EventFd, tick_offset: i64) -> RTC {
         RTC {
             // This is used only for duration measuring purposes.
             previous_now: Instant::now(),
-            tick_offset: utils::time::get_time(utils::time::ClockType::Real) as i64,
+            tick_offset,
             match_value: 0,
             load: 0,
             imsc: 0,
@@ -82,6 +83,12 @@ impl RTC {
         }
     }
 
+    /// Makes the RTC count from `tick_offset` nanoseconds since the Unix epoch, from now on.
+    pub fn set_time(&mut self, tick_offset: i64) {
+        self.previous_now = Instant::now();
+        self.tick_offset = tick_offset;
+    }
+
     fn trigger_interrupt(&mut self) -> Result<()> {
         self.interrupt_evt.write(1).map_err(Error::InterruptFailure)
     }
@@ -186,7 +193,10 @@ mod tests {
 
     #[test]
     fn test_rtc_read_write_and_event() {
-        let mut rtc = RTC::new(EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap());
+        let mut rtc = RTC::new(
+            EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+            utils::time::get_time(utils::time::ClockType::Real) as i64,
+        );
         let mut data = [0; 4];
 
         // Read and write to the MR register.
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest line kept before it's split, so a guest that never prints a newline can't make the
+/// pending line grow without bound.
+const MAX_LINE_LEN: usize = 4096;
+
+#[derive(Default)]
+struct Lines {
+    complete: VecDeque<String>,
+    pending: Vec<u8>,
+}
+
+/// Ring buffer of the lines the guest writes to a serial port, readable from the host.
+///
+/// Use it as the output of a `Serial` device. Only complete lines are returned, with the line
+/// terminator (`\n` or `\r\n`) stripped. Once `capacity` lines are buffered the oldest ones are
+/// dropped.
+#[derive(Clone)]
+pub struct SerialLineBuffer {
+    capacity: usize,
+    lines: Arc<(Mutex<Lines>, Condvar)>,
+}
+
+impl SerialLineBuffer {
+    pub fn new(capacity: usize) -> Self {
+        SerialLineBuffer {
+            capacity: capacity.max(1),
+            lines: Arc::new((Mutex::new(Lines::default()), Condvar::new())),
+        }
+    }
+
+    /// Returns the next complete line, waiting up to `timeout` for the guest to print one.
+    pub fn read_line(&self, timeout: Duration) -> Option<String> {
+        let deadline = Instant::now() + timeout;
+        let (lines, cond) = &*self.lines;
+        let mut lines = lines.lock().unwrap();
+        loop {
+            if let Some(line) = lines.complete.pop_front() {
+                return Some(line);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            lines = cond.wait_timeout(lines, deadline - now).unwrap().0;
+        }
+    }
+
+    fn push_line(&self, lines: &mut Lines) {
+        let mut line = std::mem::take(&mut lines.pending);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if lines.complete.len() == self.capacity {
+            lines.complete.pop_front();
+        }
+        lines
+            .complete
+            .push_back(String::from_utf8_lossy(&line).into_owned());
+    }
+}
+
+impl io::Write for SerialLineBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (lines, cond) = &*self.lines;
+        let mut lines = lines.lock().unwrap();
+        let mut new_lines = false;
+        for &b in buf {
+            if b == b'\n' {
+                self.push_line(&mut lines);
+                new_lines = true;
+            } else {
+                lines.pending.push(b);
+                if lines.pending.len() == MAX_LINE_LEN {
+                    self.push_line(&mut lines);
+                    new_lines = true;
+                }
+            }
+        }
+        if new_lines {
+            cond.notify_all();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::thread;
+
+    #[test]
+    fn test_line_framing() {
+        let mut buffer = SerialLineBuffer::new(16);
+        buffer.write_all(b"first\r\nsec").unwrap();
+        buffer.write_all(b"ond\nthird").unwrap();
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(buffer.read_line(timeout).as_deref(), Some("first"));
+        assert_eq!(buffer.read_line(timeout).as_deref(), Some("second"));
+        // "third" isn't terminated yet.
+        assert_eq!(buffer.read_line(timeout), None);
+
+        buffer.write_all(b"\n").unwrap();
+        assert_eq!(buffer.read_line(timeout).as_deref(), Some("third"));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut buffer = SerialLineBuffer::new(2);
+        buffer.write_all(b"a\nb\nc\n").unwrap();
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(buffer.read_line(timeout).as_deref(), Some("b"));
+        assert_eq!(buffer.read_line(timeout).as_deref(), Some("c"));
+        assert_eq!(buffer.read_line(timeout), None);
+
+        buffer.write_all(&[b'x'; MAX_LINE_LEN + 1]).unwrap();
+        assert_eq!(buffer.read_line(timeout).unwrap().len(), MAX_LINE_LEN);
+    }
+
+    #[test]
+    fn test_read_line_wakes_up() {
+        let buffer = SerialLineBuffer::new(16);
+        let mut writer = buffer.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            writer.write_all(b"login: \n").unwrap();
+        });
+
+        assert_eq!(
+            buffer.read_line(Duration::from_secs(10)).as_deref(),
+            Some("login: ")
+        );
+        handle.join().unwrap();
+    }
+}
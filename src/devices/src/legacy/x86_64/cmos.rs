@@ -0,0 +1,394 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! MC146818 compatible CMOS RTC, found at I/O ports 0x70 (index) and 0x71 (data) on PCs.
+//!
+//! Only the clock is emulated: the alarm, periodic and update-ended interrupts are never raised,
+//! and the rest of the 128 bytes of CMOS RAM is plain storage.
+
+use std::time::Instant;
+
+use crate::BusDevice;
+use utils::time::NANOS_PER_SECOND;
+
+const OFS_INDEX: u64 = 0;
+const OFS_DATA: u64 = 1;
+
+// Bit 7 of the index port disables NMIs on real hardware, it doesn't select a register.
+const INDEX_MASK: u8 = 0x7f;
+const CMOS_SIZE: usize = 128;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY_OF_WEEK: u8 = 0x06;
+const REG_DAY_OF_MONTH: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0a;
+const REG_B: u8 = 0x0b;
+const REG_C: u8 = 0x0c;
+const REG_D: u8 = 0x0d;
+// Not part of the MC146818, but where PC firmware keeps it.
+const REG_CENTURY: u8 = 0x32;
+
+// Update in progress, never set as the time is computed whenever it is read.
+const REG_A_UIP: u8 = 0x80;
+// 32.768 kHz time base, 1024 Hz periodic rate, the usual BIOS setting.
+const REG_A_DEFAULT: u8 = 0x26;
+// The guest is setting the time, it stops counting until this bit is cleared.
+const REG_B_SET: u8 = 0x80;
+// The time is in binary rather than BCD.
+const REG_B_DM_BINARY: u8 = 0x04;
+// The hours go from 0 to 23 rather than from 1 to 12 with bit 7 set in the afternoon.
+const REG_B_24H: u8 = 0x02;
+const HOURS_PM: u8 = 0x80;
+// The RAM and time are valid, the battery being fine.
+const REG_D_VRT: u8 = 0x80;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+// Broken-down UTC time, as kept in the registers.
+#[derive(Debug, Eq, PartialEq)]
+struct DateTime {
+    year: u32,
+    month: u8,
+    day: u8,
+    day_of_week: u8,
+    hours: u8,
+    minutes: u8,
+    seconds: u8,
+}
+
+impl DateTime {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    fn from_timestamp(secs: i64) -> Self {
+        let days = secs.div_euclid(SECONDS_PER_DAY);
+        let secs_of_day = secs.rem_euclid(SECONDS_PER_DAY);
+
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        DateTime {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            // 1970-01-01 was a Thursday, Sunday being day 1.
+            day_of_week: ((days + 4).rem_euclid(7) + 1) as u8,
+            hours: (secs_of_day / 3600) as u8,
+            minutes: (secs_of_day / 60 % 60) as u8,
+            seconds: (secs_of_day % 60) as u8,
+        }
+    }
+
+    // See http://howardhinnant.github.io/date_algorithms.html#days_from_civil. The day of the
+    // week is ignored.
+    fn timestamp(&self) -> i64 {
+        let month = i64::from(self.month);
+        let year = i64::from(self.year) - i64::from(month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * mp + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days * SECONDS_PER_DAY
+            + i64::from(self.hours) * 3600
+            + i64::from(self.minutes) * 60
+            + i64::from(self.seconds)
+    }
+}
+
+/// A CMOS RTC whose clock starts counting from a given time.
+pub struct Cmos {
+    index: u8,
+    data: [u8; CMOS_SIZE],
+    // Time in nanoseconds since the Unix epoch the clock showed at `previous_now`.
+    tick_offset: i64,
+    previous_now: Instant,
+}
+
+impl Cmos {
+    /// Constructs a CMOS RTC that starts counting from `tick_offset` nanoseconds since the Unix
+    /// epoch.
+    pub fn new(tick_offset: i64) -> Self {
+        let mut data = [0; CMOS_SIZE];
+        data[REG_A as usize] = REG_A_DEFAULT;
+        data[REG_B as usize] = REG_B_24H;
+        data[REG_D as usize] = REG_D_VRT;
+        Cmos {
+            index: 0,
+            data,
+            tick_offset,
+            previous_now: Instant::now(),
+        }
+    }
+
+    /// Makes the clock count from `tick_offset` nanoseconds since the Unix epoch, from now on.
+    pub fn set_time(&mut self, tick_offset: i64) {
+        self.tick_offset = tick_offset;
+        self.previous_now = Instant::now();
+    }
+
+    fn timestamp(&self) -> i64 {
+        let elapsed = self.previous_now.elapsed().as_nanos() as i64;
+        self.tick_offset
+            .saturating_add(elapsed)
+            .div_euclid(NANOS_PER_SECOND as i64)
+    }
+
+    fn encode(&self, val: u8) -> u8 {
+        if self.data[REG_B as usize] & REG_B_DM_BINARY != 0 {
+            val
+        } else {
+            ((val / 10) << 4) | (val % 10)
+        }
+    }
+
+    fn decode(&self, val: u8) -> u8 {
+        if self.data[REG_B as usize] & REG_B_DM_BINARY != 0 {
+            val
+        } else {
+            (val >> 4) * 10 + (val & 0xf)
+        }
+    }
+
+    fn encode_hours(&self, hours: u8) -> u8 {
+        if self.data[REG_B as usize] & REG_B_24H != 0 {
+            return self.encode(hours);
+        }
+        let pm = if hours >= 12 { HOURS_PM } else { 0 };
+        match hours % 12 {
+            0 => self.encode(12) | pm,
+            hours => self.encode(hours) | pm,
+        }
+    }
+
+    fn decode_hours(&self, val: u8) -> u8 {
+        if self.data[REG_B as usize] & REG_B_24H != 0 {
+            return self.decode(val);
+        }
+        let hours = self.decode(val & !HOURS_PM) % 12;
+        if val & HOURS_PM != 0 {
+            hours + 12
+        } else {
+            hours
+        }
+    }
+
+    // Freezes the current time into the time registers.
+    fn latch_time(&mut self) {
+        let now = DateTime::from_timestamp(self.timestamp());
+        let registers = [
+            (REG_SECONDS, self.encode(now.seconds)),
+            (REG_MINUTES, self.encode(now.minutes)),
+            (REG_HOURS, self.encode_hours(now.hours)),
+            (REG_DAY_OF_WEEK, self.encode(now.day_of_week)),
+            (REG_DAY_OF_MONTH, self.encode(now.day)),
+            (REG_MONTH, self.encode(now.month)),
+            (REG_YEAR, self.encode((now.year % 100) as u8)),
+            (REG_CENTURY, self.encode((now.year / 100) as u8)),
+        ];
+        for (reg, val) in registers {
+            self.data[reg as usize] = val;
+        }
+    }
+
+    // Makes the clock count from the time in the time registers.
+    fn load_time(&mut self) {
+        let reg = |reg: u8| self.decode(self.data[reg as usize]);
+        let time = DateTime {
+            year: u32::from(reg(REG_CENTURY)) * 100 + u32::from(reg(REG_YEAR)),
+            month: reg(REG_MONTH),
+            day: reg(REG_DAY_OF_MONTH),
+            day_of_week: reg(REG_DAY_OF_WEEK),
+            hours: self.decode_hours(self.data[REG_HOURS as usize]),
+            minutes: reg(REG_MINUTES),
+            seconds: reg(REG_SECONDS),
+        };
+        let tick_offset = time.timestamp().saturating_mul(NANOS_PER_SECOND as i64);
+        self.set_time(tick_offset);
+    }
+
+    fn is_time_register(reg: u8) -> bool {
+        matches!(
+            reg,
+            REG_SECONDS
+                | REG_MINUTES
+                | REG_HOURS
+                | REG_DAY_OF_WEEK
+                | REG_DAY_OF_MONTH
+                | REG_MONTH
+                | REG_YEAR
+                | REG_CENTURY
+        )
+    }
+
+    fn read_register(&mut self, reg: u8) -> u8 {
+        if Self::is_time_register(reg) && self.data[REG_B as usize] & REG_B_SET == 0 {
+            self.latch_time();
+        }
+        match reg {
+            // No interrupt is ever pending.
+            REG_C => 0,
+            reg => self.data[reg as usize],
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, val: u8) {
+        let setting = self.data[REG_B as usize] & REG_B_SET != 0;
+        match reg {
+            REG_A => self.data[reg as usize] = val & !REG_A_UIP,
+            REG_B => {
+                self.data[reg as usize] = val;
+                match (setting, val & REG_B_SET != 0) {
+                    (false, true) => self.latch_time(),
+                    (true, false) => self.load_time(),
+                    _ => (),
+                }
+            }
+            REG_C | REG_D => (),
+            reg if Self::is_time_register(reg) && !setting => {
+                // Like the hardware, take the new value right away.
+                self.latch_time();
+                self.data[reg as usize] = val;
+                self.load_time();
+            }
+            reg => self.data[reg as usize] = val,
+        }
+    }
+}
+
+impl BusDevice for Cmos {
+    fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        // All our ports are byte-wide. We don't know how to handle any wider data.
+        if data.len() != 1 {
+            return;
+        }
+
+        data[0] = match offset {
+            OFS_INDEX => self.index,
+            OFS_DATA => self.read_register(self.index),
+            _ => 0,
+        };
+    }
+
+    fn write(&mut self, _vcpuid: u64, offset: u64, data: &[u8]) {
+        // All our ports are byte-wide. We don't know how to handle any wider data.
+        if data.len() != 1 {
+            return;
+        }
+
+        match offset {
+            OFS_INDEX => self.index = data[0] & INDEX_MASK,
+            OFS_DATA => self.write_register(self.index, data[0]),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-02-29 13:05:09 UTC, a Thursday.
+    const TIMESTAMP: i64 = 1_709_211_909;
+
+    fn read(cmos: &mut Cmos, reg: u8) -> u8 {
+        let mut data = [0];
+        cmos.write(0, OFS_INDEX, &[reg]);
+        cmos.read(0, OFS_DATA, &mut data);
+        data[0]
+    }
+
+    fn write(cmos: &mut Cmos, reg: u8, val: u8) {
+        cmos.write(0, OFS_INDEX, &[reg]);
+        cmos.write(0, OFS_DATA, &[val]);
+    }
+
+    #[test]
+    fn test_date_conversion() {
+        let time = DateTime::from_timestamp(TIMESTAMP);
+        assert_eq!(
+            time,
+            DateTime {
+                year: 2024,
+                month: 2,
+                day: 29,
+                day_of_week: 5,
+                hours: 13,
+                minutes: 5,
+                seconds: 9,
+            }
+        );
+        assert_eq!(time.timestamp(), TIMESTAMP);
+        assert_eq!(DateTime::from_timestamp(0).day_of_week, 5);
+        assert_eq!(DateTime::from_timestamp(-1).year, 1969);
+    }
+
+    #[test]
+    fn test_read_bcd() {
+        let mut cmos = Cmos::new(TIMESTAMP * NANOS_PER_SECOND as i64);
+        // The seconds may have ticked by now.
+        assert!((0x09..=0x10).contains(&read(&mut cmos, REG_SECONDS)));
+        assert_eq!(read(&mut cmos, REG_MINUTES), 0x05);
+        assert_eq!(read(&mut cmos, REG_HOURS), 0x13);
+        assert_eq!(read(&mut cmos, REG_DAY_OF_WEEK), 0x05);
+        assert_eq!(read(&mut cmos, REG_DAY_OF_MONTH), 0x29);
+        assert_eq!(read(&mut cmos, REG_MONTH), 0x02);
+        assert_eq!(read(&mut cmos, REG_YEAR), 0x24);
+        assert_eq!(read(&mut cmos, REG_CENTURY), 0x20);
+        assert_eq!(read(&mut cmos, REG_A) & REG_A_UIP, 0);
+        assert_eq!(read(&mut cmos, REG_D), REG_D_VRT);
+
+        // The NMI mask bit of the index doesn't select another register.
+        write(&mut cmos, REG_MONTH | 0x80, 0x02);
+        assert_eq!(read(&mut cmos, REG_MONTH | 0x80), 0x02);
+    }
+
+    #[test]
+    fn test_read_binary_12h() {
+        let mut cmos = Cmos::new(TIMESTAMP * NANOS_PER_SECOND as i64);
+        write(&mut cmos, REG_B, REG_B_DM_BINARY);
+        assert_eq!(read(&mut cmos, REG_HOURS), 1 | HOURS_PM);
+        assert_eq!(read(&mut cmos, REG_DAY_OF_MONTH), 29);
+        assert_eq!(read(&mut cmos, REG_YEAR), 24);
+
+        write(&mut cmos, REG_B, 0);
+        assert_eq!(read(&mut cmos, REG_HOURS), 0x01 | HOURS_PM);
+
+        cmos.set_time(0);
+        assert_eq!(read(&mut cmos, REG_HOURS), 0x12);
+    }
+
+    #[test]
+    fn test_set_time() {
+        let mut cmos = Cmos::new(0);
+        write(&mut cmos, REG_B, REG_B_SET | REG_B_24H);
+        for (reg, val) in [
+            (REG_SECONDS, 0x09),
+            (REG_MINUTES, 0x05),
+            (REG_HOURS, 0x13),
+            (REG_DAY_OF_MONTH, 0x29),
+            (REG_MONTH, 0x02),
+            (REG_YEAR, 0x24),
+            (REG_CENTURY, 0x20),
+        ] {
+            write(&mut cmos, reg, val);
+        }
+        // The clock doesn't count while it is being set.
+        assert_eq!(read(&mut cmos, REG_YEAR), 0x24);
+        write(&mut cmos, REG_B, REG_B_24H);
+
+        assert!((TIMESTAMP..TIMESTAMP + 2).contains(&cmos.timestamp()));
+        assert_eq!(read(&mut cmos, REG_DAY_OF_WEEK), 0x05);
+    }
+}
@@ -16,7 +16,10 @@ mod bus;
 pub mod legacy;
 pub mod virtio;
 
-pub use self::bus::{Bus, BusDevice, Error as BusError};
+pub use self::bus::{
+    Bus, BusAccess, BusAccessHandler, BusDevice, Error as BusError, HandlerDevice,
+    UnhandledAccessObserver,
+};
 
 #[derive(Debug)]
 pub enum Error {
@@ -6,11 +6,11 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use utils::eventfd::EventFd;
-use vm_memory::{ByteValued, GuestMemory, GuestMemoryMmap};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
 use super::super::{
-    ActivateError, ActivateResult, BalloonError, DeviceState, Queue as VirtQueue, VirtioDevice,
-    VIRTIO_MMIO_INT_VRING,
+    ActivateError, ActivateResult, BalloonError, DeviceSnapshot, DeviceState, MemoryReclaimer,
+    Queue as VirtQueue, VirtioDevice, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
 };
 use super::{defs, defs::uapi};
 use crate::legacy::Gic;
@@ -27,6 +27,13 @@ pub(crate) const PHQ_INDEX: usize = 3;
 // Free page reporting queue.
 pub(crate) const FRQ_INDEX: usize = 4;
 
+// The guest always describes the balloon in 4 KiB pages, whatever its page size and the host's.
+const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+const VIRTIO_BALLOON_PAGE_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+
+// Offset of `actual`, the only field of the config space the guest writes.
+const ACTUAL_OFFSET: u64 = 4;
+
 // Supported features.
 pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64
     | 1 << uapi::VIRTIO_BALLOON_F_STATS_VQ as u64
@@ -61,6 +68,7 @@ pub struct Balloon {
     config: VirtioBalloonConfig,
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
+    reclaimer: MemoryReclaimer,
 }
 
 impl Balloon {
@@ -87,6 +95,7 @@ impl Balloon {
             config,
             intc: None,
             irq_line: None,
+            reclaimer: MemoryReclaimer::new(),
         })
     }
 
@@ -106,6 +115,48 @@ impl Balloon {
         self.intc = Some(intc);
     }
 
+    /// Releases the pages the guest gives up through `reclaimer`, which keeps the ones still in
+    /// use.
+    pub fn set_memory_reclaimer(&mut self, reclaimer: MemoryReclaimer) {
+        self.reclaimer = reclaimer;
+    }
+
+    /// Asks the guest to grow or shrink the balloon to `pages` 4 KiB pages. A guest without the
+    /// balloon driver loaded picks the target up once it probes the device.
+    pub fn set_target(&mut self, pages: u32) {
+        self.config.num_pages = pages;
+        if self.is_activated() {
+            if let Err(e) = self.signal_config_update() {
+                error!("balloon: failed to notify the new target: {:?}", e);
+            }
+        }
+    }
+
+    /// Returns the size of the balloon the host asked for, in 4 KiB pages.
+    pub fn target(&self) -> u32 {
+        self.config.num_pages
+    }
+
+    /// Returns the size of the balloon as last reported by the guest, in 4 KiB pages.
+    pub fn actual(&self) -> u32 {
+        self.config.actual
+    }
+
+    fn signal_config_update(&self) -> result::Result<(), DeviceError> {
+        debug!("balloon: raising config IRQ");
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
+        if let Some(intc) = &self.intc {
+            intc.lock().unwrap().set_irq(self.irq_line.unwrap());
+            Ok(())
+        } else {
+            self.interrupt_evt.write(1).map_err(|e| {
+                error!("Failed to signal config update: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+        }
+    }
+
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         debug!("balloon: raising IRQ");
         self.interrupt_status
@@ -121,6 +172,78 @@ impl Balloon {
         }
     }
 
+    /// Releases the pages the guest put in the balloon, given as arrays of 32-bit page frame
+    /// numbers, back to the host. They read as zeroes if the guest touches them again.
+    pub fn process_ifq(&mut self) -> bool {
+        debug!("balloon: process_ifq()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[IFQ_INDEX].pop(mem) {
+            let index = head.index;
+            // Contiguous pages are released together, so hosts with pages larger than 4 KiB can
+            // release the ones the guest fully inflated.
+            let mut runs = Vec::new();
+            let mut run: Option<(u64, u64)> = None;
+            for desc in head.into_iter().filter(|desc| !desc.is_write_only()) {
+                for i in 0..u64::from(desc.len) / 4 {
+                    let Ok(pfn) = mem.read_obj::<u32>(desc.addr.unchecked_add(i * 4)) else {
+                        error!("balloon: invalid page frame number address");
+                        break;
+                    };
+                    let addr = u64::from(pfn) << VIRTIO_BALLOON_PFN_SHIFT;
+                    run = match run {
+                        Some((start, end)) if end == addr => {
+                            Some((start, end + VIRTIO_BALLOON_PAGE_SIZE))
+                        }
+                        Some((start, end)) => {
+                            runs.push(GuestAddress(start)..GuestAddress(end));
+                            Some((addr, addr + VIRTIO_BALLOON_PAGE_SIZE))
+                        }
+                        None => Some((addr, addr + VIRTIO_BALLOON_PAGE_SIZE)),
+                    };
+                }
+            }
+            if let Some((start, end)) = run {
+                runs.push(GuestAddress(start)..GuestAddress(end));
+            }
+            self.reclaimer.reclaim(mem, &runs);
+
+            have_used = true;
+            if let Err(e) = self.queues[IFQ_INDEX].add_used(mem, index, 0) {
+                error!("failed to add used elements to the queue: {:?}", e);
+            }
+        }
+
+        have_used
+    }
+
+    /// Acknowledges the pages the guest took back from the balloon, which the host faults in
+    /// again on their first access.
+    pub fn process_dfq(&mut self) -> bool {
+        debug!("balloon: process_dfq()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+        while let Some(head) = self.queues[DFQ_INDEX].pop(mem) {
+            have_used = true;
+            if let Err(e) = self.queues[DFQ_INDEX].add_used(mem, head.index, 0) {
+                error!("failed to add used elements to the queue: {:?}", e);
+            }
+        }
+
+        have_used
+    }
+
     pub fn process_frq(&mut self) -> bool {
         debug!("balloon: process_frq()");
         let mem = match self.device_state {
@@ -133,20 +256,14 @@ impl Balloon {
 
         while let Some(head) = self.queues[FRQ_INDEX].pop(mem) {
             let index = head.index;
-            for desc in head.into_iter() {
-                let host_addr = mem.get_host_address(desc.addr).unwrap();
-                debug!(
-                    "balloon: should release guest_addr={:?} host_addr={:p} len={}",
-                    desc.addr, host_addr, desc.len
-                );
-                unsafe {
-                    libc::madvise(
-                        host_addr as *mut libc::c_void,
-                        desc.len.try_into().unwrap(),
-                        libc::MADV_DONTNEED,
-                    )
-                };
-            }
+            let ranges: Vec<_> = head
+                .into_iter()
+                .filter_map(|desc| {
+                    let end = desc.addr.checked_add(u64::from(desc.len))?;
+                    Some(desc.addr..end)
+                })
+                .collect();
+            self.reclaimer.reclaim(mem, &ranges);
 
             have_used = true;
             if let Err(e) = self.queues[FRQ_INDEX].add_used(mem, index, 0) {
@@ -214,11 +331,19 @@ impl VirtioDevice for Balloon {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        warn!(
-            "balloon: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        // The guest reports the size of the balloon in `actual`, the rest is read-only.
+        let actual_end = ACTUAL_OFFSET + std::mem::size_of::<u32>() as u64;
+        if offset < ACTUAL_OFFSET || offset + data.len() as u64 > actual_end {
+            warn!(
+                "balloon: guest driver attempted to write device config (offset={:x}, len={:x})",
+                offset,
+                data.len()
+            );
+            return;
+        }
+        self.config.as_mut_slice()[offset as usize..offset as usize + data.len()]
+            .copy_from_slice(data);
+        debug!("balloon: the guest reports {} pages", self.actual());
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
@@ -247,4 +372,74 @@ impl VirtioDevice for Balloon {
             DeviceState::Activated(_) => true,
         }
     }
+
+    /// The queues are processed on the event loop, so they're up to date. The size of the
+    /// balloon reported by the guest is saved along with them, the target is the host's to set.
+    fn snapshot(&self) -> Option<DeviceSnapshot> {
+        Some(DeviceSnapshot {
+            queues: self.queues.iter().map(VirtQueue::state).collect(),
+            data: self.actual().to_le_bytes().to_vec(),
+        })
+    }
+
+    fn restore_snapshot(&mut self, data: &[u8]) -> bool {
+        let Ok(actual) = data.try_into() else {
+            return false;
+        };
+        self.config.actual = u32::from_le_bytes(actual);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::queue::tests::VirtQueue as GuestQ;
+
+    #[test]
+    fn test_config() {
+        let mut balloon = Balloon::new().unwrap();
+        balloon.set_target(256);
+        assert_eq!(balloon.target(), 256);
+
+        balloon.write_config(ACTUAL_OFFSET, &128u32.to_le_bytes());
+        assert_eq!(balloon.actual(), 128);
+
+        // The target is the host's to set.
+        balloon.write_config(0, &[0; 8]);
+        assert_eq!(balloon.target(), 256);
+        assert_eq!(balloon.actual(), 128);
+
+        let mut config = [0u8; 8];
+        balloon.read_config(0, &mut config);
+        assert_eq!(config, [0, 1, 0, 0, 128, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_inflate() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let vq = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut balloon = Balloon::new().unwrap();
+        balloon.queues[IFQ_INDEX] = vq.create_queue();
+        balloon.device_state = DeviceState::Activated(mem.clone());
+
+        mem.write_slice(&[0xaa; 0x3000], GuestAddress(0x10000))
+            .unwrap();
+        // The guest gives up pages 0x10 and 0x11, keeping 0x12.
+        mem.write_obj(0x10u32, GuestAddress(0x1000)).unwrap();
+        mem.write_obj(0x11u32, GuestAddress(0x1004)).unwrap();
+        vq.dtable[0].set(0x1000, 8, 0, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        assert!(balloon.process_ifq());
+        assert_eq!(vq.used.idx.get(), 1);
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        if page_size == VIRTIO_BALLOON_PAGE_SIZE {
+            assert_eq!(mem.read_obj::<u8>(GuestAddress(0x10000)).unwrap(), 0);
+            assert_eq!(mem.read_obj::<u8>(GuestAddress(0x11fff)).unwrap(), 0);
+        }
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x12000)).unwrap(), 0xaa);
+    }
 }
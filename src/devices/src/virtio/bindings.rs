@@ -21,6 +21,7 @@ pub const LINUX_O_TRUNC: libc::c_int = 512;
 pub const LINUX_O_RSYNC: libc::c_int = 1052672;
 pub const LINUX_O_DSYNC: libc::c_int = 4096;
 pub const LINUX_O_ASYNC: libc::c_int = 0x2000;
+pub const LINUX_O_NOATIME: libc::c_int = 0x40000;
 
 pub const LINUX_RENAME_NOREPLACE: libc::c_int = 1 << 0;
 pub const LINUX_RENAME_EXCHANGE: libc::c_int = 1 << 1;
@@ -39,8 +40,21 @@ pub type off64_t = libc::off_t;
 #[cfg(target_os = "linux")]
 pub use libc::off64_t;
 
+/// `statvfs` with 64-bit counters. The block and inode counts of macOS' own `statvfs` are only 32
+/// bits wide and get clamped on large volumes, so this is filled in from `statfs` instead.
 #[cfg(target_os = "macos")]
-pub type statvfs64 = libc::statvfs;
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct statvfs64 {
+    pub f_bsize: u64,
+    pub f_frsize: u64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_namemax: u64,
+}
 #[cfg(target_os = "linux")]
 pub use libc::statvfs64;
 
@@ -181,9 +195,30 @@ pub unsafe fn lseek64(fd: libc::c_int, offset: off64_t, whence: libc::c_int) ->
     libc::lseek(fd, offset, whence)
 }
 
+#[cfg(target_os = "macos")]
+fn statvfs64_from_statfs(st: &libc::statfs, namemax: libc::c_long) -> statvfs64 {
+    statvfs64 {
+        // Like statvfs(3) on macOS, report the optimal transfer size as the block size.
+        f_bsize: st.f_iosize as u64,
+        f_frsize: st.f_bsize as u64,
+        f_blocks: st.f_blocks,
+        f_bfree: st.f_bfree,
+        f_bavail: st.f_bavail,
+        f_files: st.f_files,
+        f_ffree: st.f_ffree,
+        f_namemax: if namemax > 0 { namemax as u64 } else { 255 },
+    }
+}
+
 #[cfg(target_os = "macos")]
 pub unsafe fn statvfs64(path: *const libc::c_char, buf: *mut statvfs64) -> libc::c_int {
-    libc::statvfs(path, buf)
+    let mut st = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+    let res = libc::statfs(path, st.as_mut_ptr());
+    if res == 0 {
+        let namemax = libc::pathconf(path, libc::_PC_NAME_MAX);
+        *buf = statvfs64_from_statfs(&st.assume_init(), namemax);
+    }
+    res
 }
 
 #[cfg(target_os = "linux")]
@@ -192,7 +227,13 @@ pub unsafe fn fstatvfs64(fd: libc::c_int, buf: *mut statvfs64) -> libc::c_int {
 }
 #[cfg(target_os = "macos")]
 pub unsafe fn fstatvfs64(fd: libc::c_int, buf: *mut statvfs64) -> libc::c_int {
-    libc::fstatvfs(fd, buf)
+    let mut st = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+    let res = libc::fstatfs(fd, st.as_mut_ptr());
+    if res == 0 {
+        let namemax = libc::fpathconf(fd, libc::_PC_NAME_MAX);
+        *buf = statvfs64_from_statfs(&st.assume_init(), namemax);
+    }
+    res
 }
 
 #[cfg(target_os = "linux")]
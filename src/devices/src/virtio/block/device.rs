@@ -18,17 +18,23 @@ use std::result;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use log::{error, warn};
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
 use virtio_bindings::{
-    virtio_blk::*, virtio_config::VIRTIO_F_VERSION_1, virtio_ring::VIRTIO_RING_F_EVENT_IDX,
+    virtio_blk::*,
+    virtio_config::{VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1},
+    virtio_ring::VIRTIO_RING_F_EVENT_IDX,
 };
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::worker::BlockWorker;
 use super::{
-    super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK},
+    super::{
+        ActivateResult, DeviceState, Queue, QuiesceGate, VirtioDevice, VmmQuiesceObserver,
+        TYPE_BLOCK,
+    },
     Error, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
 };
 
@@ -51,6 +57,7 @@ pub enum CacheType {
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
+    read_only: bool,
     pub(crate) file: File,
     nsectors: u64,
     image_id: Vec<u8>,
@@ -80,6 +87,7 @@ impl DiskProperties {
 
         Ok(Self {
             cache_type,
+            read_only: is_disk_read_only,
             nsectors: disk_size >> SECTOR_SHIFT,
             image_id: Self::build_disk_image_id(&disk_image),
             file: disk_image,
@@ -130,6 +138,10 @@ impl DiskProperties {
     pub fn cache_type(&self) -> CacheType {
         self.cache_type
     }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
 impl Drop for DiskProperties {
@@ -172,6 +184,8 @@ pub struct Block {
     is_disk_read_only: bool,
     worker_thread: Option<JoinHandle<()>>,
     worker_stopfd: EventFd,
+    // Keeps the worker from processing the queue while the device is quiesced.
+    quiesce_gate: QuiesceGate,
 
     // Virtio fields.
     pub(crate) avail_features: u64,
@@ -211,7 +225,8 @@ impl Block {
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
             | (1u64 << VIRTIO_BLK_F_FLUSH)
             | (1u64 << VIRTIO_BLK_F_SEG_MAX)
-            | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_F_RING_PACKED);
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
@@ -247,6 +262,7 @@ impl Block {
             irq_line: None,
             worker_thread: None,
             worker_stopfd: EventFd::new(EFD_NONBLOCK)?,
+            quiesce_gate: QuiesceGate::default(),
         })
     }
 
@@ -344,6 +360,8 @@ impl VirtioDevice for Block {
 
         let event_idx: bool = (self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX)) != 0;
         self.queues[0].set_event_idx(event_idx);
+        let packed: bool = (self.acked_features & (1 << VIRTIO_F_RING_PACKED)) != 0;
+        self.queues[0].set_packed(packed);
 
         let disk = match self.disk.take() {
             Some(d) => d,
@@ -365,6 +383,7 @@ impl VirtioDevice for Block {
             mem.clone(),
             disk,
             self.worker_stopfd.try_clone().unwrap(),
+            self.quiesce_gate.clone(),
         );
         self.worker_thread = Some(worker.run());
 
@@ -373,6 +392,8 @@ impl VirtioDevice for Block {
     }
 
     fn reset(&mut self) -> bool {
+        // The worker waiting for the device to be resumed would never see its stop event.
+        self.quiesce_gate.open();
         if let Some(worker) = self.worker_thread.take() {
             let _ = self.worker_stopfd.write(1);
             if let Err(e) = worker.join() {
@@ -383,3 +404,14 @@ impl VirtioDevice for Block {
         true
     }
 }
+
+impl VmmQuiesceObserver for Block {
+    /// Waits for the worker to be done with the requests it took off the queue.
+    fn on_quiesce(&mut self, timeout: Duration) -> bool {
+        self.quiesce_gate.close(timeout)
+    }
+
+    fn on_resume(&mut self) {
+        self.quiesce_gate.open();
+    }
+}
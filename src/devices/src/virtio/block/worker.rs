@@ -2,7 +2,7 @@ use crate::legacy::Gic;
 use crate::virtio::descriptor_utils::{Reader, Writer};
 use crate::Error as DeviceError;
 
-use super::super::{Queue, VIRTIO_MMIO_INT_VRING};
+use super::super::{Queue, QuiesceGate, VIRTIO_MMIO_INT_VRING};
 use super::device::{CacheType, DiskProperties};
 
 use std::io::{self, Write};
@@ -22,6 +22,7 @@ pub enum RequestError {
     InvalidDataLength,
     ReadingFromDescriptor(io::Error),
     WritingToDescriptor(io::Error),
+    WriteToReadOnlyDisk,
     UnknownRequest,
 }
 
@@ -56,6 +57,7 @@ pub struct BlockWorker {
     mem: GuestMemoryMmap,
     disk: DiskProperties,
     stop_fd: EventFd,
+    quiesce_gate: QuiesceGate,
 }
 
 impl BlockWorker {
@@ -70,6 +72,7 @@ impl BlockWorker {
         mem: GuestMemoryMmap,
         disk: DiskProperties,
         stop_fd: EventFd,
+        quiesce_gate: QuiesceGate,
     ) -> Self {
         Self {
             queue,
@@ -82,6 +85,7 @@ impl BlockWorker {
             mem,
             disk,
             stop_fd,
+            quiesce_gate,
         }
     }
 
@@ -116,6 +120,8 @@ impl BlockWorker {
                         let event_set = event.event_set();
                         match event_set {
                             EventSet::IN if source == virtq_ev_fd => {
+                                let gate = self.quiesce_gate.clone();
+                                let _busy = gate.enter();
                                 self.process_queue_event();
                             }
                             EventSet::IN if source == stop_ev_fd => {
@@ -228,6 +234,9 @@ impl BlockWorker {
                         .map_err(RequestError::WritingToDescriptor)
                 }
             }
+            // The guest is told the disk is read-only with VIRTIO_BLK_F_RO, the spec has writes
+            // fail anyway.
+            VIRTIO_BLK_T_OUT if self.disk.is_read_only() => Err(RequestError::WriteToReadOnlyDisk),
             VIRTIO_BLK_T_OUT => {
                 let data_len = reader.available_bytes();
                 if data_len % 512 != 0 {
@@ -277,3 +286,129 @@ impl BlockWorker {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::queue::tests::VirtQueue;
+    use crate::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use std::time::{Duration, Instant};
+    use utils::eventfd::EFD_NONBLOCK;
+    use utils::tempfile::TempFile;
+    use vm_memory::{Bytes, GuestAddress};
+
+    // Queues a one sector request of `request_type` on a new queue in `mem`: header, data and
+    // status descriptors, the data one being writable for reads. Returns the queue along with
+    // `read_only` disk properties for a new 8 sector image.
+    fn queue_sector_request(
+        mem: &GuestMemoryMmap,
+        request_type: u32,
+        read_only: bool,
+    ) -> (VirtQueue<'_>, TempFile, DiskProperties) {
+        let vq = VirtQueue::new(GuestAddress(0), mem, 16);
+        let header = RequestHeader {
+            request_type,
+            _reserved: 0,
+            sector: 0,
+        };
+        mem.write_obj(header, GuestAddress(0x1000)).unwrap();
+        let data_flags = if request_type == VIRTIO_BLK_T_IN {
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE
+        } else {
+            VIRTQ_DESC_F_NEXT
+        };
+        vq.dtable[0].set(
+            0x1000,
+            std::mem::size_of::<RequestHeader>() as u32,
+            VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        vq.dtable[1].set(0x2000, 512, data_flags, 2);
+        vq.dtable[2].set(0x3000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(0x1000).unwrap();
+        let disk = DiskProperties::new(
+            image.as_path().to_str().unwrap().to_string(),
+            read_only,
+            CacheType::Unsafe,
+        )
+        .unwrap();
+        (vq, image, disk)
+    }
+
+    #[test]
+    fn test_quiesce() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (vq, _image, disk) = queue_sector_request(&mem, VIRTIO_BLK_T_IN, false);
+
+        let queue_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let stop_fd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let gate = QuiesceGate::default();
+        let worker = BlockWorker::new(
+            vq.create_queue(),
+            queue_evt.try_clone().unwrap(),
+            Arc::new(AtomicUsize::new(0)),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            None,
+            None,
+            mem.clone(),
+            disk,
+            stop_fd.try_clone().unwrap(),
+            gate.clone(),
+        )
+        .run();
+
+        // The request the guest queues while the device is quiesced waits for it to be resumed.
+        assert!(gate.close(Duration::from_secs(5)));
+        queue_evt.write(1).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(vq.used.idx.get(), 0);
+
+        gate.open();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while vq.used.idx.get() == 0 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        stop_fd.write(1).unwrap();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_to_read_only_disk() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xaa; 512], GuestAddress(0x2000)).unwrap();
+        let (vq, image, disk) = queue_sector_request(&mem, VIRTIO_BLK_T_OUT, true);
+
+        let mut worker = BlockWorker::new(
+            vq.create_queue(),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            Arc::new(AtomicUsize::new(0)),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            None,
+            None,
+            mem.clone(),
+            disk,
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            QuiesceGate::default(),
+        );
+        worker.process_queue(&mem);
+
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+        let mut contents = Vec::new();
+        std::io::Read::read_to_end(&mut image.as_file(), &mut contents).unwrap();
+        assert!(contents.iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use vm_memory::VolatileSlice;
+
+use crate::virtio::console::port_io::{PortInput, PortOutput};
+
+#[derive(Default)]
+struct Buffers {
+    to_guest: VecDeque<u8>,
+    from_guest: VecDeque<u8>,
+}
+
+struct Shared {
+    capacity: usize,
+    buffers: Mutex<Buffers>,
+    // Notified whenever either buffer is drained or filled.
+    cond: Condvar,
+    // Wakes the rx thread of the port up when the host queued data for the guest.
+    readable: EventFd,
+}
+
+/// Bidirectional byte stream between the host and a virtio-console port of the guest.
+///
+/// Use `input` and `output` as the ones of a `PortDescription::DuplexPipe`. Each direction is
+/// buffered in a ring of `capacity` bytes: `write` waits for the guest to drain it when it's
+/// full, and the guest waits for the host to `read` once it filled the other one.
+#[derive(Clone)]
+pub struct ConsoleChannel {
+    shared: Arc<Shared>,
+}
+
+impl ConsoleChannel {
+    pub fn new(capacity: usize) -> io::Result<Self> {
+        Ok(ConsoleChannel {
+            shared: Arc::new(Shared {
+                capacity: capacity.max(1),
+                buffers: Mutex::new(Buffers::default()),
+                cond: Condvar::new(),
+                readable: EventFd::new(EFD_NONBLOCK)?,
+            }),
+        })
+    }
+
+    /// Reads what the guest wrote into `buf`, waiting up to `timeout` for it to write anything.
+    /// Returns the number of bytes read, 0 on timeout.
+    pub fn read(&self, buf: &mut [u8], timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut buffers = self.shared.buffers.lock().unwrap();
+        loop {
+            if !buffers.from_guest.is_empty() || buf.is_empty() {
+                let len = buf.len().min(buffers.from_guest.len());
+                for (dst, src) in buf.iter_mut().zip(buffers.from_guest.drain(..len)) {
+                    *dst = src;
+                }
+                self.shared.cond.notify_all();
+                return len;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return 0;
+            }
+            buffers = self
+                .shared
+                .cond
+                .wait_timeout(buffers, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Queues `data` for the guest, waiting up to `timeout` for it to drain the buffer whenever
+    /// it's full. Returns the number of bytes queued, less than `data.len()` on timeout.
+    pub fn write(&self, data: &[u8], timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut buffers = self.shared.buffers.lock().unwrap();
+        let mut written = 0;
+        while written < data.len() {
+            let space = self.shared.capacity - buffers.to_guest.len();
+            if space > 0 {
+                let len = space.min(data.len() - written);
+                buffers.to_guest.extend(&data[written..written + len]);
+                written += len;
+                // Wakes the rx thread up if it's waiting in `wait_until_readable`.
+                if let Err(e) = self.shared.readable.write(1) {
+                    log::error!("Failed to signal the console channel: {e}");
+                }
+                continue;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            buffers = self
+                .shared
+                .cond
+                .wait_timeout(buffers, deadline - now)
+                .unwrap()
+                .0;
+        }
+        written
+    }
+
+    /// Returns the guest-bound side of the channel, to use as the input of a port.
+    pub fn input(&self) -> Box<dyn PortInput + Send> {
+        Box::new(ChannelInput(self.shared.clone()))
+    }
+
+    /// Returns the host-bound side of the channel, to use as the output of a port.
+    pub fn output(&self) -> Box<dyn PortOutput + Send> {
+        Box::new(ChannelOutput(self.shared.clone()))
+    }
+}
+
+struct ChannelInput(Arc<Shared>);
+
+impl PortInput for ChannelInput {
+    fn read_volatile(&mut self, buf: &mut VolatileSlice) -> io::Result<usize> {
+        // Cleared before looking at the buffer, so a write racing with this read still wakes
+        // the next `wait_until_readable` up.
+        let _ = self.0.readable.read();
+        let mut buffers = self.0.buffers.lock().unwrap();
+        if buffers.to_guest.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(buffers.to_guest.len());
+        let data: Vec<u8> = buffers.to_guest.drain(..len).collect();
+        buf.copy_from(&data);
+        self.0.cond.notify_all();
+        Ok(len)
+    }
+
+    fn wait_until_readable(&self, stopfd: Option<&EventFd>) {
+        let mut poll_fds = Vec::new();
+        poll_fds.push(PollFd::new(self.0.readable.as_raw_fd(), PollFlags::POLLIN));
+        if let Some(stopfd) = stopfd {
+            poll_fds.push(PollFd::new(stopfd.as_raw_fd(), PollFlags::POLLIN));
+        }
+        poll(&mut poll_fds, -1).expect("Failed to poll");
+    }
+}
+
+struct ChannelOutput(Arc<Shared>);
+
+impl PortOutput for ChannelOutput {
+    fn write_volatile(&mut self, buf: &VolatileSlice) -> io::Result<usize> {
+        let mut buffers = self.0.buffers.lock().unwrap();
+        let space = self.0.capacity - buffers.from_guest.len();
+        if space == 0 {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let mut data = vec![0u8; space.min(buf.len())];
+        let len = buf.copy_to(&mut data[..]);
+        buffers.from_guest.extend(&data[..len]);
+        self.0.cond.notify_all();
+        Ok(len)
+    }
+
+    fn wait_until_writable(&self) {
+        let buffers = self.0.buffers.lock().unwrap();
+        let _buffers = self
+            .0
+            .cond
+            .wait_while(buffers, |buffers| {
+                buffers.from_guest.len() == self.0.capacity
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn test_to_guest() {
+        let channel = ConsoleChannel::new(4).unwrap();
+        let mut input = channel.input();
+        let mut mem = [0u8; 8];
+
+        assert_eq!(
+            input
+                .read_volatile(&mut VolatileSlice::from(&mut mem[..]))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::WouldBlock
+        );
+        // Only what fits in the buffer is queued before the timeout.
+        assert_eq!(channel.write(b"hello", TIMEOUT), 4);
+        assert_eq!(
+            input
+                .read_volatile(&mut VolatileSlice::from(&mut mem[..]))
+                .unwrap(),
+            4
+        );
+        assert_eq!(&mem[..4], b"hell");
+    }
+
+    #[test]
+    fn test_from_guest() {
+        let channel = ConsoleChannel::new(4).unwrap();
+        let mut output = channel.output();
+        let mut mem = *b"world";
+
+        assert_eq!(
+            output
+                .write_volatile(&VolatileSlice::from(&mut mem[..]))
+                .unwrap(),
+            4
+        );
+        assert_eq!(
+            output
+                .write_volatile(&VolatileSlice::from(&mut mem[4..]))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::WouldBlock
+        );
+
+        let mut buf = [0u8; 8];
+        assert_eq!(channel.read(&mut buf, TIMEOUT), 4);
+        assert_eq!(&buf[..4], b"worl");
+        assert_eq!(channel.read(&mut buf, TIMEOUT), 0);
+    }
+
+    #[test]
+    fn test_write_waits_for_guest() {
+        let channel = ConsoleChannel::new(2).unwrap();
+        let mut input = channel.input();
+        let handle = thread::spawn(move || {
+            let mut mem = [0u8; 4];
+            let mut received = Vec::new();
+            while received.len() < 4 {
+                input.wait_until_readable(None);
+                if let Ok(len) = input.read_volatile(&mut VolatileSlice::from(&mut mem[..])) {
+                    received.extend_from_slice(&mem[..len]);
+                }
+            }
+            received
+        });
+
+        assert_eq!(channel.write(b"data", Duration::from_secs(10)), 4);
+        assert_eq!(handle.join().unwrap(), b"data");
+    }
+}
@@ -5,13 +5,15 @@ use std::mem::{size_of, size_of_val};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use libc::TIOCGWINSZ;
 use utils::eventfd::EventFd;
 use vm_memory::{ByteValued, Bytes, GuestMemoryMmap};
 
 use super::super::{
-    ActivateError, ActivateResult, ConsoleError, DeviceState, Queue as VirtQueue, VirtioDevice,
+    ActivateError, ActivateResult, ConsoleError, DeviceSnapshot, DeviceState, Queue as VirtQueue,
+    QuiesceGate, VirtioDevice, VmmQuiesceObserver,
 };
 use super::{defs, defs::control_event, defs::uapi};
 use crate::legacy::Gic;
@@ -89,6 +91,11 @@ pub struct Console {
     pub(crate) activate_evt: EventFd,
     pub(crate) sigwinch_evt: EventFd,
 
+    // Keeps the io threads of the ports from using buffers while the device is quiesced, when the
+    // control queues aren't processed either.
+    quiesce_gate: QuiesceGate,
+    pub(crate) quiesced: bool,
+
     config: VirtioConsoleConfig,
 }
 
@@ -128,6 +135,8 @@ impl Console {
             sigwinch_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
                 .map_err(ConsoleError::EventFd)?,
             device_state: DeviceState::Inactive,
+            quiesce_gate: QuiesceGate::default(),
+            quiesced: false,
             config,
         })
     }
@@ -275,18 +284,26 @@ impl Console {
         }
 
         for port_id in ports_to_start {
-            log::trace!("Starting port io for port {}", port_id);
-            self.ports[port_id].start(
-                mem.clone(),
-                self.queues[port_id_to_queue_idx(QueueDirection::Rx, port_id)].clone(),
-                self.queues[port_id_to_queue_idx(QueueDirection::Tx, port_id)].clone(),
-                self.irq.clone(),
-                self.control.clone(),
-            );
+            self.start_port(port_id);
         }
 
         raise_irq
     }
+
+    fn start_port(&mut self, port_id: usize) {
+        let DeviceState::Activated(ref mem) = self.device_state else {
+            unreachable!()
+        };
+        log::trace!("Starting port io for port {}", port_id);
+        self.ports[port_id].start(
+            mem.clone(),
+            self.queues[port_id_to_queue_idx(QueueDirection::Rx, port_id)].clone(),
+            self.queues[port_id_to_queue_idx(QueueDirection::Tx, port_id)].clone(),
+            self.irq.clone(),
+            self.control.clone(),
+            self.quiesce_gate.clone(),
+        );
+    }
 }
 
 impl VirtioDevice for Console {
@@ -370,12 +387,60 @@ impl VirtioDevice for Console {
         }
     }
 
+    /// The io threads of the ports work on their own copies of the queues, which would be stale
+    /// here. While the device is quiesced they don't use the buffers they take off a queue though,
+    /// so the position of each queue is the one of its used ring: the buffers taken but not used
+    /// yet are taken again once the snapshot is restored, which writes the output they hold to the
+    /// host again. Control messages not delivered to the guest yet aren't part of the snapshot.
+    fn snapshot(&self) -> Option<DeviceSnapshot> {
+        let DeviceState::Activated(ref mem) = self.device_state else {
+            return None;
+        };
+        let queues = self
+            .queues
+            .iter()
+            .map(|queue| {
+                let mut state = queue.state();
+                if queue.ready {
+                    let used_idx = queue.used_idx(mem).ok()?;
+                    state.next_avail = used_idx;
+                    state.next_used = used_idx;
+                }
+                Some(state)
+            })
+            .collect::<Option<_>>()?;
+        let data = zip(0u32.., &self.ports)
+            .filter(|(_, port)| port.is_started())
+            .flat_map(|(port_id, _)| port_id.to_le_bytes())
+            .collect();
+        Some(DeviceSnapshot { queues, data })
+    }
+
+    fn restore_snapshot(&mut self, data: &[u8]) -> bool {
+        if !data.len().is_multiple_of(4) {
+            return false;
+        }
+        let port_ids: Vec<usize> = data
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()) as usize)
+            .collect();
+        if port_ids.iter().any(|&port_id| port_id >= self.ports.len()) {
+            return false;
+        }
+        for port_id in port_ids {
+            self.start_port(port_id);
+        }
+        true
+    }
+
     fn reset(&mut self) -> bool {
         // Strictly speaking, we should also unsubscribe the queue
         // events, resubscribe the activate eventfd and deactivate
         // the device, but we don't support any scenario in which
         // neither GuestMemory nor the queue events would change,
         // so let's avoid doing any unnecessary work.
+        // The io threads waiting for the device to be resumed would never see their stop event.
+        self.quiesce_gate.open();
         for port in &mut self.ports {
             port.shutdown();
         }
@@ -383,6 +448,28 @@ impl VirtioDevice for Console {
     }
 }
 
+impl VmmQuiesceObserver for Console {
+    /// Waits for the io threads of the ports to be done with the buffers they're reading to or
+    /// writing from, and stops delivering control messages.
+    fn on_quiesce(&mut self, timeout: Duration) -> bool {
+        self.quiesced = true;
+        self.quiesce_gate.close(timeout)
+    }
+
+    fn on_resume(&mut self) {
+        self.quiesced = false;
+        self.quiesce_gate.open();
+        // Catch up with the control messages the guest sent or the ports queued meanwhile.
+        if self.is_activated() {
+            let mut raise_irq = self.process_control_tx();
+            raise_irq |= self.process_control_rx();
+            if raise_irq {
+                self.irq.signal_used_queue("resume");
+            }
+        }
+    }
+}
+
 impl VmmExitObserver for Console {
     fn on_vmm_exit(&mut self) {
         self.reset();
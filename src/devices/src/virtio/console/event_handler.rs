@@ -118,12 +118,14 @@ impl Subscriber for Console {
         if self.is_activated() {
             let mut raise_irq = false;
 
+            // While quiesced the control queues are left alone, they're processed on resume.
             if source == control_txq {
-                raise_irq |=
-                    self.read_queue_event(CONTROL_TXQ_INDEX, event) && self.process_control_tx()
+                raise_irq |= self.read_queue_event(CONTROL_TXQ_INDEX, event)
+                    && !self.quiesced
+                    && self.process_control_tx()
             } else if source == control_rxq_control {
                 self.read_control_queue_event(event);
-                raise_irq |= self.process_control_rx();
+                raise_irq |= !self.quiesced && self.process_control_rx();
             } else if source == control_rxq {
                 raise_irq |= self.read_queue_event(CONTROL_RXQ_INDEX, event)
             }
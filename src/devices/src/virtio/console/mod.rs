@@ -1,3 +1,4 @@
+mod channel;
 mod console_control;
 mod device;
 mod event_handler;
@@ -8,6 +9,7 @@ mod port_queue_mapping;
 mod process_rx;
 mod process_tx;
 
+pub use self::channel::ConsoleChannel;
 pub use self::defs::uapi::VIRTIO_ID_CONSOLE as TYPE_CONSOLE;
 pub use self::device::Console;
 pub use self::port::PortDescription;
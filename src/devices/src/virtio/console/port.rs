@@ -11,7 +11,7 @@ use crate::virtio::console::irq_signaler::IRQSignaler;
 use crate::virtio::console::port_io::{PortInput, PortOutput};
 use crate::virtio::console::process_rx::process_rx;
 use crate::virtio::console::process_tx::process_tx;
-use crate::virtio::Queue;
+use crate::virtio::{Queue, QuiesceGate};
 
 pub enum PortDescription {
     Console {
@@ -26,6 +26,11 @@ pub enum PortDescription {
         name: Cow<'static, str>,
         output: Box<dyn PortOutput + Send>,
     },
+    DuplexPipe {
+        name: Cow<'static, str>,
+        input: Box<dyn PortInput + Send>,
+        output: Box<dyn PortOutput + Send>,
+    },
 }
 
 enum PortState {
@@ -75,6 +80,18 @@ impl Port {
                 input: None,
                 output: Some(Arc::new(Mutex::new(output))),
             },
+            PortDescription::DuplexPipe {
+                name,
+                input,
+                output,
+            } => Self {
+                port_id,
+                name,
+                represents_console: false,
+                state: PortState::Inactive,
+                input: Some(Arc::new(Mutex::new(input))),
+                output: Some(Arc::new(Mutex::new(output))),
+            },
         }
     }
 
@@ -86,6 +103,11 @@ impl Port {
         self.represents_console
     }
 
+    /// Whether the guest opened the port, starting its io threads.
+    pub fn is_started(&self) -> bool {
+        matches!(self.state, PortState::Active { .. })
+    }
+
     pub fn notify_rx(&self) {
         if let PortState::Active {
             rx_thread: Some(handle),
@@ -113,6 +135,7 @@ impl Port {
         tx_queue: Queue,
         irq_signaler: IRQSignaler,
         control: Arc<ConsoleControl>,
+        quiesce_gate: QuiesceGate,
     ) {
         if let PortState::Active { .. } = &mut self.state {
             self.shutdown();
@@ -131,6 +154,7 @@ impl Port {
             let port_id = self.port_id;
             let stopfd = stopfd.try_clone().unwrap();
             let stop = stop.clone();
+            let quiesce_gate = quiesce_gate.clone();
             thread::spawn(move || {
                 process_rx(
                     mem,
@@ -141,13 +165,16 @@ impl Port {
                     port_id,
                     stopfd,
                     stop,
+                    quiesce_gate,
                 )
             })
         });
 
         let tx_thread = output.map(|output| {
             let stop = stop.clone();
-            thread::spawn(move || process_tx(mem, tx_queue, irq_signaler, output, stop))
+            thread::spawn(move || {
+                process_tx(mem, tx_queue, irq_signaler, output, stop, quiesce_gate)
+            })
         });
 
         self.state = PortState::Active {
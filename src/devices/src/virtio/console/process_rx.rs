@@ -7,7 +7,7 @@ use vm_memory::{GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegio
 use crate::virtio::console::console_control::ConsoleControl;
 use crate::virtio::console::irq_signaler::IRQSignaler;
 use crate::virtio::console::port_io::PortInput;
-use crate::virtio::{DescriptorChain, Queue};
+use crate::virtio::{DescriptorChain, Queue, QuiesceGate};
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn process_rx(
@@ -19,6 +19,7 @@ pub(crate) fn process_rx(
     port_id: u32,
     stopfd: utils::eventfd::EventFd,
     stop: Arc<AtomicBool>,
+    quiesce_gate: QuiesceGate,
 ) {
     let mem = &mem;
     let mut eof = false;
@@ -26,6 +27,8 @@ pub(crate) fn process_rx(
     let mut input = input.lock().unwrap();
     loop {
         let head = pop_head_blocking(&mut queue, mem, &irq);
+        // The buffer is only written and used while the device isn't quiesced.
+        let busy = quiesce_gate.enter();
 
         let head_index = head.index;
         let mut bytes_read = 0;
@@ -59,6 +62,7 @@ pub(crate) fn process_rx(
         } else if bytes_read == 0 {
             queue.undo_pop();
             irq.signal_used_queue("rx WouldBlock");
+            drop(busy);
             input.wait_until_readable(Some(&stopfd));
         }
 
@@ -6,7 +6,7 @@ use vm_memory::{GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegio
 
 use crate::virtio::console::irq_signaler::IRQSignaler;
 use crate::virtio::console::port_io::PortOutput;
-use crate::virtio::{DescriptorChain, Queue};
+use crate::virtio::{DescriptorChain, Queue, QuiesceGate};
 
 pub(crate) fn process_tx(
     mem: GuestMemoryMmap,
@@ -14,6 +14,7 @@ pub(crate) fn process_tx(
     irq: IRQSignaler,
     output: Arc<Mutex<Box<dyn PortOutput + Send>>>,
     stop: Arc<AtomicBool>,
+    quiesce_gate: QuiesceGate,
 ) {
     loop {
         let Some(head) = pop_head_blocking(&mut queue, &mem, &irq, &stop) else {
@@ -39,6 +40,9 @@ pub(crate) fn process_tx(
             }
         }
 
+        // Writing to the host may block, so only using the buffer waits for the device not to be
+        // quiesced: its output is written again if it's taken again after a snapshot is restored.
+        let _busy = quiesce_gate.enter();
         if bytes_written == 0 {
             log::trace!("Tx Add used {bytes_written}");
             queue.undo_pop();
@@ -21,13 +21,16 @@ use super::file_traits::{FileReadWriteAtVolatile, FileReadWriteVolatile};
 
 #[derive(Debug)]
 pub enum Error {
+    ChainTooLong(usize),
     DescriptorChainOverflow,
     FindMemoryRegion,
     GuestMemoryError(GuestMemoryError),
     InvalidChain,
     IoError(io::Error),
+    OverlappingDescriptors(u16, u16),
     SplitOutOfBounds(usize),
     VolatileMemoryError(VolatileMemoryError),
+    ZeroLengthDescriptor(u16),
 }
 
 impl Display for Error {
@@ -35,6 +38,7 @@ impl Display for Error {
         use self::Error::*;
 
         match self {
+            ChainTooLong(max) => write!(f, "`DescriptorChain` has more than {max} descriptors"),
             DescriptorChainOverflow => write!(
                 f,
                 "the combined length of all the buffers in a `DescriptorChain` would overflow"
@@ -43,8 +47,12 @@ impl Display for Error {
             GuestMemoryError(e) => write!(f, "descriptor guest memory error: {e}"),
             InvalidChain => write!(f, "invalid descriptor chain"),
             IoError(e) => write!(f, "descriptor I/O error: {e}"),
+            OverlappingDescriptors(a, b) => {
+                write!(f, "the buffers of descriptors {a} and {b} overlap")
+            }
             SplitOutOfBounds(off) => write!(f, "`DescriptorChain` split is out of bounds: {off}"),
             VolatileMemoryError(e) => write!(f, "volatile memory error: {e}"),
+            ZeroLengthDescriptor(index) => write!(f, "descriptor {index} has a zero length"),
         }
     }
 }
@@ -53,6 +61,51 @@ pub type Result<T> = result::Result<T, Error>;
 
 impl std::error::Error for Error {}
 
+/// How strictly a device checks the descriptor chains supplied by the guest.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ChainValidation {
+    /// Only reject chains that can't be mapped at all, tolerating anything else.
+    #[default]
+    Lenient,
+    /// Also reject chains that a well-behaved driver never produces: chains with zero-length
+    /// descriptors, with buffers overlapping each other, or with more than `max_descriptors`
+    /// descriptors.
+    Strict { max_descriptors: usize },
+}
+
+impl ChainValidation {
+    /// Checks `chain` according to this policy. Lenient validation always succeeds, the
+    /// remaining checks happen when the chain is mapped by `Reader::new` and `Writer::new`.
+    pub fn validate(&self, chain: &DescriptorChain) -> Result<()> {
+        let max_descriptors = match self {
+            ChainValidation::Lenient => return Ok(()),
+            ChainValidation::Strict { max_descriptors } => *max_descriptors,
+        };
+
+        let mut ranges = Vec::new();
+        for desc in chain.clone().into_iter() {
+            if ranges.len() == max_descriptors {
+                return Err(Error::ChainTooLong(max_descriptors));
+            }
+            if desc.len == 0 {
+                return Err(Error::ZeroLengthDescriptor(desc.index));
+            }
+            ranges.push((desc.addr.raw_value(), desc.len, desc.index));
+        }
+
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            let (addr, len, index) = pair[0];
+            let (next_addr, _, next_index) = pair[1];
+            if addr.saturating_add(u64::from(len)) > next_addr {
+                return Err(Error::OverlappingDescriptors(index, next_index));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 struct DescriptorChainConsumer<'a> {
     buffers: VecDeque<VolatileSlice<'a>>,
@@ -981,4 +1034,71 @@ mod tests {
             48
         );
     }
+
+    #[test]
+    fn chain_validation() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let memory = GuestMemoryMmap::from_ranges(&[(memory_start_addr, 0x10000)]).unwrap();
+        let strict = ChainValidation::Strict { max_descriptors: 4 };
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Readable, 16), (Writable, 16)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        assert!(strict.validate(&chain).is_ok());
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Readable, 16), (Readable, 0), (Writable, 16)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        assert!(ChainValidation::Lenient.validate(&chain).is_ok());
+        assert!(matches!(
+            strict.validate(&chain),
+            Err(Error::ZeroLengthDescriptor(1))
+        ));
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Readable, 16); 5],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        assert!(matches!(
+            strict.validate(&chain),
+            Err(Error::ChainTooLong(4))
+        ));
+
+        // Point the writable descriptor into the middle of the readable one.
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Readable, 16), (Writable, 16)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        memory
+            .write_obj(
+                Le64::from(0x108),
+                GuestAddress(size_of::<virtq_desc>() as u64),
+            )
+            .unwrap();
+        assert!(ChainValidation::Lenient.validate(&chain).is_ok());
+        assert!(matches!(
+            strict.validate(&chain),
+            Err(Error::OverlappingDescriptors(0, 1))
+        ));
+    }
 }
@@ -5,9 +5,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::sync::{atomic::AtomicUsize, Arc, Condvar, Mutex};
+use std::time::Duration;
 
-use super::{ActivateResult, Queue};
+use super::{ActivateResult, Queue, QueueState};
 use crate::virtio::AsAny;
 use utils::eventfd::EventFd;
 use vm_memory::GuestMemoryMmap;
@@ -26,6 +27,15 @@ pub struct VirtioShmRegion {
     pub size: usize,
 }
 
+/// State of a device saved in a snapshot of the microVM, besides the one of its transport.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceSnapshot {
+    /// Configuration and position of each queue, in the order of `VirtioDevice::queues`.
+    pub queues: Vec<QueueState>,
+    /// State specific to the device type, opaque to the transport.
+    pub data: Vec<u8>,
+}
+
 /// Trait for virtio devices to be driven by a virtio transport.
 ///
 /// The lifecycle of a virtio device is to be moved to a virtio transport, which will then query the
@@ -123,6 +133,18 @@ pub trait VirtioDevice: AsAny + Send {
     fn shm_region(&self) -> Option<&VirtioShmRegion> {
         None
     }
+
+    /// Returns the state of the activated device to save in a snapshot of the microVM, or `None`
+    /// if it can't be snapshotted. Only called with the vCPUs paused and the device quiesced.
+    fn snapshot(&self) -> Option<DeviceSnapshot> {
+        None
+    }
+
+    /// Restores the `data` of a snapshot taken by `snapshot`, once the device was activated again
+    /// with the queues of the snapshot. Returns whether it succeeded.
+    fn restore_snapshot(&mut self, _data: &[u8]) -> bool {
+        false
+    }
 }
 
 pub trait VmmExitObserver: Send {
@@ -136,8 +158,118 @@ impl<F: Fn() + Send> VmmExitObserver for F {
     }
 }
 
+/// Lets the VMM stop a device from processing its queues, e.g. to take a consistent snapshot.
+pub trait VmmQuiesceObserver: Send {
+    /// Stops processing requests, returning once the ones in flight are completed and the device
+    /// is idle, or with `false` if some still weren't after `timeout`. The device stays stopped
+    /// until `on_resume` either way.
+    fn on_quiesce(&mut self, timeout: Duration) -> bool;
+    /// Processes the requests queued since `on_quiesce` and the ones that follow.
+    fn on_resume(&mut self);
+}
+
+#[derive(Default)]
+struct QuiesceState {
+    closed: bool,
+    busy: usize,
+}
+
+/// Shared by a device and its worker threads, which are only let through between requests while
+/// the device is quiesced.
+#[derive(Clone, Default)]
+pub struct QuiesceGate(Arc<(Mutex<QuiesceState>, Condvar)>);
+
+impl QuiesceGate {
+    /// Waits for the device not to be quiesced, then counts the caller as busy until the returned
+    /// guard is dropped.
+    pub fn enter(&self) -> QuiesceGuard<'_> {
+        let (state, cond) = &*self.0;
+        let mut state = cond
+            .wait_while(state.lock().unwrap(), |state| state.closed)
+            .unwrap();
+        state.busy += 1;
+        QuiesceGuard(self)
+    }
+
+    /// Keeps new callers of `enter` waiting, and waits up to `timeout` for the busy ones to be
+    /// done, returning whether they were. The gate stays closed either way.
+    pub fn close(&self, timeout: Duration) -> bool {
+        let (state, cond) = &*self.0;
+        let mut state = state.lock().unwrap();
+        state.closed = true;
+        let (_state, result) = cond
+            .wait_timeout_while(state, timeout, |state| state.busy > 0)
+            .unwrap();
+        !result.timed_out()
+    }
+
+    /// Lets the callers of `enter` through again.
+    pub fn open(&self) {
+        let (state, cond) = &*self.0;
+        state.lock().unwrap().closed = false;
+        cond.notify_all();
+    }
+}
+
+/// Returned by `QuiesceGate::enter`, for as long as the caller is busy.
+pub struct QuiesceGuard<'a>(&'a QuiesceGate);
+
+impl Drop for QuiesceGuard<'_> {
+    fn drop(&mut self) {
+        let (state, cond) = &*self.0 .0;
+        state.lock().unwrap().busy -= 1;
+        cond.notify_all();
+    }
+}
+
 impl std::fmt::Debug for dyn VirtioDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "VirtioDevice type {}", self.device_type())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_quiesce_gate() {
+        let gate = QuiesceGate::default();
+        let done = Arc::new(AtomicBool::new(false));
+
+        // A request in flight keeps `close` waiting, up to its timeout.
+        let busy = gate.enter();
+        assert!(!gate.close(Duration::from_millis(10)));
+        let closer = {
+            let gate = gate.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                assert!(gate.close(Duration::from_secs(5)));
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!done.load(Ordering::SeqCst));
+        drop(busy);
+        closer.join().unwrap();
+        assert!(done.load(Ordering::SeqCst));
+
+        // New requests wait for the gate to be opened again.
+        done.store(false, Ordering::SeqCst);
+        let worker = {
+            let gate = gate.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let _busy = gate.enter();
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!done.load(Ordering::SeqCst));
+        gate.open();
+        worker.join().unwrap();
+        assert!(done.load(Ordering::SeqCst));
+    }
+}
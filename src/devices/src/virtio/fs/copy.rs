@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copies between host files for `FUSE_COPY_FILE_RANGE` when the host can't offload them.
+
+use std::fs::File;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// Copies up to `len` bytes from `offset_in` in `fd_in` to `offset_out` in `fd_out` through a
+/// buffer, stopping early at the end of `fd_in`. Returns the number of bytes copied, failing only
+/// if none were. Like `copy_file_range`, fails with `EBADF` if `fd_out` is opened with `O_APPEND`,
+/// which would make the writes ignore `offset_out`. Errors are the host's, the callers translate
+/// them for the guest.
+pub fn copy_range(
+    fd_in: RawFd,
+    offset_in: u64,
+    fd_out: RawFd,
+    offset_out: u64,
+    len: usize,
+) -> io::Result<usize> {
+    const BUF_SIZE: usize = 128 << 10;
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let flags = unsafe { libc::fcntl(fd_out, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if flags & libc::O_APPEND != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EBADF));
+    }
+
+    // Safe because the fds stay open for the duration of the call, which only borrows them.
+    let file_in = ManuallyDrop::new(unsafe { File::from_raw_fd(fd_in) });
+    let file_out = ManuallyDrop::new(unsafe { File::from_raw_fd(fd_out) });
+    let mut buf = vec![0u8; len.min(BUF_SIZE)];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = (len - copied).min(buf.len());
+        let res = file_in
+            .read_at(&mut buf[..chunk], offset_in + copied as u64)
+            .and_then(|read| {
+                file_out
+                    .write_all_at(&buf[..read], offset_out + copied as u64)
+                    .map(|()| read)
+            });
+        match res {
+            Ok(0) => break,
+            Ok(read) => copied += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if copied == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(copied)
+}
@@ -1,18 +1,33 @@
 use std::cmp;
 use std::io::Write;
+use std::num::NonZeroUsize;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::Duration;
 
+#[cfg(target_os = "macos")]
+use crossbeam_channel::Sender;
+#[cfg(target_os = "macos")]
+use hvf::MemoryMapping;
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
-use virtio_bindings::{virtio_config::VIRTIO_F_VERSION_1, virtio_ring::VIRTIO_RING_F_EVENT_IDX};
+#[cfg(target_os = "linux")]
+use utils::seccomp::SeccompFilter;
+use virtio_bindings::{
+    virtio_config::{VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1},
+    virtio_ring::VIRTIO_RING_F_EVENT_IDX,
+};
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::super::{
-    ActivateResult, DeviceState, FsError, Queue as VirtQueue, VirtioDevice, VirtioShmRegion,
+    ActivateError, ActivateResult, ChainValidation, DeviceSnapshot, DeviceState, FsError,
+    Queue as VirtQueue, QueueState, QuiesceGate, VirtioDevice, VirtioShmRegion, VmmQuiesceObserver,
 };
-use super::passthrough;
-use super::worker::FsWorker;
+use super::filesystem::{SpecialFilePolicy, XattrMapping};
+use super::passthrough::{self, CachePolicy, PassthroughFs};
+use super::server::{FsOpPolicy, Server};
+use super::stats::{FsStats, LatencyHistogram};
+use super::worker::{FsWorker, RequestLimit};
 use super::{defs, defs::uapi};
 use crate::legacy::Gic;
 
@@ -45,10 +60,23 @@ pub struct Fs {
     irq_line: Option<u32>,
     device_state: DeviceState,
     config: VirtioFsConfig,
+    shm_size: Option<usize>,
     shm_region: Option<VirtioShmRegion>,
+    #[cfg(target_os = "macos")]
+    map_sender: Option<Sender<MemoryMapping>>,
     passthrough_cfg: passthrough::Config,
-    worker_thread: Option<JoinHandle<()>>,
+    op_policy: FsOpPolicy,
+    chain_validation: ChainValidation,
+    stats: Option<Arc<FsStats>>,
+    worker_threads: Vec<JoinHandle<()>>,
     worker_stopfd: EventFd,
+    quiesce_gate: QuiesceGate,
+    request_limit: RequestLimit,
+    #[cfg(target_os = "linux")]
+    seccomp_filter: Option<SeccompFilter>,
+    // The server and the state of the queues as last published by the workers, while activated.
+    server: Option<Arc<Server<PassthroughFs>>>,
+    queue_states: Arc<Mutex<Vec<QueueState>>>,
 }
 
 impl Fs {
@@ -63,12 +91,14 @@ impl Fs {
                 .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(FsError::EventFd)?);
         }
 
-        let avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        let avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_F_RING_PACKED);
 
         let tag = fs_id.into_bytes();
         let mut config = VirtioFsConfig::default();
         config.tag[..tag.len()].copy_from_slice(tag.as_slice());
-        config.num_request_queues = 1;
+        config.num_request_queues = (queues.len() - defs::REQ_INDEX) as u32;
 
         let fs_cfg = passthrough::Config {
             root_dir: shared_dir,
@@ -86,32 +116,200 @@ impl Fs {
             irq_line: None,
             device_state: DeviceState::Inactive,
             config,
+            shm_size: None,
             shm_region: None,
+            #[cfg(target_os = "macos")]
+            map_sender: None,
             passthrough_cfg: fs_cfg,
-            worker_thread: None,
+            op_policy: FsOpPolicy::default(),
+            chain_validation: ChainValidation::default(),
+            stats: None,
+            worker_threads: Vec::new(),
             worker_stopfd: EventFd::new(EFD_NONBLOCK).map_err(FsError::EventFd)?,
+            quiesce_gate: QuiesceGate::default(),
+            request_limit: RequestLimit::default(),
+            #[cfg(target_os = "linux")]
+            seccomp_filter: None,
+            server: None,
+            queue_states: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
     pub fn new(fs_id: String, shared_dir: String) -> super::Result<Fs> {
-        let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
-            .iter()
-            .map(|&max_size| VirtQueue::new(max_size))
+        Self::with_request_queues(
+            fs_id,
+            shared_dir,
+            defs::NUM_REQUEST_QUEUES,
+            defs::QUEUE_SIZE,
+        )
+    }
+
+    /// Creates a device with `num_request_queues` request queues of `queue_size` entries, letting
+    /// the guest spread its requests across them. Each request queue, and the high priority queue,
+    /// is serviced by its own worker thread.
+    pub fn with_request_queues(
+        fs_id: String,
+        shared_dir: String,
+        num_request_queues: usize,
+        queue_size: u16,
+    ) -> super::Result<Fs> {
+        if num_request_queues == 0 || num_request_queues > defs::MAX_REQUEST_QUEUES {
+            return Err(FsError::InvalidRequestQueues(num_request_queues));
+        }
+        if !queue_size.is_power_of_two() {
+            return Err(FsError::InvalidQueueSize(queue_size));
+        }
+
+        let queues = (0..defs::REQ_INDEX + num_request_queues)
+            .map(|_| VirtQueue::new(queue_size))
             .collect();
         Self::with_queues(fs_id, shared_dir, queues)
     }
 
+    /// Sets whether host mount points below the shared directory are announced to the guest as
+    /// submounts.
+    pub fn set_announce_submounts(&mut self, enabled: bool) {
+        self.passthrough_cfg.announce_submounts = enabled;
+    }
+
+    /// Sets the host-side prefix guest `security.` extended attributes are stored under.
+    pub fn set_security_xattr_prefix(&mut self, prefix: Option<String>) {
+        self.passthrough_cfg.security_xattr_prefix = prefix;
+    }
+
+    /// Sets the rules renaming the extended attributes between the guest and the host.
+    pub fn set_xattr_map(&mut self, map: Vec<XattrMapping>) {
+        self.passthrough_cfg.xattr_map = map;
+    }
+
+    /// Sets the ioctl request numbers the guest may pass through to files on the host.
+    pub fn set_allowed_ioctls(&mut self, ioctls: Vec<u32>) {
+        self.passthrough_cfg.allowed_ioctls = ioctls;
+    }
+
+    /// Sets how the device nodes in the shared directory are exposed to the guest.
+    pub fn set_device_policy(&mut self, policy: SpecialFilePolicy) {
+        self.passthrough_cfg.device_policy = policy;
+    }
+
+    /// Sets how the FIFOs and Unix sockets in the shared directory are exposed to the guest.
+    pub fn set_fifo_socket_policy(&mut self, policy: SpecialFilePolicy) {
+        self.passthrough_cfg.fifo_socket_policy = policy;
+    }
+
+    /// Makes the files be read and written bypassing the host page cache where possible.
+    pub fn set_direct_io(&mut self, enabled: bool) {
+        self.passthrough_cfg.direct_io = enabled;
+    }
+
+    /// Sets how the guest caches the shared files, along with how long it trusts the entries and
+    /// attributes it looked up: not at all with `CachePolicy::Never`, for 5 seconds with
+    /// `CachePolicy::Auto` and for a day with `CachePolicy::Always`, which also lets the guest
+    /// buffer writes in its page cache (`FUSE_WRITEBACK_CACHE`).
+    pub fn set_cache_policy(&mut self, policy: CachePolicy) {
+        let timeout = match policy {
+            CachePolicy::Never => Duration::ZERO,
+            CachePolicy::Auto => passthrough::Config::default().attr_timeout,
+            CachePolicy::Always => Duration::from_secs(24 * 60 * 60),
+        };
+        self.passthrough_cfg.cache_policy = policy;
+        self.passthrough_cfg.entry_timeout = timeout;
+        self.passthrough_cfg.attr_timeout = timeout;
+        self.passthrough_cfg.writeback = policy == CachePolicy::Always;
+    }
+
+    /// Limits the number of inodes the guest may hold references to at once.
+    #[cfg(target_os = "linux")]
+    pub fn set_max_inodes(&mut self, max: Option<NonZeroUsize>) {
+        self.passthrough_cfg.max_inodes = max;
+    }
+
+    /// Limits the number of files and directories the guest may have open at once.
+    pub fn set_max_handles(&mut self, max: Option<NonZeroUsize>) {
+        self.passthrough_cfg.max_handles = max;
+    }
+
+    /// Sets the seccomp filter the worker threads install before handling any request.
+    #[cfg(target_os = "linux")]
+    pub fn set_seccomp_filter(&mut self, filter: Option<SeccompFilter>) {
+        self.seccomp_filter = filter;
+    }
+
+    /// Sets the FUSE operations the guest is not allowed to perform on this share.
+    pub fn set_op_policy(&mut self, policy: FsOpPolicy) {
+        self.op_policy = policy;
+    }
+
+    /// Sets how strictly the descriptor chains of guest requests are checked. Requests failing
+    /// strict validation are answered with `EIO` instead of being processed.
+    pub fn set_chain_validation(&mut self, validation: ChainValidation) {
+        self.chain_validation = validation;
+    }
+
+    /// Bounds the number of requests handled at once across all the request queues, or `None`
+    /// to let each queue have one in flight. Requests past the limit wait in their queue.
+    pub fn set_max_in_flight_requests(&mut self, limit: Option<NonZeroUsize>) {
+        self.request_limit = RequestLimit::new(limit);
+    }
+
+    /// Records how long the requests take to be served, per opcode, see `stats`. This adds a
+    /// couple of clock reads to every request.
+    pub fn set_latency_stats(&mut self, enabled: bool) {
+        self.stats = enabled.then(|| Arc::new(FsStats::new()));
+    }
+
+    /// Returns the latency histograms of the requests served so far, by opcode, or `None` if
+    /// they aren't recorded.
+    pub fn stats(&self) -> Option<Vec<(u32, LatencyHistogram)>> {
+        self.stats.as_ref().map(|stats| stats.snapshot())
+    }
+
     pub fn id(&self) -> &str {
         defs::FS_DEV_ID
     }
 
+    /// Returns the host directory shared with the guest.
+    pub fn shared_dir(&self) -> &str {
+        &self.passthrough_cfg.root_dir
+    }
+
+    /// Returns the tag the guest mounts the share with.
+    pub fn tag(&self) -> &str {
+        let len = self
+            .config
+            .tag
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.config.tag.len());
+        std::str::from_utf8(&self.config.tag[..len]).unwrap_or_default()
+    }
+
     pub fn set_intc(&mut self, intc: Arc<Mutex<Gic>>) {
         self.intc = Some(intc);
     }
 
+    /// Sets the size of the DAX window the guest maps the shared files into, or `None` to have
+    /// it go through the queues for all file accesses.
+    pub fn set_shm_size(&mut self, size: Option<usize>) {
+        self.shm_size = size;
+    }
+
+    /// Returns the size of the DAX window the device needs, if any.
+    pub fn shm_size(&self) -> Option<usize> {
+        self.shm_size
+    }
+
+    /// Sets the DAX window, a `shm_size` bytes long region of guest memory reserved to the
+    /// device.
     pub fn set_shm_region(&mut self, shm_region: VirtioShmRegion) {
         self.shm_region = Some(shm_region);
     }
+
+    /// Sets the channel the file mappings of the DAX window are applied to the guest through.
+    #[cfg(target_os = "macos")]
+    pub fn set_map_sender(&mut self, map_sender: Sender<MemoryMapping>) {
+        self.map_sender = Some(map_sender);
+    }
 }
 
 impl VirtioDevice for Fs {
@@ -178,32 +376,60 @@ impl VirtioDevice for Fs {
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
-        if self.worker_thread.is_some() {
-            panic!("virtio_fs: worker thread already exists");
+        if !self.worker_threads.is_empty() {
+            panic!("virtio_fs: worker threads already exist");
         }
 
         let event_idx: bool = (self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX)) != 0;
-        self.queues[defs::HPQ_INDEX].set_event_idx(event_idx);
-        self.queues[defs::REQ_INDEX].set_event_idx(event_idx);
+        let packed: bool = (self.acked_features & (1 << VIRTIO_F_RING_PACKED)) != 0;
+        for queue in self.queues.iter_mut() {
+            queue.set_event_idx(event_idx);
+            queue.set_packed(packed);
+        }
 
-        let queue_evts = self
-            .queue_events
-            .iter()
-            .map(|e| e.try_clone().unwrap())
-            .collect();
-        let worker = FsWorker::new(
-            self.queues.clone(),
-            queue_evts,
-            self.interrupt_status.clone(),
-            self.interrupt_evt.try_clone().unwrap(),
-            self.intc.clone(),
-            self.irq_line,
-            mem.clone(),
-            self.passthrough_cfg.clone(),
-            self.worker_stopfd.try_clone().unwrap(),
-        );
-        self.worker_thread = Some(worker.run());
+        #[cfg(target_os = "macos")]
+        if let (Some(shm_region), Some(map_sender)) = (&self.shm_region, &self.map_sender) {
+            self.passthrough_cfg.dax_window = Some(passthrough::DaxWindow {
+                guest_addr: shm_region.guest_addr,
+                map_sender: map_sender.clone(),
+            });
+        }
+
+        let fs = PassthroughFs::new(self.passthrough_cfg.clone()).map_err(|e| {
+            error!(
+                "virtio_fs: failed to create the passthrough file system: {:?}",
+                e
+            );
+            ActivateError::BadActivate
+        })?;
+        let server = Arc::new(Server::new(fs, self.op_policy, self.stats.clone()));
+        *self.queue_states.lock().unwrap() = self.queues.iter().map(VirtQueue::state).collect();
+
+        // Each queue gets its own worker, so the high priority queue is still serviced while the
+        // request queues wait for the requests in flight to get below the limit.
+        for (index, queue) in self.queues.iter().enumerate() {
+            let worker = FsWorker::new(
+                vec![queue.clone()],
+                self.queue_states.clone(),
+                index,
+                vec![self.queue_events[index].try_clone().unwrap()],
+                self.interrupt_status.clone(),
+                self.interrupt_evt.try_clone().unwrap(),
+                self.intc.clone(),
+                self.irq_line,
+                mem.clone(),
+                server.clone(),
+                self.chain_validation,
+                self.quiesce_gate.clone(),
+                self.request_limit.clone(),
+                #[cfg(target_os = "linux")]
+                self.seccomp_filter.clone(),
+                self.worker_stopfd.try_clone().unwrap(),
+            );
+            self.worker_threads.push(worker.run());
+        }
 
+        self.server = Some(server);
         self.device_state = DeviceState::Activated(mem);
         Ok(())
     }
@@ -219,14 +445,166 @@ impl VirtioDevice for Fs {
         self.shm_region.as_ref()
     }
 
+    /// The FUSE options negotiated with the guest are saved along with the queues, and the inodes
+    /// and handles the guest holds, which are opened again on restore. The device can't be
+    /// snapshotted while the guest holds files that can't be, e.g. the ones unlinked on the host
+    /// it still has open. The mappings of the DAX window aren't saved.
+    fn snapshot(&self) -> Option<DeviceSnapshot> {
+        let server = self.server.as_ref()?;
+        let references = match server.save_references() {
+            Ok(references) => references,
+            Err(e) => {
+                warn!(
+                    "virtio_fs: the files the guest holds of {} can't be snapshotted: {}",
+                    self.tag(),
+                    e
+                );
+                return None;
+            }
+        };
+        let mut data = server.options().to_le_bytes().to_vec();
+        data.extend(references);
+        Some(DeviceSnapshot {
+            queues: self.queue_states.lock().unwrap().clone(),
+            data,
+        })
+    }
+
+    fn restore_snapshot(&mut self, data: &[u8]) -> bool {
+        let Some(server) = &self.server else {
+            return false;
+        };
+        let Some((options, references)) = data.split_first_chunk() else {
+            return false;
+        };
+        match server.restore_session(u64::from_le_bytes(*options), references) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("virtio_fs: failed to restore the FUSE session: {}", e);
+                false
+            }
+        }
+    }
+
     fn reset(&mut self) -> bool {
-        if let Some(worker) = self.worker_thread.take() {
+        if !self.worker_threads.is_empty() {
+            // Workers waiting for the device to be resumed would never see the stop event.
+            self.quiesce_gate.open();
             let _ = self.worker_stopfd.write(1);
-            if let Err(e) = worker.join() {
-                error!("error waiting for worker thread: {:?}", e);
+            for worker in self.worker_threads.drain(..) {
+                if let Err(e) = worker.join() {
+                    error!("error waiting for worker thread: {:?}", e);
+                }
             }
+            let _ = self.worker_stopfd.read();
         }
+        self.server = None;
         self.device_state = DeviceState::Inactive;
         true
     }
 }
+
+impl VmmQuiesceObserver for Fs {
+    /// Waits for the FUSE requests being handled to complete. The guest keeps waiting for the
+    /// ones it queues meanwhile, which are only handled once the device is resumed.
+    fn on_quiesce(&mut self, timeout: Duration) -> bool {
+        self.quiesce_gate.close(timeout)
+    }
+
+    fn on_resume(&mut self) {
+        self.quiesce_gate.open();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_os = "linux")]
+    use std::ffi::CString;
+    #[cfg(target_os = "linux")]
+    use utils::tempdir::TempDir;
+
+    #[cfg(target_os = "linux")]
+    use super::super::filesystem::{Context, Extensions, FileSystem, FsOptions, OpenOptions};
+    #[cfg(target_os = "linux")]
+    use super::super::fuse;
+
+    #[test]
+    fn test_request_queues() {
+        for num_request_queues in [1, 4, defs::MAX_REQUEST_QUEUES] {
+            let fs = Fs::with_request_queues(
+                "tag".to_string(),
+                "/".to_string(),
+                num_request_queues,
+                256,
+            )
+            .unwrap();
+            // The high priority queue comes first.
+            assert_eq!(fs.queues().len(), num_request_queues + 1);
+            assert_eq!(fs.queue_events().len(), num_request_queues + 1);
+            assert!(fs.queues().iter().all(|q| q.get_max_size() == 256));
+
+            let mut config = [0u8; 4];
+            fs.read_config(36, &mut config);
+            assert_eq!(u32::from_le_bytes(config), num_request_queues as u32);
+        }
+
+        for num_request_queues in [0, defs::MAX_REQUEST_QUEUES + 1] {
+            assert!(matches!(
+                Fs::with_request_queues("tag".to_string(), "/".to_string(), num_request_queues, 256),
+                Err(FsError::InvalidRequestQueues(n)) if n == num_request_queues
+            ));
+        }
+        assert!(matches!(
+            Fs::with_request_queues("tag".to_string(), "/".to_string(), 1, 100),
+            Err(FsError::InvalidQueueSize(100))
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_cache_policy() {
+        for (cache_policy, timeout, writeback, open_opts) in [
+            (CachePolicy::Never, 0, false, OpenOptions::DIRECT_IO),
+            (CachePolicy::Auto, 5, false, OpenOptions::empty()),
+            (
+                CachePolicy::Always,
+                24 * 60 * 60,
+                true,
+                OpenOptions::KEEP_CACHE,
+            ),
+        ] {
+            let dir = TempDir::new().unwrap();
+            let mut device = Fs::new(
+                "tag".to_string(),
+                dir.as_path().to_str().unwrap().to_string(),
+            )
+            .unwrap();
+            device.set_cache_policy(cache_policy);
+            let fs = PassthroughFs::new(device.passthrough_cfg.clone()).unwrap();
+            let opts = fs.init(FsOptions::WRITEBACK_CACHE).unwrap();
+            assert_eq!(opts.contains(FsOptions::WRITEBACK_CACHE), writeback);
+
+            let ctx = Context {
+                uid: unsafe { libc::geteuid() },
+                gid: unsafe { libc::getegid() },
+                pid: 0,
+            };
+            let (entry, _, _) = fs
+                .create(
+                    ctx,
+                    fuse::ROOT_ID,
+                    &CString::new("file").unwrap(),
+                    0o644,
+                    libc::O_WRONLY as u32,
+                    0,
+                    Extensions::default(),
+                )
+                .unwrap();
+            assert_eq!(entry.entry_timeout, Duration::from_secs(timeout));
+            assert_eq!(entry.attr_timeout, Duration::from_secs(timeout));
+            let (_, opts) = fs.open(ctx, entry.inode, libc::O_WRONLY as u32).unwrap();
+            assert_eq!(opts, open_opts);
+        }
+    }
+}
@@ -37,6 +37,9 @@ pub struct Entry {
     /// If this value is not correct, incorrect data will be returned.
     pub attr: bindings::stat64,
 
+    /// Flags for `attr`, such as `fuse::ATTR_SUBMOUNT`.
+    pub attr_flags: u32,
+
     /// How long the values in `attr` should be considered valid. If the attributes of the `Entry`
     /// are only modified by the FUSE client, then this should be set to a very large value.
     pub attr_timeout: Duration,
@@ -56,7 +59,10 @@ impl From<Entry> for fuse::EntryOut {
             attr_valid: entry.attr_timeout.as_secs(),
             entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
             attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
-            attr: entry.attr.into(),
+            attr: fuse::Attr {
+                flags: entry.attr_flags,
+                ..entry.attr.into()
+            },
         }
     }
 }
@@ -82,6 +88,53 @@ pub struct DirEntry<'a> {
     pub name: &'a [u8],
 }
 
+/// How a file system exposes special files found on the host (device nodes, sockets and FIFOs)
+/// to the guest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpecialFilePolicy {
+    /// The files are left out of directory listings and looking them up fails with `ENOENT`, as
+    /// if they didn't exist.
+    Skip,
+    /// The files are exposed like any other, as far as the file system supports them.
+    Passthrough,
+    /// The files are listed in directories, but looking them up fails with `EPERM`.
+    Error,
+}
+
+impl SpecialFilePolicy {
+    /// Returns the error a lookup of a file subject to this policy fails with, if any.
+    pub fn lookup_error(&self) -> Option<io::Error> {
+        match self {
+            SpecialFilePolicy::Skip => Some(io::Error::from_raw_os_error(libc::ENOENT)),
+            SpecialFilePolicy::Passthrough => None,
+            SpecialFilePolicy::Error => Some(io::Error::from_raw_os_error(libc::EPERM)),
+        }
+    }
+
+    /// Returns the error the guest creating a file subject to this policy fails with, if any.
+    /// Files it couldn't use once created aren't created at all.
+    pub fn create_error(&self) -> Option<io::Error> {
+        match self {
+            SpecialFilePolicy::Passthrough => None,
+            SpecialFilePolicy::Skip | SpecialFilePolicy::Error => {
+                Some(io::Error::from_raw_os_error(libc::EPERM))
+            }
+        }
+    }
+}
+
+/// Rule renaming the extended attributes of a file system: the ones whose name starts with
+/// `guest_prefix` in the guest are stored on the host with the name starting with `host_prefix`
+/// instead, e.g. to keep guest `security.` attributes out of the way of the host's LSM.
+///
+/// The host attributes under `host_prefix` are only reachable through the rule, and the guest
+/// doesn't see the host attributes under `guest_prefix`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct XattrMapping {
+    pub guest_prefix: String,
+    pub host_prefix: String,
+}
+
 /// A reply to a `getxattr` method call.
 pub enum GetxattrReply {
     /// The value of the requested extended attribute. This can be arbitrary textual or binary data
@@ -381,6 +434,22 @@ pub trait FileSystem {
     /// communicate with the kernel.
     fn destroy(&self) {}
 
+    /// Saves the references the guest holds to inodes other than the root directory's and to the
+    /// files it has open, for `restore_references` to pass them on to a new instance of the file
+    /// system, e.g. the one a snapshot of the microVM is restored into. Fails if some can't be.
+    fn save_references(&self) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    /// Takes over the references saved by `save_references`, once initialized.
+    fn restore_references(&self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EINVAL))
+        }
+    }
+
     /// Look up a directory entry by name and get its attributes.
     ///
     /// If this call is successful then the lookup count of the `Inode` associated with the returned
@@ -555,7 +624,10 @@ pub trait FileSystem {
     /// `libc::RENAME_NOREPLACE` is specified, the implementation must not overwrite `newname` if it
     /// exists and must return an error instead. If `libc::RENAME_EXCHANGE` is specified, the
     /// implementation must atomically exchange the two files, i.e., both must exist and neither may
-    /// be deleted.
+    /// be deleted. `libc::RENAME_WHITEOUT` may be combined with `libc::RENAME_NOREPLACE`, and
+    /// leaves a whiteout in place of `oldname`. Other flags are rejected before this is called.
+    /// Implementations should fail with `EINVAL` when the host doesn't support the flags, like
+    /// `renameat2` does.
     fn rename(
         &self,
         ctx: Context,
@@ -1135,6 +1207,14 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Perform an ioctl on an open file.
+    ///
+    /// `data` holds the `in_size` bytes the kernel copied from the caller's argument buffer, and
+    /// `out_size` is the number of bytes the kernel expects back. On success, the file system
+    /// should return the ioctl's result code along with at most `out_size` bytes of output data.
+    ///
+    /// Only restricted ioctls (those whose argument size is encoded in `cmd`) are forwarded here;
+    /// the server rejects `FUSE_IOCTL_UNRESTRICTED` requests before they reach the file system.
     #[allow(clippy::too_many_arguments)]
     fn ioctl(
         &self,
@@ -1144,9 +1224,9 @@ pub trait FileSystem {
         flags: u32,
         cmd: u32,
         arg: u64,
-        in_size: u32,
+        data: &[u8],
         out_size: u32,
-    ) -> io::Result<Vec<u8>> {
+    ) -> io::Result<(i32, Vec<u8>)> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
@@ -439,9 +439,17 @@ pub const RELEASE_FLOCK_UNLOCK: u32 = 2;
 // Getattr flags.
 pub const GETATTR_FH: u32 = 1;
 
+// Attr flags.
+/// The object is a submount root.
+pub const ATTR_SUBMOUNT: u32 = 1;
+
 // Lock flags.
 pub const LK_FLOCK: u32 = 1;
 
+// Fsync flags.
+/// Only the file data needs to be synced, not its metadata (`fdatasync`).
+pub const FSYNC_FDATASYNC: u32 = 1;
+
 // Write flags.
 
 /// Delayed write from page cache, file handle is guessed.
@@ -539,7 +547,7 @@ pub struct Attr {
     pub gid: u32,
     pub rdev: u32,
     pub blksize: u32,
-    pub padding: u32,
+    pub flags: u32,
 }
 unsafe impl ByteValued for Attr {}
 
@@ -610,11 +618,11 @@ impl From<bindings::statvfs64> for Kstatfs {
 impl From<bindings::statvfs64> for Kstatfs {
     fn from(st: bindings::statvfs64) -> Self {
         Kstatfs {
-            blocks: st.f_blocks as u64,
-            bfree: st.f_bfree as u64,
-            bavail: st.f_bavail as u64,
-            files: st.f_files as u64,
-            ffree: st.f_ffree as u64,
+            blocks: st.f_blocks,
+            bfree: st.f_bfree,
+            bavail: st.f_bavail,
+            files: st.f_files,
+            ffree: st.f_ffree,
             bsize: st.f_bsize as u32,
             namelen: st.f_namemax as u32,
             frsize: st.f_frsize as u32,
@@ -2,33 +2,50 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::borrow::Cow;
 use std::collections::btree_map;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
 use std::mem::{self, size_of, MaybeUninit};
+use std::num::NonZeroUsize;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::time::Duration;
 
 use vm_memory::ByteValued;
 
+use super::super::copy;
 use super::super::filesystem::{
     Context, DirEntry, Entry, Extensions, FileSystem, FsOptions, GetxattrReply, ListxattrReply,
-    OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    OpenOptions, SetattrValid, SpecialFilePolicy, XattrMapping, ZeroCopyReader, ZeroCopyWriter,
 };
 use super::super::fuse;
 use super::super::multikey::MultikeyBTreeMap;
+use super::fs_utils::einval;
 
 const CURRENT_DIR_CSTR: &[u8] = b".\0";
 const PARENT_DIR_CSTR: &[u8] = b"..\0";
 const EMPTY_CSTR: &[u8] = b"\0";
 const PROC_CSTR: &[u8] = b"/proc/self/fd\0";
 const INIT_CSTR: &[u8] = b"init.krun\0";
+const SECURITY_XATTR_PREFIX: &[u8] = b"security.";
+
+// Location of the argument size in an ioctl request number (asm-generic/ioctl.h).
+const IOC_SIZESHIFT: u32 = 16;
+const IOC_SIZEMASK: u32 = (1 << 14) - 1;
+
+// Size of the argument buffer passed to the ioctls that don't encode one in their request number,
+// which the host kernel may still read or write through.
+const IOCTL_SCRATCH_SIZE: usize = 4096;
+
+// Alignment of the offset and length of the requests served with `O_DIRECT`. The logical block
+// size of most devices, larger ones fail with `EINVAL` and are served buffered.
+const DIRECT_IO_ALIGN: u64 = 512;
 
 static INIT_BINARY: &[u8] = include_bytes!("../../../../../../init/init");
 
@@ -39,18 +56,146 @@ type Handle = u64;
 struct InodeAltKey {
     ino: libc::ino64_t,
     dev: libc::dev_t,
+    // Tells apart the same directory bind-mounted in several places, which the guest needs to see
+    // as distinct submounts.
+    mnt_id: u64,
 }
 
 struct InodeData {
     inode: Inode,
-    // Most of these aren't actually files but ¯\_(ツ)_/¯.
-    file: File,
+    // Most of these aren't actually files but ¯\_(ツ)_/¯. `None` while closed to stay within
+    // `Config::max_inodes`, see `PassthroughFs::inode_file`.
+    file: RwLock<Option<Arc<File>>>,
+    // The handle of the file and a file on the same host mount, to reopen it once closed. `None`
+    // for the inodes whose file is never closed.
+    reopen: Option<(FileHandle, Arc<File>)>,
+    // Set whenever the file is used, and cleared while looking for the files to close.
+    used: AtomicBool,
     refcount: AtomicU64,
+    mnt_id: u64,
+}
+
+impl InodeData {
+    fn new(
+        inode: Inode,
+        file: File,
+        reopen: Option<(FileHandle, Arc<File>)>,
+        refcount: u64,
+        mnt_id: u64,
+    ) -> Self {
+        InodeData {
+            inode,
+            file: RwLock::new(Some(Arc::new(file))),
+            reopen,
+            used: AtomicBool::new(true),
+            refcount: AtomicU64::new(refcount),
+            mnt_id,
+        }
+    }
+}
+
+// The largest file handle, `MAX_HANDLE_SZ` (linux/fcntl.h).
+const MAX_HANDLE_SZ: usize = 128;
+
+// A `struct file_handle` (fcntl.h), which identifies a file on its host file system.
+#[repr(C)]
+struct FileHandle {
+    handle_bytes: u32,
+    handle_type: libc::c_int,
+    f_handle: [u8; MAX_HANDLE_SZ],
+}
+
+impl FileHandle {
+    // Returns the handle of `f`, if its file system supports them.
+    fn from_file(f: &File) -> Option<FileHandle> {
+        let mut handle = FileHandle {
+            handle_bytes: MAX_HANDLE_SZ as u32,
+            handle_type: 0,
+            f_handle: [0; MAX_HANDLE_SZ],
+        };
+        let mut mount_id: libc::c_int = 0;
+        // Safe because the kernel will only write data in `handle` and `mount_id`, and we check
+        // the return value.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_name_to_handle_at,
+                f.as_raw_fd(),
+                EMPTY_CSTR.as_ptr(),
+                &mut handle,
+                &mut mount_id,
+                libc::AT_EMPTY_PATH,
+            )
+        };
+        (res == 0).then_some(handle)
+    }
+
+    // Opens the file with `O_PATH`, `mount_fd` being a file (not opened with `O_PATH`) on the same
+    // file system. This takes `CAP_DAC_READ_SEARCH`.
+    fn open(&self, mount_fd: &File) -> io::Result<File> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_open_by_handle_at,
+                mount_fd.as_raw_fd(),
+                self as *const FileHandle,
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we just opened this fd.
+        Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+    }
+}
+
+// Closes the files of the least recently used inodes in `open`, the ones whose file is open
+// oldest first, until there's room for one more without going over `max`. Returns whether there
+// is. This works like a clock: the inodes used since they were last looked at are passed over,
+// so each one is looked at twice at most.
+fn close_unused_files(open: &mut VecDeque<Weak<InodeData>>, max: usize) -> bool {
+    for _ in 0..2 * open.len() {
+        if open.len() < max {
+            break;
+        }
+        let Some(data) = open.pop_front().and_then(|data| data.upgrade()) else {
+            // Forgotten, its file closed with it.
+            continue;
+        };
+        let mut file = data.file.write().unwrap();
+        // Files unlinked on the host can't be reopened by handle.
+        let closable = data.reopen.is_some()
+            && !data.used.swap(false, Ordering::Relaxed)
+            && file
+                .as_deref()
+                .is_some_and(|f| stat(f).is_ok_and(|st| st.st_nlink > 0));
+        if closable {
+            *file = None;
+        } else {
+            drop(file);
+            open.push_back(Arc::downgrade(&data));
+        }
+    }
+    open.len() < max
 }
 
 struct HandleData {
     inode: Inode,
+    // The flags the guest opened the file with, to open it again when restoring a snapshot.
+    flags: u32,
     file: RwLock<File>,
+    // The same file opened with `O_DIRECT`, used for the reads and writes it can serve when
+    // `Config::direct_io` is set.
+    direct: Option<File>,
+}
+
+impl HandleData {
+    // Returns the `O_DIRECT` file if a request of `size` bytes at `offset` is aligned for it.
+    fn direct_for(&self, offset: u64, size: u32) -> Option<&File> {
+        let aligned = offset.is_multiple_of(DIRECT_IO_ALIGN)
+            && u64::from(size).is_multiple_of(DIRECT_IO_ALIGN);
+        self.direct.as_ref().filter(|_| aligned && size != 0)
+    }
 }
 
 #[repr(C, packed)]
@@ -129,6 +274,45 @@ fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
 
+// Splits the first `len` bytes off `data`, references saved by `save_references`.
+fn split_saved<'a>(data: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(einval());
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+fn saved_u32(data: &mut &[u8]) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(
+        split_saved(data, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn saved_u64(data: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(
+        split_saved(data, 8)?.try_into().unwrap(),
+    ))
+}
+
+// Opens the host `path` with `O_PATH`, not following it if it's a symlink.
+fn open_path(path: &CStr) -> io::Result<File> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe {
+        libc::openat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
 fn stat(f: &File) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
@@ -153,10 +337,50 @@ fn stat(f: &File) -> io::Result<libc::stat64> {
     }
 }
 
+// Returns the id of the host mount `f` lives on, or 0 on kernels too old to tell (before 5.8), on
+// which only the mounts of different devices are told apart.
+fn mount_id(f: &File) -> io::Result<u64> {
+    let mut stx = MaybeUninit::<libc::statx>::zeroed();
+
+    // Safe because this is a constant value and a valid C string.
+    let pathname = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+
+    // Safe because the kernel will only write data in `stx` and we check the return value.
+    let res = unsafe {
+        libc::statx(
+            f.as_raw_fd(),
+            pathname.as_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+            libc::STATX_MNT_ID,
+            stx.as_mut_ptr(),
+        )
+    };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOSYS) => Ok(0),
+            _ => Err(err),
+        };
+    }
+
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    let stx = unsafe { stx.assume_init() };
+    if stx.stx_mask & libc::STATX_MNT_ID != 0 {
+        Ok(stx.stx_mnt_id)
+    } else {
+        Ok(0)
+    }
+}
+
+// Converts the type of a directory entry (`DT_*`) to the matching mode bits, like `DTTOIF`.
+fn dirent_type_to_mode(d_type: u32) -> libc::mode_t {
+    d_type << 12
+}
+
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
 /// protocol uses close-to-open consistency. This means that any cached contents of the file are
 /// invalidated the next time that file is opened.
-#[derive(Default, Debug, Clone)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum CachePolicy {
     /// The client should never cache file data and all I/O should be directly forwarded to the
     /// server. This policy must be selected when file contents may change without the knowledge of
@@ -242,6 +466,89 @@ pub struct Config {
     ///
     /// The default is `None`.
     pub proc_sfd_rawfd: Option<RawFd>,
+
+    /// Whether the file system should announce submounts to the guest by setting
+    /// `FUSE_ATTR_SUBMOUNT` on directories that live on a different host mount than their parent,
+    /// including bind mounts of the same device. The guest then gives each of them its own
+    /// `st_dev`, so tools like `find -xdev` stop at the mount boundaries as they would on the host.
+    /// This only takes effect if the guest kernel advertises `FUSE_SUBMOUNTS`.
+    ///
+    /// The default value for this option is `false`.
+    pub announce_submounts: bool,
+
+    /// If set, extended attributes the guest sets in the `security.` namespace (e.g.
+    /// `security.selinux`) are stored on the host under this prefix instead (e.g.
+    /// `user.virtiofs.security.selinux`), so guest labels round-trip without the host's LSM
+    /// interfering. The host's own `security.` attributes are hidden from the guest, and the guest
+    /// can't access attributes under the prefix directly. Using a `trusted.` prefix additionally
+    /// hides the attributes from unprivileged host processes.
+    ///
+    /// The default is `None`.
+    pub security_xattr_prefix: Option<String>,
+
+    /// Rules renaming the extended attributes between the guest and the host, like virtiofsd's
+    /// `xattrmap` prefix rules. The first rule whose guest prefix matches an attribute name the
+    /// guest passes applies, names matching no rule are passed through unchanged unless they're
+    /// under the host prefix of a rule, which fails with `EPERM`. They are applied before
+    /// `security_xattr_prefix`.
+    ///
+    /// The default is empty.
+    pub xattr_map: Vec<XattrMapping>,
+
+    /// The ioctl request numbers the guest may issue on open files, as encoded by the guest (e.g.
+    /// `FS_IOC_GETFLAGS`). Each allowed ioctl is performed on the host fd backing the handle, with
+    /// the argument buffer the guest kernel sent along. Any other ioctl fails with `ENOTTY`.
+    /// Ioctls taking their argument by value aren't supported: the host kernel always gets a
+    /// pointer to a buffer, never the value the guest passed, which may be an address.
+    ///
+    /// Only ioctls whose argument is a plain buffer are safe to list here: the guest kernel issues
+    /// `FS_IOC_GETFLAGS`/`FS_IOC_SETFLAGS` and `FS_IOC_FSGETXATTR`/`FS_IOC_FSSETXATTR` this way for
+    /// `chattr`/`lsattr`. `FICLONE` and `FICLONERANGE` are always refused: their argument is a
+    /// guest file descriptor, and the guest VFS handles them without consulting FUSE anyway.
+    /// Copy-on-write copies still work through `copy_file_range`, which the host file system can
+    /// satisfy with a reflink.
+    ///
+    /// The default is empty.
+    pub allowed_ioctls: Vec<u32>,
+
+    /// How the device nodes (character and block devices) found in the root directory are exposed
+    /// to the guest. Passing them through lets the guest open the host devices they refer to.
+    /// The policy also applies to the nodes the guest creates itself with `mknod`, which fails
+    /// with `EPERM` unless they're passed through.
+    ///
+    /// The default is `SpecialFilePolicy::Skip`.
+    pub device_policy: SpecialFilePolicy,
+
+    /// How the FIFOs and Unix sockets found in the root directory are exposed to the guest.
+    ///
+    /// The default is `SpecialFilePolicy::Passthrough`.
+    pub fifo_socket_policy: SpecialFilePolicy,
+
+    /// Whether regular files are read and written with `O_DIRECT`, bypassing the host page cache
+    /// so that large sequential workloads aren't cached by both the host and the guest. Requests
+    /// whose offset, length or guest buffer isn't suitably aligned, and files on host file systems
+    /// without `O_DIRECT` support, are served through the page cache instead.
+    ///
+    /// The default value for this option is `false`.
+    pub direct_io: bool,
+
+    /// The maximum number of host file descriptors the inodes the guest holds references to keep
+    /// open at once. Past the limit the files of the least recently used inodes are closed, and
+    /// reopened by handle when the guest uses them again, which takes `CAP_DAC_READ_SEARCH` and a
+    /// host file system supporting file handles. The files of the inodes that can't be reopened,
+    /// such as the root directory or files unlinked on the host, stay open: once only those are
+    /// left, looking up a file the guest doesn't reference yet fails with `ENFILE` until it
+    /// forgets others. Each host mount the inodes live on also keeps one directory open.
+    ///
+    /// The default is `None`, which doesn't limit the inodes.
+    pub max_inodes: Option<NonZeroUsize>,
+
+    /// The maximum number of files and directories the guest may have open at once, each keeping
+    /// a host file descriptor open. Past the limit opening one more fails with `ENFILE` until the
+    /// guest releases others: reopening them would lose their offset and locks.
+    ///
+    /// The default is `None`, which doesn't limit the handles.
+    pub max_handles: Option<NonZeroUsize>,
 }
 
 impl Default for Config {
@@ -254,6 +561,15 @@ impl Default for Config {
             root_dir: String::from("/"),
             xattr: true,
             proc_sfd_rawfd: None,
+            announce_submounts: false,
+            security_xattr_prefix: None,
+            xattr_map: Vec::new(),
+            allowed_ioctls: Vec::new(),
+            device_policy: SpecialFilePolicy::Skip,
+            fifo_socket_policy: SpecialFilePolicy::Passthrough,
+            direct_io: false,
+            max_inodes: None,
+            max_handles: None,
         }
     }
 }
@@ -272,6 +588,15 @@ pub struct PassthroughFs {
     next_inode: AtomicU64,
     init_inode: u64,
 
+    // The inodes whose file is open, oldest first, tracked when `cfg.max_inodes` is set.
+    open_inodes: Mutex<VecDeque<Weak<InodeData>>>,
+    // A directory on each host mount, keyed by device and mount id, to reopen the files of the
+    // inodes on it by handle.
+    mount_fds: Mutex<HashMap<(u64, u64), Arc<File>>>,
+    // Whether the files of inodes can be closed and reopened by handle to stay within
+    // `cfg.max_inodes`, which `init` checks.
+    reopen_inodes: AtomicBool,
+
     // File descriptors for open files and directories. Unlike the fds in `inodes`, these _can_ be
     // used for reading and writing data.
     handles: RwLock<BTreeMap<Handle, Arc<HandleData>>>,
@@ -288,9 +613,38 @@ pub struct PassthroughFs {
     // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
 
+    // Whether submounts should be announced to the guest. This will only be true when
+    // `cfg.announce_submounts` is true and `init` was called with `FsOptions::SUBMOUNTS`.
+    announce_submounts: AtomicBool,
+
+    // Whether the host kernel has `renameat2`, which `init` checks. Renames with flags fail with
+    // `ENOSYS` without it, so that the guest stops sending them.
+    rename2_supported: AtomicBool,
+
+    // `cfg.xattr_map` followed by the rule `cfg.security_xattr_prefix` amounts to.
+    xattr_rules: Vec<XattrRule>,
+
     cfg: Config,
 }
 
+// How the names of extended attributes are mapped, see `XattrMapping`.
+struct XattrRule {
+    guest: Vec<u8>,
+    host: Vec<u8>,
+    // Host names only reachable through this rule, the guest can't access them directly.
+    reserved: Vec<u8>,
+}
+
+impl XattrRule {
+    fn new(guest: &[u8], host: &[u8], reserved: &[u8]) -> Self {
+        XattrRule {
+            guest: guest.to_vec(),
+            host: host.to_vec(),
+            reserved: reserved.to_vec(),
+        }
+    }
+}
+
 impl PassthroughFs {
     pub fn new(cfg: Config) -> io::Result<PassthroughFs> {
         let fd = if let Some(fd) = cfg.proc_sfd_rawfd {
@@ -317,10 +671,32 @@ impl PassthroughFs {
         // Safe because we just opened this fd or it was provided by our caller.
         let proc_self_fd = unsafe { File::from_raw_fd(fd) };
 
+        let mut xattr_rules: Vec<_> = cfg
+            .xattr_map
+            .iter()
+            .map(|m| {
+                let (guest, host) = (m.guest_prefix.as_bytes(), m.host_prefix.as_bytes());
+                XattrRule::new(guest, host, host)
+            })
+            .collect();
+        // The whole prefix is reserved, not only where the guest `security.` attributes go.
+        if let Some(prefix) = &cfg.security_xattr_prefix {
+            let mut host = prefix.as_bytes().to_vec();
+            host.extend_from_slice(SECURITY_XATTR_PREFIX);
+            xattr_rules.push(XattrRule::new(
+                SECURITY_XATTR_PREFIX,
+                &host,
+                prefix.as_bytes(),
+            ));
+        }
+
         Ok(PassthroughFs {
             inodes: RwLock::new(MultikeyBTreeMap::new()),
             next_inode: AtomicU64::new(fuse::ROOT_ID + 2),
             init_inode: fuse::ROOT_ID + 1,
+            open_inodes: Mutex::new(VecDeque::new()),
+            mount_fds: Mutex::new(HashMap::new()),
+            reopen_inodes: AtomicBool::new(false),
 
             handles: RwLock::new(BTreeMap::new()),
             next_handle: AtomicU64::new(1),
@@ -329,6 +705,9 @@ impl PassthroughFs {
             proc_self_fd,
 
             writeback: AtomicBool::new(false),
+            announce_submounts: AtomicBool::new(false),
+            rename2_supported: AtomicBool::new(true),
+            xattr_rules,
             cfg,
         })
     }
@@ -342,7 +721,8 @@ impl PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        let pathname = CString::new(format!("{}", data.file.as_raw_fd()))
+        let file = self.inode_file(&data)?;
+        let pathname = CString::new(format!("{}", file.as_raw_fd()))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         // When writeback caching is enabled, the kernel may send read requests even if the
@@ -383,6 +763,122 @@ impl PassthroughFs {
         Ok(unsafe { File::from_raw_fd(fd) })
     }
 
+    // Returns the `O_PATH` file of the inode `data`, reopening it if it was closed to stay within
+    // `Config::max_inodes`.
+    fn inode_file(&self, data: &Arc<InodeData>) -> io::Result<Arc<File>> {
+        data.used.store(true, Ordering::Relaxed);
+        if let Some(file) = &*data.file.read().unwrap() {
+            return Ok(file.clone());
+        }
+        let (handle, mount_fd) = data.reopen.as_ref().ok_or_else(ebadf)?;
+        let file = handle.open(mount_fd)?;
+        Ok(self.set_inode_file(data, file))
+    }
+
+    // Makes `file` the one of the inode `data` unless it has one open already, returning the one
+    // it ends up with.
+    fn set_inode_file(&self, data: &Arc<InodeData>, file: File) -> Arc<File> {
+        let mut slot = data.file.write().unwrap();
+        if let Some(file) = &*slot {
+            return file.clone();
+        }
+        let file = Arc::new(file);
+        *slot = Some(file.clone());
+        drop(slot);
+
+        if let Some(max) = self.cfg.max_inodes {
+            let mut open = self.open_inodes.lock().unwrap();
+            // Going over the limit if nothing else can be closed, the guest uses this one.
+            close_unused_files(&mut open, max.get());
+            open.push_back(Arc::downgrade(data));
+        }
+        file
+    }
+
+    // Returns how to reopen `f`, the file of a new inode, once closed to stay within
+    // `Config::max_inodes`, if it can be.
+    fn reopen_info(
+        &self,
+        f: &File,
+        st: &libc::stat64,
+        mnt_id: u64,
+    ) -> Option<(FileHandle, Arc<File>)> {
+        if !self.reopen_inodes.load(Ordering::Relaxed) {
+            return None;
+        }
+        let handle = FileHandle::from_file(f)?;
+        let mut mount_fds = self.mount_fds.lock().unwrap();
+        let key = (st.st_dev, mnt_id);
+        if let Some(mount_fd) = mount_fds.get(&key) {
+            return Some((handle, mount_fd.clone()));
+        }
+        // Opening other kinds of files may have side effects, e.g. for devices.
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return None;
+        }
+        let mount_fd = Arc::new(self.open_directory(f).ok()?);
+        mount_fds.insert(key, mount_fd.clone());
+        Some((handle, mount_fd))
+    }
+
+    // Opens the directory `dir` refers to for reading, `dir` being opened with `O_PATH`.
+    fn open_directory(&self, dir: &File) -> io::Result<File> {
+        let pathname = CString::new(format!("{}", dir.as_raw_fd()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = unsafe {
+            libc::openat(
+                self.proc_self_fd.as_raw_fd(),
+                pathname.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we just opened this fd.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    // Returns the host path of the file of the inode `data`, checking that it still leads to it:
+    // the files unlinked or replaced since they were looked up have none.
+    fn host_path(&self, data: &Arc<InodeData>) -> io::Result<Vec<u8>> {
+        let file = self.inode_file(data)?;
+        let pathname = CString::new(format!("{}", file.as_raw_fd()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        // Safe because the kernel will only write data in `buf` and we check the return value.
+        let len = unsafe {
+            libc::readlinkat(
+                self.proc_self_fd.as_raw_fd(),
+                pathname.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+
+        let path = CString::new(buf.clone()).map_err(|_| einval())?;
+        let st = stat(&file)?;
+        let found = open_path(&path).and_then(|f| stat(&f));
+        if !found.is_ok_and(|found| found.st_dev == st.st_dev && found.st_ino == st.st_ino) {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE));
+        }
+        Ok(buf)
+    }
+
+    // Returns the policy applying to files of the type in `mode`.
+    fn special_file_policy(&self, mode: libc::mode_t) -> SpecialFilePolicy {
+        match mode & libc::S_IFMT {
+            libc::S_IFCHR | libc::S_IFBLK => self.cfg.device_policy,
+            libc::S_IFIFO | libc::S_IFSOCK => self.cfg.fifo_socket_policy,
+            _ => SpecialFilePolicy::Passthrough,
+        }
+    }
+
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
         let p = self
             .inodes
@@ -391,11 +887,12 @@ impl PassthroughFs {
             .get(&parent)
             .cloned()
             .ok_or_else(ebadf)?;
+        let parent_file = self.inode_file(&p)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
         let fd = unsafe {
             libc::openat(
-                p.file.as_raw_fd(),
+                parent_file.as_raw_fd(),
                 name.as_ptr(),
                 libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
             )
@@ -408,34 +905,53 @@ impl PassthroughFs {
         let f = unsafe { File::from_raw_fd(fd) };
 
         let st = stat(&f)?;
+        if let Some(e) = self.special_file_policy(st.st_mode).lookup_error() {
+            return Err(e);
+        }
+
+        // A directory living on a different mount than its parent is the root of a host mount,
+        // let the guest know so it can give it its own submount.
+        let mnt_id = mount_id(&f)?;
+        let mut attr_flags = 0;
+        if self.announce_submounts.load(Ordering::Relaxed)
+            && st.st_mode & libc::S_IFMT == libc::S_IFDIR
+            && (mnt_id != p.mnt_id || st.st_dev != stat(&parent_file)?.st_dev)
+        {
+            attr_flags |= fuse::ATTR_SUBMOUNT;
+        }
 
         let altkey = InodeAltKey {
             ino: st.st_ino,
             dev: st.st_dev,
+            mnt_id,
         };
         let data = self.inodes.read().unwrap().get_alt(&altkey).cloned();
 
         let inode = if let Some(data) = data {
             // Matches with the release store in `forget`.
             data.refcount.fetch_add(1, Ordering::Acquire);
+            // Saves reopening the file by handle if it was closed.
+            self.set_inode_file(&data, f);
+            data.used.store(true, Ordering::Relaxed);
             data.inode
         } else {
+            let reopen = self.reopen_info(&f, &st, mnt_id);
             // There is a possible race here where 2 threads end up adding the same file
             // into the inode list.  However, since each of those will get a unique Inode
             // value and unique file descriptors this shouldn't be that much of a problem.
+            let mut inodes = self.inodes.write().unwrap();
+            let mut open = self.open_inodes.lock().unwrap();
+            if let Some(max) = self.cfg.max_inodes {
+                if !close_unused_files(&mut open, max.get()) {
+                    return Err(io::Error::from_raw_os_error(libc::ENFILE));
+                }
+            }
             let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
-            self.inodes.write().unwrap().insert(
-                inode,
-                InodeAltKey {
-                    ino: st.st_ino,
-                    dev: st.st_dev,
-                },
-                Arc::new(InodeData {
-                    inode,
-                    file: f,
-                    refcount: AtomicU64::new(1),
-                }),
-            );
+            let data = Arc::new(InodeData::new(inode, f, reopen, 1, mnt_id));
+            if self.cfg.max_inodes.is_some() {
+                open.push_back(Arc::downgrade(&data));
+            }
+            inodes.insert(inode, altkey, data);
 
             inode
         };
@@ -446,11 +962,63 @@ impl PassthroughFs {
             inode,
             generation: 0,
             attr: st,
+            attr_flags,
             attr_timeout: self.cfg.attr_timeout,
             entry_timeout: self.cfg.entry_timeout,
         })
     }
 
+    // Maps the name of an xattr as seen by the guest to the name stored on the host.
+    fn map_xattr_name<'a>(&self, name: &'a CStr) -> io::Result<Cow<'a, CStr>> {
+        let bytes = name.to_bytes();
+        let Some(rule) = self
+            .xattr_rules
+            .iter()
+            .find(|r| bytes.starts_with(&r.guest))
+        else {
+            if self
+                .xattr_rules
+                .iter()
+                .any(|r| bytes.starts_with(&r.reserved))
+            {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            return Ok(Cow::Borrowed(name));
+        };
+
+        let mut mapped = rule.host.clone();
+        mapped.extend_from_slice(&bytes[rule.guest.len()..]);
+        CString::new(mapped)
+            .map(Cow::Owned)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+
+    // Maps a nul-separated list of xattr names stored on the host to the names seen by the guest.
+    fn map_xattr_list(&self, list: Vec<u8>) -> Vec<u8> {
+        if self.xattr_rules.is_empty() {
+            return list;
+        }
+
+        let mut mapped = Vec::with_capacity(list.len());
+        for name in list.split(|c| *c == 0).filter(|n| !n.is_empty()) {
+            if let Some(rule) = self.xattr_rules.iter().find(|r| name.starts_with(&r.host)) {
+                mapped.extend_from_slice(&rule.guest);
+                mapped.extend_from_slice(&name[rule.host.len()..]);
+            } else if self
+                .xattr_rules
+                .iter()
+                .any(|r| name.starts_with(&r.reserved) || name.starts_with(&r.guest))
+            {
+                // Either only reachable through a rule, or shadowed by one.
+                continue;
+            } else {
+                mapped.extend_from_slice(name);
+            }
+            mapped.push(0);
+        }
+        mapped
+    }
+
     fn do_readdir<F>(
         &self,
         inode: Inode,
@@ -531,6 +1099,10 @@ impl PassthroughFs {
                 // We don't want to report the "." and ".." entries. However, returning `Ok(0)` will
                 // break the loop so return `Ok` with a non-zero value instead.
                 Ok(1)
+            } else if self.special_file_policy(dirent_type_to_mode(u32::from(dirent64.d_ty)))
+                == SpecialFilePolicy::Skip
+            {
+                Ok(1)
             } else {
                 add_entry(DirEntry {
                     ino: dirent64.d_ino,
@@ -555,14 +1127,55 @@ impl PassthroughFs {
         Ok(())
     }
 
-    fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
-        debug!("do_open: {:?}", inode);
-        let file = RwLock::new(self.open_inode(inode, flags as i32)?);
+    // Opens `inode` again with `O_DIRECT` if `Config::direct_io` asks for it and `file`, the
+    // handle opened with `flags`, is a regular file opened without it.
+    fn open_direct(&self, inode: Inode, flags: u32, file: &File) -> Option<File> {
+        let flags = flags as i32;
+        if !self.cfg.direct_io || flags & libc::O_DIRECT != 0 {
+            return None;
+        }
+        // Opening FIFOs or devices again could block, or have side effects.
+        if stat(file).ok()?.st_mode & libc::S_IFMT != libc::S_IFREG {
+            return None;
+        }
+
+        let flags = (flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC)) | libc::O_DIRECT;
+        match self.open_inode(inode, flags) {
+            Ok(direct) => Some(direct),
+            Err(e) => {
+                debug!("Cannot open inode {inode} with O_DIRECT, using buffered I/O: {e}");
+                None
+            }
+        }
+    }
 
+    // Tracks `data` under a new handle, unless the guest already has `Config::max_handles` open.
+    fn insert_handle(&self, data: HandleData) -> io::Result<Handle> {
+        let mut handles = self.handles.write().unwrap();
+        if self
+            .cfg
+            .max_handles
+            .is_some_and(|max| handles.len() >= max.get())
+        {
+            return Err(io::Error::from_raw_os_error(libc::ENFILE));
+        }
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-        let data = HandleData { inode, file };
+        handles.insert(handle, Arc::new(data));
+        Ok(handle)
+    }
 
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+    fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
+        debug!("do_open: {:?}", inode);
+        let file = self.open_inode(inode, flags as i32)?;
+        let direct = self.open_direct(inode, flags, &file);
+        let file = RwLock::new(file);
+
+        let handle = self.insert_handle(HandleData {
+            inode,
+            flags,
+            file,
+            direct,
+        })?;
 
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
@@ -608,7 +1221,7 @@ impl PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        let st = stat(&data.file)?;
+        let st = stat(&*self.inode_file(&data)?)?;
 
         Ok((st, self.cfg.attr_timeout))
     }
@@ -623,7 +1236,8 @@ impl PassthroughFs {
             .ok_or_else(ebadf)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::unlinkat(data.file.as_raw_fd(), name.as_ptr(), flags) };
+        let res =
+            unsafe { libc::unlinkat(self.inode_file(&data)?.as_raw_fd(), name.as_ptr(), flags) };
         if res == 0 {
             Ok(())
         } else {
@@ -632,11 +1246,13 @@ impl PassthroughFs {
     }
 }
 
+// Returns the data of the inode if this was the last reference to it, for the caller to close its
+// fd once it no longer holds the lock on the inode map.
 fn forget_one(
     inodes: &mut MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>,
     inode: Inode,
     count: u64,
-) {
+) -> Option<Arc<InodeData>> {
     if let Some(data) = inodes.get(&inode) {
         // Acquiring the write lock on the inode map prevents new lookups from incrementing the
         // refcount but there is the possibility that a previous lookup already acquired a
@@ -662,12 +1278,13 @@ fn forget_one(
                     // thread that is waiting to do a forget on the same inode will have to wait
                     // until we release the lock. So there's is no other release store for us to
                     // synchronize with before deleting the entry.
-                    inodes.remove(&inode);
+                    return inodes.remove(&inode);
                 }
                 break;
             }
         }
     }
+    None
 }
 
 impl FileSystem for PassthroughFs {
@@ -677,57 +1294,185 @@ impl FileSystem for PassthroughFs {
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
         let root = CString::new(self.cfg.root_dir.as_str()).expect("CString::new failed");
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        // We use `O_PATH` because we just want this for traversing the directory tree
-        // and not for actually reading the contents.
-        let fd = unsafe {
-            libc::openat(
-                libc::AT_FDCWD,
-                root.as_ptr(),
-                libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-            )
-        };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        // Safe because we just opened this fd above.
-        let f = unsafe { File::from_raw_fd(fd) };
+        // We use `O_PATH` because we just want this for traversing the directory tree and not for
+        // actually reading the contents.
+        let f = open_path(&root)?;
 
         let st = stat(&f)?;
+        let mnt_id = mount_id(&f)?;
 
         // Safe because this doesn't modify any memory and there is no need to check the return
         // value because this system call always succeeds. We need to clear the umask here because
         // we want the client to be able to set all the bits in the mode.
         unsafe { libc::umask(0o000) };
 
+        if self.cfg.max_inodes.is_some() {
+            // Reopening files by handle takes `CAP_DAC_READ_SEARCH` and a file system that
+            // supports handles, see whether it works on the root.
+            let mount_fd = self
+                .open_directory(&f)
+                .ok()
+                .filter(|fd| FileHandle::from_file(&f).is_some_and(|h| h.open(fd).is_ok()));
+            let reopen = mount_fd.is_some();
+            if let Some(mount_fd) = mount_fd {
+                self.mount_fds
+                    .lock()
+                    .unwrap()
+                    .insert((st.st_dev, mnt_id), Arc::new(mount_fd));
+            } else {
+                warn!("can't reopen files by handle, lookups may fail past max_inodes");
+            }
+            self.reopen_inodes.store(reopen, Ordering::Relaxed);
+        }
+
         let mut inodes = self.inodes.write().unwrap();
 
-        // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
+        // The root is never closed. Not sure why it gets a refcount of 2 but that's what libfuse
+        // does.
+        let root = Arc::new(InodeData::new(fuse::ROOT_ID, f, None, 2, mnt_id));
         inodes.insert(
             fuse::ROOT_ID,
             InodeAltKey {
                 ino: st.st_ino,
                 dev: st.st_dev,
+                mnt_id,
             },
-            Arc::new(InodeData {
-                inode: fuse::ROOT_ID,
-                file: f,
-                refcount: AtomicU64::new(2),
-            }),
+            root.clone(),
         );
+        if self.cfg.max_inodes.is_some() {
+            self.open_inodes
+                .lock()
+                .unwrap()
+                .push_back(Arc::downgrade(&root));
+        }
 
         let mut opts = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
         if self.cfg.writeback && capable.contains(FsOptions::WRITEBACK_CACHE) {
             opts |= FsOptions::WRITEBACK_CACHE;
             self.writeback.store(true, Ordering::Relaxed);
         }
+        if self.cfg.announce_submounts && capable.contains(FsOptions::SUBMOUNTS) {
+            self.announce_submounts.store(true, Ordering::Relaxed);
+        }
+
+        // Safe because this doesn't modify any memory. The invalid fds make the call fail, only
+        // the error tells whether the syscall exists.
+        let res =
+            unsafe { libc::syscall(libc::SYS_renameat2, -1, c"".as_ptr(), -1, c"".as_ptr(), 0) };
+        let rename2_supported =
+            res == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS);
+        if !rename2_supported {
+            warn!("renameat2 is not supported by the host, renames with flags will fail");
+        }
+        self.rename2_supported
+            .store(rename2_supported, Ordering::Relaxed);
         Ok(opts)
     }
 
     fn destroy(&self) {
         self.handles.write().unwrap().clear();
         self.inodes.write().unwrap().clear();
+        self.open_inodes.lock().unwrap().clear();
+        self.mount_fds.lock().unwrap().clear();
+    }
+
+    // The inodes are saved with the host paths of their files and the handles with the flags
+    // they were opened with, to be opened again: the files must be at the same paths when the
+    // references are restored.
+    fn save_references(&self) -> io::Result<Vec<u8>> {
+        let inodes = self.inodes.read().unwrap();
+        let handles = self.handles.read().unwrap();
+        let mut data = Vec::new();
+        data.extend(self.next_inode.load(Ordering::Relaxed).to_le_bytes());
+        data.extend(self.next_handle.load(Ordering::Relaxed).to_le_bytes());
+
+        let saved: Vec<&Arc<InodeData>> = inodes
+            .values()
+            .filter(|data| data.inode != fuse::ROOT_ID)
+            .collect();
+        data.extend((saved.len() as u32).to_le_bytes());
+        for inode in saved {
+            let path = self.host_path(inode)?;
+            data.extend(inode.inode.to_le_bytes());
+            data.extend(inode.refcount.load(Ordering::Relaxed).to_le_bytes());
+            data.extend((path.len() as u32).to_le_bytes());
+            data.extend(path);
+        }
+
+        data.extend((handles.len() as u32).to_le_bytes());
+        for (handle, handle_data) in handles.iter() {
+            data.extend(handle.to_le_bytes());
+            data.extend(handle_data.inode.to_le_bytes());
+            data.extend(handle_data.flags.to_le_bytes());
+        }
+        Ok(data)
+    }
+
+    fn restore_references(&self, mut data: &[u8]) -> io::Result<()> {
+        let next_inode = saved_u64(&mut data)?;
+        let next_handle = saved_u64(&mut data)?;
+
+        let count = saved_u32(&mut data)?;
+        for _ in 0..count {
+            let inode = saved_u64(&mut data)?;
+            let refcount = saved_u64(&mut data)?;
+            let len = saved_u32(&mut data)?;
+            let path = CString::new(split_saved(&mut data, len as usize)?).map_err(|_| einval())?;
+            if inode == fuse::ROOT_ID || inode >= next_inode {
+                return Err(einval());
+            }
+
+            let f = open_path(&path)?;
+            let st = stat(&f)?;
+            let mnt_id = mount_id(&f)?;
+            let reopen = self.reopen_info(&f, &st, mnt_id);
+            let inode_data = Arc::new(InodeData::new(inode, f, reopen, refcount, mnt_id));
+            if let Some(max) = self.cfg.max_inodes {
+                let mut open = self.open_inodes.lock().unwrap();
+                close_unused_files(&mut open, max.get());
+                open.push_back(Arc::downgrade(&inode_data));
+            }
+            let altkey = InodeAltKey {
+                ino: st.st_ino,
+                dev: st.st_dev,
+                mnt_id,
+            };
+            self.inodes
+                .write()
+                .unwrap()
+                .insert(inode, altkey, inode_data);
+        }
+
+        let count = saved_u32(&mut data)?;
+        for _ in 0..count {
+            let handle = saved_u64(&mut data)?;
+            let inode = saved_u64(&mut data)?;
+            let flags = saved_u32(&mut data)?;
+            if handle >= next_handle {
+                return Err(einval());
+            }
+
+            // The file is opened again as it is, whatever the guest created or truncated it with.
+            let reopen_flags = flags as i32 & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+            let file = self.open_inode(inode, reopen_flags)?;
+            let direct = self.open_direct(inode, flags, &file);
+            self.handles.write().unwrap().insert(
+                handle,
+                Arc::new(HandleData {
+                    inode,
+                    flags,
+                    file: RwLock::new(file),
+                    direct,
+                }),
+            );
+        }
+        if !data.is_empty() {
+            return Err(einval());
+        }
+
+        self.next_inode.store(next_inode, Ordering::Relaxed);
+        self.next_handle.store(next_handle, Ordering::Relaxed);
+        Ok(())
     }
 
     fn statfs(&self, _ctx: Context, inode: Inode) -> io::Result<libc::statvfs64> {
@@ -742,7 +1487,8 @@ impl FileSystem for PassthroughFs {
         let mut out = MaybeUninit::<libc::statvfs64>::zeroed();
 
         // Safe because this will only modify `out` and we check the return value.
-        let res = unsafe { libc::fstatvfs64(data.file.as_raw_fd(), out.as_mut_ptr()) };
+        let res =
+            unsafe { libc::fstatvfs64(self.inode_file(&data)?.as_raw_fd(), out.as_mut_ptr()) };
         if res == 0 {
             // Safe because the kernel guarantees that `out` has been initialized.
             Ok(unsafe { out.assume_init() })
@@ -765,6 +1511,7 @@ impl FileSystem for PassthroughFs {
                 inode: self.init_inode,
                 generation: 0,
                 attr: st,
+                attr_flags: 0,
                 attr_timeout: self.cfg.attr_timeout,
                 entry_timeout: self.cfg.entry_timeout,
             })
@@ -774,17 +1521,22 @@ impl FileSystem for PassthroughFs {
     }
 
     fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
-        let mut inodes = self.inodes.write().unwrap();
-
-        forget_one(&mut inodes, inode, count)
+        // Closes the fd of a forgotten inode once the lock is released.
+        let forgotten = forget_one(&mut self.inodes.write().unwrap(), inode, count);
+        drop(forgotten);
     }
 
     fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
-        let mut inodes = self.inodes.write().unwrap();
-
-        for (inode, count) in requests {
-            forget_one(&mut inodes, inode, count)
-        }
+        // The whole batch is forgotten under a single acquisition of the lock, and the fds are
+        // only closed once lookups can proceed again.
+        let forgotten: Vec<_> = {
+            let mut inodes = self.inodes.write().unwrap();
+            requests
+                .into_iter()
+                .filter_map(|(inode, count)| forget_one(&mut inodes, inode, count))
+                .collect()
+        };
+        drop(forgotten);
     }
 
     fn opendir(
@@ -829,7 +1581,13 @@ impl FileSystem for PassthroughFs {
             .ok_or_else(ebadf)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::mkdirat(data.file.as_raw_fd(), name.as_ptr(), mode & !umask) };
+        let res = unsafe {
+            libc::mkdirat(
+                self.inode_file(&data)?.as_raw_fd(),
+                name.as_ptr(),
+                mode & !umask,
+            )
+        };
         if res == 0 {
             self.do_lookup(parent, name)
         } else {
@@ -869,6 +1627,23 @@ impl FileSystem for PassthroughFs {
         F: FnMut(DirEntry, Entry) -> io::Result<usize>,
     {
         self.do_readdir(inode, handle, size, offset, |dir_entry| {
+            // Entries without a node ID are listed but not looked up by the guest, which then
+            // gets the policy's error.
+            if self.special_file_policy(dirent_type_to_mode(dir_entry.type_))
+                == SpecialFilePolicy::Error
+            {
+                let entry = Entry {
+                    inode: 0,
+                    generation: 0,
+                    // Safe because `stat64` is plain data, for which all zeroes is a valid value.
+                    attr: unsafe { mem::zeroed() },
+                    attr_flags: 0,
+                    attr_timeout: Duration::ZERO,
+                    entry_timeout: Duration::ZERO,
+                };
+                return add_entry(dir_entry, entry);
+            }
+
             // Safe because the kernel guarantees that the buffer is nul-terminated. Additionally,
             // the kernel will pad the name with '\0' bytes up to 8-byte alignment and there's no
             // way for us to know exactly how many padding bytes there are. This would cause
@@ -936,7 +1711,7 @@ impl FileSystem for PassthroughFs {
         // have much bigger problems.
         let fd = unsafe {
             libc::openat(
-                data.file.as_raw_fd(),
+                self.inode_file(&data)?.as_raw_fd(),
                 name.as_ptr(),
                 flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
                 mode & !(umask & 0o777),
@@ -947,18 +1722,26 @@ impl FileSystem for PassthroughFs {
         }
 
         // Safe because we just opened this fd.
-        let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
+        let file = unsafe { File::from_raw_fd(fd) };
 
         let entry = self.do_lookup(parent, name)?;
+        let direct = self.open_direct(entry.inode, flags, &file);
+        let file = RwLock::new(file);
 
-        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-        let data = HandleData {
+        let handle = match self.insert_handle(HandleData {
             inode: entry.inode,
+            flags,
             file,
+            direct,
+        }) {
+            Ok(handle) => handle,
+            Err(e) => {
+                // The guest never hears of the entry, so it won't forget it either.
+                forget_one(&mut self.inodes.write().unwrap(), entry.inode, 1);
+                return Err(e);
+            }
         };
 
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
-
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
             CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
@@ -1006,6 +1789,14 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        if let Some(direct) = data.direct_for(offset, size) {
+            // A misaligned guest buffer fails before anything is read.
+            match w.write_from(direct, size as usize, offset) {
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                res => return res,
+            }
+        }
+
         // This is safe because write_from uses preadv64, so the underlying file descriptor
         // offset is not affected by this operation.
         let f = data.file.read().unwrap();
@@ -1040,6 +1831,14 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        if let Some(direct) = data.direct_for(offset, size) {
+            // A misaligned guest buffer fails before anything is written.
+            match r.read_to(direct, size as usize, offset) {
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                res => return res,
+            }
+        }
+
         // This is safe because read_to uses pwritev64, so the underlying file descriptor
         // offset is not affected by this operation.
         let f = data.file.read().unwrap();
@@ -1070,6 +1869,7 @@ impl FileSystem for PassthroughFs {
             .get(&inode)
             .cloned()
             .ok_or_else(ebadf)?;
+        let inode_file = self.inode_file(&inode_data)?;
 
         enum Data {
             Handle(RawFd),
@@ -1090,7 +1890,7 @@ impl FileSystem for PassthroughFs {
             let fd = hd.file.write().unwrap().as_raw_fd();
             Data::Handle(fd)
         } else {
-            let pathname = CString::new(format!("{}", inode_data.file.as_raw_fd()))
+            let pathname = CString::new(format!("{}", inode_file.as_raw_fd()))
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
             Data::ProcPath(pathname)
         };
@@ -1130,7 +1930,7 @@ impl FileSystem for PassthroughFs {
             // Safe because this doesn't modify any memory and we check the return value.
             let res = unsafe {
                 libc::fchownat(
-                    inode_data.file.as_raw_fd(),
+                    inode_file.as_raw_fd(),
                     empty.as_ptr(),
                     uid,
                     gid,
@@ -1222,18 +2022,33 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
-        // and we have glibc 2.28.
-        let res = unsafe {
-            libc::syscall(
-                libc::SYS_renameat2,
-                old_inode.file.as_raw_fd(),
-                oldname.as_ptr(),
-                new_inode.file.as_raw_fd(),
-                newname.as_ptr(),
-                flags,
-            )
+        let res = if self.rename2_supported.load(Ordering::Relaxed) {
+            // Safe because this doesn't modify any memory and we check the return value.
+            // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508
+            // lands and we have glibc 2.28.
+            unsafe {
+                libc::syscall(
+                    libc::SYS_renameat2,
+                    self.inode_file(&old_inode)?.as_raw_fd(),
+                    oldname.as_ptr(),
+                    self.inode_file(&new_inode)?.as_raw_fd(),
+                    newname.as_ptr(),
+                    flags,
+                )
+            }
+        } else if flags == 0 {
+            // Safe because this doesn't modify any memory and we check the return value.
+            unsafe {
+                libc::renameat(
+                    self.inode_file(&old_inode)?.as_raw_fd(),
+                    oldname.as_ptr(),
+                    self.inode_file(&new_inode)?.as_raw_fd(),
+                    newname.as_ptr(),
+                )
+            }
+            .into()
+        } else {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         };
         if res == 0 {
             Ok(())
@@ -1255,6 +2070,9 @@ impl FileSystem for PassthroughFs {
         if extensions.secctx.is_some() {
             unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
         }
+        if let Some(e) = self.special_file_policy(mode).create_error() {
+            return Err(e);
+        }
 
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
@@ -1268,7 +2086,7 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
             libc::mknodat(
-                data.file.as_raw_fd(),
+                self.inode_file(&data)?.as_raw_fd(),
                 name.as_ptr(),
                 (mode & !umask) as libc::mode_t,
                 u64::from(rdev),
@@ -1304,7 +2122,8 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        let procname = CString::new(format!("{}", data.file.as_raw_fd()))
+        let file = self.inode_file(&data)?;
+        let procname = CString::new(format!("{}", file.as_raw_fd()))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         // Safe because this doesn't modify any memory and we check the return value.
@@ -1312,7 +2131,7 @@ impl FileSystem for PassthroughFs {
             libc::linkat(
                 self.proc_self_fd.as_raw_fd(),
                 procname.as_ptr(),
-                new_inode.file.as_raw_fd(),
+                self.inode_file(&new_inode)?.as_raw_fd(),
                 newname.as_ptr(),
                 libc::AT_SYMLINK_FOLLOW,
             )
@@ -1347,8 +2166,13 @@ impl FileSystem for PassthroughFs {
             .ok_or_else(ebadf)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res =
-            unsafe { libc::symlinkat(linkname.as_ptr(), data.file.as_raw_fd(), name.as_ptr()) };
+        let res = unsafe {
+            libc::symlinkat(
+                linkname.as_ptr(),
+                self.inode_file(&data)?.as_raw_fd(),
+                name.as_ptr(),
+            )
+        };
         if res == 0 {
             self.do_lookup(parent, name)
         } else {
@@ -1373,7 +2197,7 @@ impl FileSystem for PassthroughFs {
         // Safe because this will only modify the contents of `buf` and we check the return value.
         let res = unsafe {
             libc::readlinkat(
-                data.file.as_raw_fd(),
+                self.inode_file(&data)?.as_raw_fd(),
                 empty.as_ptr(),
                 buf.as_mut_ptr() as *mut libc::c_char,
                 buf.len(),
@@ -1467,7 +2291,7 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        let st = stat(&data.file)?;
+        let st = stat(&*self.inode_file(&data)?)?;
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -1519,6 +2343,8 @@ impl FileSystem for PassthroughFs {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
+        let name = self.map_xattr_name(name)?;
+
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to get a new fd.
         let file = self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)?;
@@ -1555,6 +2381,8 @@ impl FileSystem for PassthroughFs {
             return Err(io::Error::from_raw_os_error(libc::ENODATA));
         }
 
+        let name = self.map_xattr_name(name)?;
+
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to get a new fd.
         let file = self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)?;
@@ -1591,24 +2419,53 @@ impl FileSystem for PassthroughFs {
         // need to get a new fd.
         let file = self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)?;
 
-        let mut buf = vec![0; size as usize];
+        // When names are mapped the guest's list can be shorter or longer than the host's, and
+        // the size the guest asked for says nothing about the host's, so always fetch the whole
+        // list in that case.
+        let mapped = !self.xattr_rules.is_empty();
+        let mut buf = Vec::new();
+        let res = loop {
+            let buf_size = if mapped {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe { libc::flistxattr(file.as_raw_fd(), std::ptr::null_mut(), 0) };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                res as usize
+            } else {
+                size as usize
+            };
+            buf.resize(buf_size, 0);
 
-        // Safe because this will only modify the contents of `buf`.
-        let res = unsafe {
-            libc::flistxattr(
-                file.as_raw_fd(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                size as libc::size_t,
-            )
+            // Safe because this will only modify the contents of `buf`.
+            let res = unsafe {
+                libc::flistxattr(
+                    file.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf_size as libc::size_t,
+                )
+            };
+            if res >= 0 {
+                break res;
+            }
+            // An attribute was added between sizing the buffer and filling it, size it again.
+            let err = io::Error::last_os_error();
+            if !mapped || err.raw_os_error() != Some(libc::ERANGE) {
+                return Err(err);
+            }
         };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
+
+        if !mapped && size == 0 {
+            return Ok(ListxattrReply::Count(res as u32));
         }
 
+        buf.resize(res as usize, 0);
+        let buf = self.map_xattr_list(buf);
         if size == 0 {
-            Ok(ListxattrReply::Count(res as u32))
+            Ok(ListxattrReply::Count(buf.len() as u32))
+        } else if buf.len() > size as usize {
+            Err(io::Error::from_raw_os_error(libc::ERANGE))
         } else {
-            buf.resize(res as usize, 0);
             Ok(ListxattrReply::Names(buf))
         }
     }
@@ -1622,6 +2479,8 @@ impl FileSystem for PassthroughFs {
         // need to get a new fd.
         let file = self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)?;
 
+        let name = self.map_xattr_name(name)?;
+
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr()) };
 
@@ -1684,9 +2543,16 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        // The guest kernel moves the offsets of its files itself, it only asks for the data and
+        // holes of sparse files.
+        if whence != libc::SEEK_DATA as u32 && whence != libc::SEEK_HOLE as u32 {
+            return Err(einval());
+        }
+
         let fd = data.file.write().unwrap().as_raw_fd();
 
-        // Safe because this doesn't modify any memory and we check the return value.
+        // Safe because this doesn't modify any memory and we check the return value. Offsets past
+        // the last data or hole fail with `ENXIO`, which the guest passes on to the caller.
         let res = unsafe { libc::lseek(fd, offset as libc::off64_t, whence as libc::c_int) };
         if res < 0 {
             Err(io::Error::last_os_error())
@@ -1716,6 +2582,10 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        if flags != 0 {
+            return Err(einval());
+        }
+
         // Take just a read lock as we're not going to alter the file descriptor offset.
         let fd_in = data_in.file.read().unwrap().as_raw_fd();
 
@@ -1731,23 +2601,80 @@ impl FileSystem for PassthroughFs {
         // Take just a read lock as we're not going to alter the file descriptor offset.
         let fd_out = data_out.file.read().unwrap().as_raw_fd();
 
-        // Safe because this will only modify `offset_in` and `offset_out` and we check
-        // the return value.
-        let res = unsafe {
-            libc::copy_file_range(
-                fd_in,
-                &mut (offset_in as i64) as &mut _ as *mut _,
-                fd_out,
-                &mut (offset_out as i64) as &mut _ as *mut _,
-                len.try_into().unwrap(),
-                flags.try_into().unwrap(),
-            )
+        // The kernel copies in the host, sharing the extents on file systems with reflinks.
+        let len = len as usize;
+        let mut off_in = offset_in as libc::loff_t;
+        let mut off_out = offset_out as libc::loff_t;
+        // Safe because this will only modify `off_in` and `off_out` and we check the return
+        // value.
+        let res =
+            unsafe { libc::copy_file_range(fd_in, &mut off_in, fd_out, &mut off_out, len, 0) };
+        if res >= 0 {
+            return Ok(res as usize);
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // Across file systems, or on a kernel without the syscall.
+            Some(libc::EXDEV) | Some(libc::ENOSYS) => {
+                copy::copy_range(fd_in, offset_in, fd_out, offset_out, len)
+            }
+            _ => Err(err),
+        }
+    }
+
+    fn ioctl(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        flags: u32,
+        cmd: u32,
+        _arg: u64,
+        data: &[u8],
+        out_size: u32,
+    ) -> io::Result<(i32, Vec<u8>)> {
+        let compat = fuse::IoctlFlags::from_bits_truncate(flags)
+            .intersects(fuse::IoctlFlags::IOCTL_COMPAT | fuse::IoctlFlags::IOCTL_32BIT);
+        // The argument layout of 32-bit ioctls doesn't necessarily match the host's, and the clone
+        // ioctls take a guest fd as their argument.
+        if compat
+            || cmd == libc::FICLONE as u32
+            || cmd == libc::FICLONERANGE as u32
+            || !self.cfg.allowed_ioctls.contains(&cmd)
+        {
+            return Err(io::Error::from_raw_os_error(libc::ENOTTY));
+        }
+
+        let data_handle = self
+            .handles
+            .read()
+            .unwrap()
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .cloned()
+            .ok_or_else(ebadf)?;
+
+        let fd = data_handle.file.read().unwrap().as_raw_fd();
+
+        // Size the buffer for whatever the host kernel may access according to the encoding of
+        // `cmd`, even if the guest kernel sent or expects fewer bytes. The guest's `arg` is never
+        // passed on, the host kernel would take it as an address in our own memory.
+        let ioc_size = match ((cmd >> IOC_SIZESHIFT) & IOC_SIZEMASK) as usize {
+            0 => IOCTL_SCRATCH_SIZE,
+            size => size,
         };
+        let mut buf = vec![0u8; data.len().max(out_size as usize).max(ioc_size)];
+        buf[..data.len()].copy_from_slice(data);
+
+        // Safe because `buf` is at least as large as the argument size encoded in `cmd`, or a
+        // scratch buffer if it encodes none, and we check the return value.
+        let res = unsafe { libc::ioctl(fd, cmd as _, buf.as_mut_ptr()) };
         if res < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(res as usize)
+            return Err(io::Error::last_os_error());
         }
+
+        buf.truncate(out_size as usize);
+        Ok((res, buf))
     }
 
     fn setupmapping(
@@ -1774,7 +2701,8 @@ impl FileSystem for PassthroughFs {
             libc::PROT_READ
         };
 
-        if (moffset + len) > shm_size {
+        // The guest picks both, make sure the mapping stays within the DAX window.
+        if moffset.checked_add(len).is_none_or(|end| end > shm_size) {
             return Err(io::Error::from_raw_os_error(libc::EINVAL));
         }
 
@@ -1812,8 +2740,8 @@ impl FileSystem for PassthroughFs {
             return Ok(());
         }
 
+        // The mapping keeps its own reference to the file, which can be closed right away.
         let file = self.open_inode(inode, open_flags)?;
-        let fd = file.as_raw_fd();
 
         let ret = unsafe {
             libc::mmap(
@@ -1821,7 +2749,7 @@ impl FileSystem for PassthroughFs {
                 len as usize,
                 prot_flags,
                 libc::MAP_SHARED | libc::MAP_FIXED,
-                fd,
+                file.as_raw_fd(),
                 foffset as libc::off_t,
             )
         };
@@ -1829,11 +2757,6 @@ impl FileSystem for PassthroughFs {
             return Err(io::Error::last_os_error());
         }
 
-        let ret = unsafe { libc::close(fd) };
-        if ret == -1 {
-            return Err(io::Error::last_os_error());
-        }
-
         Ok(())
     }
 
@@ -1845,10 +2768,14 @@ impl FileSystem for PassthroughFs {
         shm_size: u64,
     ) -> io::Result<()> {
         for req in requests {
-            let addr = host_shm_base + req.moffset;
-            if (req.moffset + req.len) > shm_size {
+            if req
+                .moffset
+                .checked_add(req.len)
+                .is_none_or(|end| end > shm_size)
+            {
                 return Err(io::Error::from_raw_os_error(libc::EINVAL));
             }
+            let addr = host_shm_base + req.moffset;
             debug!("removemapping: addr={:x} len={:?}", addr, req.len);
             let ret = unsafe {
                 libc::mmap(
@@ -1868,3 +2795,798 @@ impl FileSystem for PassthroughFs {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::bindings;
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{FileExt, MetadataExt};
+    use std::time::SystemTime;
+    use utils::tempdir::TempDir;
+
+    // Hands the file system the bytes the guest would write.
+    struct SliceReader<'a>(&'a [u8]);
+
+    impl io::Read for SliceReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl ZeroCopyReader for SliceReader<'_> {
+        fn read_to(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+            let count = count.min(self.0.len());
+            let written = f.write_at(&self.0[..count], off)?;
+            self.0 = &self.0[written..];
+            Ok(written)
+        }
+    }
+
+    // Serves a temporary directory with `cfg`, creating `file` in it.
+    fn create_file(cfg: Config) -> (TempDir, PassthroughFs, Context, Entry, Handle) {
+        let dir = TempDir::new().unwrap();
+        let fs = PassthroughFs::new(Config {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            ..cfg
+        })
+        .unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+
+        // Safe because these calls can't fail.
+        let ctx = Context {
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            pid: 0,
+        };
+        let name = CString::new("file").unwrap();
+        let (entry, handle, _) = fs
+            .create(
+                ctx,
+                fuse::ROOT_ID,
+                &name,
+                0o644,
+                libc::O_RDWR as u32,
+                0,
+                Extensions::default(),
+            )
+            .unwrap();
+        (dir, fs, ctx, entry, handle.unwrap())
+    }
+
+    fn write(
+        fs: &PassthroughFs,
+        ctx: Context,
+        entry: &Entry,
+        handle: Handle,
+        data: &[u8],
+        offset: u64,
+    ) {
+        let written = fs
+            .write(
+                ctx,
+                entry.inode,
+                handle,
+                SliceReader(data),
+                data.len() as u32,
+                offset,
+                None,
+                false,
+                false,
+                0,
+            )
+            .unwrap();
+        assert_eq!(written, data.len());
+    }
+
+    #[test]
+    fn test_write_fsync() {
+        let (dir, fs, ctx, entry, handle) = create_file(Config::default());
+
+        let start = SystemTime::now();
+        let data = b"durable";
+        write(&fs, ctx, &entry, handle, data, 0);
+
+        fs.fsync(ctx, entry.inode, false, handle).unwrap();
+        fs.fsync(ctx, entry.inode, true, handle).unwrap();
+        // Syncing needs a handle of the inode.
+        assert_eq!(
+            fs.fsync(ctx, entry.inode + 1, false, handle)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EBADF)
+        );
+
+        let path = dir.as_path().join("file");
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        // File systems may keep coarser timestamps than the clock.
+        assert!(mtime + Duration::from_secs(1) >= start);
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let (dir, fs, ctx, entry, handle) = create_file(Config {
+            direct_io: true,
+            ..Default::default()
+        });
+
+        let data = fs.handles.read().unwrap().get(&handle).cloned().unwrap();
+        if data.direct.is_some() {
+            assert!(data.direct_for(0, 4096).is_some());
+            assert!(data.direct_for(512, 1024).is_some());
+            assert!(data.direct_for(3, 512).is_none());
+            assert!(data.direct_for(512, 10).is_none());
+            assert!(data.direct_for(0, 0).is_none());
+        }
+
+        // Whether it's served with O_DIRECT or not, every write must land.
+        let mut contents = vec![0x5a; 8192];
+        write(&fs, ctx, &entry, handle, &contents, 0);
+        write(&fs, ctx, &entry, handle, b"misaligned", 3);
+        contents[3..13].copy_from_slice(b"misaligned");
+        assert_eq!(std::fs::read(dir.as_path().join("file")).unwrap(), contents);
+    }
+
+    #[test]
+    fn test_lseek() {
+        let (_dir, fs, ctx, entry, handle) = create_file(Config::default());
+        let size = 1 << 20;
+        write(&fs, ctx, &entry, handle, &[0xa5; 4096], 0);
+        write(&fs, ctx, &entry, handle, &[0x5a; 4096], size - 4096);
+        let lseek = |offset, whence| {
+            fs.lseek(ctx, entry.inode, handle, offset, whence as u32)
+                .map_err(|e| e.raw_os_error())
+        };
+
+        // File systems without sparse files report them as data up to their end.
+        assert_eq!(lseek(0, libc::SEEK_DATA), Ok(0));
+        let hole = lseek(0, libc::SEEK_HOLE).unwrap();
+        assert!((4096..=size).contains(&hole));
+        let data = lseek(8192, libc::SEEK_DATA).unwrap();
+        assert!((8192..=size - 4096).contains(&data));
+        assert_eq!(lseek(size - 1, libc::SEEK_HOLE), Ok(size));
+
+        assert_eq!(lseek(size, libc::SEEK_DATA), Err(Some(libc::ENXIO)));
+        assert_eq!(lseek(size, libc::SEEK_HOLE), Err(Some(libc::ENXIO)));
+        assert_eq!(lseek(0, libc::SEEK_SET), Err(Some(libc::EINVAL)));
+    }
+
+    #[test]
+    fn test_mknod_policy() {
+        let mknod = |cfg: Config, name: &str, mode: u32| {
+            let (dir, fs, ctx, _, _) = create_file(cfg);
+            let res = fs.mknod(
+                ctx,
+                fuse::ROOT_ID,
+                &CString::new(name).unwrap(),
+                mode | 0o644,
+                0,
+                0,
+                Extensions::default(),
+            );
+            let created = dir.as_path().join(name).exists();
+            (res.map(|_| ()).map_err(|e| e.raw_os_error()), created)
+        };
+        let fifo_error = || Config {
+            fifo_socket_policy: SpecialFilePolicy::Error,
+            ..Default::default()
+        };
+
+        // Nodes the guest couldn't use aren't created.
+        assert_eq!(
+            mknod(fifo_error(), "fifo", libc::S_IFIFO),
+            (Err(Some(libc::EPERM)), false)
+        );
+        assert_eq!(
+            mknod(fifo_error(), "null", libc::S_IFCHR),
+            (Err(Some(libc::EPERM)), false)
+        );
+        assert_eq!(
+            mknod(fifo_error(), "regular", libc::S_IFREG),
+            (Ok(()), true)
+        );
+        assert_eq!(
+            mknod(Config::default(), "fifo", libc::S_IFIFO),
+            (Ok(()), true)
+        );
+    }
+
+    #[test]
+    fn test_special_file_policies() {
+        let serve = |policy: SpecialFilePolicy| {
+            let (dir, fs, ctx, _, _) = create_file(Config {
+                fifo_socket_policy: policy,
+                ..Default::default()
+            });
+            let path = CString::new(dir.as_path().join("fifo").to_str().unwrap()).unwrap();
+            assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o644) }, 0);
+            (dir, fs, ctx)
+        };
+        let fifo = CString::new("fifo").unwrap();
+        let lookup = |fs: &PassthroughFs, ctx| {
+            fs.lookup(ctx, fuse::ROOT_ID, &fifo)
+                .map(|_| ())
+                .map_err(|e| e.raw_os_error())
+        };
+        // Returns the names in the root directory, with the node ID of each under readdirplus.
+        let list = |fs: &PassthroughFs, ctx, plus: bool| {
+            let (handle, _) = fs.opendir(ctx, fuse::ROOT_ID, 0).unwrap();
+            let handle = handle.unwrap();
+            let mut entries = Vec::new();
+            let name = |entry: &DirEntry| {
+                let len = entry.name.iter().position(|b| *b == 0);
+                String::from_utf8(entry.name[..len.unwrap_or(entry.name.len())].to_vec()).unwrap()
+            };
+            if plus {
+                fs.readdirplus(ctx, fuse::ROOT_ID, handle, 4096, 0, |dir_entry, entry| {
+                    entries.push((name(&dir_entry), entry.inode));
+                    Ok(1)
+                })
+                .unwrap();
+            } else {
+                fs.readdir(ctx, fuse::ROOT_ID, handle, 4096, 0, |dir_entry| {
+                    entries.push((name(&dir_entry), 0));
+                    Ok(1)
+                })
+                .unwrap();
+            }
+            fs.releasedir(ctx, fuse::ROOT_ID, 0, handle).unwrap();
+            entries.sort();
+            entries
+        };
+
+        // Skipped files don't exist for the guest.
+        let (_dir, fs, ctx) = serve(SpecialFilePolicy::Skip);
+        assert_eq!(lookup(&fs, ctx), Err(Some(libc::ENOENT)));
+        assert_eq!(list(&fs, ctx, false), [("file".to_string(), 0)]);
+
+        // Refused files are listed, without a node ID the guest could use.
+        let (_dir, fs, ctx) = serve(SpecialFilePolicy::Error);
+        assert_eq!(lookup(&fs, ctx), Err(Some(libc::EPERM)));
+        let entries = list(&fs, ctx, true);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, "fifo");
+        assert_eq!(entries[0].1, 0);
+        assert_eq!(entries[1].0, "file");
+        assert_ne!(entries[1].1, 0);
+
+        let (_dir, fs, ctx) = serve(SpecialFilePolicy::Passthrough);
+        assert_eq!(lookup(&fs, ctx), Ok(()));
+        assert_eq!(list(&fs, ctx, false).len(), 2);
+    }
+
+    #[ignore = "needs CAP_SYS_ADMIN to create a mount namespace"]
+    #[test]
+    fn test_bind_mount_submount() {
+        // The bind mount is only made in a mount namespace of the test's own.
+        std::thread::spawn(|| {
+            let ret = unsafe { libc::unshare(libc::CLONE_NEWNS) };
+            assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+            let root = CString::new("/").unwrap();
+            let ret = unsafe {
+                libc::mount(
+                    std::ptr::null(),
+                    root.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                )
+            };
+            assert_eq!(ret, 0);
+
+            let dir = TempDir::new().unwrap();
+            std::fs::create_dir(dir.as_path().join("source")).unwrap();
+            std::fs::create_dir(dir.as_path().join("target")).unwrap();
+            let source_path = CString::new(dir.as_path().join("source").to_str().unwrap()).unwrap();
+            let target_path = CString::new(dir.as_path().join("target").to_str().unwrap()).unwrap();
+            let ret = unsafe {
+                libc::mount(
+                    source_path.as_ptr(),
+                    target_path.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND,
+                    std::ptr::null(),
+                )
+            };
+            assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+
+            let ctx = Context {
+                uid: 0,
+                gid: 0,
+                pid: 0,
+            };
+            let has_mount_ids = mount_id(&File::open(dir.as_path()).unwrap()).unwrap() != 0;
+            for announce_submounts in [false, true] {
+                let fs = PassthroughFs::new(Config {
+                    root_dir: dir.as_path().to_str().unwrap().to_string(),
+                    announce_submounts,
+                    ..Default::default()
+                })
+                .unwrap();
+                fs.init(FsOptions::SUBMOUNTS).unwrap();
+
+                // Both directories are the same on the same device, only the mount tells them
+                // apart.
+                let source = fs
+                    .lookup(ctx, fuse::ROOT_ID, &CString::new("source").unwrap())
+                    .unwrap();
+                let target = fs
+                    .lookup(ctx, fuse::ROOT_ID, &CString::new("target").unwrap())
+                    .unwrap();
+                assert_eq!(source.attr.st_ino, target.attr.st_ino);
+                assert_eq!(source.attr.st_dev, target.attr.st_dev);
+                assert_ne!(source.inode, target.inode);
+                assert_eq!(source.attr_flags & fuse::ATTR_SUBMOUNT, 0);
+                // Even when the guest supports them, submounts are only announced if asked for.
+                if has_mount_ids {
+                    assert_eq!(
+                        target.attr_flags & fuse::ATTR_SUBMOUNT != 0,
+                        announce_submounts
+                    );
+                }
+            }
+
+            unsafe { libc::umount2(target_path.as_ptr(), libc::MNT_DETACH) };
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_inode_and_handle_limits() {
+        let (dir, fs, ctx, entry, handle) = create_file(Config {
+            max_inodes: NonZeroUsize::new(64),
+            max_handles: NonZeroUsize::new(4),
+            ..Default::default()
+        });
+        let names: Vec<CString> = (0..1000)
+            .map(|i| {
+                File::create(dir.as_path().join(format!("f{i}"))).unwrap();
+                CString::new(format!("f{i}")).unwrap()
+            })
+            .collect();
+
+        let open_files = || {
+            let open = fs
+                .open_inodes
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter(|data| data.file.read().unwrap().is_some())
+                .count();
+            assert!(open <= 64);
+            open
+        };
+
+        // Closing the files of inodes takes reopening them by handle, which takes
+        // `CAP_DAC_READ_SEARCH`. Without it they stay open, and only the limit itself is checked.
+        if fs.reopen_inodes.load(Ordering::Relaxed) {
+            let host_fds = || fs::read_dir("/proc/self/fd").unwrap().count();
+            // Other tests open files in parallel, leave them some room.
+            let max_host_fds = host_fds() + 64 + 32;
+
+            // Stat lots of files like a guest walking the tree would, forgetting the oldest ones
+            // past more than the limit of files: the lookups don't fail, the host fds stay bounded.
+            let mut referenced = VecDeque::new();
+            for (i, name) in names.iter().cycle().take(20_000).enumerate() {
+                let entry = fs.lookup(ctx, fuse::ROOT_ID, name).unwrap();
+                referenced.push_back((entry.inode, i % names.len()));
+                if referenced.len() > 200 {
+                    let (inode, _) = referenced.pop_front().unwrap();
+                    fs.forget(ctx, inode, 1);
+                }
+                open_files();
+                if i % 100 == 0 {
+                    assert!(host_fds() <= max_host_fds);
+                }
+            }
+            assert_eq!(fs.inodes.read().unwrap().len(), 202);
+
+            // The inodes whose files were closed are reopened as they are used.
+            for &(inode, i) in &referenced {
+                let (st, _) = fs.getattr(ctx, inode, None).unwrap();
+                let host = fs::metadata(dir.as_path().join(format!("f{i}"))).unwrap();
+                assert_eq!(st.st_ino, host.ino());
+                open_files();
+            }
+            assert!(host_fds() <= max_host_fds);
+            fs.batch_forget(ctx, referenced.drain(..).map(|(i, _)| (i, 1)).collect());
+        }
+
+        // Files unlinked on the host can't be reopened, so they are never closed: the lookups fail
+        // once only those are left open.
+        fs.getattr(ctx, entry.inode, None).unwrap();
+        fs::remove_file(dir.as_path().join("file")).unwrap();
+        let mut referenced = Vec::new();
+        for (i, name) in names[..62].iter().enumerate() {
+            referenced.push(fs.lookup(ctx, fuse::ROOT_ID, name).unwrap().inode);
+            fs::remove_file(dir.as_path().join(format!("f{i}"))).unwrap();
+        }
+        let err = fs.lookup(ctx, fuse::ROOT_ID, &names[62]).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENFILE));
+        assert_eq!(open_files(), 64);
+        fs.batch_forget(ctx, referenced.drain(..).map(|i| (i, 1)).collect());
+        // Only the root and `file` are left.
+        assert_eq!(fs.inodes.read().unwrap().len(), 2);
+
+        let open = || fs.open(ctx, entry.inode, libc::O_RDONLY as u32);
+        let handles: Vec<Handle> = (0..3).map(|_| open().unwrap().0.unwrap()).collect();
+        assert_eq!(open().unwrap_err().raw_os_error(), Some(libc::ENFILE));
+        // A file created past the limit doesn't leave its inode behind.
+        let err = fs
+            .create(
+                ctx,
+                fuse::ROOT_ID,
+                &CString::new("new").unwrap(),
+                0o644,
+                libc::O_RDWR as u32,
+                0,
+                Extensions::default(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENFILE));
+        assert_eq!(fs.inodes.read().unwrap().len(), 2);
+
+        fs.release(ctx, entry.inode, 0, handle, false, false, None)
+            .unwrap();
+        open().unwrap();
+        for handle in handles {
+            fs.release(ctx, entry.inode, 0, handle, false, false, None)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_save_references() {
+        let (dir, fs, ctx, entry, handle) = create_file(Config::default());
+        fs::create_dir(dir.as_path().join("dir")).unwrap();
+        let dir_entry = fs
+            .lookup(ctx, fuse::ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        let saved = fs.save_references().unwrap();
+
+        // A new instance takes over the inodes and the handle, for files at the same paths.
+        let restored = PassthroughFs::new(Config {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        restored.init(FsOptions::empty()).unwrap();
+        restored.restore_references(&saved).unwrap();
+        let (st, _) = restored.getattr(ctx, dir_entry.inode, None).unwrap();
+        assert_eq!(st.st_ino, dir_entry.attr.st_ino);
+        write(&restored, ctx, &entry, handle, b"restored", 0);
+        assert_eq!(fs::read(dir.as_path().join("file")).unwrap(), b"restored");
+        // New inodes and handles don't take the numbers of the restored ones.
+        let (other, other_handle, _) = restored
+            .create(
+                ctx,
+                fuse::ROOT_ID,
+                &CString::new("other").unwrap(),
+                0o644,
+                libc::O_RDWR as u32,
+                0,
+                Extensions::default(),
+            )
+            .unwrap();
+        assert!(other.inode > dir_entry.inode.max(entry.inode));
+        assert!(other_handle.unwrap() > handle);
+        // Truncated data is refused.
+        assert!(restored
+            .restore_references(&saved[..saved.len() - 1])
+            .is_err());
+
+        // A file unlinked since it was looked up can't be opened again.
+        fs::remove_file(dir.as_path().join("file")).unwrap();
+        let err = fs.save_references().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+    }
+
+    #[test]
+    fn test_copyfilerange() {
+        let (dir, fs, ctx, entry, handle) = create_file(Config::default());
+        let contents: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        write(&fs, ctx, &entry, handle, &contents, 0);
+        let (copy, copy_handle, _) = fs
+            .create(
+                ctx,
+                fuse::ROOT_ID,
+                &CString::new("copy").unwrap(),
+                0o644,
+                libc::O_RDWR as u32,
+                0,
+                Extensions::default(),
+            )
+            .unwrap();
+        let copy_handle = copy_handle.unwrap();
+
+        // Copies stop at the end of the source.
+        let copied = fs
+            .copyfilerange(
+                ctx,
+                entry.inode,
+                handle,
+                100,
+                copy.inode,
+                copy_handle,
+                0,
+                1 << 20,
+                0,
+            )
+            .unwrap();
+        assert_eq!(copied, contents.len() - 100);
+        let copy_path = dir.as_path().join("copy");
+        assert_eq!(std::fs::read(&copy_path).unwrap(), &contents[100..]);
+
+        let err = fs
+            .copyfilerange(
+                ctx,
+                entry.inode,
+                handle,
+                0,
+                copy.inode,
+                copy_handle,
+                0,
+                1,
+                1,
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        // The fallback for copies across file systems does the same.
+        let src = std::fs::File::open(dir.as_path().join("file")).unwrap();
+        let dst = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&copy_path)
+            .unwrap();
+        let copied = copy::copy_range(src.as_raw_fd(), 0, dst.as_raw_fd(), 5000, 1 << 20).unwrap();
+        assert_eq!(copied, contents.len());
+        assert_eq!(std::fs::read(&copy_path).unwrap()[5000..], contents[..]);
+
+        // Appending writes would land at the end rather than at the offset asked for.
+        let dst = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&copy_path)
+            .unwrap();
+        let err = copy::copy_range(src.as_raw_fd(), 0, dst.as_raw_fd(), 0, 1).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn test_rename_flags() {
+        let (dir, fs, ctx, entry, handle) = create_file(Config::default());
+        write(&fs, ctx, &entry, handle, b"file", 0);
+        fs::write(dir.as_path().join("other"), b"other").unwrap();
+        let file = CString::new("file").unwrap();
+        let other = CString::new("other").unwrap();
+        let rename =
+            |flags: u32| fs.rename(ctx, fuse::ROOT_ID, &file, fuse::ROOT_ID, &other, flags);
+
+        let err = rename(bindings::LINUX_RENAME_NOREPLACE as u32).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        assert_eq!(fs::read(dir.as_path().join("file")).unwrap(), b"file");
+        assert_eq!(fs::read(dir.as_path().join("other")).unwrap(), b"other");
+
+        rename(bindings::LINUX_RENAME_EXCHANGE as u32).unwrap();
+        assert_eq!(fs::read(dir.as_path().join("file")).unwrap(), b"other");
+        assert_eq!(fs::read(dir.as_path().join("other")).unwrap(), b"file");
+    }
+
+    #[test]
+    fn test_ioctl() {
+        let (dir, fs, ctx, entry, handle) = create_file(Config {
+            allowed_ioctls: vec![libc::FS_IOC_GETFLAGS as u32, libc::FIONREAD as u32],
+            ..Default::default()
+        });
+        write(&fs, ctx, &entry, handle, &[0; 100], 0);
+        let ioctl = |cmd, arg, out_size| {
+            fs.ioctl(ctx, entry.inode, handle, 0, cmd as u32, arg, &[], out_size)
+        };
+
+        // The argument buffer is passed through.
+        let (res, flags) = ioctl(libc::FS_IOC_GETFLAGS, 0, 8).unwrap();
+        assert_eq!(res, 0);
+        let file = File::open(dir.as_path().join("file")).unwrap();
+        let mut host_flags = 0u64;
+        // Safe because this will only modify `host_flags`.
+        assert_eq!(
+            unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut host_flags) },
+            0
+        );
+        assert_eq!(flags, host_flags.to_ne_bytes());
+
+        // An ioctl without an argument buffer doesn't get the guest's argument, whatever it
+        // would be as a host address: FIONREAD would write the size of the file there.
+        let mut sentinel = 0xdead_u32;
+        let (res, out) = ioctl(libc::FIONREAD, &mut sentinel as *mut u32 as u64, 0).unwrap();
+        assert_eq!(res, 0);
+        assert!(out.is_empty());
+        assert_eq!(sentinel, 0xdead);
+
+        let err = ioctl(libc::FS_IOC_SETFLAGS, 0, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+    }
+
+    // Reads the xattr `name` of `path` on the host, bypassing the file system.
+    fn host_xattr(path: &std::path::Path, name: &str) -> Option<Vec<u8>> {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new(name).unwrap();
+        let mut buf = vec![0; 256];
+        // Safe because this will only modify the contents of `buf`.
+        let res = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        (res >= 0).then(|| {
+            buf.truncate(res as usize);
+            buf
+        })
+    }
+
+    #[test]
+    fn test_security_xattr_prefix() {
+        let (dir, fs, ctx, entry, _) = create_file(Config {
+            security_xattr_prefix: Some("user.virtiofs.".to_string()),
+            ..Default::default()
+        });
+        let path = dir.as_path().join("file");
+        let name = |name: &str| CString::new(name).unwrap();
+
+        // The guest's label round-trips, stored under the prefix on the host.
+        fs.setxattr(ctx, entry.inode, &name("security.selinux"), b"label", 0)
+            .unwrap();
+        let Ok(GetxattrReply::Value(value)) =
+            fs.getxattr(ctx, entry.inode, &name("security.selinux"), 64)
+        else {
+            panic!("getxattr failed");
+        };
+        assert_eq!(value, b"label");
+        assert_eq!(
+            host_xattr(&path, "user.virtiofs.security.selinux").unwrap(),
+            b"label"
+        );
+
+        // The prefixed names are only reachable through the mapping.
+        let err = fs
+            .setxattr(ctx, entry.inode, &name("user.virtiofs.x"), b"x", 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        let err = fs
+            .getxattr(
+                ctx,
+                entry.inode,
+                &name("user.virtiofs.security.selinux"),
+                64,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+
+        fs.setxattr(ctx, entry.inode, &name("user.plain"), b"plain", 0)
+            .unwrap();
+        let Ok(ListxattrReply::Names(names)) = fs.listxattr(ctx, entry.inode, 256) else {
+            panic!("listxattr failed");
+        };
+        assert_eq!(names, b"security.selinux\0user.plain\0");
+    }
+
+    #[test]
+    fn test_xattr_map() {
+        let (dir, fs, ctx, entry, _) = create_file(Config {
+            xattr_map: vec![XattrMapping {
+                guest_prefix: "user.guest.".to_string(),
+                host_prefix: "user.host.".to_string(),
+            }],
+            ..Default::default()
+        });
+        let path = dir.as_path().join("file");
+        let name = |name: &str| CString::new(name).unwrap();
+        let get = |n: &str| match fs.getxattr(ctx, entry.inode, &name(n), 64) {
+            Ok(GetxattrReply::Value(value)) => Ok(value),
+            Ok(GetxattrReply::Count(_)) => unreachable!(),
+            Err(e) => Err(e.raw_os_error()),
+        };
+
+        // Set then get through the mapping.
+        fs.setxattr(ctx, entry.inode, &name("user.guest.label"), b"mapped", 0)
+            .unwrap();
+        assert_eq!(get("user.guest.label").unwrap(), b"mapped");
+        assert_eq!(host_xattr(&path, "user.host.label").unwrap(), b"mapped");
+        assert_eq!(host_xattr(&path, "user.guest.label"), None);
+
+        // Names matching no rule are passed through.
+        fs.setxattr(ctx, entry.inode, &name("user.plain"), b"plain", 0)
+            .unwrap();
+        assert_eq!(get("user.plain").unwrap(), b"plain");
+        assert_eq!(host_xattr(&path, "user.plain").unwrap(), b"plain");
+
+        // The host prefix is only reachable through the mapping.
+        assert_eq!(get("user.host.label"), Err(Some(libc::EPERM)));
+
+        let Ok(ListxattrReply::Names(names)) = fs.listxattr(ctx, entry.inode, 256) else {
+            panic!("listxattr failed");
+        };
+        assert_eq!(names, b"user.guest.label\0user.plain\0");
+    }
+
+    #[test]
+    fn test_setupmapping() {
+        let (_dir, fs, ctx, entry, handle) = create_file(Config::default());
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let contents = vec![0xa5; 2 * page_size as usize];
+        write(&fs, ctx, &entry, handle, &contents, 0);
+
+        // Stands in for the DAX window, four pages of anonymous memory.
+        let shm_size = 4 * page_size;
+        let shm = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                shm_size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(shm, libc::MAP_FAILED);
+        let shm_base = shm as u64;
+        let window = |offset: u64| unsafe { *((shm_base + offset) as *const u8) };
+
+        // Mappings must not go past the end of the window, even by wrapping around.
+        for (moffset, len) in [(3 * page_size, 2 * page_size), (page_size, u64::MAX)] {
+            let err = fs
+                .setupmapping(
+                    ctx,
+                    entry.inode,
+                    handle,
+                    0,
+                    len,
+                    0,
+                    moffset,
+                    shm_base,
+                    shm_size,
+                )
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        }
+        let remove_past_end = vec![fuse::RemovemappingOne {
+            moffset: 3 * page_size,
+            len: 2 * page_size,
+        }];
+        let err = fs
+            .removemapping(ctx, remove_past_end, shm_base, shm_size)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        // The second page of the file shows up in the second page of the window.
+        fs.setupmapping(
+            ctx,
+            entry.inode,
+            handle,
+            page_size,
+            page_size,
+            0,
+            page_size,
+            shm_base,
+            shm_size,
+        )
+        .unwrap();
+        assert_eq!(window(page_size), 0xa5);
+        assert_eq!(window(0), 0);
+
+        let remove = vec![fuse::RemovemappingOne {
+            moffset: page_size,
+            len: page_size,
+        }];
+        fs.removemapping(ctx, remove, shm_base, shm_size).unwrap();
+
+        unsafe { libc::munmap(shm, shm_size as usize) };
+    }
+}
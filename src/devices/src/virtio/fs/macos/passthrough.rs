@@ -10,21 +10,25 @@ use std::io;
 #[cfg(not(feature = "efi"))]
 use std::mem;
 use std::mem::MaybeUninit;
+use std::num::NonZeroUsize;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
+use crossbeam_channel::{unbounded, Sender};
+use hvf::MemoryMapping;
 use vm_memory::ByteValued;
 
 use crate::virtio::fs::filesystem::SecContext;
 
 use super::super::super::linux_errno::{linux_error, LINUX_ERANGE};
 use super::super::bindings;
+use super::super::copy;
 use super::super::filesystem::{
     Context, DirEntry, Entry, Extensions, FileSystem, FsOptions, GetxattrReply, ListxattrReply,
-    OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    OpenOptions, SetattrValid, SpecialFilePolicy, XattrMapping, ZeroCopyReader, ZeroCopyWriter,
 };
 use super::super::fuse;
 
@@ -33,6 +37,10 @@ const XATTR_KEY: &[u8] = b"user.containers.override_stat\0";
 
 const UID_MAX: u32 = u32::MAX - 1;
 
+// `whence` values of the guest's `lseek`.
+const LINUX_SEEK_DATA: u32 = 3;
+const LINUX_SEEK_HOLE: u32 = 4;
+
 #[cfg(not(feature = "efi"))]
 static INIT_BINARY: &[u8] = include_bytes!("../../../../../../init/init");
 
@@ -236,6 +244,20 @@ fn set_xattr_stat(file: StatFile, owner: Option<(u32, u32)>, mode: Option<u32>)
     }
 }
 
+// Overrides the owner and mode in `st` with the ones stored in the extended attributes of `file`.
+fn apply_xattr_stat(st: &mut bindings::stat64, file: StatFile) -> io::Result<()> {
+    if let Some((uid, gid, mode)) = get_xattr_stat(file)? {
+        st.st_uid = uid;
+        st.st_gid = gid;
+        if mode as u16 & libc::S_IFMT == 0 {
+            st.st_mode = (st.st_mode & libc::S_IFMT) | mode as u16;
+        } else {
+            st.st_mode = mode as u16;
+        }
+    }
+    Ok(())
+}
+
 fn fstat(fd: RawFd, host: bool) -> io::Result<bindings::stat64> {
     let mut st = MaybeUninit::<bindings::stat64>::zeroed();
 
@@ -247,15 +269,7 @@ fn fstat(fd: RawFd, host: bool) -> io::Result<bindings::stat64> {
         let mut st = unsafe { st.assume_init() };
 
         if !host {
-            if let Some((uid, gid, mode)) = get_xattr_stat(StatFile::Fd(fd))? {
-                st.st_uid = uid;
-                st.st_gid = gid;
-                if mode as u16 & libc::S_IFMT == 0 {
-                    st.st_mode = (st.st_mode & libc::S_IFMT) | mode as u16;
-                } else {
-                    st.st_mode = mode as u16;
-                }
-            }
+            apply_xattr_stat(&mut st, StatFile::Fd(fd))?;
         }
 
         Ok(st)
@@ -275,15 +289,7 @@ fn lstat(c_path: &CString, host: bool) -> io::Result<bindings::stat64> {
         let mut st = unsafe { st.assume_init() };
 
         if !host {
-            if let Some((uid, gid, mode)) = get_xattr_stat(StatFile::Path(c_path))? {
-                st.st_uid = uid;
-                st.st_gid = gid;
-                if mode as u16 & libc::S_IFMT == 0 {
-                    st.st_mode = (st.st_mode & libc::S_IFMT) | mode as u16;
-                } else {
-                    st.st_mode = mode as u16;
-                }
-            }
+            apply_xattr_stat(&mut st, StatFile::Path(c_path))?;
         }
 
         Ok(st)
@@ -292,10 +298,15 @@ fn lstat(c_path: &CString, host: bool) -> io::Result<bindings::stat64> {
     }
 }
 
+// Converts the type of a directory entry (`DT_*`) to the matching mode bits, like `DTTOIF`.
+fn dirent_type_to_mode(d_type: u32) -> libc::mode_t {
+    (d_type << 12) as libc::mode_t
+}
+
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
 /// protocol uses close-to-open consistency. This means that any cached contents of the file are
 /// invalidated the next time that file is opened.
-#[derive(Debug, Default, Clone)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum CachePolicy {
     /// The client should never cache file data and all I/O should be directly forwarded to the
     /// server. This policy must be selected when file contents may change without the knowledge of
@@ -328,6 +339,9 @@ impl FromStr for CachePolicy {
 }
 
 /// Options that configure the behavior of the file system.
+///
+/// `announce_submounts`, `security_xattr_prefix`, `xattr_map` and `allowed_ioctls` mirror the
+/// Linux options so a share is configured the same way on both hosts, but have no effect here.
 #[derive(Debug, Clone)]
 pub struct Config {
     /// How long the FUSE client should consider directory entries to be valid. If the contents of a
@@ -381,6 +395,92 @@ pub struct Config {
     ///
     /// The default is `None`.
     pub proc_sfd_rawfd: Option<RawFd>,
+
+    /// Whether the file system should announce submounts to the guest by setting
+    /// `FUSE_ATTR_SUBMOUNT` on directories that live on a different device than their parent.
+    /// This only takes effect if the guest kernel advertises `FUSE_SUBMOUNTS`.
+    ///
+    /// The default value for this option is `false`.
+    pub announce_submounts: bool,
+
+    /// Prefix under which guest `security.` extended attributes are stored on the host.
+    ///
+    /// The default is `None`.
+    pub security_xattr_prefix: Option<String>,
+
+    /// Rules renaming the extended attributes between the guest and the host.
+    ///
+    /// The default is empty.
+    pub xattr_map: Vec<XattrMapping>,
+
+    /// Ioctl request numbers the guest may issue on open files.
+    ///
+    /// The default is empty.
+    pub allowed_ioctls: Vec<u32>,
+
+    /// How the device nodes (character and block devices) found in the root directory are exposed
+    /// to the guest. Passing them through lets the guest open the host devices they refer to.
+    /// The nodes the guest creates itself with `mknod` are regular files on the host, which this
+    /// policy doesn't apply to.
+    ///
+    /// The default is `SpecialFilePolicy::Skip`.
+    pub device_policy: SpecialFilePolicy,
+
+    /// How the FIFOs and Unix sockets found in the root directory are exposed to the guest.
+    ///
+    /// The default is `SpecialFilePolicy::Passthrough`.
+    pub fifo_socket_policy: SpecialFilePolicy,
+
+    /// Whether the files are read and written without going through the host page cache, as if
+    /// the guest opened all of them with `O_DIRECT`. macOS has no alignment requirements for
+    /// uncached I/O.
+    ///
+    /// The default value for this option is `false`.
+    pub direct_io: bool,
+
+    /// Where the DAX window lies in the guest, required for the guest to map files with
+    /// `FUSE_SETUPMAPPING`.
+    ///
+    /// The default value for this option is `None`.
+    pub dax_window: Option<DaxWindow>,
+
+    /// The maximum number of files and directories the guest may have open at once, each keeping
+    /// a host file descriptor open. Past the limit opening one more fails with `ENFILE` until the
+    /// guest releases others.
+    ///
+    /// The default is `None`, which doesn't limit the handles.
+    pub max_handles: Option<NonZeroUsize>,
+}
+
+/// Hypervisor side of the DAX window. Unlike KVM, HVF doesn't follow the changes to the host
+/// mappings backing the guest memory, so every mapping change is applied to the guest by the VMM
+/// thread.
+#[derive(Debug, Clone)]
+pub struct DaxWindow {
+    /// Guest physical address of the start of the window.
+    pub guest_addr: u64,
+    /// Channel to the VMM thread.
+    pub map_sender: Sender<MemoryMapping>,
+}
+
+impl DaxWindow {
+    // Makes the guest see the host pages now at `host_addr`, backing `len` bytes at `moffset` in
+    // the window.
+    fn map(&self, host_addr: u64, moffset: u64, len: u64) -> io::Result<()> {
+        let (reply_sender, reply_receiver) = unbounded();
+        self.map_sender
+            .send(MemoryMapping::AddMapping(
+                reply_sender,
+                host_addr,
+                self.guest_addr + moffset,
+                len,
+            ))
+            .map_err(|_| linux_error(io::Error::from_raw_os_error(libc::EIO)))?;
+        if !reply_receiver.recv().unwrap_or(false) {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EIO)));
+        }
+        Ok(())
+    }
 }
 
 impl Default for Config {
@@ -393,6 +493,15 @@ impl Default for Config {
             root_dir: String::from("/"),
             xattr: true,
             proc_sfd_rawfd: None,
+            announce_submounts: false,
+            security_xattr_prefix: None,
+            xattr_map: Vec::new(),
+            allowed_ioctls: Vec::new(),
+            device_policy: SpecialFilePolicy::Skip,
+            fifo_socket_policy: SpecialFilePolicy::Passthrough,
+            direct_io: false,
+            dax_window: None,
+            max_handles: None,
         }
     }
 }
@@ -518,9 +627,23 @@ impl PassthroughFs {
         Ok(unsafe { File::from_raw_fd(fd) })
     }
 
+    // Returns the policy applying to files of the type in `mode`.
+    fn special_file_policy(&self, mode: libc::mode_t) -> SpecialFilePolicy {
+        match mode & libc::S_IFMT {
+            libc::S_IFCHR | libc::S_IFBLK => self.cfg.device_policy,
+            libc::S_IFIFO | libc::S_IFSOCK => self.cfg.fifo_socket_policy,
+            _ => SpecialFilePolicy::Passthrough,
+        }
+    }
+
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
         let c_path = self.name_to_path(parent, name)?;
-        let st = lstat(&c_path, false)?;
+        // The policy applies to the file type on the host, not the one the guest may have set.
+        let mut st = lstat(&c_path, true)?;
+        if let Some(e) = self.special_file_policy(st.st_mode).lookup_error() {
+            return Err(e);
+        }
+        apply_xattr_stat(&mut st, StatFile::Path(&c_path))?;
 
         debug!(
             "do_lookup: inode={} path={}",
@@ -532,6 +655,7 @@ impl PassthroughFs {
             inode: st.st_ino,
             generation: 0,
             attr: st,
+            attr_flags: 0,
             attr_timeout: self.cfg.attr_timeout,
             entry_timeout: self.cfg.entry_timeout,
         })
@@ -601,6 +725,13 @@ impl PassthroughFs {
                 continue;
             }
 
+            let d_type = unsafe { (*dentry).d_type };
+            if self.special_file_policy(dirent_type_to_mode(u32::from(d_type)))
+                == SpecialFilePolicy::Skip
+            {
+                continue;
+            }
+
             let res = unsafe {
                 add_entry(DirEntry {
                     ino: (*dentry).d_ino,
@@ -631,22 +762,40 @@ impl PassthroughFs {
         Ok(())
     }
 
+    // Tracks `data` under a new handle, unless the guest already has `Config::max_handles` open.
+    fn insert_handle(&self, data: HandleData) -> io::Result<Handle> {
+        let mut handles = self.handles.write().unwrap();
+        if self
+            .cfg
+            .max_handles
+            .is_some_and(|max| handles.len() >= max.get())
+        {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::ENFILE)));
+        }
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        handles.insert(handle, Arc::new(data));
+        Ok(handle)
+    }
+
     fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
-        let flags = self.parse_open_flags(flags as i32);
+        let direct = (flags as i32 & bindings::LINUX_O_DIRECT) != 0;
+        let flags = self.parse_open_flags(flags as i32)?;
+        let direct = direct || (self.cfg.direct_io && flags & libc::O_DIRECTORY == 0);
 
-        let file = RwLock::new(self.open_inode(inode, flags)?);
+        let file = self.open_inode(inode, flags)?;
+        if direct {
+            set_nocache(&file)?;
+        }
+        let file = RwLock::new(file);
 
-        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-        let data = HandleData {
+        let handle = self.insert_handle(HandleData {
             inode,
             file,
             dirstream: Mutex::new(DirStream {
                 stream: 0,
                 offset: 0,
             }),
-        };
-
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        })?;
 
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
@@ -713,7 +862,14 @@ impl PassthroughFs {
         }
     }
 
-    fn parse_open_flags(&self, flags: i32) -> i32 {
+    // Translates Linux open flags to their macOS equivalents. macOS can't open files without
+    // updating their access time, so `O_NOATIME` fails with `EPERM`, which is also what Linux
+    // returns when it can't honor the flag. Callers using it (e.g. tar) already retry without it.
+    fn parse_open_flags(&self, flags: i32) -> io::Result<i32> {
+        if (flags & bindings::LINUX_O_NOATIME) != 0 {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EPERM)));
+        }
+
         let mut mflags: i32 = flags & 0b11;
 
         if (flags & bindings::LINUX_O_NONBLOCK) != 0 {
@@ -737,8 +893,25 @@ impl PassthroughFs {
         if (flags & bindings::LINUX_O_CLOEXEC) != 0 {
             mflags |= libc::O_CLOEXEC;
         }
+        // `LINUX_O_SYNC` includes the `LINUX_O_DSYNC` bit.
+        if (flags & bindings::LINUX_O_SYNC) == bindings::LINUX_O_SYNC {
+            mflags |= libc::O_SYNC;
+        } else if (flags & bindings::LINUX_O_DSYNC) != 0 {
+            mflags |= libc::O_DSYNC;
+        }
+
+        Ok(mflags)
+    }
+}
 
-        mflags
+// macOS has no `O_DIRECT`, but disabling the unified buffer cache for the file has the same effect.
+fn set_nocache(file: &File) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) };
+    if res < 0 {
+        Err(linux_error(io::Error::last_os_error()))
+    } else {
+        Ok(())
     }
 }
 
@@ -796,6 +969,15 @@ impl FileSystem for PassthroughFs {
         self.handles.write().unwrap().clear();
     }
 
+    // Inodes are the ones of the host files, only the handles would be lost.
+    fn save_references(&self) -> io::Result<Vec<u8>> {
+        if self.handles.read().unwrap().is_empty() {
+            Ok(Vec::new())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+        }
+    }
+
     fn statfs(&self, _ctx: Context, inode: Inode) -> io::Result<bindings::statvfs64> {
         let mut out = MaybeUninit::<bindings::statvfs64>::zeroed();
 
@@ -826,6 +1008,7 @@ impl FileSystem for PassthroughFs {
                 inode: self.init_inode,
                 generation: 0,
                 attr: st,
+                attr_flags: 0,
                 attr_timeout: self.cfg.attr_timeout,
                 entry_timeout: self.cfg.entry_timeout,
             })
@@ -935,6 +1118,23 @@ impl FileSystem for PassthroughFs {
         F: FnMut(DirEntry, Entry) -> io::Result<usize>,
     {
         self.do_readdir(inode, handle, size, offset, |dir_entry| {
+            // Entries without a node ID are listed but not looked up by the guest, which then
+            // gets the policy's error.
+            if self.special_file_policy(dirent_type_to_mode(dir_entry.type_))
+                == SpecialFilePolicy::Error
+            {
+                let entry = Entry {
+                    inode: 0,
+                    generation: 0,
+                    // Safe because `stat64` is plain data, for which all zeroes is a valid value.
+                    attr: unsafe { mem::zeroed() },
+                    attr_flags: 0,
+                    attr_timeout: Duration::ZERO,
+                    entry_timeout: Duration::ZERO,
+                };
+                return add_entry(dir_entry, entry);
+            }
+
             // Safe because the kernel guarantees that the buffer is nul-terminated. Additionally,
             // the kernel will pad the name with '\0' bytes up to 8-byte alignment and there's no
             // way for us to know exactly how many padding bytes there are. This would cause
@@ -986,7 +1186,8 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
         let c_path = self.name_to_path(parent, name)?;
 
-        let flags = self.parse_open_flags(flags as i32);
+        let direct = (flags as i32 & bindings::LINUX_O_DIRECT) != 0 || self.cfg.direct_io;
+        let flags = self.parse_open_flags(flags as i32)?;
         let hostmode = if (flags & libc::O_DIRECTORY) != 0 {
             0o700
         } else {
@@ -1022,21 +1223,22 @@ impl FileSystem for PassthroughFs {
         };
 
         // Safe because we just opened this fd.
-        let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
+        let file = unsafe { File::from_raw_fd(fd) };
+        if direct {
+            set_nocache(&file)?;
+        }
+        let file = RwLock::new(file);
 
         let entry = self.do_lookup(parent, name)?;
 
-        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-        let data = HandleData {
+        let handle = self.insert_handle(HandleData {
             inode: entry.inode,
             file,
             dirstream: Mutex::new(DirStream {
                 stream: 0,
                 offset: 0,
             }),
-        };
-
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        })?;
 
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
@@ -1292,7 +1494,12 @@ impl FileSystem for PassthroughFs {
 
             Ok(())
         } else {
-            Err(linux_error(io::Error::last_os_error()))
+            let err = io::Error::last_os_error();
+            // Like renameat2, report flags the host filesystem doesn't support as invalid.
+            if mflags != 0 && err.raw_os_error() == Some(libc::ENOTSUP) {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
+            }
+            Err(linux_error(err))
         }
     }
 
@@ -1460,8 +1667,18 @@ impl FileSystem for PassthroughFs {
 
         let fd = data.file.write().unwrap().as_raw_fd();
 
+        // On macOS, fsync only hands the data to the drive, which may keep it in its volatile
+        // cache. Linux guarantees the data is on stable storage, which takes `F_FULLFSYNC` here.
+        // It covers `fdatasync` semantics too. Not every file system supports it (e.g. network
+        // file systems), so fall back to a plain fsync like other applications do.
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::fsync(fd) };
+        let res = unsafe {
+            if libc::fcntl(fd, libc::F_FULLFSYNC) == 0 {
+                0
+            } else {
+                libc::fsync(fd)
+            }
+        };
 
         if res == 0 {
             Ok(())
@@ -1555,6 +1772,7 @@ impl FileSystem for PassthroughFs {
 
         let c_path = self.inode_to_path(inode)?;
 
+        // The guest kernel already resolved symlinks, so `inode` itself is the target.
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
             libc::setxattr(
@@ -1563,7 +1781,7 @@ impl FileSystem for PassthroughFs {
                 value.as_ptr() as *const libc::c_void,
                 value.len(),
                 0,
-                mflags as libc::c_int,
+                mflags as libc::c_int | libc::XATTR_NOFOLLOW,
             )
         };
         if res == 0 {
@@ -1607,7 +1825,7 @@ impl FileSystem for PassthroughFs {
                     std::ptr::null_mut(),
                     size as libc::size_t,
                     0,
-                    0,
+                    libc::XATTR_NOFOLLOW,
                 )
             } else {
                 libc::getxattr(
@@ -1616,7 +1834,7 @@ impl FileSystem for PassthroughFs {
                     buf.as_mut_ptr() as *mut libc::c_void,
                     size as libc::size_t,
                     0,
-                    0,
+                    libc::XATTR_NOFOLLOW,
                 )
             }
         };
@@ -1637,22 +1855,45 @@ impl FileSystem for PassthroughFs {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
         }
 
-        let mut buf = vec![0; 512_usize];
-
         let c_path = self.inode_to_path(inode)?;
 
-        // Safe because this will only modify the contents of `buf`.
-        let res = unsafe {
-            libc::listxattr(
-                c_path.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                512,
-                0,
-            )
+        // We have to filter out our own attribute, so always fetch the full list, however long it
+        // is. Retry if attributes get added between sizing the buffer and filling it.
+        let (mut buf, res) = loop {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let len = unsafe {
+                libc::listxattr(
+                    c_path.as_ptr(),
+                    std::ptr::null_mut(),
+                    0,
+                    libc::XATTR_NOFOLLOW,
+                )
+            };
+            if len < 0 {
+                return Err(linux_error(io::Error::last_os_error()));
+            }
+            if len == 0 {
+                break (Vec::new(), 0);
+            }
+
+            let mut buf = vec![0; len as usize];
+            // Safe because this will only modify the contents of `buf`.
+            let res = unsafe {
+                libc::listxattr(
+                    c_path.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf.len(),
+                    libc::XATTR_NOFOLLOW,
+                )
+            };
+            if res >= 0 {
+                break (buf, res);
+            }
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ERANGE) {
+                return Err(linux_error(err));
+            }
         };
-        if res < 0 {
-            return Err(linux_error(io::Error::last_os_error()));
-        }
 
         buf.truncate(res as usize);
 
@@ -1702,7 +1943,8 @@ impl FileSystem for PassthroughFs {
         let c_path = self.inode_to_path(inode)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::removexattr(c_path.as_ptr(), name.as_ptr(), 0) };
+        let res =
+            unsafe { libc::removexattr(c_path.as_ptr(), name.as_ptr(), libc::XATTR_NOFOLLOW) };
 
         if res == 0 {
             Ok(())
@@ -1774,26 +2016,195 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        // SEEK_DATA and SEEK_HOLE have slightly different semantics
-        // in Linux vs. macOS, which means we can't support them.
-        let mwhence = if whence == 3 {
-            // SEEK_DATA
-            return Ok(offset);
-        } else if whence == 4 {
-            // SEEK_HOLE
-            libc::SEEK_END
-        } else {
-            whence as i32
+        // The guest kernel moves the offsets of its files itself, it only asks for the data and
+        // holes of sparse files. The values of SEEK_DATA and SEEK_HOLE are swapped on macOS.
+        let mwhence = match whence {
+            LINUX_SEEK_DATA => libc::SEEK_DATA,
+            LINUX_SEEK_HOLE => libc::SEEK_HOLE,
+            _ => return Err(einval()),
         };
 
         let fd = data.file.write().unwrap().as_raw_fd();
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::lseek(fd, offset as bindings::off64_t, mwhence as libc::c_int) };
-        if res < 0 {
-            Err(linux_error(io::Error::last_os_error()))
+        let res = unsafe { libc::lseek(fd, offset as bindings::off64_t, mwhence) };
+        if res >= 0 {
+            return Ok(res as u64);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            return Err(linux_error(err));
+        }
+
+        // File systems without sparse files refuse both, treat their files as data followed by
+        // the hole at their end, failing with ENXIO past it as Linux does.
+        let size = fstat(fd, true)?.st_size as u64;
+        if offset >= size {
+            Err(linux_error(io::Error::from_raw_os_error(libc::ENXIO)))
+        } else if whence == LINUX_SEEK_DATA {
+            Ok(offset)
+        } else {
+            Ok(size)
+        }
+    }
+
+    fn copyfilerange(
+        &self,
+        _ctx: Context,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        let data_in = self
+            .handles
+            .read()
+            .unwrap()
+            .get(&handle_in)
+            .filter(|hd| hd.inode == inode_in)
+            .cloned()
+            .ok_or_else(ebadf)?;
+        let data_out = self
+            .handles
+            .read()
+            .unwrap()
+            .get(&handle_out)
+            .filter(|hd| hd.inode == inode_out)
+            .cloned()
+            .ok_or_else(ebadf)?;
+        if flags != 0 {
+            return Err(einval());
+        }
+
+        // There's no copy_file_range on macOS, but copying in the host still spares the guest
+        // a round trip of the data through the queues.
+        let fd_in = data_in.file.read().unwrap().as_raw_fd();
+        let fd_out = data_out.file.read().unwrap().as_raw_fd();
+        copy::copy_range(fd_in, offset_in, fd_out, offset_out, len as usize).map_err(linux_error)
+    }
+
+    fn setupmapping(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        host_shm_base: u64,
+        shm_size: u64,
+    ) -> io::Result<()> {
+        let Some(dax_window) = &self.cfg.dax_window else {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
+        };
+
+        // The guest picks both, make sure the mapping stays within the DAX window.
+        if moffset.checked_add(len).is_none_or(|end| end > shm_size) {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
+        }
+
+        let addr = host_shm_base + moffset;
+
+        debug!("setupmapping: ino {:?} addr={:x} len={}", inode, addr, len);
+
+        #[cfg(not(feature = "efi"))]
+        if inode == self.init_inode {
+            let ret = unsafe {
+                libc::mmap(
+                    addr as *mut libc::c_void,
+                    len as usize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_FIXED,
+                    -1,
+                    0,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                return Err(linux_error(io::Error::last_os_error()));
+            }
+
+            let off = foffset.min(INIT_BINARY.len() as u64) as usize;
+            let to_copy = (len as usize).min(INIT_BINARY.len() - off);
+            unsafe {
+                libc::memcpy(
+                    addr as *mut libc::c_void,
+                    INIT_BINARY[off..].as_ptr() as *const _,
+                    to_copy,
+                )
+            };
+            return dax_window.map(addr, moffset, len);
+        }
+
+        let (open_flags, prot_flags) = if (flags & fuse::SetupmappingFlags::WRITE.bits()) != 0 {
+            (libc::O_RDWR, libc::PROT_READ | libc::PROT_WRITE)
         } else {
-            Ok(res as u64)
+            (libc::O_RDONLY, libc::PROT_READ)
+        };
+
+        // The mapping keeps its own reference to the file, which can be closed right away.
+        let file = self.open_inode(inode, open_flags)?;
+
+        let ret = unsafe {
+            libc::mmap(
+                addr as *mut libc::c_void,
+                len as usize,
+                prot_flags,
+                libc::MAP_SHARED | libc::MAP_FIXED,
+                file.as_raw_fd(),
+                foffset as libc::off_t,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(linux_error(io::Error::last_os_error()));
         }
+
+        dax_window.map(addr, moffset, len)
+    }
+
+    fn removemapping(
+        &self,
+        _ctx: Context,
+        requests: Vec<fuse::RemovemappingOne>,
+        host_shm_base: u64,
+        shm_size: u64,
+    ) -> io::Result<()> {
+        let Some(dax_window) = &self.cfg.dax_window else {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
+        };
+
+        for req in requests {
+            if req
+                .moffset
+                .checked_add(req.len)
+                .is_none_or(|end| end > shm_size)
+            {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
+            }
+            let addr = host_shm_base + req.moffset;
+            debug!("removemapping: addr={:x} len={:?}", addr, req.len);
+
+            // Put anonymous memory back in the window, dropping the file pages.
+            let ret = unsafe {
+                libc::mmap(
+                    addr as *mut libc::c_void,
+                    req.len as usize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_ANON | libc::MAP_PRIVATE | libc::MAP_FIXED,
+                    -1,
+                    0,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                return Err(linux_error(io::Error::last_os_error()));
+            }
+            dax_window.map(addr, req.moffset, req.len)?;
+        }
+
+        Ok(())
     }
 }
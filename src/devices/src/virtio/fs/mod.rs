@@ -1,3 +1,4 @@
+mod copy;
 mod device;
 #[allow(dead_code)]
 mod filesystem;
@@ -5,6 +6,7 @@ pub mod fuse;
 #[allow(dead_code)]
 mod multikey;
 mod server;
+mod stats;
 mod worker;
 
 #[cfg(target_os = "linux")]
@@ -24,15 +26,22 @@ use super::bindings;
 use super::descriptor_utils;
 
 pub use self::defs::uapi::VIRTIO_ID_FS as TYPE_FS;
+pub use self::defs::{NUM_REQUEST_QUEUES as FS_NUM_REQUEST_QUEUES, QUEUE_SIZE as FS_QUEUE_SIZE};
 pub use self::device::Fs;
+pub use self::filesystem::{Context, FileSystem, SpecialFilePolicy, XattrMapping};
+pub use self::passthrough::CachePolicy;
+pub use self::server::FsOpPolicy;
+pub use self::stats::{LatencyHistogram, LATENCY_BUCKETS};
 
 mod defs {
     pub const FS_DEV_ID: &str = "virtio_fs";
-    pub const NUM_QUEUES: usize = 2;
-    pub const QUEUE_SIZES: &[u16] = &[1024; NUM_QUEUES];
+    pub const NUM_REQUEST_QUEUES: usize = 1;
+    // Each request queue is serviced by its own worker thread.
+    pub const MAX_REQUEST_QUEUES: usize = 64;
+    pub const QUEUE_SIZE: u16 = 1024;
     // High priority queue.
     pub const HPQ_INDEX: usize = 0;
-    // Request queue.
+    // First request queue, the other ones follow it.
     pub const REQ_INDEX: usize = 1;
 
     pub mod uapi {
@@ -53,6 +62,10 @@ pub enum FsError {
     EncodeMessage(io::Error),
     /// Failed to create event fd.
     EventFd(std::io::Error),
+    /// The number of request queues is zero or too large.
+    InvalidRequestQueues(usize),
+    /// The queue size is zero or not a power of 2.
+    InvalidQueueSize(u16),
     /// The guest failed to send a require extensions.
     MissingExtension,
     /// One or more parameters are missing.
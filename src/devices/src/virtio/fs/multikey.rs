@@ -107,6 +107,21 @@ where
         })
     }
 
+    /// Returns an iterator over the values of the map, in the order of their main key.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.main.values().map(|(_, v)| v)
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.main.len()
+    }
+
+    /// Returns `true` if the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.main.is_empty()
+    }
+
     /// Clears the map, removing all values.
     pub fn clear(&mut self) {
         self.alt.clear();
@@ -8,6 +8,8 @@ use std::fs::File;
 use std::io::{self, Read, Write};
 use std::mem::size_of;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 use vm_memory::ByteValued;
 
@@ -19,6 +21,7 @@ use super::filesystem::{
 };
 use super::fs_utils::einval;
 use super::fuse::*;
+use super::stats::FsStats;
 use super::{FsError as Error, Result};
 use crate::virtio::VirtioShmRegion;
 
@@ -26,6 +29,46 @@ const MAX_BUFFER_SIZE: u32 = 1 << 20;
 const BUFFER_HEADER_SIZE: u32 = 0x1000;
 const DIRENT_PADDING: [u8; 8] = [0; 8];
 
+/// A set of FUSE operations that are refused on a share, independently of the permissions of
+/// the underlying files. Denied operations fail with `EPERM` before reaching the file system.
+///
+/// Operations the kernel doesn't expect a reply for (`FUSE_FORGET`, `FUSE_BATCH_FORGET` and
+/// `FUSE_INTERRUPT`) can't be denied.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FsOpPolicy {
+    denied: u64,
+}
+
+impl FsOpPolicy {
+    /// Returns a policy that allows every operation.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Denies `op` in addition to the operations already denied by this policy.
+    pub fn deny(mut self, op: Opcode) -> Self {
+        self.denied |= 1 << op as u32;
+        self
+    }
+
+    /// Denies creating device nodes, FIFOs and sockets, symlinks and hard links.
+    pub fn deny_special_files(self) -> Self {
+        self.deny(Opcode::Mknod)
+            .deny(Opcode::Symlink)
+            .deny(Opcode::Link)
+    }
+
+    /// Denies setting and removing extended attributes.
+    pub fn deny_xattr_writes(self) -> Self {
+        self.deny(Opcode::Setxattr).deny(Opcode::Removexattr)
+    }
+
+    /// Returns whether requests with the given opcode are refused.
+    pub fn is_denied(&self, opcode: u32) -> bool {
+        opcode < u64::BITS && self.denied & (1 << opcode) != 0 && expects_reply(opcode)
+    }
+}
+
 struct ZCReader<'a>(Reader<'a>);
 
 impl<'a> ZeroCopyReader for ZCReader<'a> {
@@ -61,16 +104,54 @@ impl<'a> io::Write for ZCWriter<'a> {
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
     options: AtomicU64,
+    policy: FsOpPolicy,
+    // Where the latency of each request is recorded, if anywhere.
+    stats: Option<Arc<FsStats>>,
 }
 
 impl<F: FileSystem + Sync> Server<F> {
-    pub fn new(fs: F) -> Server<F> {
+    pub fn new(fs: F, policy: FsOpPolicy, stats: Option<Arc<FsStats>>) -> Server<F> {
         Server {
             fs,
             options: AtomicU64::new(FsOptions::empty().bits()),
+            policy,
+            stats,
         }
     }
 
+    /// Returns the FUSE options negotiated with the guest by its `FUSE_INIT` request, none if it
+    /// didn't send it yet.
+    pub fn options(&self) -> u64 {
+        self.options.load(Ordering::Relaxed)
+    }
+
+    /// Picks up the session a guest set up with `options` on another server, e.g. the one of the
+    /// microVM a snapshot was taken of, as if it had sent its `FUSE_INIT` request again, along
+    /// with the `references` to files it held, saved by `save_references`.
+    pub fn restore_session(&self, options: u64, references: &[u8]) -> io::Result<()> {
+        if options != 0 {
+            self.fs.init(FsOptions::from_bits_truncate(options))?;
+        }
+        self.fs.restore_references(references)?;
+        self.options.store(options, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Saves the references the guest holds to files of the file system, see
+    /// `FileSystem::save_references`.
+    pub fn save_references(&self) -> io::Result<Vec<u8>> {
+        self.fs.save_references()
+    }
+
+    /// Answers the request in `r` with `EIO` without passing it to the file system.
+    pub fn reject_message(&self, mut r: Reader, w: Writer) -> Result<usize> {
+        let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
+        if !expects_reply(in_header.opcode) {
+            return Ok(0);
+        }
+        reply_error(io::Error::from_raw_os_error(libc::EIO), in_header.unique, w)
+    }
+
     #[allow(clippy::cognitive_complexity)]
     pub fn handle_message(
         &self,
@@ -88,7 +169,19 @@ impl<F: FileSystem + Sync> Server<F> {
             );
         }
         debug!("opcode: {}", in_header.opcode);
-        match in_header.opcode {
+        if self.policy.is_denied(in_header.opcode) {
+            debug!("opcode {} denied by policy", in_header.opcode);
+            return reply_error(
+                io::Error::from_raw_os_error(libc::EPERM),
+                in_header.unique,
+                w,
+            );
+        }
+        // Keep a handle to the reply buffer so a request that fails to decode
+        // can still be answered instead of leaving the guest waiting on it.
+        let err_w = w.clone();
+        let start = self.stats.is_some().then(Instant::now);
+        let ret = match in_header.opcode {
             x if x == Opcode::Lookup as u32 => self.lookup(in_header, r, w),
             x if x == Opcode::Forget as u32 => self.forget(in_header, r), // No reply.
             x if x == Opcode::Getattr as u32 => self.getattr(in_header, r, w),
@@ -128,7 +221,7 @@ impl<F: FileSystem + Sync> Server<F> {
             x if x == Opcode::Ioctl as u32 => self.ioctl(in_header, r, w),
             x if x == Opcode::Poll as u32 => self.poll(in_header, r, w),
             x if x == Opcode::NotifyReply as u32 => self.notify_reply(in_header, r, w),
-            x if x == Opcode::BatchForget as u32 => self.batch_forget(in_header, r, w),
+            x if x == Opcode::BatchForget as u32 => self.batch_forget(in_header, r), // No reply.
             x if x == Opcode::Fallocate as u32 => self.fallocate(in_header, r, w),
             x if x == Opcode::Readdirplus as u32 => self.readdirplus(in_header, r, w),
             x if x == Opcode::Rename2 as u32 => self.rename2(in_header, r, w),
@@ -147,6 +240,17 @@ impl<F: FileSystem + Sync> Server<F> {
                 in_header.unique,
                 w,
             ),
+        };
+        if let (Some(stats), Some(start)) = (&self.stats, start) {
+            stats.record(in_header.opcode, start.elapsed());
+        }
+
+        match ret {
+            Err(e) if expects_reply(in_header.opcode) && is_decode_error(&e) => {
+                warn!("malformed request (opcode {}): {:?}", in_header.opcode, e);
+                reply_error(einval(), in_header.unique, err_w)
+            }
+            ret => ret,
         }
     }
 
@@ -446,8 +550,20 @@ impl<F: FileSystem + Sync> Server<F> {
     fn rename2(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let Rename2In { newdir, flags, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        #[cfg(target_os = "linux")]
-        let flags = flags & (libc::RENAME_EXCHANGE | libc::RENAME_NOREPLACE);
+        // Passing unknown flags down could turn them into a plain rename, and an exchange can't
+        // also be a no-replace or whiteout rename.
+        let noreplace = bindings::LINUX_RENAME_NOREPLACE as u32;
+        let exchange = bindings::LINUX_RENAME_EXCHANGE as u32;
+        let whiteout = bindings::LINUX_RENAME_WHITEOUT as u32;
+        if flags & !(noreplace | exchange | whiteout) != 0
+            || (flags & exchange != 0 && flags & (noreplace | whiteout) != 0)
+        {
+            return reply_error(
+                io::Error::from_raw_os_error(libc::EINVAL),
+                in_header.unique,
+                w,
+            );
+        }
 
         self.do_rename(in_header, size_of::<Rename2In>(), newdir, flags, r, w)
     }
@@ -650,7 +766,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let FsyncIn {
             fh, fsync_flags, ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
-        let datasync = fsync_flags & 0x1 != 0;
+        let datasync = fsync_flags & FSYNC_FDATASYNC != 0;
 
         match self.fs.fsync(
             Context::from(in_header),
@@ -1009,7 +1125,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let FsyncIn {
             fh, fsync_flags, ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
-        let datasync = fsync_flags & 0x1 != 0;
+        let datasync = fsync_flags & FSYNC_FDATASYNC != 0;
 
         match self.fs.fsyncdir(
             Context::from(in_header),
@@ -1144,6 +1260,28 @@ impl<F: FileSystem + Sync> Server<F> {
             out_size,
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        // Unrestricted ioctls need the `FUSE_IOCTL_RETRY` dance to let the server describe the
+        // argument buffers, which we don't implement. The kernel only sends these for CUSE, so
+        // regular virtio-fs ioctls always carry their buffers inline.
+        if IoctlFlags::from_bits_truncate(flags).contains(IoctlFlags::IOCTL_UNRESTRICTED) {
+            return reply_error(
+                io::Error::from_raw_os_error(libc::ENOTTY),
+                in_header.unique,
+                w,
+            );
+        }
+
+        if in_size > MAX_BUFFER_SIZE || out_size > MAX_BUFFER_SIZE {
+            return reply_error(
+                io::Error::from_raw_os_error(libc::ENOMEM),
+                in_header.unique,
+                w,
+            );
+        }
+
+        let mut data = vec![0u8; in_size as usize];
+        r.read_exact(&mut data).map_err(Error::DecodeMessage)?;
+
         match self.fs.ioctl(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -1151,12 +1289,13 @@ impl<F: FileSystem + Sync> Server<F> {
             flags,
             cmd,
             arg,
-            in_size,
+            &data,
             out_size,
         ) {
-            Ok(data) => {
+            Ok((result, mut data)) => {
+                data.truncate(out_size as usize);
                 let out = IoctlOut {
-                    result: 0,
+                    result,
                     ..Default::default()
                 };
                 reply_ok(Some(out), Some(&data), in_header.unique, w)
@@ -1181,23 +1320,17 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn batch_forget(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+    fn batch_forget(&self, in_header: InHeader, mut r: Reader) -> Result<usize> {
         let BatchForgetIn { count, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        if let Some(size) = (count as usize).checked_mul(size_of::<ForgetOne>()) {
-            if size > MAX_BUFFER_SIZE as usize {
-                return reply_error(
-                    io::Error::from_raw_os_error(libc::ENOMEM),
-                    in_header.unique,
-                    w,
-                );
-            }
-        } else {
-            return reply_error(
-                io::Error::from_raw_os_error(libc::EOVERFLOW),
-                in_header.unique,
-                w,
-            );
+        // The entries make up the rest of the message, which is otherwise taken to be malformed
+        // rather than forgetting only some of them. There's no reply to tell the guest either way.
+        let len = (count as usize)
+            .checked_mul(size_of::<ForgetOne>())
+            .and_then(|len| len.checked_add(size_of::<InHeader>() + size_of::<BatchForgetIn>()))
+            .ok_or(Error::InvalidHeaderLength)?;
+        if len != in_header.len as usize {
+            return Err(Error::InvalidHeaderLength);
         }
 
         let mut requests = Vec::with_capacity(count as usize);
@@ -1269,6 +1402,8 @@ impl<F: FileSystem + Sync> Server<F> {
             flags,
             ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
+        // The reply can only count up to `u32::MAX` bytes, the guest asks again for the rest.
+        let len = len.min(u64::from(u32::MAX) & !(4096 - 1));
 
         match self.fs.copyfilerange(
             Context::from(in_header),
@@ -1420,6 +1555,25 @@ fn reply_error(e: io::Error, unique: u64, mut w: Writer) -> Result<usize> {
     Ok(w.bytes_written())
 }
 
+fn expects_reply(opcode: u32) -> bool {
+    opcode != Opcode::Forget as u32
+        && opcode != Opcode::BatchForget as u32
+        && opcode != Opcode::Interrupt as u32
+}
+
+fn is_decode_error(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::DecodeMessage(_)
+            | Error::InvalidHeaderLength
+            | Error::InvalidCString(_)
+            | Error::InvalidCString2(_)
+            | Error::InvalidXattrSize(..)
+            | Error::MissingParameter
+            | Error::MissingExtension
+    )
+}
+
 fn bytes_to_cstr(buf: &[u8]) -> Result<&CStr> {
     // Convert to a `CStr` first so that we can drop the '\0' byte at the end
     // and make sure there are no interior '\0' bytes.
@@ -1595,3 +1749,225 @@ fn get_extensions(options: FsOptions, skip: usize, request_bytes: &[u8]) -> Resu
 
     Ok(extensions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+    use std::sync::Mutex;
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    // Records the forget, rename and symlink requests that reach the file system.
+    #[derive(Default)]
+    struct Recorder {
+        forgets: Mutex<Vec<(u64, u64)>>,
+        renames: Mutex<Vec<u32>>,
+        symlinks: Mutex<usize>,
+    }
+
+    impl FileSystem for Recorder {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn batch_forget(&self, _ctx: Context, requests: Vec<(u64, u64)>) {
+            self.forgets.lock().unwrap().extend(requests);
+        }
+
+        fn rename(
+            &self,
+            _ctx: Context,
+            _olddir: u64,
+            _oldname: &CStr,
+            _newdir: u64,
+            _newname: &CStr,
+            flags: u32,
+        ) -> io::Result<()> {
+            self.renames.lock().unwrap().push(flags);
+            Ok(())
+        }
+
+        fn symlink(
+            &self,
+            _ctx: Context,
+            _linkname: &CStr,
+            _parent: u64,
+            _name: &CStr,
+            _extensions: Extensions,
+        ) -> io::Result<Entry> {
+            *self.symlinks.lock().unwrap() += 1;
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        }
+    }
+
+    // Builds a request with `opcode` and `payload`, claiming to be `len` bytes long.
+    fn message(opcode: Opcode, payload: &[u8], len: usize) -> Vec<u8> {
+        let mut msg = InHeader {
+            len: len as u32,
+            opcode: opcode as u32,
+            unique: 1,
+            ..Default::default()
+        }
+        .as_slice()
+        .to_vec();
+        msg.extend_from_slice(payload);
+        msg
+    }
+
+    // Hands `server` the request `msg`, returning the result and the header of the reply, if
+    // any was written.
+    fn send(server: &Server<Recorder>, msg: &[u8]) -> (Result<usize>, Option<OutHeader>) {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(msg, GuestAddress(0x1000)).unwrap();
+        let chain = create_descriptor_chain(
+            &mem,
+            GuestAddress(0),
+            GuestAddress(0x1000),
+            vec![
+                (DescriptorType::Readable, msg.len() as u32),
+                (DescriptorType::Writable, 0x100),
+            ],
+            0,
+        )
+        .unwrap();
+        let r = Reader::new(&mem, chain.clone()).unwrap();
+        let w = Writer::new(&mem, chain).unwrap();
+        let res = server.handle_message(r, w, None);
+        let reply = matches!(res, Ok(len) if len > 0).then(|| {
+            mem.read_obj(GuestAddress(0x1000 + msg.len() as u64))
+                .unwrap()
+        });
+        (res, reply)
+    }
+
+    // Hands `server` a `BATCH_FORGET` message with `forgets`, claiming to be `len` bytes long.
+    fn batch_forget(
+        server: &Server<Recorder>,
+        forgets: &[(u64, u64)],
+        len: usize,
+    ) -> Result<usize> {
+        let mut payload = BatchForgetIn {
+            count: forgets.len() as u32,
+            dummy: 0,
+        }
+        .as_slice()
+        .to_vec();
+        for &(nodeid, nlookup) in forgets {
+            payload.extend_from_slice(ForgetOne { nodeid, nlookup }.as_slice());
+        }
+        send(server, &message(Opcode::BatchForget, &payload, len)).0
+    }
+
+    // Hands `server` a `RENAME2` request with `flags`, returning the error of the reply.
+    fn rename2(server: &Server<Recorder>, flags: u32) -> i32 {
+        let mut payload = Rename2In {
+            newdir: 1,
+            flags,
+            padding: 0,
+        }
+        .as_slice()
+        .to_vec();
+        payload.extend_from_slice(b"old\0new\0");
+        let len = size_of::<InHeader>() + payload.len();
+        let (res, reply) = send(server, &message(Opcode::Rename2, &payload, len));
+        res.unwrap();
+        reply.unwrap().error
+    }
+
+    #[test]
+    fn test_batch_forget() {
+        let server = Server::new(Recorder::default(), FsOpPolicy::new(), None);
+        let forgets: Vec<(u64, u64)> = (2..1002).map(|inode| (inode, inode % 3 + 1)).collect();
+        let len = size_of::<InHeader>()
+            + size_of::<BatchForgetIn>()
+            + forgets.len() * size_of::<ForgetOne>();
+
+        assert_eq!(batch_forget(&server, &forgets, len).unwrap(), 0);
+        assert_eq!(*server.fs.forgets.lock().unwrap(), forgets);
+
+        // Nothing is forgotten when the count and the length of the message disagree.
+        server.fs.forgets.lock().unwrap().clear();
+        for len in [len - size_of::<ForgetOne>(), len + size_of::<ForgetOne>()] {
+            assert!(matches!(
+                batch_forget(&server, &forgets, len),
+                Err(Error::InvalidHeaderLength)
+            ));
+        }
+        assert!(matches!(
+            batch_forget(&server, &forgets[..1], len),
+            Err(Error::InvalidHeaderLength)
+        ));
+        assert!(server.fs.forgets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_malformed_request() {
+        let server = Server::new(Recorder::default(), FsOpPolicy::new(), None);
+
+        // A lookup whose name is missing its nul terminator, or is shorter than the header says.
+        let len = size_of::<InHeader>() + 4;
+        for msg in [
+            message(Opcode::Lookup, b"name", len),
+            message(Opcode::Lookup, b"na", len),
+        ] {
+            let (res, reply) = send(&server, &msg);
+            assert_eq!(res.unwrap(), size_of::<OutHeader>());
+            let reply = reply.unwrap();
+            assert_eq!(reply.error, -libc::EINVAL);
+            assert_eq!(reply.unique, 1);
+        }
+        // A length too short for the header itself.
+        let (res, reply) = send(&server, &message(Opcode::Symlink, b"a\0b\0", 8));
+        assert_eq!(res.unwrap(), size_of::<OutHeader>());
+        assert_eq!(reply.unwrap().error, -libc::EINVAL);
+
+        // Requests the guest expects no reply to get none.
+        let (res, reply) = send(
+            &server,
+            &message(Opcode::Forget, &[], size_of::<InHeader>()),
+        );
+        assert!(matches!(res, Err(Error::DecodeMessage(_))));
+        assert!(reply.is_none());
+        assert_eq!(*server.fs.symlinks.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_op_policy() {
+        let policy = FsOpPolicy::new().deny(Opcode::Symlink);
+        let server = Server::new(Recorder::default(), policy, None);
+        let payload = b"name\0target\0";
+        let msg = message(
+            Opcode::Symlink,
+            payload,
+            size_of::<InHeader>() + payload.len(),
+        );
+        let (res, reply) = send(&server, &msg);
+        assert_eq!(res.unwrap(), size_of::<OutHeader>());
+        assert_eq!(reply.unwrap().error, -libc::EPERM);
+        assert_eq!(*server.fs.symlinks.lock().unwrap(), 0);
+
+        // Other servers still let it through.
+        let server = Server::new(Recorder::default(), FsOpPolicy::new(), None);
+        send(&server, &msg).0.unwrap();
+        assert_eq!(*server.fs.symlinks.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_rename2_flags() {
+        let server = Server::new(Recorder::default(), FsOpPolicy::new(), None);
+        let noreplace = bindings::LINUX_RENAME_NOREPLACE as u32;
+        let exchange = bindings::LINUX_RENAME_EXCHANGE as u32;
+        let whiteout = bindings::LINUX_RENAME_WHITEOUT as u32;
+
+        let valid = [0, noreplace, exchange, whiteout, noreplace | whiteout];
+        for flags in valid {
+            assert_eq!(rename2(&server, flags), 0);
+        }
+        assert_eq!(*server.fs.renames.lock().unwrap(), valid);
+
+        // Unknown flags and exchanges combined with other flags never reach the file system.
+        for flags in [1 << 3, exchange | noreplace, exchange | whiteout] {
+            assert_eq!(rename2(&server, flags), -libc::EINVAL);
+        }
+        assert_eq!(server.fs.renames.lock().unwrap().len(), valid.len());
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Latency histograms of the FUSE requests served by the host, per opcode.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Number of buckets of a `LatencyHistogram`. Bucket `i` counts the requests that took less than
+/// 2^i µs but at least 2^(i-1) µs, except for the last one, which counts all the slower ones.
+pub const LATENCY_BUCKETS: usize = 24;
+
+// No FUSE opcode the server handles is this high.
+const MAX_OPCODES: usize = 64;
+
+/// Latency distribution of the requests with a given opcode, from decoding a request to writing
+/// its reply.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LatencyHistogram {
+    /// Number of requests in each bucket, see `LATENCY_BUCKETS`.
+    pub buckets: [u64; LATENCY_BUCKETS],
+    /// Number of requests.
+    pub count: u64,
+    /// Time spent on all of them.
+    pub total: Duration,
+    /// Time spent on the slowest one.
+    pub max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Upper bound of bucket `i`, or `None` for the last one.
+    pub fn bucket_bound(i: usize) -> Option<Duration> {
+        (i < LATENCY_BUCKETS - 1).then(|| Duration::from_micros(1 << i))
+    }
+}
+
+#[derive(Default)]
+struct AtomicHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+/// Latencies of the requests served by a virtio-fs device, shared by its workers.
+pub struct FsStats {
+    ops: Vec<AtomicHistogram>,
+}
+
+impl FsStats {
+    pub fn new() -> Self {
+        FsStats {
+            ops: (0..MAX_OPCODES)
+                .map(|_| AtomicHistogram::default())
+                .collect(),
+        }
+    }
+
+    /// Records that a request with `opcode` took `latency`.
+    pub fn record(&self, opcode: u32, latency: Duration) {
+        let Some(histogram) = self.ops.get(opcode as usize) else {
+            return;
+        };
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1);
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+
+        histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram.count.fetch_add(1, Ordering::Relaxed);
+        histogram.total_ns.fetch_add(nanos, Ordering::Relaxed);
+        histogram.max_ns.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Returns the histograms of the opcodes with at least one request, ordered by opcode.
+    /// Requests completing meanwhile may be only partly accounted for.
+    pub fn snapshot(&self) -> Vec<(u32, LatencyHistogram)> {
+        self.ops
+            .iter()
+            .enumerate()
+            .filter(|(_, histogram)| histogram.count.load(Ordering::Relaxed) != 0)
+            .map(|(opcode, histogram)| {
+                let mut snapshot = LatencyHistogram {
+                    count: histogram.count.load(Ordering::Relaxed),
+                    total: Duration::from_nanos(histogram.total_ns.load(Ordering::Relaxed)),
+                    max: Duration::from_nanos(histogram.max_ns.load(Ordering::Relaxed)),
+                    ..Default::default()
+                };
+                for (count, bucket) in snapshot.buckets.iter_mut().zip(&histogram.buckets) {
+                    *count = bucket.load(Ordering::Relaxed);
+                }
+                (opcode as u32, snapshot)
+            })
+            .collect()
+    }
+}
+
+impl Default for FsStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let stats = FsStats::new();
+        stats.record(1, Duration::from_nanos(500));
+        stats.record(1, Duration::from_micros(3));
+        stats.record(16, Duration::from_secs(60));
+        // Out of range opcodes are ignored.
+        stats.record(4096, Duration::from_micros(1));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 2);
+
+        let (opcode, lookup) = &snapshot[0];
+        assert_eq!(*opcode, 1);
+        assert_eq!(lookup.count, 2);
+        assert_eq!(lookup.buckets[0], 1);
+        assert_eq!(lookup.buckets[2], 1);
+        assert_eq!(lookup.total, Duration::from_nanos(3500));
+        assert_eq!(lookup.max, Duration::from_micros(3));
+
+        let (opcode, write) = &snapshot[1];
+        assert_eq!(*opcode, 16);
+        assert_eq!(write.buckets[LATENCY_BUCKETS - 1], 1);
+
+        assert_eq!(
+            LatencyHistogram::bucket_bound(2),
+            Some(Duration::from_micros(4))
+        );
+        assert_eq!(LatencyHistogram::bucket_bound(LATENCY_BUCKETS - 1), None);
+    }
+}
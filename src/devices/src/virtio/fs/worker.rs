@@ -1,21 +1,66 @@
+use std::num::NonZeroUsize;
 use std::os::fd::AsRawFd;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
+#[cfg(target_os = "linux")]
+use utils::seccomp::SeccompFilter;
 use vm_memory::GuestMemoryMmap;
 
-use super::super::{FsError, Queue, VIRTIO_MMIO_INT_VRING};
-use super::defs::{HPQ_INDEX, REQ_INDEX};
-use super::descriptor_utils::{Reader, Writer};
-use super::passthrough::{self, PassthroughFs};
+use super::super::{FsError, Queue, QueueState, QuiesceGate, VIRTIO_MMIO_INT_VRING};
+use super::defs;
+use super::descriptor_utils::{ChainValidation, Reader, Writer};
+use super::passthrough::PassthroughFs;
 use super::server::Server;
 use crate::legacy::Gic;
 
+/// Shared by the workers of a device to bound the number of requests they handle at once, across
+/// all the request queues. The high priority queue isn't limited, its requests never block.
+#[derive(Clone, Default)]
+pub struct RequestLimit(Option<Arc<(Mutex<usize>, Condvar)>>);
+
+impl RequestLimit {
+    /// Lets at most `limit` requests be in flight at once, or any number of them if `None`.
+    pub fn new(limit: Option<NonZeroUsize>) -> Self {
+        RequestLimit(limit.map(|limit| Arc::new((Mutex::new(limit.get()), Condvar::new()))))
+    }
+
+    /// Waits for fewer requests than the limit to be in flight, then counts the caller's as in
+    /// flight until the returned guard is dropped.
+    pub fn acquire(&self) -> RequestPermit<'_> {
+        if let Some((available, cond)) = self.0.as_deref() {
+            let mut available = cond
+                .wait_while(available.lock().unwrap(), |available| *available == 0)
+                .unwrap();
+            *available -= 1;
+        }
+        RequestPermit(self)
+    }
+}
+
+/// Returned by `RequestLimit::acquire`, for as long as the request is in flight.
+pub struct RequestPermit<'a>(&'a RequestLimit);
+
+impl Drop for RequestPermit<'_> {
+    fn drop(&mut self) {
+        if let Some((available, cond)) = self.0 .0.as_deref() {
+            *available.lock().unwrap() += 1;
+            cond.notify_one();
+        }
+    }
+}
+
+/// Services a subset of the queues of a virtio-fs device. Request queues may be spread across
+/// several workers, which then share the same `Server` and its inodes and handles.
 pub struct FsWorker {
     queues: Vec<Queue>,
+    // Where the state of the queues is published after each request, for the device to snapshot
+    // it, and the index of the first queue of the worker in it.
+    queue_states: Arc<Mutex<Vec<QueueState>>>,
+    first_queue: usize,
     queue_evts: Vec<EventFd>,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
@@ -23,7 +68,12 @@ pub struct FsWorker {
     irq_line: Option<u32>,
 
     mem: GuestMemoryMmap,
-    server: Server<PassthroughFs>,
+    server: Arc<Server<PassthroughFs>>,
+    chain_validation: ChainValidation,
+    quiesce_gate: QuiesceGate,
+    request_limit: RequestLimit,
+    #[cfg(target_os = "linux")]
+    seccomp_filter: Option<SeccompFilter>,
     stop_fd: EventFd,
 }
 
@@ -31,17 +81,25 @@ impl FsWorker {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         queues: Vec<Queue>,
+        queue_states: Arc<Mutex<Vec<QueueState>>>,
+        first_queue: usize,
         queue_evts: Vec<EventFd>,
         interrupt_status: Arc<AtomicUsize>,
         interrupt_evt: EventFd,
         intc: Option<Arc<Mutex<Gic>>>,
         irq_line: Option<u32>,
         mem: GuestMemoryMmap,
-        passthrough_cfg: passthrough::Config,
+        server: Arc<Server<PassthroughFs>>,
+        chain_validation: ChainValidation,
+        quiesce_gate: QuiesceGate,
+        request_limit: RequestLimit,
+        #[cfg(target_os = "linux")] seccomp_filter: Option<SeccompFilter>,
         stop_fd: EventFd,
     ) -> Self {
         Self {
             queues,
+            queue_states,
+            first_queue,
             queue_evts,
             interrupt_status,
             interrupt_evt,
@@ -49,7 +107,12 @@ impl FsWorker {
             irq_line,
 
             mem,
-            server: Server::new(PassthroughFs::new(passthrough_cfg).unwrap()),
+            server,
+            chain_validation,
+            quiesce_gate,
+            request_limit,
+            #[cfg(target_os = "linux")]
+            seccomp_filter,
             stop_fd,
         }
     }
@@ -59,28 +122,35 @@ impl FsWorker {
     }
 
     fn work(mut self) {
-        let virtq_hpq_ev_fd = self.queue_evts[HPQ_INDEX].as_raw_fd();
-        let virtq_req_ev_fd = self.queue_evts[REQ_INDEX].as_raw_fd();
         let stop_ev_fd = self.stop_fd.as_raw_fd();
 
         let epoll = Epoll::new().unwrap();
 
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_hpq_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_hpq_ev_fd as u64),
-        );
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_req_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_req_ev_fd as u64),
-        );
+        for queue_evt in &self.queue_evts {
+            let fd = queue_evt.as_raw_fd();
+            let _ = epoll.ctl(
+                ControlOperation::Add,
+                fd,
+                &EpollEvent::new(EventSet::IN, fd as u64),
+            );
+        }
         let _ = epoll.ctl(
             ControlOperation::Add,
             stop_ev_fd,
             &EpollEvent::new(EventSet::IN, stop_ev_fd as u64),
         );
 
+        #[cfg(target_os = "linux")]
+        if let Some(filter) = &self.seccomp_filter {
+            if let Err(e) = filter.apply() {
+                error!(
+                    "Cannot restrict the worker thread, not handling requests: {}",
+                    e
+                );
+                return;
+            }
+        }
+
         loop {
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
             match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
@@ -88,16 +158,18 @@ impl FsWorker {
                     for event in &epoll_events[0..ev_cnt] {
                         let source = event.fd();
                         let event_set = event.event_set();
-                        match event_set {
-                            EventSet::IN if source == virtq_hpq_ev_fd => {
-                                self.handle_event(HPQ_INDEX);
-                            }
-                            EventSet::IN if source == virtq_req_ev_fd => {
-                                self.handle_event(REQ_INDEX);
+                        let queue_index = self
+                            .queue_evts
+                            .iter()
+                            .position(|evt| evt.as_raw_fd() == source);
+                        match (event_set, queue_index) {
+                            (EventSet::IN, Some(queue_index)) => {
+                                self.handle_event(queue_index);
                             }
-                            EventSet::IN if source == stop_ev_fd => {
+                            (EventSet::IN, None) if source == stop_ev_fd => {
+                                // The stop event is shared by all the workers of the device, so
+                                // it's left for the device to clear once they're all gone.
                                 debug!("stopping worker thread");
-                                let _ = self.stop_fd.read();
                                 return;
                             }
                             _ => {
@@ -124,40 +196,65 @@ impl FsWorker {
         }
 
         loop {
-            self.queues[queue_index]
-                .disable_notification(&self.mem)
-                .unwrap();
+            if let Err(e) = self.queues[queue_index].disable_notification(&self.mem) {
+                error!("Failed to disable queue notifications: {:?}", e);
+            }
 
             self.process_queue(queue_index);
 
-            if !self.queues[queue_index]
-                .enable_notification(&self.mem)
-                .unwrap()
-            {
-                break;
+            match self.queues[queue_index].enable_notification(&self.mem) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    error!("Failed to enable queue notifications: {:?}", e);
+                    break;
+                }
             }
         }
     }
 
     fn process_queue(&mut self, queue_index: usize) {
+        let limited = self.first_queue + queue_index != defs::HPQ_INDEX;
         let queue = &mut self.queues[queue_index];
-        while let Some(head) = queue.pop(&self.mem) {
-            let reader = Reader::new(&self.mem, head.clone())
+        loop {
+            // Requests are only taken off the queue while the device isn't quiesced, and they
+            // complete before it can be.
+            let _busy = self.quiesce_gate.enter();
+            // Past the limit the requests are left in the queue until the ones in flight on the
+            // other queues complete, which they do without waiting for anything.
+            let _permit = limited.then(|| self.request_limit.acquire());
+            let Some(head) = queue.pop(&self.mem) else {
+                break;
+            };
+
+            // A malformed descriptor chain leaves us without anywhere to write
+            // a reply, so just return the descriptor to the guest and move on.
+            let ret = Reader::new(&self.mem, head.clone())
                 .map_err(FsError::QueueReader)
-                .unwrap();
-            let writer = Writer::new(&self.mem, head.clone())
-                .map_err(FsError::QueueWriter)
-                .unwrap();
+                .and_then(|reader| {
+                    Writer::new(&self.mem, head.clone())
+                        .map_err(FsError::QueueWriter)
+                        .map(|writer| (reader, writer))
+                })
+                .and_then(|(reader, writer)| {
+                    if let Err(e) = self.chain_validation.validate(&head) {
+                        warn!("rejecting request with suspicious descriptor chain: {}", e);
+                        self.server.reject_message(reader, writer)
+                    } else {
+                        self.server.handle_message(reader, writer, None)
+                    }
+                });
 
-            if let Err(e) = self.server.handle_message(reader, writer, None) {
+            if let Err(e) = ret {
                 error!("error handling message: {:?}", e);
             }
 
             if let Err(e) = queue.add_used(&self.mem, head.index, 0) {
                 error!("failed to add used elements to the queue: {:?}", e);
             }
+            self.queue_states.lock().unwrap()[self.first_queue + queue_index] = queue.state();
 
-            if queue.needs_notification(&self.mem).unwrap() {
+            if queue.needs_notification(&self.mem).unwrap_or(true) {
                 self.interrupt_status
                     .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
                 if let Some(intc) = &self.intc {
@@ -169,3 +266,35 @@ impl FsWorker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    #[test]
+    fn test_request_limit() {
+        let limit = RequestLimit::new(NonZeroUsize::new(2));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let first = limit.acquire();
+        let _second = limit.acquire();
+        let waiter = {
+            let limit = limit.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let _permit = limit.acquire();
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!done.load(Ordering::SeqCst));
+        drop(first);
+        waiter.join().unwrap();
+        assert!(done.load(Ordering::SeqCst));
+
+        let unlimited = RequestLimit::new(None);
+        let _permits: Vec<_> = (0..16).map(|_| unlimited.acquire()).collect();
+    }
+}
@@ -10,6 +10,8 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
 
 use utils::byte_order;
+#[cfg(target_os = "linux")]
+use utils::seccomp::UnfilteredRunner;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
 use super::device_status;
@@ -26,6 +28,20 @@ const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
 //current version specified by the mmio standard (legacy devices used 1 here)
 const MMIO_VERSION: u32 = 2;
 
+/// State of a `MmioTransport` and its device, as saved in a snapshot of the microVM.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MmioTransportState {
+    pub features_select: u32,
+    pub acked_features_select: u32,
+    pub queue_select: u32,
+    pub device_status: u32,
+    pub config_generation: u32,
+    pub shm_region_select: u32,
+    pub interrupt_status: u32,
+    pub acked_features: u64,
+    pub device: DeviceSnapshot,
+}
+
 /// Implements the
 /// [MMIO](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1090002)
 /// transport for virtio devices.
@@ -53,6 +69,9 @@ pub struct MmioTransport {
     pub(crate) interrupt_status: Arc<AtomicUsize>,
     queue_evts: HashMap<u32, EventFd>,
     shm_region_select: u32,
+    reclaimer: Option<MemoryReclaimer>,
+    #[cfg(target_os = "linux")]
+    unfiltered_runner: Option<UnfilteredRunner>,
 }
 
 impl MmioTransport {
@@ -74,9 +93,54 @@ impl MmioTransport {
             interrupt_status,
             queue_evts: HashMap::new(),
             shm_region_select: 0,
+            reclaimer: None,
+            #[cfg(target_os = "linux")]
+            unfiltered_runner: None,
         }
     }
 
+    /// Keeps `reclaimer` from releasing the rings of the queues while the device is activated.
+    pub fn set_memory_reclaimer(&mut self, reclaimer: MemoryReclaimer) {
+        self.reclaimer = Some(reclaimer);
+    }
+
+    /// Activates the device on `runner` when the guest asks for it, so the threads the device
+    /// spawns don't inherit the seccomp filter of the vCPU thread accessing the registers.
+    #[cfg(target_os = "linux")]
+    pub fn set_unfiltered_runner(&mut self, runner: UnfilteredRunner) {
+        self.unfiltered_runner = Some(runner);
+    }
+
+    fn activate_device(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(runner) = &self.unfiltered_runner {
+            let device = self.device.clone();
+            let mem = self.mem.clone();
+            runner
+                .run(move || device.lock().expect("Poisoned device lock").activate(mem))
+                .expect("Failed to activate device");
+            self.guard_queues(&*self.locked_device());
+            return;
+        }
+
+        let mut device = self.locked_device();
+        device
+            .activate(self.mem.clone())
+            .expect("Failed to activate device");
+        self.guard_queues(&*device);
+    }
+
+    // Registers the rings of the queues of the device, just activated, with the reclaimer.
+    fn guard_queues(&self, device: &dyn VirtioDevice) {
+        if let Some(reclaimer) = &self.reclaimer {
+            reclaimer.set_queues(self.reclaimer_key(), device.queues());
+        }
+    }
+
+    fn reclaimer_key(&self) -> usize {
+        Arc::as_ptr(&self.device) as *const () as usize
+    }
+
     pub fn locked_device(&self) -> MutexGuard<dyn VirtioDevice + 'static> {
         self.device.lock().expect("Poisoned device lock")
     }
@@ -90,6 +154,81 @@ impl MmioTransport {
         self.queue_evts.insert(id, queue_evt);
     }
 
+    /// Whether the guest driver started initializing the device and didn't reset it since.
+    pub fn is_driver_bound(&self) -> bool {
+        self.device_status != device_status::INIT
+    }
+
+    /// Raises a configuration change interrupt through the interrupt event of the device.
+    pub fn signal_config_change(&self) -> std::io::Result<()> {
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
+        self.locked_device().interrupt_evt().write(1)
+    }
+
+    /// Returns the state of the transport and its device to save in a snapshot of the microVM, or
+    /// `None` if the guest activated a device that can't be snapshotted. Only call it with the
+    /// vCPUs paused and the device quiesced.
+    pub fn save_state(&self) -> Option<MmioTransportState> {
+        let device = self.locked_device();
+        let device_snapshot = if device.is_activated() {
+            device.snapshot()?
+        } else {
+            // Nothing processes the queues yet, so the ones of the device are up to date.
+            DeviceSnapshot {
+                queues: device.queues().iter().map(Queue::state).collect(),
+                data: Vec::new(),
+            }
+        };
+        Some(MmioTransportState {
+            features_select: self.features_select,
+            acked_features_select: self.acked_features_select,
+            queue_select: self.queue_select,
+            device_status: self.device_status,
+            config_generation: self.config_generation,
+            shm_region_select: self.shm_region_select,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst) as u32,
+            acked_features: device.acked_features(),
+            device: device_snapshot,
+        })
+    }
+
+    /// Restores the state returned by `save_state` into a transport whose device wasn't activated
+    /// yet, activating it if the driver had. The queues are then notified, for the device to
+    /// pick up the requests the guest queued before the snapshot.
+    pub fn restore_state(&mut self, state: &MmioTransportState) -> ActivateResult {
+        let mut device = self.device.lock().expect("Poisoned device lock");
+        if device.is_activated() || device.queues().len() != state.device.queues.len() {
+            return Err(ActivateError::BadActivate);
+        }
+
+        self.features_select = state.features_select;
+        self.acked_features_select = state.acked_features_select;
+        self.queue_select = state.queue_select;
+        self.device_status = state.device_status;
+        self.config_generation = state.config_generation;
+        self.shm_region_select = state.shm_region_select;
+        self.interrupt_status
+            .store(state.interrupt_status as usize, Ordering::SeqCst);
+        device.set_acked_features(state.acked_features);
+        for (queue, queue_state) in device.queues_mut().iter_mut().zip(&state.device.queues) {
+            queue.set_state(queue_state);
+        }
+
+        if self.device_status & device_status::DRIVER_OK == 0 {
+            return Ok(());
+        }
+        device.activate(self.mem.clone())?;
+        self.guard_queues(&*device);
+        if !device.restore_snapshot(&state.device.data) {
+            return Err(ActivateError::BadActivate);
+        }
+        for queue_evt in device.queue_events() {
+            queue_evt.write(1).map_err(|_| ActivateError::BadActivate)?;
+        }
+        Ok(())
+    }
+
     fn check_device_status(&self, set: u32, clr: u32) -> bool {
         self.device_status & (set | clr) == set
     }
@@ -148,6 +287,9 @@ impl MmioTransport {
         for queue in self.locked_device().queues_mut() {
             *queue = Queue::new(queue.get_max_size());
         }
+        if let Some(reclaimer) = &self.reclaimer {
+            reclaimer.clear_queues(self.reclaimer_key());
+        }
     }
 
     /// Update device status according to the state machine defined by VirtIO Spec 1.0.
@@ -175,9 +317,7 @@ impl MmioTransport {
                 self.device_status = status;
                 let device_activated = self.locked_device().is_activated();
                 if !device_activated {
-                    self.locked_device()
-                        .activate(self.mem.clone())
-                        .expect("Failed to activate device");
+                    self.activate_device();
                 }
             }
             _ if (status & FAILED) != 0 => {
@@ -351,6 +491,27 @@ impl BusDevice for MmioTransport {
     }
 }
 
+/// Placeholder for a virtio device plugged in later, in an MMIO slot declared to the guest at
+/// boot. It reports device id 0, which the Linux driver skips without an error, so the guest
+/// has to probe the slot again once it's populated, e.g. through the driver `bind` file in
+/// sysfs.
+#[derive(Default)]
+pub struct MmioEmptySlot;
+
+impl BusDevice for MmioEmptySlot {
+    fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        if data.len() != 4 {
+            return;
+        }
+        let v = match offset {
+            0x0 => MMIO_MAGIC_VALUE,
+            0x04 => MMIO_VERSION,
+            _ => 0,
+        };
+        byte_order::write_le_u32(data, v);
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     use utils::byte_order::{read_le_u32, write_le_u32};
@@ -449,6 +610,17 @@ pub(crate) mod tests {
         fn is_activated(&self) -> bool {
             self.device_activated
         }
+
+        fn snapshot(&self) -> Option<DeviceSnapshot> {
+            Some(DeviceSnapshot {
+                queues: self.queues.iter().map(Queue::state).collect(),
+                data: vec![1],
+            })
+        }
+
+        fn restore_snapshot(&mut self, data: &[u8]) -> bool {
+            data == [1]
+        }
     }
 
     fn set_device_status(d: &mut MmioTransport, status: u32) {
@@ -483,6 +655,21 @@ pub(crate) mod tests {
         assert!(!d.with_queue_mut(|q| q.size = 16));
     }
 
+    #[test]
+    fn test_empty_slot() {
+        let mut slot = MmioEmptySlot;
+        let mut buf = [0xffu8; 4];
+
+        slot.read(0, 0x0, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), MMIO_MAGIC_VALUE);
+        slot.read(0, 0x04, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), MMIO_VERSION);
+        slot.read(0, 0x08, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+        // Writes, e.g. of the device status, are dropped.
+        slot.write(0, 0x70, &buf[..]);
+    }
+
     #[test]
     fn test_bus_device_read() {
         let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
@@ -826,6 +1013,38 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bus_device_activate_unfiltered() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioTransport::new(m, Arc::new(Mutex::new(DummyDevice::new())));
+        d.set_unfiltered_runner(UnfilteredRunner::new().unwrap());
+        activate_device(&mut d);
+    }
+
+    #[test]
+    fn test_save_restore_state() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioTransport::new(m.clone(), Arc::new(Mutex::new(DummyDevice::new())));
+        activate_device(&mut d);
+        d.interrupt_status
+            .store(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        let state = d.save_state().unwrap();
+        assert_eq!(state.device.queues[1].size, 16);
+
+        let mut restored = MmioTransport::new(m, Arc::new(Mutex::new(DummyDevice::new())));
+        restored.restore_state(&state).unwrap();
+        assert!(restored.locked_device().is_activated());
+        assert_eq!(restored.save_state().unwrap(), state);
+        // The queues are notified, in case the guest queued requests before the snapshot.
+        for queue_evt in restored.locked_device().queue_events() {
+            assert_eq!(queue_evt.read().unwrap(), 1);
+        }
+
+        // The device must not be activated already.
+        assert!(restored.restore_state(&state).is_err());
+    }
+
     #[test]
     fn test_bus_device_reset() {
         let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
@@ -834,7 +1053,9 @@ pub(crate) mod tests {
 
         assert!(!d.locked_device().is_activated());
         assert_eq!(d.device_status, 0);
+        assert!(!d.is_driver_bound());
         activate_device(&mut d);
+        assert!(d.is_driver_bound());
 
         // Marking device as FAILED should not affect device_activated state
         write_le_u32(&mut buf[..], 0x8f);
@@ -847,6 +1068,7 @@ pub(crate) mod tests {
         d.write(0, 0x70, &buf[..]);
         assert_eq!(d.device_status, 0x8f);
         assert!(d.locked_device().is_activated());
+        assert!(d.is_driver_bound());
     }
 
     #[test]
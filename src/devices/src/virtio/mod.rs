@@ -31,10 +31,13 @@ mod mmio;
 #[cfg(feature = "net")]
 pub mod net;
 mod queue;
+mod reclaim;
 #[cfg(not(feature = "tee"))]
 pub mod rng;
 #[cfg(feature = "snd")]
 pub mod snd;
+#[cfg(any(not(feature = "tee"), feature = "net"))]
+mod token_bucket;
 pub mod vsock;
 
 #[cfg(not(feature = "tee"))]
@@ -42,6 +45,7 @@ pub use self::balloon::*;
 #[cfg(feature = "blk")]
 pub use self::block::{Block, CacheType};
 pub use self::console::*;
+pub use self::descriptor_utils::ChainValidation;
 pub use self::device::*;
 #[cfg(not(feature = "tee"))]
 pub use self::fs::*;
@@ -50,7 +54,8 @@ pub use self::gpu::*;
 pub use self::mmio::*;
 #[cfg(feature = "net")]
 pub use self::net::Net;
-pub use self::queue::{Descriptor, DescriptorChain, Queue};
+pub use self::queue::{Descriptor, DescriptorChain, Queue, QueueState};
+pub use self::reclaim::MemoryReclaimer;
 #[cfg(not(feature = "tee"))]
 pub use self::rng::*;
 #[cfg(feature = "snd")]
@@ -6,12 +6,15 @@
 // found in the THIRD-PARTY file.
 use crate::legacy::Gic;
 use crate::virtio::net::{Error, Result};
-use crate::virtio::net::{QUEUE_SIZES, RX_INDEX, TX_INDEX};
+use crate::virtio::net::{NUM_QUEUES, QUEUE_SIZES, RX_INDEX, TX_INDEX};
 use crate::virtio::queue::Error as QueueError;
-use crate::virtio::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_NET};
+use crate::virtio::{
+    ActivateResult, DeviceState, Queue, QuiesceGate, VirtioDevice, VmmQuiesceObserver, TYPE_NET,
+};
 use crate::Error as DeviceError;
 
 use super::backend::{ReadError, WriteError};
+use super::rate_limiter::{RateLimit, RateLimiter};
 use super::worker::NetWorker;
 
 use std::cmp;
@@ -20,6 +23,8 @@ use std::os::fd::RawFd;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
 use virtio_bindings::virtio_net::{
     VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
@@ -88,6 +93,14 @@ pub struct Net {
     irq_line: Option<u32>,
 
     config: VirtioNetConfig,
+
+    // Shared with the worker, by queue, and `rate_limit_evt` written when they're replaced.
+    rate_limiters: Arc<Mutex<[RateLimiter; NUM_QUEUES]>>,
+    rate_limit_evt: EventFd,
+    // Keeps the worker from processing the queues while the device is quiesced.
+    quiesce_gate: QuiesceGate,
+    worker_thread: Option<JoinHandle<()>>,
+    worker_stopfd: EventFd,
 }
 
 impl Net {
@@ -135,6 +148,14 @@ impl Net {
             irq_line: None,
 
             config,
+
+            rate_limiters: Arc::new(Mutex::new(std::array::from_fn(|_| {
+                RateLimiter::new(RateLimit::default())
+            }))),
+            rate_limit_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?,
+            quiesce_gate: QuiesceGate::default(),
+            worker_thread: None,
+            worker_stopfd: EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?,
         })
     }
 
@@ -146,6 +167,20 @@ impl Net {
     pub fn set_intc(&mut self, intc: Arc<Mutex<Gic>>) {
         self.intc = Some(intc);
     }
+
+    /// Caps the traffic the guest receives (`rx`) and sends (`tx`) on this interface, replacing
+    /// the previous caps, also while the device is running. Frames past the caps are left in the
+    /// backend and in the transmit queue until there's budget for them.
+    pub fn set_rate_limits(&mut self, rx: RateLimit, tx: RateLimit) {
+        {
+            let mut rate_limiters = self.rate_limiters.lock().unwrap();
+            rate_limiters[RX_INDEX] = RateLimiter::new(rx);
+            rate_limiters[TX_INDEX] = RateLimiter::new(tx);
+        }
+        if let Err(e) = self.rate_limit_evt.write(1) {
+            error!("Failed to signal the rate limits update: {:?}", e);
+        }
+    }
 }
 
 impl VirtioDevice for Net {
@@ -212,6 +247,10 @@ impl VirtioDevice for Net {
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if self.worker_thread.is_some() {
+            panic!("virtio_net: worker thread already exists");
+        }
+
         let event_idx: bool = (self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX)) != 0;
         self.queues[RX_INDEX].set_event_idx(event_idx);
         self.queues[TX_INDEX].set_event_idx(event_idx);
@@ -230,8 +269,12 @@ impl VirtioDevice for Net {
             self.irq_line,
             mem.clone(),
             self.cfg_backend.clone(),
+            self.rate_limiters.clone(),
+            self.rate_limit_evt.try_clone().unwrap(),
+            self.quiesce_gate.clone(),
+            self.worker_stopfd.try_clone().unwrap(),
         );
-        worker.run();
+        self.worker_thread = Some(worker.run());
 
         self.device_state = DeviceState::Activated(mem);
         Ok(())
@@ -243,4 +286,62 @@ impl VirtioDevice for Net {
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn reset(&mut self) -> bool {
+        // The worker waiting for the device to be resumed would never see its stop event.
+        self.quiesce_gate.open();
+        if let Some(worker) = self.worker_thread.take() {
+            let _ = self.worker_stopfd.write(1);
+            if let Err(e) = worker.join() {
+                error!("error waiting for worker thread: {:?}", e);
+            }
+        }
+        self.device_state = DeviceState::Inactive;
+        true
+    }
+}
+
+impl VmmQuiesceObserver for Net {
+    /// Waits for the worker to be done with the frames it's moving between the queues and the
+    /// backend.
+    fn on_quiesce(&mut self, timeout: Duration) -> bool {
+        self.quiesce_gate.close(timeout)
+    }
+
+    fn on_resume(&mut self) {
+        self.quiesce_gate.open();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_reset() {
+        let (backend, _peer) = UnixStream::pair().unwrap();
+        let mut net = Net::new(
+            "net0".to_string(),
+            VirtioNetBackend::Passt(backend.as_raw_fd()),
+            [0; 6],
+        )
+        .unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        net.activate(mem.clone()).unwrap();
+        assert!(net.is_activated());
+        // Waits for the worker to be gone, also when the device was quiesced.
+        assert!(net.on_quiesce(Duration::from_secs(5)));
+        assert!(net.reset());
+        assert!(!net.is_activated());
+        assert!(net.worker_thread.is_none());
+
+        // The guest driver can bind the device again.
+        net.activate(mem).unwrap();
+        assert!(net.is_activated());
+        assert!(net.reset());
+    }
 }
@@ -15,9 +15,11 @@ mod backend;
 pub mod device;
 mod gvproxy;
 mod passt;
+mod rate_limiter;
 mod worker;
 
 pub use self::device::Net;
+pub use self::rate_limiter::RateLimit;
 #[derive(Debug)]
 pub enum Error {
     /// EventFd error.
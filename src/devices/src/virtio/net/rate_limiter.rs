@@ -0,0 +1,99 @@
+use std::num::NonZeroU64;
+use std::time::Duration;
+
+use super::super::token_bucket::TokenBucket;
+
+/// Caps on the traffic in one direction of a network interface.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RateLimit {
+    /// Bytes per second, the virtio-net header of each frame included. `None` leaves it uncapped.
+    pub bytes_per_sec: Option<NonZeroU64>,
+    /// Frames per second. `None` leaves it uncapped.
+    pub ops_per_sec: Option<NonZeroU64>,
+}
+
+// Enforces a `RateLimit`. Frames are never split, so one larger than what the bytes bucket holds
+// puts it in debt, and the following ones wait for it to be paid off.
+pub(crate) struct RateLimiter {
+    bytes: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            bytes: limit.bytes_per_sec.map(TokenBucket::new),
+            ops: limit.ops_per_sec.map(TokenBucket::new),
+        }
+    }
+
+    // Returns how long to wait for before handling another frame, if the budget is exhausted.
+    pub(crate) fn throttled(&mut self) -> Option<Duration> {
+        [self.bytes.as_mut(), self.ops.as_mut()]
+            .into_iter()
+            .flatten()
+            .filter_map(|bucket| (bucket.available() == 0).then(|| bucket.wait_time()))
+            .max()
+    }
+
+    // Takes a frame of `len` bytes out of the budget.
+    pub(crate) fn consume(&mut self, len: usize) {
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.consume(len as u64);
+        }
+        if let Some(bucket) = self.ops.as_mut() {
+            bucket.consume(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    // Handles frames of `len` bytes as fast as `limiter` lets it for `duration`, and returns how
+    // many it handled and how long it took.
+    fn saturate(limiter: &mut RateLimiter, len: usize, duration: Duration) -> (u64, Duration) {
+        let start = Instant::now();
+        let mut frames = 0;
+        while start.elapsed() < duration {
+            match limiter.throttled() {
+                Some(wait) => thread::sleep(wait),
+                None => {
+                    limiter.consume(len);
+                    frames += 1;
+                }
+            }
+        }
+        (frames, start.elapsed())
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        const OPS: u64 = 500;
+        let mut limiter = RateLimiter::new(RateLimit {
+            bytes_per_sec: None,
+            ops_per_sec: NonZeroU64::new(OPS),
+        });
+        let (frames, elapsed) = saturate(&mut limiter, 64, Duration::from_millis(500));
+        let expected = OPS as f64 * elapsed.as_secs_f64();
+        assert!(frames as f64 <= expected + 1.0, "{frames} frames");
+        assert!(frames as f64 >= expected * 0.8, "{frames} frames");
+
+        // Frames larger than the bucket still go through, once every few refills.
+        const RATE: u64 = 100_000;
+        const FRAME: usize = 65_000;
+        let mut limiter = RateLimiter::new(RateLimit {
+            bytes_per_sec: NonZeroU64::new(RATE),
+            ops_per_sec: None,
+        });
+        let (frames, elapsed) = saturate(&mut limiter, FRAME, Duration::from_secs(1));
+        let expected = RATE as f64 * elapsed.as_secs_f64() / FRAME as f64;
+        assert!(frames as f64 <= expected + 1.0, "{frames} frames");
+        assert!(frames >= 1, "{frames} frames");
+
+        assert!(RateLimiter::new(RateLimit::default()).throttled().is_none());
+    }
+}
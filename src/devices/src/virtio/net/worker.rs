@@ -1,18 +1,20 @@
 use crate::legacy::Gic;
 use crate::virtio::net::gvproxy::Gvproxy;
 use crate::virtio::net::passt::Passt;
-use crate::virtio::net::{MAX_BUFFER_SIZE, QUEUE_SIZE, RX_INDEX, TX_INDEX};
-use crate::virtio::{Queue, VIRTIO_MMIO_INT_VRING};
+use crate::virtio::net::{MAX_BUFFER_SIZE, NUM_QUEUES, QUEUE_SIZE, RX_INDEX, TX_INDEX};
+use crate::virtio::{Queue, QuiesceGate, VIRTIO_MMIO_INT_VRING};
 use crate::Error as DeviceError;
 
 use super::backend::{NetBackend, ReadError, WriteError};
 use super::device::{FrontendError, RxError, TxError, VirtioNetBackend};
+use super::rate_limiter::RateLimiter;
 
 use std::os::fd::AsRawFd;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::{cmp, mem, result};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
@@ -49,6 +51,15 @@ pub struct NetWorker {
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
     tx_frame_len: usize,
+
+    // Shared with the device, by queue, which writes `rate_limit_evt` when it replaces them.
+    rate_limiters: Arc<Mutex<[RateLimiter; NUM_QUEUES]>>,
+    rate_limit_evt: EventFd,
+    // When the queues left unprocessed for lack of budget are processed again.
+    throttled_until: [Option<Instant>; NUM_QUEUES],
+    // Keeps the worker from processing the queues and the backend while the device is quiesced.
+    quiesce_gate: QuiesceGate,
+    stop_fd: EventFd,
 }
 
 impl NetWorker {
@@ -62,6 +73,10 @@ impl NetWorker {
         irq_line: Option<u32>,
         mem: GuestMemoryMmap,
         cfg_backend: VirtioNetBackend,
+        rate_limiters: Arc<Mutex<[RateLimiter; NUM_QUEUES]>>,
+        rate_limit_evt: EventFd,
+        quiesce_gate: QuiesceGate,
+        stop_fd: EventFd,
     ) -> Self {
         let backend = match cfg_backend {
             VirtioNetBackend::Passt(fd) => Box::new(Passt::new(fd)) as Box<dyn NetBackend + Send>,
@@ -88,17 +103,25 @@ impl NetWorker {
             tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_frame_len: 0,
             tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
+
+            rate_limiters,
+            rate_limit_evt,
+            throttled_until: [None; NUM_QUEUES],
+            quiesce_gate,
+            stop_fd,
         }
     }
 
-    pub fn run(self) {
-        thread::spawn(|| self.work());
+    pub fn run(self) -> thread::JoinHandle<()> {
+        thread::spawn(|| self.work())
     }
 
     fn work(mut self) {
         let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
         let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
         let backend_socket = self.backend.raw_socket_fd();
+        let rate_limit_ev_fd = self.rate_limit_evt.as_raw_fd();
+        let stop_ev_fd = self.stop_fd.as_raw_fd();
 
         let epoll = Epoll::new().unwrap();
 
@@ -120,10 +143,33 @@ impl NetWorker {
                 backend_socket as u64,
             ),
         );
+        let _ = epoll.ctl(
+            ControlOperation::Add,
+            rate_limit_ev_fd,
+            &EpollEvent::new(EventSet::IN, rate_limit_ev_fd as u64),
+        );
+        let _ = epoll.ctl(
+            ControlOperation::Add,
+            stop_ev_fd,
+            &EpollEvent::new(EventSet::IN, stop_ev_fd as u64),
+        );
 
         loop {
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
-            match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
+            let timeout = match self.throttled_until.iter().flatten().min() {
+                // Rounded up, not to wake up before there's budget again.
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_micros()
+                    .div_ceil(1000)
+                    .min(i32::MAX as u128) as i32,
+                None => -1,
+            };
+            let events = epoll.wait(epoll_events.len(), timeout, epoll_events.as_mut_slice());
+            // The events seen while the device is quiesced are handled once it's resumed.
+            let gate = self.quiesce_gate.clone();
+            let _busy = gate.enter();
+            match events {
                 Ok(ev_cnt) => {
                     for event in &epoll_events[0..ev_cnt] {
                         let source = event.fd();
@@ -135,6 +181,14 @@ impl NetWorker {
                             EventSet::IN if source == virtq_tx_ev_fd => {
                                 self.process_tx_queue_event();
                             }
+                            EventSet::IN if source == rate_limit_ev_fd => {
+                                self.process_rate_limit_event();
+                            }
+                            EventSet::IN if source == stop_ev_fd => {
+                                debug!("stopping worker thread");
+                                let _ = self.stop_fd.read();
+                                return;
+                            }
                             _ if source == backend_socket => {
                                 if event_set.contains(EventSet::HANG_UP)
                                     || event_set.contains(EventSet::READ_HANG_UP)
@@ -165,9 +219,41 @@ impl NetWorker {
                     debug!("vsock: failed to consume muxer epoll event: {}", e);
                 }
             }
+            self.process_throttled();
         }
     }
 
+    // Processes the queues again once the rate limiters have budget for them.
+    fn process_throttled(&mut self) {
+        let now = Instant::now();
+        if self.throttled_until[TX_INDEX].is_some_and(|deadline| deadline <= now) {
+            self.throttled_until[TX_INDEX] = None;
+            self.process_tx_loop();
+        }
+        if self.throttled_until[RX_INDEX].is_some_and(|deadline| deadline <= now) {
+            self.throttled_until[RX_INDEX] = None;
+            if let Err(e) = self.process_rx() {
+                log::error!("Failed to process rx: {e:?} (after being throttled)");
+            }
+        }
+    }
+
+    // The rate limits changed, the budget of the throttled queues with them.
+    fn process_rate_limit_event(&mut self) {
+        if let Err(e) = self.rate_limit_evt.read() {
+            log::error!("Failed to get rate limit event: {e:?}");
+        }
+        for deadline in self.throttled_until.iter_mut().flatten() {
+            *deadline = Instant::now();
+        }
+        self.process_throttled();
+    }
+
+    // Returns how long the guest is out of budget for on `queue`, if it is.
+    fn throttled(&self, queue: usize) -> Option<Duration> {
+        self.rate_limiters.lock().unwrap()[queue].throttled()
+    }
+
     pub(crate) fn process_rx_queue_event(&mut self) {
         if let Err(e) = self.queue_evts[RX_INDEX].read() {
             log::error!("Failed to get rx event from queue: {:?}", e);
@@ -224,6 +310,10 @@ impl NetWorker {
         // if we have a deferred frame we try to process it first,
         // if that is not possible, we don't continue processing other frames
         if self.rx_has_deferred_frame {
+            if let Some(wait) = self.throttled(RX_INDEX) {
+                self.throttled_until[RX_INDEX] = Some(Instant::now() + wait);
+                return Ok(());
+            }
             if self.write_frame_to_guest() {
                 self.rx_has_deferred_frame = false;
             } else {
@@ -233,8 +323,13 @@ impl NetWorker {
 
         let mut signal_queue = false;
 
-        // Read as many frames as possible.
+        // Read as many frames as possible. The ones the guest has no budget for are left to the
+        // backend, until it has.
         let result = loop {
+            if let Some(wait) = self.throttled(RX_INDEX) {
+                self.throttled_until[RX_INDEX] = Some(Instant::now() + wait);
+                break Ok(());
+            }
             match self.read_into_rx_frame_buf_from_backend() {
                 Ok(()) => {
                     if self.write_frame_to_guest() {
@@ -268,6 +363,11 @@ impl NetWorker {
                 log::error!("Failed to process rx: {e:?} (triggered by backend socket readable)");
             };
 
+            // Until there's budget again the guest has no reason to notify us.
+            if self.throttled_until[TX_INDEX].is_some() {
+                break;
+            }
+
             if !self.queues[TX_INDEX]
                 .enable_notification(&self.mem)
                 .unwrap()
@@ -278,8 +378,6 @@ impl NetWorker {
     }
 
     fn process_tx(&mut self) -> result::Result<(), TxError> {
-        let tx_queue = &mut self.queues[TX_INDEX];
-
         if self.backend.has_unfinished_write()
             && self
                 .backend
@@ -292,7 +390,13 @@ impl NetWorker {
 
         let mut raise_irq = false;
 
-        while let Some(head) = tx_queue.pop(&self.mem) {
+        while let Some(head) = self.queues[TX_INDEX].pop(&self.mem) {
+            // Left in the queue, not to complete it, until there's budget for it.
+            if let Some(wait) = self.throttled(TX_INDEX) {
+                self.queues[TX_INDEX].undo_pop();
+                self.throttled_until[TX_INDEX] = Some(Instant::now() + wait);
+                break;
+            }
             let head_index = head.index;
             let mut read_count = 0;
             let mut next_desc = Some(head);
@@ -335,13 +439,14 @@ impl NetWorker {
             {
                 Ok(()) => {
                     self.tx_frame_len = 0;
-                    tx_queue
+                    self.rate_limiters.lock().unwrap()[TX_INDEX].consume(read_count);
+                    self.queues[TX_INDEX]
                         .add_used(&self.mem, head_index, 0)
                         .map_err(TxError::QueueError)?;
                     raise_irq = true;
                 }
                 Err(WriteError::NothingWritten) => {
-                    tx_queue.undo_pop();
+                    self.queues[TX_INDEX].undo_pop();
                     break;
                 }
                 Err(WriteError::PartialWrite) => {
@@ -357,7 +462,8 @@ impl NetWorker {
                     the backend could be blocked on sending a remainder of a frame to us - us waiting
                     for backend would cause a deadlock.
                      */
-                    tx_queue
+                    self.rate_limiters.lock().unwrap()[TX_INDEX].consume(read_count);
+                    self.queues[TX_INDEX]
                         .add_used(&self.mem, head_index, 0)
                         .map_err(TxError::QueueError)?;
                     raise_irq = true;
@@ -369,7 +475,7 @@ impl NetWorker {
             }
         }
 
-        if raise_irq && tx_queue.needs_notification(&self.mem).unwrap() {
+        if raise_irq && self.queues[TX_INDEX].needs_notification(&self.mem).unwrap() {
             self.signal_used_queue().map_err(TxError::DeviceError)?;
         }
 
@@ -447,7 +553,10 @@ impl NetWorker {
         let max_iterations = self.queues[RX_INDEX].actual_size();
         for _ in 0..max_iterations {
             match self.write_frame_to_guest_impl() {
-                Ok(()) => return true,
+                Ok(()) => {
+                    self.rate_limiters.lock().unwrap()[RX_INDEX].consume(self.rx_frame_buf_len);
+                    return true;
+                }
                 Err(FrontendError::EmptyQueue) => {
                     // retry
                     continue;
@@ -471,3 +580,177 @@ impl NetWorker {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::net::rate_limiter::RateLimit;
+    use crate::virtio::queue::tests::VirtQueue as GuestQ;
+    use crate::virtio::queue::VIRTQ_DESC_F_WRITE;
+    use std::io::{Read, Write};
+    use std::num::NonZeroU64;
+    use std::os::unix::net::UnixStream;
+    use utils::eventfd::EFD_NONBLOCK;
+
+    #[test]
+    fn test_tx_rate_limit() {
+        const RATE: u64 = 100_000;
+        const FRAME_LEN: u32 = 1000;
+        const FRAMES: u16 = 256;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100000)]).unwrap();
+        let rx = GuestQ::new(GuestAddress(0), &mem, 16);
+        let tx = GuestQ::new(GuestAddress(0x4000), &mem, FRAMES);
+        // The guest sends about two and a half seconds worth of frames at once.
+        for i in 0..FRAMES {
+            let addr = 0x10000 + u64::from(i) * u64::from(FRAME_LEN);
+            tx.dtable[i as usize].set(addr, FRAME_LEN, 0, 0);
+            tx.avail.ring[i as usize].set(i);
+        }
+        tx.avail.idx.set(FRAMES);
+
+        let (host, mut peer) = UnixStream::pair().unwrap();
+        let received = thread::spawn(move || {
+            let mut buf = vec![0u8; 0x10000];
+            let mut total = 0;
+            while let Ok(len @ 1..) = peer.read(&mut buf) {
+                total += len;
+            }
+            total
+        });
+
+        let unlimited = RateLimiter::new(RateLimit::default());
+        let limited = RateLimiter::new(RateLimit {
+            bytes_per_sec: NonZeroU64::new(RATE),
+            ops_per_sec: None,
+        });
+        let rate_limiters = Arc::new(Mutex::new([unlimited, limited]));
+        let rate_limit_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut worker = NetWorker::new(
+            vec![rx.create_queue(), tx.create_queue()],
+            vec![
+                EventFd::new(EFD_NONBLOCK).unwrap(),
+                EventFd::new(EFD_NONBLOCK).unwrap(),
+            ],
+            Arc::new(AtomicUsize::new(0)),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            None,
+            None,
+            mem.clone(),
+            VirtioNetBackend::Passt(host.as_raw_fd()),
+            rate_limiters.clone(),
+            rate_limit_evt.try_clone().unwrap(),
+            QuiesceGate::default(),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+        );
+
+        let start = Instant::now();
+        worker.process_tx_loop();
+        while start.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(5));
+            worker.process_throttled();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let sent = tx.used.idx.get();
+        let bytes = u64::from(sent) * u64::from(FRAME_LEN);
+        assert!(sent < FRAMES);
+        assert!(bytes as f64 <= RATE as f64 * elapsed + f64::from(FRAME_LEN));
+        assert!(bytes as f64 >= RATE as f64 * elapsed * 0.8);
+
+        // Lifting the cap sends the rest right away.
+        rate_limiters.lock().unwrap()[TX_INDEX] = RateLimiter::new(RateLimit::default());
+        rate_limit_evt.write(1).unwrap();
+        worker.process_rate_limit_event();
+        assert_eq!(tx.used.idx.get(), FRAMES);
+
+        drop(worker);
+        drop(host);
+        // Each frame gets a length instead of its virtio-net header.
+        let frame_len = FRAME_LEN as usize - vnet_hdr_len() + 4;
+        assert_eq!(received.join().unwrap(), usize::from(FRAMES) * frame_len);
+    }
+
+    #[test]
+    fn test_rx_rate_limit() {
+        const RATE: u64 = 100_000;
+        const FRAME_LEN: u32 = 1000;
+        const FRAMES: u16 = 256;
+        const BUF_LEN: u32 = 0x800;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100000)]).unwrap();
+        let rx = GuestQ::new(GuestAddress(0), &mem, FRAMES);
+        let tx = GuestQ::new(GuestAddress(0x4000), &mem, 16);
+        // The guest has a buffer for each of the frames.
+        for i in 0..FRAMES {
+            let addr = 0x10000 + u64::from(i) * u64::from(BUF_LEN);
+            rx.dtable[i as usize].set(addr, BUF_LEN, VIRTQ_DESC_F_WRITE, 0);
+            rx.avail.ring[i as usize].set(i);
+        }
+        rx.avail.idx.set(FRAMES);
+
+        // The backend sends about two and a half seconds worth of frames at once.
+        let (host, mut peer) = UnixStream::pair().unwrap();
+        let sender = thread::spawn(move || {
+            let mut frame = (FRAME_LEN).to_be_bytes().to_vec();
+            frame.resize(4 + FRAME_LEN as usize, 0xaa);
+            for _ in 0..FRAMES {
+                peer.write_all(&frame).unwrap();
+            }
+            // Closing the socket would be a backend failure.
+            peer
+        });
+
+        let unlimited = RateLimiter::new(RateLimit::default());
+        let limited = RateLimiter::new(RateLimit {
+            bytes_per_sec: NonZeroU64::new(RATE),
+            ops_per_sec: None,
+        });
+        let rate_limiters = Arc::new(Mutex::new([limited, unlimited]));
+        let rate_limit_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut worker = NetWorker::new(
+            vec![rx.create_queue(), tx.create_queue()],
+            vec![
+                EventFd::new(EFD_NONBLOCK).unwrap(),
+                EventFd::new(EFD_NONBLOCK).unwrap(),
+            ],
+            Arc::new(AtomicUsize::new(0)),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            None,
+            None,
+            mem.clone(),
+            VirtioNetBackend::Passt(host.as_raw_fd()),
+            rate_limiters.clone(),
+            rate_limit_evt.try_clone().unwrap(),
+            QuiesceGate::default(),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+        );
+
+        let start = Instant::now();
+        worker.process_backend_socket_readable();
+        while start.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(5));
+            worker.process_throttled();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        // The guest is charged for the virtio-net header of each frame too.
+        let frame_len = vnet_hdr_len() + FRAME_LEN as usize;
+        let received = rx.used.idx.get();
+        let bytes = usize::from(received) * frame_len;
+        assert!(received < FRAMES);
+        assert!(bytes as f64 <= RATE as f64 * elapsed + frame_len as f64);
+        assert!(bytes as f64 >= RATE as f64 * elapsed * 0.8);
+        for i in 0..received {
+            assert_eq!(rx.used.ring[i as usize].get().len, frame_len as u32);
+        }
+
+        // Lifting the cap hands the guest the rest right away.
+        rate_limiters.lock().unwrap()[RX_INDEX] = RateLimiter::new(RateLimit::default());
+        rate_limit_evt.write(1).unwrap();
+        worker.process_rate_limit_event();
+        let _peer = sender.join().unwrap();
+        worker.process_backend_socket_readable();
+        assert_eq!(rx.used.idx.get(), FRAMES);
+    }
+}
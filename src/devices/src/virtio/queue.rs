@@ -9,7 +9,11 @@ use std::cmp::min;
 use std::fmt::{self, Debug, Display};
 use std::num::Wrapping;
 use std::sync::atomic::{fence, Ordering};
-use virtio_bindings::virtio_ring::VRING_USED_F_NO_NOTIFY;
+use virtio_bindings::virtio_ring::{
+    VRING_PACKED_DESC_F_AVAIL, VRING_PACKED_DESC_F_USED, VRING_PACKED_EVENT_FLAG_DESC,
+    VRING_PACKED_EVENT_FLAG_DISABLE, VRING_PACKED_EVENT_FLAG_ENABLE, VRING_PACKED_EVENT_F_WRAP_CTR,
+    VRING_USED_F_NO_NOTIFY,
+};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
     VolatileMemoryError,
@@ -27,8 +31,16 @@ pub(crate) const VIRTQ_AVAIL_RING_HEADER_SIZE: u64 = 4;
 /// Size of one element in the available ring (le16).
 pub(crate) const VIRTQ_AVAIL_ELEMENT_SIZE: u64 = 2;
 
+/// Size of one descriptor in the packed ring: addr (le64) + len (le32) + id (le16) + flags (le16).
+pub(crate) const VIRTQ_PACKED_DESC_SIZE: u64 = 16;
+
+/// Size of a packed ring event suppression structure: off_wrap (le16) + flags (le16).
+pub(crate) const VIRTQ_PACKED_EVENT_SIZE: u64 = 4;
+
 pub(super) const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 pub(super) const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+pub(super) const VIRTQ_DESC_F_AVAIL: u16 = 1 << VRING_PACKED_DESC_F_AVAIL;
+pub(super) const VIRTQ_DESC_F_USED: u16 = 1 << VRING_PACKED_DESC_F_USED;
 
 /// Virtio Queue related errors.
 #[allow(clippy::enum_variant_names)]
@@ -207,17 +219,30 @@ pub struct Descriptor {
 
 unsafe impl ByteValued for Descriptor {}
 
+/// A virtio packed ring descriptor, as laid out in guest memory.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct PackedDescriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub id: u16,
+    pub flags: u16,
+}
+
+unsafe impl ByteValued for PackedDescriptor {}
+
 /// A virtio descriptor chain.
 #[derive(Clone)]
 pub struct DescriptorChain<'a> {
     desc_table: GuestAddress,
     queue_size: u16,
     ttl: u16, // used to prevent infinite chain cycles
+    packed: bool,
 
     /// Reference to guest memory
     pub mem: &'a GuestMemoryMmap,
 
-    /// Index into the descriptor table
+    /// Index into the descriptor table. For packed rings this is the buffer id of the chain.
     pub index: u16,
 
     /// Guest physical address of device specific data
@@ -229,8 +254,8 @@ pub struct DescriptorChain<'a> {
     /// Includes next, write, and indirect bits
     pub flags: u16,
 
-    /// Index into the descriptor table of the next descriptor if flags has
-    /// the next bit set
+    /// Index into the descriptor table (or position in the packed ring) of the next
+    /// descriptor if flags has the next bit set
     pub next: u16,
 }
 
@@ -265,6 +290,7 @@ impl<'a> DescriptorChain<'a> {
             desc_table,
             queue_size,
             ttl: queue_size,
+            packed: false,
             index,
             addr: GuestAddress(desc.addr),
             len: desc.len,
@@ -279,6 +305,47 @@ impl<'a> DescriptorChain<'a> {
         }
     }
 
+    /// Creates a chain from the descriptor at `position` in a packed ring. Descriptors of a
+    /// packed chain occupy consecutive ring slots, so `ttl` is the number of descriptors left
+    /// in the chain and `id` the buffer id the driver assigned to it.
+    pub fn checked_new_packed(
+        mem: &GuestMemoryMmap,
+        ring: GuestAddress,
+        queue_size: u16,
+        position: u16,
+        id: u16,
+        ttl: u16,
+    ) -> Option<DescriptorChain<'_>> {
+        if position >= queue_size || id >= queue_size {
+            return None;
+        }
+
+        let desc_addr =
+            mem.checked_offset(ring, (position as usize) * VIRTQ_PACKED_DESC_SIZE as usize)?;
+        mem.checked_offset(desc_addr, VIRTQ_PACKED_DESC_SIZE as usize)?;
+
+        let desc = match mem.read_obj::<PackedDescriptor>(desc_addr) {
+            Ok(ret) => ret,
+            Err(_) => {
+                error!("Failed to read packed descriptor from memory");
+                return None;
+            }
+        };
+
+        Some(DescriptorChain {
+            mem,
+            desc_table: ring,
+            queue_size,
+            ttl,
+            packed: true,
+            index: id,
+            addr: GuestAddress(desc.addr),
+            len: desc.len,
+            flags: desc.flags,
+            next: (position + 1) % queue_size,
+        })
+    }
+
     fn is_valid(&self) -> bool {
         !self.has_next() || self.next < self.queue_size
     }
@@ -309,7 +376,16 @@ impl<'a> DescriptorChain<'a> {
     /// Note that this is distinct from the next descriptor chain returned by `AvailIter`, which is
     /// the head of the next _available_ descriptor chain.
     pub fn next_descriptor(&self) -> Option<DescriptorChain<'a>> {
-        if self.has_next() {
+        if self.has_next() && self.packed {
+            DescriptorChain::checked_new_packed(
+                self.mem,
+                self.desc_table,
+                self.queue_size,
+                self.next,
+                self.index,
+                self.ttl - 1,
+            )
+        } else if self.has_next() {
             DescriptorChain::checked_new(self.mem, self.desc_table, self.queue_size, self.next).map(
                 |mut c| {
                     c.ttl = self.ttl - 1;
@@ -349,13 +425,15 @@ pub struct Queue {
     /// Indicates if the queue is finished with configuration
     pub ready: bool,
 
-    /// Guest physical address of the descriptor table
+    /// Guest physical address of the descriptor table (the descriptor ring for packed queues)
     pub desc_table: GuestAddress,
 
-    /// Guest physical address of the available ring
+    /// Guest physical address of the available ring (the driver event suppression structure
+    /// for packed queues)
     pub avail_ring: GuestAddress,
 
-    /// Guest physical address of the used ring
+    /// Guest physical address of the used ring (the device event suppression structure for
+    /// packed queues)
     pub used_ring: GuestAddress,
 
     pub(crate) next_avail: Wrapping<u16>,
@@ -366,7 +444,40 @@ pub struct Queue {
 
     /// The number of descriptor chains placed in the used ring via `add_used`
     /// since the last time `needs_notification` was called on the associated queue.
+    /// For packed queues this counts ring slots instead of chains.
     num_added: Wrapping<u16>,
+
+    /// VIRTIO_F_RING_PACKED negotiated. When set, `next_avail` and `next_used` are
+    /// positions in the descriptor ring rather than free-running indices.
+    packed: bool,
+
+    /// Driver ring wrap counter for packed queues.
+    avail_wrap_counter: bool,
+
+    /// Device ring wrap counter for packed queues.
+    used_wrap_counter: bool,
+
+    /// Position and wrap counter before the last `pop` on a packed queue, used to undo it.
+    last_avail: (Wrapping<u16>, bool),
+
+    /// Number of ring slots used by each in-flight buffer id of a packed queue.
+    packed_chain_len: Vec<u16>,
+}
+
+/// Configuration and position of a `Queue`, e.g. to save it in a snapshot of the microVM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueState {
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    pub next_avail: u16,
+    pub next_used: u16,
+    pub event_idx_enabled: bool,
+    pub packed: bool,
+    pub avail_wrap_counter: bool,
+    pub used_wrap_counter: bool,
 }
 
 impl Queue {
@@ -383,6 +494,11 @@ impl Queue {
             next_used: Wrapping(0),
             event_idx_enabled: false,
             num_added: Wrapping(0),
+            packed: false,
+            avail_wrap_counter: true,
+            used_wrap_counter: true,
+            last_avail: (Wrapping(0), true),
+            packed_chain_len: Vec::new(),
         }
     }
 
@@ -396,18 +512,55 @@ impl Queue {
         min(self.size, self.max_size)
     }
 
+    /// Returns the guest ranges of the descriptor table and of the available and used rings (the
+    /// event suppression structures for packed queues), as start address and size.
+    pub fn ring_ranges(&self) -> [(GuestAddress, u64); 3] {
+        let queue_size = u64::from(self.actual_size());
+        let (avail_ring_size, used_ring_size) = if self.packed {
+            (VIRTQ_PACKED_EVENT_SIZE, VIRTQ_PACKED_EVENT_SIZE)
+        } else {
+            (6 + 2 * queue_size, 6 + 8 * queue_size)
+        };
+        [
+            (self.desc_table, 16 * queue_size),
+            (self.avail_ring, avail_ring_size),
+            (self.used_ring, used_ring_size),
+        ]
+    }
+
+    /// Returns the guest ranges of the buffers the descriptors of the queue point to, as start
+    /// address and size. These include the buffers in flight, but also the ones of descriptors
+    /// the driver is done with and hasn't reused yet.
+    pub fn buffer_ranges(&self, mem: &GuestMemoryMmap) -> Vec<(GuestAddress, u64)> {
+        (0..u64::from(self.actual_size()))
+            .filter_map(|index| {
+                // Packed descriptors start with the same address and length as split ones.
+                let addr = self.desc_table.checked_add(index * 16)?;
+                let desc = mem.read_obj::<Descriptor>(addr).ok()?;
+                (desc.len > 0).then_some((GuestAddress(desc.addr), u64::from(desc.len)))
+            })
+            .collect()
+    }
+
     pub fn is_valid(&self, mem: &GuestMemoryMmap) -> bool {
         let queue_size = u64::from(self.actual_size());
         let desc_table = self.desc_table;
         let desc_table_size = 16 * queue_size;
         let avail_ring = self.avail_ring;
-        let avail_ring_size = 6 + 2 * queue_size;
         let used_ring = self.used_ring;
-        let used_ring_size = 6 + 8 * queue_size;
+        // Packed queues replace both rings with event suppression structures, and don't
+        // require the queue size to be a power of 2.
+        let (avail_ring_size, used_ring_size, avail_ring_align) = if self.packed {
+            (VIRTQ_PACKED_EVENT_SIZE, VIRTQ_PACKED_EVENT_SIZE, 0x3)
+        } else {
+            (6 + 2 * queue_size, 6 + 8 * queue_size, 0x1)
+        };
         if !self.ready {
             error!("attempt to use virtio queue that is not marked ready");
             false
-        } else if self.size > self.max_size || self.size == 0 || (self.size & (self.size - 1)) != 0
+        } else if self.size > self.max_size
+            || self.size == 0
+            || (!self.packed && (self.size & (self.size - 1)) != 0)
         {
             error!("virtio queue with invalid size: {}", self.size);
             false
@@ -444,7 +597,7 @@ impl Queue {
         } else if desc_table.raw_value() & 0xf != 0 {
             error!("virtio queue descriptor table breaks alignment contraints");
             false
-        } else if avail_ring.raw_value() & 0x1 != 0 {
+        } else if avail_ring.raw_value() & avail_ring_align != 0 {
             error!("virtio queue available ring breaks alignment contraints");
             false
         } else if used_ring.raw_value() & 0x3 != 0 {
@@ -458,6 +611,9 @@ impl Queue {
     /// Returns the number of yet-to-be-popped descriptor chains in the avail ring.
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self, mem: &GuestMemoryMmap) -> u16 {
+        if self.packed {
+            return self.packed_len(mem);
+        }
         (self.avail_idx(mem, Ordering::Acquire).unwrap() - self.next_avail).0
     }
 
@@ -468,6 +624,10 @@ impl Queue {
 
     /// Pop the first available descriptor chain from the avail ring.
     pub fn pop<'b>(&mut self, mem: &'b GuestMemoryMmap) -> Option<DescriptorChain<'b>> {
+        if self.packed {
+            return self.pop_packed(mem);
+        }
+
         if self.len(mem) == 0 || self.actual_size() == 0 {
             return None;
         }
@@ -518,6 +678,10 @@ impl Queue {
     /// Undo the effects of the last `self.pop()` call.
     /// The caller can use this, if it was unable to consume the last popped descriptor chain.
     pub fn undo_pop(&mut self) {
+        if self.packed {
+            (self.next_avail, self.avail_wrap_counter) = self.last_avail;
+            return;
+        }
         self.next_avail -= Wrapping(1);
     }
 
@@ -535,6 +699,10 @@ impl Queue {
             return Err(Error::InvalidDescriptorIndex);
         }
 
+        if self.packed {
+            return self.add_used_packed(mem, head_index, len);
+        }
+
         let next_used_index = u64::from(self.next_used.0 % self.size);
         // This can not overflow an u64 since it is working with relatively small numbers compared
         // to u64::MAX.
@@ -608,6 +776,64 @@ impl Queue {
         self.event_idx_enabled = enabled;
     }
 
+    /// Switches the queue to the packed ring layout. Must be called before the queue is used,
+    /// once VIRTIO_F_RING_PACKED has been negotiated.
+    pub fn set_packed(&mut self, enabled: bool) {
+        self.packed = enabled;
+    }
+
+    pub fn is_packed(&self) -> bool {
+        self.packed
+    }
+
+    /// Returns the configuration and position of the queue. The descriptor chains popped but not
+    /// added to the used ring yet aren't part of it, so take it while the queue is idle.
+    pub fn state(&self) -> QueueState {
+        QueueState {
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table.0,
+            avail_ring: self.avail_ring.0,
+            used_ring: self.used_ring.0,
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+            event_idx_enabled: self.event_idx_enabled,
+            packed: self.packed,
+            avail_wrap_counter: self.avail_wrap_counter,
+            used_wrap_counter: self.used_wrap_counter,
+        }
+    }
+
+    /// Restores the configuration and position returned by `state`.
+    pub fn set_state(&mut self, state: &QueueState) {
+        self.size = state.size;
+        self.ready = state.ready;
+        self.desc_table = GuestAddress(state.desc_table);
+        self.avail_ring = GuestAddress(state.avail_ring);
+        self.used_ring = GuestAddress(state.used_ring);
+        self.next_avail = Wrapping(state.next_avail);
+        self.next_used = Wrapping(state.next_used);
+        self.event_idx_enabled = state.event_idx_enabled;
+        self.num_added = Wrapping(0);
+        self.packed = state.packed;
+        self.avail_wrap_counter = state.avail_wrap_counter;
+        self.used_wrap_counter = state.used_wrap_counter;
+        self.last_avail = (self.next_avail, self.avail_wrap_counter);
+        self.packed_chain_len.clear();
+    }
+
+    /// Returns the `idx` field of the used ring of a split queue, i.e. the position of the
+    /// device in the queue as seen by the driver.
+    pub fn used_idx(&self, mem: &GuestMemoryMmap) -> Result<u16, Error> {
+        mem.load(
+            self.used_ring
+                .checked_add(2)
+                .ok_or(Error::AddressOverflow)?,
+            Ordering::Acquire,
+        )
+        .map_err(Error::GuestMemory)
+    }
+
     // Set the value of the `flags` field of the used ring, applying the specified ordering.
     fn set_used_flags(
         &mut self,
@@ -624,6 +850,23 @@ impl Queue {
     // Every access in this method uses `Relaxed` ordering because a fence is added by the caller
     // when appropriate.
     fn set_notification(&mut self, mem: &GuestMemoryMmap, enable: bool) -> Result<(), Error> {
+        if self.packed {
+            // We always use the plain enable/disable modes for the device event suppression
+            // structure, even if `VIRTIO_F_EVENT_IDX` was negotiated.
+            let flags = if enable {
+                VRING_PACKED_EVENT_FLAG_ENABLE
+            } else {
+                VRING_PACKED_EVENT_FLAG_DISABLE
+            };
+            let addr = self
+                .used_ring
+                .checked_add(2)
+                .ok_or(Error::AddressOverflow)?;
+            return mem
+                .store(flags as u16, addr, Ordering::Relaxed)
+                .map_err(Error::GuestMemory);
+        }
+
         if enable {
             if self.event_idx_enabled {
                 // We call `set_avail_event` using the `next_avail` value, instead of reading
@@ -673,6 +916,14 @@ impl Queue {
         // entries. There are situations where we intentionally avoid processing everything in the
         // available ring (which will cause this method to return `true`), but in that case we'll
         // probably not re-enable notifications as we already know there are pending entries.
+        if self.packed {
+            return Ok(self.packed_desc_available(
+                mem,
+                self.next_avail.0,
+                self.avail_wrap_counter,
+                Ordering::Relaxed,
+            ));
+        }
         self.avail_idx(mem, Ordering::Relaxed)
             .map(|idx| idx != self.next_avail)
     }
@@ -687,6 +938,10 @@ impl Queue {
         // Complete all the writes in add_used() before reading the event.
         fence(Ordering::SeqCst);
 
+        if self.packed {
+            return self.needs_notification_packed(mem);
+        }
+
         // The VRING_AVAIL_F_NO_INTERRUPT flag isn't supported yet.
 
         // When the `EVENT_IDX` feature is negotiated, the driver writes into `used_event`
@@ -718,7 +973,7 @@ impl Queue {
     /// Rust does not support bidirectional iterators. This is the only way to revert the effect
     /// of an iterator increment on the queue.
     pub fn go_to_previous_position(&mut self) {
-        self.next_avail -= Wrapping(1);
+        self.undo_pop();
     }
 
     /// Fetch the available ring index (`virtq_avail->idx`) from guest memory.
@@ -734,6 +989,226 @@ impl Queue {
             .map(Wrapping)
             .map_err(Error::GuestMemory)
     }
+
+    // Advance a packed ring `position` by `count` slots, flipping `wrap_counter` every time
+    // the end of the ring is crossed.
+    fn packed_advance(&self, position: &mut Wrapping<u16>, wrap_counter: &mut bool, count: u16) {
+        let size = u32::from(self.actual_size());
+        let mut next = u32::from(position.0) + u32::from(count);
+        if next >= size {
+            next -= size;
+            *wrap_counter = !*wrap_counter;
+        }
+        *position = Wrapping(next as u16);
+    }
+
+    // Load the `flags` field of the descriptor at `position` in the packed ring.
+    fn packed_desc_flags(
+        &self,
+        mem: &GuestMemoryMmap,
+        position: u16,
+        order: Ordering,
+    ) -> Result<u16, Error> {
+        // This can not overflow an u64 since it is working with relatively small numbers compared
+        // to u64::MAX.
+        let offset = u64::from(position) * VIRTQ_PACKED_DESC_SIZE + 14;
+        let addr = self
+            .desc_table
+            .checked_add(offset)
+            .ok_or(Error::AddressOverflow)?;
+
+        mem.load(addr, order).map_err(Error::GuestMemory)
+    }
+
+    // A packed descriptor is available when its AVAIL flag matches the driver's wrap counter
+    // and its USED flag doesn't.
+    fn packed_desc_available(
+        &self,
+        mem: &GuestMemoryMmap,
+        position: u16,
+        wrap_counter: bool,
+        order: Ordering,
+    ) -> bool {
+        match self.packed_desc_flags(mem, position, order) {
+            Ok(flags) => {
+                (flags & VIRTQ_DESC_F_AVAIL != 0) == wrap_counter
+                    && (flags & VIRTQ_DESC_F_USED != 0) != wrap_counter
+            }
+            Err(e) => {
+                error!("failed to read packed descriptor flags: {e}");
+                false
+            }
+        }
+    }
+
+    // Count the available descriptor chains in a packed ring, starting at `next_avail`.
+    fn packed_len(&self, mem: &GuestMemoryMmap) -> u16 {
+        let mut position = self.next_avail;
+        let mut wrap_counter = self.avail_wrap_counter;
+        let mut chains = 0;
+
+        for _ in 0..self.actual_size() {
+            if !self.packed_desc_available(mem, position.0, wrap_counter, Ordering::Acquire) {
+                break;
+            }
+            // The descriptor was just checked to be readable.
+            let flags = self
+                .packed_desc_flags(mem, position.0, Ordering::Relaxed)
+                .unwrap();
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                chains += 1;
+            }
+            self.packed_advance(&mut position, &mut wrap_counter, 1);
+        }
+
+        chains
+    }
+
+    // Pop the next available descriptor chain from a packed ring.
+    //
+    // The descriptors of a chain occupy consecutive ring slots starting at `next_avail`, and the
+    // driver only stores the buffer id in the last one, so the whole chain is walked here to find
+    // it. The chain length is remembered, as `add_used` must advance `next_used` by the same
+    // amount of slots when the buffer is returned.
+    fn pop_packed<'b>(&mut self, mem: &'b GuestMemoryMmap) -> Option<DescriptorChain<'b>> {
+        let size = self.actual_size();
+        if size == 0
+            || !self.packed_desc_available(
+                mem,
+                self.next_avail.0,
+                self.avail_wrap_counter,
+                Ordering::Acquire,
+            )
+        {
+            return None;
+        }
+
+        // Make sure the descriptors are read after their flags.
+        fence(Ordering::Acquire);
+
+        let mut position = self.next_avail.0;
+        let mut count: u16 = 0;
+        let last = loop {
+            let addr = self
+                .desc_table
+                .checked_add(u64::from(position) * VIRTQ_PACKED_DESC_SIZE)?;
+            let desc: PackedDescriptor = match mem.read_obj(addr) {
+                Ok(desc) => desc,
+                Err(e) => {
+                    error!("failed to read packed descriptor: {e}");
+                    return None;
+                }
+            };
+            count += 1;
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break desc;
+            }
+            if count == size {
+                error!("packed descriptor chain is longer than the queue");
+                return None;
+            }
+            position = (position + 1) % size;
+        };
+
+        let id = last.id;
+        let chain = DescriptorChain::checked_new_packed(
+            mem,
+            self.desc_table,
+            size,
+            self.next_avail.0,
+            id,
+            count,
+        )?;
+
+        if self.packed_chain_len.len() < usize::from(size) {
+            self.packed_chain_len.resize(usize::from(size), 0);
+        }
+        self.packed_chain_len[usize::from(id)] = count;
+
+        self.last_avail = (self.next_avail, self.avail_wrap_counter);
+        let (mut next_avail, mut wrap_counter) = self.last_avail;
+        self.packed_advance(&mut next_avail, &mut wrap_counter, count);
+        self.next_avail = next_avail;
+        self.avail_wrap_counter = wrap_counter;
+
+        Some(chain)
+    }
+
+    // Write a used descriptor for buffer `id` at `next_used` in the packed ring.
+    fn add_used_packed(&mut self, mem: &GuestMemoryMmap, id: u16, len: u32) -> Result<(), Error> {
+        let count = match self.packed_chain_len.get(usize::from(id)) {
+            Some(&count) if count != 0 => count,
+            _ => {
+                error!("attempted to add a buffer id that isn't in flight to the used ring: {id}");
+                return Err(Error::InvalidDescriptorIndex);
+            }
+        };
+        self.packed_chain_len[usize::from(id)] = 0;
+
+        let addr = self
+            .desc_table
+            .checked_add(u64::from(self.next_used.0) * VIRTQ_PACKED_DESC_SIZE)
+            .ok_or(Error::AddressOverflow)?;
+        mem.write_obj(len, addr.unchecked_add(8))
+            .map_err(Error::GuestMemory)?;
+        mem.write_obj(id, addr.unchecked_add(12))
+            .map_err(Error::GuestMemory)?;
+
+        let mut flags = if self.used_wrap_counter {
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+        } else {
+            0
+        };
+        if len != 0 {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+
+        let (mut next_used, mut wrap_counter) = (self.next_used, self.used_wrap_counter);
+        self.packed_advance(&mut next_used, &mut wrap_counter, count);
+        self.next_used = next_used;
+        self.used_wrap_counter = wrap_counter;
+        self.num_added += Wrapping(count);
+
+        // The flags must be the last thing the driver sees, as they hand the slot back to it.
+        mem.store(flags, addr.unchecked_add(14), Ordering::Release)
+            .map_err(Error::GuestMemory)
+    }
+
+    // Check the driver event suppression structure of a packed ring.
+    //
+    // When the driver asks for a notification at a specific ring position, the same
+    // `num_added` based inequality used for split queues is applied to ring positions, after
+    // moving the event position back by one ring length if it belongs to the previous lap.
+    fn needs_notification_packed(&mut self, mem: &GuestMemoryMmap) -> Result<bool, Error> {
+        let off_wrap: u16 = mem
+            .load(self.avail_ring, Ordering::Relaxed)
+            .map_err(Error::GuestMemory)?;
+        let flags: u16 = mem
+            .load(
+                self.avail_ring
+                    .checked_add(2)
+                    .ok_or(Error::AddressOverflow)?,
+                Ordering::Relaxed,
+            )
+            .map_err(Error::GuestMemory)?;
+
+        let new = self.next_used;
+        let old = new - self.num_added;
+        self.num_added = Wrapping(0);
+
+        match u32::from(flags) {
+            VRING_PACKED_EVENT_FLAG_DISABLE => Ok(false),
+            VRING_PACKED_EVENT_FLAG_DESC if self.event_idx_enabled => {
+                let wrap_bit = 1 << VRING_PACKED_EVENT_F_WRAP_CTR;
+                let mut event = Wrapping(off_wrap & !wrap_bit);
+                if (off_wrap & wrap_bit != 0) != self.used_wrap_counter {
+                    event -= Wrapping(self.actual_size());
+                }
+                Ok(new - event - Wrapping(1) < new - old)
+            }
+            _ => Ok(true),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1164,4 +1639,288 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    // Lays out a packed ring at 0, followed by the driver and device event suppression
+    // structures, and returns a queue using it.
+    fn packed_queue(qsize: u16) -> Queue {
+        let mut q = Queue::new(qsize);
+        q.size = qsize;
+        q.ready = true;
+        q.desc_table = GuestAddress(0);
+        q.avail_ring = GuestAddress(u64::from(qsize) * VIRTQ_PACKED_DESC_SIZE);
+        q.used_ring = q.avail_ring.unchecked_add(VIRTQ_PACKED_EVENT_SIZE);
+        q.set_packed(true);
+        q
+    }
+
+    fn set_packed_desc(m: &GuestMemoryMmap, position: u16, desc: PackedDescriptor) {
+        m.write_obj(
+            desc,
+            GuestAddress(u64::from(position) * VIRTQ_PACKED_DESC_SIZE),
+        )
+        .unwrap();
+    }
+
+    fn get_packed_desc(m: &GuestMemoryMmap, position: u16) -> PackedDescriptor {
+        m.read_obj(GuestAddress(u64::from(position) * VIRTQ_PACKED_DESC_SIZE))
+            .unwrap()
+    }
+
+    // Flags the driver uses to make a descriptor available with the given wrap counter.
+    fn avail_flags(wrap_counter: bool) -> u16 {
+        if wrap_counter {
+            VIRTQ_DESC_F_AVAIL
+        } else {
+            VIRTQ_DESC_F_USED
+        }
+    }
+
+    #[test]
+    fn test_packed_queue_validation() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = packed_queue(12);
+
+        // Packed queues don't need a power of 2 size.
+        assert!(q.is_valid(m));
+
+        // The event suppression structures must be 4 byte aligned.
+        q.avail_ring = GuestAddress(0x1002);
+        assert!(!q.is_valid(m));
+        q.avail_ring = GuestAddress(0x1000);
+        assert!(q.is_valid(m));
+
+        q.used_ring = GuestAddress(0xffff_ffff);
+        assert!(!q.is_valid(m));
+    }
+
+    #[test]
+    fn test_packed_queue_processing() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = packed_queue(4);
+
+        assert!(q.is_empty(m));
+        assert!(q.pop(m).is_none());
+
+        // A two descriptor chain with buffer id 3, and a single descriptor chain with id 1.
+        let flags = avail_flags(true);
+        for (position, next, id) in [(0, VIRTQ_DESC_F_NEXT, 3), (1, 0, 3), (2, 0, 1)] {
+            set_packed_desc(
+                m,
+                position,
+                PackedDescriptor {
+                    addr: 0x1000 * u64::from(position + 1),
+                    len: 0x100,
+                    id,
+                    flags: flags | next,
+                },
+            );
+        }
+        assert_eq!(q.len(m), 2);
+
+        let c = q.pop(m).unwrap();
+        assert_eq!(c.index, 3);
+        assert_eq!(c.addr, GuestAddress(0x1000));
+        let d = c.next_descriptor().unwrap();
+        assert_eq!(d.index, 3);
+        assert_eq!(d.addr, GuestAddress(0x2000));
+        assert!(d.next_descriptor().is_none());
+        assert_eq!(q.len(m), 1);
+
+        // Undoing a pop walks the same chain again.
+        let c = q.pop(m).unwrap();
+        assert_eq!(c.index, 1);
+        q.undo_pop();
+        let c = q.pop(m).unwrap();
+        assert_eq!(c.index, 1);
+        assert!(q.is_empty(m));
+
+        // Buffers can be returned out of order, each one taking up as many slots as its chain.
+        q.add_used(m, 1, 0x80).unwrap();
+        let used = get_packed_desc(m, 0);
+        assert_eq!(used.id, 1);
+        assert_eq!(used.len, 0x80);
+        assert_eq!(
+            used.flags,
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE
+        );
+        assert_eq!(q.next_used.0, 1);
+
+        q.add_used(m, 3, 0).unwrap();
+        assert_eq!(get_packed_desc(m, 1).id, 3);
+        assert_eq!(q.next_used.0, 3);
+
+        // Returning the same buffer twice isn't allowed.
+        assert!(q.add_used(m, 3, 0).is_err());
+        assert_eq!(q.next_used.0, 3);
+    }
+
+    #[test]
+    fn test_packed_queue_wrap() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = packed_queue(4);
+
+        // Consume and return three single descriptor chains.
+        for position in 0..3 {
+            set_packed_desc(
+                m,
+                position,
+                PackedDescriptor {
+                    addr: 0x1000,
+                    len: 0x100,
+                    id: position,
+                    flags: avail_flags(true),
+                },
+            );
+            let c = q.pop(m).unwrap();
+            q.add_used(m, c.index, 0).unwrap();
+        }
+
+        // The next chain crosses the end of the ring, so its second descriptor is made available
+        // with the flipped wrap counter.
+        set_packed_desc(
+            m,
+            3,
+            PackedDescriptor {
+                addr: 0x1000,
+                len: 0x100,
+                id: 0,
+                flags: avail_flags(true) | VIRTQ_DESC_F_NEXT,
+            },
+        );
+        set_packed_desc(
+            m,
+            0,
+            PackedDescriptor {
+                addr: 0x2000,
+                len: 0x100,
+                id: 0,
+                flags: avail_flags(false),
+            },
+        );
+        assert_eq!(q.len(m), 1);
+
+        let c = q.pop(m).unwrap();
+        assert_eq!(c.next_descriptor().unwrap().addr, GuestAddress(0x2000));
+        assert!(!q.avail_wrap_counter);
+        assert!(q.is_empty(m));
+
+        q.add_used(m, 0, 0).unwrap();
+        assert_eq!(q.next_used.0, 1);
+        assert!(!q.used_wrap_counter);
+        // The used descriptor was written with the old wrap counter.
+        assert_eq!(
+            get_packed_desc(m, 3).flags,
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+        );
+    }
+
+    #[test]
+    fn test_queue_state() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = packed_queue(4);
+
+        // Go around the ring once, flipping both wrap counters.
+        for position in 0..4 {
+            set_packed_desc(
+                m,
+                position,
+                PackedDescriptor {
+                    addr: 0x1000,
+                    len: 0x100,
+                    id: position,
+                    flags: avail_flags(true),
+                },
+            );
+            let c = q.pop(m).unwrap();
+            q.add_used(m, c.index, 0).unwrap();
+        }
+
+        let state = q.state();
+        assert!(!state.avail_wrap_counter && !state.used_wrap_counter);
+        let mut restored = Queue::new(4);
+        restored.set_state(&state);
+        assert_eq!(restored.state(), state);
+
+        // The restored queue picks the next chain up where the original one left off.
+        set_packed_desc(
+            m,
+            0,
+            PackedDescriptor {
+                addr: 0x2000,
+                len: 0x100,
+                id: 1,
+                flags: avail_flags(false),
+            },
+        );
+        let c = restored.pop(m).unwrap();
+        assert_eq!(c.addr, GuestAddress(0x2000));
+        restored.add_used(m, c.index, 0).unwrap();
+        assert_eq!(restored.next_used.0, 1);
+    }
+
+    #[test]
+    fn test_packed_queue_notification() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = packed_queue(4);
+        let driver_flags = q.avail_ring.unchecked_add(2);
+        let device_flags = q.used_ring.unchecked_add(2);
+
+        q.disable_notification(m).unwrap();
+        assert_eq!(
+            m.read_obj::<u16>(device_flags).unwrap(),
+            VRING_PACKED_EVENT_FLAG_DISABLE as u16
+        );
+        assert!(!q.enable_notification(m).unwrap());
+        assert_eq!(
+            m.read_obj::<u16>(device_flags).unwrap(),
+            VRING_PACKED_EVENT_FLAG_ENABLE as u16
+        );
+
+        set_packed_desc(
+            m,
+            0,
+            PackedDescriptor {
+                addr: 0x1000,
+                len: 0x100,
+                id: 0,
+                flags: avail_flags(true),
+            },
+        );
+        assert!(q.enable_notification(m).unwrap());
+
+        let c = q.pop(m).unwrap();
+        q.add_used(m, c.index, 0).unwrap();
+
+        // The driver disabled used buffer notifications.
+        m.write_obj(VRING_PACKED_EVENT_FLAG_DISABLE as u16, driver_flags)
+            .unwrap();
+        assert!(!q.needs_notification(m).unwrap());
+
+        // The driver asks to be notified once the slot at position 2 has been used.
+        q.set_event_idx(true);
+        m.write_obj(VRING_PACKED_EVENT_FLAG_DESC as u16, driver_flags)
+            .unwrap();
+        m.write_obj(2u16 | 1 << VRING_PACKED_EVENT_F_WRAP_CTR, q.avail_ring)
+            .unwrap();
+        for position in 1..3 {
+            set_packed_desc(
+                m,
+                position,
+                PackedDescriptor {
+                    addr: 0x1000,
+                    len: 0x100,
+                    id: position,
+                    flags: avail_flags(true),
+                },
+            );
+        }
+
+        let c = q.pop(m).unwrap();
+        q.add_used(m, c.index, 0).unwrap();
+        assert!(!q.needs_notification(m).unwrap());
+
+        let c = q.pop(m).unwrap();
+        q.add_used(m, c.index, 0).unwrap();
+        assert!(q.needs_notification(m).unwrap());
+    }
 }
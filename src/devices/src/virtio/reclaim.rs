@@ -0,0 +1,286 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Releases guest memory the guest gave up back to the host.
+
+use std::collections::HashMap;
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap};
+
+use super::Queue;
+
+#[derive(Default)]
+struct InUse {
+    // Ranges the VMM populates itself, e.g. the shared memory region holding the DAX windows.
+    reserved: Vec<Range<u64>>,
+    // Queues of the activated devices, keyed by transport.
+    queues: HashMap<usize, Vec<Queue>>,
+}
+
+/// Releases guest memory back to the host, so it no longer counts in the RSS of the VMM. Released
+/// pages read as zeroes the next time they're touched, or as the contents of the file backing a
+/// private mapping of guest memory. The pages of shared mappings are removed from the file or
+/// memfd backing them too.
+///
+/// The ranges still in use are never released: the rings of the queues of the activated virtio
+/// devices, which their transports register, the buffers their descriptors point to and the
+/// ranges the VMM `reserve`s.
+#[derive(Clone, Default)]
+pub struct MemoryReclaimer {
+    in_use: Arc<Mutex<InUse>>,
+}
+
+impl MemoryReclaimer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Never releases the guest memory from `start` to `start + size`.
+    pub fn reserve(&self, start: GuestAddress, size: u64) {
+        self.in_use
+            .lock()
+            .unwrap()
+            .reserved
+            .push(start.0..start.0.saturating_add(size));
+    }
+
+    /// Never releases the rings of `queues`, nor the buffers of their descriptors, until
+    /// `clear_queues` is called with the same `key`.
+    pub(crate) fn set_queues(&self, key: usize, queues: &[Queue]) {
+        let queues = queues.iter().filter(|queue| queue.ready).cloned().collect();
+        self.in_use.lock().unwrap().queues.insert(key, queues);
+    }
+
+    pub(crate) fn clear_queues(&self, key: usize) {
+        self.in_use.lock().unwrap().queues.remove(&key);
+    }
+
+    /// Releases the pages fully covered by `ranges` and not overlapping any range in use, whose
+    /// size is the one of the host pages backing each guest memory region, e.g. huge pages.
+    /// Returns the number of bytes released.
+    pub fn reclaim(&self, mem: &GuestMemoryMmap, ranges: &[Range<GuestAddress>]) -> u64 {
+        let in_use = self.in_use.lock().unwrap();
+        // The descriptors are read now, the buffers of the ones in flight can't change until
+        // they're used.
+        let queue_ranges = in_use.queues.values().flatten().flat_map(|queue| {
+            let mut ranges = queue.buffer_ranges(mem);
+            ranges.extend(queue.ring_ranges());
+            ranges
+        });
+        let mut guarded: Vec<Range<u64>> = queue_ranges
+            .map(|(start, size)| start.0..start.0.saturating_add(size))
+            .chain(in_use.reserved.iter().cloned())
+            .filter(|range| !range.is_empty())
+            .collect();
+        drop(in_use);
+        guarded.sort_by_key(|range| range.start);
+
+        let mut released = 0;
+        for region in mem.iter() {
+            let base = region.start_addr().0;
+            let region_end = base + region.len();
+            let page_size = page_size(region);
+            let align_up = |addr: u64| base + (addr - base).next_multiple_of(page_size);
+            let align_down = |addr: u64| addr - (addr - base) % page_size;
+
+            for range in ranges {
+                let mut start = align_up(range.start.0.clamp(base, region_end));
+                let end = align_down(range.end.0.clamp(base, region_end));
+                for guard in &guarded {
+                    let guard_start = guard.start.clamp(base, region_end);
+                    let guard_end = guard.end.clamp(base, region_end);
+                    if guard_start >= guard_end || align_up(guard_end) <= start {
+                        continue;
+                    }
+                    if align_down(guard_start) >= end {
+                        break;
+                    }
+                    released += release(region, start - base, align_down(guard_start) - base);
+                    start = start.max(align_up(guard_end));
+                }
+                released += release(region, start - base, end.max(start) - base);
+            }
+        }
+        released
+    }
+}
+
+// Returns the size of the host pages backing `region`, which can only be released whole.
+fn page_size(region: &GuestRegionMmap) -> u64 {
+    #[cfg(target_os = "linux")]
+    {
+        let flags = region.flags();
+        let huge_shift = (flags >> libc::MAP_HUGE_SHIFT) & libc::MAP_HUGE_MASK;
+        if flags & libc::MAP_HUGETLB != 0 && huge_shift != 0 {
+            return 1 << huge_shift;
+        }
+        if let Some(file_offset) = region.file_offset() {
+            let mut stat = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+            // Safe because the kernel will only write data in `stat` and we check the return
+            // value.
+            let ret = unsafe { libc::fstatfs(file_offset.file().as_raw_fd(), stat.as_mut_ptr()) };
+            // Safe because the kernel filled `stat` in.
+            let stat = unsafe { stat.assume_init() };
+            if ret == 0 && stat.f_type as u64 == libc::HUGETLBFS_MAGIC as u64 {
+                return stat.f_bsize as u64;
+            }
+        }
+    }
+
+    // Safe because this call just returns the page size and doesn't have any side effects.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+// Releases the bytes of `region` from `offset` to `end`, both aligned to the size of its pages.
+fn release(region: &GuestRegionMmap, offset: u64, end: u64) -> u64 {
+    if offset >= end {
+        return 0;
+    }
+    let len = (end - offset) as usize;
+    // The pages of a shared mapping would stay in the file, or memfd, backing it otherwise.
+    #[cfg(target_os = "linux")]
+    let advice = if region.flags() & libc::MAP_SHARED != 0 {
+        libc::MADV_REMOVE
+    } else {
+        libc::MADV_DONTNEED
+    };
+    #[cfg(not(target_os = "linux"))]
+    let advice = libc::MADV_DONTNEED;
+    let start = region.start_addr().0 + offset;
+    debug!("releasing guest_addr={start:#x} len={len}");
+    // Safe because the range is guest memory within the region, which the guest gave up.
+    let ret = unsafe {
+        libc::madvise(
+            region.as_ptr().add(offset as usize) as *mut libc::c_void,
+            len,
+            advice,
+        )
+    };
+    if ret != 0 {
+        error!(
+            "Cannot release guest memory {start:#x}-{:#x}: {}",
+            start + len as u64,
+            std::io::Error::last_os_error()
+        );
+        return 0;
+    }
+    len as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::queue::Descriptor;
+    use vm_memory::{Address, Bytes};
+
+    // Returns the number of resident pages of `mem` from `start` to `start + len`.
+    fn resident_pages(mem: &GuestMemoryMmap, start: u64, len: usize) -> usize {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+        let slice = mem.get_slice(GuestAddress(start), len).unwrap();
+        let mut vec = vec![0u8; len.div_ceil(page_size)];
+        let ret = unsafe {
+            libc::mincore(
+                slice.ptr_guard_mut().as_ptr() as *mut libc::c_void,
+                len,
+                vec.as_mut_ptr() as *mut _,
+            )
+        };
+        assert_eq!(ret, 0);
+        vec.iter().filter(|page| *page & 1 != 0).count()
+    }
+
+    #[test]
+    fn test_reclaim() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        let len = 256 * page_size;
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), len as usize)]).unwrap();
+        mem.write_slice(&vec![0xaa; len as usize], GuestAddress(0))
+            .unwrap();
+        assert_eq!(resident_pages(&mem, 0, len as usize), 256);
+
+        let reclaimer = MemoryReclaimer::new();
+        reclaimer.reserve(GuestAddress(page_size), 1);
+        let mut queue = Queue::new(16);
+        queue.ready = true;
+        queue.size = 16;
+        queue.desc_table = GuestAddress(0x10 * page_size);
+        queue.avail_ring = GuestAddress(0x10 * page_size + 0x100);
+        queue.used_ring = GuestAddress(0x11 * page_size);
+        // The buffer of a descriptor, possibly in flight.
+        mem.write_slice(&[0; 16 * 16], queue.desc_table).unwrap();
+        mem.write_obj(
+            Descriptor {
+                addr: 0x20 * page_size,
+                len: 1,
+                ..Default::default()
+            },
+            queue.desc_table.unchecked_add(16),
+        )
+        .unwrap();
+        reclaimer.set_queues(0, &[queue]);
+
+        let released = reclaimer.reclaim(&mem, &[GuestAddress(0)..GuestAddress(len)]);
+        assert_eq!(released, (256 - 4) * page_size);
+        assert_eq!(resident_pages(&mem, 0, len as usize), 4);
+
+        // Once the device is reset its rings can be released too, but partial pages never are.
+        reclaimer.clear_queues(0);
+        let released = reclaimer.reclaim(
+            &mem,
+            &[GuestAddress(0x10 * page_size)..GuestAddress(0x12 * page_size - 1)],
+        );
+        assert_eq!(released, page_size);
+        assert_eq!(resident_pages(&mem, 0, len as usize), 3);
+
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0)).unwrap(), 0);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(page_size)).unwrap(), 0xaa);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x11 * page_size)).unwrap(),
+            0xaa
+        );
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x20 * page_size)).unwrap(),
+            0xaa
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reclaim_shared() {
+        use std::fs::File;
+        use std::os::unix::fs::MetadataExt;
+        use std::os::unix::io::FromRawFd;
+        use vm_memory::{FileOffset, MmapRegion};
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        let len = 16 * page_size;
+        let fd = unsafe { libc::memfd_create(c"guest-memory".as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(len).unwrap();
+        let region = MmapRegion::build(
+            Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+            len as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+        )
+        .unwrap();
+        let mem =
+            GuestMemoryMmap::from_regions(vec![
+                GuestRegionMmap::new(region, GuestAddress(0)).unwrap()
+            ])
+            .unwrap();
+        mem.write_slice(&vec![0xaa; len as usize], GuestAddress(0))
+            .unwrap();
+        assert_eq!(file.metadata().unwrap().blocks() * 512, len);
+
+        // The pages are gone from the memfd too.
+        let released = MemoryReclaimer::new().reclaim(&mem, &[GuestAddress(0)..GuestAddress(len)]);
+        assert_eq!(released, len);
+        assert_eq!(file.metadata().unwrap().blocks(), 0);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0)).unwrap(), 0);
+    }
+}
@@ -1,14 +1,18 @@
+use std::num::NonZeroU64;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use rand::{rngs::OsRng, RngCore};
 use utils::eventfd::EventFd;
+use utils::timerfd::TimerFd;
 use vm_memory::{Bytes, GuestMemoryMmap};
 
+use super::super::token_bucket::TokenBucket;
 use super::super::{
-    ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, RngError, VirtioDevice,
-    VIRTIO_MMIO_INT_VRING,
+    ActivateError, ActivateResult, DeviceSnapshot, DeviceState, Queue as VirtQueue, RngError,
+    VirtioDevice, VIRTIO_MMIO_INT_VRING,
 };
 use super::{defs, defs::uapi};
 use crate::legacy::Gic;
@@ -33,6 +37,10 @@ pub struct Rng {
     pub(crate) interrupt_evt: EventFd,
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
+    // Expires once the guest may get more bytes after being throttled.
+    pub(crate) refill_timer: TimerFd,
+    refill_pending: bool,
+    rate_limiter: Option<TokenBucket>,
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
 }
@@ -54,6 +62,9 @@ impl Rng {
             interrupt_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(RngError::EventFd)?,
             activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(RngError::EventFd)?,
             device_state: DeviceState::Inactive,
+            refill_timer: TimerFd::new().map_err(RngError::TimerFd)?,
+            refill_pending: false,
+            rate_limiter: None,
             intc: None,
             irq_line: None,
         })
@@ -75,6 +86,33 @@ impl Rng {
         self.intc = Some(intc);
     }
 
+    /// Caps the entropy handed to the guest to `bytes_per_sec`, or lifts the cap with `None`.
+    /// Requests get as many bytes as the budget allows, and are left pending while it's
+    /// exhausted rather than answered with no bytes.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<NonZeroU64>) {
+        self.rate_limiter = bytes_per_sec.map(TokenBucket::new);
+    }
+
+    // Arms `refill_timer` to expire once the throttled guest may get bytes again.
+    fn schedule_refill(&mut self, wait: Duration) {
+        if self.refill_pending {
+            return;
+        }
+        // A zero duration would disarm the timer instead.
+        match self
+            .refill_timer
+            .reset(wait.max(Duration::from_nanos(1)), None)
+        {
+            Ok(()) => self.refill_pending = true,
+            Err(e) => error!("rng: failed to arm the refill timer: {:?}", e),
+        }
+    }
+
+    pub(crate) fn handle_refill(&mut self) -> bool {
+        self.refill_pending = false;
+        self.process_req()
+    }
+
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         debug!("rng: raising IRQ");
         self.interrupt_status
@@ -99,19 +137,39 @@ impl Rng {
         };
 
         let mut have_used = false;
+        let mut throttled = None;
 
         while let Some(head) = self.queues[REQ_INDEX].pop(mem) {
+            let mut budget = match self.rate_limiter.as_mut() {
+                Some(bucket) => bucket.available(),
+                None => u64::MAX,
+            };
+            if budget == 0 {
+                // Answered once the bucket has refilled.
+                self.queues[REQ_INDEX].go_to_previous_position();
+                throttled = self.rate_limiter.as_ref().map(TokenBucket::refill_time);
+                break;
+            }
+
             let index = head.index;
             let mut written = 0;
             for desc in head.into_iter() {
-                let mut rand_bytes = vec![0u8; desc.len as usize];
+                let len = u64::from(desc.len).min(budget) as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut rand_bytes = vec![0u8; len];
                 OsRng.fill_bytes(&mut rand_bytes);
                 if let Err(e) = mem.write_slice(&rand_bytes[..], desc.addr) {
                     error!("Failed to write slice: {:?}", e);
                     self.queues[REQ_INDEX].go_to_previous_position();
                     break;
                 }
-                written += desc.len;
+                written += len as u32;
+                budget -= len as u64;
+            }
+            if let Some(bucket) = self.rate_limiter.as_mut() {
+                bucket.consume(u64::from(written));
             }
 
             have_used = true;
@@ -120,6 +178,10 @@ impl Rng {
             }
         }
 
+        if let Some(wait) = throttled {
+            self.schedule_refill(wait);
+        }
+
         have_used
     }
 }
@@ -204,6 +266,19 @@ impl VirtioDevice for Rng {
         }
     }
 
+    /// The requests are answered on the event loop, so the queues are up to date. Throttled ones
+    /// are answered right away after a restore.
+    fn snapshot(&self) -> Option<DeviceSnapshot> {
+        Some(DeviceSnapshot {
+            queues: self.queues.iter().map(VirtQueue::state).collect(),
+            data: Vec::new(),
+        })
+    }
+
+    fn restore_snapshot(&mut self, data: &[u8]) -> bool {
+        data.is_empty()
+    }
+
     fn reset(&mut self) -> bool {
         // Strictly speaking, we should unsubscribe the queue events resubscribe
         // the activate eventfd and deactivate the device, but we don't support
@@ -212,3 +287,54 @@ impl VirtioDevice for Rng {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::queue::tests::{VirtQueue as GuestQ, VIRTQ_DESC_F_WRITE};
+    use std::thread;
+    use std::time::Instant;
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_rate_limit() {
+        const RATE: u64 = 4096;
+        const QUEUE_SIZE: u16 = 256;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = GuestQ::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        // The guest asks for four times what it may get in a second.
+        for i in 0..QUEUE_SIZE {
+            vq.dtable[i as usize].set(0x4000 + u64::from(i) * 64, 64, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[i as usize].set(i);
+        }
+        vq.avail.idx.set(QUEUE_SIZE);
+
+        let mut rng = Rng::new().unwrap();
+        rng.queues[REQ_INDEX] = vq.create_queue();
+        rng.device_state = DeviceState::Activated(mem.clone());
+        let start = Instant::now();
+        rng.set_rate_limit(NonZeroU64::new(RATE));
+
+        while start.elapsed() < Duration::from_secs(1) {
+            rng.handle_refill();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let elapsed = start.elapsed();
+
+        let used = vq.used.idx.get();
+        assert!(used < QUEUE_SIZE);
+        let written: u64 = vq.used.ring[..used as usize]
+            .iter()
+            .map(|elem| u64::from(elem.get().len))
+            .sum();
+        assert!(written as f64 <= RATE as f64 * elapsed.as_secs_f64());
+        // Throttled, not starved.
+        assert!(written >= RATE / 2);
+
+        // The requests left pending are answered once the refill timer expires.
+        assert!(rng.refill_timer.wait().unwrap() >= 1);
+        assert!(rng.handle_refill());
+        assert!(vq.used.idx.get() > used);
+    }
+}
@@ -23,6 +23,22 @@ impl Rng {
         }
     }
 
+    fn handle_refill_event(&mut self, event: &EpollEvent) {
+        debug!("rng: refill event");
+
+        let event_set = event.event_set();
+        if event_set != EventSet::IN {
+            warn!("rng: refill unexpected event {:?}", event_set);
+            return;
+        }
+
+        if let Err(e) = self.refill_timer.wait() {
+            error!("Failed to read refill timer: {:?}", e);
+        } else if self.handle_refill() {
+            self.signal_used_queue().unwrap();
+        }
+    }
+
     fn handle_activate_event(&self, event_manager: &mut EventManager) {
         debug!("rng: activate event");
         if let Err(e) = self.activate_evt.read() {
@@ -48,6 +64,19 @@ impl Rng {
                 error!("Failed to register rng frq with event manager: {:?}", e);
             });
 
+        event_manager
+            .register(
+                self.refill_timer.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, self.refill_timer.as_raw_fd() as u64),
+                self_subscriber.clone(),
+            )
+            .unwrap_or_else(|e| {
+                error!(
+                    "Failed to register rng refill timer with event manager: {:?}",
+                    e
+                );
+            });
+
         event_manager
             .unregister(self.activate_evt.as_raw_fd())
             .unwrap_or_else(|e| {
@@ -60,11 +89,13 @@ impl Subscriber for Rng {
     fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
         let source = event.fd();
         let req = self.queue_events[REQ_INDEX].as_raw_fd();
+        let refill_timer = self.refill_timer.as_raw_fd();
         let activate_evt = self.activate_evt.as_raw_fd();
 
         if self.is_activated() {
             match source {
                 _ if source == req => self.handle_req_event(event),
+                _ if source == refill_timer => self.handle_refill_event(event),
                 _ if source == activate_evt => {
                     self.handle_activate_event(event_manager);
                 }
@@ -19,6 +19,8 @@ mod defs {
 pub enum RngError {
     /// Failed to create event fd.
     EventFd(std::io::Error),
+    /// Failed to create the refill timer.
+    TimerFd(utils::errno::Error),
 }
 
 type Result<T> = std::result::Result<T, RngError>;
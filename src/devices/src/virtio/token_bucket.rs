@@ -0,0 +1,63 @@
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+// Token bucket holding what the guest may still get or send, e.g. bytes, refilled at `rate` tokens
+// per second. It starts empty and holds at most a tenth of a second worth of tokens, so the guest
+// never gets much more than `rate` tokens over any second.
+//
+// Taking more tokens than the bucket holds leaves it in debt, for the requests that can't be split
+// to go through even if they're larger than the bucket, at the cost of waiting for the debt to be
+// paid off before the next one.
+pub(crate) struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: i128,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: NonZeroU64) -> Self {
+        let rate = rate.get();
+        TokenBucket {
+            rate,
+            capacity: (rate / 10).max(1),
+            tokens: 0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Returns the number of tokens the guest may take right now.
+    pub(crate) fn available(&mut self) -> u64 {
+        let now = Instant::now();
+        let refill = (now - self.last_refill).as_nanos() * self.rate as u128 / 1_000_000_000;
+        if refill > 0 {
+            self.tokens = (self.tokens + refill as i128).min(self.capacity.into());
+            // Only account for the time the refilled tokens took, not to lose the remainder.
+            let elapsed = Duration::from_nanos((refill * 1_000_000_000 / self.rate as u128) as u64);
+            self.last_refill = if self.tokens == self.capacity.into() {
+                now
+            } else {
+                self.last_refill + elapsed
+            };
+        }
+        self.tokens.max(0) as u64
+    }
+
+    pub(crate) fn consume(&mut self, tokens: u64) {
+        self.tokens -= i128::from(tokens);
+    }
+
+    // Time it takes for the bucket to fill up from empty.
+    #[cfg(not(feature = "tee"))]
+    pub(crate) fn refill_time(&self) -> Duration {
+        Duration::from_nanos((self.capacity as u128 * 1_000_000_000 / self.rate as u128) as u64)
+    }
+
+    // Time it takes for the bucket to get a token again, as of the last call to `available`.
+    #[cfg(feature = "net")]
+    pub(crate) fn wait_time(&self) -> Duration {
+        let missing = (1 - self.tokens).max(0) as u128;
+        let nanos = (missing * 1_000_000_000).div_ceil(self.rate as u128);
+        Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+    }
+}
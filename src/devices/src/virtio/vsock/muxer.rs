@@ -12,7 +12,7 @@ use super::defs::uapi;
 use super::muxer_rxq::{rx_to_pkt, MuxerRxQ};
 use super::muxer_thread::MuxerThread;
 use super::packet::{TsiConnectReq, TsiGetnameRsp, VsockPacket};
-use super::proxy::{Proxy, ProxyRemoval, ProxyUpdate};
+use super::proxy::{Proxy, ProxyRemoval, ProxyStatus, ProxyUpdate};
 use super::reaper::ReaperThread;
 use super::tcp::TcpProxy;
 #[cfg(target_os = "macos")]
@@ -498,33 +498,54 @@ impl VsockMuxer {
 
         if let Some(proxy) = proxy_map.get(&id) {
             proxy.lock().unwrap().confirm_connect(pkt)
-        } else if let Some(ref mut ipc_map) = &mut self.unix_ipc_port_map {
-            if let Some(path) = ipc_map.get(&pkt.dst_port()) {
-                let mem = self.mem.as_ref().unwrap();
-                let queue = self.queue.as_ref().unwrap();
-                let rxq = self.rxq.clone();
+        } else if let Some(path) = self
+            .unix_ipc_port_map
+            .as_ref()
+            .and_then(|ipc_map| ipc_map.get(&pkt.dst_port()))
+        {
+            let mem = self.mem.as_ref().unwrap();
+            let queue = self.queue.as_ref().unwrap();
+            let rxq = self.rxq.clone();
 
-                let mut unix = UnixProxy::new(
-                    id,
-                    self.cid,
+            let mut unix = match UnixProxy::new(
+                id,
+                self.cid,
+                pkt.dst_port(),
+                pkt.src_port(),
+                mem.clone(),
+                queue.clone(),
+                rxq,
+                path.to_path_buf(),
+            ) {
+                Ok(unix) => unix,
+                Err(e) => {
+                    warn!("vsock: couldn't create a socket for {:?}: {:?}", path, e);
+                    self.push_reset(pkt);
+                    return;
+                }
+            };
+            let tsi = TsiConnectReq {
+                peer_port: 0,
+                addr: Ipv4Addr::new(0, 0, 0, 0),
+                port: 0,
+            };
+            let update = unix.connect(pkt, tsi);
+            // Nobody is listening on the host socket.
+            if unix.status() == ProxyStatus::Idle {
+                debug!(
+                    "vsock: couldn't connect port {} to {:?}",
                     pkt.dst_port(),
-                    pkt.src_port(),
-                    mem.clone(),
-                    queue.clone(),
-                    rxq,
-                    path.to_path_buf(),
-                )
-                .unwrap();
-                let tsi = TsiConnectReq {
-                    peer_port: 0,
-                    addr: Ipv4Addr::new(0, 0, 0, 0),
-                    port: 0,
-                };
-                let update = unix.connect(pkt, tsi);
-                unix.confirm_connect(pkt);
-                proxy_map.insert(id, Mutex::new(Box::new(unix)));
-                self.process_proxy_update(id, update);
+                    path
+                );
+                self.push_reset(pkt);
+                return;
             }
+            unix.confirm_connect(pkt);
+            proxy_map.insert(id, Mutex::new(Box::new(unix)));
+            self.process_proxy_update(id, update);
+        } else {
+            debug!("vsock: no route for port {}, sending reset", pkt.dst_port());
+            self.push_reset(pkt);
         }
     }
 
@@ -589,30 +610,35 @@ impl VsockMuxer {
             self.process_proxy_update(id, update);
         } else {
             debug!("vsock: invalid OP_RW for {}, sending reset", pkt.src_port());
-            let mem = match self.mem.as_ref() {
-                Some(m) => m,
-                None => {
-                    warn!("OP_RW without mem");
-                    return;
-                }
-            };
-            let queue = match self.queue.as_ref() {
-                Some(q) => q,
-                None => {
-                    warn!("OP_RW without queue");
-                    return;
-                }
-            };
-
-            // This response goes to the connection.
-            let rx = MuxerRx::Reset {
-                local_port: pkt.dst_port(),
-                peer_port: pkt.src_port(),
-            };
-            push_packet(self.cid, rx, &self.rxq, queue, mem);
+            self.push_reset(pkt);
         }
     }
 
+    // Resets the connection `pkt` belongs to.
+    fn push_reset(&self, pkt: &VsockPacket) {
+        let mem = match self.mem.as_ref() {
+            Some(m) => m,
+            None => {
+                warn!("reset without mem");
+                return;
+            }
+        };
+        let queue = match self.queue.as_ref() {
+            Some(q) => q,
+            None => {
+                warn!("reset without queue");
+                return;
+            }
+        };
+
+        // This response goes to the connection.
+        let rx = MuxerRx::Reset {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+        };
+        push_packet(self.cid, rx, &self.rxq, queue, mem);
+    }
+
     fn process_stream_rst(&self, pkt: &VsockPacket) {
         debug!("vsock: OP_RST");
         let id: u64 = (pkt.src_port() as u64) << 32 | pkt.dst_port() as u64;
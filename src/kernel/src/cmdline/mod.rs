@@ -140,6 +140,20 @@ impl Cmdline {
         Ok(())
     }
 
+    /// Validates and inserts a key without a value into this command line.
+    pub fn insert_flag<T: AsRef<str>>(&mut self, key: T) -> Result<()> {
+        let k = key.as_ref();
+
+        valid_element(k)?;
+        self.has_capacity(k.len())?;
+
+        self.start_push();
+        self.line.push_str(k);
+        self.end_push();
+
+        Ok(())
+    }
+
     /// Validates and inserts a string to the end of the current command line.
     pub fn insert_str<T: AsRef<str>>(&mut self, slug: T) -> Result<()> {
         let s = slug.as_ref();
@@ -183,6 +197,16 @@ mod tests {
         assert_eq!(cl2.as_str(), cl.as_str());
     }
 
+    #[test]
+    fn insert_flag() {
+        let mut cl = Cmdline::new(100);
+        assert!(cl.insert("hello", "world").is_ok());
+        assert!(cl.insert_flag("quiet").is_ok());
+        assert_eq!(cl.as_str(), "hello=world quiet");
+        assert_eq!(cl.insert_flag("a b"), Err(Error::HasSpace));
+        assert_eq!(cl.insert_flag("a=b"), Err(Error::HasEquals));
+    }
+
     #[test]
     fn insert_multi() {
         let mut cl = Cmdline::new(100);
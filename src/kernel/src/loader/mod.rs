@@ -10,6 +10,7 @@
 use std;
 use std::ffi::CString;
 use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
 
 use super::cmdline::Error as CmdlineError;
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
@@ -22,11 +23,16 @@ pub enum Error {
     InvalidProgramHeaderSize,
     InvalidProgramHeaderOffset,
     InvalidProgramHeaderAddress,
+    InvalidSectionHeaderSize,
     ReadKernelDataStruct(&'static str),
     ReadKernelImage,
     SeekKernelStart,
     SeekKernelImage,
     SeekProgramHeader,
+    UnknownKernelFormat,
+    UnsupportedElfClass,
+    UnsupportedBzImage,
+    InvalidLoadBuffer,
 }
 
 impl fmt::Display for Error {
@@ -41,6 +47,7 @@ impl fmt::Display for Error {
                 Error::InvalidProgramHeaderSize => "Invalid ELF program header size",
                 Error::InvalidProgramHeaderOffset => "Invalid ELF program header offset",
                 Error::InvalidProgramHeaderAddress => "Invalid ELF program header address",
+                Error::InvalidSectionHeaderSize => "Invalid ELF section header size",
                 Error::ReadKernelDataStruct(e) => e,
                 Error::ReadKernelImage => "Failed to write kernel image to guest memory",
                 Error::SeekKernelStart => {
@@ -48,6 +55,14 @@ impl fmt::Display for Error {
                 }
                 Error::SeekKernelImage => "Failed to seek to offset of kernel image",
                 Error::SeekProgramHeader => "Failed to seek to ELF program header",
+                Error::UnknownKernelFormat => {
+                    "Kernel image is neither an ELF, a bzImage nor an arm64 Image"
+                }
+                Error::UnsupportedElfClass => "Only 64-bit ELF kernels are supported",
+                Error::UnsupportedBzImage => {
+                    "bzImage kernels can't be loaded directly, use the ELF vmlinux"
+                }
+                Error::InvalidLoadBuffer => "The kernel image doesn't fit in the load buffer",
             }
         )
     }
@@ -55,6 +70,453 @@ impl fmt::Display for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELF_HEADER_SIZE: usize = 64;
+const ELF_PROGRAM_HEADER_SIZE: usize = 56;
+const ELF_SECTION_HEADER_SIZE: usize = 64;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const SHT_NULL: u32 = 0;
+// Note holding the 32-bit PVH entry point, see xen/include/public/elfnote.h.
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+const XEN_ELFNOTE_NAME: &[u8] = b"Xen\0";
+
+// Offsets in the bzImage setup header, see Documentation/arch/x86/boot.rst.
+const BZIMAGE_MAGIC: &[u8] = b"HdrS";
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+const BZIMAGE_CODE32_START_OFFSET: usize = 0x214;
+
+// Offsets in the arm64 Image header, see Documentation/arch/arm64/booting.rst.
+const ARM64_IMAGE_MAGIC: &[u8] = b"ARM\x64";
+const ARM64_IMAGE_MAGIC_OFFSET: usize = 0x38;
+const ARM64_IMAGE_TEXT_OFFSET_OFFSET: usize = 0x8;
+const ARM64_IMAGE_SIZE_OFFSET: usize = 0x10;
+
+// Large enough for any of the image headers above.
+const PROBE_SIZE: usize = 0x240;
+
+/// Boot protocol a kernel image is meant to be started with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BootProtocol {
+    /// An ELF `vmlinux`, entered in 64-bit mode at its ELF entry point.
+    Elf,
+    /// An ELF `vmlinux` with a PVH note, which can also be entered in 32-bit mode.
+    Pvh,
+    /// An x86 `bzImage`, following the Linux/x86 boot protocol.
+    BzImage,
+    /// An arm64 `Image`.
+    Arm64Image,
+}
+
+/// A section of an ELF kernel image.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KernelSection {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// What the loader found in a kernel image.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KernelInfo {
+    /// Entry point from the ELF header, or the one the boot protocol defines for other formats.
+    pub entry_addr: u64,
+    pub boot_protocol: BootProtocol,
+    /// PVH entry point, if the image has a `XEN_ELFNOTE_PHYS32_ENTRY` note.
+    pub pvh_entry_addr: Option<u64>,
+    /// The sections of an ELF image, empty for other formats.
+    pub sections: Vec<KernelSection>,
+}
+
+fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// Reads `len` bytes at `offset`, refusing ranges past the end of the image so a corrupted header
+// can't make us allocate an arbitrary amount of memory.
+fn read_at<F: Read + Seek>(
+    kernel_image: &mut F,
+    image_size: u64,
+    offset: u64,
+    len: u64,
+    what: &'static str,
+) -> Result<Vec<u8>> {
+    if !matches!(offset.checked_add(len), Some(end) if end <= image_size) {
+        return Err(Error::ReadKernelDataStruct(what));
+    }
+    kernel_image
+        .seek(SeekFrom::Start(offset))
+        .map_err(|_| Error::SeekKernelImage)?;
+    let mut buf = vec![0; len as usize];
+    kernel_image
+        .read_exact(&mut buf)
+        .map_err(|_| Error::ReadKernelDataStruct(what))?;
+    Ok(buf)
+}
+
+// Looks for the PVH entry point in the contents of a `PT_NOTE` segment.
+fn find_pvh_entry(notes: &[u8]) -> Option<u64> {
+    let align4 = |len: usize| (len + 3) & !3;
+    let mut offset = 0;
+    while offset + 12 <= notes.len() {
+        let name_size = le_u32(notes, offset) as usize;
+        let desc_size = le_u32(notes, offset + 4) as usize;
+        let note_type = le_u32(notes, offset + 8);
+        let name_start = offset + 12;
+        let desc_start = name_start + align4(name_size);
+        let next = desc_start + align4(desc_size);
+        if next > notes.len() {
+            break;
+        }
+
+        if note_type == XEN_ELFNOTE_PHYS32_ENTRY
+            && &notes[name_start..name_start + name_size] == XEN_ELFNOTE_NAME
+        {
+            return match desc_size {
+                4 => Some(le_u32(notes, desc_start) as u64),
+                8 => Some(le_u64(notes, desc_start)),
+                _ => None,
+            };
+        }
+        offset = next;
+    }
+    None
+}
+
+fn inspect_elf<F: Read + Seek>(kernel_image: &mut F, image_size: u64) -> Result<KernelInfo> {
+    let ehdr = read_at(
+        kernel_image,
+        image_size,
+        0,
+        ELF_HEADER_SIZE as u64,
+        "Failed to read ELF header",
+    )?;
+    if ehdr[EI_CLASS] != ELFCLASS64 {
+        return Err(Error::UnsupportedElfClass);
+    }
+    if ehdr[EI_DATA] != ELFDATA2LSB {
+        return Err(Error::BigEndianElfOnLittle);
+    }
+
+    let entry_addr = le_u64(&ehdr, 0x18);
+    let phoff = le_u64(&ehdr, 0x20);
+    let shoff = le_u64(&ehdr, 0x28);
+    let phentsize = le_u16(&ehdr, 0x36) as usize;
+    let phnum = le_u16(&ehdr, 0x38) as u64;
+    let shentsize = le_u16(&ehdr, 0x3a) as usize;
+    let shnum = le_u16(&ehdr, 0x3c) as u64;
+    let shstrndx = le_u16(&ehdr, 0x3e) as usize;
+
+    if phentsize != ELF_PROGRAM_HEADER_SIZE {
+        return Err(Error::InvalidProgramHeaderSize);
+    }
+    if phoff < ELF_HEADER_SIZE as u64 {
+        return Err(Error::InvalidProgramHeaderOffset);
+    }
+    let phdrs = read_at(
+        kernel_image,
+        image_size,
+        phoff,
+        phnum * ELF_PROGRAM_HEADER_SIZE as u64,
+        "Failed to read ELF program headers",
+    )?;
+
+    let mut entry_in_segment = false;
+    let mut pvh_entry_addr = None;
+    for phdr in phdrs.chunks_exact(ELF_PROGRAM_HEADER_SIZE) {
+        match le_u32(phdr, 0) {
+            PT_LOAD => {
+                let memsz = le_u64(phdr, 40);
+                // Linux puts the physical entry point in the header, other kernels may not.
+                entry_in_segment |= [le_u64(phdr, 16), le_u64(phdr, 24)]
+                    .into_iter()
+                    .any(|start| (start..start.saturating_add(memsz)).contains(&entry_addr));
+            }
+            PT_NOTE if pvh_entry_addr.is_none() => {
+                let notes = read_at(
+                    kernel_image,
+                    image_size,
+                    le_u64(phdr, 8),
+                    le_u64(phdr, 32),
+                    "Failed to read ELF notes",
+                )?;
+                pvh_entry_addr = find_pvh_entry(&notes);
+            }
+            _ => (),
+        }
+    }
+    if !entry_in_segment {
+        return Err(Error::InvalidEntryAddress);
+    }
+
+    let mut sections = Vec::new();
+    if shoff != 0 && shnum != 0 {
+        if shentsize != ELF_SECTION_HEADER_SIZE {
+            return Err(Error::InvalidSectionHeaderSize);
+        }
+        let shdrs = read_at(
+            kernel_image,
+            image_size,
+            shoff,
+            shnum * ELF_SECTION_HEADER_SIZE as u64,
+            "Failed to read ELF section headers",
+        )?;
+        let names = match shdrs.chunks_exact(ELF_SECTION_HEADER_SIZE).nth(shstrndx) {
+            Some(shdr) => read_at(
+                kernel_image,
+                image_size,
+                le_u64(shdr, 24),
+                le_u64(shdr, 32),
+                "Failed to read ELF section names",
+            )?,
+            None => Vec::new(),
+        };
+
+        for shdr in shdrs.chunks_exact(ELF_SECTION_HEADER_SIZE) {
+            if le_u32(shdr, 4) == SHT_NULL {
+                continue;
+            }
+            let name = names
+                .get(le_u32(shdr, 0) as usize..)
+                .and_then(|name| name.split(|&b| b == 0).next())
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .unwrap_or_default();
+            sections.push(KernelSection {
+                name,
+                addr: le_u64(shdr, 16),
+                size: le_u64(shdr, 32),
+            });
+        }
+    }
+
+    Ok(KernelInfo {
+        entry_addr,
+        boot_protocol: if pvh_entry_addr.is_some() {
+            BootProtocol::Pvh
+        } else {
+            BootProtocol::Elf
+        },
+        pvh_entry_addr,
+        sections,
+    })
+}
+
+/// Inspects a kernel image without loading it in guest memory, returning what the loader found
+/// in it: its format and entry point, whether it has a PVH note and, for ELF images, a summary of
+/// its sections. Fails with the reason the image can't be booted, if it can tell.
+///
+/// # Arguments
+///
+/// * `kernel_image` - Input kernel image: an ELF `vmlinux`, an x86 `bzImage` or an arm64 `Image`.
+pub fn inspect_kernel<F: Read + Seek>(kernel_image: &mut F) -> Result<KernelInfo> {
+    let image_size = kernel_image
+        .seek(SeekFrom::End(0))
+        .map_err(|_| Error::SeekKernelImage)?;
+    kernel_image
+        .seek(SeekFrom::Start(0))
+        .map_err(|_| Error::SeekKernelStart)?;
+    let mut probe = Vec::with_capacity(PROBE_SIZE);
+    kernel_image
+        .by_ref()
+        .take(PROBE_SIZE as u64)
+        .read_to_end(&mut probe)
+        .map_err(|_| Error::ReadKernelDataStruct("Failed to read kernel image header"))?;
+
+    let has_magic =
+        |magic: &[u8], offset: usize| probe.get(offset..offset + magic.len()) == Some(magic);
+    if has_magic(ELF_MAGIC, 0) {
+        inspect_elf(kernel_image, image_size)
+    } else if has_magic(BZIMAGE_MAGIC, BZIMAGE_MAGIC_OFFSET) {
+        Ok(KernelInfo {
+            entry_addr: le_u32(&probe, BZIMAGE_CODE32_START_OFFSET) as u64,
+            boot_protocol: BootProtocol::BzImage,
+            pvh_entry_addr: None,
+            sections: Vec::new(),
+        })
+    } else if has_magic(ARM64_IMAGE_MAGIC, ARM64_IMAGE_MAGIC_OFFSET) {
+        Ok(KernelInfo {
+            entry_addr: le_u64(&probe, ARM64_IMAGE_TEXT_OFFSET_OFFSET),
+            boot_protocol: BootProtocol::Arm64Image,
+            pvh_entry_addr: None,
+            sections: Vec::new(),
+        })
+    } else {
+        Err(Error::UnknownKernelFormat)
+    }
+}
+
+/// Where a kernel image goes in guest memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KernelLayout {
+    /// Lowest guest physical address the image occupies.
+    pub start_addr: u64,
+    /// Guest physical address right after the highest one the image occupies, including the
+    /// memory it expects to be zeroed past its file contents.
+    pub end_addr: u64,
+    /// Guest physical address the vCPUs start at.
+    pub entry_addr: u64,
+}
+
+// Part of the image file copied to guest memory, zero extended to `mem_size` bytes.
+struct Segment {
+    addr: u64,
+    offset: u64,
+    file_size: u64,
+    mem_size: u64,
+}
+
+// Returns the entry point of `kernel_image` and the segments it's loaded from.
+fn segments<F: Read + Seek>(kernel_image: &mut F, image_base: u64) -> Result<(u64, Vec<Segment>)> {
+    let info = inspect_kernel(kernel_image)?;
+    let image_size = kernel_image
+        .seek(SeekFrom::End(0))
+        .map_err(|_| Error::SeekKernelImage)?;
+
+    match info.boot_protocol {
+        BootProtocol::Elf | BootProtocol::Pvh => {
+            let ehdr = read_at(
+                kernel_image,
+                image_size,
+                0,
+                ELF_HEADER_SIZE as u64,
+                "Failed to read ELF header",
+            )?;
+            let phdrs = read_at(
+                kernel_image,
+                image_size,
+                le_u64(&ehdr, 0x20),
+                le_u16(&ehdr, 0x38) as u64 * ELF_PROGRAM_HEADER_SIZE as u64,
+                "Failed to read ELF program headers",
+            )?;
+            let mut segments = Vec::new();
+            let mut entry_addr = info.entry_addr;
+            for phdr in phdrs.chunks_exact(ELF_PROGRAM_HEADER_SIZE) {
+                if le_u32(phdr, 0) != PT_LOAD {
+                    continue;
+                }
+                let segment = Segment {
+                    addr: le_u64(phdr, 24),
+                    offset: le_u64(phdr, 8),
+                    file_size: le_u64(phdr, 32),
+                    mem_size: le_u64(phdr, 40),
+                };
+                if segment.file_size > segment.mem_size
+                    || segment.addr.checked_add(segment.mem_size).is_none()
+                    || !matches!(segment.offset.checked_add(segment.file_size),
+                        Some(end) if end <= image_size)
+                {
+                    return Err(Error::InvalidProgramHeaderAddress);
+                }
+                // The vCPUs start with paging off, translate a virtual entry point.
+                let vaddr = le_u64(phdr, 16);
+                let in_segment =
+                    |addr: u64| (addr..addr + segment.mem_size).contains(&info.entry_addr);
+                if !in_segment(segment.addr) && in_segment(vaddr) {
+                    entry_addr = info.entry_addr - vaddr + segment.addr;
+                }
+                segments.push(segment);
+            }
+            Ok((entry_addr, segments))
+        }
+        BootProtocol::Arm64Image => {
+            let header = read_at(
+                kernel_image,
+                image_size,
+                0,
+                ARM64_IMAGE_SIZE_OFFSET as u64 + 8,
+                "Failed to read the Image header",
+            )?;
+            // The image size is only set by kernels newer than 3.17, older ones don't need any
+            // room past the end of the file.
+            let addr = image_base
+                .checked_add(info.entry_addr)
+                .ok_or(Error::InvalidEntryAddress)?;
+            let mem_size = le_u64(&header, ARM64_IMAGE_SIZE_OFFSET).max(image_size);
+            if addr.checked_add(mem_size).is_none() {
+                return Err(Error::InvalidEntryAddress);
+            }
+            let segment = Segment {
+                addr,
+                offset: 0,
+                file_size: image_size,
+                mem_size,
+            };
+            Ok((addr, vec![segment]))
+        }
+        BootProtocol::BzImage => Err(Error::UnsupportedBzImage),
+    }
+}
+
+/// Works out where `kernel_image` goes in guest memory, see `load_kernel`.
+///
+/// # Arguments
+///
+/// * `kernel_image` - Input kernel image: an ELF `vmlinux` or an arm64 `Image`.
+/// * `image_base` - Guest physical address an arm64 `Image` is loaded at, plus its text offset.
+pub fn kernel_layout<F: Read + Seek>(
+    kernel_image: &mut F,
+    image_base: u64,
+) -> Result<KernelLayout> {
+    let (entry_addr, segments) = segments(kernel_image, image_base)?;
+    let start_addr = segments.iter().map(|s| s.addr).min();
+    let end_addr = segments.iter().map(|s| s.addr + s.mem_size).max();
+    match (start_addr, end_addr) {
+        (Some(start_addr), Some(end_addr)) => Ok(KernelLayout {
+            start_addr,
+            end_addr,
+            entry_addr,
+        }),
+        _ => Err(Error::InvalidProgramHeaderAddress),
+    }
+}
+
+/// Copies `kernel_image` to `buf`, the zeroed contents of the guest memory from `buf_addr`, as
+/// laid out by `kernel_layout`. `buf` must cover the whole layout.
+///
+/// # Arguments
+///
+/// * `kernel_image` - Input kernel image: an ELF `vmlinux` or an arm64 `Image`.
+/// * `image_base` - Guest physical address an arm64 `Image` is loaded at, plus its text offset.
+/// * `buf_addr` - Guest physical address of the first byte of `buf`.
+/// * `buf` - Where the kernel image is copied to.
+pub fn load_kernel<F: Read + Seek>(
+    kernel_image: &mut F,
+    image_base: u64,
+    buf_addr: u64,
+    buf: &mut [u8],
+) -> Result<()> {
+    let (_, segments) = segments(kernel_image, image_base)?;
+    for segment in segments {
+        let start = segment
+            .addr
+            .checked_sub(buf_addr)
+            .ok_or(Error::InvalidLoadBuffer)? as usize;
+        let dst = start
+            .checked_add(segment.mem_size as usize)
+            .and_then(|end| buf.get_mut(start..end))
+            .ok_or(Error::InvalidLoadBuffer)?;
+        kernel_image
+            .seek(SeekFrom::Start(segment.offset))
+            .map_err(|_| Error::SeekKernelStart)?;
+        kernel_image
+            .read_exact(&mut dst[..segment.file_size as usize])
+            .map_err(|_| Error::ReadKernelImage)?;
+    }
+    Ok(())
+}
+
 /// Writes the command line string to the given memory slice.
 ///
 /// # Arguments
@@ -91,6 +553,7 @@ pub fn load_cmdline(
 mod tests {
     use super::super::cmdline::Cmdline;
     use super::*;
+    use std::io::Cursor;
     use vm_memory::{GuestAddress, GuestMemoryMmap};
 
     const MEM_SIZE: usize = 0x18_0000;
@@ -135,4 +598,215 @@ mod tests {
         let val: u8 = gm.read_obj(cmdline_address).unwrap();
         assert_eq!(val, b'\0');
     }
+
+    // Builds a minimal vmlinux-like ELF: one PT_LOAD segment, optionally a PVH note, and a
+    // `.text` section.
+    fn build_elf(entry_addr: u64, pvh_entry_addr: Option<u32>) -> Vec<u8> {
+        const PHDRS_START: usize = ELF_HEADER_SIZE;
+        const NOTES_START: usize = PHDRS_START + 2 * ELF_PROGRAM_HEADER_SIZE;
+        const NOTES_SIZE: usize = 20;
+        const NAMES: &[u8] = b"\0.text\0.shstrtab\0";
+        const NAMES_START: usize = NOTES_START + NOTES_SIZE;
+        const SHDRS_START: usize = 216;
+
+        let mut elf = vec![0u8; SHDRS_START + 3 * ELF_SECTION_HEADER_SIZE];
+        let put = |elf: &mut Vec<u8>, offset: usize, bytes: &[u8]| {
+            elf[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+
+        put(&mut elf, 0, ELF_MAGIC);
+        elf[EI_CLASS] = ELFCLASS64;
+        elf[EI_DATA] = ELFDATA2LSB;
+        put(&mut elf, 0x18, &entry_addr.to_le_bytes());
+        put(&mut elf, 0x20, &(PHDRS_START as u64).to_le_bytes());
+        put(&mut elf, 0x28, &(SHDRS_START as u64).to_le_bytes());
+        put(
+            &mut elf,
+            0x36,
+            &(ELF_PROGRAM_HEADER_SIZE as u16).to_le_bytes(),
+        );
+        put(&mut elf, 0x38, &2u16.to_le_bytes());
+        put(
+            &mut elf,
+            0x3a,
+            &(ELF_SECTION_HEADER_SIZE as u16).to_le_bytes(),
+        );
+        put(&mut elf, 0x3c, &3u16.to_le_bytes());
+        put(&mut elf, 0x3e, &2u16.to_le_bytes());
+
+        // PT_LOAD mapping 0x100_0000 at the usual kernel virtual address.
+        put(&mut elf, PHDRS_START, &PT_LOAD.to_le_bytes());
+        put(
+            &mut elf,
+            PHDRS_START + 16,
+            &0xffff_ffff_8100_0000u64.to_le_bytes(),
+        );
+        put(&mut elf, PHDRS_START + 24, &0x100_0000u64.to_le_bytes());
+        put(&mut elf, PHDRS_START + 40, &0x10_0000u64.to_le_bytes());
+
+        if let Some(pvh_entry_addr) = pvh_entry_addr {
+            let phdr = PHDRS_START + ELF_PROGRAM_HEADER_SIZE;
+            put(&mut elf, phdr, &PT_NOTE.to_le_bytes());
+            put(&mut elf, phdr + 8, &(NOTES_START as u64).to_le_bytes());
+            put(&mut elf, phdr + 32, &(NOTES_SIZE as u64).to_le_bytes());
+            put(&mut elf, NOTES_START, &4u32.to_le_bytes());
+            put(&mut elf, NOTES_START + 4, &4u32.to_le_bytes());
+            put(
+                &mut elf,
+                NOTES_START + 8,
+                &XEN_ELFNOTE_PHYS32_ENTRY.to_le_bytes(),
+            );
+            put(&mut elf, NOTES_START + 12, XEN_ELFNOTE_NAME);
+            put(&mut elf, NOTES_START + 16, &pvh_entry_addr.to_le_bytes());
+        }
+
+        put(&mut elf, NAMES_START, NAMES);
+        let text = SHDRS_START + ELF_SECTION_HEADER_SIZE;
+        put(&mut elf, text, &1u32.to_le_bytes());
+        put(&mut elf, text + 4, &1u32.to_le_bytes());
+        put(&mut elf, text + 16, &0xffff_ffff_8100_0000u64.to_le_bytes());
+        put(&mut elf, text + 32, &0x8000u64.to_le_bytes());
+        let shstrtab = text + ELF_SECTION_HEADER_SIZE;
+        put(&mut elf, shstrtab, &7u32.to_le_bytes());
+        put(&mut elf, shstrtab + 4, &3u32.to_le_bytes());
+        put(&mut elf, shstrtab + 24, &(NAMES_START as u64).to_le_bytes());
+        put(&mut elf, shstrtab + 32, &(NAMES.len() as u64).to_le_bytes());
+        elf
+    }
+
+    #[test]
+    fn test_inspect_elf() {
+        let info =
+            inspect_kernel(&mut Cursor::new(build_elf(0x100_0000, Some(0x100_0040)))).unwrap();
+        assert_eq!(info.entry_addr, 0x100_0000);
+        assert_eq!(info.boot_protocol, BootProtocol::Pvh);
+        assert_eq!(info.pvh_entry_addr, Some(0x100_0040));
+        assert_eq!(
+            info.sections,
+            vec![
+                KernelSection {
+                    name: ".text".to_string(),
+                    addr: 0xffff_ffff_8100_0000,
+                    size: 0x8000,
+                },
+                KernelSection {
+                    name: ".shstrtab".to_string(),
+                    addr: 0,
+                    size: 17,
+                },
+            ]
+        );
+
+        let info = inspect_kernel(&mut Cursor::new(build_elf(0x100_0000, None))).unwrap();
+        assert_eq!(info.boot_protocol, BootProtocol::Elf);
+        assert_eq!(info.pvh_entry_addr, None);
+
+        assert_eq!(
+            inspect_kernel(&mut Cursor::new(build_elf(0x200_0000, None))),
+            Err(Error::InvalidEntryAddress)
+        );
+
+        let mut elf = build_elf(0x100_0000, None);
+        elf[EI_DATA] = 2;
+        assert_eq!(
+            inspect_kernel(&mut Cursor::new(elf)),
+            Err(Error::BigEndianElfOnLittle)
+        );
+
+        let mut elf = build_elf(0x100_0000, None);
+        elf.truncate(ELF_HEADER_SIZE + 8);
+        assert_eq!(
+            inspect_kernel(&mut Cursor::new(elf)),
+            Err(Error::ReadKernelDataStruct(
+                "Failed to read ELF program headers"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_inspect_other_formats() {
+        let mut bzimage = vec![0u8; PROBE_SIZE];
+        bzimage[BZIMAGE_MAGIC_OFFSET..BZIMAGE_MAGIC_OFFSET + 4].copy_from_slice(BZIMAGE_MAGIC);
+        bzimage[BZIMAGE_CODE32_START_OFFSET..BZIMAGE_CODE32_START_OFFSET + 4]
+            .copy_from_slice(&0x10_0000u32.to_le_bytes());
+        let info = inspect_kernel(&mut Cursor::new(bzimage)).unwrap();
+        assert_eq!(info.boot_protocol, BootProtocol::BzImage);
+        assert_eq!(info.entry_addr, 0x10_0000);
+
+        let mut image = vec![0u8; 0x40];
+        image[ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4]
+            .copy_from_slice(ARM64_IMAGE_MAGIC);
+        let info = inspect_kernel(&mut Cursor::new(image)).unwrap();
+        assert_eq!(info.boot_protocol, BootProtocol::Arm64Image);
+        assert_eq!(info.entry_addr, 0);
+
+        assert_eq!(
+            inspect_kernel(&mut Cursor::new(vec![0u8; 16])),
+            Err(Error::UnknownKernelFormat)
+        );
+    }
+
+    #[test]
+    fn test_load_elf() {
+        // Make the segment start with the section names, so there's something to copy.
+        let mut elf = build_elf(0xffff_ffff_8100_0010, None);
+        let names_start = ELF_HEADER_SIZE + 2 * ELF_PROGRAM_HEADER_SIZE + 20;
+        elf[ELF_HEADER_SIZE + 8..ELF_HEADER_SIZE + 16]
+            .copy_from_slice(&(names_start as u64).to_le_bytes());
+        elf[ELF_HEADER_SIZE + 32..ELF_HEADER_SIZE + 40].copy_from_slice(&17u64.to_le_bytes());
+
+        let layout = kernel_layout(&mut Cursor::new(&elf), 0).unwrap();
+        assert_eq!(
+            layout,
+            KernelLayout {
+                start_addr: 0x100_0000,
+                end_addr: 0x110_0000,
+                entry_addr: 0x100_0010,
+            }
+        );
+
+        let mut buf = vec![0u8; 0x10_1000];
+        load_kernel(&mut Cursor::new(&elf), 0, 0xff_f000, &mut buf).unwrap();
+        assert_eq!(&buf[0x1000..0x1011], b"\0.text\0.shstrtab\0");
+        assert_eq!(
+            load_kernel(&mut Cursor::new(&elf), 0, 0x100_1000, &mut buf),
+            Err(Error::InvalidLoadBuffer)
+        );
+        assert_eq!(
+            load_kernel(&mut Cursor::new(&elf), 0, 0x100_0000, &mut buf[..0x1000]),
+            Err(Error::InvalidLoadBuffer)
+        );
+    }
+
+    #[test]
+    fn test_load_other_formats() {
+        let mut image = vec![0xaau8; 0x40];
+        image[ARM64_IMAGE_TEXT_OFFSET_OFFSET..ARM64_IMAGE_TEXT_OFFSET_OFFSET + 8]
+            .copy_from_slice(&0x8_0000u64.to_le_bytes());
+        image[ARM64_IMAGE_SIZE_OFFSET..ARM64_IMAGE_SIZE_OFFSET + 8]
+            .copy_from_slice(&0x1000u64.to_le_bytes());
+        image[ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4]
+            .copy_from_slice(ARM64_IMAGE_MAGIC);
+
+        let layout = kernel_layout(&mut Cursor::new(&image), 0x8000_0000).unwrap();
+        assert_eq!(
+            layout,
+            KernelLayout {
+                start_addr: 0x8008_0000,
+                end_addr: 0x8008_1000,
+                entry_addr: 0x8008_0000,
+            }
+        );
+        let mut buf = vec![0u8; 0x1000];
+        load_kernel(&mut Cursor::new(&image), 0x8000_0000, 0x8008_0000, &mut buf).unwrap();
+        assert_eq!(&buf[..0x40], &image[..]);
+        assert!(buf[0x40..].iter().all(|&b| b == 0));
+
+        let mut bzimage = vec![0u8; PROBE_SIZE];
+        bzimage[BZIMAGE_MAGIC_OFFSET..BZIMAGE_MAGIC_OFFSET + 4].copy_from_slice(BZIMAGE_MAGIC);
+        assert_eq!(
+            kernel_layout(&mut Cursor::new(bzimage), 0),
+            Err(Error::UnsupportedBzImage)
+        );
+    }
 }
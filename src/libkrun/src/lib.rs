@@ -22,7 +22,11 @@ use crossbeam_channel::unbounded;
 use devices::virtio::net::device::VirtioNetBackend;
 #[cfg(feature = "blk")]
 use devices::virtio::CacheType;
-use env_logger::Env;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::{
+    CachePolicy, ChainValidation, FsOpPolicy, SpecialFilePolicy, FS_NUM_REQUEST_QUEUES,
+    FS_QUEUE_SIZE,
+};
 #[cfg(target_os = "macos")]
 use hvf::MemoryMapping;
 #[cfg(not(feature = "efi"))]
@@ -43,7 +47,7 @@ use vmm::vmm_config::kernel_bundle::KernelBundle;
 use vmm::vmm_config::kernel_bundle::{InitrdBundle, QbootBundle};
 use vmm::vmm_config::machine_config::VmConfig;
 #[cfg(feature = "net")]
-use vmm::vmm_config::net::NetworkInterfaceConfig;
+use vmm::vmm_config::net::{generate_mac, NetworkInterfaceConfig};
 use vmm::vmm_config::vsock::VsockDeviceConfig;
 
 // Minimum krunfw version we require.
@@ -85,6 +89,7 @@ struct ContextConfig {
     rlimits: Option<String>,
     net_cfg: NetworkConfig,
     mac: Option<[u8; 6]>,
+    mac_seed: Option<u64>,
     #[cfg(not(feature = "tee"))]
     fs_devs: Vec<FsDeviceConfig>,
     #[cfg(feature = "blk")]
@@ -98,6 +103,8 @@ struct ContextConfig {
     gpu_virgl_flags: Option<u32>,
     enable_snd: bool,
     console_output: Option<PathBuf>,
+    #[cfg(all(feature = "gdb", target_os = "linux"))]
+    gdb_socket: Option<vmm::gdb::GdbSocket>,
 }
 
 impl ContextConfig {
@@ -189,6 +196,10 @@ impl ContextConfig {
         self.mac = Some(mac);
     }
 
+    fn set_net_mac_seed(&mut self, seed: u64) {
+        self.mac_seed = Some(seed);
+    }
+
     fn set_port_map(&mut self, new_port_map: HashMap<u16, u16>) -> Result<(), ()> {
         match &mut self.net_cfg {
             NetworkConfig::Tsi(tsi_config) => {
@@ -262,8 +273,10 @@ pub extern "C" fn krun_set_log_level(level: u32) -> i32 {
         4 => "debug",
         _ => "trace",
     };
-    env_logger::Builder::from_env(Env::default().default_filter_or(log_level)).init();
-    KRUN_SUCCESS
+    match utils::logger::init(log_level) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(_) => -libc::EEXIST,
+    }
 }
 
 #[no_mangle]
@@ -366,6 +379,13 @@ pub extern "C" fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -
         mem_size_mib: Some(mem_size_mib),
         ht_enabled: Some(false),
         cpu_template: None,
+        cpuid_overrides: None,
+        mem_init: None,
+        nested: None,
+        tsc_khz: None,
+        cpu_topology: None,
+        pmu: None,
+        sve: None,
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
@@ -398,7 +418,28 @@ pub unsafe extern "C" fn krun_set_root(ctx_id: u32, c_root_path: *const c_char)
             if !cfg.fs_devs.is_empty() {
                 return -libc::EINVAL;
             }
-            cfg.add_fs_dev(FsDeviceConfig { fs_id, shared_dir });
+            cfg.add_fs_dev(FsDeviceConfig {
+                fs_id,
+                shared_dir,
+                announce_submounts: false,
+                security_xattr_prefix: None,
+                xattr_map: Vec::new(),
+                allowed_ioctls: Vec::new(),
+                device_policy: SpecialFilePolicy::Skip,
+                fifo_socket_policy: SpecialFilePolicy::Passthrough,
+                direct_io: false,
+                cache_policy: CachePolicy::Auto,
+                shm_size: None,
+                op_policy: FsOpPolicy::default(),
+                chain_validation: ChainValidation::default(),
+                latency_stats: false,
+                num_request_queues: FS_NUM_REQUEST_QUEUES,
+                queue_size: FS_QUEUE_SIZE,
+                max_in_flight_requests: None,
+                #[cfg(target_os = "linux")]
+                max_inodes: None,
+                max_handles: None,
+            });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -413,6 +454,18 @@ pub unsafe extern "C" fn krun_add_virtiofs(
     ctx_id: u32,
     c_tag: *const c_char,
     c_path: *const c_char,
+) -> i32 {
+    krun_add_virtiofs2(ctx_id, c_tag, c_path, 0)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_add_virtiofs2(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_path: *const c_char,
+    shm_size: u64,
 ) -> i32 {
     let tag = match CStr::from_ptr(c_tag).to_str() {
         Ok(tag) => tag,
@@ -422,13 +475,38 @@ pub unsafe extern "C" fn krun_add_virtiofs(
         Ok(path) => path,
         Err(_) => return -libc::EINVAL,
     };
+    if !(shm_size as usize).is_multiple_of(vmm::builder::FS_DAX_WINDOW_ALIGN) {
+        return -libc::EINVAL;
+    }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
+            // The guest mounts the devices by tag, they must be unique.
+            if cfg.fs_devs.iter().any(|fs| fs.fs_id == tag) {
+                return -libc::EEXIST;
+            }
             cfg.add_fs_dev(FsDeviceConfig {
                 fs_id: tag.to_string(),
                 shared_dir: path.to_string(),
+                announce_submounts: false,
+                security_xattr_prefix: None,
+                xattr_map: Vec::new(),
+                allowed_ioctls: Vec::new(),
+                device_policy: SpecialFilePolicy::Skip,
+                fifo_socket_policy: SpecialFilePolicy::Passthrough,
+                direct_io: false,
+                cache_policy: CachePolicy::Auto,
+                shm_size: (shm_size != 0).then_some(shm_size as usize),
+                op_policy: FsOpPolicy::default(),
+                chain_validation: ChainValidation::default(),
+                latency_stats: false,
+                num_request_queues: FS_NUM_REQUEST_QUEUES,
+                queue_size: FS_QUEUE_SIZE,
+                max_in_flight_requests: None,
+                #[cfg(target_os = "linux")]
+                max_inodes: None,
+                max_handles: None,
             });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -573,6 +651,46 @@ pub unsafe extern "C" fn krun_set_net_mac(ctx_id: u32, c_mac: *const u8) -> i32
     KRUN_SUCCESS
 }
 
+#[no_mangle]
+pub extern "C" fn krun_set_net_mac_seed(ctx_id: u32, seed: u64) -> i32 {
+    if cfg!(not(feature = "net")) {
+        return -libc::ENOTSUP;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_net_mac_seed(seed);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_device_id(
+    ctx_id: u32,
+    c_default_id: *const c_char,
+    c_id: *const c_char,
+) -> i32 {
+    if c_default_id.is_null() || c_id.is_null() {
+        return -libc::EINVAL;
+    }
+    let (Ok(default_id), Ok(id)) = (
+        CStr::from_ptr(c_default_id).to_str(),
+        CStr::from_ptr(c_id).to_str(),
+    ) else {
+        return -libc::EINVAL;
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().vmr.set_device_id(default_id, id),
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *const c_char) -> i32 {
@@ -836,6 +954,33 @@ pub unsafe extern "C" fn krun_set_gpu_options(ctx_id: u32, virgl_flags: u32) ->
     KRUN_SUCCESS
 }
 
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_submounts(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    enable: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.fs_devs.iter_mut().find(|fs| fs.fs_id == tag) {
+                Some(fs) => fs.announce_submounts = enable,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_snd_device(ctx_id: u32, enable: bool) -> i32 {
@@ -911,6 +1056,10 @@ pub unsafe extern "C" fn krun_set_smbios_oem_strings(
         };
         oem_strings.push(s.to_string());
     }
+    if let Err(e) = smbios::check_oem_strings(&oem_strings) {
+        error!("Invalid SMBIOS OEM strings: {e}");
+        return -libc::EINVAL;
+    }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
@@ -923,19 +1072,42 @@ pub unsafe extern "C" fn krun_set_smbios_oem_strings(
     KRUN_SUCCESS
 }
 
+#[no_mangle]
+#[cfg(all(feature = "gdb", target_os = "linux"))]
+pub extern "C" fn krun_set_gdb_port(ctx_id: u32, port: u16) -> i32 {
+    if port == 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, port));
+            ctx_cfg.get_mut().gdb_socket = Some(vmm::gdb::GdbSocket::Tcp(addr));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
 #[cfg(feature = "net")]
 fn create_virtio_net(ctx_cfg: &mut ContextConfig, backend: VirtioNetBackend) {
+    let iface_id = "eth0";
     let mac = if let Some(mac) = ctx_cfg.mac {
         mac
+    } else if let Some(seed) = ctx_cfg.mac_seed {
+        generate_mac(seed, iface_id)
     } else {
         // By default, use podman-machine's well-known MAC address
         [0x5a, 0x94, 0xef, 0xe4, 0x0c, 0xee]
     };
 
     let network_interface_config = NetworkInterfaceConfig {
-        iface_id: "eth0".to_string(),
+        iface_id: iface_id.to_string(),
         backend,
         mac,
+        rx_rate_limit: Default::default(),
+        tx_rate_limit: Default::default(),
     };
     ctx_cfg
         .vmr
@@ -1019,6 +1191,8 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
             ctx_cfg.get_env(),
         )),
         kernel_cmdline_epilog: Some(format!(" -- {}", ctx_cfg.get_args())),
+        kernel_cmdline_args: Default::default(),
+        reserved_regions: Vec::new(),
     };
 
     if ctx_cfg.vmr.set_boot_source(boot_source).is_err() {
@@ -1060,7 +1234,10 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
     }
 
     if vsock_set {
-        ctx_cfg.vmr.set_vsock_device(vsock_config).unwrap();
+        if let Err(e) = ctx_cfg.vmr.set_vsock_device(vsock_config) {
+            error!("Error configuring vsock: {e}");
+            return -libc::EINVAL;
+        }
     }
 
     if let Some(virgl_flags) = ctx_cfg.gpu_virgl_flags {
@@ -1074,6 +1251,13 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         ctx_cfg.vmr.set_console_output(console_output);
     }
 
+    if let Err(errors) = vmm::builder::validate_microvm(&ctx_cfg.vmr) {
+        for e in errors {
+            error!("Invalid microVM configuration: {e}");
+        }
+        return -libc::EINVAL;
+    }
+
     #[cfg(target_os = "macos")]
     let (sender, receiver) = unbounded();
 
@@ -1091,6 +1275,14 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         }
     };
 
+    #[cfg(all(feature = "gdb", target_os = "linux"))]
+    if let Some(socket) = &ctx_cfg.gdb_socket {
+        if let Err(e) = vmm::gdb::start_server(_vmm.clone(), socket) {
+            error!("Starting the gdb server failed: {e}");
+            return -libc::EINVAL;
+        }
+    }
+
     #[cfg(target_os = "macos")]
     let mapper_vmm = _vmm.clone();
 
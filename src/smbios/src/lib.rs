@@ -5,7 +5,13 @@ use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
 mod table;
 
-#[derive(Debug)]
+/// Longest OEM string, in bytes, as recommended by the SMBIOS specification.
+pub const OEM_STRING_MAX_LEN: usize = 64;
+
+const BIOS_STRINGS: [&str; 3] = ["libkrun", "0", "01/05/2024"];
+const SYSTEM_STRINGS: [&str; 2] = ["Libkrun", "libkrun Virtual Machine"];
+
+#[derive(Debug, Eq, PartialEq)]
 pub enum Error {
     /// The size of the SMBIOS table is too big.
     SmBiosOverflow,
@@ -17,6 +23,12 @@ pub enum Error {
     WriteData,
     /// There was too many OEM Strings
     OEMStringsOverflow,
+    /// The OEM string with this index is longer than `OEM_STRING_MAX_LEN`.
+    OemStringTooLong(usize),
+    /// The OEM string with this index is empty or has a NUL byte.
+    InvalidOemString(usize),
+    /// The tables take this many bytes, more than the space reserved for them.
+    TableTooLarge(u64, u64),
 }
 
 impl std::error::Error for Error {}
@@ -24,7 +36,8 @@ impl std::error::Error for Error {}
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Error::{
-            NotEnoughMemory, OEMStringsOverflow, SmBiosOverflow, WriteData, WriteSmbiosEp,
+            InvalidOemString, NotEnoughMemory, OEMStringsOverflow, OemStringTooLong,
+            SmBiosOverflow, TableTooLarge, WriteData, WriteSmbiosEp,
         };
 
         let description = match self {
@@ -33,6 +46,13 @@ impl Display for Error {
             WriteSmbiosEp => "Failure to write SMBIOS entrypoint structure".to_string(),
             WriteData => "Failure to write additional data to memory".to_string(),
             OEMStringsOverflow => "There was too many OEM Strings".to_string(),
+            OemStringTooLong(index) => {
+                format!("OEM string {index} is longer than {OEM_STRING_MAX_LEN} bytes")
+            }
+            InvalidOemString(index) => format!("OEM string {index} is empty or has a NUL byte"),
+            TableTooLarge(size, max_size) => {
+                format!("The SMBIOS tables take {size} bytes, more than the {max_size} available")
+            }
         };
 
         write!(f, "SMBIOS error: {description}")
@@ -41,11 +61,39 @@ impl Display for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Checks that the guest can be given these OEM strings.
+pub fn check_oem_strings(oem_strings: &[String]) -> Result<()> {
+    if oem_strings.len() > u8::MAX as usize {
+        return Err(Error::OEMStringsOverflow);
+    }
+    for (index, s) in oem_strings.iter().enumerate() {
+        if s.len() > OEM_STRING_MAX_LEN {
+            return Err(Error::OemStringTooLong(index));
+        }
+        // Either would end the string-set early.
+        if s.is_empty() || s.contains('\0') {
+            return Err(Error::InvalidOemString(index));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the SMBIOS entry point at `start_addr`, followed by the tables, making sure they fit
+/// in `max_size` bytes. Returns how many bytes were written.
 pub fn setup_smbios(
     mem: &GuestMemoryMmap,
     start_addr: u64,
+    max_size: u64,
     oem_strings: &Option<Vec<String>>,
 ) -> Result<u64> {
+    if let Some(oem_strings) = oem_strings {
+        check_oem_strings(oem_strings)?;
+    }
+    let size = smbios_size(oem_strings);
+    if size > max_size {
+        return Err(Error::TableTooLarge(size, max_size));
+    }
+
     let start_addr = GuestAddress(start_addr);
     let table_starting_addr = start_addr
         .checked_add(mem::size_of::<Entrypoint30>() as u64)
@@ -68,6 +116,29 @@ pub fn setup_smbios(
     write_entry_point(mem, start_addr, next_write_addr)
 }
 
+// Size of a structure whose formatted section is `T`, followed by `strings`.
+fn structure_size<T>(strings: &[&str]) -> u64 {
+    let strings_size = if strings.is_empty() {
+        2
+    } else {
+        strings.iter().map(|s| s.len() as u64 + 1).sum::<u64>() + 1
+    };
+    mem::size_of::<T>() as u64 + strings_size
+}
+
+// Number of bytes `setup_smbios` writes.
+fn smbios_size(oem_strings: &Option<Vec<String>>) -> u64 {
+    let oem_strings_size = oem_strings.as_ref().map_or(0, |oem_strings| {
+        let strings: Vec<&str> = oem_strings.iter().map(String::as_str).collect();
+        structure_size::<OemStrings>(&strings)
+    });
+    mem::size_of::<Entrypoint30>() as u64
+        + structure_size::<BiosInfo>(&BIOS_STRINGS)
+        + structure_size::<SystemInfo>(&SYSTEM_STRINGS)
+        + oem_strings_size
+        + structure_size::<EndOfTable>(&[])
+}
+
 fn write_entry_point(
     mem: &GuestMemoryMmap,
     start_addr: GuestAddress,
@@ -96,9 +167,10 @@ fn write_type_0_table(mem: &GuestMemoryMmap, mut current: GuestAddress) -> Resul
     let biosinfo = BiosInfo::new(1, 2, 3);
 
     current = write_obj(mem, biosinfo, current)?;
-    current = write_string(mem, "libkrun", current)?; // vendor string
-    current = write_string(mem, "0", current)?; // version string
-    current = write_string(mem, "01/05/2024", current)?; // release date string
+    // vendor, version and release date strings
+    for s in BIOS_STRINGS {
+        current = write_string(mem, s, current)?;
+    }
 
     // the set of strings is terminated with an additional null (00h) byte
     current = write_obj(mem, 0u8, current)?;
@@ -111,8 +183,10 @@ fn write_type_1_table(mem: &GuestMemoryMmap, mut current: GuestAddress) -> Resul
     let sysinfo = SystemInfo::new(1, 2);
 
     current = write_obj(mem, sysinfo, current)?;
-    current = write_string(mem, "Libkrun", current)?;
-    current = write_string(mem, "libkrun Virtual Machine", current)?;
+    // manufacturer and product name strings
+    for s in SYSTEM_STRINGS {
+        current = write_string(mem, s, current)?;
+    }
 
     // the set of strings is terminated with an additional null (00h) byte
     current = write_obj(mem, 0u8, current)?;
@@ -185,3 +259,97 @@ fn write_obj<T: ByteValued>(
         .ok_or(Error::NotEnoughMemory)?;
     Ok(next)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Splits the structure table the way the kernel's DMI scan does, returning the raw bytes of
+    // each structure, as found in `/sys/firmware/dmi/entries/<type>-<instance>/raw`.
+    fn dmi_walk(mem: &GuestMemoryMmap, start_addr: u64) -> Vec<Vec<u8>> {
+        let mut ep = [0u8; mem::size_of::<Entrypoint30>()];
+        mem.read_slice(&mut ep, GuestAddress(start_addr)).unwrap();
+        assert_eq!(&ep[..5], b"_SM3_");
+        assert_eq!(ep.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+        let table_max_size = u32::from_le_bytes(ep[12..16].try_into().unwrap()) as usize;
+        let table_addr = u64::from_le_bytes(ep[16..24].try_into().unwrap());
+
+        let mut table = vec![0u8; table_max_size];
+        mem.read_slice(&mut table, GuestAddress(table_addr))
+            .unwrap();
+
+        let mut structures = Vec::new();
+        let mut rest = &table[..];
+        while !rest.is_empty() {
+            let formatted_len = rest[1] as usize;
+            let strings_len = rest[formatted_len..]
+                .windows(2)
+                .position(|w| w == [0, 0])
+                .unwrap();
+            let (structure, tail) = rest.split_at(formatted_len + strings_len + 2);
+            structures.push(structure.to_vec());
+            rest = tail;
+        }
+        structures
+    }
+
+    #[test]
+    fn test_oem_strings() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let oem_strings = Some(vec![
+            "io.systemd.credential:a=b".to_string(),
+            "x".to_string(),
+        ]);
+        let size = setup_smbios(&mem, 0x1000, 0x1000, &oem_strings).unwrap();
+        assert_eq!(size, smbios_size(&oem_strings));
+
+        let structures = dmi_walk(&mem, 0x1000);
+        let types: Vec<u8> = structures.iter().map(|s| s[0]).collect();
+        assert_eq!(types, [0, 1, 11, 127]);
+        assert_eq!(
+            structures[2],
+            b"\x0b\x05\x00\x0e\x02io.systemd.credential:a=b\0x\0\0"
+        );
+        assert_eq!(structures[3], b"\x7f\x04\x00\x7f\0\0");
+
+        // Without OEM strings, there is no type 11 structure.
+        setup_smbios(&mem, 0x2000, 0x1000, &None).unwrap();
+        let types: Vec<u8> = dmi_walk(&mem, 0x2000).iter().map(|s| s[0]).collect();
+        assert_eq!(types, [0, 1, 127]);
+    }
+
+    #[test]
+    fn test_oem_strings_limits() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        let longest = "a".repeat(OEM_STRING_MAX_LEN);
+        let oem_strings = Some(vec![longest.clone()]);
+        assert!(setup_smbios(&mem, 0, 0x1000, &oem_strings).is_ok());
+        let oem_strings = Some(vec!["b".to_string(), longest + "a"]);
+        assert_eq!(
+            setup_smbios(&mem, 0, 0x1000, &oem_strings),
+            Err(Error::OemStringTooLong(1))
+        );
+        assert_eq!(
+            check_oem_strings(&["".to_string()]),
+            Err(Error::InvalidOemString(0))
+        );
+        assert_eq!(
+            check_oem_strings(&["a\0b".to_string()]),
+            Err(Error::InvalidOemString(0))
+        );
+        assert_eq!(
+            check_oem_strings(&vec!["a".to_string(); 256]),
+            Err(Error::OEMStringsOverflow)
+        );
+
+        let oem_strings = Some(vec!["c".repeat(OEM_STRING_MAX_LEN); 100]);
+        let size = smbios_size(&oem_strings);
+        assert_eq!(
+            setup_smbios(&mem, 0x8000, 0x1000, &oem_strings),
+            Err(Error::TableTooLarge(size, 0x1000))
+        );
+        // Nothing was written.
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x8000)).unwrap(), 0);
+    }
+}
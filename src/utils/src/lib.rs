@@ -3,19 +3,24 @@
 
 pub use vmm_sys_util::{errno, tempdir, tempfile, terminal};
 #[cfg(target_os = "linux")]
-pub use vmm_sys_util::{eventfd, ioctl};
+pub use vmm_sys_util::{eventfd, ioctl, timerfd};
 
 pub mod byte_order;
 #[cfg(target_os = "linux")]
 pub mod linux;
+pub mod logger;
 #[cfg(target_os = "linux")]
 pub use linux::epoll;
+#[cfg(target_os = "linux")]
+pub use linux::seccomp;
 #[cfg(target_os = "macos")]
 pub mod macos;
 #[cfg(target_os = "macos")]
 pub use macos::epoll;
 #[cfg(target_os = "macos")]
 pub use macos::eventfd;
+#[cfg(target_os = "macos")]
+pub use macos::timerfd;
 pub mod rand;
 #[cfg(target_os = "linux")]
 pub mod signal;
@@ -0,0 +1,349 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Seccomp filters restricting the threads of the VMM to the system calls they need.
+//!
+//! A filter applies to the thread installing it and to the threads it spawns afterwards, so each
+//! kind of thread can have its own allowlist, as long as it isn't spawned by a filtered thread.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e; // AUDIT_ARCH_X86_64
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7; // AUDIT_ARCH_AARCH64
+
+// Offsets of the fields of `struct seccomp_data` the filters look at. The request number of an
+// ioctl is the low half of its second argument, at the start of it on little-endian hosts.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_IOCTL_REQUEST: u32 = 24;
+
+// Bits of an ioctl request number holding its type (asm-generic/ioctl.h).
+const IOC_TYPESHIFT: u32 = 8;
+const IOC_TYPEMASK: u32 = 0xff;
+
+/// What happens when a filtered thread makes a system call outside of its allowlist.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SeccompMode {
+    /// No filter is installed.
+    #[default]
+    Off,
+    /// The system call goes through but the kernel logs it to the audit log, e.g. to find out
+    /// what an allowlist misses.
+    Log,
+    /// The thread gets a `SIGSYS` instead, whose handler is expected to terminate the process.
+    Kill,
+}
+
+impl FromStr for SeccompMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(SeccompMode::Off),
+            "log" => Ok(SeccompMode::Log),
+            "kill" => Ok(SeccompMode::Kill),
+            _ => Err("invalid seccomp mode"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The filter has more instructions than the kernel accepts.
+    TooLarge(usize),
+    /// Cannot keep the thread from gaining privileges, which installing a filter requires.
+    NoNewPrivs(io::Error),
+    /// The kernel refused the filter.
+    Install(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            TooLarge(len) => write!(f, "The seccomp filter has too many instructions: {len}"),
+            NoNewPrivs(e) => write!(f, "Cannot set PR_SET_NO_NEW_PRIVS: {e}"),
+            Install(e) => write!(f, "Cannot install the seccomp filter: {e}"),
+        }
+    }
+}
+
+/// Builds the filter of a kind of thread out of the system calls it may make.
+#[derive(Clone, Debug, Default)]
+pub struct SeccompFilterBuilder {
+    syscalls: BTreeSet<libc::c_long>,
+    ioctls: BTreeSet<u32>,
+    ioctl_types: BTreeSet<u8>,
+}
+
+impl SeccompFilterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `syscalls`. Allowing `SYS_ioctl` allows all the ioctls.
+    pub fn allow(mut self, syscalls: &[libc::c_long]) -> Self {
+        self.syscalls.extend(syscalls);
+        self
+    }
+
+    /// Allows the ioctls with these request numbers.
+    pub fn allow_ioctls(mut self, requests: &[u32]) -> Self {
+        self.ioctls.extend(requests);
+        self
+    }
+
+    /// Allows all the ioctls of a type, e.g. `KVMIO` for the KVM ones.
+    pub fn allow_ioctl_type(mut self, ioctl_type: u8) -> Self {
+        self.ioctl_types.insert(ioctl_type);
+        self
+    }
+
+    /// Returns the filter taking `mode`'s action on the system calls that aren't allowed, or
+    /// `None` with `SeccompMode::Off`.
+    pub fn build(&self, mode: SeccompMode) -> Result<Option<SeccompFilter>, Error> {
+        let denied = match mode {
+            SeccompMode::Off => return Ok(None),
+            SeccompMode::Log => libc::SECCOMP_RET_LOG,
+            SeccompMode::Kill => libc::SECCOMP_RET_TRAP,
+        };
+
+        let mut program = vec![
+            // System calls made through another ABI would have other numbers.
+            load(SECCOMP_DATA_ARCH),
+            jump_eq(AUDIT_ARCH, 1, 0),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+            load(SECCOMP_DATA_NR),
+        ];
+        for &syscall in &self.syscalls {
+            program.push(jump_eq(syscall as u32, 0, 1));
+            program.push(ret(libc::SECCOMP_RET_ALLOW));
+        }
+
+        // The ioctls come last, so the ones that aren't allowed fall through to the denial.
+        if !self.syscalls.contains(&libc::SYS_ioctl)
+            && (!self.ioctls.is_empty() || !self.ioctl_types.is_empty())
+        {
+            let mut ioctls = vec![load(SECCOMP_DATA_IOCTL_REQUEST)];
+            for &request in &self.ioctls {
+                ioctls.push(jump_eq(request, 0, 1));
+                ioctls.push(ret(libc::SECCOMP_RET_ALLOW));
+            }
+            if !self.ioctl_types.is_empty() {
+                ioctls.push(alu(libc::BPF_RSH, IOC_TYPESHIFT));
+                ioctls.push(alu(libc::BPF_AND, IOC_TYPEMASK));
+                for &ioctl_type in &self.ioctl_types {
+                    ioctls.push(jump_eq(ioctl_type.into(), 0, 1));
+                    ioctls.push(ret(libc::SECCOMP_RET_ALLOW));
+                }
+            }
+            let skip = u8::try_from(ioctls.len())
+                .map_err(|_| Error::TooLarge(program.len() + ioctls.len()))?;
+            program.push(jump_eq(libc::SYS_ioctl as u32, 0, skip));
+            program.extend(ioctls);
+        }
+        program.push(ret(denied));
+
+        if program.len() > libc::BPF_MAXINSNS as usize {
+            return Err(Error::TooLarge(program.len()));
+        }
+        Ok(Some(SeccompFilter {
+            program: program.into(),
+        }))
+    }
+}
+
+/// A compiled filter, shared by the threads it's installed in.
+#[derive(Clone)]
+pub struct SeccompFilter {
+    program: Arc<[libc::sock_filter]>,
+}
+
+impl SeccompFilter {
+    /// Installs the filter in the calling thread, for good.
+    pub fn apply(&self) -> Result<(), Error> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(Error::NoNewPrivs(io::Error::last_os_error()));
+        }
+        let prog = libc::sock_fprog {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr() as *mut libc::sock_filter,
+        };
+        // Safe because the kernel only reads `prog` and the program it points to, which outlive
+        // the call, and we check the return value.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                0,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Install(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of instructions of the filter.
+    pub fn len(&self) -> usize {
+        self.program.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.program.is_empty()
+    }
+}
+
+impl fmt::Debug for SeccompFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SeccompFilter")
+            .field("len", &self.program.len())
+            .finish()
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs closures on a thread spawned before any filter is installed, so the threads they spawn
+/// don't inherit the filter of the caller, e.g. the device threads the vCPUs start when the guest
+/// activates a device.
+#[derive(Clone)]
+pub struct UnfilteredRunner {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl UnfilteredRunner {
+    pub fn new() -> io::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("unfiltered runner".into())
+            .spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            })?;
+        Ok(UnfilteredRunner { jobs })
+    }
+
+    /// Runs `f` on the unfiltered thread and returns what it returns.
+    pub fn run<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static) -> T {
+        let (result_sender, result) = mpsc::sync_channel(1);
+        self.jobs
+            .send(Box::new(move || {
+                let _ = result_sender.send(f());
+            }))
+            .expect("The unfiltered runner thread is gone");
+        result
+            .recv()
+            .expect("The unfiltered runner thread panicked")
+    }
+}
+
+fn load(offset: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+
+fn alu(op: u32, operand: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_ALU | op | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: operand,
+    }
+}
+
+fn jump_eq(value: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k: value,
+    }
+}
+
+fn ret(action: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: action,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Applies `filter` in a child process that then makes `syscall` with `args`, and returns
+    // whether it survived.
+    fn run_filtered(
+        filter: &SeccompFilter,
+        syscall: libc::c_long,
+        args: [libc::c_long; 2],
+    ) -> bool {
+        // Safe because the child only makes system calls before exiting.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            if filter.apply().is_err() {
+                unsafe { libc::_exit(2) };
+            }
+            unsafe {
+                libc::syscall(syscall, args[0], args[1]);
+                libc::_exit(0);
+            }
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        if libc::WIFSIGNALED(status) {
+            assert_eq!(libc::WTERMSIG(status), libc::SIGSYS);
+            return false;
+        }
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        true
+    }
+
+    #[test]
+    fn test_seccomp_filter() {
+        let builder = SeccompFilterBuilder::new()
+            .allow(&[libc::SYS_exit_group, libc::SYS_getppid])
+            .allow_ioctls(&[0x5413])
+            .allow_ioctl_type(0xae);
+        assert!(builder.build(SeccompMode::Off).unwrap().is_none());
+        assert_eq!(builder.build(SeccompMode::Log).unwrap().unwrap().len(), 17);
+
+        // Without a `SIGSYS` handler a denied system call kills the process.
+        let filter = builder.build(SeccompMode::Kill).unwrap().unwrap();
+        assert!(run_filtered(&filter, libc::SYS_getppid, [0, 0]));
+        assert!(!run_filtered(&filter, libc::SYS_getpid, [0, 0]));
+        assert!(run_filtered(&filter, libc::SYS_ioctl, [-1, 0x5413]));
+        assert!(run_filtered(&filter, libc::SYS_ioctl, [-1, 0xae80]));
+        assert!(!run_filtered(&filter, libc::SYS_ioctl, [-1, 0x5414]));
+
+        let filter = builder.build(SeccompMode::Log).unwrap().unwrap();
+        assert!(run_filtered(&filter, libc::SYS_getpid, [0, 0]));
+    }
+
+    #[test]
+    fn test_unfiltered_runner() {
+        let runner = UnfilteredRunner::new().unwrap();
+        let caller = thread::current().id();
+        let (id, answer) = runner.run(|| (thread::current().id(), 42));
+        assert_ne!(id, caller);
+        assert_eq!(answer, 42);
+        assert_eq!(runner.clone().run(move || thread::current().id()), id);
+    }
+}
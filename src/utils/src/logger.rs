@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! `log` backend whose per-module filter can be changed while the VMM runs, to raise the
+//! verbosity of a live guest's devices without restarting it. Records are formatted and written
+//! by `env_logger`.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+
+use env_logger::filter::{self, Filter};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Errors changing the log filter.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Another logger was installed instead of this one.
+    NotInstalled,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Error::NotInstalled => write!(f, "The reloadable logger is not installed"),
+        }
+    }
+}
+
+struct Filters {
+    // Parsed from `RUST_LOG`, or the default filter given to `init`.
+    base: Filter,
+    // Levels set at runtime, which take precedence over `base`.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filters {
+    // Level set at runtime for the most specific module `target` belongs to, if any.
+    fn module_level(&self, target: &str) -> Option<LevelFilter> {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                module.is_empty()
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|&(_, level)| level)
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.module_level(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.base.enabled(metadata),
+        }
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.base.filter(), Ord::max)
+    }
+
+    fn set_module_level(&mut self, module: &str, level: LevelFilter) {
+        match self.modules.iter_mut().find(|(m, _)| m == module) {
+            Some(entry) => entry.1 = level,
+            None => self.modules.push((module.to_string(), level)),
+        }
+    }
+}
+
+fn base_filter(default_filter: &str) -> Filter {
+    let spec = env::var(env_logger::DEFAULT_FILTER_ENV).unwrap_or_else(|_| default_filter.into());
+    filter::Builder::new().parse(&spec).build()
+}
+
+struct ReloadableLogger {
+    // Lets every record through, filtering is done by `filters`.
+    writer: env_logger::Logger,
+    filters: RwLock<Filters>,
+}
+
+impl ReloadableLogger {
+    fn new(base: Filter) -> Self {
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(LevelFilter::Trace);
+        if let Ok(style) = env::var(env_logger::DEFAULT_WRITE_STYLE_ENV) {
+            builder.parse_write_style(&style);
+        }
+        ReloadableLogger {
+            writer: builder.build(),
+            filters: RwLock::new(Filters {
+                base,
+                modules: Vec::new(),
+            }),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Filters)) {
+        let mut filters = self.filters.write().unwrap();
+        f(&mut filters);
+        log::set_max_level(filters.max_level());
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filters.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.writer.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+/// Installs the reloadable logger as the `log` backend. Its filter is read from `RUST_LOG` when
+/// set, and from `default_filter` otherwise, both using the `env_logger` syntax. Calling this
+/// again replaces that filter, keeping the levels set with `set_module_level`.
+pub fn init(default_filter: &str) -> Result<(), SetLoggerError> {
+    let base = base_filter(default_filter);
+    if INSTALLED.load(Ordering::Acquire) {
+        LOGGER.get().unwrap().update(|filters| filters.base = base);
+        return Ok(());
+    }
+
+    let logger = LOGGER.get_or_init(|| ReloadableLogger::new(base));
+    log::set_logger(logger)?;
+    INSTALLED.store(true, Ordering::Release);
+    logger.update(|_| ());
+    Ok(())
+}
+
+/// Logs records of `module` and its submodules up to `level`, overriding the filter given to
+/// `init` and any level previously set for the same module. An empty `module` applies to all of
+/// them, though more specific modules keep their own level.
+pub fn set_module_level(module: &str, level: LevelFilter) -> Result<(), Error> {
+    if !INSTALLED.load(Ordering::Acquire) {
+        return Err(Error::NotInstalled);
+    }
+    LOGGER
+        .get()
+        .unwrap()
+        .update(|filters| filters.set_module_level(module, level));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn enabled(filters: &Filters, target: &str, level: Level) -> bool {
+        filters.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn test_module_levels() {
+        let mut filters = Filters {
+            base: filter::Builder::new().parse("warn,vmm=info").build(),
+            modules: Vec::new(),
+        };
+        assert!(enabled(&filters, "devices::virtio::fs", Level::Warn));
+        assert!(!enabled(&filters, "devices::virtio::fs", Level::Info));
+        assert!(enabled(&filters, "vmm::builder", Level::Info));
+        assert_eq!(filters.max_level(), LevelFilter::Info);
+
+        filters.set_module_level("devices::virtio::fs", LevelFilter::Trace);
+        filters.set_module_level("devices", LevelFilter::Error);
+        assert!(enabled(
+            &filters,
+            "devices::virtio::fs::server",
+            Level::Trace
+        ));
+        assert!(enabled(&filters, "devices::virtio::fs", Level::Trace));
+        assert!(!enabled(&filters, "devices::virtio::fsx", Level::Trace));
+        assert!(!enabled(&filters, "devices::virtio::net", Level::Warn));
+        assert!(enabled(&filters, "vmm::builder", Level::Info));
+        assert_eq!(filters.max_level(), LevelFilter::Trace);
+
+        filters.set_module_level("", LevelFilter::Off);
+        filters.set_module_level("devices::virtio::fs", LevelFilter::Debug);
+        assert!(!enabled(&filters, "vmm::builder", Level::Error));
+        assert!(enabled(&filters, "devices::virtio::fs", Level::Debug));
+        assert!(!enabled(&filters, "devices::virtio::fs", Level::Trace));
+        assert_eq!(filters.modules.len(), 3);
+    }
+
+    #[test]
+    fn test_not_installed() {
+        // No test installs the logger, as it's process wide.
+        assert_eq!(
+            set_module_level("vmm", LevelFilter::Debug),
+            Err(Error::NotInstalled)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Structure and wrapper functions emulating timerfd using a kqueue, which is readable while it
+//! holds a pending event.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use std::{mem, ptr};
+
+use crate::errno::{errno_result, Result};
+
+// The one timer in the queue.
+const TIMER_IDENT: usize = 0;
+
+#[derive(Debug)]
+pub struct TimerFd {
+    queue: RawFd,
+    // Period the timer is rearmed with once it first expires, if it repeats.
+    interval: Option<Duration>,
+}
+
+impl TimerFd {
+    /// Creates a disarmed timer, see `reset` to arm it.
+    pub fn new() -> Result<TimerFd> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let queue = unsafe { libc::kqueue() };
+        if queue < 0 {
+            return errno_result();
+        }
+        Ok(TimerFd {
+            queue,
+            interval: None,
+        })
+    }
+
+    // Arms the timer to expire after `dur`, then every `dur` if `periodic`.
+    fn arm(&self, dur: Duration, periodic: bool) -> Result<()> {
+        let flags = if periodic {
+            libc::EV_ADD
+        } else {
+            libc::EV_ADD | libc::EV_ONESHOT
+        };
+        // Safe because `kevent` is plain data, for which all zeroes is a valid value.
+        let mut kev: libc::kevent = unsafe { mem::zeroed() };
+        kev.ident = TIMER_IDENT;
+        kev.filter = libc::EVFILT_TIMER;
+        kev.flags = flags;
+        kev.fflags = libc::NOTE_NSECONDS;
+        kev.data = dur.as_nanos().min(isize::MAX as u128) as isize;
+        // Safe because this only reads `kev` and we check the return value.
+        let ret = unsafe { libc::kevent(self.queue, &kev, 1, ptr::null_mut(), 0, ptr::null()) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    /// Arms the timer to expire after `dur`, then every `interval` if given, replacing any
+    /// previous setting.
+    pub fn reset(&mut self, dur: Duration, interval: Option<Duration>) -> Result<()> {
+        self.interval = interval;
+        self.arm(dur, false)
+    }
+
+    /// Waits until the timer expires, returning the number of expirations since the last call.
+    pub fn wait(&mut self) -> Result<u64> {
+        // Safe because `kevent` is plain data, for which all zeroes is a valid value.
+        let mut kev: libc::kevent = unsafe { mem::zeroed() };
+        // Safe because this only writes `kev` and we check the return value.
+        let ret = unsafe { libc::kevent(self.queue, ptr::null(), 0, &mut kev, 1, ptr::null()) };
+        if ret < 0 {
+            return errno_result();
+        }
+        // The first expiration of a repeating timer was a one-shot one, the next ones aren't.
+        if let Some(interval) = self.interval.take() {
+            self.arm(interval, true)?;
+        }
+        Ok(kev.data as u64)
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.queue
+    }
+}
+
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        // Safe because we own the queue and nothing uses it after this.
+        unsafe { libc::close(self.queue) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_one_shot() {
+        let mut timer = TimerFd::new().unwrap();
+        let start = Instant::now();
+        timer.reset(Duration::from_millis(50), None).unwrap();
+        assert_eq!(timer.wait().unwrap(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -6,14 +6,17 @@
 #[cfg(target_os = "macos")]
 use crossbeam_channel::{unbounded, Sender};
 use std::fmt::{Display, Formatter};
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io;
 #[cfg(target_os = "linux")]
-use std::os::fd::AsRawFd;
-use std::path::PathBuf;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+use std::time::Instant;
 
-use super::{Error, Vmm};
+use super::{BootTimings, Error, Vmm, KERNEL_CMDLINE_LIMIT};
 
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
@@ -27,6 +30,7 @@ use devices::virtio::VirtioShmRegion;
 use devices::virtio::{port_io, MmioTransport, PortDescription, Vsock};
 #[cfg(target_os = "macos")]
 use hvf::MemoryMapping;
+use kernel::loader::KernelInfo;
 
 #[cfg(feature = "tee")]
 use kbs_types::Tee;
@@ -35,10 +39,16 @@ use crate::device_manager;
 #[cfg(feature = "tee")]
 use crate::resources::TeeConfig;
 #[cfg(target_os = "linux")]
+use crate::signal_handler::register_sigbus_handler;
+#[cfg(target_os = "linux")]
 use crate::signal_handler::register_sigint_handler;
 #[cfg(target_os = "linux")]
+use crate::signal_handler::register_sigsys_handler;
+#[cfg(target_os = "linux")]
 use crate::signal_handler::register_sigwinch_handler;
 use crate::terminal::term_set_raw_mode;
+#[cfg(not(feature = "tee"))]
+use crate::vmm_config::balloon::BalloonConfig;
 #[cfg(feature = "blk")]
 use crate::vmm_config::block::BlockBuilder;
 use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
@@ -47,12 +57,24 @@ use crate::vmm_config::fs::FsBuilder;
 #[cfg(feature = "tee")]
 use crate::vmm_config::kernel_bundle::{InitrdBundle, QbootBundle};
 #[cfg(target_os = "linux")]
+use crate::vmm_config::machine_config::HugePageSize;
+use crate::vmm_config::machine_config::{MemoryBackend, MemoryInit};
+#[cfg(target_os = "linux")]
+use crate::vmm_config::readonly_memory::{
+    ReadOnlyRegionConfig, ReadOnlyWriteHandler, ReadOnlyWritePolicy,
+};
+#[cfg(not(feature = "tee"))]
+use crate::vmm_config::rng::RngConfig;
+#[cfg(target_arch = "aarch64")]
+use crate::vmm_config::rtc::RtcConfig;
+#[cfg(target_os = "linux")]
 use crate::vstate::KvmContext;
 #[cfg(all(target_os = "linux", feature = "tee"))]
 use crate::vstate::MeasuredRegion;
+#[cfg(target_os = "linux")]
+use crate::vstate::VcpuScheduler;
 use crate::vstate::{Error as VstateError, Vcpu, VcpuConfig, Vm};
 use arch::ArchMemoryInfo;
-#[cfg(feature = "tee")]
 use arch::InitrdConfig;
 #[cfg(feature = "tee")]
 use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
@@ -60,15 +82,13 @@ use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
 use nix::unistd::isatty;
 use polly::event_manager::{Error as EventManagerError, EventManager};
 use utils::eventfd::EventFd;
+#[cfg(target_os = "linux")]
+use utils::seccomp::{SeccompMode, UnfilteredRunner};
 use utils::time::TimestampUs;
-#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
-use vm_memory::mmap::GuestRegionMmap;
-#[cfg(not(feature = "efi"))]
-use vm_memory::mmap::MmapRegion;
-#[cfg(any(target_arch = "aarch64", feature = "tee"))]
+use vm_memory::mmap::{GuestRegionMmap, MmapRegion};
 use vm_memory::Bytes;
-use vm_memory::GuestMemory;
-use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
+use vm_memory::{GuestMemory, GuestMemoryRegion};
 
 #[cfg(feature = "efi")]
 static EDK2_BINARY: &[u8] = include_bytes!("../../../edk2/KRUN_EFI.silent.fd");
@@ -80,8 +100,24 @@ pub enum StartMicrovmError {
     AttachBlockDevice(io::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// The DAX window of the virtio-fs device with this tag isn't a multiple of
+    /// `FS_DAX_WINDOW_ALIGN` or doesn't fit in the shared memory region.
+    FsDaxWindow(String),
     /// Memory regions are overlapping or mmap fails.
     GuestMemoryMmap(vm_memory::Error),
+    /// Cannot prefault or zero the guest memory.
+    GuestMemoryInit(io::Error),
+    /// Cannot open or extend the file backing the guest memory.
+    MemoryFile(io::Error),
+    /// Cannot map the file backing the guest memory.
+    MemoryFileMmap(vm_memory::mmap::MmapRegionError),
+    /// File-backed guest memory can't be initialized this way.
+    MemoryFileInit(MemoryInit),
+    /// The offset of the guest memory in its file isn't a multiple of the host page size.
+    MemoryFileOffset(u64),
+    /// Cannot allocate the guest memory from huge pages of this size.
+    #[cfg(target_os = "linux")]
+    HugePages(HugePageSize, vm_memory::mmap::MmapRegionError),
     /// Cannot load initrd due to an invalid memory configuration.
     InitrdLoad,
     /// Cannot load initrd due to an invalid image.
@@ -112,11 +148,20 @@ pub enum StartMicrovmError {
     RegisterBlockDevice(device_manager::mmio::Error),
     /// Cannot register an EventHandler.
     RegisterEvent(EventManagerError),
+    /// Cannot restore the snapshot into the microVM.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    RestoreSnapshot(crate::snapshot::Error),
     /// Cannot initialize a MMIO Fs Device or add ad device to the MMIO Bus.
     RegisterFsDevice(device_manager::mmio::Error),
     /// Cannot register SIGWINCH event file descriptor.
     #[cfg(target_os = "linux")]
     RegisterFsSigwinch(kvm_ioctls::Error),
+    /// Cannot install the SIGBUS handler.
+    #[cfg(target_os = "linux")]
+    RegisterSigbusHandler(utils::errno::Error),
+    /// Cannot install the SIGSYS handler.
+    #[cfg(target_os = "linux")]
+    RegisterSigsysHandler(utils::errno::Error),
     /// Cannot initialize a MMIO Gpu device or add a device to the MMIO Bus.
     RegisterGpuDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Network Device or add a device to the MMIO Bus.
@@ -127,6 +172,27 @@ pub enum StartMicrovmError {
     RegisterSndDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus.
     RegisterVsockDevice(device_manager::mmio::Error),
+    /// Cannot declare the empty slots for hot-plugged devices.
+    #[cfg(target_os = "linux")]
+    ReserveHotplugSlots(device_manager::mmio::Error),
+    /// A reserved memory range covers memory the VMM needs to populate itself.
+    ReservedMemoryConflict(u64, u64),
+    /// A firmware reserved region isn't page aligned, lies outside guest RAM, or overlaps
+    /// memory the VMM populates itself or another reserved region.
+    InvalidReservedRegion(u64, u64),
+    /// A read-only region overlaps the guest RAM or an MMIO device.
+    ReadOnlyRegionConflict(u64, u64),
+    /// The I/O port range of an embedder handler overlaps a legacy device or another handler.
+    #[cfg(target_arch = "x86_64")]
+    PioHandlerConflict(u64, u64),
+    /// Cannot build the seccomp filter of the VMM threads.
+    #[cfg(target_os = "linux")]
+    SeccompFilter(utils::seccomp::Error),
+    /// Cannot spawn the thread activating the devices outside of the seccomp filters.
+    #[cfg(target_os = "linux")]
+    SpawnUnfilteredRunner(io::Error),
+    /// The serial console output is captured but the serial ports are disabled.
+    SerialCaptureWithoutSerial,
     /// Cannot attest the VM in the Secure Virtualization context.
     SecureVirtAttest(VstateError),
     /// Cannot initialize the Secure Virtualization backend.
@@ -152,12 +218,33 @@ impl Display for StartMicrovmError {
                 write!(f, "Unable to attach block device to Vmm. Error: {err}")
             }
             CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {err}"),
+            FsDaxWindow(ref tag) => write!(
+                f,
+                "The DAX window of the virtio-fs device {tag} is misaligned or too large"
+            ),
             GuestMemoryMmap(ref err) => {
                 // Remove imbricated quotes from error message.
                 let mut err_msg = format!("{err:?}");
                 err_msg = err_msg.replace('\"', "");
                 write!(f, "Invalid Memory Configuration: {err_msg}")
             }
+            GuestMemoryInit(ref err) => write!(f, "Cannot initialize the guest memory: {err}"),
+            MemoryFile(ref err) => write!(f, "Cannot open the guest memory file: {err}"),
+            MemoryFileMmap(ref err) => write!(f, "Cannot map the guest memory file: {err}"),
+            MemoryFileInit(mem_init) => write!(
+                f,
+                "File-backed guest memory can only be initialized lazily, not {mem_init}"
+            ),
+            MemoryFileOffset(offset) => write!(
+                f,
+                "The guest memory file offset {offset:#x} isn't a multiple of the page size"
+            ),
+            #[cfg(target_os = "linux")]
+            HugePages(page_size, ref err) => write!(
+                f,
+                "Cannot allocate the guest memory from {page_size} huge pages, check that enough \
+                 of them are free in /sys/kernel/mm/hugepages: {err}"
+            ),
             InitrdLoad => write!(
                 f,
                 "Cannot load initrd due to an invalid memory configuration."
@@ -226,6 +313,10 @@ impl Display for StartMicrovmError {
                 )
             }
             #[cfg(target_os = "linux")]
+            RegisterSigbusHandler(ref err) => write!(f, "Cannot install the SIGBUS handler: {err}"),
+            #[cfg(target_os = "linux")]
+            RegisterSigsysHandler(ref err) => write!(f, "Cannot install the SIGSYS handler: {err}"),
+            #[cfg(target_os = "linux")]
             RegisterFsSigwinch(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -277,6 +368,38 @@ impl Display for StartMicrovmError {
                     "Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus. {err_msg}"
                 )
             }
+            #[cfg(target_os = "linux")]
+            ReserveHotplugSlots(ref err) => {
+                write!(f, "Cannot reserve the slots for hot-plugged devices. {err}")
+            }
+            ReservedMemoryConflict(start, size) => write!(
+                f,
+                "The reserved memory range {start:#x}+{size:#x} overlaps memory needed to boot \
+                 the guest."
+            ),
+            InvalidReservedRegion(start, size) => write!(
+                f,
+                "The firmware reserved region {start:#x}+{size:#x} must be page aligned and lie \
+                 in guest RAM, away from the memory needed to boot the guest and from other \
+                 reserved regions."
+            ),
+            ReadOnlyRegionConflict(start, size) => write!(
+                f,
+                "The read-only region {start:#x}+{size:#x} overlaps the guest RAM or an MMIO \
+                 device."
+            ),
+            #[cfg(target_arch = "x86_64")]
+            PioHandlerConflict(base, len) => write!(
+                f,
+                "The I/O port handler {base:#x}+{len:#x} overlaps a legacy device or another \
+                 handler."
+            ),
+            SerialCaptureWithoutSerial => write!(
+                f,
+                "Cannot capture the serial console output without a serial port."
+            ),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            RestoreSnapshot(ref err) => write!(f, "Cannot restore the snapshot: {err}"),
             SecureVirtAttest(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -286,6 +409,12 @@ impl Display for StartMicrovmError {
                     "Cannot attest the VM in the Secure Virtualization context. {err_msg}"
                 )
             }
+            #[cfg(target_os = "linux")]
+            SeccompFilter(ref err) => write!(f, "Cannot build the seccomp filter: {err}"),
+            #[cfg(target_os = "linux")]
+            SpawnUnfilteredRunner(ref err) => {
+                write!(f, "Cannot spawn the thread activating the devices: {err}")
+            }
             SecureVirtPrepare(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -302,6 +431,212 @@ impl Display for StartMicrovmError {
     }
 }
 
+/// Configuration errors reported by `validate_microvm`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The kernel, or on TEE builds the qboot or initrd bundle, isn't configured.
+    MissingKernel,
+    /// Cannot open KVM to query its limits.
+    #[cfg(target_os = "linux")]
+    Hypervisor(kvm_ioctls::Error),
+    /// The vCPU count is higher than the hypervisor supports.
+    TooManyVcpus(u8, usize),
+    /// The kernel command line is invalid or too large, see `Error::LoadCommandline` and
+    /// `Error::KernelCmdlineTooLarge`.
+    KernelCmdline(Error),
+    /// An MMIO handler or read-only region overlaps the guest RAM or another one of them.
+    RegionOverlap(u64, u64),
+    /// The shared directory of a virtio-fs device, given as `(tag, dir)`, isn't a directory.
+    #[cfg(not(feature = "tee"))]
+    MissingSharedDir(String, String),
+    /// A file the VMM opens, or the directory it's created in, doesn't exist.
+    MissingPath(PathBuf),
+    /// The configured TEE isn't available on the host.
+    #[cfg(feature = "amd-sev")]
+    TeeUnavailable,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::ConfigError::*;
+        match self {
+            MissingKernel => write!(f, "No kernel bundle is configured"),
+            #[cfg(target_os = "linux")]
+            Hypervisor(err) => write!(f, "Cannot open KVM: {err}"),
+            TooManyVcpus(vcpu_count, max) => write!(
+                f,
+                "The VM has {vcpu_count} vCPUs but the hypervisor supports at most {max}"
+            ),
+            KernelCmdline(err) => write!(f, "Invalid kernel command line: {err}"),
+            RegionOverlap(start, size) => write!(
+                f,
+                "The region at {start:#x} ({size:#x} bytes) overlaps the guest RAM or another \
+                 region"
+            ),
+            #[cfg(not(feature = "tee"))]
+            MissingSharedDir(tag, dir) => write!(
+                f,
+                "The shared directory {dir} of the virtio-fs device {tag} is not a directory"
+            ),
+            MissingPath(path) => write!(f, "{} does not exist", path.display()),
+            #[cfg(feature = "amd-sev")]
+            TeeUnavailable => write!(f, "The configured TEE is not available on this host"),
+        }
+    }
+}
+
+// Physical memory of the host, in MiB.
+fn host_memory_mib() -> u64 {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages < 0 || page_size < 0 {
+        return u64::MAX;
+    }
+    (pages as u64 * page_size as u64) >> 20
+}
+
+/// Checks the configuration in `vm_resources` without building the microVM, reporting every
+/// problem found instead of only the first one `build_microvm` would fail on. Devices add
+/// parameters to the kernel command line while the microVM is built, so it can still turn out
+/// too long then. The files the devices open right away, like the block device images, and the
+/// vsock guest CID are already checked when the devices are configured.
+pub fn validate_microvm(
+    vm_resources: &super::resources::VmResources,
+) -> std::result::Result<(), Vec<ConfigError>> {
+    let mut errors = Vec::new();
+
+    #[cfg(not(feature = "efi"))]
+    let missing_kernel = vm_resources.kernel_bundle().is_none();
+    #[cfg(feature = "efi")]
+    let missing_kernel = false;
+    #[cfg(feature = "tee")]
+    let missing_kernel = missing_kernel
+        || vm_resources.qboot_bundle().is_none()
+        || vm_resources.initrd_bundle().is_none();
+    if missing_kernel {
+        errors.push(ConfigError::MissingKernel);
+    }
+
+    let mut ram = Vec::new();
+    if let Some(mem_size_mib) = vm_resources.vm_config().mem_size_mib {
+        // Only a warning, the guest memory is allocated as the guest uses it so this works as
+        // long as the guest doesn't use all of it.
+        let host_mib = host_memory_mib();
+        if mem_size_mib as u64 > host_mib {
+            warn!(
+                "The guest memory ({mem_size_mib} MiB) is larger than the host memory \
+                 ({host_mib} MiB)"
+            );
+        }
+        let mem_size = mem_size_mib << 20;
+        #[cfg(target_arch = "x86_64")]
+        let layout = vm_resources
+            .kernel_bundle()
+            .map(|kernel| arch::arch_memory_regions(mem_size, kernel.guest_addr, kernel.size));
+        #[cfg(target_arch = "aarch64")]
+        let layout = Some(arch::arch_memory_regions(mem_size));
+        if let Some((mut arch_mem_info, arch_mem_regions)) = layout {
+            ram = arch::reserve_memory_ranges(
+                &mut arch_mem_info,
+                &arch_mem_regions,
+                &vm_resources.reserved_memory,
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(vcpu_count) = vm_resources.vm_config().vcpu_count {
+        match kvm_ioctls::Kvm::new() {
+            Ok(kvm) if vcpu_count as usize > kvm.get_max_vcpus() => {
+                errors.push(ConfigError::TooManyVcpus(vcpu_count, kvm.get_max_vcpus()));
+            }
+            Ok(_) => {}
+            Err(err) => errors.push(ConfigError::Hypervisor(err)),
+        }
+    }
+
+    match vm_resources.boot_config.kernel_cmdline_len() {
+        Ok(len) if len > KERNEL_CMDLINE_LIMIT => {
+            errors.push(ConfigError::KernelCmdline(Error::KernelCmdlineTooLarge(
+                len,
+                KERNEL_CMDLINE_LIMIT,
+            )));
+        }
+        Ok(_) => {}
+        Err(err) => errors.push(ConfigError::KernelCmdline(Error::LoadCommandline(err))),
+    }
+
+    // Accesses to guest memory never exit to the VMM, so neither can overlap it.
+    #[allow(unused_mut)]
+    let mut regions: Vec<(u64, u64)> = vm_resources
+        .mmio_handlers
+        .iter()
+        .map(|&(base, len, _)| (base, len))
+        .collect();
+    #[cfg(target_os = "linux")]
+    regions.extend(
+        vm_resources
+            .readonly_regions
+            .iter()
+            .map(|region| (region.guest_addr, region.size())),
+    );
+    for (i, &(start, size)) in regions.iter().enumerate() {
+        let overlaps =
+            |s: u64, sz: u64| start < s.saturating_add(sz) && s < start.saturating_add(size);
+        if ram.iter().any(|&(addr, len)| overlaps(addr.0, len as u64))
+            || regions[..i].iter().any(|&(s, sz)| overlaps(s, sz))
+        {
+            errors.push(ConfigError::RegionOverlap(start, size));
+        }
+    }
+
+    #[cfg(not(feature = "tee"))]
+    for fs in vm_resources.fs.list.iter() {
+        let fs = fs.lock().unwrap();
+        if !Path::new(fs.shared_dir()).is_dir() {
+            errors.push(ConfigError::MissingSharedDir(
+                fs.tag().to_string(),
+                fs.shared_dir().to_string(),
+            ));
+        }
+    }
+
+    // Created if missing, but not their directory.
+    let mut created = Vec::new();
+    if let Some(path) = &vm_resources.console_output {
+        created.push(path);
+    }
+    match &vm_resources.memory_backend {
+        MemoryBackend::FileBacked {
+            path, shared: true, ..
+        } => created.push(path),
+        MemoryBackend::FileBacked { path, .. } if !path.exists() => {
+            errors.push(ConfigError::MissingPath(path.clone()));
+        }
+        _ => {}
+    }
+    for path in created {
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                errors.push(ConfigError::MissingPath(dir.to_path_buf()));
+            }
+        }
+    }
+
+    #[cfg(feature = "amd-sev")]
+    if !matches!(vm_resources.tee_config().tee, Tee::Sev | Tee::Snp)
+        || !Path::new("/dev/sev").exists()
+    {
+        errors.push(ConfigError::TeeUnavailable);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
 /// Builds and starts a microVM based on the current Firecracker VmResources configuration.
 ///
 /// This is the default build recipe, one could build other microVM flavors by using the
@@ -310,13 +645,55 @@ impl Display for StartMicrovmError {
 /// An `Arc` reference of the built `Vmm` is also plugged in the `EventManager`, while another
 /// is returned.
 pub fn build_microvm(
+    vm_resources: &super::resources::VmResources,
+    event_manager: &mut EventManager,
+    shutdown_efd: Option<EventFd>,
+    #[cfg(target_os = "macos")] map_sender: Sender<MemoryMapping>,
+) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
+    build_microvm_inner(
+        vm_resources,
+        event_manager,
+        shutdown_efd,
+        #[cfg(target_os = "macos")]
+        map_sender,
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        None,
+    )
+}
+
+/// Builds a microVM from `vm_resources` like `build_microvm`, then restores the snapshot written
+/// by `Vmm::snapshot` to `state_path` and `mem_path` into it, leaving its vCPUs paused until
+/// `Vmm::resume_vcpus`.
+///
+/// `vm_resources` must describe the snapshotted microVM: the same kernel command line, memory
+/// layout, vCPU count and devices. The snapshot is rejected if the host supports other CPU
+/// features than the one it was taken on.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub fn build_microvm_from_snapshot(
+    vm_resources: &super::resources::VmResources,
+    event_manager: &mut EventManager,
+    shutdown_efd: Option<EventFd>,
+    state_path: &Path,
+    mem_path: &Path,
+) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
+    build_microvm_inner(
+        vm_resources,
+        event_manager,
+        shutdown_efd,
+        Some((state_path, mem_path)),
+    )
+}
+
+fn build_microvm_inner(
     vm_resources: &super::resources::VmResources,
     event_manager: &mut EventManager,
     _shutdown_efd: Option<EventFd>,
     #[cfg(target_os = "macos")] _map_sender: Sender<MemoryMapping>,
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))] snapshot: Option<(&Path, &Path)>,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
     // Timestamp for measuring microVM boot duration.
     let request_ts = TimestampUs::default();
+    let build_start = Instant::now();
 
     #[cfg(not(feature = "efi"))]
     let kernel_bundle = vm_resources
@@ -343,26 +720,58 @@ pub fn build_microvm(
             .vm_config()
             .mem_size_mib
             .ok_or(StartMicrovmError::MissingMemSizeConfig)?,
+        vm_resources.vm_config().mem_init.unwrap_or_default(),
+        &vm_resources.memory_backend,
+        &vm_resources.reserved_memory,
+        &vm_resources.boot_config.reserved_regions,
         #[cfg(not(feature = "efi"))]
         kernel_region,
         #[cfg(not(feature = "efi"))]
         kernel_bundle.guest_addr,
         #[cfg(not(feature = "efi"))]
         kernel_bundle.size,
+        #[cfg(not(feature = "tee"))]
+        vm_resources.initrd.as_ref().map(Vec::len),
         #[cfg(feature = "tee")]
         qboot_bundle,
         #[cfg(feature = "tee")]
         initrd_bundle,
     )?;
+    #[cfg(target_os = "linux")]
+    register_sigbus_handler(&guest_memory).map_err(StartMicrovmError::RegisterSigbusHandler)?;
+    #[cfg(target_os = "linux")]
+    if vm_resources.seccomp_mode == SeccompMode::Kill {
+        register_sigsys_handler().map_err(StartMicrovmError::RegisterSigsysHandler)?;
+    }
+    #[cfg(target_os = "linux")]
+    let vcpu_seccomp_filter = crate::linux::seccomp::vcpu_filter(vm_resources.seccomp_mode)
+        .map_err(StartMicrovmError::SeccompFilter)?;
     let vcpu_config = vm_resources.vcpu_config();
 
+    // The VMM only adds to the command line, so don't go any further if the one configured
+    // already doesn't fit.
+    let cmdline_len = vm_resources
+        .boot_config
+        .kernel_cmdline_len()
+        .map_err(StartMicrovmError::LoadCommandline)?;
+    if cmdline_len > KERNEL_CMDLINE_LIMIT {
+        return Err(StartMicrovmError::Internal(Error::KernelCmdlineTooLarge(
+            cmdline_len,
+            KERNEL_CMDLINE_LIMIT,
+        )));
+    }
+
     // Clone the command-line so that a failed boot doesn't pollute the original.
     #[allow(unused_mut)]
     let mut kernel_cmdline = kernel::cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
-    match &vm_resources.boot_config.kernel_cmdline_prolog {
-        None => kernel_cmdline.insert_str(DEFAULT_KERNEL_CMDLINE).unwrap(),
-        Some(s) => kernel_cmdline.insert_str(s).unwrap(),
+    let prolog = match &vm_resources.boot_config.kernel_cmdline_prolog {
+        None => DEFAULT_KERNEL_CMDLINE,
+        Some(s) => s.as_str(),
     };
+    vm_resources
+        .boot_config
+        .kernel_cmdline_args
+        .apply(prolog, &mut kernel_cmdline)?;
 
     #[cfg(not(feature = "tee"))]
     #[allow(unused_mut)]
@@ -376,6 +785,7 @@ pub fn build_microvm(
         let vm = setup_vm(&kvm, &guest_memory, vm_resources.tee_config())?;
         (kvm, vm)
     };
+    let vm_created = Instant::now();
 
     #[cfg(feature = "tee")]
     let tee = vm_resources.tee_config().tee;
@@ -436,9 +846,23 @@ pub fn build_microvm(
         m
     };
 
-    // On x86_64 always create a serial device,
-    // while on aarch64 only create it if 'console=' is specified in the boot args.
-    let serial_device = if cfg!(feature = "efi") {
+    if vm_resources.disable_serial && vm_resources.serial_capture_lines.is_some() {
+        return Err(StartMicrovmError::SerialCaptureWithoutSerial);
+    }
+    let serial_lines = vm_resources
+        .serial_capture_lines
+        .map(devices::legacy::SerialLineBuffer::new);
+
+    // Only create a serial device if its output is captured, or to get the firmware's.
+    let serial_device = if vm_resources.disable_serial {
+        None
+    } else if let Some(serial_lines) = &serial_lines {
+        Some(setup_serial_device(
+            event_manager,
+            None,
+            Some(Box::new(serial_lines.clone())),
+        )?)
+    } else if cfg!(feature = "efi") {
         Some(setup_serial_device(
             event_manager,
             None,
@@ -455,10 +879,10 @@ pub fn build_microvm(
         .map_err(StartMicrovmError::Internal)?;
 
     #[cfg(target_arch = "x86_64")]
-    // Safe to unwrap 'serial_device' as it's always 'Some' on x86_64.
     // x86_64 uses the i8042 reset event as the Vmm exit event.
     let mut pio_device_manager = PortIODeviceManager::new(
         serial_device,
+        !vm_resources.disable_serial,
         exit_evt
             .try_clone()
             .map_err(Error::EventFd)
@@ -475,6 +899,42 @@ pub fn build_microvm(
         &mut (arch::MMIO_MEM_START.clone()),
         (arch::IRQ_BASE, arch::IRQ_MAX),
     );
+    mmio_device_manager.set_device_ids(vm_resources.device_ids.clone());
+
+    if let Some(observer) = &vm_resources.unhandled_access_observer {
+        mmio_device_manager.set_unhandled_access_observer(observer.clone());
+        #[cfg(target_arch = "x86_64")]
+        pio_device_manager.set_unhandled_access_observer(observer.clone());
+    }
+
+    #[cfg(target_os = "linux")]
+    map_readonly_regions(
+        &mut vm,
+        &guest_memory,
+        &mut mmio_device_manager,
+        &vm_resources.readonly_regions,
+    )?;
+
+    #[cfg(target_arch = "aarch64")]
+    let guest_panic_evt = if vm_resources.pvpanic_stop_on_panic.is_some() {
+        let evt = EventFd::new(utils::eventfd::EFD_NONBLOCK)
+            .map_err(Error::EventFd)
+            .map_err(StartMicrovmError::Internal)?;
+        let device_evt = evt
+            .try_clone()
+            .map_err(Error::EventFd)
+            .map_err(StartMicrovmError::Internal)?;
+        mmio_device_manager
+            .register_mmio_pvpanic(device_evt)
+            .map_err(Error::RegisterMMIODevice)
+            .map_err(StartMicrovmError::Internal)?;
+        Some(evt)
+    } else {
+        None
+    };
+    // Rejected by `VmResources::set_pvpanic`, the guest would have no way to find the device.
+    #[cfg(target_arch = "x86_64")]
+    let guest_panic_evt = None;
 
     #[cfg(target_os = "linux")]
     let intc = None;
@@ -493,6 +953,24 @@ pub fn build_microvm(
     {
         setup_interrupt_controller(&vm)?;
         attach_legacy_devices(&vm, &mut pio_device_manager)?;
+        // Registered after the legacy devices, so one overlapping them is the one refused, but
+        // before the vCPUs are created as they get a copy of the I/O bus.
+        for (base, len, handler) in &vm_resources.pio_handlers {
+            pio_device_manager
+                .register_handler(*base, *len, handler.clone())
+                .map_err(|_| StartMicrovmError::PioHandlerConflict(*base, *len))?;
+        }
+
+        if vcpu_config.nested {
+            vm.check_nested_support()
+                .map_err(Error::Vm)
+                .map_err(StartMicrovmError::Internal)?;
+        }
+        if vm_resources.ptp_kvm {
+            vm.check_ptp_kvm_support()
+                .map_err(Error::Vm)
+                .map_err(StartMicrovmError::Internal)?;
+        }
 
         vcpus = create_vcpus_x86_64(
             &vm,
@@ -502,6 +980,8 @@ pub fn build_microvm(
             request_ts,
             &pio_device_manager.io_bus,
             &exit_evt,
+            vm_resources.halt_exit_code,
+            vm_resources.vcpu_time_slice,
         )
         .map_err(StartMicrovmError::Internal)?;
     }
@@ -512,6 +992,15 @@ pub fn build_microvm(
     // Search for `kvm_arch_vcpu_create` in arch/arm/kvm/arm.c.
     #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
     {
+        let readonly_faults: Vec<_> = vm_resources
+            .readonly_regions
+            .iter()
+            .filter(|region| matches!(region.policy, ReadOnlyWritePolicy::FaultToGuest))
+            .map(|region| (region.guest_addr, region.size()))
+            .collect();
+        vm.check_vcpu_features(&vcpu_config)
+            .map_err(Error::Vm)
+            .map_err(StartMicrovmError::Internal)?;
         vcpus = create_vcpus_aarch64(
             &vm,
             &vcpu_config,
@@ -519,18 +1008,46 @@ pub fn build_microvm(
             GuestAddress(kernel_bundle.guest_addr),
             request_ts,
             &exit_evt,
+            vm_resources.vcpu_time_slice,
+            &readonly_faults,
         )
         .map_err(StartMicrovmError::Internal)?;
 
+        if vm_resources.ptp_kvm {
+            vm.check_ptp_kvm_support()
+                .map_err(Error::Vm)
+                .map_err(StartMicrovmError::Internal)?;
+            for vcpu in &vcpus {
+                vcpu.enable_ptp_kvm()
+                    .map_err(Error::Vcpu)
+                    .map_err(StartMicrovmError::Internal)?;
+            }
+        }
+
         setup_interrupt_controller(&mut vm, vcpu_config.vcpu_count)?;
+        for vcpu in vcpus.iter().filter(|vcpu| vcpu.has_pmu()) {
+            vcpu.init_pmu()
+                .map_err(Error::Vcpu)
+                .map_err(StartMicrovmError::Internal)?;
+        }
         attach_legacy_devices(
             &vm,
             &mut mmio_device_manager,
             &mut kernel_cmdline,
             serial_device,
+            &vm_resources.rtc_config,
         )?;
     }
 
+    // Kept by the `Vmm` to press the power button itself on `shutdown`.
+    #[cfg(target_os = "macos")]
+    let shutdown_evt = _shutdown_efd
+        .as_ref()
+        .map(EventFd::try_clone)
+        .transpose()
+        .map_err(Error::EventFd)
+        .map_err(StartMicrovmError::Internal)?;
+
     #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
     {
         #[cfg(not(feature = "efi"))]
@@ -558,17 +1075,39 @@ pub fn build_microvm(
             serial_device,
             event_manager,
             _shutdown_efd,
+            &vm_resources.rtc_config,
         )?;
     }
 
     #[cfg(not(feature = "tee"))]
-    let _shm_region = Some(VirtioShmRegion {
-        host_addr: guest_memory
-            .get_host_address(GuestAddress(arch_memory_info.shm_start_addr))
-            .unwrap() as u64,
-        guest_addr: arch_memory_info.shm_start_addr,
-        size: arch_memory_info.shm_size as usize,
-    });
+    let (_shm_region, fs_shm_regions) = {
+        let shm_region = VirtioShmRegion {
+            host_addr: guest_memory
+                .get_host_address(GuestAddress(arch_memory_info.shm_start_addr))
+                .unwrap() as u64,
+            guest_addr: arch_memory_info.shm_start_addr,
+            size: arch_memory_info.shm_size as usize,
+        };
+        let fs_windows: Vec<_> = vm_resources
+            .fs
+            .list
+            .iter()
+            .map(|fs| {
+                let fs = fs.lock().unwrap();
+                (fs.tag().to_string(), fs.shm_size())
+            })
+            .collect();
+        let (gpu_shm_region, fs_shm_regions) = split_shm_region(shm_region, &fs_windows)?;
+        (Some(gpu_shm_region), fs_shm_regions)
+    };
+
+    let memory_reclaimer = devices::virtio::MemoryReclaimer::new();
+    if arch_memory_info.shm_size > 0 {
+        memory_reclaimer.reserve(
+            GuestAddress(arch_memory_info.shm_start_addr),
+            arch_memory_info.shm_size,
+        );
+    }
 
     let mut vmm = Vmm {
         guest_memory,
@@ -577,21 +1116,78 @@ pub fn build_microvm(
         vcpus_handles: Vec::new(),
         exit_evt,
         exit_observers: Vec::new(),
+        quiesce_observers: Vec::new(),
+        #[cfg(not(feature = "tee"))]
+        fs_devices: Vec::new(),
+        #[cfg(feature = "net")]
+        net_devices: Vec::new(),
+        #[cfg(not(feature = "tee"))]
+        balloon_device: None,
+        vsock_cid: None,
+        _kernel_image: vm_resources.kernel_image.clone(),
+        events_observer: vm_resources.events_observer.clone(),
+        #[cfg(target_os = "macos")]
+        next_guest_window_addr: 0,
+        memory_advice: None,
+        memory_reclaimer,
+        #[cfg(target_os = "linux")]
+        unfiltered_runner: vcpu_seccomp_filter
+            .is_some()
+            .then(UnfilteredRunner::new)
+            .transpose()
+            .map_err(StartMicrovmError::SpawnUnfilteredRunner)?,
+        rtc_config: vm_resources.rtc_config,
+        serial_lines,
+        console_channel: None,
+        #[cfg(target_os = "linux")]
+        guest_signal_evt: None,
+        #[cfg(target_os = "linux")]
+        guest_signal_actions: Vec::new(),
+        #[cfg(target_os = "macos")]
+        shutdown_evt,
+        guest_panic_evt,
+        #[cfg(target_arch = "aarch64")]
+        stop_on_guest_panic: vm_resources.pvpanic_stop_on_panic.unwrap_or(false),
+        exit_on_stop: true,
+        exit_code: None,
+        #[cfg(target_os = "linux")]
+        vcpu_entry_failure: None,
+        last_exit_code: None,
+        vcpu_handshake_timeout: vm_resources
+            .vcpu_handshake_timeout
+            .unwrap_or(crate::DEFAULT_VCPU_HANDSHAKE_TIMEOUT),
+        boot_timings: BootTimings {
+            build_start,
+            vm_created,
+            vcpus_started: None,
+            vcpus_resumed: None,
+            guest_boot_complete: None,
+        },
+        #[cfg(target_os = "linux")]
+        guest_boot_complete: Default::default(),
+        #[cfg(target_os = "macos")]
+        vcpus_paused: false,
         vm,
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
     };
 
+    if let Some(advice) = vm_resources.memory_advice {
+        vmm.advise_memory(advice)
+            .map_err(StartMicrovmError::Internal)?;
+    }
+
     #[cfg(not(feature = "tee"))]
-    attach_balloon_device(&mut vmm, event_manager, intc.clone())?;
+    attach_balloon_device(&mut vmm, event_manager, intc.clone(), &vm_resources.balloon)?;
     #[cfg(not(feature = "tee"))]
-    attach_rng_device(&mut vmm, event_manager, intc.clone())?;
+    attach_rng_device(&mut vmm, event_manager, intc.clone(), &vm_resources.rng)?;
     attach_console_devices(
         &mut vmm,
         event_manager,
         intc.clone(),
         vm_resources.console_output.clone(),
+        vm_resources.console_channel_capacity,
     )?;
     #[cfg(feature = "gpu")]
     if let Some(virgl_flags) = vm_resources.gpu_virgl_flags {
@@ -602,15 +1198,25 @@ pub fn build_microvm(
             intc.clone(),
             virgl_flags,
             #[cfg(target_os = "macos")]
-            _map_sender,
+            _map_sender.clone(),
         )?;
     }
     #[cfg(not(feature = "tee"))]
-    attach_fs_devices(&mut vmm, &vm_resources.fs, None, intc.clone())?;
+    attach_fs_devices(
+        &mut vmm,
+        &vm_resources.fs,
+        fs_shm_regions,
+        intc.clone(),
+        #[cfg(target_os = "linux")]
+        vm_resources.seccomp_mode,
+        #[cfg(target_os = "macos")]
+        _map_sender,
+    )?;
     #[cfg(feature = "blk")]
     attach_block_devices(&mut vmm, &vm_resources.block, intc.clone())?;
     if let Some(vsock) = vm_resources.vsock.get() {
         attach_unixsock_vsock_device(&mut vmm, vsock, event_manager, intc.clone())?;
+        vmm.vsock_cid = vm_resources.vsock.guest_cid().cloned();
         #[cfg(not(feature = "net"))]
         vmm.kernel_cmdline.insert_str("tsi_hijack")?;
         #[cfg(feature = "net")]
@@ -631,6 +1237,29 @@ pub fn build_microvm(
         attach_snd_device(&mut vmm, intc.clone())?;
     }
 
+    #[cfg(target_os = "linux")]
+    vmm.mmio_device_manager
+        .reserve_hotplug_slots(&mut vmm.kernel_cmdline, vm_resources.hotplug_slots)
+        .map_err(StartMicrovmError::ReserveHotplugSlots)?;
+
+    // The others are on the MMIO bus, so the devices couldn't be registered over them.
+    #[cfg(target_os = "linux")]
+    for region in &vm_resources.readonly_regions {
+        let (start, size) = (region.guest_addr, region.size());
+        if matches!(region.policy, ReadOnlyWritePolicy::FaultToGuest)
+            && vmm.mmio_device_manager.overlaps_device(start, size)
+        {
+            return Err(StartMicrovmError::ReadOnlyRegionConflict(start, size));
+        }
+    }
+
+    // Registered last so they can't take the place of the devices above.
+    attach_mmio_handlers(&mut vmm, &vm_resources.mmio_handlers)?;
+
+    if vm_resources.disable_kaslr {
+        vmm.kernel_cmdline.insert_str("nokaslr")?;
+    }
+
     if let Some(s) = &vm_resources.boot_config.kernel_cmdline_epilog {
         vmm.kernel_cmdline.insert_str(s).unwrap();
     };
@@ -647,12 +1276,18 @@ pub fn build_microvm(
     });
 
     #[cfg(not(feature = "tee"))]
-    let initrd_config = None;
+    let initrd_config = vm_resources
+        .initrd
+        .as_deref()
+        .map(|initrd| load_initrd(vmm.guest_memory(), initrd))
+        .transpose()?;
 
     vmm.configure_system(
         vcpus.as_slice(),
+        &vm_resources.cpu_topology(),
         &initrd_config,
         &vm_resources.smbios_oem_strings,
+        &vm_resources.boot_entropy,
     )
     .map_err(StartMicrovmError::Internal)?;
 
@@ -685,7 +1320,34 @@ pub fn build_microvm(
         println!("Starting TEE/microVM.");
     }
 
-    vmm.start_vcpus(vcpus)
+    // Installed last so these take precedence over the console SIGINT handler.
+    #[cfg(target_os = "linux")]
+    if !vm_resources.guest_signals.is_empty() {
+        vmm.forward_guest_signals(vm_resources.guest_signals.clone())
+            .map_err(StartMicrovmError::Internal)?;
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    let start_paused = vm_resources.start_paused || snapshot.is_some();
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    let start_paused = vm_resources.start_paused;
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    if let Some((state_path, mem_path)) = snapshot {
+        crate::snapshot::restore(&mut vmm, &vcpus, state_path, mem_path)
+            .map_err(StartMicrovmError::RestoreSnapshot)?;
+    }
+
+    // The vCPU threads install their filter themselves, before the guest runs.
+    #[cfg(target_os = "linux")]
+    let vcpus: Vec<_> = vcpus
+        .into_iter()
+        .map(|mut vcpu| {
+            vcpu.set_seccomp_filter(vcpu_seccomp_filter.clone());
+            vcpu
+        })
+        .collect();
+
+    vmm.start_vcpus(vcpus, start_paused)
         .map_err(StartMicrovmError::Internal)?;
 
     // Clippy thinks we don't need Arc<Mutex<...
@@ -699,26 +1361,58 @@ pub fn build_microvm(
     Ok(vmm)
 }
 
+/// Inspects the kernel image at `path` without building a VM, so it can be validated up front.
+/// Errors tell what the loader found unexpected in the image.
+pub fn inspect_kernel(path: &Path) -> std::result::Result<KernelInfo, Error> {
+    let mut file = File::open(path).map_err(Error::KernelFile)?;
+    kernel::loader::inspect_kernel(&mut file).map_err(Error::KernelLoader)
+}
+
 /// Creates GuestMemory of `mem_size_mib` MiB in size.
 #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+#[allow(clippy::too_many_arguments)]
 pub fn create_guest_memory(
     mem_size_mib: usize,
+    mem_init: MemoryInit,
+    memory_backend: &MemoryBackend,
+    reserved_memory: &[(u64, u64)],
+    reserved_regions: &[(GuestAddress, u64)],
     kernel_region: MmapRegion,
     kernel_load_addr: u64,
     kernel_size: usize,
+    initrd_size: Option<usize>,
 ) -> std::result::Result<(GuestMemoryMmap, ArchMemoryInfo), StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
-    let (arch_mem_info, arch_mem_regions) =
+    let (mut arch_mem_info, arch_mem_regions) =
         arch::arch_memory_regions(mem_size, kernel_load_addr, kernel_size);
+    // Below 1 MiB are the zero page, the command line, the setup data, the MP table and SMBIOS.
+    let in_use = [
+        (0, arch::x86_64::layout::HIMEM_START),
+        (kernel_load_addr, kernel_size as u64),
+        (arch_mem_info.shm_start_addr, arch_mem_info.shm_size),
+    ];
+    let arch_mem_regions = reserve_guest_memory(
+        &mut arch_mem_info,
+        &arch_mem_regions,
+        reserved_memory,
+        &in_use,
+    )?;
+
+    let guest_mem = map_guest_memory(&arch_mem_regions, memory_backend, mem_init)?;
+    let in_use = [&in_use[..], &boot_ranges(&guest_mem, initrd_size)].concat();
+    claim_firmware_regions(
+        &mut arch_mem_info,
+        &arch_mem_regions,
+        reserved_regions,
+        &in_use,
+    )?;
 
     Ok((
-        GuestMemoryMmap::from_ranges(&arch_mem_regions)
-            .and_then(|memory| {
-                memory.insert_region(Arc::new(GuestRegionMmap::new(
-                    kernel_region,
-                    GuestAddress(kernel_load_addr),
-                )?))
-            })
+        guest_mem
+            .insert_region(Arc::new(
+                GuestRegionMmap::new(kernel_region, GuestAddress(kernel_load_addr))
+                    .map_err(StartMicrovmError::GuestMemoryMmap)?,
+            ))
             .map_err(StartMicrovmError::GuestMemoryMmap)?,
         arch_mem_info,
     ))
@@ -726,8 +1420,13 @@ pub fn create_guest_memory(
 
 /// Creates GuestMemory of `mem_size_mib` MiB in size.
 #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "tee"))]
+#[allow(clippy::too_many_arguments)]
 pub fn create_guest_memory(
     mem_size_mib: usize,
+    mem_init: MemoryInit,
+    memory_backend: &MemoryBackend,
+    reserved_memory: &[(u64, u64)],
+    reserved_regions: &[(GuestAddress, u64)],
     kernel_region: MmapRegion,
     kernel_load_addr: u64,
     kernel_size: usize,
@@ -735,11 +1434,32 @@ pub fn create_guest_memory(
     initrd_bundle: &InitrdBundle,
 ) -> std::result::Result<(GuestMemoryMmap, ArchMemoryInfo), StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
-    let (arch_mem_info, arch_mem_regions) =
+    let (mut arch_mem_info, arch_mem_regions) =
         arch::arch_memory_regions(mem_size, kernel_load_addr, kernel_size);
+    // Below 1 MiB are the boot structures and the SNP pages.
+    let in_use = [
+        (0, arch::x86_64::layout::HIMEM_START),
+        (kernel_load_addr, kernel_size as u64),
+        (arch::BIOS_START, arch::BIOS_SIZE as u64),
+        (
+            arch::x86_64::layout::INITRD_SEV_START,
+            initrd_bundle.size as u64,
+        ),
+    ];
+    let arch_mem_regions = reserve_guest_memory(
+        &mut arch_mem_info,
+        &arch_mem_regions,
+        reserved_memory,
+        &in_use,
+    )?;
+    claim_firmware_regions(
+        &mut arch_mem_info,
+        &arch_mem_regions,
+        reserved_regions,
+        &in_use,
+    )?;
 
-    let guest_mem = GuestMemoryMmap::from_ranges(&arch_mem_regions)
-        .map_err(StartMicrovmError::GuestMemoryMmap)?;
+    let guest_mem = map_guest_memory(&arch_mem_regions, memory_backend, mem_init)?;
 
     let kernel_data = unsafe { std::slice::from_raw_parts(kernel_region.as_ptr(), kernel_size) };
     guest_mem
@@ -766,37 +1486,425 @@ pub fn create_guest_memory(
 }
 
 #[cfg(all(target_arch = "aarch64", not(feature = "efi")))]
+#[allow(clippy::too_many_arguments)]
 pub fn create_guest_memory(
     mem_size_mib: usize,
+    mem_init: MemoryInit,
+    memory_backend: &MemoryBackend,
+    reserved_memory: &[(u64, u64)],
+    reserved_regions: &[(GuestAddress, u64)],
     kernel_region: MmapRegion,
     kernel_load_addr: u64,
     kernel_size: usize,
+    initrd_size: Option<usize>,
+) -> std::result::Result<(GuestMemoryMmap, ArchMemoryInfo), StartMicrovmError> {
+    let mem_size = mem_size_mib << 20;
+    let (mut arch_mem_info, arch_mem_regions) = arch::arch_memory_regions(mem_size);
+    let in_use = [
+        (kernel_load_addr, kernel_size as u64),
+        (arch_mem_info.shm_start_addr, arch_mem_info.shm_size),
+    ];
+    let arch_mem_regions = reserve_guest_memory(
+        &mut arch_mem_info,
+        &arch_mem_regions,
+        reserved_memory,
+        &in_use,
+    )?;
+
+    let guest_mem = map_guest_memory(&arch_mem_regions, memory_backend, mem_init)?;
+    let in_use = [&in_use[..], &boot_ranges(&guest_mem, initrd_size)].concat();
+    claim_firmware_regions(
+        &mut arch_mem_info,
+        &arch_mem_regions,
+        reserved_regions,
+        &in_use,
+    )?;
+
+    let kernel_data = unsafe { std::slice::from_raw_parts(kernel_region.as_ptr(), kernel_size) };
+    guest_mem
+        .write(kernel_data, GuestAddress(kernel_load_addr))
+        .unwrap();
+    Ok((guest_mem, arch_mem_info))
+}
+
+#[cfg(all(target_arch = "aarch64", feature = "efi"))]
+pub fn create_guest_memory(
+    mem_size_mib: usize,
+    mem_init: MemoryInit,
+    memory_backend: &MemoryBackend,
+    reserved_memory: &[(u64, u64)],
+    reserved_regions: &[(GuestAddress, u64)],
+    initrd_size: Option<usize>,
 ) -> std::result::Result<(GuestMemoryMmap, ArchMemoryInfo), StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
-    let (arch_mem_info, arch_mem_regions) = arch::arch_memory_regions(mem_size);
+    let (mut arch_mem_info, arch_mem_regions) = arch::arch_memory_regions(mem_size);
+    let in_use = [
+        (0, EDK2_BINARY.len() as u64),
+        (
+            arch::aarch64::layout::SMBIOS_START,
+            arch::aarch64::layout::SMBIOS_MAX_SIZE,
+        ),
+        (arch_mem_info.shm_start_addr, arch_mem_info.shm_size),
+    ];
+    let arch_mem_regions = reserve_guest_memory(
+        &mut arch_mem_info,
+        &arch_mem_regions,
+        reserved_memory,
+        &in_use,
+    )?;
+
+    let guest_mem = map_guest_memory(&arch_mem_regions, memory_backend, mem_init)?;
+    let in_use = [&in_use[..], &boot_ranges(&guest_mem, initrd_size)].concat();
+    claim_firmware_regions(
+        &mut arch_mem_info,
+        &arch_mem_regions,
+        reserved_regions,
+        &in_use,
+    )?;
+
+    guest_mem.write(EDK2_BINARY, GuestAddress(0u64)).unwrap();
+    Ok((guest_mem, arch_mem_info))
+}
+
+/// Maps the guest memory `regions` from `memory_backend`, then initializes them according to
+/// `mem_init`.
+fn map_guest_memory(
+    regions: &[(GuestAddress, usize)],
+    memory_backend: &MemoryBackend,
+    mem_init: MemoryInit,
+) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
+    let (path, offset, shared) = match memory_backend {
+        MemoryBackend::FileBacked {
+            path,
+            offset,
+            shared,
+        } => (path, offset, shared),
+        #[cfg(target_os = "linux")]
+        MemoryBackend::HugePages(page_size) => {
+            let guest_mem = map_huge_pages(regions, *page_size)?;
+            init_guest_memory(&guest_mem, mem_init)?;
+            return Ok(guest_mem);
+        }
+        #[cfg(target_os = "linux")]
+        MemoryBackend::Memfd => {
+            let guest_mem = map_memfd(regions)?;
+            init_guest_memory(&guest_mem, mem_init)?;
+            return Ok(guest_mem);
+        }
+        MemoryBackend::Anonymous => {
+            let guest_mem = GuestMemoryMmap::from_ranges(regions)
+                .map_err(StartMicrovmError::GuestMemoryMmap)?;
+            init_guest_memory(&guest_mem, mem_init)?;
+            return Ok(guest_mem);
+        }
+    };
+
+    // Zeroing or prefaulting the memory would write every page of a shared file, and copy every
+    // page of a private one.
+    if mem_init != MemoryInit::Lazy {
+        return Err(StartMicrovmError::MemoryFileInit(mem_init));
+    }
+    // Checked before a shared file gets created or extended, `mmap` would refuse it anyway.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    if offset % page_size != 0 {
+        return Err(StartMicrovmError::MemoryFileOffset(*offset));
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(*shared)
+        .create(*shared)
+        .truncate(false)
+        .open(path)
+        .map_err(StartMicrovmError::MemoryFile)?;
+    if *shared {
+        let size: u64 = regions.iter().map(|&(_, len)| len as u64).sum();
+        let end = offset.saturating_add(size);
+        if file
+            .metadata()
+            .map_err(StartMicrovmError::MemoryFile)?
+            .len()
+            < end
+        {
+            file.set_len(end).map_err(StartMicrovmError::MemoryFile)?;
+        }
+    }
+
+    let flags = libc::MAP_NORESERVE
+        | if *shared {
+            libc::MAP_SHARED
+        } else {
+            libc::MAP_PRIVATE
+        };
+    let file = Arc::new(file);
+    let mut file_offset = *offset;
+    let regions = regions
+        .iter()
+        .map(|&(addr, len)| {
+            let region = MmapRegion::build(
+                Some(FileOffset::from_arc(file.clone(), file_offset)),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+            )
+            .map_err(StartMicrovmError::MemoryFileMmap)?;
+            file_offset += len as u64;
+            GuestRegionMmap::new(region, addr).map_err(StartMicrovmError::GuestMemoryMmap)
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    GuestMemoryMmap::from_regions(regions).map_err(StartMicrovmError::GuestMemoryMmap)
+}
+
+/// Maps the guest memory `regions` from huge pages of `page_size`, backing the parts of the
+/// regions that aren't aligned to it with normal pages.
+#[cfg(target_os = "linux")]
+fn map_huge_pages(
+    regions: &[(GuestAddress, usize)],
+    page_size: HugePageSize,
+) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
+    let align = page_size.size() as u64;
+    let mut ranges = Vec::new();
+    for &(addr, len) in regions {
+        let end = addr.0 + len as u64;
+        let huge_start = addr.0.next_multiple_of(align);
+        let huge_end = end - end % align;
+        if huge_start >= huge_end {
+            ranges.push((addr.0, end, false));
+            continue;
+        }
+        ranges.push((addr.0, huge_start, false));
+        ranges.push((huge_start, huge_end, true));
+        ranges.push((huge_end, end, false));
+    }
+
+    let regions = ranges
+        .into_iter()
+        .filter(|&(start, end, _)| start < end)
+        .map(|(start, end, huge)| {
+            let len = (end - start) as usize;
+            let region = if huge {
+                // Without MAP_NORESERVE the huge pages are reserved now, so running out of them
+                // fails here rather than with a SIGBUS when the guest touches them.
+                MmapRegion::build(
+                    None,
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | page_size.mmap_flags(),
+                )
+                .map_err(|err| StartMicrovmError::HugePages(page_size, err))?
+            } else {
+                MmapRegion::new(len).map_err(|err| {
+                    StartMicrovmError::GuestMemoryMmap(vm_memory::Error::MmapRegion(err))
+                })?
+            };
+            GuestRegionMmap::new(region, GuestAddress(start))
+                .map_err(StartMicrovmError::GuestMemoryMmap)
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    GuestMemoryMmap::from_regions(regions).map_err(StartMicrovmError::GuestMemoryMmap)
+}
+
+/// Maps the guest memory `regions` from a new memfd, laid out back to back from its start with
+/// each region beginning on a page boundary.
+#[cfg(target_os = "linux")]
+fn map_memfd(
+    regions: &[(GuestAddress, usize)],
+) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+    // SAFETY: the name is a valid C string, and the returned fd is owned by nobody else.
+    let fd = unsafe { libc::memfd_create(c"guest-memory".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(StartMicrovmError::MemoryFile(io::Error::last_os_error()));
+    }
+    let file = Arc::new(unsafe { File::from_raw_fd(fd) });
+
+    let mut offsets = Vec::with_capacity(regions.len());
+    let mut size = 0;
+    for &(_, len) in regions {
+        offsets.push(size);
+        size = (size + len as u64).next_multiple_of(page_size);
+    }
+    file.set_len(size).map_err(StartMicrovmError::MemoryFile)?;
+
+    let regions = regions
+        .iter()
+        .zip(offsets)
+        .map(|(&(addr, len), offset)| {
+            let region = MmapRegion::build(
+                Some(FileOffset::from_arc(file.clone(), offset)),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_NORESERVE,
+            )
+            .map_err(StartMicrovmError::MemoryFileMmap)?;
+            GuestRegionMmap::new(region, addr).map_err(StartMicrovmError::GuestMemoryMmap)
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    GuestMemoryMmap::from_regions(regions).map_err(StartMicrovmError::GuestMemoryMmap)
+}
+
+// Whether `start`+`size` overlaps any of `ranges`.
+fn overlaps(ranges: &[(u64, u64)], start: u64, size: u64) -> bool {
+    ranges
+        .iter()
+        .any(|&(s, sz)| start < s + sz && s < start + size)
+}
+
+/// Carves `reserved_memory` out of the guest memory regions, refusing ranges that overlap any of
+/// the `in_use` ones, which the VMM populates itself.
+fn reserve_guest_memory(
+    arch_mem_info: &mut ArchMemoryInfo,
+    arch_mem_regions: &[(GuestAddress, usize)],
+    reserved_memory: &[(u64, u64)],
+    in_use: &[(u64, u64)],
+) -> std::result::Result<Vec<(GuestAddress, usize)>, StartMicrovmError> {
+    for &(start, size) in reserved_memory {
+        if overlaps(in_use, start, size) {
+            return Err(StartMicrovmError::ReservedMemoryConflict(start, size));
+        }
+    }
+    Ok(arch::reserve_memory_ranges(
+        arch_mem_info,
+        arch_mem_regions,
+        reserved_memory,
+    ))
+}
+
+/// Returns where the VMM loads an initrd of `initrd_size` bytes in `guest_mem` and, on aarch64,
+/// the FDT. A placement that doesn't fit is left for the loaders to report.
+#[cfg(not(feature = "tee"))]
+fn boot_ranges(guest_mem: &GuestMemoryMmap, initrd_size: Option<usize>) -> Vec<(u64, u64)> {
+    let mut ranges = Vec::new();
+    if let Some(size) = initrd_size {
+        if let Ok(addr) = arch::initrd_load_addr(guest_mem, size) {
+            ranges.push((addr, size.next_multiple_of(arch::PAGE_SIZE) as u64));
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    ranges.push((
+        arch::aarch64::get_fdt_addr(guest_mem),
+        arch::aarch64::layout::FDT_MAX_SIZE as u64,
+    ));
+    ranges
+}
+
+/// Records the firmware `reserved_regions`, which must lie in the guest RAM `regions`, away from
+/// the `in_use` ranges and from each other.
+fn claim_firmware_regions(
+    arch_mem_info: &mut ArchMemoryInfo,
+    regions: &[(GuestAddress, usize)],
+    reserved_regions: &[(GuestAddress, u64)],
+    in_use: &[(u64, u64)],
+) -> std::result::Result<(), StartMicrovmError> {
+    let page_mask = arch::PAGE_SIZE as u64 - 1;
+    let mut firmware_ranges: Vec<(u64, u64)> = Vec::new();
+    for &(GuestAddress(start), size) in reserved_regions {
+        let in_ram = start.checked_add(size).is_some_and(|end| {
+            regions
+                .iter()
+                .any(|&(addr, len)| addr.0 <= start && end <= addr.0 + len as u64)
+        });
+        if size == 0
+            || (start | size) & page_mask != 0
+            || !in_ram
+            || overlaps(in_use, start, size)
+            || overlaps(&firmware_ranges, start, size)
+        {
+            return Err(StartMicrovmError::InvalidReservedRegion(start, size));
+        }
+        firmware_ranges.push((start, size));
+    }
+    firmware_ranges.sort_unstable();
+    arch_mem_info.firmware_ranges = firmware_ranges;
+
+    Ok(())
+}
 
-    let guest_mem = GuestMemoryMmap::from_ranges(&arch_mem_regions)
-        .map_err(StartMicrovmError::GuestMemoryMmap)?;
+/// Prefaults or zeroes the guest memory according to `mem_init`. The regions are split in
+/// chunks that are processed by as many threads as there are host CPUs.
+fn init_guest_memory(
+    guest_memory: &GuestMemoryMmap,
+    mem_init: MemoryInit,
+) -> std::result::Result<(), StartMicrovmError> {
+    // Chunks are a multiple of the page size, and big enough to keep the thread overhead low.
+    const CHUNK_SIZE: usize = 64 << 20;
 
-    let kernel_data = unsafe { std::slice::from_raw_parts(kernel_region.as_ptr(), kernel_size) };
-    guest_mem
-        .write(kernel_data, GuestAddress(kernel_load_addr))
-        .unwrap();
-    Ok((guest_mem, arch_mem_info))
-}
+    if mem_init == MemoryInit::Lazy {
+        return Ok(());
+    }
 
-#[cfg(all(target_arch = "aarch64", feature = "efi"))]
-pub fn create_guest_memory(
-    mem_size_mib: usize,
-) -> std::result::Result<(GuestMemoryMmap, ArchMemoryInfo), StartMicrovmError> {
-    let mem_size = mem_size_mib << 20;
-    let (arch_mem_info, arch_mem_regions) = arch::arch_memory_regions(mem_size);
+    let mut chunks = Vec::new();
+    for region in guest_memory.iter() {
+        let start = region.as_ptr() as usize;
+        let len = region.len() as usize;
+        chunks.extend(
+            (0..len)
+                .step_by(CHUNK_SIZE)
+                .map(|offset| (start + offset, std::cmp::min(CHUNK_SIZE, len - offset))),
+        );
+    }
+
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let per_thread = chunks.len().div_ceil(threads).max(1);
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = chunks
+            .chunks(per_thread)
+            .map(|chunks| {
+                scope.spawn(move || {
+                    chunks
+                        .iter()
+                        .try_for_each(|&(addr, len)| init_memory_chunk(addr, len, mem_init))
+                })
+            })
+            .collect();
 
-    let guest_mem = GuestMemoryMmap::from_ranges(&arch_mem_regions)
-        .map_err(StartMicrovmError::GuestMemoryMmap)?;
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("memory init thread panicked"))
+    })
+    .map_err(StartMicrovmError::GuestMemoryInit)
+}
 
-    guest_mem.write(EDK2_BINARY, GuestAddress(0u64)).unwrap();
-    Ok((guest_mem, arch_mem_info))
+fn init_memory_chunk(addr: usize, len: usize, mem_init: MemoryInit) -> io::Result<()> {
+    match mem_init {
+        MemoryInit::Lazy => Ok(()),
+        // Safe because the chunk lies within a guest memory mapping, which the guest can't
+        // be using yet.
+        MemoryInit::Zeroed => {
+            unsafe { std::ptr::write_bytes(addr as *mut u8, 0, len) };
+            Ok(())
+        }
+        MemoryInit::Prefault => {
+            #[cfg(target_os = "linux")]
+            {
+                // Safe because the chunk lies within a guest memory mapping, and populating it
+                // doesn't change its contents.
+                let ret = unsafe {
+                    libc::madvise(addr as *mut libc::c_void, len, libc::MADV_POPULATE_WRITE)
+                };
+                if ret == 0 {
+                    return Ok(());
+                }
+                let err = io::Error::last_os_error();
+                // Kernels older than 5.14 don't know about MADV_POPULATE_WRITE, touch each page
+                // instead.
+                if err.raw_os_error() != Some(libc::EINVAL) {
+                    return Err(err);
+                }
+            }
+
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            for offset in (0..len).step_by(page_size) {
+                let ptr = (addr + offset) as *mut u8;
+                // Safe because the page lies within a guest memory mapping, and writing back the
+                // value just read leaves it unchanged.
+                unsafe { ptr.write_volatile(ptr.read_volatile()) };
+            }
+            Ok(())
+        }
+    }
 }
 
 #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
@@ -811,6 +1919,22 @@ fn load_cmdline(vmm: &Vmm) -> std::result::Result<(), StartMicrovmError> {
     .map_err(StartMicrovmError::LoadCommandline)
 }
 
+#[cfg(not(feature = "tee"))]
+fn load_initrd(
+    guest_memory: &GuestMemoryMmap,
+    initrd: &[u8],
+) -> std::result::Result<InitrdConfig, StartMicrovmError> {
+    let address = arch::initrd_load_addr(guest_memory, initrd.len())
+        .map_err(|_| StartMicrovmError::InitrdLoad)?;
+    guest_memory
+        .write_slice(initrd, GuestAddress(address))
+        .map_err(|_| StartMicrovmError::InitrdLoad)?;
+    Ok(InitrdConfig {
+        address: GuestAddress(address),
+        size: initrd.len(),
+    })
+}
+
 #[cfg(all(target_os = "linux", not(feature = "tee")))]
 pub(crate) fn setup_vm(
     guest_memory: &GuestMemoryMmap,
@@ -919,8 +2043,10 @@ fn attach_legacy_devices(
         }};
     }
 
-    register_irqfd_evt!(com_evt_1_3, 4);
-    register_irqfd_evt!(com_evt_2_4, 3);
+    if pio_device_manager.serial_ports {
+        register_irqfd_evt!(com_evt_1_3, 4);
+        register_irqfd_evt!(com_evt_2_4, 3);
+    }
     register_irqfd_evt!(kbd_evt, 1);
     Ok(())
 }
@@ -931,6 +2057,7 @@ fn attach_legacy_devices(
     mmio_device_manager: &mut MMIODeviceManager,
     kernel_cmdline: &mut kernel::cmdline::Cmdline,
     serial: Option<Arc<Mutex<Serial>>>,
+    rtc_config: &RtcConfig,
 ) -> std::result::Result<(), StartMicrovmError> {
     if let Some(serial) = serial {
         mmio_device_manager
@@ -940,7 +2067,7 @@ fn attach_legacy_devices(
     }
 
     mmio_device_manager
-        .register_mmio_rtc(vm.fd())
+        .register_mmio_rtc(vm.fd(), rtc_config)
         .map_err(Error::RegisterMMIODevice)
         .map_err(StartMicrovmError::Internal)?;
 
@@ -956,6 +2083,7 @@ fn attach_legacy_devices(
     serial: Option<Arc<Mutex<Serial>>>,
     event_manager: &mut EventManager,
     shutdown_efd: Option<EventFd>,
+    rtc_config: &RtcConfig,
 ) -> std::result::Result<(), StartMicrovmError> {
     if let Some(serial) = serial {
         mmio_device_manager
@@ -965,7 +2093,7 @@ fn attach_legacy_devices(
     }
 
     mmio_device_manager
-        .register_mmio_rtc(vm, intc.clone())
+        .register_mmio_rtc(vm, intc.clone(), rtc_config)
         .map_err(Error::RegisterMMIODevice)
         .map_err(StartMicrovmError::Internal)?;
 
@@ -985,6 +2113,7 @@ fn attach_legacy_devices(
 }
 
 #[cfg(target_arch = "x86_64")]
+#[allow(clippy::too_many_arguments)]
 fn create_vcpus_x86_64(
     vm: &Vm,
     vcpu_config: &VcpuConfig,
@@ -993,8 +2122,11 @@ fn create_vcpus_x86_64(
     request_ts: TimestampUs,
     io_bus: &devices::Bus,
     exit_evt: &EventFd,
+    halt_exit_code: Option<u8>,
+    time_slice: Option<Duration>,
 ) -> super::Result<Vec<Vcpu>> {
     let mut vcpus = Vec::with_capacity(vcpu_config.vcpu_count as usize);
+    let scheduler = time_slice.map(|slice| VcpuScheduler::new(vcpu_config.vcpu_count, slice));
     for cpu_index in 0..vcpu_config.vcpu_count {
         let mut vcpu = Vcpu::new_x86_64(
             cpu_index,
@@ -1009,6 +2141,13 @@ fn create_vcpus_x86_64(
 
         vcpu.configure_x86_64(guest_mem, entry_addr, vcpu_config)
             .map_err(Error::Vcpu)?;
+        // APs parked with interrupts disabled (e.g. offlined CPUs) don't mean the guest is done.
+        if cpu_index == 0 {
+            vcpu.set_halt_exit_code(halt_exit_code);
+        }
+        if let Some(scheduler) = &scheduler {
+            vcpu.set_scheduler(scheduler.clone());
+        }
 
         vcpus.push(vcpu);
     }
@@ -1023,8 +2162,11 @@ fn create_vcpus_aarch64(
     entry_addr: GuestAddress,
     request_ts: TimestampUs,
     exit_evt: &EventFd,
+    time_slice: Option<Duration>,
+    readonly_faults: &[(u64, u64)],
 ) -> super::Result<Vec<Vcpu>> {
     let mut vcpus = Vec::with_capacity(vcpu_config.vcpu_count as usize);
+    let scheduler = time_slice.map(|slice| VcpuScheduler::new(vcpu_config.vcpu_count, slice));
     for cpu_index in 0..vcpu_config.vcpu_count {
         let mut vcpu = Vcpu::new_aarch64(
             cpu_index,
@@ -1034,8 +2176,15 @@ fn create_vcpus_aarch64(
         )
         .map_err(Error::Vcpu)?;
 
-        vcpu.configure_aarch64(vm.fd(), guest_mem, entry_addr)
+        vcpu.configure_aarch64(vm.fd(), guest_mem, entry_addr, vcpu_config)
             .map_err(Error::Vcpu)?;
+        if let Some(hypercalls) = vm.hypercalls() {
+            vcpu.set_hypercalls(hypercalls.clone());
+        }
+        vcpu.set_readonly_faults(readonly_faults.to_vec());
+        if let Some(scheduler) = &scheduler {
+            vcpu.set_scheduler(scheduler.clone());
+        }
 
         vcpus.push(vcpu);
     }
@@ -1084,12 +2233,52 @@ fn create_vcpus_aarch64(
     Ok(vcpus)
 }
 
+/// Maps the read-only regions in the guest, and registers the bus handlers taking the guest
+/// writes to them when they aren't made to fault.
+#[cfg(target_os = "linux")]
+fn map_readonly_regions(
+    vm: &mut Vm,
+    guest_memory: &GuestMemoryMmap,
+    mmio_device_manager: &mut MMIODeviceManager,
+    regions: &[ReadOnlyRegionConfig],
+) -> std::result::Result<(), StartMicrovmError> {
+    for region in regions {
+        let (start, size) = (region.guest_addr, region.size());
+        let overlaps_memory = guest_memory
+            .iter()
+            .any(|r| r.start_addr().0 < start.saturating_add(size) && start <= r.last_addr().0);
+        if overlaps_memory {
+            return Err(StartMicrovmError::ReadOnlyRegionConflict(start, size));
+        }
+
+        vm.map_readonly_memory(GuestAddress(start), size as usize, &region.contents)
+            .map_err(Error::Vm)
+            .map_err(StartMicrovmError::Internal)?;
+        if !matches!(region.policy, ReadOnlyWritePolicy::FaultToGuest) {
+            mmio_device_manager
+                .register_mmio_handler(
+                    start,
+                    size,
+                    Arc::new(ReadOnlyWriteHandler::new(region.policy.clone())),
+                )
+                .map_err(Error::RegisterMMIODevice)
+                .map_err(StartMicrovmError::Internal)?;
+        }
+    }
+    Ok(())
+}
+
 /// Attaches an MmioTransport device to the device manager.
 fn attach_mmio_device(
     vmm: &mut Vmm,
     id: String,
-    device: MmioTransport,
+    mut device: MmioTransport,
 ) -> std::result::Result<(), device_manager::mmio::Error> {
+    device.set_memory_reclaimer(vmm.memory_reclaimer.clone());
+    #[cfg(target_os = "linux")]
+    if let Some(runner) = &vmm.unfiltered_runner {
+        device.set_unfiltered_runner(runner.clone());
+    }
     let type_id = device
         .device()
         .lock()
@@ -1113,26 +2302,80 @@ fn attach_mmio_device(
     Ok(())
 }
 
+#[cfg(not(feature = "tee"))]
+/// Granularity of the virtio-fs DAX windows, the guest maps the shared files in ranges this
+/// large.
+pub const FS_DAX_WINDOW_ALIGN: usize = 2 << 20;
+
+// Carves the DAX windows of the fs devices, given by tag and size, out of the end of the shared
+// memory region, leaving the rest of it to the GPU.
+#[cfg(not(feature = "tee"))]
+fn split_shm_region(
+    shm_region: VirtioShmRegion,
+    fs_windows: &[(String, Option<usize>)],
+) -> std::result::Result<(VirtioShmRegion, Vec<Option<VirtioShmRegion>>), StartMicrovmError> {
+    let mut remaining = shm_region.size;
+    let mut fs_regions = Vec::with_capacity(fs_windows.len());
+    for (tag, size) in fs_windows {
+        let Some(size) = *size else {
+            fs_regions.push(None);
+            continue;
+        };
+        if size == 0 || !size.is_multiple_of(FS_DAX_WINDOW_ALIGN) || size > remaining {
+            return Err(StartMicrovmError::FsDaxWindow(tag.clone()));
+        }
+        remaining -= size;
+        fs_regions.push(Some(VirtioShmRegion {
+            host_addr: shm_region.host_addr + remaining as u64,
+            guest_addr: shm_region.guest_addr + remaining as u64,
+            size,
+        }));
+    }
+
+    Ok((
+        VirtioShmRegion {
+            size: remaining,
+            ..shm_region
+        },
+        fs_regions,
+    ))
+}
+
 #[cfg(not(feature = "tee"))]
 fn attach_fs_devices(
     vmm: &mut Vmm,
     fs_devs: &FsBuilder,
-    shm_region: Option<VirtioShmRegion>,
+    shm_regions: Vec<Option<VirtioShmRegion>>,
     intc: Option<Arc<Mutex<Gic>>>,
+    #[cfg(target_os = "linux")] seccomp_mode: SeccompMode,
+    #[cfg(target_os = "macos")] map_sender: Sender<MemoryMapping>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
-    for (i, fs) in fs_devs.list.iter().enumerate() {
+    #[cfg(target_os = "linux")]
+    let seccomp_filter = crate::linux::seccomp::fs_filter(seccomp_mode).map_err(SeccompFilter)?;
+
+    for (i, (fs, shm_region)) in fs_devs.list.iter().zip(shm_regions).enumerate() {
         let id = format!("{}{}", String::from(fs.lock().unwrap().id()), i);
 
+        #[cfg(target_os = "linux")]
+        fs.lock()
+            .unwrap()
+            .set_seccomp_filter(seccomp_filter.clone());
+
         if let Some(ref intc) = intc {
             fs.lock().unwrap().set_intc(intc.clone());
         }
 
-        if let Some(ref shm) = shm_region {
-            fs.lock().unwrap().set_shm_region(shm.clone());
+        if let Some(shm) = shm_region {
+            fs.lock().unwrap().set_shm_region(shm);
+            #[cfg(target_os = "macos")]
+            fs.lock().unwrap().set_map_sender(map_sender.clone());
         }
 
+        vmm.quiesce_observers.push(fs.clone());
+        vmm.fs_devices.push(fs.clone());
+
         // The device mutex mustn't be locked here otherwise it will deadlock.
         attach_mmio_device(
             vmm,
@@ -1150,10 +2393,11 @@ fn attach_console_devices(
     event_manager: &mut EventManager,
     intc: Option<Arc<Mutex<Gic>>>,
     console_output: Option<PathBuf>,
+    channel_capacity: Option<usize>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
-    let ports = if let Some(console_output) = console_output {
+    let mut ports = if let Some(console_output) = console_output {
         let file = File::create(console_output.as_path()).map_err(OpenConsoleFile)?;
         vec![PortDescription::Console {
             input: Some(port_io::input_empty().unwrap()),
@@ -1217,9 +2461,21 @@ fn attach_console_devices(
         ports
     };
 
+    if let Some(capacity) = channel_capacity {
+        let channel = devices::virtio::ConsoleChannel::new(capacity)
+            .map_err(|e| Internal(Error::EventFd(e)))?;
+        ports.push(PortDescription::DuplexPipe {
+            name: "krun-channel".into(),
+            input: channel.input(),
+            output: channel.output(),
+        });
+        vmm.console_channel = Some(channel);
+    }
+
     let console = Arc::new(Mutex::new(devices::virtio::Console::new(ports).unwrap()));
 
     vmm.exit_observers.push(console.clone());
+    vmm.quiesce_observers.push(console.clone());
 
     if let Some(intc) = intc {
         console.lock().unwrap().set_intc(intc);
@@ -1263,6 +2519,8 @@ fn attach_net_devices<'a>(
             MmioTransport::new(vmm.guest_memory.clone(), net_device.clone()),
         )
         .map_err(StartMicrovmError::RegisterNetDevice)?;
+        vmm.quiesce_observers.push(net_device.clone());
+        vmm.net_devices.push(net_device.clone());
     }
     Ok(())
 }
@@ -1301,10 +2559,17 @@ fn attach_balloon_device(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
     intc: Option<Arc<Mutex<Gic>>>,
+    config: &BalloonConfig,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
     let balloon = Arc::new(Mutex::new(devices::virtio::Balloon::new().unwrap()));
+    // Checked by `VmResources::set_balloon_config`.
+    balloon
+        .lock()
+        .unwrap()
+        .set_target(config.target_pages as u32);
+    vmm.balloon_device = Some(balloon.clone());
 
     event_manager
         .add_subscriber(balloon.clone())
@@ -1315,6 +2580,10 @@ fn attach_balloon_device(
     if let Some(intc) = intc {
         balloon.lock().unwrap().set_intc(intc);
     }
+    balloon
+        .lock()
+        .unwrap()
+        .set_memory_reclaimer(vmm.memory_reclaimer.clone());
 
     // The device mutex mustn't be locked here otherwise it will deadlock.
     attach_mmio_device(
@@ -1349,6 +2618,7 @@ fn attach_block_devices(
             MmioTransport::new(vmm.guest_memory().clone(), block.clone()),
         )
         .map_err(RegisterBlockDevice)?;
+        vmm.quiesce_observers.push(block.clone());
     }
 
     Ok(())
@@ -1359,10 +2629,12 @@ fn attach_rng_device(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
     intc: Option<Arc<Mutex<Gic>>>,
+    config: &RngConfig,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
     let rng = Arc::new(Mutex::new(devices::virtio::Rng::new().unwrap()));
+    rng.lock().unwrap().set_rate_limit(config.rate_limit);
 
     event_manager
         .add_subscriber(rng.clone())
@@ -1443,9 +2715,354 @@ fn attach_snd_device(
     Ok(())
 }
 
+fn attach_mmio_handlers(
+    vmm: &mut Vmm,
+    handlers: &[(u64, u64, Arc<dyn devices::BusAccessHandler>)],
+) -> std::result::Result<(), StartMicrovmError> {
+    for (base, len, handler) in handlers {
+        // Accesses to guest memory never exit to the VMM, so the handler would never be called.
+        let overlaps_memory = vmm.guest_memory().iter().any(|region| {
+            region.start_addr().0 < base.saturating_add(*len) && *base <= region.last_addr().0
+        });
+        if overlaps_memory {
+            return Err(StartMicrovmError::Internal(Error::RegisterMMIODevice(
+                device_manager::mmio::Error::BusError(devices::BusError::Overlap),
+            )));
+        }
+
+        vmm.mmio_device_manager
+            .register_mmio_handler(*base, *len, handler.clone())
+            .map_err(Error::RegisterMMIODevice)
+            .map_err(StartMicrovmError::Internal)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use vm_memory::Bytes;
+
+    /// Port the test guest writes its counter to, see `test_guest_resources`.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) const TEST_GUEST_PORT: u64 = 0x600;
+    /// Guest address of the counter of the test guest, see `test_guest_resources`.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) const TEST_GUEST_COUNTER: u64 = TEST_GUEST_ENTRY + 0x800;
+    /// Guest address the test guest starts at, see `test_guest_resources`.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) const TEST_GUEST_ENTRY: u64 = 0x100_0000;
+
+    /// Records the values the test guest writes to `TEST_GUEST_PORT`.
+    #[cfg(target_arch = "x86_64")]
+    #[derive(Default)]
+    pub(crate) struct PortRecorder {
+        pub first: Mutex<Option<u32>>,
+        pub last: Mutex<Option<u32>>,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    impl devices::BusAccessHandler for PortRecorder {
+        fn read(&self, _vcpuid: u64, _addr: u64, _data: &mut [u8]) {}
+
+        fn write(&self, _vcpuid: u64, _addr: u64, data: &[u8]) {
+            let Ok(value) = data.try_into().map(u32::from_le_bytes) else {
+                return;
+            };
+            self.first.lock().unwrap().get_or_insert(value);
+            *self.last.lock().unwrap() = Some(value);
+        }
+    }
+
+    /// Returns the resources of a microVM with a single vCPU running, in place of a kernel, a loop
+    /// incrementing the `u64` at `TEST_GUEST_COUNTER` and writing its low half to
+    /// `TEST_GUEST_PORT`, where `recorder` gets it. The console output goes to `console_output`.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn test_guest_resources(
+        recorder: Arc<PortRecorder>,
+        console_output: &Path,
+    ) -> crate::resources::VmResources {
+        use crate::vmm_config::kernel_bundle::KernelBundle;
+        use crate::vmm_config::machine_config::VmConfig;
+
+        #[rustfmt::skip]
+        let code = [
+            0xbb, 0x00, 0x08, 0x00, 0x01, // mov ebx, TEST_GUEST_COUNTER
+            0x48, 0xff, 0x03, // 1: inc qword ptr [rbx]
+            0x8b, 0x03, // mov eax, dword ptr [rbx]
+            0x66, 0xba, 0x00, 0x06, // mov dx, TEST_GUEST_PORT
+            0xef, // out dx, eax
+            0xeb, 0xf4, // jmp 1b
+        ];
+        let size = 0x1000;
+        // The bundle is mapped in the guest for as long as the microVM exists, so it's leaked.
+        let bundle = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(bundle, libc::MAP_FAILED);
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), bundle as *mut u8, code.len()) };
+
+        let mut vm_resources = crate::resources::VmResources::default();
+        vm_resources
+            .set_vm_config(&VmConfig {
+                vcpu_count: Some(1),
+                mem_size_mib: Some(64),
+                ..Default::default()
+            })
+            .unwrap();
+        vm_resources
+            .set_kernel_bundle(KernelBundle {
+                host_addr: bundle as u64,
+                guest_addr: TEST_GUEST_ENTRY,
+                entry_addr: TEST_GUEST_ENTRY,
+                size,
+            })
+            .unwrap();
+        vm_resources.set_console_output(console_output.to_path_buf());
+        vm_resources.add_pio_handler(TEST_GUEST_PORT, 4, recorder);
+        vm_resources
+    }
+
+    /// Waits up to a few seconds for `cond` to hold, returning whether it did.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn wait_until(mut cond: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cond() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_kernel_cmdline_too_large() {
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let recorder = Arc::new(PortRecorder::default());
+        let mut vm_resources = test_guest_resources(recorder, &dir.as_path().join("console"));
+        vm_resources.boot_config.kernel_cmdline_prolog = Some("a".repeat(KERNEL_CMDLINE_LIMIT));
+        let mut event_manager = EventManager::new().unwrap();
+        assert!(matches!(
+            build_microvm(&vm_resources, &mut event_manager, None),
+            Err(StartMicrovmError::Internal(Error::KernelCmdlineTooLarge(
+                len,
+                KERNEL_CMDLINE_LIMIT
+            ))) if len > KERNEL_CMDLINE_LIMIT
+        ));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_guest_memory_fd() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let recorder = Arc::new(PortRecorder::default());
+        let mut vm_resources =
+            test_guest_resources(recorder.clone(), &dir.as_path().join("console"));
+        vm_resources.memory_backend = MemoryBackend::Memfd;
+        let mut event_manager = EventManager::new().unwrap();
+        let vmm = build_microvm(&vm_resources, &mut event_manager, None).unwrap();
+        assert!(wait_until(|| recorder.last.lock().unwrap().is_some()));
+
+        // What's written to the guest memory can be read from the memfd.
+        let addr = arch::x86_64::layout::HIMEM_START;
+        let mut vmm = vmm.lock().unwrap();
+        vmm.guest_memory()
+            .write_obj(0x1234_5678u32, GuestAddress(addr))
+            .unwrap();
+        let (fd, regions) = vmm.guest_memory_fd().unwrap();
+        let region = regions
+            .iter()
+            .find(|r| r.guest_addr.0 <= addr && addr < r.guest_addr.0 + r.len)
+            .unwrap();
+        let mut value = [0u8; 4];
+        File::from(fd.try_clone_to_owned().unwrap())
+            .read_exact_at(&mut value, region.file_offset + addr - region.guest_addr.0)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(value), 0x1234_5678);
+        vmm.teardown().unwrap();
+        drop(vmm);
+
+        vm_resources.memory_backend = MemoryBackend::Anonymous;
+        let vmm = build_microvm(&vm_resources, &mut event_manager, None).unwrap();
+        assert!(vmm.lock().unwrap().guest_memory_fd().is_none());
+        vmm.lock().unwrap().teardown().unwrap();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_events_observer() {
+        #[derive(Default)]
+        struct Recorder {
+            events: Vec<&'static str>,
+            fail_boot: bool,
+        }
+
+        impl crate::VmmEventsObserver for Recorder {
+            fn on_vmm_boot(&mut self) -> std::result::Result<(), utils::errno::Error> {
+                self.events.push("boot");
+                if self.fail_boot {
+                    return Err(utils::errno::Error::new(libc::EPERM));
+                }
+                Ok(())
+            }
+            fn on_vmm_stop(&mut self) -> std::result::Result<(), utils::errno::Error> {
+                self.events.push("stop");
+                Ok(())
+            }
+            fn on_vcpus_ready(&mut self) {
+                self.events.push("vcpus_ready");
+            }
+        }
+
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let recorder = Arc::new(PortRecorder::default());
+        let mut vm_resources =
+            test_guest_resources(recorder.clone(), &dir.as_path().join("console"));
+        let observer = Arc::new(Mutex::new(Recorder::default()));
+        vm_resources.set_events_observer(observer.clone());
+        let mut event_manager = EventManager::new().unwrap();
+        let vmm = build_microvm(&vm_resources, &mut event_manager, None).unwrap();
+        assert!(wait_until(|| recorder.last.lock().unwrap().is_some()));
+        vmm.lock().unwrap().teardown().unwrap();
+        // Only stopped once.
+        vmm.lock().unwrap().stop(0);
+        assert_eq!(
+            std::mem::take(&mut observer.lock().unwrap().events),
+            ["boot", "vcpus_ready", "stop"]
+        );
+
+        // A failing boot hook keeps the vCPUs from starting.
+        observer.lock().unwrap().fail_boot = true;
+        assert!(matches!(
+            build_microvm(&vm_resources, &mut event_manager, None),
+            Err(StartMicrovmError::Internal(Error::VmmObserverInit(_)))
+        ));
+        assert_eq!(observer.lock().unwrap().events, ["boot"]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_guest_panic() {
+        #[derive(Default)]
+        struct PanicRecorder(Vec<crate::GuestPanic>);
+
+        impl crate::VmmEventsObserver for PanicRecorder {
+            fn on_guest_panic(&mut self, reason: crate::GuestPanic) {
+                self.0.push(reason);
+            }
+        }
+
+        // x86_64 guests can't find the pvpanic device, so the events it would have raised are
+        // reported directly.
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let recorder = Arc::new(PortRecorder::default());
+        let mut vm_resources = test_guest_resources(recorder, &dir.as_path().join("console"));
+        let observer = Arc::new(Mutex::new(PanicRecorder::default()));
+        vm_resources.set_events_observer(observer.clone());
+        let mut event_manager = EventManager::new().unwrap();
+        let vmm = build_microvm(&vm_resources, &mut event_manager, None).unwrap();
+        let mut vmm = vmm.lock().unwrap();
+        vmm.exit_on_stop = false;
+
+        vmm.report_guest_panic(0, true);
+        vmm.report_guest_panic(devices::legacy::PVPANIC_PANICKED, false);
+        assert_eq!(vmm.exit_code, None);
+
+        vmm.report_guest_panic(
+            devices::legacy::PVPANIC_PANICKED | devices::legacy::PVPANIC_CRASH_LOADED,
+            true,
+        );
+        assert_eq!(
+            vmm.exit_code,
+            Some(i32::from(crate::FC_EXIT_CODE_GENERIC_ERROR))
+        );
+        assert_eq!(
+            observer.lock().unwrap().0,
+            [
+                crate::GuestPanic {
+                    panicked: true,
+                    crash_loaded: false,
+                },
+                crate::GuestPanic {
+                    panicked: true,
+                    crash_loaded: true,
+                },
+            ]
+        );
+        vmm.teardown().unwrap();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_pio_handler_conflict() {
+        // The legacy devices keep their ports, the handler overlapping the CMOS is refused.
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let recorder = Arc::new(PortRecorder::default());
+        let mut vm_resources =
+            test_guest_resources(recorder.clone(), &dir.as_path().join("console"));
+        vm_resources.add_pio_handler(0x71, 1, recorder);
+        let mut event_manager = EventManager::new().unwrap();
+        assert!(matches!(
+            build_microvm(&vm_resources, &mut event_manager, None),
+            Err(StartMicrovmError::PioHandlerConflict(0x71, 1))
+        ));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_seccomp_kill() {
+        // A system call missing from the filter of the vCPU thread kills the test process.
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let recorder = Arc::new(PortRecorder::default());
+        let mut vm_resources =
+            test_guest_resources(recorder.clone(), &dir.as_path().join("console"));
+        vm_resources.set_seccomp_mode(SeccompMode::Kill);
+        let mut event_manager = EventManager::new().unwrap();
+        let vmm = build_microvm(&vm_resources, &mut event_manager, None).unwrap();
+        assert!(wait_until(|| recorder.last.lock().unwrap().is_some()));
+
+        // Pausing and resuming go through the signal handler of the vCPU thread.
+        vmm.lock().unwrap().pause_vcpus().unwrap();
+        let paused = *recorder.last.lock().unwrap();
+        vmm.lock().unwrap().resume_vcpus().unwrap();
+        assert!(wait_until(|| *recorder.last.lock().unwrap() != paused));
+        vmm.lock().unwrap().teardown().unwrap();
+
+        // A denied one exits with FC_EXIT_CODE_BAD_SYSCALL, through the SIGSYS handler.
+        let filter = crate::linux::seccomp::vcpu_filter(SeccompMode::Kill)
+            .unwrap()
+            .unwrap();
+        // Safe because the child only makes system calls before exiting.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            if register_sigsys_handler().is_err() || filter.apply().is_err() {
+                unsafe { libc::_exit(2) };
+            }
+            unsafe {
+                libc::syscall(libc::SYS_socket, libc::AF_UNIX, libc::SOCK_STREAM, 0);
+                libc::_exit(0);
+            }
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(
+            libc::WEXITSTATUS(status),
+            i32::from(crate::FC_EXIT_CODE_BAD_SYSCALL)
+        );
+    }
 
     fn default_guest_memory(
         mem_size_mib: usize,
@@ -1458,7 +3075,53 @@ pub mod tests {
             MmapRegion::build_raw(kernel_host_addr as *mut _, kernel_size, 0, 0).unwrap()
         };
 
-        create_guest_memory(mem_size_mib, kernel_region, kernel_guest_addr, kernel_size)
+        create_guest_memory(
+            mem_size_mib,
+            MemoryInit::Lazy,
+            &MemoryBackend::Anonymous,
+            &[],
+            &[],
+            kernel_region,
+            kernel_guest_addr,
+            kernel_size,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_init_guest_memory() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x100000), 0x4000),
+        ])
+        .unwrap();
+        guest_memory
+            .write_obj(0xaau8, GuestAddress(0x8000))
+            .unwrap();
+        guest_memory
+            .write_obj(0x55u8, GuestAddress(0x101000))
+            .unwrap();
+
+        // Prefaulting leaves the contents alone.
+        init_guest_memory(&guest_memory, MemoryInit::Prefault).unwrap();
+        assert_eq!(
+            guest_memory.read_obj::<u8>(GuestAddress(0x8000)).unwrap(),
+            0xaa
+        );
+        assert_eq!(
+            guest_memory.read_obj::<u8>(GuestAddress(0x101000)).unwrap(),
+            0x55
+        );
+
+        init_guest_memory(&guest_memory, MemoryInit::Zeroed).unwrap();
+        assert_eq!(
+            guest_memory.read_obj::<u8>(GuestAddress(0x8000)).unwrap(),
+            0
+        );
+        assert_eq!(
+            guest_memory.read_obj::<u8>(GuestAddress(0x101000)).unwrap(),
+            0
+        );
     }
 
     #[test]
@@ -1473,6 +3136,10 @@ pub mod tests {
             vcpu_count,
             ht_enabled: false,
             cpu_template: None,
+            cpuid_overrides: Vec::new(),
+            nested: false,
+            tsc_khz: None,
+            cpu_topology: None,
         };
 
         // Dummy entry_addr, vcpus will not boot.
@@ -1486,6 +3153,8 @@ pub mod tests {
             TimestampUs::default(),
             &bus,
             &EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+            None,
+            None,
         )
         .unwrap();
         assert_eq!(vcpu_vec.len(), vcpu_count as usize);
@@ -1502,6 +3171,10 @@ pub mod tests {
             vcpu_count,
             ht_enabled: false,
             cpu_template: None,
+            #[cfg(target_os = "linux")]
+            pmu: false,
+            #[cfg(target_os = "linux")]
+            sve: false,
         };
 
         // Dummy entry_addr, vcpus will not boot.
@@ -1513,6 +3186,7 @@ pub mod tests {
             entry_addr,
             TimestampUs::default(),
             &EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+            None,
         )
         .unwrap();
         assert_eq!(vcpu_vec.len(), vcpu_count as usize);
@@ -1573,6 +3247,417 @@ pub mod tests {
             io::Error::from_raw_os_error(0),
         ));
         let _ = format!("{}{:?}", err, err);
+
+        #[cfg(target_os = "linux")]
+        {
+            let err = ReserveHotplugSlots(device_manager::mmio::Error::IrqsExhausted);
+            let _ = format!("{}{:?}", err, err);
+        }
+
+        let err = ReservedMemoryConflict(0, 0x1000);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidReservedRegion(0, 0x1000);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = ReadOnlyRegionConflict(0, 0x1000);
+        let _ = format!("{}{:?}", err, err);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let err = PioHandlerConflict(0x70, 2);
+            let _ = format!("{}{:?}", err, err);
+        }
+
+        let err = SerialCaptureWithoutSerial;
+        let _ = format!("{}{:?}", err, err);
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+    #[test]
+    fn test_reserved_memory_conflict() {
+        // The zero page is written by the VMM, the range can't be left out of RAM.
+        let kernel_region =
+            unsafe { MmapRegion::build_raw(0x1000 as *mut _, 0x1000, 0, 0).unwrap() };
+        assert!(matches!(
+            create_guest_memory(
+                128,
+                MemoryInit::Lazy,
+                &MemoryBackend::Anonymous,
+                &[(arch::x86_64::layout::ZERO_PAGE_START, 0x1000)],
+                &[],
+                kernel_region,
+                0x20_0000,
+                0x1000,
+                None,
+            ),
+            Err(StartMicrovmError::ReservedMemoryConflict(0x7000, 0x1000))
+        ));
+    }
+
+    #[cfg(not(any(feature = "tee", feature = "efi")))]
+    #[test]
+    fn test_firmware_regions_boot_conflict() {
+        const INITRD_SIZE: usize = 0x1800;
+        const KERNEL_SIZE: usize = 0x1000;
+
+        let create = |reserved_regions: &[(GuestAddress, u64)]| {
+            create_guest_memory(
+                128,
+                MemoryInit::Lazy,
+                &MemoryBackend::Anonymous,
+                &[],
+                reserved_regions,
+                MmapRegion::new(KERNEL_SIZE).unwrap(),
+                arch::get_kernel_start() + 0x10_0000,
+                KERNEL_SIZE,
+                Some(INITRD_SIZE),
+            )
+        };
+
+        let (guest_mem, _) = create(&[]).unwrap();
+        let initrd_addr = arch::initrd_load_addr(&guest_mem, INITRD_SIZE).unwrap();
+        let placements = [
+            // The last page of the initrd, which only partly fills it.
+            initrd_addr + 0x1000,
+            #[cfg(target_arch = "aarch64")]
+            arch::aarch64::get_fdt_addr(&guest_mem),
+        ];
+        for start in placements {
+            assert!(matches!(
+                create(&[(GuestAddress(start), 0x1000)]),
+                Err(StartMicrovmError::InvalidReservedRegion(s, 0x1000)) if s == start
+            ));
+        }
+        // The page right below the initrd is free.
+        let (_, info) = create(&[(GuestAddress(initrd_addr - 0x1000), 0x1000)]).unwrap();
+        assert_eq!(info.firmware_ranges, [(initrd_addr - 0x1000, 0x1000)]);
+    }
+
+    #[test]
+    fn test_reserve_firmware_regions() {
+        let regions = [
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(0x20_0000), 0x10_0000),
+        ];
+        let in_use = [(0x20_0000, 0x1000)];
+        let reserve = |reserved_regions: &[(GuestAddress, u64)]| {
+            let mut info = ArchMemoryInfo::default();
+            let regions =
+                reserve_guest_memory(&mut info, &regions, &[(0x8_0000, 0x1000)], &in_use)?;
+            claim_firmware_regions(&mut info, &regions, reserved_regions, &in_use)
+                .map(|_| info.firmware_ranges)
+        };
+
+        assert_eq!(
+            reserve(&[
+                (GuestAddress(0x28_0000), 0x2000),
+                (GuestAddress(0x1000), 0x1000)
+            ])
+            .unwrap(),
+            [(0x1000, 0x1000), (0x28_0000, 0x2000)]
+        );
+        for invalid in [
+            // Not page aligned.
+            (GuestAddress(0x1000), 0x800),
+            // Outside guest RAM.
+            (GuestAddress(0x10_0000), 0x1000),
+            (GuestAddress(0xf_f000), 0x2000),
+            // Over a reserved memory hole.
+            (GuestAddress(0x8_0000), 0x1000),
+            // Over the kernel.
+            (GuestAddress(0x20_0000), 0x1000),
+        ] {
+            assert!(matches!(
+                reserve(&[invalid]),
+                Err(StartMicrovmError::InvalidReservedRegion(..))
+            ));
+        }
+        assert!(matches!(
+            reserve(&[
+                (GuestAddress(0x1000), 0x2000),
+                (GuestAddress(0x2000), 0x1000)
+            ]),
+            Err(StartMicrovmError::InvalidReservedRegion(0x2000, 0x1000))
+        ));
+    }
+
+    #[test]
+    fn test_map_file_backed_guest_memory() {
+        use std::io::{Read, Seek, SeekFrom};
+        use utils::tempfile::TempFile;
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let regions = [
+            (GuestAddress(0), page_size),
+            (GuestAddress(0x100000), 2 * page_size),
+        ];
+        let file = TempFile::new().unwrap();
+        let backend = |shared| MemoryBackend::FileBacked {
+            path: file.as_path().to_path_buf(),
+            offset: page_size as u64,
+            shared,
+        };
+
+        // A private file must be large enough, and is left alone.
+        let err = map_guest_memory(&regions, &backend(false), MemoryInit::Lazy).unwrap_err();
+        assert!(matches!(err, StartMicrovmError::MemoryFileMmap(_)));
+        file.as_file().set_len(4 * page_size as u64).unwrap();
+        let guest_memory = map_guest_memory(&regions, &backend(false), MemoryInit::Lazy).unwrap();
+        guest_memory
+            .write_obj(0xaau8, GuestAddress(0x100000))
+            .unwrap();
+        drop(guest_memory);
+        let mut contents = Vec::new();
+        file.as_file().read_to_end(&mut contents).unwrap();
+        assert!(contents.iter().all(|b| *b == 0));
+
+        // Regions are laid out back to back from the offset, and writes to a shared file persist.
+        file.as_file().set_len(0).unwrap();
+        let guest_memory = map_guest_memory(&regions, &backend(true), MemoryInit::Lazy).unwrap();
+        assert_eq!(
+            file.as_file().metadata().unwrap().len(),
+            4 * page_size as u64
+        );
+        guest_memory
+            .write_obj(0x55u8, GuestAddress(0x100001))
+            .unwrap();
+        drop(guest_memory);
+        let mut byte = [0u8];
+        let mut contents = file.as_file();
+        contents
+            .seek(SeekFrom::Start(2 * page_size as u64 + 1))
+            .unwrap();
+        contents.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], 0x55);
+
+        // A missing shared file is created, a private one is required.
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let missing = |shared| MemoryBackend::FileBacked {
+            path: dir.as_path().join("memory"),
+            offset: 0,
+            shared,
+        };
+        let err = map_guest_memory(&regions, &missing(false), MemoryInit::Lazy).unwrap_err();
+        assert!(matches!(err, StartMicrovmError::MemoryFile(_)));
+        map_guest_memory(&regions, &missing(true), MemoryInit::Lazy).unwrap();
+        assert_eq!(
+            std::fs::metadata(dir.as_path().join("memory"))
+                .unwrap()
+                .len(),
+            3 * page_size as u64
+        );
+
+        let err = map_guest_memory(&regions, &backend(true), MemoryInit::Zeroed).unwrap_err();
+        assert!(matches!(
+            err,
+            StartMicrovmError::MemoryFileInit(MemoryInit::Zeroed)
+        ));
+
+        // The offset must be page aligned, and nothing is created when it isn't.
+        let unaligned = MemoryBackend::FileBacked {
+            path: dir.as_path().join("unaligned"),
+            offset: 0x10,
+            shared: true,
+        };
+        let err = map_guest_memory(&regions, &unaligned, MemoryInit::Lazy).unwrap_err();
+        assert!(matches!(err, StartMicrovmError::MemoryFileOffset(0x10)));
+        assert!(!dir.as_path().join("unaligned").exists());
+    }
+
+    #[test]
+    fn test_map_memfd_guest_memory() {
+        use std::os::unix::fs::FileExt;
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let regions = [
+            (GuestAddress(0), page_size + 1),
+            (GuestAddress(0x100000), page_size),
+        ];
+        let guest_memory =
+            map_guest_memory(&regions, &MemoryBackend::Memfd, MemoryInit::Zeroed).unwrap();
+        guest_memory
+            .write_obj(0x55u8, GuestAddress(0x100001))
+            .unwrap();
+
+        // Both regions share the memfd, the second one starting on the next page boundary.
+        let file_offset = guest_memory
+            .find_region(GuestAddress(0x100000))
+            .unwrap()
+            .file_offset()
+            .unwrap();
+        assert_eq!(file_offset.start(), 2 * page_size as u64);
+        let mut byte = [0u8];
+        file_offset
+            .file()
+            .read_exact_at(&mut byte, 2 * page_size as u64 + 1)
+            .unwrap();
+        assert_eq!(byte[0], 0x55);
+    }
+
+    #[test]
+    fn test_map_huge_pages_guest_memory() {
+        let huge_page = HugePageSize::Size2M.size();
+        let regions = [
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 2 * huge_page),
+        ];
+        match map_guest_memory(
+            &regions,
+            &MemoryBackend::HugePages(HugePageSize::Size2M),
+            MemoryInit::Lazy,
+        ) {
+            // The unaligned head and tail of the second region are split off.
+            Ok(guest_memory) => {
+                let ranges: Vec<_> = guest_memory
+                    .iter()
+                    .map(|region| (region.start_addr().0, region.len()))
+                    .collect();
+                assert_eq!(
+                    ranges,
+                    [
+                        (0, 0x1000),
+                        (0x1000, huge_page as u64 - 0x1000),
+                        (huge_page as u64, huge_page as u64),
+                        (2 * huge_page as u64, 0x1000),
+                    ]
+                );
+            }
+            // The host has no huge pages to spare.
+            Err(err) => assert!(matches!(
+                err,
+                StartMicrovmError::HugePages(HugePageSize::Size2M, _)
+            )),
+        }
+    }
+
+    #[test]
+    fn test_inspect_kernel() {
+        use std::io::Write;
+        use utils::tempfile::TempFile;
+
+        let err = inspect_kernel(Path::new("/nonexistent/vmlinux")).unwrap_err();
+        assert!(matches!(err, Error::KernelFile(_)));
+
+        let image = TempFile::new().unwrap();
+        image.as_file().write_all(&[0u8; 64]).unwrap();
+        let err = inspect_kernel(image.as_path()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::KernelLoader(kernel::loader::Error::UnknownKernelFormat)
+        ));
+    }
+
+    #[test]
+    fn test_validate_microvm() {
+        use crate::resources::VmResources;
+        use crate::vmm_config::kernel_bundle::KernelBundle;
+        use crate::vmm_config::machine_config::VmConfig;
+
+        struct Dummy;
+        impl devices::BusAccessHandler for Dummy {
+            fn read(&self, _vcpuid: u64, _addr: u64, _data: &mut [u8]) {}
+            fn write(&self, _vcpuid: u64, _addr: u64, _data: &[u8]) {}
+        }
+        let set_mem_size = |vm_resources: &mut VmResources, mem_size_mib| {
+            vm_resources
+                .set_vm_config(&VmConfig {
+                    mem_size_mib: Some(mem_size_mib),
+                    ..Default::default()
+                })
+                .unwrap()
+        };
+
+        let mut vm_resources = VmResources::default();
+        vm_resources.add_mmio_handler(0x40_0000_0000, 0x1000, Arc::new(Dummy));
+        vm_resources.add_mmio_handler(0x40_0000_0800, 0x1000, Arc::new(Dummy));
+        vm_resources.boot_config.kernel_cmdline_args.extra =
+            vec!["a".repeat(arch::CMDLINE_MAX_SIZE)];
+        set_mem_size(&mut vm_resources, 128);
+
+        let errors = validate_microvm(&vm_resources).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(errors[0], ConfigError::MissingKernel));
+        assert!(matches!(
+            errors[1],
+            ConfigError::KernelCmdline(Error::KernelCmdlineTooLarge(len, KERNEL_CMDLINE_LIMIT))
+                if len > KERNEL_CMDLINE_LIMIT
+        ));
+        assert!(matches!(
+            errors[2],
+            ConfigError::RegionOverlap(0x40_0000_0800, 0x1000)
+        ));
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+
+        vm_resources.kernel_bundle = Some(KernelBundle {
+            host_addr: 0x1000,
+            guest_addr: 0x1000,
+            entry_addr: 0x1000,
+            size: 0x1000,
+        });
+        vm_resources.mmio_handlers.pop();
+        vm_resources.boot_config.kernel_cmdline_args.extra.clear();
+        validate_microvm(&vm_resources).unwrap();
+        // More memory than the host has is only warned about.
+        set_mem_size(&mut vm_resources, host_memory_mib() as usize + 1);
+        validate_microvm(&vm_resources).unwrap();
+        set_mem_size(&mut vm_resources, 128);
+
+        #[cfg(not(feature = "tee"))]
+        vm_resources
+            .fs
+            .insert(crate::vmm_config::fs::tests::fs_config(
+                "share",
+                "/nonexistent",
+            ))
+            .unwrap();
+        vm_resources.console_output = Some(PathBuf::from("/nonexistent/console.log"));
+        // A missing shared file is created, as long as its directory exists.
+        let missing_paths = |vm_resources: &crate::resources::VmResources, path: &str| {
+            validate_microvm(vm_resources)
+                .unwrap_err()
+                .iter()
+                .filter(|err| matches!(err, ConfigError::MissingPath(p) if p == Path::new(path)))
+                .count()
+        };
+        for (memory_path, missing_dirs) in [("/nonexistent.img", 1), ("/nonexistent/memory.img", 2)]
+        {
+            vm_resources.memory_backend = MemoryBackend::FileBacked {
+                path: PathBuf::from(memory_path),
+                offset: 0,
+                shared: true,
+            };
+            assert_eq!(missing_paths(&vm_resources, memory_path), 0);
+            assert_eq!(missing_paths(&vm_resources, "/nonexistent"), missing_dirs);
+        }
+        vm_resources.memory_backend = MemoryBackend::FileBacked {
+            path: PathBuf::from("/nonexistent.img"),
+            offset: 0,
+            shared: false,
+        };
+        let errors = validate_microvm(&vm_resources).unwrap_err();
+        #[cfg(not(feature = "tee"))]
+        let errors = {
+            assert!(matches!(
+                &errors[0],
+                ConfigError::MissingSharedDir(tag, dir) if tag == "share" && dir == "/nonexistent"
+            ));
+            &errors[1..]
+        };
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            &errors[0],
+            ConfigError::MissingPath(path) if path == Path::new("/nonexistent.img")
+        ));
+        assert!(matches!(
+            &errors[1],
+            ConfigError::MissingPath(path) if path == Path::new("/nonexistent")
+        ));
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
     }
 
     #[test]
@@ -1580,4 +3665,46 @@ pub mod tests {
         let err = StartMicrovmError::from(kernel::cmdline::Error::HasSpace);
         let _ = format!("{}{:?}", err, err);
     }
+
+    #[test]
+    fn test_split_shm_region() {
+        let shm_region = VirtioShmRegion {
+            host_addr: 0x7000_0000_0000,
+            guest_addr: 0x1_0000_0000,
+            size: 16 * FS_DAX_WINDOW_ALIGN,
+        };
+        let fs_windows = [
+            ("toolchain".to_string(), Some(8 * FS_DAX_WINDOW_ALIGN)),
+            ("workspace".to_string(), None),
+            ("cache".to_string(), Some(2 * FS_DAX_WINDOW_ALIGN)),
+        ];
+        let (gpu, fs) = split_shm_region(shm_region.clone(), &fs_windows).unwrap();
+        assert_eq!(gpu.guest_addr, 0x1_0000_0000);
+        assert_eq!(gpu.size, 6 * FS_DAX_WINDOW_ALIGN);
+
+        let toolchain = fs[0].as_ref().unwrap();
+        assert_eq!(
+            toolchain.guest_addr,
+            0x1_0000_0000 + 8 * FS_DAX_WINDOW_ALIGN as u64
+        );
+        assert_eq!(
+            toolchain.host_addr,
+            0x7000_0000_0000 + 8 * FS_DAX_WINDOW_ALIGN as u64
+        );
+        assert!(fs[1].is_none());
+        let cache = fs[2].as_ref().unwrap();
+        assert_eq!(
+            cache.guest_addr,
+            0x1_0000_0000 + 6 * FS_DAX_WINDOW_ALIGN as u64
+        );
+        assert_eq!(cache.size, 2 * FS_DAX_WINDOW_ALIGN);
+
+        for size in [0, FS_DAX_WINDOW_ALIGN + 4096, 17 * FS_DAX_WINDOW_ALIGN] {
+            let fs_windows = [("toolchain".to_string(), Some(size))];
+            assert!(matches!(
+                split_shm_region(shm_region.clone(), &fs_windows),
+                Err(StartMicrovmError::FsDaxWindow(tag)) if tag == "toolchain"
+            ));
+        }
+    }
 }
@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! ELF core dumps of a paused guest, as written by `Vmm::export_core_dump`.
+//!
+//! There's a `PT_LOAD` segment for each guest RAM range, whose virtual and physical addresses are
+//! both the guest physical address of the range, and a `PT_NOTE` segment with an `NT_PRSTATUS`
+//! note per vCPU, the vCPU with index `i` being the thread with pid `i + 1`. The segments start
+//! on a page boundary.
+
+use std::io::{self, Write};
+
+use vm_memory::GuestMemoryMmap;
+
+use crate::vstate::VcpuRegisters;
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+#[cfg(target_arch = "x86_64")]
+const EM_MACHINE: u16 = 62; // EM_X86_64
+#[cfg(target_arch = "aarch64")]
+const EM_MACHINE: u16 = 183; // EM_AARCH64
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 7;
+
+const NT_PRSTATUS: u32 = 1;
+const NOTE_NAME: &[u8] = b"CORE\0";
+// `struct elf_prstatus` up to `pr_reg`: the signal info, the pids, and the cpu times.
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REG_OFFSET: usize = 112;
+// Size of `pr_reg`, a `struct user_regs_struct` on x86_64 and a `struct user_pt_regs` on aarch64.
+#[cfg(target_arch = "x86_64")]
+const PRSTATUS_REG_COUNT: usize = 27;
+#[cfg(target_arch = "aarch64")]
+const PRSTATUS_REG_COUNT: usize = 34;
+// `pr_reg` is followed by `pr_fpvalid` and the padding to 8 bytes.
+const PRSTATUS_SIZE: usize = PRSTATUS_REG_OFFSET + PRSTATUS_REG_COUNT * 8 + 8;
+
+const SEGMENT_ALIGNMENT: u64 = 0x1000;
+
+// Registers in the order of `struct user_regs_struct`.
+#[cfg(target_arch = "x86_64")]
+fn prstatus_registers(vcpu: &VcpuRegisters) -> [u64; PRSTATUS_REG_COUNT] {
+    let (r, s) = (&vcpu.regs, &vcpu.sregs);
+    [
+        r.r15,
+        r.r14,
+        r.r13,
+        r.r12,
+        r.rbp,
+        r.rbx,
+        r.r11,
+        r.r10,
+        r.r9,
+        r.r8,
+        r.rax,
+        r.rcx,
+        r.rdx,
+        r.rsi,
+        r.rdi,
+        // orig_rax, the vCPU isn't in a system call.
+        u64::MAX,
+        r.rip,
+        u64::from(s.cs.selector),
+        r.rflags,
+        r.rsp,
+        u64::from(s.ss.selector),
+        s.fs.base,
+        s.gs.base,
+        u64::from(s.ds.selector),
+        u64::from(s.es.selector),
+        u64::from(s.fs.selector),
+        u64::from(s.gs.selector),
+    ]
+}
+
+// Registers in the order of `struct user_pt_regs`, `sp` being the stack pointer of the exception
+// level the vCPU is at.
+#[cfg(target_arch = "aarch64")]
+fn prstatus_registers(vcpu: &VcpuRegisters) -> [u64; PRSTATUS_REG_COUNT] {
+    const PSTATE_MODE_MASK: u64 = 0xf;
+    const PSTATE_MODE_EL1H: u64 = 0x5;
+
+    let r = &vcpu.regs;
+    let mut regs = [0; PRSTATUS_REG_COUNT];
+    regs[..31].copy_from_slice(&r.regs);
+    regs[31] = if r.pstate & PSTATE_MODE_MASK == PSTATE_MODE_EL1H {
+        vcpu.sp_el1
+    } else {
+        r.sp
+    };
+    regs[32] = r.pc;
+    regs[33] = r.pstate;
+    regs
+}
+
+fn note(desc: &[u8]) -> Vec<u8> {
+    let mut note = Vec::new();
+    note.extend_from_slice(&(NOTE_NAME.len() as u32).to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    note.extend_from_slice(NOTE_NAME);
+    note.resize(note.len().next_multiple_of(4), 0);
+    note.extend_from_slice(desc);
+    note.resize(note.len().next_multiple_of(4), 0);
+    note
+}
+
+fn prstatus_note(index: usize, vcpu: &VcpuRegisters) -> Vec<u8> {
+    let mut prstatus = vec![0; PRSTATUS_SIZE];
+    prstatus[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4]
+        .copy_from_slice(&(index as u32 + 1).to_le_bytes());
+    for (i, reg) in prstatus_registers(vcpu).iter().enumerate() {
+        let offset = PRSTATUS_REG_OFFSET + i * 8;
+        prstatus[offset..offset + 8].copy_from_slice(&reg.to_le_bytes());
+    }
+    note(&prstatus)
+}
+
+fn program_header(p_type: u32, offset: u64, addr: u64, size: u64, align: u64) -> Vec<u8> {
+    let flags = if p_type == PT_LOAD { PF_RWX } else { 0 };
+    let mut header = Vec::with_capacity(PROGRAM_HEADER_SIZE as usize);
+    header.extend_from_slice(&p_type.to_le_bytes());
+    header.extend_from_slice(&flags.to_le_bytes());
+    for field in [offset, addr, addr, size, size, align] {
+        header.extend_from_slice(&field.to_le_bytes());
+    }
+    header
+}
+
+/// Writes the core dump of a guest whose RAM is `ram_ranges` of `guest_memory` and whose vCPUs
+/// have the `vcpus` registers to `out`.
+pub(crate) fn write_core_dump<W: Write>(
+    guest_memory: &GuestMemoryMmap,
+    ram_ranges: &[(u64, u64)],
+    vcpus: &[VcpuRegisters],
+    out: &mut W,
+) -> io::Result<()> {
+    let notes: Vec<u8> = vcpus
+        .iter()
+        .enumerate()
+        .flat_map(|(index, vcpu)| prstatus_note(index, vcpu))
+        .collect();
+    let phnum = ram_ranges.len() + 1;
+    let notes_offset = ELF_HEADER_SIZE + phnum as u64 * PROGRAM_HEADER_SIZE;
+    let data_offset = (notes_offset + notes.len() as u64).next_multiple_of(SEGMENT_ALIGNMENT);
+
+    let mut headers = Vec::with_capacity(data_offset as usize);
+    headers.extend_from_slice(b"\x7fELF");
+    headers.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    headers.resize(16, 0);
+    headers.extend_from_slice(&ET_CORE.to_le_bytes());
+    headers.extend_from_slice(&EM_MACHINE.to_le_bytes());
+    headers.extend_from_slice(&u32::from(EV_CURRENT).to_le_bytes());
+    // e_entry, e_phoff, e_shoff
+    for field in [0, ELF_HEADER_SIZE, 0] {
+        headers.extend_from_slice(&field.to_le_bytes());
+    }
+    // e_flags
+    headers.extend_from_slice(&0u32.to_le_bytes());
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+    for field in [ELF_HEADER_SIZE, PROGRAM_HEADER_SIZE, phnum as u64, 0, 0, 0] {
+        headers.extend_from_slice(&(field as u16).to_le_bytes());
+    }
+
+    headers.extend(program_header(
+        PT_NOTE,
+        notes_offset,
+        0,
+        notes.len() as u64,
+        4,
+    ));
+    let mut offset = data_offset;
+    for &(start, size) in ram_ranges {
+        headers.extend(program_header(
+            PT_LOAD,
+            offset,
+            start,
+            size,
+            SEGMENT_ALIGNMENT,
+        ));
+        offset += size;
+    }
+    headers.extend(notes);
+    headers.resize(data_offset as usize, 0);
+
+    out.write_all(&headers)?;
+    crate::write_guest_ranges(guest_memory, ram_ranges, out)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::{Bytes, GuestAddress};
+
+    fn u16_at(buf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_write_core_dump() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x10_0000), 0x2000),
+        ])
+        .unwrap();
+        guest_memory
+            .write_slice(b"ram", GuestAddress(0x10_1000))
+            .unwrap();
+        let ram_ranges = [(0, 0x1000), (0x10_0000, 0x2000)];
+
+        let mut vcpus = [VcpuRegisters::default(); 2];
+        #[cfg(target_arch = "x86_64")]
+        {
+            vcpus[1].regs.rip = 0xffff_ffff_8100_0000;
+            vcpus[1].sregs.cs.selector = 0x10;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            vcpus[1].regs.pc = 0xffff_8000_8000_0000;
+            vcpus[1].regs.pstate = 0x3c5;
+            vcpus[1].sp_el1 = 0xffff_8000_8200_0000;
+        }
+
+        let mut dump = Vec::new();
+        write_core_dump(&guest_memory, &ram_ranges, &vcpus, &mut dump).unwrap();
+
+        assert_eq!(&dump[..7], b"\x7fELF\x02\x01\x01");
+        assert_eq!(u16_at(&dump, 16), ET_CORE);
+        assert_eq!(u16_at(&dump, 18), EM_MACHINE);
+        assert_eq!(u64_at(&dump, 32), ELF_HEADER_SIZE);
+        assert_eq!(u16_at(&dump, 56), 3);
+
+        let phdr = |i: usize| &dump[64 + i * 56..64 + (i + 1) * 56];
+        assert_eq!(u32_at(phdr(0), 0), PT_NOTE);
+        let notes_offset = u64_at(phdr(0), 8) as usize;
+        let notes_size = u64_at(phdr(0), 32) as usize;
+        let notes = &dump[notes_offset..notes_offset + notes_size];
+
+        // Two notes, one per vCPU.
+        let note_size = 12 + 8 + PRSTATUS_SIZE;
+        assert_eq!(notes.len(), 2 * note_size);
+        let note = &notes[note_size..];
+        assert_eq!(u32_at(note, 0), 5);
+        assert_eq!(u32_at(note, 4) as usize, PRSTATUS_SIZE);
+        assert_eq!(u32_at(note, 8), NT_PRSTATUS);
+        assert_eq!(&note[12..17], b"CORE\0");
+        let prstatus = &note[20..];
+        assert_eq!(u32_at(prstatus, PRSTATUS_PID_OFFSET), 2);
+        let reg = |i: usize| u64_at(prstatus, PRSTATUS_REG_OFFSET + i * 8);
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(reg(16), 0xffff_ffff_8100_0000);
+            assert_eq!(reg(17), 0x10);
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            assert_eq!(reg(31), 0xffff_8000_8200_0000);
+            assert_eq!(reg(32), 0xffff_8000_8000_0000);
+        }
+
+        for (i, &(start, size)) in ram_ranges.iter().enumerate() {
+            let phdr = phdr(i + 1);
+            assert_eq!(u32_at(phdr, 0), PT_LOAD);
+            assert_eq!(u64_at(phdr, 8) % SEGMENT_ALIGNMENT, 0);
+            assert_eq!(u64_at(phdr, 16), start);
+            assert_eq!(u64_at(phdr, 24), start);
+            assert_eq!(u64_at(phdr, 32), size);
+        }
+        let ram_offset = u64_at(phdr(2), 8) as usize;
+        assert_eq!(&dump[ram_offset + 0x1000..ram_offset + 0x1003], b"ram");
+        assert_eq!(dump.len(), ram_offset + 0x2000);
+    }
+}
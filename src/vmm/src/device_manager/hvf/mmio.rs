@@ -21,6 +21,8 @@ use polly::event_manager::EventManager;
 #[cfg(target_arch = "aarch64")]
 use utils::eventfd::EventFd;
 
+#[cfg(target_arch = "aarch64")]
+use crate::vmm_config::rtc::RtcConfig;
 use crate::vstate::Vm;
 
 /// Errors for MMIO device manager.
@@ -41,6 +43,8 @@ pub enum Error {
     RegisterIrqFd,
     /// The device couldn't be found
     DeviceNotFound,
+    /// Another device of the same type was registered with the same id.
+    DeviceIdInUse(String),
     /// Failed to update the mmio device.
     UpdateFailed,
 }
@@ -57,6 +61,7 @@ impl fmt::Display for Error {
             Error::RegisterIoEvent => write!(f, "failed to register IO event"),
             Error::RegisterIrqFd => write!(f, "failed to register irqfd"),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
+            Error::DeviceIdInUse(ref id) => write!(f, "device id {} is already in use", id),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
         }
     }
@@ -77,6 +82,11 @@ pub struct MMIODeviceManager {
     irq: u32,
     last_irq: u32,
     id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
+    device_ids: HashMap<String, String>,
+    #[cfg(target_arch = "aarch64")]
+    rtc: Option<Arc<Mutex<devices::legacy::RTC>>>,
+    #[cfg(target_arch = "aarch64")]
+    pvpanic: Option<Arc<Mutex<devices::legacy::PvPanic>>>,
 }
 
 impl MMIODeviceManager {
@@ -91,9 +101,28 @@ impl MMIODeviceManager {
             last_irq: irq_interval.1,
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
+            device_ids: HashMap::new(),
+            #[cfg(target_arch = "aarch64")]
+            rtc: None,
+            #[cfg(target_arch = "aarch64")]
+            pvpanic: None,
         }
     }
 
+    /// Registers the devices whose default id is a key of `ids` with the matching value instead,
+    /// e.g. to look them up with `get_device` by an id the embedder chose.
+    pub fn set_device_ids(&mut self, ids: HashMap<String, String>) {
+        self.device_ids = ids;
+    }
+
+    /// Returns the id the device with `default_id` is registered with, see `set_device_ids`.
+    pub fn device_id(&self, default_id: &str) -> String {
+        self.device_ids
+            .get(default_id)
+            .cloned()
+            .unwrap_or_else(|| default_id.to_string())
+    }
+
     /// Register an already created MMIO device to be used via MMIO transport.
     pub fn register_mmio_device(
         &mut self,
@@ -105,6 +134,14 @@ impl MMIODeviceManager {
             return Err(Error::IrqsExhausted);
         }
 
+        let device_id = self.device_id(&device_id);
+        if self
+            .id_to_dev_info
+            .contains_key(&(DeviceType::Virtio(type_id), device_id.clone()))
+        {
+            return Err(Error::DeviceIdInUse(device_id));
+        }
+
         let mut queue_evts: Vec<EventFd> = Vec::new();
 
         for queue_evt in mmio_device.locked_device().queue_events().iter() {
@@ -183,18 +220,28 @@ impl MMIODeviceManager {
 
     #[cfg(target_arch = "aarch64")]
     /// Register a MMIO RTC device.
-    pub fn register_mmio_rtc(&mut self, _vm: &Vm, _intc: Option<Arc<Mutex<Gic>>>) -> Result<()> {
+    pub fn register_mmio_rtc(
+        &mut self,
+        _vm: &Vm,
+        _intc: Option<Arc<Mutex<Gic>>>,
+        rtc_config: &RtcConfig,
+    ) -> Result<()> {
         if self.irq > self.last_irq {
             return Err(Error::IrqsExhausted);
         }
 
         // Attaching the RTC device.
         let rtc_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(Error::EventFd)?;
-        let device = devices::legacy::RTC::new(rtc_evt.try_clone().map_err(Error::EventFd)?);
+        let device = devices::legacy::RTC::new(
+            rtc_evt.try_clone().map_err(Error::EventFd)?,
+            rtc_config.initial_time_ns(),
+        );
 
+        let device = Arc::new(Mutex::new(device));
         self.bus
-            .insert(Arc::new(Mutex::new(device)), self.mmio_base, MMIO_LEN)
+            .insert(device.clone(), self.mmio_base, MMIO_LEN)
             .map_err(Error::BusError)?;
+        self.rtc = Some(device);
 
         let ret = self.mmio_base;
         self.id_to_dev_info.insert(
@@ -282,6 +329,65 @@ impl MMIODeviceManager {
         &self.id_to_dev_info
     }
 
+    #[cfg(target_arch = "aarch64")]
+    /// Gets the RTC device, if registered.
+    pub fn rtc(&self) -> Option<&Arc<Mutex<devices::legacy::RTC>>> {
+        self.rtc.as_ref()
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Register a MMIO pvpanic device, writing to `panic_evt` when the guest raises a panic
+    /// event. It doesn't take an IRQ.
+    pub fn register_mmio_pvpanic(&mut self, panic_evt: EventFd) -> Result<()> {
+        let device = Arc::new(Mutex::new(devices::legacy::PvPanic::new(panic_evt)));
+        self.bus
+            .insert(device.clone(), self.mmio_base, MMIO_LEN)
+            .map_err(Error::BusError)?;
+        self.pvpanic = Some(device);
+
+        self.id_to_dev_info.insert(
+            (DeviceType::PvPanic, DeviceType::PvPanic.to_string()),
+            MMIODeviceInfo {
+                addr: self.mmio_base,
+                len: MMIO_LEN,
+                irq: 0,
+            },
+        );
+        self.mmio_base += MMIO_LEN;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Gets the pvpanic device, if registered.
+    pub fn pvpanic(&self) -> Option<&Arc<Mutex<devices::legacy::PvPanic>>> {
+        self.pvpanic.as_ref()
+    }
+
+    /// Routes guest accesses to the `len` bytes at `base` to an embedder-provided `handler`.
+    pub fn register_mmio_handler(
+        &mut self,
+        base: u64,
+        len: u64,
+        handler: Arc<dyn devices::BusAccessHandler>,
+    ) -> Result<()> {
+        self.bus
+            .insert(
+                Arc::new(Mutex::new(devices::HandlerDevice::new(base, handler))),
+                base,
+                len,
+            )
+            .map_err(Error::BusError)
+    }
+
+    /// Sets the observer notified about guest accesses to unregistered MMIO addresses.
+    pub fn set_unhandled_access_observer(
+        &mut self,
+        observer: Arc<dyn devices::UnhandledAccessObserver>,
+    ) {
+        self.bus.set_unhandled_access_observer(observer);
+    }
+
     /// Gets the the specified device.
     pub fn get_device(
         &self,
@@ -473,7 +579,7 @@ mod tests {
         #[cfg(target_arch = "aarch64")]
         assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
 
-        for _i in arch::IRQ_BASE..=arch::IRQ_MAX {
+        for i in arch::IRQ_BASE..=arch::IRQ_MAX {
             device_manager
                 .register_virtio_device(
                     vm.fd(),
@@ -481,7 +587,7 @@ mod tests {
                     Arc::new(Mutex::new(DummyDevice::new())),
                     &mut cmdline,
                     0,
-                    "dummy1",
+                    &format!("dummy{}", i),
                 )
                 .unwrap();
         }
@@ -20,6 +20,9 @@ use kvm_ioctls::{IoEventAddress, VmFd};
 #[cfg(target_arch = "aarch64")]
 use utils::eventfd::EventFd;
 
+#[cfg(target_arch = "aarch64")]
+use crate::vmm_config::rtc::RtcConfig;
+
 /// Errors for MMIO device manager.
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
@@ -38,8 +41,18 @@ pub enum Error {
     RegisterIrqFd(kvm_ioctls::Error),
     /// The device couldn't be found
     DeviceNotFound,
+    /// Another device of the same type was registered with the same id.
+    DeviceIdInUse(String),
     /// Failed to update the mmio device.
     UpdateFailed,
+    /// The guest driver didn't release the device with this id.
+    DeviceBusy(String),
+    /// Unregistering an IO Event failed.
+    UnregisterIoEvent(kvm_ioctls::Error),
+    /// Unregistering an IRQ FD failed.
+    UnregisterIrqFd(kvm_ioctls::Error),
+    /// All the slots reserved for hot-plugged devices are in use.
+    NoHotplugSlots,
 }
 
 impl fmt::Display for Error {
@@ -54,7 +67,12 @@ impl fmt::Display for Error {
             Error::RegisterIoEvent(ref e) => write!(f, "failed to register IO event: {e}"),
             Error::RegisterIrqFd(ref e) => write!(f, "failed to register irqfd: {e}"),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
+            Error::DeviceIdInUse(ref id) => write!(f, "device id {id} is already in use"),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
+            Error::DeviceBusy(ref id) => write!(f, "device {id} is still in use by the guest"),
+            Error::UnregisterIoEvent(ref e) => write!(f, "failed to unregister IO event: {e}"),
+            Error::UnregisterIrqFd(ref e) => write!(f, "failed to unregister irqfd: {e}"),
+            Error::NoHotplugSlots => write!(f, "no slot is left for hot-plugged devices"),
         }
     }
 }
@@ -67,6 +85,9 @@ type Result<T> = ::std::result::Result<T, Error>;
 /// Currently hardcoded to 4K.
 const MMIO_LEN: u64 = 0x1000;
 
+/// Virtio device id the empty slots are registered with, it's reserved by the specification.
+const EMPTY_SLOT_TYPE: u32 = 0;
+
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
     pub bus: devices::Bus,
@@ -74,6 +95,15 @@ pub struct MMIODeviceManager {
     irq: u32,
     last_irq: u32,
     id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
+    device_ids: HashMap<String, String>,
+    virtio_devices: HashMap<(DeviceType, String), Arc<Mutex<devices::virtio::MmioTransport>>>,
+    // MMIO ranges and IRQs of the empty slots, i.e. the ones reserved at boot for hot-plugged
+    // devices and the ones of the unregistered devices, reused by the next ones.
+    free_slots: Vec<(u64, u32)>,
+    #[cfg(target_arch = "aarch64")]
+    rtc: Option<Arc<Mutex<devices::legacy::RTC>>>,
+    #[cfg(target_arch = "aarch64")]
+    pvpanic: Option<Arc<Mutex<devices::legacy::PvPanic>>>,
 }
 
 impl MMIODeviceManager {
@@ -88,9 +118,30 @@ impl MMIODeviceManager {
             last_irq: irq_interval.1,
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
+            device_ids: HashMap::new(),
+            virtio_devices: HashMap::new(),
+            free_slots: Vec::new(),
+            #[cfg(target_arch = "aarch64")]
+            rtc: None,
+            #[cfg(target_arch = "aarch64")]
+            pvpanic: None,
         }
     }
 
+    /// Registers the devices whose default id is a key of `ids` with the matching value instead,
+    /// e.g. to look them up with `get_device` by an id the embedder chose.
+    pub fn set_device_ids(&mut self, ids: HashMap<String, String>) {
+        self.device_ids = ids;
+    }
+
+    /// Returns the id the device with `default_id` is registered with, see `set_device_ids`.
+    pub fn device_id(&self, default_id: &str) -> String {
+        self.device_ids
+            .get(default_id)
+            .cloned()
+            .unwrap_or_else(|| default_id.to_string())
+    }
+
     /// Register an already created MMIO device to be used via MMIO transport.
     pub fn register_mmio_device(
         &mut self,
@@ -99,43 +150,204 @@ impl MMIODeviceManager {
         type_id: u32,
         device_id: String,
     ) -> Result<(u64, u32)> {
-        if self.irq > self.last_irq {
-            return Err(Error::IrqsExhausted);
-        }
+        let (mmio_base, irq) = match self.free_slots.last() {
+            Some(slot) => *slot,
+            None if self.irq > self.last_irq => return Err(Error::IrqsExhausted),
+            None => (self.mmio_base, self.irq),
+        };
 
-        for (i, queue_evt) in mmio_device
-            .locked_device()
-            .queue_events()
-            .iter()
-            .enumerate()
+        let device_id = self.device_id(&device_id);
+        if self
+            .id_to_dev_info
+            .contains_key(&(DeviceType::Virtio(type_id), device_id.clone()))
         {
-            let io_addr = IoEventAddress::Mmio(
-                self.mmio_base + u64::from(devices::virtio::NOTIFY_REG_OFFSET),
-            );
-
-            vm.register_ioevent(queue_evt, &io_addr, i as u32)
-                .map_err(Error::RegisterIoEvent)?;
+            return Err(Error::DeviceIdInUse(device_id));
         }
 
-        vm.register_irqfd(mmio_device.locked_device().interrupt_evt(), self.irq)
-            .map_err(Error::RegisterIrqFd)?;
+        let io_addr =
+            IoEventAddress::Mmio(mmio_base + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
+        {
+            let device = mmio_device.locked_device();
+            for (i, queue_evt) in device.queue_events().iter().enumerate() {
+                if let Err(e) = vm.register_ioevent(queue_evt, &io_addr, i as u32) {
+                    unregister_events(vm, &*device, &io_addr, i, None);
+                    return Err(Error::RegisterIoEvent(e));
+                }
+            }
+            if let Err(e) = vm.register_irqfd(device.interrupt_evt(), irq) {
+                let count = device.queue_events().len();
+                unregister_events(vm, &*device, &io_addr, count, None);
+                return Err(Error::RegisterIrqFd(e));
+            }
+        }
 
-        self.bus
-            .insert(Arc::new(Mutex::new(mmio_device)), self.mmio_base, MMIO_LEN)
-            .map_err(Error::BusError)?;
-        let ret = (self.mmio_base, self.irq);
+        // The empty slot stays in place, for the next device to try, unless this one makes it.
+        let mmio_device = Arc::new(Mutex::new(mmio_device));
+        let empty_slot = match self.free_slots.last() {
+            Some(_) => self.bus.remove(mmio_base),
+            None => None,
+        };
+        if let Err(e) = self.bus.insert(mmio_device.clone(), mmio_base, MMIO_LEN) {
+            if let Some(empty_slot) = empty_slot {
+                // Can't fail, the range was just freed.
+                let _ = self.bus.insert(empty_slot, mmio_base, MMIO_LEN);
+            }
+            let transport = mmio_device.lock().unwrap();
+            let device = transport.locked_device();
+            let count = device.queue_events().len();
+            unregister_events(vm, &*device, &io_addr, count, Some(irq));
+            return Err(Error::BusError(e));
+        }
+        self.id_to_dev_info.retain(|(device_type, _), info| {
+            info.addr != mmio_base || *device_type != DeviceType::Virtio(EMPTY_SLOT_TYPE)
+        });
+        let key = (DeviceType::Virtio(type_id), device_id);
         self.id_to_dev_info.insert(
-            (DeviceType::Virtio(type_id), device_id),
+            key.clone(),
             MMIODeviceInfo {
-                addr: self.mmio_base,
+                addr: mmio_base,
                 _len: MMIO_LEN,
-                _irq: self.irq,
+                _irq: irq,
             },
         );
-        self.mmio_base += MMIO_LEN;
-        self.irq += 1;
+        self.virtio_devices.insert(key, mmio_device);
+        if self.free_slots.pop().is_none() {
+            self.mmio_base += MMIO_LEN;
+            self.irq += 1;
+        }
 
-        Ok(ret)
+        Ok((mmio_base, irq))
+    }
+
+    /// Declares `count` empty virtio slots to the guest, for `register_hotplug_device` to
+    /// populate after boot. They're backed by a `MmioEmptySlot` until then, and listed by
+    /// `get_device_info` as `hotplug_slot0`, `hotplug_slot1`...
+    pub fn reserve_hotplug_slots(
+        &mut self,
+        _cmdline: &mut kernel_cmdline::Cmdline,
+        count: usize,
+    ) -> Result<()> {
+        for i in 0..count {
+            if self.irq > self.last_irq {
+                return Err(Error::IrqsExhausted);
+            }
+            self.bus
+                .insert(
+                    Arc::new(Mutex::new(devices::virtio::MmioEmptySlot)),
+                    self.mmio_base,
+                    MMIO_LEN,
+                )
+                .map_err(Error::BusError)?;
+            #[cfg(target_arch = "x86_64")]
+            self.add_device_to_cmdline(_cmdline, self.mmio_base, self.irq)?;
+            self.id_to_dev_info.insert(
+                (
+                    DeviceType::Virtio(EMPTY_SLOT_TYPE),
+                    format!("hotplug_slot{i}"),
+                ),
+                MMIODeviceInfo {
+                    addr: self.mmio_base,
+                    _len: MMIO_LEN,
+                    _irq: self.irq,
+                },
+            );
+            self.free_slots.insert(0, (self.mmio_base, self.irq));
+            self.mmio_base += MMIO_LEN;
+            self.irq += 1;
+        }
+        Ok(())
+    }
+
+    /// Registers a device after boot in one of the empty slots the guest already knows about,
+    /// see `reserve_hotplug_slots`.
+    #[cfg(any(test, feature = "net"))]
+    pub fn register_hotplug_device(
+        &mut self,
+        vm: &VmFd,
+        mmio_device: devices::virtio::MmioTransport,
+        type_id: u32,
+        device_id: String,
+    ) -> Result<(u64, u32)> {
+        if self.free_slots.is_empty() {
+            return Err(Error::NoHotplugSlots);
+        }
+        self.register_mmio_device(vm, mmio_device, type_id, device_id)
+    }
+
+    /// Gives the guest driver of the virtio device registered with `device_id`, if it's bound to
+    /// it, a configuration change interrupt, which a guest agent can watch to release the device.
+    pub fn request_release(&self, device_type: DeviceType, device_id: &str) -> Result<()> {
+        let transport = self
+            .virtio_devices
+            .get(&(device_type, device_id.to_string()))
+            .ok_or(Error::DeviceNotFound)?
+            .lock()
+            .unwrap();
+        if transport.is_driver_bound() {
+            transport.signal_config_change().map_err(Error::EventFd)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the MMIO transports of the registered virtio devices, by type and id.
+    #[cfg(target_arch = "x86_64")]
+    pub fn virtio_devices(
+        &self,
+    ) -> &HashMap<(DeviceType, String), Arc<Mutex<devices::virtio::MmioTransport>>> {
+        &self.virtio_devices
+    }
+
+    /// Removes the virtio device registered with `device_id` from the bus, and frees its MMIO
+    /// range and IRQ for the next device registered. The guest driver must have released it by
+    /// resetting it, it's `DeviceBusy` otherwise. Clones of the bus made earlier, such as the ones
+    /// of the vCPUs, still route accesses to the device.
+    pub fn unregister_device(
+        &mut self,
+        vm: &VmFd,
+        device_type: DeviceType,
+        device_id: &str,
+    ) -> Result<Arc<Mutex<dyn devices::virtio::VirtioDevice>>> {
+        let key = (device_type, device_id.to_string());
+        let (Some(transport), Some(info)) =
+            (self.virtio_devices.get(&key), self.id_to_dev_info.get(&key))
+        else {
+            return Err(Error::DeviceNotFound);
+        };
+        let transport = transport.lock().unwrap();
+        if transport.is_driver_bound() {
+            return Err(Error::DeviceBusy(device_id.to_string()));
+        }
+
+        {
+            let mut device = transport.locked_device();
+            // The guest reset already deactivated the devices supporting it.
+            if device.is_activated() && !device.reset() {
+                return Err(Error::DeviceBusy(device_id.to_string()));
+            }
+            let io_addr =
+                IoEventAddress::Mmio(info.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
+            for (i, queue_evt) in device.queue_events().iter().enumerate() {
+                vm.unregister_ioevent(queue_evt, &io_addr, i as u32)
+                    .map_err(Error::UnregisterIoEvent)?;
+            }
+            vm.unregister_irqfd(device.interrupt_evt(), info._irq)
+                .map_err(Error::UnregisterIrqFd)?;
+        }
+
+        let device = transport.device();
+        drop(transport);
+        self.bus.remove(info.addr);
+        self.bus
+            .insert(
+                Arc::new(Mutex::new(devices::virtio::MmioEmptySlot)),
+                info.addr,
+                MMIO_LEN,
+            )
+            .map_err(Error::BusError)?;
+        self.free_slots.push((info.addr, info._irq));
+        self.id_to_dev_info.remove(&key);
+        self.virtio_devices.remove(&key);
+        Ok(device)
     }
 
     /// Append a registered MMIO device to the kernel cmdline.
@@ -200,20 +412,25 @@ impl MMIODeviceManager {
 
     #[cfg(target_arch = "aarch64")]
     /// Register a MMIO RTC device.
-    pub fn register_mmio_rtc(&mut self, vm: &VmFd) -> Result<()> {
+    pub fn register_mmio_rtc(&mut self, vm: &VmFd, rtc_config: &RtcConfig) -> Result<()> {
         if self.irq > self.last_irq {
             return Err(Error::IrqsExhausted);
         }
 
         // Attaching the RTC device.
         let rtc_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(Error::EventFd)?;
-        let device = devices::legacy::RTC::new(rtc_evt.try_clone().map_err(Error::EventFd)?);
+        let device = devices::legacy::RTC::new(
+            rtc_evt.try_clone().map_err(Error::EventFd)?,
+            rtc_config.initial_time_ns(),
+        );
         vm.register_irqfd(&rtc_evt, self.irq)
             .map_err(Error::RegisterIrqFd)?;
 
+        let device = Arc::new(Mutex::new(device));
         self.bus
-            .insert(Arc::new(Mutex::new(device)), self.mmio_base, MMIO_LEN)
+            .insert(device.clone(), self.mmio_base, MMIO_LEN)
             .map_err(Error::BusError)?;
+        self.rtc = Some(device);
 
         let ret = self.mmio_base;
         self.id_to_dev_info.insert(
@@ -231,12 +448,79 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    /// Whether `start..start + size` overlaps the MMIO range of a registered device or of an
+    /// empty slot.
+    pub fn overlaps_device(&self, start: u64, size: u64) -> bool {
+        self.id_to_dev_info
+            .values()
+            .any(|info| start < info.addr + info._len && info.addr < start.saturating_add(size))
+    }
+
     #[cfg(target_arch = "aarch64")]
     /// Gets the information of the devices registered up to some point in time.
     pub fn get_device_info(&self) -> &HashMap<(DeviceType, String), MMIODeviceInfo> {
         &self.id_to_dev_info
     }
 
+    #[cfg(target_arch = "aarch64")]
+    /// Gets the RTC device, if registered.
+    pub fn rtc(&self) -> Option<&Arc<Mutex<devices::legacy::RTC>>> {
+        self.rtc.as_ref()
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Register a MMIO pvpanic device, writing to `panic_evt` when the guest raises a panic
+    /// event. It doesn't take an IRQ.
+    pub fn register_mmio_pvpanic(&mut self, panic_evt: EventFd) -> Result<()> {
+        let device = Arc::new(Mutex::new(devices::legacy::PvPanic::new(panic_evt)));
+        self.bus
+            .insert(device.clone(), self.mmio_base, MMIO_LEN)
+            .map_err(Error::BusError)?;
+        self.pvpanic = Some(device);
+
+        self.id_to_dev_info.insert(
+            (DeviceType::PvPanic, DeviceType::PvPanic.to_string()),
+            MMIODeviceInfo {
+                addr: self.mmio_base,
+                _len: MMIO_LEN,
+                _irq: 0,
+            },
+        );
+        self.mmio_base += MMIO_LEN;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Gets the pvpanic device, if registered.
+    pub fn pvpanic(&self) -> Option<&Arc<Mutex<devices::legacy::PvPanic>>> {
+        self.pvpanic.as_ref()
+    }
+
+    /// Routes guest accesses to the `len` bytes at `base` to an embedder-provided `handler`.
+    pub fn register_mmio_handler(
+        &mut self,
+        base: u64,
+        len: u64,
+        handler: Arc<dyn devices::BusAccessHandler>,
+    ) -> Result<()> {
+        self.bus
+            .insert(
+                Arc::new(Mutex::new(devices::HandlerDevice::new(base, handler))),
+                base,
+                len,
+            )
+            .map_err(Error::BusError)
+    }
+
+    /// Sets the observer notified about guest accesses to unregistered MMIO addresses.
+    pub fn set_unhandled_access_observer(
+        &mut self,
+        observer: Arc<dyn devices::UnhandledAccessObserver>,
+    ) {
+        self.bus.set_unhandled_access_observer(observer);
+    }
+
     /// Gets the the specified device.
     pub fn get_device(
         &self,
@@ -255,6 +539,27 @@ impl MMIODeviceManager {
     }
 }
 
+/// Unregisters the first `count` queue events of `device`, and its interrupt if `irq` is given,
+/// when `register_mmio_device` fails halfway.
+fn unregister_events(
+    vm: &VmFd,
+    device: &dyn devices::virtio::VirtioDevice,
+    io_addr: &IoEventAddress,
+    count: usize,
+    irq: Option<u32>,
+) {
+    for (i, queue_evt) in device.queue_events()[..count].iter().enumerate() {
+        if let Err(e) = vm.unregister_ioevent(queue_evt, io_addr, i as u32) {
+            error!("Cannot unregister the queue {i} event: {e}");
+        }
+    }
+    if let Some(irq) = irq {
+        if let Err(e) = vm.unregister_irqfd(device.interrupt_evt(), irq) {
+            error!("Cannot unregister the interrupt event: {e}");
+        }
+    }
+}
+
 /// Private structure for storing information about the MMIO device registered at some address on the bus.
 #[derive(Clone, Debug)]
 pub struct MMIODeviceInfo {
@@ -430,7 +735,7 @@ mod tests {
         #[cfg(target_arch = "aarch64")]
         assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
 
-        for _i in arch::IRQ_BASE..=arch::IRQ_MAX {
+        for i in arch::IRQ_BASE..=arch::IRQ_MAX {
             device_manager
                 .register_virtio_device(
                     vm.fd(),
@@ -438,7 +743,7 @@ mod tests {
                     Arc::new(Mutex::new(DummyDevice::new())),
                     &mut cmdline,
                     0,
-                    "dummy1",
+                    &format!("dummy{i}"),
                 )
                 .unwrap();
         }
@@ -515,6 +820,83 @@ mod tests {
             format!("{}", Error::RegisterIrqFd(errno::Error::new(0))),
             format!("failed to register irqfd: {}", errno::Error::new(0))
         );
+        assert_eq!(
+            format!("{}", Error::DeviceIdInUse("foo".to_string())),
+            "device id foo is already in use"
+        );
+        assert_eq!(
+            format!("{}", Error::DeviceBusy("foo".to_string())),
+            "device foo is still in use by the guest"
+        );
+        assert_eq!(
+            format!("{}", Error::UnregisterIoEvent(errno::Error::new(0))),
+            format!("failed to unregister IO event: {}", errno::Error::new(0))
+        );
+        assert_eq!(
+            format!("{}", Error::UnregisterIrqFd(errno::Error::new(0))),
+            format!("failed to unregister irqfd: {}", errno::Error::new(0))
+        );
+        assert_eq!(
+            format!("{}", Error::NoHotplugSlots),
+            "no slot is left for hot-plugged devices"
+        );
+    }
+
+    #[test]
+    fn test_device_ids() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let guest_mem =
+            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        #[allow(unused_mut)]
+        let mut vm = builder::setup_vm(&guest_mem).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        device_manager.set_device_ids(HashMap::from([("dummy".to_string(), "disk0".to_string())]));
+        let type_id = 0;
+        device_manager
+            .register_virtio_device(
+                vm.fd(),
+                guest_mem.clone(),
+                Arc::new(Mutex::new(DummyDevice::new())),
+                &mut cmdline,
+                type_id,
+                "dummy",
+            )
+            .unwrap();
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "disk0")
+            .is_some());
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "dummy")
+            .is_none());
+
+        // Ids only need to be unique among the devices of a type.
+        device_manager
+            .register_virtio_device(
+                vm.fd(),
+                guest_mem.clone(),
+                Arc::new(Mutex::new(DummyDevice::new())),
+                &mut cmdline,
+                type_id + 1,
+                "disk0",
+            )
+            .unwrap();
+        let res = device_manager.register_virtio_device(
+            vm.fd(),
+            guest_mem,
+            Arc::new(Mutex::new(DummyDevice::new())),
+            &mut cmdline,
+            type_id,
+            "disk0",
+        );
+        assert!(matches!(res, Err(Error::DeviceIdInUse(id)) if id == "disk0"));
     }
 
     #[test]
@@ -556,4 +938,240 @@ mod tests {
             .get_device(DeviceType::Virtio(type_id), id)
             .is_none());
     }
+
+    #[test]
+    fn test_unregister_device() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let guest_mem =
+            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        #[allow(unused_mut)]
+        let mut vm = builder::setup_vm(&guest_mem).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let type_id = 0;
+        let addr = device_manager
+            .register_virtio_device(
+                vm.fd(),
+                guest_mem.clone(),
+                Arc::new(Mutex::new(DummyDevice::new())),
+                &mut cmdline,
+                type_id,
+                "foo",
+            )
+            .unwrap();
+        assert!(device_manager
+            .request_release(DeviceType::Virtio(type_id), "foo")
+            .is_ok());
+        assert!(device_manager
+            .unregister_device(vm.fd(), DeviceType::Virtio(type_id), "foo")
+            .is_ok());
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "foo")
+            .is_none());
+        assert!(device_manager
+            .bus
+            .get_device(addr)
+            .is_some_and(|(_, device)| device
+                .lock()
+                .unwrap()
+                .as_any()
+                .is::<devices::virtio::MmioEmptySlot>()));
+        assert!(matches!(
+            device_manager.unregister_device(vm.fd(), DeviceType::Virtio(type_id), "foo"),
+            Err(Error::DeviceNotFound)
+        ));
+
+        // The next device takes the freed range.
+        assert_eq!(
+            device_manager
+                .register_virtio_device(
+                    vm.fd(),
+                    guest_mem,
+                    Arc::new(Mutex::new(DummyDevice::new())),
+                    &mut cmdline,
+                    type_id,
+                    "bar",
+                )
+                .unwrap(),
+            addr
+        );
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_unregister_activated_net_device() {
+        use devices::virtio::net::device::VirtioNetBackend;
+        use std::os::fd::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        #[allow(unused_mut)]
+        let mut vm = builder::setup_vm(&guest_mem).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let (backend, _peer) = UnixStream::pair().unwrap();
+        let net = devices::virtio::Net::new(
+            "net0".to_string(),
+            VirtioNetBackend::Passt(backend.as_raw_fd()),
+            [0; 6],
+        )
+        .unwrap();
+        let type_id = devices::virtio::TYPE_NET;
+        let addr = device_manager
+            .register_virtio_device(
+                vm.fd(),
+                guest_mem,
+                Arc::new(Mutex::new(net)),
+                &mut cmdline,
+                type_id,
+                "net0",
+            )
+            .unwrap();
+
+        // The guest driver binds the device and starts its worker.
+        for status in [1u32, 3, 11, 15] {
+            device_manager
+                .bus
+                .write(0, addr + 0x70, &status.to_le_bytes());
+        }
+        assert!(matches!(
+            device_manager.unregister_device(vm.fd(), DeviceType::Virtio(type_id), "net0"),
+            Err(Error::DeviceBusy(_))
+        ));
+
+        // Then releases it, which stops the worker.
+        device_manager
+            .bus
+            .write(0, addr + 0x70, &0u32.to_le_bytes());
+        let mut status = [0u8; 4];
+        device_manager.bus.read(0, addr + 0x70, &mut status);
+        assert_eq!(u32::from_le_bytes(status), 0);
+        let device = device_manager
+            .unregister_device(vm.fd(), DeviceType::Virtio(type_id), "net0")
+            .unwrap();
+        assert!(!device.lock().unwrap().is_activated());
+    }
+
+    #[test]
+    fn test_hotplug_slots() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let guest_mem =
+            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        #[allow(unused_mut)]
+        let mut vm = builder::setup_vm(&guest_mem).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let type_id = 1;
+        assert!(matches!(
+            device_manager.register_hotplug_device(
+                vm.fd(),
+                devices::virtio::MmioTransport::new(
+                    guest_mem.clone(),
+                    Arc::new(Mutex::new(DummyDevice::new())),
+                ),
+                type_id,
+                "foo".to_string(),
+            ),
+            Err(Error::NoHotplugSlots)
+        ));
+
+        device_manager
+            .reserve_hotplug_slots(&mut cmdline, 2)
+            .unwrap();
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            cmdline.as_str(),
+            "virtio_mmio.device=4K@0xd0000000:5 virtio_mmio.device=4K@0xd0001000:6"
+        );
+        let base = device_manager.id_to_dev_info[&(
+            DeviceType::Virtio(EMPTY_SLOT_TYPE),
+            "hotplug_slot0".to_string(),
+        )]
+            .addr;
+        assert!(device_manager.overlaps_device(base + 0x1800, 0x1000));
+        assert!(!device_manager.overlaps_device(base + 0x2000, 0x1000));
+        assert!(!device_manager.overlaps_device(base - 0x1000, 0x1000));
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(EMPTY_SLOT_TYPE), "hotplug_slot0")
+            .is_some());
+
+        // KVM refuses an interrupt event already in use, after the queue events got registered.
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        let busy_irq = arch::IRQ_MAX;
+        vm.fd()
+            .register_irqfd(&dummy.lock().unwrap().interrupt_evt, busy_irq)
+            .unwrap();
+        assert!(matches!(
+            device_manager.register_hotplug_device(
+                vm.fd(),
+                devices::virtio::MmioTransport::new(guest_mem.clone(), dummy.clone()),
+                type_id,
+                "foo".to_string(),
+            ),
+            Err(Error::RegisterIrqFd(_))
+        ));
+        // The slot is left as it was, and the queue event free to be registered again.
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(EMPTY_SLOT_TYPE), "hotplug_slot0")
+            .is_some());
+        let io_addr = IoEventAddress::Mmio(base + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
+        let queue_evt = &dummy.lock().unwrap().queue_evts[0];
+        vm.fd().register_ioevent(queue_evt, &io_addr, 0u32).unwrap();
+        vm.fd()
+            .unregister_ioevent(queue_evt, &io_addr, 0u32)
+            .unwrap();
+
+        for (id, addr) in [("foo", 0xd000_0000), ("bar", 0xd000_1000)] {
+            let (mmio_base, _) = device_manager
+                .register_hotplug_device(
+                    vm.fd(),
+                    devices::virtio::MmioTransport::new(
+                        guest_mem.clone(),
+                        Arc::new(Mutex::new(DummyDevice::new())),
+                    ),
+                    type_id,
+                    id.to_string(),
+                )
+                .unwrap();
+            assert_eq!(mmio_base, addr);
+            assert!(device_manager
+                .get_device(DeviceType::Virtio(type_id), id)
+                .is_some());
+        }
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(EMPTY_SLOT_TYPE), "hotplug_slot0")
+            .is_none());
+        assert!(matches!(
+            device_manager.register_hotplug_device(
+                vm.fd(),
+                devices::virtio::MmioTransport::new(
+                    guest_mem,
+                    Arc::new(Mutex::new(DummyDevice::new())),
+                ),
+                type_id,
+                "baz".to_string(),
+            ),
+            Err(Error::NoHotplugSlots)
+        ));
+    }
 }
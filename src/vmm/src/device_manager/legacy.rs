@@ -35,12 +35,16 @@ impl fmt::Display for Error {
 type Result<T> = ::std::result::Result<T, Error>;
 
 /// The `PortIODeviceManager` is a wrapper that is used for registering legacy devices
-/// on an I/O Bus. It currently manages the uart and i8042 devices.
+/// on an I/O Bus. It currently manages the uart, i8042 and CMOS RTC devices.
 /// The `LegacyDeviceManger` should be initialized only by using the constructor.
 pub struct PortIODeviceManager {
     pub io_bus: devices::Bus,
     pub stdio_serial: Option<Arc<Mutex<devices::legacy::Serial>>>,
+    /// Whether the uarts are registered, `stdio_serial` being `None` if not.
+    pub serial_ports: bool,
     pub i8042: Arc<Mutex<devices::legacy::I8042Device>>,
+    /// The CMOS RTC, its clock is set when the system is configured.
+    pub cmos: Arc<Mutex<devices::legacy::Cmos>>,
 
     pub com_evt_1_3: EventFd,
     pub com_evt_2_4: EventFd,
@@ -48,11 +52,14 @@ pub struct PortIODeviceManager {
 }
 
 impl PortIODeviceManager {
-    /// Create a new DeviceManager handling legacy devices (uart, i8042).
+    /// Create a new DeviceManager handling legacy devices (uart, i8042, rtc), without the uarts
+    /// unless `serial_ports` is set.
     pub fn new(
         stdio_serial: Option<Arc<Mutex<devices::legacy::Serial>>>,
+        serial_ports: bool,
         i8042_reset_evfd: EventFd,
     ) -> Result<Self> {
+        debug_assert!(serial_ports || stdio_serial.is_none());
         let io_bus = devices::Bus::new();
         let com_evt_1_3 = if let Some(serial) = &stdio_serial {
             serial
@@ -75,15 +82,54 @@ impl PortIODeviceManager {
         Ok(PortIODeviceManager {
             io_bus,
             stdio_serial,
+            serial_ports,
             i8042,
+            cmos: Arc::new(Mutex::new(devices::legacy::Cmos::new(0))),
             com_evt_1_3,
             com_evt_2_4,
             kbd_evt,
         })
     }
 
+    /// Sets the observer notified about guest accesses to unregistered I/O ports.
+    pub fn set_unhandled_access_observer(
+        &mut self,
+        observer: Arc<dyn devices::UnhandledAccessObserver>,
+    ) {
+        self.io_bus.set_unhandled_access_observer(observer);
+    }
+
+    /// Routes guest accesses to the `len` ports at `base` to an embedder-provided `handler`.
+    pub fn register_handler(
+        &mut self,
+        base: u64,
+        len: u64,
+        handler: Arc<dyn devices::BusAccessHandler>,
+    ) -> Result<()> {
+        self.io_bus
+            .insert(
+                Arc::new(Mutex::new(devices::HandlerDevice::new(base, handler))),
+                base,
+                len,
+            )
+            .map_err(Error::BusError)
+    }
+
     /// Register supported legacy devices.
     pub fn register_devices(&mut self) -> Result<()> {
+        if self.serial_ports {
+            self.register_serial_ports()?;
+        }
+        self.io_bus
+            .insert(self.i8042.clone(), 0x060, 0x5)
+            .map_err(Error::BusError)?;
+        self.io_bus
+            .insert(self.cmos.clone(), 0x070, 0x2)
+            .map_err(Error::BusError)?;
+        Ok(())
+    }
+
+    fn register_serial_ports(&mut self) -> Result<()> {
         if let Some(serial) = &self.stdio_serial {
             self.io_bus
                 .insert(serial.clone(), 0x3f8, 0x8)
@@ -116,9 +162,6 @@ impl PortIODeviceManager {
                 0x8,
             )
             .map_err(Error::BusError)?;
-        self.io_bus
-            .insert(self.i8042.clone(), 0x060, 0x5)
-            .map_err(Error::BusError)?;
         Ok(())
     }
 }
@@ -133,12 +176,28 @@ mod tests {
             devices::legacy::Serial::new_sink(EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap());
         let ldm = PortIODeviceManager::new(
             Some(Arc::new(Mutex::new(serial))),
+            true,
             EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
         );
         assert!(ldm.is_ok());
         assert!(&ldm.unwrap().register_devices().is_ok());
     }
 
+    #[test]
+    fn test_register_without_serial_ports() {
+        let mut ldm = PortIODeviceManager::new(
+            None,
+            false,
+            EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+        )
+        .unwrap();
+        ldm.register_devices().unwrap();
+        assert!(ldm.io_bus.get_device(0x3f8).is_none());
+        assert!(ldm.io_bus.get_device(0x2f8).is_none());
+        assert!(ldm.io_bus.get_device(0x060).is_some());
+        assert!(ldm.io_bus.get_device(0x070).is_some());
+    }
+
     #[test]
     fn test_debug_error() {
         assert_eq!(
@@ -0,0 +1,1019 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Debug server speaking the gdb remote serial protocol, as started by `start_server`.
+//!
+//! The vCPUs are paused as soon as a debugger connects, and show up as threads, the vCPU with
+//! index `i` being thread `i + 1`. gdb can read their registers, read and write the guest memory,
+//! set breakpoints and watchpoints, continue and single-step. Breakpoints, including the ones gdb
+//! would otherwise write to memory, and watchpoints use the debug registers of the vCPUs, so only
+//! `MAX_HW_BREAKPOINTS` and `MAX_HW_WATCHPOINTS` of them can be set at once. On x86_64 memory
+//! addresses are translated with the page tables of the selected vCPU, on aarch64 they're guest
+//! physical addresses. Detaching resumes the vCPUs.
+//!
+//! The server drives the vCPUs through the same channels as the `Vmm`, the embedder shouldn't
+//! pause or resume them while a debugger is attached.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{unbounded, Receiver, RecvError, Select};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::vstate::{
+    GuestDebug, GuestDebugError, VcpuEvent, VcpuRegisters, VcpuResponse, Watchpoint, WatchpointKind,
+};
+use crate::Vmm;
+
+// Largest packet gdb may send us, in bytes.
+const PACKET_SIZE: usize = 0x4000;
+// Memory accesses are split on guest page boundaries, pages possibly being mapped elsewhere.
+const PAGE_SIZE: u64 = 0x1000;
+// Signals reported to gdb when the vCPUs stop.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+// gdb only looks for an `E` in error replies.
+const REPLY_ERROR: &str = "E01";
+const REPLY_FAULT: &str = "E0e";
+const REPLY_NO_SPACE: &str = "E1c";
+
+/// Socket the debug server listens on for gdb to connect to, see `target remote`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GdbSocket {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Errors starting the debug server.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot listen on the socket.
+    Bind(io::Error),
+    /// Cannot spawn the server thread.
+    Spawn(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Bind(e) => write!(f, "Cannot listen for gdb connections: {e}"),
+            Spawn(e) => write!(f, "Cannot spawn the gdb server thread: {e}"),
+        }
+    }
+}
+
+/// Starts a thread serving the debuggers connecting to `socket`, one at a time. The vCPUs must
+/// have been started.
+pub fn start_server(vmm: Arc<Mutex<Vmm>>, socket: &GdbSocket) -> Result<(), Error> {
+    let listener = Listener::bind(socket).map_err(Error::Bind)?;
+    thread::Builder::new()
+        .name("gdb server".into())
+        .spawn(move || listener.serve(&vmm))
+        .map(|_| ())
+        .map_err(Error::Spawn)
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn bind(socket: &GdbSocket) -> io::Result<Self> {
+        match socket {
+            GdbSocket::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+            GdbSocket::Unix(path) => UnixListener::bind(path).map(Listener::Unix),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                // Packets are small and acknowledged one by one.
+                stream.set_nodelay(true)?;
+                Ok(Connection::Tcp(stream))
+            }
+            Listener::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+
+    fn serve(&self, vmm: &Arc<Mutex<Vmm>>) {
+        loop {
+            let connection = match self.accept() {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Cannot accept gdb connections: {e}");
+                    return;
+                }
+            };
+            info!("gdb connected");
+            match Session::new(vmm, connection).and_then(|session| session.run()) {
+                Ok(()) => info!("gdb detached"),
+                Err(e) => error!("gdb session failed: {e}"),
+            }
+        }
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SessionError {
+    Connection(io::Error),
+    Vcpu(usize, String),
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            SessionError::Connection(e) => write!(f, "connection error: {e}"),
+            SessionError::Vcpu(id, reason) => write!(f, "vCPU {id} failed: {reason}"),
+        }
+    }
+}
+
+type SessionResult<T> = std::result::Result<T, SessionError>;
+
+/// What the remote end sent, as split by `PacketParser`.
+#[derive(Debug, Eq, PartialEq)]
+enum Input {
+    /// A packet with a valid checksum, without its framing.
+    Packet(Vec<u8>),
+    /// A packet with an invalid checksum, to be sent again.
+    Corrupted,
+    /// A request to stop the running vCPUs.
+    Interrupt,
+}
+
+#[derive(Default)]
+enum ParserState {
+    #[default]
+    Idle,
+    Data(Vec<u8>),
+    Checksum(Vec<u8>, Vec<u8>),
+}
+
+/// Splits the byte stream coming from gdb into packets.
+#[derive(Default)]
+struct PacketParser {
+    state: ParserState,
+}
+
+impl PacketParser {
+    fn feed(&mut self, bytes: &[u8], inputs: &mut Vec<Input>) {
+        for &byte in bytes {
+            self.state = match std::mem::take(&mut self.state) {
+                ParserState::Idle => match byte {
+                    b'$' => ParserState::Data(Vec::new()),
+                    0x03 => {
+                        inputs.push(Input::Interrupt);
+                        ParserState::Idle
+                    }
+                    // Acknowledgements of our replies, which are never sent again.
+                    _ => ParserState::Idle,
+                },
+                ParserState::Data(data) if byte == b'#' => ParserState::Checksum(data, Vec::new()),
+                // Start over on an oversized packet, gdb sends it again once it's refused.
+                ParserState::Data(_) if byte == b'$' => ParserState::Data(Vec::new()),
+                ParserState::Data(mut data) => {
+                    data.push(byte);
+                    if data.len() > PACKET_SIZE {
+                        inputs.push(Input::Corrupted);
+                        ParserState::Idle
+                    } else {
+                        ParserState::Data(data)
+                    }
+                }
+                ParserState::Checksum(data, mut checksum) => {
+                    checksum.push(byte);
+                    if checksum.len() < 2 {
+                        ParserState::Checksum(data, checksum)
+                    } else {
+                        let valid =
+                            decode_hex(&checksum).as_deref() == Some(&[packet_checksum(&data)]);
+                        inputs.push(if valid {
+                            Input::Packet(data)
+                        } else {
+                            Input::Corrupted
+                        });
+                        ParserState::Idle
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    let pairs = hex.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+fn parse_hex(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+// Parses the `addr,length` arguments of the memory packets.
+fn parse_range(args: &str) -> Option<(u64, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)? as usize))
+}
+
+// Parses a thread id, `None` standing for any thread. The vCPU with index `i` is thread `i + 1`.
+fn parse_thread(thread: &str) -> Option<Option<usize>> {
+    match thread {
+        "-1" | "0" => Some(None),
+        _ => parse_hex(thread)
+            .and_then(|id| (id as usize).checked_sub(1))
+            .map(Some),
+    }
+}
+
+// Registers in the order of the `g` packet of gdb, leaving out the floating point ones.
+#[cfg(target_arch = "x86_64")]
+fn encode_registers(registers: &VcpuRegisters) -> String {
+    let regs = &registers.regs;
+    let sregs = &registers.sregs;
+    let mut reply = String::new();
+    for reg in [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ] {
+        reply.push_str(&encode_hex(&reg.to_le_bytes()));
+    }
+    for reg in [
+        regs.rflags as u32,
+        sregs.cs.selector.into(),
+        sregs.ss.selector.into(),
+        sregs.ds.selector.into(),
+        sregs.es.selector.into(),
+        sregs.fs.selector.into(),
+        sregs.gs.selector.into(),
+    ] {
+        reply.push_str(&encode_hex(&reg.to_le_bytes()));
+    }
+    reply
+}
+
+// Registers in the order of the `g` packet of gdb, leaving out the floating point ones.
+#[cfg(target_arch = "aarch64")]
+fn encode_registers(registers: &VcpuRegisters) -> String {
+    // M[3:0] of EL1h, the mode using `sp_el1` as the stack pointer.
+    const PSTATE_MODE_MASK: u64 = 0xf;
+    const PSTATE_MODE_EL1H: u64 = 0b0101;
+
+    let regs = &registers.regs;
+    let sp = if regs.pstate & PSTATE_MODE_MASK == PSTATE_MODE_EL1H {
+        registers.sp_el1
+    } else {
+        regs.sp
+    };
+    let mut reply = String::new();
+    for reg in regs.regs.iter().chain([sp, regs.pc].iter()) {
+        reply.push_str(&encode_hex(&reg.to_le_bytes()));
+    }
+    reply.push_str(&encode_hex(&(regs.pstate as u32).to_le_bytes()));
+    reply
+}
+
+enum Flow {
+    Continue,
+    Detach,
+}
+
+// What happened while the vCPUs were running.
+enum RunEvent {
+    Input(Result<Vec<u8>, RecvError>),
+    Stopped,
+    GuestExited,
+}
+
+struct Session {
+    vmm: Arc<Mutex<Vmm>>,
+    guest_memory: GuestMemoryMmap,
+    responses: Vec<Receiver<VcpuResponse>>,
+    handshake_timeout: Duration,
+    connection: Connection,
+    // vCPU the registers and memory are accessed through, as selected with `Hg`.
+    current: usize,
+    // vCPU to single-step, as selected with `Hc`, or `current` if `None`.
+    step_vcpu: Option<usize>,
+    // Breakpoints and watchpoints the vCPUs get when resumed.
+    debug: GuestDebug,
+    // Whether the vCPUs were resumed by the last `c` or `s` packet.
+    running: bool,
+    // Signal the vCPUs last stopped with.
+    stop_signal: u8,
+    // Address reported by the watchpoint the vCPUs last stopped on, if they did.
+    stop_watchpoint: Option<u64>,
+}
+
+impl Session {
+    fn new(vmm: &Arc<Mutex<Vmm>>, connection: Connection) -> SessionResult<Self> {
+        let locked_vmm = vmm.lock().unwrap();
+        Ok(Session {
+            vmm: vmm.clone(),
+            guest_memory: locked_vmm.guest_memory.clone(),
+            responses: locked_vmm
+                .vcpus_handles
+                .iter()
+                .map(|handle| handle.response_receiver().clone())
+                .collect(),
+            handshake_timeout: locked_vmm.vcpu_handshake_timeout,
+            connection,
+            current: 0,
+            step_vcpu: None,
+            debug: GuestDebug::default(),
+            running: false,
+            stop_signal: SIGTRAP,
+            stop_watchpoint: None,
+        })
+    }
+
+    fn run(mut self) -> SessionResult<()> {
+        let mut reader = self
+            .connection
+            .try_clone()
+            .map_err(SessionError::Connection)?;
+        let (bytes_sender, bytes) = unbounded();
+        thread::Builder::new()
+            .name("gdb reader".into())
+            .spawn(move || {
+                let mut buf = [0u8; 4096];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(len) => {
+                            if bytes_sender.send(buf[..len].to_vec()).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            })
+            .map_err(SessionError::Connection)?;
+
+        self.pause_vcpus()?;
+        let result = match self.serve(&bytes) {
+            // Let the guest run on whatever happened to the debugger.
+            Ok(Flow::Detach) | Err(SessionError::Connection(_)) => self.release_vcpus(),
+            Ok(Flow::Continue) => Ok(()),
+            Err(e) => Err(e),
+        };
+        self.connection.shutdown();
+        result
+    }
+
+    // Handles the packets until the debugger detaches, or `Flow::Continue` once the guest exited.
+    fn serve(&mut self, bytes: &Receiver<Vec<u8>>) -> SessionResult<Flow> {
+        let mut parser = PacketParser::default();
+        let mut inputs = Vec::new();
+        loop {
+            let received = if self.running {
+                match self.wait_running(bytes)? {
+                    RunEvent::Input(received) => received,
+                    RunEvent::Stopped => continue,
+                    RunEvent::GuestExited => return Ok(Flow::Continue),
+                }
+            } else {
+                bytes.recv()
+            };
+            let Ok(received) = received else {
+                // The debugger went away.
+                return Ok(Flow::Detach);
+            };
+
+            parser.feed(&received, &mut inputs);
+            for input in inputs.drain(..) {
+                match input {
+                    Input::Packet(packet) => {
+                        self.send(b"+")?;
+                        if let Flow::Detach = self.handle_packet(&packet)? {
+                            return Ok(Flow::Detach);
+                        }
+                    }
+                    Input::Corrupted => self.send(b"-")?,
+                    Input::Interrupt if self.running => self.stop(self.current, SIGINT, None)?,
+                    Input::Interrupt => (),
+                }
+            }
+        }
+    }
+
+    // Waits for input from the debugger while the vCPUs run, reporting their stops meanwhile.
+    fn wait_running(&mut self, bytes: &Receiver<Vec<u8>>) -> SessionResult<RunEvent> {
+        loop {
+            let responses = self.responses.clone();
+            let mut select = Select::new();
+            select.recv(bytes);
+            for response in responses.iter() {
+                select.recv(response);
+            }
+            let operation = select.select();
+            let index = operation.index();
+            if index == 0 {
+                return Ok(RunEvent::Input(operation.recv(bytes)));
+            }
+
+            let id = index - 1;
+            match operation.recv(&responses[id]) {
+                Ok(VcpuResponse::DebugExit { watchpoint }) => {
+                    self.stop(id, SIGTRAP, watchpoint)?;
+                    return Ok(RunEvent::Stopped);
+                }
+                // Left over from a handshake with the `Vmm`.
+                Ok(VcpuResponse::Paused | VcpuResponse::Resumed) => (),
+                Ok(response) => {
+                    info!("vCPU {id} stopped while debugged: {response:?}");
+                    let code = match response {
+                        VcpuResponse::Exited(code) => code,
+                        #[cfg(target_arch = "x86_64")]
+                        VcpuResponse::Halted(code) => code,
+                        _ => crate::FC_EXIT_CODE_GENERIC_ERROR,
+                    };
+                    self.running = false;
+                    self.reply(&format!("W{code:02x}"))?;
+                    return Ok(RunEvent::GuestExited);
+                }
+                Err(_) => return Ok(RunEvent::GuestExited),
+            }
+        }
+    }
+
+    // Pauses the vCPUs after one of them stopped, and reports it.
+    fn stop(&mut self, id: usize, signal: u8, watchpoint: Option<u64>) -> SessionResult<()> {
+        self.pause_vcpus()?;
+        self.running = false;
+        self.current = id;
+        self.stop_signal = signal;
+        self.stop_watchpoint = watchpoint;
+        self.reply(&self.stop_reply())
+    }
+
+    fn stop_reply(&self) -> String {
+        let watch = self.stop_watchpoint.map(|addr| {
+            let kind = self
+                .debug
+                .watchpoints
+                .iter()
+                .find(|watchpoint| {
+                    (watchpoint.addr..watchpoint.addr + watchpoint.len).contains(&addr)
+                })
+                .map_or(WatchpointKind::Write, |watchpoint| watchpoint.kind);
+            let name = match kind {
+                WatchpointKind::Write => "watch",
+                WatchpointKind::Read => "rwatch",
+                WatchpointKind::Access => "awatch",
+            };
+            format!("{name}:{addr:x};")
+        });
+        format!(
+            "T{:02x}{}thread:{:x};",
+            self.stop_signal,
+            watch.unwrap_or_default(),
+            self.current + 1
+        )
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) -> SessionResult<Flow> {
+        let Ok(packet) = std::str::from_utf8(packet) else {
+            self.reply("")?;
+            return Ok(Flow::Continue);
+        };
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => self.stop_reply(),
+            "q" => self.handle_query(args),
+            "H" => self.select_thread(args),
+            "T" => match parse_thread(args) {
+                Some(Some(id)) if id < self.responses.len() => "OK".to_string(),
+                _ => REPLY_ERROR.to_string(),
+            },
+            "g" => self.read_registers()?,
+            "m" => self.read_memory(args)?,
+            "M" => self.write_memory(args)?,
+            "Z" | "z" => self.set_breakpoint(command == "Z", args),
+            "c" => {
+                self.resume_vcpus(None)?;
+                return Ok(Flow::Continue);
+            }
+            "s" => {
+                let id = self.step_vcpu.unwrap_or(self.current);
+                self.resume_vcpus(Some(id))?;
+                return Ok(Flow::Continue);
+            }
+            "D" => {
+                self.reply("OK")?;
+                return Ok(Flow::Detach);
+            }
+            "k" => return Ok(Flow::Detach),
+            _ => String::new(),
+        };
+        self.reply(&reply)?;
+        Ok(Flow::Continue)
+    }
+
+    fn handle_query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            format!("PacketSize={PACKET_SIZE:x};hwbreak+")
+        } else if query == "Attached" {
+            "1".to_string()
+        } else if query == "C" {
+            format!("QC{:x}", self.current + 1)
+        } else if query == "fThreadInfo" {
+            let threads: Vec<String> = (1..=self.responses.len())
+                .map(|thread| format!("{thread:x}"))
+                .collect();
+            format!("m{}", threads.join(","))
+        } else if query == "sThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn select_thread(&mut self, args: &str) -> String {
+        let (operation, thread) = args.split_at(args.len().min(1));
+        match (operation, parse_thread(thread)) {
+            (_, Some(Some(id))) if id >= self.responses.len() => REPLY_ERROR.to_string(),
+            ("g", Some(id)) => {
+                if let Some(id) = id {
+                    self.current = id;
+                }
+                "OK".to_string()
+            }
+            ("c", Some(id)) => {
+                self.step_vcpu = id;
+                "OK".to_string()
+            }
+            _ => REPLY_ERROR.to_string(),
+        }
+    }
+
+    fn read_registers(&self) -> SessionResult<String> {
+        match self.request(self.current, VcpuEvent::GetRegisters)? {
+            VcpuResponse::Registers(registers) => Ok(encode_registers(&registers)),
+            response => {
+                error!(
+                    "Cannot read the registers of vCPU {}: {response:?}",
+                    self.current
+                );
+                Ok(REPLY_ERROR.to_string())
+            }
+        }
+    }
+
+    // Guest physical address `addr` maps to for the current vCPU.
+    #[cfg(target_arch = "x86_64")]
+    fn translate(&self, addr: u64) -> SessionResult<Option<u64>> {
+        match self.request(self.current, VcpuEvent::TranslateAddress(addr))? {
+            VcpuResponse::Translated(gpa) => Ok(gpa),
+            _ => Ok(None),
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn translate(&self, addr: u64) -> SessionResult<Option<u64>> {
+        Ok(Some(addr))
+    }
+
+    // Calls `access` on the guest physical address and length of each page of the range.
+    fn access_memory(
+        &self,
+        mut addr: u64,
+        len: usize,
+        mut access: impl FnMut(GuestAddress, std::ops::Range<usize>) -> bool,
+    ) -> SessionResult<bool> {
+        let mut done = 0;
+        while done < len {
+            let chunk = ((PAGE_SIZE - addr % PAGE_SIZE) as usize).min(len - done);
+            let Some(gpa) = self.translate(addr)? else {
+                return Ok(false);
+            };
+            if !access(GuestAddress(gpa), done..done + chunk) {
+                return Ok(false);
+            }
+            done += chunk;
+            addr = addr.wrapping_add(chunk as u64);
+        }
+        Ok(true)
+    }
+
+    fn read_memory(&self, args: &str) -> SessionResult<String> {
+        let Some((addr, len)) = parse_range(args) else {
+            return Ok(REPLY_ERROR.to_string());
+        };
+        // gdb asks for the rest when it gets less than it asked for.
+        let mut data = vec![0u8; len.min(PACKET_SIZE / 2)];
+        let len = data.len();
+        let read = self.access_memory(addr, len, |gpa, range| {
+            self.guest_memory.read_slice(&mut data[range], gpa).is_ok()
+        })?;
+        Ok(if read {
+            encode_hex(&data)
+        } else {
+            REPLY_FAULT.to_string()
+        })
+    }
+
+    fn write_memory(&self, args: &str) -> SessionResult<String> {
+        let Some((range, hex)) = args.split_once(':') else {
+            return Ok(REPLY_ERROR.to_string());
+        };
+        let (Some((addr, len)), Some(data)) = (parse_range(range), decode_hex(hex.as_bytes()))
+        else {
+            return Ok(REPLY_ERROR.to_string());
+        };
+        if data.len() != len {
+            return Ok(REPLY_ERROR.to_string());
+        }
+        let written = self.access_memory(addr, len, |gpa, range| {
+            self.guest_memory.write_slice(&data[range], gpa).is_ok()
+        })?;
+        Ok(if written {
+            "OK".to_string()
+        } else {
+            REPLY_FAULT.to_string()
+        })
+    }
+
+    // Handles the `Z` and `z` packets, whose arguments are `type,addr,kind`. Both software (type
+    // 0) and hardware (type 1) breakpoints are hardware ones, the write, read and access
+    // watchpoints (types 2 to 4) watch `kind` bytes. The vCPUs get them when resumed.
+    fn set_breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (
+            fields.next(),
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(parse_hex),
+        ) else {
+            return String::new();
+        };
+
+        let mut debug = self.debug.clone();
+        let watchpoint_kind = match kind {
+            "0" | "1" => {
+                debug
+                    .hw_breakpoints
+                    .retain(|breakpoint| *breakpoint != addr);
+                if insert {
+                    debug.hw_breakpoints.push(addr);
+                }
+                None
+            }
+            "2" => Some(WatchpointKind::Write),
+            "3" => Some(WatchpointKind::Read),
+            "4" => Some(WatchpointKind::Access),
+            _ => return String::new(),
+        };
+        if let Some(kind) = watchpoint_kind {
+            let watchpoint = Watchpoint { addr, len, kind };
+            debug.watchpoints.retain(|known| *known != watchpoint);
+            if insert {
+                debug.watchpoints.push(watchpoint);
+            }
+        }
+
+        match debug.check() {
+            Ok(()) => {
+                self.debug = debug;
+                "OK".to_string()
+            }
+            Err(GuestDebugError::NoDebugRegisters) => REPLY_NO_SPACE.to_string(),
+            Err(e) => {
+                warn!("Cannot set the gdb breakpoint {args}: {e}");
+                REPLY_ERROR.to_string()
+            }
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> SessionResult<()> {
+        self.connection
+            .write_all(bytes)
+            .map_err(SessionError::Connection)
+    }
+
+    fn reply(&mut self, data: &str) -> SessionResult<()> {
+        let packet = format!("${data}#{:02x}", packet_checksum(data.as_bytes()));
+        self.send(packet.as_bytes())
+    }
+
+    fn send_event(&self, id: usize, event: VcpuEvent) -> SessionResult<()> {
+        self.vmm.lock().unwrap().vcpus_handles[id]
+            .send_event(event)
+            .map_err(|e| SessionError::Vcpu(id, format!("{e:?}")))
+    }
+
+    // Waits for the vCPU to answer. A vCPU hitting a breakpoint while the others are being paused
+    // reports it before handling the next event, it's paused anyway.
+    fn recv_response(&self, id: usize) -> SessionResult<VcpuResponse> {
+        loop {
+            match self.responses[id].recv_timeout(self.handshake_timeout) {
+                Ok(VcpuResponse::DebugExit { .. }) => (),
+                Ok(response) => return Ok(response),
+                Err(e) => return Err(SessionError::Vcpu(id, e.to_string())),
+            }
+        }
+    }
+
+    fn request(&self, id: usize, event: VcpuEvent) -> SessionResult<VcpuResponse> {
+        self.send_event(id, event)?;
+        self.recv_response(id)
+    }
+
+    fn expect_response(&self, id: usize, expected: VcpuResponse) -> SessionResult<()> {
+        match self.recv_response(id)? {
+            response if response == expected => Ok(()),
+            response => Err(SessionError::Vcpu(
+                id,
+                format!("unexpected response {response:?}"),
+            )),
+        }
+    }
+
+    fn pause_vcpus(&self) -> SessionResult<()> {
+        for id in 0..self.responses.len() {
+            self.send_event(id, VcpuEvent::Pause)?;
+        }
+        for id in 0..self.responses.len() {
+            self.expect_response(id, VcpuResponse::Paused)?;
+        }
+        Ok(())
+    }
+
+    // Installs the breakpoints in the paused vCPUs, and resumes them, or only `step` to
+    // single-step it.
+    fn resume_vcpus(&mut self, step: Option<usize>) -> SessionResult<()> {
+        for id in 0..self.responses.len() {
+            let debug = GuestDebug {
+                single_step: step == Some(id),
+                ..self.debug.clone()
+            };
+            match self.request(id, VcpuEvent::SetDebug(debug))? {
+                VcpuResponse::DebugSet => (),
+                response => {
+                    return Err(SessionError::Vcpu(
+                        id,
+                        format!("cannot set the breakpoints: {response:?}"),
+                    ))
+                }
+            }
+        }
+
+        let resumed: Vec<usize> = match step {
+            Some(id) => vec![id],
+            None => (0..self.responses.len()).collect(),
+        };
+        for id in resumed.iter() {
+            self.send_event(*id, VcpuEvent::Resume)?;
+        }
+        for id in resumed {
+            self.expect_response(id, VcpuResponse::Resumed)?;
+        }
+        self.running = true;
+        Ok(())
+    }
+
+    // Removes the breakpoints and lets the guest run freely.
+    fn release_vcpus(&mut self) -> SessionResult<()> {
+        if self.running {
+            self.pause_vcpus()?;
+        }
+        self.debug = GuestDebug::default();
+        self.resume_vcpus(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // gdb's end of a session, over a socket pair.
+    #[cfg(target_arch = "x86_64")]
+    struct Client {
+        stream: UnixStream,
+        parser: PacketParser,
+        inputs: Vec<Input>,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    impl Client {
+        // Sends `packet`, returning the reply.
+        fn command(&mut self, packet: &str) -> String {
+            let framed = format!("${packet}#{:02x}", packet_checksum(packet.as_bytes()));
+            self.stream.write_all(framed.as_bytes()).unwrap();
+            let mut buf = [0u8; 4096];
+            while self.inputs.is_empty() {
+                let len = self.stream.read(&mut buf).unwrap();
+                assert_ne!(len, 0);
+                self.parser.feed(&buf[..len], &mut self.inputs);
+            }
+            match self.inputs.remove(0) {
+                Input::Packet(reply) => String::from_utf8(reply).unwrap(),
+                input => panic!("unexpected reply {input:?}"),
+            }
+        }
+
+        // The instruction pointer of the current vCPU.
+        fn rip(&mut self) -> u64 {
+            let registers = decode_hex(self.command("g").as_bytes()).unwrap();
+            u64::from_le_bytes(registers[16 * 8..17 * 8].try_into().unwrap())
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_session() {
+        use crate::builder::tests::{
+            test_guest_resources, wait_until, PortRecorder, TEST_GUEST_COUNTER, TEST_GUEST_ENTRY,
+        };
+        use polly::event_manager::EventManager;
+
+        // The loop of the test guest, and the instruction the `inc` is followed by.
+        const LOOP: u64 = TEST_GUEST_ENTRY + 5;
+        const AFTER_INC: u64 = LOOP + 3;
+
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let recorder = Arc::new(PortRecorder::default());
+        let vm_resources = test_guest_resources(recorder.clone(), &dir.as_path().join("console"));
+        let mut event_manager = EventManager::new().unwrap();
+        let vmm = crate::builder::build_microvm(&vm_resources, &mut event_manager, None).unwrap();
+        assert!(wait_until(|| recorder.last.lock().unwrap().is_some()));
+
+        let (stream, server) = UnixStream::pair().unwrap();
+        let session = Session::new(&vmm, Connection::Unix(server)).unwrap();
+        let server = thread::spawn(move || session.run());
+        let mut client = Client {
+            stream,
+            parser: PacketParser::default(),
+            inputs: Vec::new(),
+        };
+        assert_eq!(client.command("?"), "T05thread:1;");
+
+        // The vCPU is paused in the 17 bytes of its code, its counter can be read and written.
+        let rip = client.rip();
+        assert!(
+            (TEST_GUEST_ENTRY..TEST_GUEST_ENTRY + 17).contains(&rip),
+            "{rip:#x}"
+        );
+        let counter: u64 = vmm
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .read_obj(GuestAddress(TEST_GUEST_COUNTER))
+            .unwrap();
+        assert_eq!(
+            client.command(&format!("m{TEST_GUEST_COUNTER:x},8")),
+            encode_hex(&counter.to_le_bytes())
+        );
+        assert_eq!(
+            client.command(&format!("M{TEST_GUEST_COUNTER:x},8:0000000001000000")),
+            "OK"
+        );
+        let counter: u64 = vmm
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .read_obj(GuestAddress(TEST_GUEST_COUNTER))
+            .unwrap();
+        assert_eq!(counter, 1 << 32);
+        assert_eq!(client.command("m0,4,"), REPLY_ERROR);
+
+        // Continuing stops on the breakpoint at the top of the loop.
+        assert_eq!(client.command(&format!("Z1,{LOOP:x},1")), "OK");
+        assert_eq!(client.command("c"), "T05thread:1;");
+        assert_eq!(client.rip(), LOOP);
+
+        // Single-stepping runs the `inc` alone.
+        assert_eq!(client.command(&format!("z1,{LOOP:x},1")), "OK");
+        assert_eq!(client.command("s"), "T05thread:1;");
+        assert_eq!(client.rip(), AFTER_INC);
+        let counter: u64 = vmm
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .read_obj(GuestAddress(TEST_GUEST_COUNTER))
+            .unwrap();
+        assert!(counter > 1 << 32);
+
+        // Detaching lets the guest run again.
+        assert_eq!(client.command("D"), "OK");
+        server.join().unwrap().unwrap();
+        let last = *recorder.last.lock().unwrap();
+        assert!(wait_until(|| *recorder.last.lock().unwrap() != last));
+        vmm.lock().unwrap().teardown().unwrap();
+    }
+
+    #[test]
+    fn test_packet_parser() {
+        let mut parser = PacketParser::default();
+        let mut inputs = Vec::new();
+
+        // Packets may be split anywhere, acknowledgements are skipped.
+        parser.feed(b"+$g#6", &mut inputs);
+        assert!(inputs.is_empty());
+        parser.feed(b"7+$m1000,4#", &mut inputs);
+        parser.feed(b"8e$?#00\x03", &mut inputs);
+        assert_eq!(
+            inputs,
+            vec![
+                Input::Packet(b"g".to_vec()),
+                Input::Packet(b"m1000,4".to_vec()),
+                Input::Corrupted,
+                Input::Interrupt,
+            ]
+        );
+
+        inputs.clear();
+        parser.feed(b"$", &mut inputs);
+        parser.feed(&vec![b'0'; PACKET_SIZE + 1], &mut inputs);
+        assert_eq!(inputs, vec![Input::Corrupted]);
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(encode_hex(&[0x00, 0x7f, 0xff]), "007fff");
+        assert_eq!(decode_hex(b"007fFF"), Some(vec![0x00, 0x7f, 0xff]));
+        assert_eq!(decode_hex(b"0"), None);
+        assert_eq!(decode_hex(b"0g"), None);
+        assert_eq!(packet_checksum(b"OK"), 0x9a);
+
+        assert_eq!(parse_range("ffff8000,40"), Some((0xffff_8000, 0x40)));
+        assert_eq!(parse_range("ffff8000"), None);
+        assert_eq!(parse_thread("-1"), Some(None));
+        assert_eq!(parse_thread("0"), Some(None));
+        assert_eq!(parse_thread("a"), Some(Some(9)));
+        assert_eq!(parse_thread("x"), None);
+    }
+}
@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Paravirtual hypercalls the guest makes to signal the host without going through a device.
+//!
+//! Only aarch64 guests on Linux hosts can make them, as SMCCC fast calls (`hvc #0`, or `smc #0`)
+//! in the OEM service range: function ID `0x8300_0000 | id` for the SMC32 convention or
+//! `0xc300_0000 | id` for SMC64. The arguments are passed in `x1`..`x6` and the handler's return
+//! value is handed back in `x0`. Calls to an `id` without a handler return `NOT_SUPPORTED` (-1),
+//! like KVM does for the calls it doesn't implement. KVM doesn't implement any call of the OEM
+//! range, so these never shadow its own hypercalls. On x86_64, KVM handles every `vmcall` itself
+//! and can't forward them to the VMM.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// Highest hypercall id, SMCCC function numbers are 16 bits wide.
+pub const MAX_HYPERCALL_ID: u32 = 0xffff;
+/// Hypercall ids up to this one are reserved for libkrun's own use.
+pub const MAX_RESERVED_HYPERCALL_ID: u32 = 0xff;
+
+/// Host side of a hypercall.
+pub trait HypercallHandler: Send + Sync {
+    /// Handles hypercall `id` made by vCPU `vcpu` with `args`, returning the value the guest gets
+    /// back. It runs on the vCPU thread, which stays out of the guest until it returns.
+    fn handle(&self, id: u32, vcpu: u8, args: [u64; 6]) -> u64;
+}
+
+/// Errors registering a hypercall handler.
+#[derive(Debug, Eq, PartialEq)]
+pub enum HypercallError {
+    /// The host can't forward hypercalls to the VMM.
+    Unsupported,
+    /// The id is above `MAX_HYPERCALL_ID`.
+    OutOfRange(u32),
+    /// The id is in the range reserved for libkrun.
+    Reserved(u32),
+    /// A handler is already registered for the id.
+    InUse(u32),
+}
+
+impl fmt::Display for HypercallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::HypercallError::*;
+        match self {
+            Unsupported => write!(f, "Hypercalls are not supported on this host"),
+            OutOfRange(id) => write!(f, "Hypercall id {id:#x} is above {MAX_HYPERCALL_ID:#x}"),
+            Reserved(id) => write!(f, "Hypercall id {id:#x} is reserved"),
+            InUse(id) => write!(f, "Hypercall id {id:#x} already has a handler"),
+        }
+    }
+}
+
+/// Handlers of the hypercalls of a VM, shared by its vCPUs.
+#[derive(Default)]
+pub struct Hypercalls {
+    handlers: RwLock<HashMap<u32, Arc<dyn HypercallHandler>>>,
+}
+
+impl Hypercalls {
+    /// Makes `handler` handle the hypercalls with this `id`.
+    pub fn register(
+        &self,
+        id: u32,
+        handler: Arc<dyn HypercallHandler>,
+    ) -> Result<(), HypercallError> {
+        if id > MAX_HYPERCALL_ID {
+            return Err(HypercallError::OutOfRange(id));
+        }
+        if id <= MAX_RESERVED_HYPERCALL_ID {
+            return Err(HypercallError::Reserved(id));
+        }
+
+        let mut handlers = self.handlers.write().unwrap();
+        if handlers.contains_key(&id) {
+            return Err(HypercallError::InUse(id));
+        }
+        handlers.insert(id, handler);
+        Ok(())
+    }
+
+    /// Runs the handler of hypercall `id`, returning `None` if it has none.
+    pub fn dispatch(&self, id: u32, vcpu: u8, args: [u64; 6]) -> Option<u64> {
+        // Don't hold the lock while the handler runs, it may take a while.
+        let handler = self.handlers.read().unwrap().get(&id).cloned()?;
+        Some(handler.handle(id, vcpu, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sum;
+
+    impl HypercallHandler for Sum {
+        fn handle(&self, id: u32, vcpu: u8, args: [u64; 6]) -> u64 {
+            u64::from(id) + u64::from(vcpu) + args.iter().sum::<u64>()
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let hypercalls = Hypercalls::default();
+        assert_eq!(
+            hypercalls.register(0x10, Arc::new(Sum)),
+            Err(HypercallError::Reserved(0x10))
+        );
+        assert_eq!(
+            hypercalls.register(0x1_0000, Arc::new(Sum)),
+            Err(HypercallError::OutOfRange(0x1_0000))
+        );
+        assert_eq!(hypercalls.register(0x100, Arc::new(Sum)), Ok(()));
+        assert_eq!(
+            hypercalls.register(0x100, Arc::new(Sum)),
+            Err(HypercallError::InUse(0x100))
+        );
+
+        assert_eq!(
+            hypercalls.dispatch(0x100, 1, [1, 2, 3, 0, 0, 0]),
+            Some(0x107)
+        );
+        assert_eq!(hypercalls.dispatch(0x101, 0, [0; 6]), None);
+    }
+}
@@ -15,12 +15,22 @@ extern crate log;
 
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
+#[cfg(target_os = "linux")]
+mod core_dump;
 pub(crate) mod device_manager;
+/// Remote debugging of the guest with gdb.
+#[cfg(all(feature = "gdb", target_os = "linux"))]
+pub mod gdb;
+/// Paravirtual hypercalls dispatched to handlers registered by the embedder.
+pub mod hypercall;
 /// Resource store for configured microVM resources.
 pub mod resources;
 /// Signal handling utilities.
 #[cfg(target_os = "linux")]
 pub mod signal_handler;
+/// Snapshots of paused microVMs, saved to disk and restored in another process.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub mod snapshot;
 /// Wrappers over structures used to configure the VMM.
 pub mod vmm_config;
 
@@ -36,35 +46,60 @@ mod terminal;
 pub use hvf::MemoryMapping;
 #[cfg(target_os = "macos")]
 use macos::vstate;
+pub use vstate::VcpuStats;
+#[cfg(target_os = "linux")]
+pub use vstate::{
+    DirtyRegion, KvmIoExit, KvmMmioExit, KvmRunSnapshot, VcpuEntryFailure, VcpuRegisters, VcpuState,
+};
 
 use std::fmt::{Display, Formatter};
-use std::io;
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsFd, BorrowedFd};
 use std::os::unix::io::AsRawFd;
-use std::sync::{Arc, Mutex};
+use std::path::Path;
 #[cfg(target_os = "linux")]
-use std::time::Duration;
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
+use crate::hypercall::{HypercallError, HypercallHandler};
+#[cfg(target_os = "linux")]
+use crate::signal_handler::GuestSignalAction;
 use crate::terminal::term_set_canonical_mode;
+#[cfg(not(feature = "tee"))]
+use crate::vmm_config::balloon::{balloon_target_pages, BalloonConfigError};
+use crate::vmm_config::machine_config::MemoryAdvice;
+#[cfg(feature = "net")]
+use crate::vmm_config::net::NetworkInterfaceError;
+#[cfg(all(target_os = "linux", feature = "net"))]
+use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
+use crate::vmm_config::rtc::RtcConfig;
+use crate::vmm_config::vsock::GuestCid;
 #[cfg(target_os = "linux")]
 use crate::vstate::VcpuEvent;
 use crate::vstate::{Vcpu, VcpuHandle, VcpuResponse, Vm};
 
 use arch::ArchMemoryInfo;
+use arch::BootEntropy;
+use arch::CpuTopology;
 use arch::DeviceType;
 use arch::InitrdConfig;
 #[cfg(target_os = "macos")]
 use crossbeam_channel::Sender;
-use devices::virtio::VmmExitObserver;
+use devices::virtio::{VmmExitObserver, VmmQuiesceObserver};
 use devices::BusDevice;
 use kernel::cmdline::Cmdline as KernelCmdline;
 use polly::event_manager::{self, EventManager, Subscriber};
 use utils::epoll::{EpollEvent, EventSet};
 use utils::eventfd::EventFd;
 use utils::time::TimestampUs;
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 /// Success exit code.
 pub const FC_EXIT_CODE_OK: u8 = 0;
@@ -83,6 +118,131 @@ pub const FC_EXIT_CODE_BAD_CONFIGURATION: u8 = 152;
 /// Command line arguments parsing error.
 pub const FC_EXIT_CODE_ARG_PARSING: u8 = 153;
 
+/// How long the VMM waits for a vCPU to acknowledge an event by default, see
+/// `Vmm::set_vcpu_handshake_timeout`.
+pub const DEFAULT_VCPU_HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(1000);
+/// How long `Vmm::snapshot` waits for the requests in flight in the devices to complete.
+pub const SNAPSHOT_QUIESCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HVF VMs are created with the default 36-bit IPA size, so guest windows can't go beyond it.
+#[cfg(target_os = "macos")]
+const HVF_DEFAULT_IPA_LIMIT: u64 = 1 << 36;
+
+/// vCPUs a non-maskable interrupt injected with `Vmm::send_nmi` is delivered to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NmiTarget {
+    /// Every vCPU of the microVM.
+    AllVcpus,
+    /// Only the vCPU with this index.
+    Vcpu(usize),
+}
+
+/// Where the in-kernel interrupt controller delivers a GSI.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GsiDestination {
+    /// A pin of an interrupt controller. On x86_64 chips 0 and 1 are the master and slave PICs
+    /// and chip 2 is the IOAPIC. On aarch64 chip 0 is the GIC, and pin 0 is its first SPI.
+    Irqchip { chip: u32, pin: u32 },
+    /// An MSI message, i.e. a write of `data` to `address`.
+    Msi { address: u64, data: u32 },
+}
+
+/// An entry of the GSI routing table, as set with `Vmm::set_gsi_routing`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GsiRoute {
+    /// Global system interrupt number devices raise through their irqfd.
+    pub gsi: u32,
+    pub destination: GsiDestination,
+}
+
+/// Host memory used by the guest memory regions, as returned by `Vmm::memory_metrics`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryMetrics {
+    /// Size of the guest memory mapped by the VMM, in bytes.
+    pub committed: u64,
+    /// Guest memory currently backed by host RAM, in bytes.
+    pub resident: u64,
+    /// Number of guest memory pages faulted in so far, including the ones since swapped out. macOS
+    /// can't tell swapped out pages apart from untouched ones, so only resident pages are counted
+    /// there.
+    pub faulted_pages: u64,
+}
+
+/// First bytes of a file written by `Vmm::dump_guest_memory`.
+pub const GUEST_MEMORY_DUMP_MAGIC: &[u8; 8] = b"KRUNMEM\0";
+/// Version of the format of the files written by `Vmm::dump_guest_memory`.
+pub const GUEST_MEMORY_DUMP_VERSION: u32 = 1;
+// Size of the pieces the guest memory is copied to dumps in.
+const GUEST_MEMORY_DUMP_CHUNK_SIZE: usize = 1 << 20;
+
+/// Layout of the guest physical address space, as returned by `Vmm::memory_info`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryInfo {
+    /// Size of the guest RAM, in bytes.
+    pub ram_size: u64,
+    /// Guest RAM ranges, as `(start, size)` pairs ordered by address.
+    pub ram_ranges: Vec<(u64, u64)>,
+    /// Guest physical ranges left out of RAM, as `(start, size)` pairs.
+    pub reserved_ranges: Vec<(u64, u64)>,
+    /// Start and size of the shared memory region the GPU blobs and virtio-fs DAX windows are
+    /// mapped in, empty if there's none.
+    pub shm_range: (u64, u64),
+    /// Start and size of the hole below 4 GiB the MMIO devices live in.
+    #[cfg(target_arch = "x86_64")]
+    pub mmio_gap: (u64, u64),
+    /// Start of the region the MMIO devices live in, below the RAM.
+    #[cfg(target_arch = "aarch64")]
+    pub mmio_base: u64,
+}
+
+/// Guest memory region mapped from the file returned by `Vmm::guest_memory_fd`.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GuestMemoryFileRegion {
+    pub guest_addr: GuestAddress,
+    pub len: u64,
+    /// Where the region starts in the file.
+    pub file_offset: u64,
+}
+
+/// Monotonic timestamps of the milestones the microVM went through while booting, as returned by
+/// `Vmm::boot_timings`. The ones it didn't reach yet are `None`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BootTimings {
+    /// When `builder::build_microvm` started building the microVM.
+    pub build_start: Instant,
+    /// When the KVM VM was created, right after the guest memory was allocated.
+    pub vm_created: Instant,
+    /// When the vCPU threads were spawned, just before `Vmm::start_vcpus` returns.
+    pub vcpus_started: Option<Instant>,
+    /// When `Vmm::resume_vcpus` first let the vCPUs run, which `start_vcpus` does itself unless
+    /// they are started paused.
+    pub vcpus_resumed: Option<Instant>,
+    /// When the guest first signaled it finished booting, by writing 123 to port 0x3f0 on x86_64
+    /// or to address 0x4000_0000 on aarch64, e.g. from its init or an agent once userspace is up.
+    /// Linux only.
+    pub guest_boot_complete: Option<Instant>,
+}
+
+/// State of the microVM after `Vmm::run_once` returns.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RunOutcome {
+    /// The microVM is still running, `run_once` should be called again.
+    Running,
+    /// The microVM requested to exit with this code. Its vCPU threads aren't joined, the caller
+    /// is expected to exit the process once it's done with its own work.
+    Exited(i32),
+}
+
+/// How the microVM stopped after `Vmm::shutdown`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShutdownOutcome {
+    /// The guest shut down within the grace period, with this exit code.
+    Graceful(i32),
+    /// The guest didn't shut down in time, or couldn't be asked to, and was stopped.
+    Forced,
+}
+
 /// Errors associated with the VMM internal logic. These errors cannot be generated by direct user
 /// input, but can result from bad configuration of the host (for example if Firecracker doesn't
 /// have permissions to open the KVM fd).
@@ -100,8 +260,13 @@ pub enum Error {
     EventManager(event_manager::Error),
     /// I8042 Error.
     I8042Error(devices::legacy::I8042DeviceError),
+    /// The i8042 keyboard buffer has no room for the scancodes.
+    #[cfg(target_arch = "x86_64")]
+    I8042BufferFull,
     /// Cannot access kernel file.
     KernelFile(io::Error),
+    /// The kernel image can't be booted.
+    KernelLoader(kernel::loader::Error),
     /// Cannot open /dev/kvm. Either the host does not have KVM or Firecracker does not have
     /// permission to open the file descriptor.
     KvmContext(vstate::Error),
@@ -122,8 +287,12 @@ pub enum Error {
     VcpuEvent(vstate::Error),
     /// Cannot create a vCPU handle.
     VcpuHandle(vstate::Error),
-    /// vCPU resume failed.
-    VcpuResume,
+    /// The vCPU with this index failed to resume, for this reason.
+    VcpuResume { index: usize, reason: String },
+    /// The vCPUs with these indices failed to pause, the other ones are paused.
+    VcpuPause(Vec<usize>),
+    /// The thread of the vCPU with this index couldn't be joined, for this reason.
+    VcpuJoin { index: usize, reason: String },
     /// Cannot spawn a new Vcpu thread.
     VcpuSpawn(std::io::Error),
     /// Vm error.
@@ -132,6 +301,86 @@ pub enum Error {
     VmmObserverInit(utils::errno::Error),
     /// Error thrown by observer object on Vmm teardown.
     VmmObserverTeardown(utils::errno::Error),
+    /// There's no free guest physical window large enough for the requested size.
+    #[cfg(target_os = "macos")]
+    GuestWindowExhausted(u64),
+    /// The requested guest window size is zero.
+    #[cfg(target_os = "macos")]
+    InvalidGuestWindowSize,
+    /// The memory advice is not supported on this host.
+    UnsupportedMemoryAdvice(MemoryAdvice),
+    /// Applying the memory advice failed for the guest memory regions starting at these addresses.
+    AdviseMemory(MemoryAdvice, Vec<(GuestAddress, io::Error)>),
+    /// Cannot tell which pages of the guest memory are resident.
+    MemoryMetrics(io::Error),
+    /// Cannot write the guest memory dump.
+    DumpGuestMemory(io::Error),
+    /// Cannot change the log filter.
+    LogFilter(utils::logger::Error),
+    /// Cannot register the hypercall handler.
+    Hypercall(HypercallError),
+    /// There's no vCPU with this index.
+    InvalidVcpuIndex(usize),
+    /// NMI injection is not supported on this host or architecture.
+    NmiUnsupported,
+    /// GSI routing can't be configured on this host.
+    #[cfg(target_os = "macos")]
+    GsiRoutingUnsupported,
+    /// The vCPU with this index isn't running.
+    VcpuNotRunning(usize),
+    /// The vCPU with this index isn't paused.
+    VcpuNotPaused(usize),
+    /// The devices still had requests in flight after this long.
+    QuiesceTimeout(Duration),
+    /// Injecting an NMI into the vCPU with this index failed.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    VcpuNmi(usize, kvm_ioctls::Error),
+    /// Reading the registers of the vCPU with this index failed.
+    #[cfg(target_os = "linux")]
+    VcpuRegisters(usize, kvm_ioctls::Error),
+    /// Cannot write the core dump.
+    #[cfg(target_os = "linux")]
+    CoreDump(io::Error),
+    /// Cannot take a snapshot of the microVM.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    Snapshot(snapshot::Error),
+    /// Cannot install the handlers for the signals forwarded to the guest.
+    #[cfg(target_os = "linux")]
+    GuestSignalHandler(utils::errno::Error),
+    /// The guest has no device to deliver a graceful shutdown request to.
+    #[cfg(not(target_arch = "x86_64"))]
+    NoShutdownDevice,
+    /// The guest has no virtio-console channel.
+    NoConsoleChannel,
+    /// The kernel command line, including its nul terminator, exceeds the size the guest
+    /// can receive.
+    KernelCmdlineTooLarge(usize, usize),
+    /// The guest RAM doesn't cover the range of this many bytes at this address.
+    GuestMemoryAccess(GuestAddress, usize),
+    /// The balloon target is invalid.
+    #[cfg(not(feature = "tee"))]
+    BalloonConfig(BalloonConfigError),
+    /// The guest didn't bring the balloon to its target, in pages, in time; it has this many.
+    #[cfg(not(feature = "tee"))]
+    BalloonTimeout { target: u64, actual: u64 },
+    /// Cannot unplug the device.
+    #[cfg(target_os = "linux")]
+    HotUnplug(device_manager::mmio::Error),
+    /// Cannot plug the device in.
+    #[cfg(target_os = "linux")]
+    HotPlug(device_manager::mmio::Error),
+    /// Cannot create the hot-plugged network device.
+    #[cfg(all(target_os = "linux", feature = "net"))]
+    HotPlugNet(NetworkInterfaceError),
+    /// Devices can't be plugged in or unplugged on this host.
+    #[cfg(target_os = "macos")]
+    HotplugUnsupported,
+    /// Cannot update the network interface.
+    #[cfg(feature = "net")]
+    UpdateNet(NetworkInterfaceError),
+    /// The vCPU with this index didn't switch to the updated MMIO bus.
+    #[cfg(target_os = "linux")]
+    VcpuMmioBus(usize),
 }
 
 impl Display for Error {
@@ -145,7 +394,10 @@ impl Display for Error {
             EventFd(e) => write!(f, "Event fd error: {e}"),
             EventManager(e) => write!(f, "Event manager error: {e:?}"),
             I8042Error(e) => write!(f, "I8042 error: {e}"),
+            #[cfg(target_arch = "x86_64")]
+            I8042BufferFull => write!(f, "The i8042 keyboard buffer is full"),
             KernelFile(e) => write!(f, "Cannot access kernel file: {e}"),
+            KernelLoader(e) => write!(f, "Invalid kernel image: {e}"),
             KvmContext(e) => write!(f, "Failed to validate KVM support: {e:?}"),
             #[cfg(target_arch = "x86_64")]
             LegacyIOBus(e) => write!(f, "Cannot add devices to the legacy I/O Bus. {e}"),
@@ -156,7 +408,9 @@ impl Display for Error {
             Vcpu(e) => write!(f, "Vcpu error: {e}"),
             VcpuEvent(e) => write!(f, "Cannot send event to vCPU. {e:?}"),
             VcpuHandle(e) => write!(f, "Cannot create a vCPU handle. {e}"),
-            VcpuResume => write!(f, "vCPUs resume failed."),
+            VcpuResume { index, reason } => write!(f, "vCPU {index} resume failed: {reason}"),
+            VcpuPause(ids) => write!(f, "vCPUs {ids:?} failed to pause."),
+            VcpuJoin { index, reason } => write!(f, "vCPU {index} join failed: {reason}"),
             VcpuSpawn(e) => write!(f, "Cannot spawn Vcpu thread: {e}"),
             Vm(e) => write!(f, "Vm error: {e}"),
             VmmObserverInit(e) => write!(
@@ -166,22 +420,130 @@ impl Display for Error {
             VmmObserverTeardown(e) => {
                 write!(f, "Error thrown by observer object on Vmm teardown: {e}")
             }
+            #[cfg(target_os = "macos")]
+            GuestWindowExhausted(len) => write!(
+                f,
+                "No free guest physical window of {len} bytes is available"
+            ),
+            #[cfg(target_os = "macos")]
+            InvalidGuestWindowSize => write!(f, "Guest window size must be greater than zero"),
+            UnsupportedMemoryAdvice(advice) => {
+                write!(f, "Memory advice {advice} is not supported on this host")
+            }
+            AdviseMemory(advice, errors) => {
+                write!(f, "Cannot apply {advice} to guest memory:")?;
+                for (addr, e) in errors {
+                    write!(f, " region at {:#x}: {e};", addr.0)?;
+                }
+                Ok(())
+            }
+            MemoryMetrics(e) => write!(f, "Cannot get the guest memory metrics: {e}"),
+            DumpGuestMemory(e) => write!(f, "Cannot dump the guest memory: {e}"),
+            LogFilter(e) => write!(f, "Cannot change the log filter: {e}"),
+            Hypercall(e) => write!(f, "Cannot register the hypercall handler: {e}"),
+            InvalidVcpuIndex(id) => write!(f, "There is no vCPU with index {id}"),
+            NmiUnsupported => write!(f, "NMI injection is not supported on this host"),
+            #[cfg(target_os = "macos")]
+            GsiRoutingUnsupported => write!(f, "GSI routing is not supported on this host"),
+            VcpuNotRunning(id) => write!(f, "vCPU {id} is not running"),
+            VcpuNotPaused(id) => write!(f, "vCPU {id} is not paused"),
+            QuiesceTimeout(timeout) => {
+                write!(
+                    f,
+                    "The devices still had requests in flight after {timeout:?}"
+                )
+            }
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            VcpuNmi(id, e) => write!(f, "Cannot inject an NMI into vCPU {id}: {e}"),
+            #[cfg(target_os = "linux")]
+            VcpuRegisters(id, e) => write!(f, "Cannot read the registers of vCPU {id}: {e}"),
+            #[cfg(target_os = "linux")]
+            CoreDump(e) => write!(f, "Cannot write the core dump: {e}"),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            Snapshot(e) => write!(f, "Cannot snapshot the microVM: {e}"),
+            #[cfg(target_os = "linux")]
+            GuestSignalHandler(e) => write!(f, "Cannot forward signals to the guest: {e}"),
+            #[cfg(not(target_arch = "x86_64"))]
+            NoShutdownDevice => write!(f, "The guest has no graceful shutdown device"),
+            NoConsoleChannel => write!(f, "The guest has no console channel"),
+            KernelCmdlineTooLarge(len, limit) => write!(
+                f,
+                "Kernel command line is {len} bytes, the guest accepts at most {limit}"
+            ),
+            GuestMemoryAccess(addr, len) => {
+                write!(f, "Cannot access {len} bytes of guest RAM at {:#x}", addr.0)
+            }
+            #[cfg(not(feature = "tee"))]
+            BalloonConfig(e) => write!(f, "Invalid balloon configuration: {e}"),
+            #[cfg(not(feature = "tee"))]
+            BalloonTimeout { target, actual } => write!(
+                f,
+                "The guest has {actual} pages in the balloon instead of {target}"
+            ),
+            #[cfg(target_os = "linux")]
+            HotUnplug(e) => write!(f, "Cannot unplug the device: {e}"),
+            #[cfg(target_os = "linux")]
+            HotPlug(e) => write!(f, "Cannot plug the device in: {e}"),
+            #[cfg(all(target_os = "linux", feature = "net"))]
+            HotPlugNet(e) => write!(f, "Cannot create the network device: {e}"),
+            #[cfg(target_os = "macos")]
+            HotplugUnsupported => write!(f, "Device hotplug is not supported on this host"),
+            #[cfg(feature = "net")]
+            UpdateNet(e) => write!(f, "Cannot update the network interface: {e}"),
+            #[cfg(target_os = "linux")]
+            VcpuMmioBus(id) => write!(f, "vCPU {id} didn't switch to the updated MMIO bus"),
         }
     }
 }
 
 /// Trait for objects that need custom initialization and teardown during the Vmm lifetime.
 pub trait VmmEventsObserver {
-    /// This function will be called during microVm boot.
+    /// This function will be called during microVm boot, before the vcpu threads are spawned.
+    /// An error aborts the boot.
     fn on_vmm_boot(&mut self) -> std::result::Result<(), utils::errno::Error> {
         Ok(())
     }
-    /// This function will be called on microVm teardown.
+    /// This function will be called once, when the microVm stops or is torn down, after the
+    /// exit observers.
     fn on_vmm_stop(&mut self) -> std::result::Result<(), utils::errno::Error> {
         Ok(())
     }
+    /// This function will be called once the vcpu threads are running and can reach the MMIO
+    /// bus. They only wait for it to return before entering the guest on Linux, or on macOS if
+    /// the microVM is started paused: otherwise the guest may already be running.
+    fn on_vcpus_ready(&mut self) {}
+    /// This function will be called when the guest reports a panic through the pvpanic device,
+    /// see `VmResources::set_pvpanic`.
+    fn on_guest_panic(&mut self, _reason: GuestPanic) {}
 }
 
+/// Panic events the guest raised through the pvpanic device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GuestPanic {
+    /// The guest kernel panicked.
+    pub panicked: bool,
+    /// The guest kernel panicked and loaded a crash kernel, which is now capturing the dump.
+    pub crash_loaded: bool,
+}
+
+#[cfg(any(target_arch = "aarch64", test))]
+impl GuestPanic {
+    fn from_events(events: u8) -> Self {
+        GuestPanic {
+            panicked: events & devices::legacy::PVPANIC_PANICKED != 0,
+            crash_loaded: events & devices::legacy::PVPANIC_CRASH_LOADED != 0,
+        }
+    }
+}
+
+/// Length of the longest kernel command line the guest accepts, nul terminator included. SEV
+/// guests get a fixed size command line area, everyone else is only bounded by the architecture
+/// limit.
+#[cfg(all(target_arch = "x86_64", feature = "tee"))]
+pub const KERNEL_CMDLINE_LIMIT: usize = arch::x86_64::layout::CMDLINE_SEV_SIZE;
+#[cfg(not(all(target_arch = "x86_64", feature = "tee")))]
+pub const KERNEL_CMDLINE_LIMIT: usize = arch::CMDLINE_MAX_SIZE;
+
 /// Shorthand result type for internal VMM commands.
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -197,6 +559,68 @@ pub struct Vmm {
     exit_evt: EventFd,
     vm: Vm,
     exit_observers: Vec<Arc<Mutex<dyn VmmExitObserver>>>,
+    quiesce_observers: Vec<Arc<Mutex<dyn VmmQuiesceObserver>>>,
+    #[cfg(not(feature = "tee"))]
+    fs_devices: Vec<Arc<Mutex<devices::virtio::Fs>>>,
+    #[cfg(feature = "net")]
+    net_devices: Vec<Arc<Mutex<devices::virtio::Net>>>,
+    #[cfg(not(feature = "tee"))]
+    balloon_device: Option<Arc<Mutex<devices::virtio::Balloon>>>,
+    // Claim on the CID of the vsock device, held as long as the microVM exists.
+    vsock_cid: Option<Arc<GuestCid>>,
+    // Memory the guest runs the kernel from, when it isn't a bundle owned by the caller.
+    _kernel_image: Option<Arc<vm_memory::MmapRegion>>,
+    events_observer: Option<Arc<Mutex<dyn VmmEventsObserver + Send>>>,
+    // First guest physical address available for windows handed out by `reserve_guest_window`.
+    #[cfg(target_os = "macos")]
+    next_guest_window_addr: u64,
+    // Last `madvise` hint successfully applied to the guest memory.
+    memory_advice: Option<MemoryAdvice>,
+    // Releases guest memory while keeping the ranges still in use.
+    memory_reclaimer: devices::virtio::MemoryReclaimer,
+    // Activates the devices when the vCPUs have a seccomp filter, for the threads of the devices
+    // not to inherit it.
+    #[cfg(target_os = "linux")]
+    unfiltered_runner: Option<utils::seccomp::UnfilteredRunner>,
+    // Time the guest RTC is seeded with.
+    rtc_config: RtcConfig,
+    // Lines printed by the guest to the serial console, if capturing them.
+    serial_lines: Option<devices::legacy::SerialLineBuffer>,
+    // Host end of the `krun-channel` virtio-console port, if the guest has one.
+    console_channel: Option<devices::virtio::ConsoleChannel>,
+    // Written by the signal handler when a signal forwarded to the guest is received.
+    #[cfg(target_os = "linux")]
+    guest_signal_evt: Option<EventFd>,
+    #[cfg(target_os = "linux")]
+    guest_signal_actions: Vec<(libc::c_int, GuestSignalAction)>,
+    // Presses the power button of the GPIO controller, if the guest has one.
+    #[cfg(target_os = "macos")]
+    shutdown_evt: Option<EventFd>,
+    // Written by the pvpanic device when the guest raises a panic event, if it has one.
+    guest_panic_evt: Option<EventFd>,
+    // Whether a guest panic stops the microVM.
+    #[cfg(target_arch = "aarch64")]
+    stop_on_guest_panic: bool,
+    // Whether `stop` terminates the process, unset once the caller drives the loop with
+    // `run_once`.
+    exit_on_stop: bool,
+    // Exit code the microVM stopped with, when `stop` didn't terminate the process.
+    exit_code: Option<i32>,
+    // Why a vCPU couldn't enter guest mode, if that's what stopped the microVM.
+    #[cfg(target_os = "linux")]
+    vcpu_entry_failure: Option<VcpuEntryFailure>,
+    // Exit code a vCPU last reported it stopped with.
+    last_exit_code: Option<u8>,
+    // How long to wait for a vCPU to acknowledge an event.
+    vcpu_handshake_timeout: Duration,
+    // When the boot milestones were reached. The guest boot completion is recorded by the vCPUs.
+    boot_timings: BootTimings,
+    #[cfg(target_os = "linux")]
+    guest_boot_complete: Arc<OnceLock<Instant>>,
+    // Whether the vcpus were started paused and wait for `resume_vcpus`. They always do on
+    // Linux.
+    #[cfg(target_os = "macos")]
+    vcpus_paused: bool,
 
     // Guest VM devices.
     mmio_device_manager: MMIODeviceManager,
@@ -205,7 +629,8 @@ pub struct Vmm {
 }
 
 impl Vmm {
-    /// Gets the the specified bus device.
+    /// Gets the the specified bus device. The virtio-fs devices are `virtio_fs0`, `virtio_fs1`...
+    /// in the order they were added, unless given another id, see `fs_device_id`.
     pub fn get_bus_device(
         &self,
         device_type: DeviceType,
@@ -214,59 +639,448 @@ impl Vmm {
         self.mmio_device_manager.get_device(device_type, device_id)
     }
 
-    /// Starts the microVM vcpus.
-    pub fn start_vcpus(&mut self, mut vcpus: Vec<Vcpu>) -> Result<()> {
+    /// Unplugs the virtio device registered with `device_id`. The guest driver is asked to
+    /// release it first with a configuration change interrupt, and has up to `timeout` to reset
+    /// it, failing this with `mmio::Error::DeviceBusy` and leaving the device plugged. Once
+    /// unplugged, its MMIO range is reused by the next device registered and the host resources
+    /// it holds are freed with the last reference to it. The guest reset stops the worker of a
+    /// network device, so its backend, such as the passt socket, can be closed once unplugged.
+    #[cfg(target_os = "linux")]
+    pub fn hot_unplug(
+        &mut self,
+        device_type: DeviceType,
+        device_id: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+        self.mmio_device_manager
+            .request_release(device_type, device_id)
+            .map_err(Error::HotUnplug)?;
+        let deadline = Instant::now() + timeout;
+        let device = loop {
+            match self
+                .mmio_device_manager
+                .unregister_device(self.vm.fd(), device_type, device_id)
+            {
+                Ok(device) => break device,
+                Err(device_manager::mmio::Error::DeviceBusy(_)) if Instant::now() < deadline => {
+                    std::thread::sleep(POLL_INTERVAL.min(deadline - Instant::now()));
+                }
+                Err(e) => return Err(Error::HotUnplug(e)),
+            }
+        };
+        self.quiesce_observers.retain(|observer| {
+            Arc::as_ptr(observer) as *const () != Arc::as_ptr(&device) as *const ()
+        });
+        #[cfg(feature = "net")]
+        if device_type == DeviceType::Virtio(devices::virtio::TYPE_NET) {
+            let device_manager = &self.mmio_device_manager;
+            self.net_devices.retain(|net| {
+                let net = net.lock().expect("Poisoned mutex for the net device");
+                device_manager.device_id(net.id()) != device_id
+            });
+        }
+        self.update_vcpus_mmio_bus()
+    }
+
+    /// Plugs a new network interface in, in one of the empty slots declared to the guest at
+    /// boot, see `VmResources::set_hotplug_slots`, failing with `mmio::Error::NoHotplugSlots`
+    /// once they're all in use. As virtio-mmio has no way to tell the guest about it, the guest
+    /// must probe the slot again to find the device, e.g. by writing its platform device name
+    /// to `/sys/bus/platform/drivers/virtio-mmio/bind`.
+    #[cfg(all(target_os = "linux", feature = "net"))]
+    pub fn hot_plug_net(&mut self, config: NetworkInterfaceConfig) -> Result<()> {
+        let net = Arc::new(Mutex::new(
+            NetBuilder::create_net(config).map_err(Error::HotPlugNet)?,
+        ));
+        let id = net.lock().unwrap().id().to_string();
+        let mut transport =
+            devices::virtio::MmioTransport::new(self.guest_memory.clone(), net.clone());
+        if let Some(runner) = &self.unfiltered_runner {
+            transport.set_unfiltered_runner(runner.clone());
+        }
+        self.mmio_device_manager
+            .register_hotplug_device(self.vm.fd(), transport, devices::virtio::TYPE_NET, id)
+            .map_err(Error::HotPlug)?;
+        self.quiesce_observers.push(net.clone());
+        self.net_devices.push(net);
+        self.update_vcpus_mmio_bus()
+    }
+
+    /// Plugs a new network interface in. Not supported on this host.
+    #[cfg(all(target_os = "macos", feature = "net"))]
+    pub fn hot_plug_net(
+        &mut self,
+        _config: crate::vmm_config::net::NetworkInterfaceConfig,
+    ) -> Result<()> {
+        Err(Error::HotplugUnsupported)
+    }
+
+    // The vcpus route MMIO exits through their own copy of the bus, which has to be replaced
+    // when devices are plugged in or unplugged.
+    #[cfg(target_os = "linux")]
+    fn update_vcpus_mmio_bus(&self) -> Result<()> {
+        for handle in self.vcpus_handles.iter() {
+            handle
+                .send_request(VcpuEvent::SetMmioBus(self.mmio_device_manager.bus.clone()))
+                .map_err(Error::VcpuEvent)?;
+        }
+        for (id, handle) in self.vcpus_handles.iter().enumerate() {
+            match handle.recv_response(self.vcpu_handshake_timeout) {
+                Some(VcpuResponse::MmioBusSet) => (),
+                _ => return Err(Error::VcpuMmioBus(id)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Unplugs a virtio device. Not supported on this host.
+    #[cfg(target_os = "macos")]
+    pub fn hot_unplug(
+        &mut self,
+        _device_type: DeviceType,
+        _device_id: &str,
+        _timeout: Duration,
+    ) -> Result<()> {
+        Err(Error::HotplugUnsupported)
+    }
+
+    /// Starts the microVM vcpus. With `start_paused`, their threads are spawned and the vcpus
+    /// initialized, but the guest doesn't run its first instruction until `resume_vcpus` is
+    /// called, which leaves the caller a window to e.g. set registers or attach a debugger.
+    pub fn start_vcpus(&mut self, mut vcpus: Vec<Vcpu>, start_paused: bool) -> Result<()> {
         let vcpu_count = vcpus.len();
 
+        if let Some(observer) = &self.events_observer {
+            observer
+                .lock()
+                .unwrap()
+                .on_vmm_boot()
+                .map_err(Error::VmmObserverInit)?;
+        }
+
         Vcpu::register_kick_signal_handler();
 
         self.vcpus_handles.reserve(vcpu_count);
 
         for mut vcpu in vcpus.drain(..) {
             vcpu.set_mmio_bus(self.mmio_device_manager.bus.clone());
+            #[cfg(target_os = "linux")]
+            vcpu.set_boot_complete_time(self.guest_boot_complete.clone());
+            #[cfg(target_os = "macos")]
+            vcpu.set_start_paused(start_paused);
 
             self.vcpus_handles
                 .push(vcpu.start_threaded().map_err(Error::VcpuHandle)?);
         }
 
+        self.boot_timings.vcpus_started = Some(Instant::now());
+        if let Some(observer) = &self.events_observer {
+            observer.lock().unwrap().on_vcpus_ready();
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            self.vcpus_paused = start_paused;
+        }
         // The vcpus start off in the `Paused` state, let them run.
-        self.resume_vcpus()?;
+        if !start_paused {
+            self.resume_vcpus()?;
+        }
 
         Ok(())
     }
 
     /// Sends a resume command to the vcpus.
-    #[cfg(target_os = "linux")]
     pub fn resume_vcpus(&mut self) -> Result<()> {
+        // Only vcpus started paused wait for it on this host.
+        #[cfg(target_os = "macos")]
+        if !std::mem::take(&mut self.vcpus_paused) {
+            return Ok(());
+        }
+
         for handle in self.vcpus_handles.iter() {
             handle
-                .send_event(VcpuEvent::Resume)
+                .send_request(VcpuEvent::Resume)
                 .map_err(Error::VcpuEvent)?;
         }
-        for handle in self.vcpus_handles.iter() {
-            match handle
-                .response_receiver()
-                .recv_timeout(Duration::from_millis(1000))
-            {
-                Ok(VcpuResponse::Resumed) => (),
-                _ => return Err(Error::VcpuResume),
-            }
+        for (index, handle) in self.vcpus_handles.iter().enumerate() {
+            let reason = match handle.recv_response(self.vcpu_handshake_timeout) {
+                Some(VcpuResponse::Resumed) => continue,
+                Some(response) => format!("unexpected response {response:?}"),
+                None => "no response in time".to_string(),
+            };
+            return Err(Error::VcpuResume { index, reason });
         }
+        self.boot_timings
+            .vcpus_resumed
+            .get_or_insert_with(Instant::now);
         Ok(())
     }
 
+    /// Returns when the microVM reached each of the boot milestones, to measure how long
+    /// starting it takes, e.g. from `vm_created` to `vcpus_started`.
+    pub fn boot_timings(&self) -> BootTimings {
+        BootTimings {
+            #[cfg(target_os = "linux")]
+            guest_boot_complete: self.guest_boot_complete.get().copied(),
+            ..self.boot_timings
+        }
+    }
+
+    /// Sets how long `resume_vcpus`, `pause_vcpus` and the other calls waiting for the vCPUs to
+    /// acknowledge an event wait before giving up, `DEFAULT_VCPU_HANDSHAKE_TIMEOUT` by default.
+    /// Loaded hosts, especially with nested virtualization, may need more.
+    pub fn set_vcpu_handshake_timeout(&mut self, timeout: Duration) {
+        self.vcpu_handshake_timeout = timeout;
+    }
+
+    /// Sends a pause command to the vcpus, returning once they're all out of guest mode. The
+    /// vcpus that fail to pause are listed in `Error::VcpuPause`, the other ones are left paused
+    /// and the caller can decide whether to `resume_vcpus`.
+    #[cfg(target_os = "linux")]
+    pub fn pause_vcpus(&mut self) -> Result<()> {
+        let mut failed = Vec::new();
+        for (id, handle) in self.vcpus_handles.iter().enumerate() {
+            if let Err(e) = handle.send_request(VcpuEvent::Pause) {
+                error!("Cannot send the pause event to vCPU {id}: {e:?}");
+                failed.push(id);
+            }
+        }
+        for (id, handle) in self.vcpus_handles.iter().enumerate() {
+            if failed.contains(&id) {
+                continue;
+            }
+            match handle.recv_response(self.vcpu_handshake_timeout) {
+                Some(VcpuResponse::Paused) => (),
+                _ => failed.push(id),
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            failed.sort_unstable();
+            Err(Error::VcpuPause(failed))
+        }
+    }
+
+    /// Sends a pause command to the vcpus. Not supported on this host yet, the vcpus keep
+    /// running.
     #[cfg(target_os = "macos")]
-    pub fn resume_vcpus(&mut self) -> Result<()> {
+    pub fn pause_vcpus(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Injects a non-maskable interrupt into the selected vCPUs, which must be running. This is
+    /// mostly useful to force a hung guest into its NMI handler, e.g. to trigger a crash dump.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn send_nmi(&self, target: NmiTarget) -> Result<()> {
+        let handles: Vec<(usize, &VcpuHandle)> = match target {
+            NmiTarget::AllVcpus => self.vcpus_handles.iter().enumerate().collect(),
+            NmiTarget::Vcpu(id) => vec![(
+                id,
+                self.vcpus_handles
+                    .get(id)
+                    .ok_or(Error::InvalidVcpuIndex(id))?,
+            )],
+        };
+
+        for (_, handle) in handles.iter() {
+            handle
+                .send_request(VcpuEvent::InjectNmi)
+                .map_err(Error::VcpuEvent)?;
+        }
+        for (id, handle) in handles {
+            match handle.recv_response(self.vcpu_handshake_timeout) {
+                Some(VcpuResponse::NmiInjected) => (),
+                Some(VcpuResponse::NmiFailed(e)) => return Err(Error::VcpuNmi(id, e)),
+                _ => return Err(Error::VcpuNotRunning(id)),
+            }
+        }
         Ok(())
     }
 
+    /// Returns the counters of each vCPU, by index: how many times it exited out of the guest,
+    /// how long it ran, and why it last exited.
+    pub fn vcpu_stats(&self) -> Vec<VcpuStats> {
+        self.vcpus_handles
+            .iter()
+            .map(|handle| handle.stats())
+            .collect()
+    }
+
+    /// Returns a copy of the `kvm_run` area of a paused vCPU, which describes its last exit out
+    /// of `KVM_RUN`. Embedders can use it to inspect the port I/O or MMIO access that caused the
+    /// exit without racing the vCPU run loop.
+    #[cfg(target_os = "linux")]
+    pub fn vcpu_run_state(&self, id: usize) -> Result<KvmRunSnapshot> {
+        let handle = self
+            .vcpus_handles
+            .get(id)
+            .ok_or(Error::InvalidVcpuIndex(id))?;
+        handle
+            .send_request(VcpuEvent::GetRunState)
+            .map_err(Error::VcpuEvent)?;
+        match handle.recv_response(self.vcpu_handshake_timeout) {
+            Some(VcpuResponse::RunState(run_state)) => Ok(run_state),
+            _ => Err(Error::VcpuNotPaused(id)),
+        }
+    }
+
+    /// Returns the registers of a paused vCPU.
+    #[cfg(target_os = "linux")]
+    pub fn vcpu_registers(&self, id: usize) -> Result<VcpuRegisters> {
+        let handle = self
+            .vcpus_handles
+            .get(id)
+            .ok_or(Error::InvalidVcpuIndex(id))?;
+        handle
+            .send_request(VcpuEvent::GetRegisters)
+            .map_err(Error::VcpuEvent)?;
+        match handle.recv_response(self.vcpu_handshake_timeout) {
+            Some(VcpuResponse::Registers(registers)) => Ok(*registers),
+            Some(VcpuResponse::RegistersFailed(e)) => Err(Error::VcpuRegisters(id, e)),
+            _ => Err(Error::VcpuNotPaused(id)),
+        }
+    }
+
+    /// Starts tracking the guest memory pages the vCPUs write to. Use `dirty_pages` to collect
+    /// them.
+    ///
+    /// This isn't enough for live migration or incremental snapshots on its own: the writes the
+    /// devices do to the guest memory aren't tracked, see `dirty_pages`.
+    #[cfg(target_os = "linux")]
+    pub fn enable_dirty_logging(&mut self) -> Result<()> {
+        self.vm.enable_dirty_logging().map_err(Error::Vm)
+    }
+
+    /// Stops tracking the guest memory pages the guest writes to.
+    #[cfg(target_os = "linux")]
+    pub fn disable_dirty_logging(&mut self) -> Result<()> {
+        self.vm.disable_dirty_logging().map_err(Error::Vm)
+    }
+
+    /// Returns the ranges of guest memory the guest wrote to since dirty logging was enabled or
+    /// since the last call, each page being reported once. The vCPUs should be paused for the
+    /// result to still be accurate when it's used.
+    ///
+    /// Only the writes of the vCPUs are tracked. The pages the VMM writes to, e.g. the buffers
+    /// the devices fill for the guest and the used rings of their queues, are never reported.
+    /// A copy of the dirty pages alone therefore can't be used to migrate or snapshot a running
+    /// guest: the devices must be quiesced, see `quiesce_devices`, and the memory they may have
+    /// written to copied in full.
+    #[cfg(target_os = "linux")]
+    pub fn dirty_pages(&self) -> Result<Vec<DirtyRegion>> {
+        self.vm.get_dirty_bitmap().map_err(Error::Vm)
+    }
+
+    /// Returns the general purpose registers, program counter and segments of a vCPU. Unlike
+    /// `vcpu_registers`, the vCPU doesn't need to be paused: a running one is kicked out of the
+    /// guest to read them.
+    #[cfg(target_os = "linux")]
+    pub fn vcpu_state(&self, id: usize) -> Result<VcpuState> {
+        let handle = self
+            .vcpus_handles
+            .get(id)
+            .ok_or(Error::InvalidVcpuIndex(id))?;
+        handle
+            .send_request(VcpuEvent::DumpState)
+            .map_err(Error::VcpuEvent)?;
+        match handle.recv_response(self.vcpu_handshake_timeout) {
+            Some(VcpuResponse::State(state)) => Ok(*state),
+            Some(VcpuResponse::RegistersFailed(e)) => Err(Error::VcpuRegisters(id, e)),
+            _ => Err(Error::VcpuNotRunning(id)),
+        }
+    }
+
+    /// Writes an ELF core dump of the guest to a new file at `path`, which gdb or crash can
+    /// load: the guest RAM, as for `dump_guest_memory`, and the registers of each vCPU. The vCPUs
+    /// must have been paused with `pause_vcpus`. See `core_dump` for the layout.
+    #[cfg(target_os = "linux")]
+    pub fn export_core_dump(&self, path: &Path) -> Result<()> {
+        let vcpus = (0..self.vcpus_handles.len())
+            .map(|id| self.vcpu_registers(id))
+            .collect::<Result<Vec<_>>>()?;
+        let ram_ranges = self.memory_info().ram_ranges;
+
+        let mut file = File::create(path).map_err(Error::CoreDump)?;
+        core_dump::write_core_dump(&self.guest_memory, &ram_ranges, &vcpus, &mut file)
+            .and_then(|_| file.sync_all())
+            .map_err(Error::CoreDump)
+    }
+
+    /// Injects a non-maskable interrupt into the selected vCPUs. Not supported on this host.
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    pub fn send_nmi(&self, _target: NmiTarget) -> Result<()> {
+        Err(Error::NmiUnsupported)
+    }
+
+    /// Returns the GSI routing table the in-kernel interrupt controller uses, which is KVM's
+    /// default one until `set_gsi_routing` is called.
+    #[cfg(target_os = "linux")]
+    pub fn get_gsi_routing(&self) -> Result<Vec<GsiRoute>> {
+        Ok(self.vm.gsi_routing())
+    }
+
+    /// Replaces the whole GSI routing table, e.g. to route MSIs of a device model added by the
+    /// embedder to fixed GSIs. The default routes of the GSIs used by the VMM's own devices must
+    /// be kept, see `get_gsi_routing`.
+    #[cfg(target_os = "linux")]
+    pub fn set_gsi_routing(&mut self, routes: &[GsiRoute]) -> Result<()> {
+        self.vm.set_gsi_routing(routes).map_err(Error::Vm)
+    }
+
+    /// Makes `handler` handle the hypercalls the guest makes with this `id`, see
+    /// `crate::hypercall` for how the guest makes them. Ids up to `MAX_RESERVED_HYPERCALL_ID`
+    /// are reserved for libkrun. Fails with `HypercallError::Unsupported` on x86_64, and on Arm
+    /// hosts whose KVM can't forward SMCCC calls (before Linux 6.4).
+    #[cfg(target_os = "linux")]
+    pub fn register_hypercall(&self, id: u32, handler: Arc<dyn HypercallHandler>) -> Result<()> {
+        self.vm
+            .hypercalls()
+            .ok_or(HypercallError::Unsupported)
+            .and_then(|hypercalls| hypercalls.register(id, handler))
+            .map_err(Error::Hypercall)
+    }
+
+    /// Registers a hypercall handler. Not supported on this host.
+    #[cfg(target_os = "macos")]
+    pub fn register_hypercall(&self, _id: u32, _handler: Arc<dyn HypercallHandler>) -> Result<()> {
+        Err(Error::Hypercall(HypercallError::Unsupported))
+    }
+
+    /// Returns the GSI routing table. Not supported on this host.
+    #[cfg(target_os = "macos")]
+    pub fn get_gsi_routing(&self) -> Result<Vec<GsiRoute>> {
+        Err(Error::GsiRoutingUnsupported)
+    }
+
+    /// Replaces the GSI routing table. Not supported on this host.
+    #[cfg(target_os = "macos")]
+    pub fn set_gsi_routing(&mut self, _routes: &[GsiRoute]) -> Result<()> {
+        Err(Error::GsiRoutingUnsupported)
+    }
+
     /// Configures the system for boot.
     pub fn configure_system(
         &self,
         vcpus: &[Vcpu],
+        cpu_topology: &CpuTopology,
         initrd: &Option<InitrdConfig>,
-        _smbios_oem_strings: &Option<Vec<String>>,
+        smbios_oem_strings: &Option<Vec<String>>,
+        boot_entropy: &BootEntropy,
     ) -> Result<()> {
+        debug_assert_eq!(vcpus.len(), cpu_topology.vcpu_count());
+
+        if self.kernel_cmdline.len() + 1 > KERNEL_CMDLINE_LIMIT {
+            return Err(Error::KernelCmdlineTooLarge(
+                self.kernel_cmdline.len() + 1,
+                KERNEL_CMDLINE_LIMIT,
+            ));
+        }
+
         #[cfg(target_arch = "x86_64")]
         {
             let cmdline_len = if cfg!(feature = "tee") {
@@ -281,7 +1095,9 @@ impl Vmm {
                 vm_memory::GuestAddress(arch::x86_64::layout::CMDLINE_START),
                 cmdline_len,
                 initrd,
-                vcpus.len() as u8,
+                cpu_topology,
+                smbios_oem_strings,
+                boot_entropy,
             )
             .map_err(Error::ConfigureSystem)?;
         }
@@ -294,10 +1110,13 @@ impl Vmm {
                 &self.arch_memory_info,
                 self.kernel_cmdline.as_str(),
                 vcpu_mpidr,
+                cpu_topology,
                 self.mmio_device_manager.get_device_info(),
                 self.vm.get_irqchip(),
                 initrd,
-                _smbios_oem_strings,
+                smbios_oem_strings,
+                boot_entropy,
+                vcpus.iter().any(Vcpu::has_pmu),
             )
             .map_err(Error::ConfigureSystem)?;
         }
@@ -310,21 +1129,259 @@ impl Vmm {
                 &self.arch_memory_info,
                 self.kernel_cmdline.as_str(),
                 vcpu_mpidr,
+                cpu_topology,
                 self.mmio_device_manager.get_device_info(),
                 self.vm.get_irqchip(),
                 initrd,
-                _smbios_oem_strings,
+                smbios_oem_strings,
+                boot_entropy,
+                false,
             )
             .map_err(Error::ConfigureSystem)?;
         }
+
+        self.update_guest_rtc();
         Ok(())
     }
 
+    /// Seeds the guest RTC from its configuration. The microVM does so when configuring the
+    /// system, calling this again e.g. after the vcpus were paused for a while gives the guest an
+    /// up to date wallclock to resync from, a `Fixed` base setting the RTC back to that time.
+    pub fn update_guest_rtc(&self) {
+        let time = self.rtc_config.initial_time_ns();
+
+        #[cfg(target_arch = "x86_64")]
+        self.pio_device_manager.cmos.lock().unwrap().set_time(time);
+
+        #[cfg(target_arch = "aarch64")]
+        if let Some(rtc) = self.mmio_device_manager.rtc() {
+            rtc.lock().unwrap().set_time(time);
+        }
+    }
+
     /// Returns a reference to the inner `GuestMemoryMmap` object if present, or `None` otherwise.
     pub fn guest_memory(&self) -> &GuestMemoryMmap {
         &self.guest_memory
     }
 
+    /// Returns the memfd or the file the guest memory is mapped from, with the regions of guest
+    /// memory that are, for another process to map them with `MAP_SHARED`. `None` if the guest
+    /// memory is anonymous. On x86_64 the kernel image is left out, being mapped on its own.
+    #[cfg(target_os = "linux")]
+    pub fn guest_memory_fd(&self) -> Option<(BorrowedFd<'_>, Vec<GuestMemoryFileRegion>)> {
+        let mut file = None;
+        let mut regions = Vec::new();
+        for region in self.guest_memory.iter() {
+            let Some(file_offset) = region.file_offset() else {
+                continue;
+            };
+            file.get_or_insert(file_offset.file());
+            regions.push(GuestMemoryFileRegion {
+                guest_addr: region.start_addr(),
+                len: region.len(),
+                file_offset: file_offset.start(),
+            });
+        }
+        file.map(|file| (file.as_fd(), regions))
+    }
+
+    /// Returns how much RAM the guest got and where it lies, along with the other regions of its
+    /// physical address space.
+    pub fn memory_info(&self) -> MemoryInfo {
+        memory_info(&self.guest_memory, &self.arch_memory_info)
+    }
+
+    /// Copies `buf` to the guest RAM at `gpa`, e.g. to stage a blob for the guest before it
+    /// boots. The whole range must be guest RAM: the shared memory region and the ranges left
+    /// out of RAM are refused.
+    pub fn write_guest_slice(&self, gpa: GuestAddress, buf: &[u8]) -> Result<()> {
+        check_guest_ram_range(&self.arch_memory_info, gpa, buf.len())?;
+        self.guest_memory
+            .write_slice(buf, gpa)
+            .map_err(|_| Error::GuestMemoryAccess(gpa, buf.len()))
+    }
+
+    /// Fills `buf` from the guest RAM at `gpa`, with the same restrictions as
+    /// `write_guest_slice`.
+    pub fn read_guest_slice(&self, gpa: GuestAddress, buf: &mut [u8]) -> Result<()> {
+        check_guest_ram_range(&self.arch_memory_info, gpa, buf.len())?;
+        self.guest_memory
+            .read_slice(buf, gpa)
+            .map_err(|_| Error::GuestMemoryAccess(gpa, buf.len()))
+    }
+
+    /// Forwards the host signals in `actions` to the guest as the associated events, instead of
+    /// letting them take their default effect on the VMM process.
+    #[cfg(target_os = "linux")]
+    pub fn forward_guest_signals(
+        &mut self,
+        actions: Vec<(libc::c_int, GuestSignalAction)>,
+    ) -> Result<()> {
+        // The actions couldn't be delivered, so don't take the signals from the VMM either.
+        #[cfg(target_arch = "aarch64")]
+        if !actions.is_empty() {
+            return Err(Error::NoShutdownDevice);
+        }
+        let evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let signals: Vec<libc::c_int> = actions.iter().map(|(signum, _)| *signum).collect();
+        signal_handler::register_guest_signal_handlers(evt.as_raw_fd(), &signals)
+            .map_err(Error::GuestSignalHandler)?;
+        self.guest_signal_evt = Some(evt);
+        self.guest_signal_actions = actions;
+        Ok(())
+    }
+
+    /// Asks the guest to shut down gracefully through the event described by `action`.
+    #[cfg(target_os = "linux")]
+    pub fn trigger_guest_action(&mut self, action: GuestSignalAction) -> Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            // There's no power button on x86_64, CTRL+ALT+DEL is the graceful path.
+            if action == GuestSignalAction::PowerButton {
+                info!("No power button on x86_64, sending CTRL+ALT+DEL instead");
+            }
+            self.send_ctrl_alt_del()
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            // KVM guests have neither a keyboard controller nor a GPIO power button.
+            let _ = action;
+            Err(Error::NoShutdownDevice)
+        }
+    }
+
+    // Reports the panic events the guest raised since the last call.
+    #[cfg(target_arch = "aarch64")]
+    fn handle_guest_panic(&mut self) {
+        let events = match self.mmio_device_manager.pvpanic() {
+            Some(device) => device.lock().unwrap().take_events(),
+            None => return,
+        };
+        self.report_guest_panic(events, self.stop_on_guest_panic);
+    }
+
+    // Notifies the events observer about the pvpanic `events`, then stops the microVM if `stop`
+    // is set.
+    #[cfg(any(target_arch = "aarch64", test))]
+    fn report_guest_panic(&mut self, events: u8, stop: bool) {
+        if events == 0 {
+            return;
+        }
+
+        let reason = GuestPanic::from_events(events);
+        warn!("The guest panicked: {reason:?}");
+        if let Some(observer) = &self.events_observer {
+            observer.lock().unwrap().on_guest_panic(reason);
+        }
+        if stop {
+            self.stop(i32::from(FC_EXIT_CODE_GENERIC_ERROR));
+        }
+    }
+
+    // Translate the forwarded signals received since the last call into guest events.
+    #[cfg(target_os = "linux")]
+    fn handle_guest_signals(&mut self) {
+        for signum in signal_handler::take_guest_signals() {
+            let Some(&(_, action)) = self.guest_signal_actions.iter().find(|(s, _)| *s == signum)
+            else {
+                continue;
+            };
+            info!("Forwarding signal {signum} to the guest as {action}");
+            if let Err(e) = self.trigger_guest_action(action) {
+                error!("Failed to forward signal {signum} to the guest: {e}");
+            }
+        }
+    }
+
+    /// Asks the guest to power off, as if its power button were pressed, and gives it up to
+    /// `grace` to do so before stopping the microVM anyway, with its vCPUs paused on Linux.
+    /// Either way the microVM is stopped through `stop`, so this only returns if the event loop
+    /// is driven by `run_once`.
+    pub fn shutdown(&mut self, grace: Duration) -> ShutdownOutcome {
+        if let Some(exit_code) = self.exit_code {
+            return ShutdownOutcome::Graceful(exit_code);
+        }
+
+        #[cfg(target_os = "linux")]
+        let requested = self.trigger_guest_action(GuestSignalAction::PowerButton);
+        #[cfg(target_os = "macos")]
+        let requested = match &self.shutdown_evt {
+            Some(evt) => evt.write(1).map_err(Error::EventFd),
+            None => Err(Error::NoShutdownDevice),
+        };
+        match requested {
+            Ok(()) => {
+                if let Some(exit_code) = self.wait_for_exit(grace) {
+                    let exit_code = i32::from(exit_code);
+                    self.stop(exit_code);
+                    return ShutdownOutcome::Graceful(exit_code);
+                }
+                warn!("The guest didn't shut down within {grace:?}, stopping it");
+            }
+            Err(e) => warn!("Cannot ask the guest to shut down, stopping it: {e}"),
+        }
+
+        if let Err(e) = self.pause_vcpus() {
+            error!("Cannot pause the vCPUs of the stopped microVM: {e}");
+        }
+        self.stop(i32::from(FC_EXIT_CODE_GENERIC_ERROR));
+        ShutdownOutcome::Forced
+    }
+
+    // Waits up to `timeout` for a vCPU to write the exit event, returning its exit code. The
+    // event loop can't process the event meanwhile, as the caller holds the `Vmm`.
+    fn wait_for_exit(&mut self, timeout: Duration) -> Option<u8> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.exit_evt.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let timeout_ms = i32::try_from(remaining.as_millis()).unwrap_or(i32::MAX);
+            // Safe because `pollfd` lives until the call returns.
+            let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+            if ret > 0 {
+                let _ = self.exit_evt.read();
+                return Some(self.vcpus_exit_code());
+            }
+            if ret == 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                return None;
+            }
+        }
+    }
+
+    // Queries each vcpu for the exit code, draining all the responses they already sent, as
+    // several of them may have exited by now. See `select_exit_code` for which one wins. If it
+    // can't be found on any vcpu, the exit signal has been issued by the i8042 controller, in
+    // which case the exit code is FC_EXIT_CODE_OK.
+    fn vcpus_exit_code(&mut self) -> u8 {
+        let mut exit_codes = Vec::new();
+        for handle in self.vcpus_handles.iter() {
+            for response in handle.drain_responses() {
+                match response {
+                    VcpuResponse::Exited(exit_code) => exit_codes.push(exit_code),
+                    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+                    VcpuResponse::Halted(exit_code) => exit_codes.push(exit_code),
+                    #[cfg(target_os = "linux")]
+                    VcpuResponse::EntryFailed(failure) => {
+                        error!("Stopping the microVM: {failure}");
+                        self.vcpu_entry_failure = Some(failure);
+                        exit_codes.push(FC_EXIT_CODE_GENERIC_ERROR);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let Some(exit_code) = select_exit_code(&exit_codes) else {
+            return FC_EXIT_CODE_OK;
+        };
+        self.last_exit_code = Some(exit_code);
+        exit_code
+    }
+
     /// Injects CTRL+ALT+DEL keystroke combo in the i8042 device.
     #[cfg(target_arch = "x86_64")]
     pub fn send_ctrl_alt_del(&mut self) -> Result<()> {
@@ -336,8 +1393,65 @@ impl Vmm {
             .map_err(Error::I8042Error)
     }
 
-    /// Waits for all vCPUs to exit and terminates the Firecracker process.
+    /// Writes raw `scancodes` (scan code set 2, which the guest usually has the i8042 translate to
+    /// set 1) into the i8042 keyboard buffer, as if the keys were pressed or released, e.g.
+    /// `[0x5a, 0xf0, 0x5a]` to press and release Enter. Either all of them are queued or none,
+    /// failing with `Error::I8042BufferFull` when the guest hasn't read enough of the previous
+    /// ones yet.
+    #[cfg(target_arch = "x86_64")]
+    pub fn send_key_event(&mut self, scancodes: &[u8]) -> Result<()> {
+        self.pio_device_manager
+            .i8042
+            .lock()
+            .expect("i8042 lock was poisoned")
+            .trigger_scancodes(scancodes)
+            .map_err(|e| match e {
+                devices::legacy::I8042DeviceError::InternalBufferFull => Error::I8042BufferFull,
+                e => Error::I8042Error(e),
+            })
+    }
+
+    /// Processes the events pending in `event_manager`, vCPU exits and device notifications
+    /// alike, waiting up to `timeout` (forever if `None`) for one to arrive, then returns control
+    /// to the caller. This is the cooperative counterpart to blocking in `EventManager::run`, for
+    /// embedders that run the VMM on a thread of their own runtime.
+    ///
+    /// `vmm` is the one returned by `builder::build_microvm` along with its `event_manager`; it's
+    /// taken by reference as the event manager locks it to dispatch its events. Once this has
+    /// been called, the microVM no longer terminates the process when it exits, and reports it as
+    /// `RunOutcome::Exited` instead.
+    pub fn run_once(
+        vmm: &Arc<Mutex<Vmm>>,
+        event_manager: &mut EventManager,
+        timeout: Option<Duration>,
+    ) -> Result<RunOutcome> {
+        {
+            let mut vmm = vmm.lock().expect("Poisoned lock for Vmm");
+            if let Some(exit_code) = vmm.exit_code {
+                return Ok(RunOutcome::Exited(exit_code));
+            }
+            vmm.exit_on_stop = false;
+        }
+
+        let timeout_ms = timeout.map_or(-1, |timeout| {
+            i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX)
+        });
+        event_manager
+            .run_with_timeout(timeout_ms)
+            .map_err(Error::EventManager)?;
+
+        Ok(match vmm.lock().expect("Poisoned lock for Vmm").exit_code {
+            Some(exit_code) => RunOutcome::Exited(exit_code),
+            None => RunOutcome::Running,
+        })
+    }
+
+    /// Notifies the exit observers and the events observer and terminates the Firecracker
+    /// process, unless the event loop is driven by `run_once`, which then reports `exit_code`.
     pub fn stop(&mut self, exit_code: i32) {
+        if self.exit_code.is_some() {
+            return;
+        }
         info!("Vmm is stopping.");
 
         if let Err(e) = term_set_canonical_mode() {
@@ -350,6 +1464,14 @@ impl Vmm {
                 .expect("Poisoned mutex for exit observer")
                 .on_vmm_exit();
         }
+        if let Err(e) = self.notify_vmm_stop() {
+            log::error!("{e}");
+        }
+
+        if !self.exit_on_stop {
+            self.exit_code = Some(exit_code);
+            return;
+        }
 
         // Exit from Firecracker using the provided exit code. Safe because we're terminating
         // the process anyway.
@@ -358,6 +1480,83 @@ impl Vmm {
         }
     }
 
+    /// Stops the microVM like `stop` does, but returns to the caller instead of terminating the
+    /// process, so it can be embedded in a long-running host. A running guest isn't asked to
+    /// shut down first, see `shutdown` for that.
+    ///
+    /// The teardown happens in this order:
+    /// 1. Every vCPU thread is made to finish and joined, so no vCPU touches the guest memory or
+    ///    the devices afterwards. On macOS the vCPUs can't be kicked out of the guest, so their
+    ///    threads are left behind instead.
+    /// 2. The exit observers are notified, in the order they were registered, then the events
+    ///    observer.
+    /// 3. The terminal is restored to canonical mode.
+    ///
+    /// The observers are only notified once: not at all if `stop` already did, nor by a later
+    /// `stop`, which does nothing once the microVM is torn down. `run_once` then reports the
+    /// exit code the microVM stopped with, `FC_EXIT_CODE_OK` if this stopped it. If a vCPU
+    /// thread can't be joined or the events observer fails, the teardown carries on and the
+    /// first of these errors is returned.
+    pub fn teardown(&mut self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        let joined = self.join_vcpus();
+        #[cfg(target_os = "macos")]
+        let joined = Ok(());
+
+        if self.exit_code.is_some() {
+            return joined;
+        }
+        info!("Vmm is tearing down.");
+        self.exit_code = Some(i32::from(FC_EXIT_CODE_OK));
+
+        for observer in &self.exit_observers {
+            observer
+                .lock()
+                .expect("Poisoned mutex for exit observer")
+                .on_vmm_exit();
+        }
+        let stopped = self.notify_vmm_stop();
+
+        if let Err(e) = term_set_canonical_mode() {
+            log::error!("Failed to restore terminal to canonical mode: {e}")
+        }
+
+        joined.and(stopped)
+    }
+
+    // Tells the events observer, if any, that the microVM is stopping.
+    fn notify_vmm_stop(&self) -> Result<()> {
+        match &self.events_observer {
+            Some(observer) => observer
+                .lock()
+                .unwrap()
+                .on_vmm_stop()
+                .map_err(Error::VmmObserverTeardown),
+            None => Ok(()),
+        }
+    }
+
+    // Joins every vCPU thread and drops its handle, returning the first failure.
+    #[cfg(target_os = "linux")]
+    fn join_vcpus(&mut self) -> Result<()> {
+        let mut joined = Ok(());
+        for (index, mut handle) in std::mem::take(&mut self.vcpus_handles)
+            .into_iter()
+            .enumerate()
+        {
+            if let Err(e) = handle.join() {
+                error!("Cannot join vCPU {index}: {e}");
+                if joined.is_ok() {
+                    joined = Err(Error::VcpuJoin {
+                        index,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+        joined
+    }
+
     #[cfg(target_os = "linux")]
     fn log_boot_time(t0_ts: &TimestampUs) {
         let now_tm_us = TimestampUs::default();
@@ -373,11 +1572,382 @@ impl Vmm {
         );
     }
 
+    /// Returns a duplicate of the event a vCPU writes when it exits, for an embedder's own event
+    /// loop to watch: it becomes readable once a vCPU has exited, see `last_exit_code` for its
+    /// exit code. The counter is shared with the microVM event loop, which stops the microVM
+    /// when it reads the event, so only poll it for readability while that loop runs.
+    pub fn exit_eventfd(&self) -> Result<EventFd> {
+        self.exit_evt.try_clone().map_err(Error::EventFd)
+    }
+
+    /// Returns the exit code of the last `VcpuResponse::Exited` the event loop received, i.e.
+    /// the one a vCPU reported when it stopped. When several vCPUs exited together, an error
+    /// code wins over `FC_EXIT_CODE_OK`. `None` until a vCPU exits, or if the microVM was
+    /// stopped from the host side, e.g. by the i8042 controller.
+    pub fn last_exit_code(&self) -> Option<u8> {
+        self.last_exit_code
+    }
+
+    /// Returns why a vCPU failed to enter guest mode, if that's what stopped the microVM. The
+    /// reason code usually points at an invalid vCPU register state.
+    #[cfg(target_os = "linux")]
+    pub fn vcpu_entry_failure(&self) -> Option<VcpuEntryFailure> {
+        self.vcpu_entry_failure
+    }
+
+    /// Returns the next complete line the guest printed to the serial console, waiting up to
+    /// `timeout` for one. Returns `None` on timeout or if the serial console isn't being
+    /// captured (see `VmResources::set_serial_capture`).
+    pub fn read_console_line(&self, timeout: Duration) -> Option<String> {
+        self.serial_lines.as_ref()?.read_line(timeout)
+    }
+
+    /// Reads what the guest wrote to the `krun-channel` virtio-console port into `buf`, waiting
+    /// up to `timeout` for it to write anything. Returns the number of bytes read, 0 on timeout.
+    /// See `VmResources::set_console_channel`.
+    pub fn console_read(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let channel = self
+            .console_channel
+            .as_ref()
+            .ok_or(Error::NoConsoleChannel)?;
+        Ok(channel.read(buf, timeout))
+    }
+
+    /// Writes `data` to the `krun-channel` virtio-console port, waiting up to `timeout` for the
+    /// guest to read it whenever the channel buffer is full. Returns the number of bytes written,
+    /// less than `data.len()` on timeout.
+    pub fn console_write(&self, data: &[u8], timeout: Duration) -> Result<usize> {
+        let channel = self
+            .console_channel
+            .as_ref()
+            .ok_or(Error::NoConsoleChannel)?;
+        Ok(channel.write(data, timeout))
+    }
+
+    /// Returns the last `madvise` hint successfully applied to the guest memory, if any.
+    pub fn memory_advice(&self) -> Option<MemoryAdvice> {
+        self.memory_advice
+    }
+
+    /// Applies `advice` to all guest memory regions. Every region is attempted even if some of
+    /// them fail, and the failing ones are reported together.
+    pub fn advise_memory(&mut self, advice: MemoryAdvice) -> Result<()> {
+        let madv = advice
+            .as_madvise()
+            .ok_or(Error::UnsupportedMemoryAdvice(advice))?;
+
+        let mut errors = Vec::new();
+        for region in self.guest_memory.iter() {
+            // Safe because the region is a valid mapping owned by `guest_memory` for its whole
+            // length, and `madvise` doesn't change its contents.
+            let ret = unsafe {
+                libc::madvise(
+                    region.as_ptr() as *mut libc::c_void,
+                    region.len() as usize,
+                    madv,
+                )
+            };
+            if ret != 0 {
+                errors.push((region.start_addr(), io::Error::last_os_error()));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(Error::AdviseMemory(advice, errors));
+        }
+        self.memory_advice = Some(advice);
+        Ok(())
+    }
+
+    /// Returns how much host memory the guest memory regions actually use, to tell guests using
+    /// their memory apart from the ones merely reserving it. This walks the page tables of every
+    /// region, so it shouldn't be called in a tight loop for large guests.
+    pub fn memory_metrics(&self) -> Result<MemoryMetrics> {
+        guest_memory_metrics(&self.guest_memory).map_err(Error::MemoryMetrics)
+    }
+
+    /// Releases the host memory backing the guest `ranges`, e.g. the ones a guest trim freed, so
+    /// it no longer counts in the RSS of the VMM. Returns the number of bytes released.
+    ///
+    /// Only the host pages fully covered by a range are released, huge pages being released whole,
+    /// and never the ones holding the rings of virtio queues, the buffers of their descriptors or
+    /// the shared memory region of the DAX windows. The guest reads the released pages as zeroes,
+    /// or as the contents of the file it privately maps its memory from: the pages of shared
+    /// mappings are removed from the file or memfd. The balloon releases the pages the guest
+    /// inflates it with the same way.
+    pub fn reclaim_memory(&self, ranges: &[Range<GuestAddress>]) -> u64 {
+        self.memory_reclaimer.reclaim(&self.guest_memory, ranges)
+    }
+
+    /// Writes the guest RAM to a new file at `path`, e.g. to analyze a guest crash offline. Call
+    /// `pause_vcpus` first to get a consistent snapshot. The shared memory region isn't dumped.
+    ///
+    /// All integers are little endian. The file starts with `GUEST_MEMORY_DUMP_MAGIC`, then
+    /// `GUEST_MEMORY_DUMP_VERSION` and the number of RAM ranges as `u32`s, then the guest physical
+    /// address and size of each range as `u64`s, as in `MemoryInfo::ram_ranges`. The contents of
+    /// the ranges follow, in the same order.
+    pub fn dump_guest_memory(&self, path: &Path) -> Result<()> {
+        let mut file = File::create(path).map_err(Error::DumpGuestMemory)?;
+        dump_guest_memory(&self.guest_memory, &self.arch_memory_info, &mut file)
+            .and_then(|_| file.sync_all())
+            .map_err(Error::DumpGuestMemory)
+    }
+
+    /// Saves the state of the paused microVM to a new file at `state_path` and its RAM to one at
+    /// `mem_path`, to resume it later with `builder::build_microvm_from_snapshot`, possibly in
+    /// another process. Call `pause_vcpus` first. The devices are quiesced until both files are
+    /// written, so that their state and the RAM agree, then resumed: the vCPUs stay paused until
+    /// `resume_vcpus`.
+    ///
+    /// Only microVMs with a single vCPU can be snapshotted, see the `snapshot` module for the
+    /// devices that can.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn snapshot(&self, state_path: &Path, mem_path: &Path) -> Result<()> {
+        if self.vcpus_handles.len() != 1 {
+            return Err(Error::Snapshot(snapshot::Error::VcpuCount(
+                self.vcpus_handles.len(),
+            )));
+        }
+        // Quiesced before anything is saved, their interrupts would change the state of the vCPUs
+        // and the interrupt controllers otherwise.
+        self.quiesce_devices(SNAPSHOT_QUIESCE_TIMEOUT)?;
+        let saved = self.save_microvm(state_path, mem_path);
+        self.resume_devices();
+        saved
+    }
+
+    // Saves the paused microVM for `snapshot`, once its devices are quiesced.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn save_microvm(&self, state_path: &Path, mem_path: &Path) -> Result<()> {
+        let mut vcpus = Vec::with_capacity(self.vcpus_handles.len());
+        for (id, handle) in self.vcpus_handles.iter().enumerate() {
+            handle
+                .send_request(VcpuEvent::SaveState)
+                .map_err(Error::VcpuEvent)?;
+            match handle.recv_response(self.vcpu_handshake_timeout) {
+                Some(VcpuResponse::SavedState(state)) => vcpus.push(state),
+                Some(VcpuResponse::SaveStateFailed) => {
+                    return Err(Error::Snapshot(snapshot::Error::SaveVcpu(id)))
+                }
+                _ => return Err(Error::VcpuNotPaused(id)),
+            }
+        }
+
+        let state = snapshot::MicrovmState {
+            host_cpuid: self.vm.supported_cpuid().as_slice().to_vec(),
+            cmdline: self.kernel_cmdline.as_str().to_string(),
+            memory_info: self.arch_memory_info.clone(),
+            vm: self
+                .vm
+                .save_state()
+                .map_err(|e| Error::Snapshot(snapshot::Error::Kvm(e)))?,
+            vcpus,
+            devices: self.save_devices().map_err(Error::Snapshot)?,
+        };
+
+        let mut state_file =
+            File::create(state_path).map_err(|e| Error::Snapshot(snapshot::Error::Io(e)))?;
+        state_file
+            .write_all(&state.encode())
+            .and_then(|_| state_file.sync_all())
+            .map_err(|e| Error::Snapshot(snapshot::Error::Io(e)))?;
+        self.dump_guest_memory(mem_path)
+    }
+
+    // Saves the state of the virtio devices, which must be quiesced.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn save_devices(&self) -> snapshot::Result<Vec<snapshot::DeviceState>> {
+        let mut devices = Vec::new();
+        for ((device_type, id), transport) in self.mmio_device_manager.virtio_devices() {
+            let DeviceType::Virtio(device_type) = *device_type;
+            let transport = transport
+                .lock()
+                .unwrap()
+                .save_state()
+                .ok_or_else(|| snapshot::Error::UnsupportedDevice(id.clone()))?;
+            devices.push(snapshot::DeviceState {
+                device_type,
+                id: id.clone(),
+                transport,
+            });
+        }
+        Ok(devices)
+    }
+
+    /// Asks the guest to grow or shrink its balloon to `pages` 4 KiB pages, giving their memory
+    /// back to the host or taking it back. This returns right away, see `wait_balloon`. A guest
+    /// without the balloon driver loaded picks the target up once it loads it.
+    #[cfg(not(feature = "tee"))]
+    pub fn set_balloon_target(&self, pages: u64) -> Result<()> {
+        let pages = balloon_target_pages(pages).map_err(Error::BalloonConfig)?;
+        if let Some(balloon) = &self.balloon_device {
+            balloon.lock().unwrap().set_target(pages);
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the balloon as last reported by the guest, in 4 KiB pages.
+    #[cfg(not(feature = "tee"))]
+    pub fn balloon_actual(&self) -> u64 {
+        self.balloon_device
+            .as_ref()
+            .map_or(0, |balloon| u64::from(balloon.lock().unwrap().actual()))
+    }
+
+    /// Waits up to `timeout` for the guest to bring its balloon to the target, returning its
+    /// size. A guest without the balloon driver leaves it empty, failing this with
+    /// `Error::BalloonTimeout`. The guest can't make progress while the event loop is blocked,
+    /// so this must be called from another thread than the one running it.
+    #[cfg(not(feature = "tee"))]
+    pub fn wait_balloon(&self, timeout: Duration) -> Result<u64> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+        let Some(balloon) = &self.balloon_device else {
+            return Ok(0);
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (target, actual) = {
+                let balloon = balloon.lock().unwrap();
+                (u64::from(balloon.target()), u64::from(balloon.actual()))
+            };
+            if actual == target {
+                return Ok(actual);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::BalloonTimeout { target, actual });
+            }
+            std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Returns the CID the guest is reachable at over vsock, if it has a vsock device.
+    pub fn vsock_cid(&self) -> Option<u32> {
+        self.vsock_cid.as_ref().map(|cid| cid.cid())
+    }
+
+    /// Logs the records of `module` and its submodules, e.g. `devices::virtio::fs`, up to `level`
+    /// from now on, while the guest keeps running. An empty `module` applies to every module
+    /// without a level of its own. This requires the logger installed by `krun_set_log_level`,
+    /// which embedders using their own logger don't have.
+    pub fn set_log_filter(&self, module: &str, level: log::LevelFilter) -> Result<()> {
+        utils::logger::set_module_level(module, level).map_err(Error::LogFilter)
+    }
+
+    /// Stops the virtio-fs, console, block and net devices from processing their queues, returning
+    /// once the requests in flight are completed, so that the guest memory and the host side of
+    /// the devices agree, e.g. before dumping the memory for a snapshot. Pause the vCPUs first, the
+    /// requests the guest queues meanwhile are only processed after `resume_devices`.
+    ///
+    /// The other devices, vsock in particular, keep processing theirs.
+    ///
+    /// A request can take arbitrarily long, e.g. on a hung file system, so each device waits up to
+    /// `timeout` for its own. If one still has some in flight, the devices are resumed and
+    /// `Error::QuiesceTimeout` returned.
+    pub fn quiesce_devices(&self, timeout: Duration) -> Result<()> {
+        for observer in &self.quiesce_observers {
+            let idle = observer
+                .lock()
+                .expect("Poisoned mutex for quiesce observer")
+                .on_quiesce(timeout);
+            if !idle {
+                self.resume_devices();
+                return Err(Error::QuiesceTimeout(timeout));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the latency histograms of the requests served by the virtio-fs device with the
+    /// given mount `tag`, by FUSE opcode, or `None` if there's no such device or it wasn't
+    /// configured to record them.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_stats(&self, tag: &str) -> Option<Vec<(u32, devices::virtio::LatencyHistogram)>> {
+        self.fs_devices
+            .iter()
+            .map(|fs| fs.lock().expect("Poisoned mutex for the fs device"))
+            .find(|fs| fs.tag() == tag)
+            .and_then(|fs| fs.stats())
+    }
+
+    /// Returns the id the virtio-fs device with the given mount `tag` is registered with, to look
+    /// it up with `get_bus_device`, or `None` if there's no such device.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_device_id(&self, tag: &str) -> Option<String> {
+        // Named like `attach_fs_devices` does.
+        let default_id = self.fs_devices.iter().enumerate().find_map(|(index, fs)| {
+            let fs = fs.lock().expect("Poisoned mutex for the fs device");
+            (fs.tag() == tag).then(|| format!("{}{index}", fs.id()))
+        })?;
+        Some(self.mmio_device_manager.device_id(&default_id))
+    }
+
+    /// Replaces the caps on the traffic the guest receives (`rx`) and sends (`tx`) on the network
+    /// interface `iface_id`, taking effect right away, including on the frames held back by the
+    /// previous caps.
+    #[cfg(feature = "net")]
+    pub fn update_net_rate_limit(
+        &self,
+        iface_id: &str,
+        rx: devices::virtio::net::RateLimit,
+        tx: devices::virtio::net::RateLimit,
+    ) -> Result<()> {
+        let mut net = self
+            .net_devices
+            .iter()
+            .map(|net| net.lock().expect("Poisoned mutex for the net device"))
+            .find(|net| net.id() == iface_id)
+            .ok_or(Error::UpdateNet(NetworkInterfaceError::DeviceIdNotFound))?;
+        net.set_rate_limits(rx, tx);
+        Ok(())
+    }
+
+    /// Lets the devices stopped by `quiesce_devices` process their queues again.
+    pub fn resume_devices(&self) {
+        for observer in &self.quiesce_observers {
+            observer
+                .lock()
+                .expect("Poisoned mutex for quiesce observer")
+                .on_resume();
+        }
+    }
+
     /// Returns a reference to the inner KVM Vm object.
     pub fn kvm_vm(&self) -> &Vm {
         &self.vm
     }
 
+    /// Reserves a free window of `len` bytes in the guest physical address space, suitable to be
+    /// passed to `add_mapping`. Windows are placed above the shared memory region, so they never
+    /// collide with guest RAM, MMIO devices or previously reserved windows.
+    #[cfg(target_os = "macos")]
+    pub fn reserve_guest_window(&mut self, len: u64) -> Result<GuestAddress> {
+        if len == 0 {
+            return Err(Error::InvalidGuestWindowSize);
+        }
+
+        // Safe because this call just returns the page size and doesn't have any side effects.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        let align_up = |addr: u64| {
+            addr.checked_add(page_size - 1)
+                .map(|a| a & !(page_size - 1))
+        };
+
+        let start = align_up(
+            self.next_guest_window_addr
+                .max(self.arch_memory_info.shm_start_addr + self.arch_memory_info.shm_size),
+        )
+        .ok_or(Error::GuestWindowExhausted(len))?;
+        let end = align_up(len)
+            .and_then(|len| start.checked_add(len))
+            .filter(|end| *end <= HVF_DEFAULT_IPA_LIMIT)
+            .ok_or(Error::GuestWindowExhausted(len))?;
+
+        self.next_guest_window_addr = end;
+        Ok(GuestAddress(start))
+    }
+
     #[cfg(target_os = "macos")]
     pub fn add_mapping(
         &self,
@@ -396,6 +1966,232 @@ impl Vmm {
     }
 }
 
+// Picks the exit code the microVM stops with out of the ones its vcpus reported, in vcpu
+// order. An error code takes precedence over FC_EXIT_CODE_OK, so a vcpu exiting cleanly at the
+// same time another one crashes doesn't hide the crash, and among several errors the first one
+// wins.
+fn select_exit_code(exit_codes: &[u8]) -> Option<u8> {
+    exit_codes
+        .iter()
+        .find(|&&exit_code| exit_code != FC_EXIT_CODE_OK)
+        .or(exit_codes.first())
+        .copied()
+}
+
+fn memory_info(guest_memory: &GuestMemoryMmap, info: &ArchMemoryInfo) -> MemoryInfo {
+    // Every guest memory region but the shared memory one is RAM. Adjacent regions, like the
+    // kernel injected in the middle of the RAM, are reported as a single range.
+    let mut ram_ranges: Vec<(u64, u64)> = Vec::new();
+    for region in guest_memory.iter() {
+        let start = region.start_addr().0;
+        if info.shm_size != 0 && start == info.shm_start_addr {
+            continue;
+        }
+        match ram_ranges.last_mut() {
+            Some((last_start, last_size)) if *last_start + *last_size == start => {
+                *last_size += region.len()
+            }
+            _ => ram_ranges.push((start, region.len())),
+        }
+    }
+
+    MemoryInfo {
+        ram_size: ram_ranges.iter().map(|(_, size)| size).sum(),
+        ram_ranges,
+        reserved_ranges: info.reserved_ranges.clone(),
+        shm_range: (info.shm_start_addr, info.shm_size),
+        #[cfg(target_arch = "x86_64")]
+        mmio_gap: (arch::MMIO_MEM_START, arch::x86_64::MEM_32BIT_GAP_SIZE),
+        #[cfg(target_arch = "aarch64")]
+        mmio_base: arch::MMIO_MEM_START,
+    }
+}
+
+// Writes the dump described in `Vmm::dump_guest_memory` to `out`, a chunk at a time so large
+// guests don't need as much host memory.
+fn dump_guest_memory<W: Write>(
+    guest_memory: &GuestMemoryMmap,
+    info: &ArchMemoryInfo,
+    out: &mut W,
+) -> io::Result<()> {
+    let ram_ranges = memory_info(guest_memory, info).ram_ranges;
+
+    out.write_all(GUEST_MEMORY_DUMP_MAGIC)?;
+    out.write_all(&GUEST_MEMORY_DUMP_VERSION.to_le_bytes())?;
+    out.write_all(&(ram_ranges.len() as u32).to_le_bytes())?;
+    for &(start, size) in &ram_ranges {
+        out.write_all(&start.to_le_bytes())?;
+        out.write_all(&size.to_le_bytes())?;
+    }
+    write_guest_ranges(guest_memory, &ram_ranges, out)?;
+    out.flush()
+}
+
+// Reads a dump written by `dump_guest_memory` back into `guest_memory`, which must have the same
+// RAM ranges as the dumped one, a chunk at a time.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn load_guest_memory<R: io::Read>(
+    guest_memory: &GuestMemoryMmap,
+    info: &ArchMemoryInfo,
+    input: &mut R,
+) -> io::Result<()> {
+    let ram_ranges = memory_info(guest_memory, info).ram_ranges;
+
+    let mut header = [0u8; 16];
+    input.read_exact(&mut header)?;
+    if header[..8] != GUEST_MEMORY_DUMP_MAGIC[..]
+        || header[8..12] != GUEST_MEMORY_DUMP_VERSION.to_le_bytes()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a guest memory dump of a supported version",
+        ));
+    }
+    let count = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    let mut dumped_ranges = Vec::new();
+    for _ in 0..count.min(ram_ranges.len() + 1) {
+        let mut range = [0u8; 16];
+        input.read_exact(&mut range)?;
+        dumped_ranges.push((
+            u64::from_le_bytes(range[..8].try_into().unwrap()),
+            u64::from_le_bytes(range[8..].try_into().unwrap()),
+        ));
+    }
+    if dumped_ranges != ram_ranges {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the guest memory dump has other RAM ranges than the guest",
+        ));
+    }
+
+    let mut chunk = vec![0; GUEST_MEMORY_DUMP_CHUNK_SIZE];
+    for (start, size) in ram_ranges {
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(chunk.len() as u64) as usize;
+            input.read_exact(&mut chunk[..len])?;
+            guest_memory
+                .write_slice(&chunk[..len], GuestAddress(start + offset))
+                .map_err(io::Error::other)?;
+            offset += len as u64;
+        }
+    }
+    Ok(())
+}
+
+// Copies the `(start, size)` ranges of the guest memory to `out` in turn, a chunk at a time.
+fn write_guest_ranges<W: Write>(
+    guest_memory: &GuestMemoryMmap,
+    ranges: &[(u64, u64)],
+    out: &mut W,
+) -> io::Result<()> {
+    let mut chunk = vec![0; GUEST_MEMORY_DUMP_CHUNK_SIZE];
+    for &(start, size) in ranges {
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(chunk.len() as u64) as usize;
+            guest_memory
+                .read_slice(&mut chunk[..len], GuestAddress(start + offset))
+                .map_err(io::Error::other)?;
+            out.write_all(&chunk[..len])?;
+            offset += len as u64;
+        }
+    }
+    Ok(())
+}
+
+// Checks that the `len` bytes at `gpa` are below the end of the guest RAM and out of the reserved
+// ranges. The holes below the end, like the MMIO gap of x86_64, aren't part of the guest memory,
+// accessing them fails anyway.
+fn check_guest_ram_range(info: &ArchMemoryInfo, gpa: GuestAddress, len: usize) -> Result<()> {
+    let start = gpa.0;
+    let in_ram = start.checked_add(len as u64).is_some_and(|end| {
+        end <= info.ram_last_addr
+            && info
+                .reserved_ranges
+                .iter()
+                .all(|&(reserved, size)| end <= reserved || start >= reserved + size)
+    });
+    if in_ram {
+        Ok(())
+    } else {
+        Err(Error::GuestMemoryAccess(gpa, len))
+    }
+}
+
+// Counts the resident and faulted in pages of the guest memory, from the page map of the process.
+#[cfg(target_os = "linux")]
+fn guest_memory_metrics(guest_memory: &GuestMemoryMmap) -> io::Result<MemoryMetrics> {
+    use std::os::unix::fs::FileExt;
+
+    const PAGEMAP_ENTRY_SIZE: usize = 8;
+    const PM_PRESENT: u64 = 1 << 63;
+    const PM_SWAP: u64 = 1 << 62;
+    // Page map entries read at once.
+    const BATCH_LEN: usize = 4096;
+
+    // Safe because this call just returns the page size and doesn't have any side effects.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    let pagemap = std::fs::File::open("/proc/self/pagemap")?;
+
+    let mut metrics = MemoryMetrics::default();
+    let mut buf = vec![0u8; BATCH_LEN * PAGEMAP_ENTRY_SIZE];
+    for region in guest_memory.iter() {
+        metrics.committed += region.len();
+
+        let first_page = region.as_ptr() as usize / page_size;
+        let num_pages = (region.len() as usize).div_ceil(page_size);
+        let mut page = 0;
+        while page < num_pages {
+            let len = (num_pages - page).min(BATCH_LEN);
+            let buf = &mut buf[..len * PAGEMAP_ENTRY_SIZE];
+            pagemap.read_exact_at(buf, ((first_page + page) * PAGEMAP_ENTRY_SIZE) as u64)?;
+            for entry in buf.chunks_exact(PAGEMAP_ENTRY_SIZE) {
+                let entry = u64::from_ne_bytes(entry.try_into().unwrap());
+                if entry & PM_PRESENT != 0 {
+                    metrics.resident += page_size as u64;
+                }
+                if entry & (PM_PRESENT | PM_SWAP) != 0 {
+                    metrics.faulted_pages += 1;
+                }
+            }
+            page += len;
+        }
+    }
+    Ok(metrics)
+}
+
+// Counts the resident pages of the guest memory with `mincore`.
+#[cfg(target_os = "macos")]
+fn guest_memory_metrics(guest_memory: &GuestMemoryMmap) -> io::Result<MemoryMetrics> {
+    // Safe because this call just returns the page size and doesn't have any side effects.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+
+    let mut metrics = MemoryMetrics::default();
+    for region in guest_memory.iter() {
+        metrics.committed += region.len();
+
+        let mut vec = vec![0 as libc::c_char; (region.len() as usize).div_ceil(page_size)];
+        // Safe because the region is a valid mapping owned by `guest_memory` for its whole
+        // length, and `vec` has room for a byte per page of it.
+        let ret = unsafe {
+            libc::mincore(
+                region.as_ptr() as *const libc::c_void,
+                region.len() as usize,
+                vec.as_mut_ptr(),
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Bit 0 of each byte is `MINCORE_INCORE`.
+        let resident_pages = vec.iter().filter(|page| **page & 1 != 0).count() as u64;
+        metrics.resident += resident_pages * page_size as u64;
+        metrics.faulted_pages += resident_pages;
+    }
+    Ok(metrics)
+}
+
 impl Subscriber for Vmm {
     /// Handle a read event (EPOLLIN).
     fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
@@ -404,28 +2200,225 @@ impl Subscriber for Vmm {
 
         if source == self.exit_evt.as_raw_fd() && event_set == EventSet::IN {
             let _ = self.exit_evt.read();
-            // Query each vcpu for the exit_code.
-            // If the exit_code can't be found on any vcpu, it means that the exit signal
-            // has been issued by the i8042 controller in which case we exit with
-            // FC_EXIT_CODE_OK.
-            let exit_code = self
-                .vcpus_handles
-                .iter()
-                .find_map(|handle| match handle.response_receiver().try_recv() {
-                    Ok(VcpuResponse::Exited(exit_code)) => Some(exit_code),
-                    _ => None,
-                })
-                .unwrap_or(FC_EXIT_CODE_OK);
+            let exit_code = self.vcpus_exit_code();
             self.stop(i32::from(exit_code));
+        } else if self
+            .guest_panic_evt
+            .as_ref()
+            .is_some_and(|evt| source == evt.as_raw_fd() && event_set == EventSet::IN)
+        {
+            let _ = self.guest_panic_evt.as_ref().unwrap().read();
+            #[cfg(target_arch = "aarch64")]
+            self.handle_guest_panic();
         } else {
+            #[cfg(target_os = "linux")]
+            if let Some(evt) = self.guest_signal_evt.as_ref() {
+                if source == evt.as_raw_fd() && event_set == EventSet::IN {
+                    let _ = evt.read();
+                    self.handle_guest_signals();
+                    return;
+                }
+            }
             error!("Spurious EventManager event for handler: Vmm");
         }
     }
 
     fn interest_list(&self) -> Vec<EpollEvent> {
-        vec![EpollEvent::new(
+        let mut events = vec![EpollEvent::new(
             EventSet::IN,
             self.exit_evt.as_raw_fd() as u64,
-        )]
+        )];
+        if let Some(evt) = self.guest_panic_evt.as_ref() {
+            events.push(EpollEvent::new(EventSet::IN, evt.as_raw_fd() as u64));
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(evt) = self.guest_signal_evt.as_ref() {
+            events.push(EpollEvent::new(EventSet::IN, evt.as_raw_fd() as u64));
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::Bytes;
+
+    #[test]
+    fn test_select_exit_code() {
+        assert_eq!(select_exit_code(&[]), None);
+        assert_eq!(select_exit_code(&[FC_EXIT_CODE_OK]), Some(FC_EXIT_CODE_OK));
+        assert_eq!(
+            select_exit_code(&[FC_EXIT_CODE_OK, FC_EXIT_CODE_GENERIC_ERROR, 3]),
+            Some(FC_EXIT_CODE_GENERIC_ERROR)
+        );
+        assert_eq!(select_exit_code(&[3, FC_EXIT_CODE_OK]), Some(3));
+    }
+
+    #[test]
+    fn test_memory_info() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x3000),
+            (GuestAddress(0x10_0000), 0x1000),
+            (GuestAddress(0x4000_0000), 0x2000),
+        ])
+        .unwrap();
+        let info = ArchMemoryInfo {
+            ram_last_addr: 0x10_1000,
+            shm_start_addr: 0x4000_0000,
+            shm_size: 0x2000,
+            reserved_ranges: vec![(0x4000, 0x1000)],
+            ..Default::default()
+        };
+
+        let memory_info = memory_info(&guest_memory, &info);
+        assert_eq!(memory_info.ram_size, 0x5000);
+        assert_eq!(memory_info.ram_ranges, [(0, 0x4000), (0x10_0000, 0x1000)]);
+        assert_eq!(memory_info.reserved_ranges, [(0x4000, 0x1000)]);
+        assert_eq!(memory_info.shm_range, (0x4000_0000, 0x2000));
+    }
+
+    #[test]
+    fn test_dump_guest_memory() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+            (
+                GuestAddress(0x20_0000),
+                GUEST_MEMORY_DUMP_CHUNK_SIZE + 0x1000,
+            ),
+            (GuestAddress(0x4000_0000), 0x1000),
+        ])
+        .unwrap();
+        let info = ArchMemoryInfo {
+            shm_start_addr: 0x4000_0000,
+            shm_size: 0x1000,
+            ..Default::default()
+        };
+        guest_memory
+            .write_slice(b"low", GuestAddress(0xffe))
+            .unwrap();
+        let high_end = 0x20_0000 + GUEST_MEMORY_DUMP_CHUNK_SIZE as u64 + 0x1000;
+        guest_memory
+            .write_slice(b"high", GuestAddress(high_end - 4))
+            .unwrap();
+        guest_memory
+            .write_slice(b"shm", GuestAddress(0x4000_0000))
+            .unwrap();
+
+        let mut dump = Vec::new();
+        dump_guest_memory(&guest_memory, &info, &mut dump).unwrap();
+
+        let (header, data) = dump.split_at(48);
+        assert_eq!(&header[..8], GUEST_MEMORY_DUMP_MAGIC);
+        assert_eq!(header[8..12], GUEST_MEMORY_DUMP_VERSION.to_le_bytes());
+        assert_eq!(header[12..16], 2u32.to_le_bytes());
+        let range = |i: usize| {
+            let field =
+                |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+            (field(16 + i * 16), field(24 + i * 16))
+        };
+        assert_eq!(range(0), (0, 0x2000));
+        assert_eq!(range(1), (0x20_0000, high_end - 0x20_0000));
+
+        assert_eq!(data.len() as u64, 0x2000 + high_end - 0x20_0000);
+        assert_eq!(&data[0xffe..0x1001], b"low");
+        assert_eq!(&data[data.len() - 4..], b"high");
+        assert!(!data.windows(3).any(|w| w == b"shm"));
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn test_load_guest_memory() {
+        let ranges = [
+            (GuestAddress(0), 0x2000),
+            (
+                GuestAddress(0x20_0000),
+                GUEST_MEMORY_DUMP_CHUNK_SIZE + 0x1000,
+            ),
+        ];
+        let info = ArchMemoryInfo::default();
+        let guest_memory = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+        guest_memory
+            .write_slice(b"low", GuestAddress(0xffe))
+            .unwrap();
+        let high_end = 0x20_0000 + GUEST_MEMORY_DUMP_CHUNK_SIZE as u64 + 0x1000;
+        guest_memory
+            .write_slice(b"high", GuestAddress(high_end - 4))
+            .unwrap();
+        let mut dump = Vec::new();
+        dump_guest_memory(&guest_memory, &info, &mut dump).unwrap();
+
+        let restored = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+        load_guest_memory(&restored, &info, &mut &dump[..]).unwrap();
+        let mut buf = [0u8; 4];
+        restored
+            .read_slice(&mut buf[..3], GuestAddress(0xffe))
+            .unwrap();
+        assert_eq!(&buf[..3], b"low");
+        restored
+            .read_slice(&mut buf, GuestAddress(high_end - 4))
+            .unwrap();
+        assert_eq!(&buf, b"high");
+
+        // The guest must have the same RAM ranges as the dumped one.
+        let smaller = GuestMemoryMmap::from_ranges(&ranges[..1]).unwrap();
+        assert_eq!(
+            load_guest_memory(&smaller, &info, &mut &dump[..])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(load_guest_memory(&restored, &info, &mut &dump[..dump.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_check_guest_ram_range() {
+        let info = ArchMemoryInfo {
+            ram_last_addr: 0x10_0000,
+            shm_start_addr: 0x4000_0000,
+            shm_size: 0x1000,
+            reserved_ranges: vec![(0x8000, 0x1000)],
+            ..Default::default()
+        };
+        assert!(check_guest_ram_range(&info, GuestAddress(0x1000), 0x7000).is_ok());
+        assert!(check_guest_ram_range(&info, GuestAddress(0xf_f000), 0x1000).is_ok());
+
+        for (addr, len) in [
+            // Overlaps the reserved range.
+            (0x7000, 0x1001),
+            (0x8fff, 1),
+            // Goes past the end of RAM, or wraps around.
+            (0xf_f000, 0x1001),
+            (0x4000_0000, 0x10),
+            (u64::MAX, 2),
+        ] {
+            assert!(matches!(
+                check_guest_ram_range(&info, GuestAddress(addr), len),
+                Err(Error::GuestMemoryAccess(a, l)) if a.0 == addr && l == len
+            ));
+        }
+    }
+
+    #[test]
+    fn test_guest_memory_metrics() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 16 * page_size as usize)]).unwrap();
+
+        let metrics = guest_memory_metrics(&guest_memory).unwrap();
+        assert_eq!(metrics.committed, 16 * page_size);
+        assert_eq!(metrics.resident, 0);
+        assert_eq!(metrics.faulted_pages, 0);
+
+        for page in [0, 3, 15] {
+            guest_memory
+                .write_obj(1u8, GuestAddress(page * page_size))
+                .unwrap();
+        }
+        let metrics = guest_memory_metrics(&guest_memory).unwrap();
+        assert_eq!(metrics.resident, 3 * page_size);
+        assert_eq!(metrics.faulted_pages, 3);
     }
 }
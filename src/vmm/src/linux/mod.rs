@@ -1,4 +1,5 @@
 #[cfg(feature = "tee")]
 pub mod tee;
 
+pub mod seccomp;
 pub mod vstate;
@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The system calls each kind of VMM thread is restricted to, see `VmResources::seccomp_mode`.
+
+use utils::seccomp::{Error, SeccompFilter, SeccompFilterBuilder, SeccompMode};
+
+// Type of the KVM ioctls (linux/kvm.h).
+const KVMIO: u8 = 0xae;
+// Asked by the console devices, whose registers the vCPUs access.
+const TIOCGWINSZ: u32 = 0x5413;
+
+// What any thread needs: allocating memory, locking, signals, logging, closing files, which
+// the standard library checks with `fcntl` in debug builds, and exiting.
+fn common() -> SeccompFilterBuilder {
+    SeccompFilterBuilder::new().allow(&[
+        libc::SYS_brk,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_close,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_fcntl,
+        libc::SYS_futex,
+        libc::SYS_getpid,
+        libc::SYS_getrandom,
+        libc::SYS_gettid,
+        libc::SYS_madvise,
+        libc::SYS_mmap,
+        libc::SYS_mprotect,
+        libc::SYS_mremap,
+        libc::SYS_munmap,
+        libc::SYS_nanosleep,
+        libc::SYS_read,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_sched_yield,
+        libc::SYS_sigaltstack,
+        libc::SYS_tgkill,
+        libc::SYS_write,
+        libc::SYS_writev,
+    ])
+}
+
+/// Returns the filter of the vCPU threads, which run the guest and emulate the devices' registers
+/// it accesses: the KVM ioctls, the kick timer, and signaling the device threads through
+/// eventfds. The devices the guest activates are set up on an unfiltered thread instead.
+pub fn vcpu_filter(mode: SeccompMode) -> Result<Option<SeccompFilter>, Error> {
+    common()
+        .allow(&[
+            libc::SYS_lseek,
+            libc::SYS_pread64,
+            libc::SYS_pwrite64,
+            libc::SYS_timer_create,
+            libc::SYS_timer_delete,
+            libc::SYS_timer_settime,
+        ])
+        .allow_ioctl_type(KVMIO)
+        .allow_ioctls(&[TIOCGWINSZ])
+        .build(mode)
+}
+
+/// Returns the filter of the virtio-fs worker threads, which in addition to waiting on their
+/// queues make all the file system calls of the passthrough file system, including the ioctls the
+/// guest is allowed to forward and the file handles it reopens the inodes with.
+pub fn fs_filter(mode: SeccompMode) -> Result<Option<SeccompFilter>, Error> {
+    let builder = common().allow(&[
+        libc::SYS_copy_file_range,
+        libc::SYS_dup,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_fallocate,
+        libc::SYS_fchmod,
+        libc::SYS_fchmodat,
+        libc::SYS_fchown,
+        libc::SYS_fchownat,
+        libc::SYS_fdatasync,
+        libc::SYS_fgetxattr,
+        libc::SYS_flistxattr,
+        libc::SYS_flock,
+        libc::SYS_fremovexattr,
+        libc::SYS_fsetxattr,
+        libc::SYS_fstat,
+        libc::SYS_fstatfs,
+        libc::SYS_fsync,
+        libc::SYS_ftruncate,
+        libc::SYS_getdents64,
+        libc::SYS_getxattr,
+        libc::SYS_ioctl,
+        libc::SYS_lgetxattr,
+        libc::SYS_linkat,
+        libc::SYS_listxattr,
+        libc::SYS_llistxattr,
+        libc::SYS_lremovexattr,
+        libc::SYS_lseek,
+        libc::SYS_lsetxattr,
+        libc::SYS_mkdirat,
+        libc::SYS_mknodat,
+        libc::SYS_name_to_handle_at,
+        libc::SYS_newfstatat,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_openat,
+        libc::SYS_pread64,
+        libc::SYS_preadv,
+        libc::SYS_preadv2,
+        libc::SYS_pwrite64,
+        libc::SYS_pwritev,
+        libc::SYS_pwritev2,
+        libc::SYS_readlinkat,
+        libc::SYS_readv,
+        libc::SYS_removexattr,
+        libc::SYS_renameat2,
+        libc::SYS_setresgid,
+        libc::SYS_setresuid,
+        libc::SYS_setxattr,
+        libc::SYS_statfs,
+        libc::SYS_statx,
+        libc::SYS_symlinkat,
+        libc::SYS_umask,
+        libc::SYS_unlinkat,
+        libc::SYS_utimensat,
+    ]);
+    // The system calls aarch64 only has the `*at` variants of.
+    #[cfg(target_arch = "x86_64")]
+    let builder = builder.allow(&[
+        libc::SYS_epoll_wait,
+        libc::SYS_open,
+        libc::SYS_renameat,
+        libc::SYS_stat,
+    ]);
+    builder.build(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devices::virtio::fs::fuse::ROOT_ID;
+    use devices::virtio::fs::passthrough::{Config, PassthroughFs};
+    use devices::virtio::fs::{Context, FileSystem};
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::num::NonZeroUsize;
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_filters() {
+        for mode in [SeccompMode::Log, SeccompMode::Kill] {
+            let vcpu = vcpu_filter(mode).unwrap().unwrap();
+            let fs = fs_filter(mode).unwrap().unwrap();
+            assert!(fs.len() > vcpu.len());
+        }
+        assert!(vcpu_filter(SeccompMode::Off).unwrap().is_none());
+        assert!(fs_filter(SeccompMode::Off).unwrap().is_none());
+    }
+
+    // Looks up more files than `fs` keeps open, forgetting the oldest ones, so that their files
+    // are closed and reopened through their handles.
+    fn lookup_and_evict(fs: &PassthroughFs, ctx: Context, names: &[CString]) -> io::Result<()> {
+        let mut referenced = Vec::new();
+        for name in names.iter().cycle().take(4 * names.len()) {
+            referenced.push(fs.lookup(ctx, ROOT_ID, name)?.inode);
+            if referenced.len() > 2 * names.len() / 3 {
+                fs.forget(ctx, referenced.remove(0), 1);
+            }
+        }
+        for &inode in &referenced {
+            fs.getattr(ctx, inode, None)?;
+        }
+        fs.batch_forget(ctx, referenced.into_iter().map(|i| (i, 1)).collect());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_filter_passthrough() {
+        let dir = TempDir::new().unwrap();
+        let names: Vec<CString> = (0..16)
+            .map(|i| {
+                File::create(dir.as_path().join(format!("f{i}"))).unwrap();
+                CString::new(format!("f{i}")).unwrap()
+            })
+            .collect();
+        let fs = PassthroughFs::new(Config {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            max_inodes: NonZeroUsize::new(8),
+            ..Default::default()
+        })
+        .unwrap();
+        fs.init(devices::virtio::fs::fuse::FsOptions::empty())
+            .unwrap();
+        // Safe because these calls can't fail.
+        let ctx = Context {
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            pid: 0,
+        };
+        // Without the privilege to open file handles the lookups run out of files instead.
+        let expected = lookup_and_evict(&fs, ctx, &names).is_ok();
+
+        let filter = fs_filter(SeccompMode::Kill).unwrap().unwrap();
+        // Safe because the child only makes the calls of the file system before exiting.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            if filter.apply().is_err() {
+                unsafe { libc::_exit(2) };
+            }
+            let ok = lookup_and_evict(&fs, ctx, &names).is_ok();
+            unsafe { libc::_exit(i32::from(!ok)) };
+        }
+        // Without a `SIGSYS` handler a denied system call kills the child.
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status), "status {status:#x}");
+        assert_eq!(libc::WEXITSTATUS(status) == 0, expected);
+    }
+}
@@ -7,6 +7,7 @@
 
 use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
 use libc::{c_int, c_void, siginfo_t};
+use libc::{c_uint, c_ulong};
 use std::cell::Cell;
 use std::fmt::{Display, Formatter};
 use std::io;
@@ -15,13 +16,17 @@ use std::io;
 use std::os::unix::io::RawFd;
 
 use std::result;
-use std::sync::atomic::{fence, Ordering};
-#[cfg(not(test))]
-use std::sync::Barrier;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use super::super::TimestampUs;
 use super::super::{FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK};
+use crate::hypercall::Hypercalls;
+#[cfg(target_arch = "aarch64")]
+use crate::hypercall::MAX_HYPERCALL_ID;
+use crate::{GsiDestination, GsiRoute};
 
 #[cfg(feature = "amd-sev")]
 use super::tee::amdsev::{AmdSev, Error as SevError};
@@ -35,21 +40,38 @@ use kbs_types::Tee;
 #[cfg(feature = "tee")]
 use crate::resources::TeeConfig;
 use crate::vmm_config::machine_config::CpuFeaturesTemplate;
+#[cfg(target_arch = "x86_64")]
+use crate::vmm_config::machine_config::{CpuidOverride, CpuidRegister};
 use arch;
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::GICDevice;
 #[cfg(target_arch = "x86_64")]
 use cpuid::{c3, filter_cpuid, t2, VmSpec};
+#[cfg(target_arch = "aarch64")]
+use kvm_bindings::KVM_GUESTDBG_USE_HW;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::KVM_GUESTDBG_USE_HW_BP;
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
-    kvm_clock_data, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_pit_config,
-    kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, MsrList,
-    Msrs, KVM_CLOCK_TSC_STABLE, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
-    KVM_MAX_CPUID_ENTRIES, KVM_PIT_SPEAKER_DUMMY,
+    kvm_clock_data, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_nested_state,
+    kvm_pit_config, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+    CpuId, MsrList, Msrs, KVM_CAP_NESTED_STATE, KVM_CLOCK_TSC_STABLE, KVM_IRQCHIP_IOAPIC,
+    KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_MAX_CPUID_ENTRIES, KVM_MP_STATE_HALTED,
+    KVM_PIT_SPEAKER_DUMMY, KVM_STATE_NESTED_VMX_VMCS_SIZE,
+};
+use kvm_bindings::{
+    kvm_debug_exit_arch, kvm_guest_debug, kvm_irq_routing, kvm_irq_routing_entry,
+    kvm_irq_routing_irqchip, kvm_irq_routing_msi, kvm_userspace_memory_region, KVM_API_VERSION,
+    KVM_EXIT_IO, KVM_EXIT_IO_OUT, KVM_EXIT_MMIO, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP,
+    KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI,
 };
-use kvm_bindings::{kvm_userspace_memory_region, KVM_API_VERSION};
 use kvm_ioctls::*;
 use utils::eventfd::EventFd;
+#[cfg(target_arch = "x86_64")]
+use utils::ioctl::{ioctl_expr, ioctl_with_mut_ptr, ioctl_with_ptr, _IOC_READ, _IOC_WRITE};
+#[cfg(target_arch = "aarch64")]
+use utils::ioctl::{ioctl_expr, ioctl_with_ref, _IOC_WRITE};
+use utils::seccomp::{self, SeccompFilter};
 use utils::signal::{register_signal_handler, sigrtmin, Killable};
 use utils::sm::StateMachine;
 use vm_memory::{
@@ -71,6 +93,90 @@ const MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE: u8 = 123;
 /// Signal number (SIGRTMIN) used to kick Vcpus.
 pub(crate) const VCPU_RTSIG_OFFSET: i32 = 0;
 
+/// How often a Vcpu watching for a guest halt is kicked out of `KVM_RUN` to check for it.
+#[cfg(target_arch = "x86_64")]
+const HALT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+#[cfg(target_arch = "x86_64")]
+const X86_EFLAGS_IF: u64 = 1 << 9;
+// Not exported by libc on every target environment.
+const SIGEV_THREAD_ID: c_int = 4;
+
+// KVM paravirtual CPUID leaf and the kvmclock feature bit `ptp_kvm` depends on.
+#[cfg(target_arch = "x86_64")]
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+#[cfg(target_arch = "x86_64")]
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 3;
+// KVM_REG_ARM_VENDOR_HYP_BMAP, the firmware register selecting the KVM hypercall services.
+#[cfg(target_arch = "aarch64")]
+const KVM_REG_ARM_VENDOR_HYP_BMAP: u64 = kvm_bindings::KVM_REG_ARM64
+    | kvm_bindings::KVM_REG_SIZE_U64
+    | kvm_bindings::KVM_REG_ARM_FW_FEAT_BMAP as u64
+    | 2;
+
+const KVMIO: c_uint = 0xAE;
+// kvm-ioctls doesn't wrap the nested state ioctls yet.
+#[cfg(target_arch = "x86_64")]
+const KVM_GET_NESTED_STATE: c_ulong = ioctl_expr(
+    _IOC_READ | _IOC_WRITE,
+    KVMIO,
+    0xbe,
+    std::mem::size_of::<kvm_nested_state>() as c_uint,
+);
+#[cfg(target_arch = "x86_64")]
+const KVM_SET_NESTED_STATE: c_ulong = ioctl_expr(
+    _IOC_WRITE,
+    KVMIO,
+    0xbf,
+    std::mem::size_of::<kvm_nested_state>() as c_uint,
+);
+
+// kvm-ioctls doesn't wrap the VM device attributes, nor kvm-bindings the SMCCC filter.
+#[cfg(target_arch = "aarch64")]
+const KVM_SET_DEVICE_ATTR: c_ulong = ioctl_expr(
+    _IOC_WRITE,
+    KVMIO,
+    0xe1,
+    std::mem::size_of::<kvm_bindings::kvm_device_attr>() as c_uint,
+);
+#[cfg(target_arch = "aarch64")]
+const KVM_ARM_VM_SMCCC_CTRL: u32 = 0;
+#[cfg(target_arch = "aarch64")]
+const KVM_ARM_VM_SMCCC_FILTER: u64 = 0;
+#[cfg(target_arch = "aarch64")]
+const KVM_SMCCC_FILTER_FWD_TO_USER: u8 = 2;
+
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+#[derive(Default)]
+struct KvmSmcccFilter {
+    base: u32,
+    nr_functions: u32,
+    action: u8,
+    pad: [u8; 15],
+}
+
+// SMCCC fast call function IDs of the OEM service range, for the SMC32 and SMC64 conventions.
+#[cfg(target_arch = "aarch64")]
+const SMCCC_OEM_SMC32_BASE: u32 = 0x8300_0000;
+#[cfg(target_arch = "aarch64")]
+const SMCCC_OEM_SMC64_BASE: u32 = 0xc300_0000;
+#[cfg(target_arch = "aarch64")]
+const SMCCC_RET_NOT_SUPPORTED: u64 = -1i64 as u64;
+
+// Id of the `index`th u64 of `struct user_pt_regs`, which holds x0..x30, sp and pc.
+#[cfg(target_arch = "aarch64")]
+const fn arm64_core_reg(index: u64) -> u64 {
+    kvm_bindings::KVM_REG_ARM64
+        | kvm_bindings::KVM_REG_SIZE_U64
+        | kvm_bindings::KVM_REG_ARM_CORE as u64
+        | index * (std::mem::size_of::<u64>() / std::mem::size_of::<u32>()) as u64
+}
+#[cfg(target_arch = "aarch64")]
+const ARM64_REG_PC: u64 = arm64_core_reg(32);
+// `struct user_pt_regs` is followed by `sp_el1` in `struct kvm_regs`.
+#[cfg(target_arch = "aarch64")]
+const ARM64_REG_SP_EL1: u64 = arm64_core_reg(34);
+
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
 pub enum Error {
@@ -105,8 +211,19 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     /// Error configuring the MSR registers
     MSRSConfiguration(arch::x86_64::msr::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Nested virtualization was requested but the host doesn't allow it.
+    NestedVirtUnsupported,
     /// The number of configured slots is bigger than the maximum reported by KVM.
     NotEnoughMemorySlots,
+    /// The `ptp_kvm` clock was requested but the host doesn't provide it.
+    PtpKvmUnsupported,
+    #[cfg(target_arch = "aarch64")]
+    /// A guest PMU was requested but the host doesn't provide it.
+    PmuUnsupported,
+    #[cfg(target_arch = "aarch64")]
+    /// SVE was requested but the host doesn't provide it.
+    SveUnsupported,
     #[cfg(target_arch = "aarch64")]
     /// Error configuring the general purpose aarch64 registers.
     REGSConfiguration(arch::aarch64::regs::Error),
@@ -118,6 +235,12 @@ pub enum Error {
     SetupGIC(arch::aarch64::gic::Error),
     /// Cannot set the memory regions.
     SetUserMemoryRegion(kvm_ioctls::Error),
+    /// KVM doesn't support read-only memory slots.
+    ReadOnlyMemUnsupported,
+    /// Cannot allocate the host memory backing a read-only region.
+    ReadOnlyMemAlloc(vm_memory::mmap::MmapRegionError),
+    /// Cannot get the dirty page bitmap of a memory slot.
+    GetDirtyLog(kvm_ioctls::Error),
     #[cfg(feature = "amd-sev")]
     /// Error initializing the Secure Virtualization Backend (SEV).
     SevSecVirtInit(SevError),
@@ -145,9 +268,21 @@ pub enum Error {
     /// Error configuring the special registers
     SREGSConfiguration(arch::x86_64::regs::Error),
     #[cfg(target_arch = "aarch64")]
+    /// Error enabling the KVM PTP hypercall service on Arm.
+    VcpuArmEnablePtp(kvm_ioctls::Error),
+    #[cfg(target_arch = "aarch64")]
     /// Error doing Vcpu Init on Arm.
     VcpuArmInit(kvm_ioctls::Error),
     #[cfg(target_arch = "aarch64")]
+    /// Error finalizing the SVE configuration of the Vcpu on Arm.
+    VcpuArmFinalizeSve(kvm_ioctls::Error),
+    #[cfg(target_arch = "aarch64")]
+    /// Error initializing the PMU of the Vcpu on Arm.
+    VcpuArmPmuInit(kvm_ioctls::Error),
+    #[cfg(target_arch = "aarch64")]
+    /// Error accessing the registers of a hypercall on Arm.
+    VcpuArmHypercall(kvm_ioctls::Error),
+    #[cfg(target_arch = "aarch64")]
     /// Error getting the Vcpu preferred target on Arm.
     VcpuArmPreferredTarget(kvm_ioctls::Error),
     /// vCPU count is not initialized.
@@ -167,6 +302,9 @@ pub enum Error {
     /// Failed to get KVM vcpu msrs.
     VcpuGetMsrs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
+    /// Failed to get KVM vcpu nested state.
+    VcpuGetNestedState(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
     /// Failed to get KVM vcpu regs.
     VcpuGetRegs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -199,9 +337,15 @@ pub enum Error {
     /// Failed to set KVM vcpu msrs.
     VcpuSetMsrs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
+    /// Failed to set KVM vcpu nested state.
+    VcpuSetNestedState(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
     /// Failed to set KVM vcpu regs.
     VcpuSetRegs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
+    /// Failed to set the KVM vcpu TSC frequency.
+    VcpuSetTscKhz(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
     /// Failed to set KVM vcpu sregs.
     VcpuSetSregs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -215,12 +359,18 @@ pub enum Error {
     VcpuSetXsave(kvm_ioctls::Error),
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(io::Error),
+    /// Cannot install the seccomp filter of the vCPU thread.
+    VcpuSeccompFilter(seccomp::Error),
+    /// The vCPU thread panicked.
+    VcpuThreadPanicked,
     /// Cannot cleanly initialize vcpu TLS.
     VcpuTlsInit,
     /// Vcpu not present in TLS.
     VcpuTlsNotPresent,
     /// Unexpected KVM_RUN exit reason
     VcpuUnhandledKvmExit,
+    /// The vCPU failed to enter guest mode (`KVM_EXIT_FAIL_ENTRY`).
+    VcpuFailEntry(VcpuEntryFailure),
     /// Cannot open the VM file descriptor.
     VmFd(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -243,6 +393,13 @@ pub enum Error {
     VmSetIrqChip(kvm_ioctls::Error),
     /// Cannot configure the microvm.
     VmSetup(kvm_ioctls::Error),
+    /// The GSI routing table has more entries than KVM accepts.
+    TooManyGsiRoutes(usize),
+    /// This GSI route points past the interrupt controllers, or conflicts with another route for
+    /// the same GSI.
+    InvalidGsiRoute(GsiRoute),
+    /// Failed to install the KVM GSI routing table.
+    VmSetGsiRouting(kvm_ioctls::Error),
 }
 
 impl Display for Error {
@@ -277,6 +434,11 @@ impl Display for Error {
                 "Cannot set the local interruption due to bad configuration: {e:?}"
             ),
             SetUserMemoryRegion(e) => write!(f, "Cannot set the memory regions: {e}"),
+            ReadOnlyMemUnsupported => write!(f, "KVM doesn't support read-only memory"),
+            GetDirtyLog(e) => write!(f, "Cannot get the dirty page bitmap: {e}"),
+            ReadOnlyMemAlloc(e) => {
+                write!(f, "Cannot allocate the memory of a read-only region: {e}")
+            }
             #[cfg(feature = "tee")]
             SevSecVirtInit(e) => {
                 write!(
@@ -312,6 +474,17 @@ impl Display for Error {
             MissingTeeConfig => write!(f, "Missing TEE configuration"),
             #[cfg(target_arch = "x86_64")]
             MSRSConfiguration(e) => write!(f, "Error configuring the MSR registers: {e:?}"),
+            #[cfg(target_arch = "x86_64")]
+            NestedVirtUnsupported => write!(
+                f,
+                "Nested virtualization is not enabled on the host (see the `nested` parameter \
+                 of the kvm_intel/kvm_amd module)"
+            ),
+            PtpKvmUnsupported => write!(f, "The host doesn't provide the KVM PTP clock"),
+            #[cfg(target_arch = "aarch64")]
+            PmuUnsupported => write!(f, "The host doesn't provide a PMU to the guest"),
+            #[cfg(target_arch = "aarch64")]
+            SveUnsupported => write!(f, "The host doesn't provide SVE to the guest"),
             #[cfg(target_arch = "aarch64")]
             REGSConfiguration(e) => write!(
                 f,
@@ -338,6 +511,8 @@ impl Display for Error {
             #[cfg(target_arch = "x86_64")]
             VcpuGetMsrs(e) => write!(f, "Failed to get KVM vcpu msrs: {e}"),
             #[cfg(target_arch = "x86_64")]
+            VcpuGetNestedState(e) => write!(f, "Failed to get KVM vcpu nested state: {e}"),
+            #[cfg(target_arch = "x86_64")]
             VcpuGetRegs(e) => write!(f, "Failed to get KVM vcpu regs: {e}"),
             #[cfg(target_arch = "x86_64")]
             VcpuGetSregs(e) => write!(f, "Failed to get KVM vcpu sregs: {e}"),
@@ -358,8 +533,16 @@ impl Display for Error {
             #[cfg(target_arch = "x86_64")]
             VcpuSetMsrs(e) => write!(f, "Failed to set KVM vcpu msrs: {e}"),
             #[cfg(target_arch = "x86_64")]
+            VcpuSetNestedState(e) => write!(f, "Failed to set KVM vcpu nested state: {e}"),
+            #[cfg(target_arch = "x86_64")]
             VcpuSetRegs(e) => write!(f, "Failed to set KVM vcpu regs: {e}"),
             #[cfg(target_arch = "x86_64")]
+            VcpuSetTscKhz(e) => write!(
+                f,
+                "Failed to set the KVM vcpu TSC frequency (it takes TSC scaling to differ from \
+                 the host's): {e}"
+            ),
+            #[cfg(target_arch = "x86_64")]
             VcpuSetSregs(e) => write!(f, "Failed to set KVM vcpu sregs: {e}"),
             #[cfg(target_arch = "x86_64")]
             VcpuSetVcpuEvents(e) => write!(f, "Failed to set KVM vcpu event: {e}"),
@@ -368,9 +551,12 @@ impl Display for Error {
             #[cfg(target_arch = "x86_64")]
             VcpuSetXsave(e) => write!(f, "Failed to set KVM vcpu xsave: {e}"),
             VcpuSpawn(e) => write!(f, "Cannot spawn a new vCPU thread: {e}"),
+            VcpuSeccompFilter(e) => write!(f, "Cannot restrict the vCPU thread: {e}"),
+            VcpuThreadPanicked => write!(f, "The vCPU thread panicked"),
             VcpuTlsInit => write!(f, "Cannot clean init vcpu TLS"),
             VcpuTlsNotPresent => write!(f, "Vcpu not present in TLS"),
             VcpuUnhandledKvmExit => write!(f, "Unexpected KVM_RUN exit reason"),
+            VcpuFailEntry(failure) => write!(f, "{failure}"),
             #[cfg(target_arch = "x86_64")]
             VmGetPit2(e) => write!(f, "Failed to get KVM vm pit state: {e}"),
             #[cfg(target_arch = "x86_64")]
@@ -383,6 +569,12 @@ impl Display for Error {
             VmSetClock(e) => write!(f, "Failed to set KVM vm clock: {e}"),
             #[cfg(target_arch = "x86_64")]
             VmSetIrqChip(e) => write!(f, "Failed to set KVM vm irqchip: {e}"),
+            TooManyGsiRoutes(n) => write!(
+                f,
+                "The GSI routing table has {n} entries, KVM accepts at most {MAX_GSI_ROUTES}"
+            ),
+            InvalidGsiRoute(route) => write!(f, "Invalid GSI route: {route:?}"),
+            VmSetGsiRouting(e) => write!(f, "Failed to set the KVM GSI routing table: {e}"),
             #[cfg(target_arch = "aarch64")]
             SetupGIC(e) => write!(f, "Error setting up the global interrupt controller: {e:?}"),
             #[cfg(target_arch = "aarch64")]
@@ -390,7 +582,24 @@ impl Display for Error {
                 write!(f, "Error getting the Vcpu preferred target on Arm: {e}")
             }
             #[cfg(target_arch = "aarch64")]
+            VcpuArmEnablePtp(e) => {
+                write!(
+                    f,
+                    "Error enabling the KVM PTP hypercall service on Arm: {e}"
+                )
+            }
+            #[cfg(target_arch = "aarch64")]
             VcpuArmInit(e) => write!(f, "Error doing Vcpu Init on Arm: {e}"),
+            #[cfg(target_arch = "aarch64")]
+            VcpuArmFinalizeSve(e) => {
+                write!(f, "Error finalizing the SVE configuration on Arm: {e}")
+            }
+            #[cfg(target_arch = "aarch64")]
+            VcpuArmPmuInit(e) => write!(f, "Error initializing the Vcpu PMU on Arm: {e}"),
+            #[cfg(target_arch = "aarch64")]
+            VcpuArmHypercall(e) => {
+                write!(f, "Error accessing the hypercall registers on Arm: {e}")
+            }
 
             #[cfg(feature = "tee")]
             InvalidTee => write!(f, "TEE selected is not currently supported"),
@@ -457,6 +666,153 @@ impl KvmContext {
     }
 }
 
+/// Largest GSI routing table KVM accepts (`KVM_MAX_IRQ_ROUTES`).
+pub const MAX_GSI_ROUTES: usize = 4096;
+
+/// Number of pins of the in-kernel PICs (chips 0 and 1) and IOAPIC (chip 2).
+#[cfg(target_arch = "x86_64")]
+const PIC_NUM_PINS: u32 = 8;
+#[cfg(target_arch = "x86_64")]
+const IOAPIC_NUM_PINS: u32 = 24;
+
+/// Number of SPIs KVM lets GSIs be routed to (`KVM_IRQCHIP_NUM_PINS`), and the number the GIC is
+/// actually configured with.
+#[cfg(target_arch = "aarch64")]
+const GIC_NUM_PINS: u32 = 988;
+#[cfg(target_arch = "aarch64")]
+const GIC_NUM_SPIS: u32 = arch::IRQ_MAX - arch::IRQ_BASE + 1;
+
+/// Routing table KVM sets up when the irqchip is created: GSIs 0 to 15 go to both the PICs and
+/// the IOAPIC, and GSIs 16 to 23 only to the IOAPIC.
+#[cfg(target_arch = "x86_64")]
+fn default_gsi_routing() -> Vec<GsiRoute> {
+    let mut routes = Vec::new();
+    for gsi in 0..IOAPIC_NUM_PINS {
+        if gsi < 2 * PIC_NUM_PINS {
+            routes.push(GsiRoute {
+                gsi,
+                destination: GsiDestination::Irqchip {
+                    chip: gsi / PIC_NUM_PINS,
+                    pin: gsi % PIC_NUM_PINS,
+                },
+            });
+        }
+        routes.push(GsiRoute {
+            gsi,
+            destination: GsiDestination::Irqchip { chip: 2, pin: gsi },
+        });
+    }
+    routes
+}
+
+/// Routing table KVM sets up when the GIC is initialized: each GSI goes to the SPI with the same
+/// number.
+#[cfg(target_arch = "aarch64")]
+fn default_gsi_routing() -> Vec<GsiRoute> {
+    (0..GIC_NUM_SPIS)
+        .map(|gsi| GsiRoute {
+            gsi,
+            destination: GsiDestination::Irqchip { chip: 0, pin: gsi },
+        })
+        .collect()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn irqchip_num_pins(chip: u32) -> u32 {
+    match chip {
+        0 | 1 => PIC_NUM_PINS,
+        2 => IOAPIC_NUM_PINS,
+        _ => 0,
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn irqchip_num_pins(chip: u32) -> u32 {
+    match chip {
+        0 => GIC_NUM_PINS,
+        _ => 0,
+    }
+}
+
+/// Checks a GSI routing table against the rules KVM enforces, so a bad entry is reported by
+/// itself rather than as a bare EINVAL. A GSI may be routed to several irqchips, but to a single
+/// pin of each, while an MSI route must be the only one for its GSI.
+fn validate_gsi_routing(routes: &[GsiRoute]) -> Result<()> {
+    if routes.len() > MAX_GSI_ROUTES {
+        return Err(Error::TooManyGsiRoutes(routes.len()));
+    }
+    for (i, route) in routes.iter().enumerate() {
+        let valid = route.gsi < MAX_GSI_ROUTES as u32
+            && match route.destination {
+                GsiDestination::Irqchip { chip, pin } => pin < irqchip_num_pins(chip),
+                GsiDestination::Msi { .. } => true,
+            };
+        let conflict = routes[..i]
+            .iter()
+            .filter(|other| other.gsi == route.gsi)
+            .any(|other| match (other.destination, route.destination) {
+                (
+                    GsiDestination::Irqchip { chip: a, .. },
+                    GsiDestination::Irqchip { chip: b, .. },
+                ) => a == b,
+                _ => true,
+            });
+        if !valid || conflict {
+            return Err(Error::InvalidGsiRoute(*route));
+        }
+    }
+    Ok(())
+}
+
+fn kvm_routing_entry(route: &GsiRoute) -> kvm_irq_routing_entry {
+    let mut entry = kvm_irq_routing_entry {
+        gsi: route.gsi,
+        ..Default::default()
+    };
+    match route.destination {
+        GsiDestination::Irqchip { chip, pin } => {
+            entry.type_ = KVM_IRQ_ROUTING_IRQCHIP;
+            entry.u.irqchip = kvm_irq_routing_irqchip { irqchip: chip, pin };
+        }
+        GsiDestination::Msi { address, data } => {
+            entry.type_ = KVM_IRQ_ROUTING_MSI;
+            entry.u.msi = kvm_irq_routing_msi {
+                address_lo: address as u32,
+                address_hi: (address >> 32) as u32,
+                data,
+                ..Default::default()
+            };
+        }
+    }
+    entry
+}
+
+/// Makes KVM hand the SMCCC calls of the OEM service range, which carry the hypercalls, to the
+/// VMM. It must be done before any vCPU runs.
+#[cfg(target_arch = "aarch64")]
+fn forward_hypercalls(vm_fd: &VmFd) -> std::result::Result<(), kvm_ioctls::Error> {
+    for base in [SMCCC_OEM_SMC32_BASE, SMCCC_OEM_SMC64_BASE] {
+        let filter = KvmSmcccFilter {
+            base,
+            nr_functions: MAX_HYPERCALL_ID + 1,
+            action: KVM_SMCCC_FILTER_FWD_TO_USER,
+            ..Default::default()
+        };
+        let attr = kvm_bindings::kvm_device_attr {
+            group: KVM_ARM_VM_SMCCC_CTRL,
+            attr: KVM_ARM_VM_SMCCC_FILTER,
+            addr: &filter as *const KvmSmcccFilter as u64,
+            flags: 0,
+        };
+        // Safe because the kernel only reads `attr` and the filter it points to.
+        let ret = unsafe { ioctl_with_ref(vm_fd, KVM_SET_DEVICE_ATTR, &attr) };
+        if ret < 0 {
+            return Err(kvm_ioctls::Error::last());
+        }
+    }
+    Ok(())
+}
+
 /// A wrapper around creating and using a VM.
 pub struct Vm {
     fd: VmFd,
@@ -466,11 +822,19 @@ pub struct Vm {
     supported_cpuid: CpuId,
     #[cfg(target_arch = "x86_64")]
     supported_msrs: MsrList,
+    #[cfg(target_arch = "x86_64")]
+    nested_state_supported: bool,
 
     // Arm specific fields.
     // On aarch64 we need to keep around the fd obtained by creating the VGIC device.
     #[cfg(target_arch = "aarch64")]
     irqchip_handle: Option<Box<dyn GICDevice>>,
+    #[cfg(target_arch = "aarch64")]
+    ptp_kvm_supported: bool,
+    #[cfg(target_arch = "aarch64")]
+    pmu_supported: bool,
+    #[cfg(target_arch = "aarch64")]
+    sve_supported: bool,
 
     #[cfg(feature = "amd-sev")]
     sev: Option<AmdSev>,
@@ -480,6 +844,73 @@ pub struct Vm {
 
     #[cfg(feature = "amd-sev")]
     pub tee: Tee,
+
+    // GSI routing table installed with `set_gsi_routing`, `None` while KVM still uses the
+    // default one it set up along with the irqchip.
+    gsi_routing: Option<Vec<GsiRoute>>,
+    // Handlers of the hypercalls KVM forwards to us, `None` if it can't.
+    hypercalls: Option<Arc<Hypercalls>>,
+
+    readonly_mem_supported: bool,
+    // Host memory backing the read-only regions, which KVM maps in the guest.
+    readonly_memory: Vec<vm_memory::mmap::MmapRegion>,
+    // First memory slot not used by the guest memory or the read-only regions.
+    next_memslot: u32,
+    // Number of memory slots KVM supports, as given to `memory_init`.
+    max_memslots: usize,
+    // Memory slots of the guest memory, the ones whose dirty pages are tracked.
+    guest_memory_slots: Vec<kvm_userspace_memory_region>,
+}
+
+/// Range of guest memory the guest wrote to, see `Vm::get_dirty_bitmap`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DirtyRegion {
+    pub guest_addr: GuestAddress,
+    pub len: u64,
+}
+
+// Turns the dirty page bitmap of a memory slot starting at `guest_addr` into the ranges of
+// consecutive dirty pages.
+fn dirty_regions(guest_addr: u64, bitmap: &[u64], page_size: u64) -> Vec<DirtyRegion> {
+    let mut regions: Vec<DirtyRegion> = Vec::new();
+    for (word_index, word) in bitmap.iter().enumerate() {
+        for bit in (0..64).filter(|bit| word & (1 << bit) != 0) {
+            let addr = guest_addr + (word_index as u64 * 64 + bit) * page_size;
+            match regions.last_mut() {
+                Some(region) if region.guest_addr.0 + region.len == addr => {
+                    region.len += page_size;
+                }
+                _ => regions.push(DirtyRegion {
+                    guest_addr: GuestAddress(addr),
+                    len: page_size,
+                }),
+            }
+        }
+    }
+    regions
+}
+
+// Applies `overrides` in turn to the matching entries of `cpuid`.
+#[cfg(target_arch = "x86_64")]
+fn apply_cpuid_overrides(cpuid: &mut CpuId, overrides: &[CpuidOverride]) {
+    for entry in cpuid.as_mut_slice() {
+        for cpuid_override in overrides {
+            if cpuid_override.leaf != entry.function
+                || cpuid_override
+                    .index
+                    .is_some_and(|index| index != entry.index)
+            {
+                continue;
+            }
+            let register = match cpuid_override.register {
+                CpuidRegister::Eax => &mut entry.eax,
+                CpuidRegister::Ebx => &mut entry.ebx,
+                CpuidRegister::Ecx => &mut entry.ecx,
+                CpuidRegister::Edx => &mut entry.edx,
+            };
+            *register = cpuid_override.apply(*register);
+        }
+    }
 }
 
 impl Vm {
@@ -496,6 +927,25 @@ impl Vm {
         #[cfg(target_arch = "x86_64")]
         let supported_msrs =
             arch::x86_64::msr::supported_guest_msrs(kvm).map_err(Error::GuestMSRs)?;
+        #[cfg(target_arch = "x86_64")]
+        let nested_state_supported = kvm.check_extension_raw(KVM_CAP_NESTED_STATE.into()) > 0;
+        #[cfg(target_arch = "aarch64")]
+        let ptp_kvm_supported = kvm.check_extension_raw(kvm_bindings::KVM_CAP_PTP_KVM.into()) > 0;
+        #[cfg(target_arch = "aarch64")]
+        let pmu_supported = kvm.check_extension(Cap::ArmPmuV3);
+        #[cfg(target_arch = "aarch64")]
+        let sve_supported = kvm.check_extension(Cap::ArmSve);
+        #[cfg(target_arch = "aarch64")]
+        let hypercalls = match forward_hypercalls(&vm_fd) {
+            Ok(()) => Some(Arc::new(Hypercalls::default())),
+            Err(e) => {
+                debug!("Cannot forward hypercalls to the VMM: {e}");
+                None
+            }
+        };
+        #[cfg(not(target_arch = "aarch64"))]
+        let hypercalls = None;
+        let readonly_mem_supported = vm_fd.check_extension(Cap::ReadonlyMem);
 
         Ok(Vm {
             fd: vm_fd,
@@ -503,8 +953,23 @@ impl Vm {
             supported_cpuid,
             #[cfg(target_arch = "x86_64")]
             supported_msrs,
+            #[cfg(target_arch = "x86_64")]
+            nested_state_supported,
             #[cfg(target_arch = "aarch64")]
             irqchip_handle: None,
+            #[cfg(target_arch = "aarch64")]
+            ptp_kvm_supported,
+            #[cfg(target_arch = "aarch64")]
+            pmu_supported,
+            #[cfg(target_arch = "aarch64")]
+            sve_supported,
+            gsi_routing: None,
+            hypercalls,
+            readonly_mem_supported,
+            readonly_memory: Vec::new(),
+            next_memslot: 0,
+            max_memslots: 0,
+            guest_memory_slots: Vec::new(),
         })
     }
 
@@ -519,6 +984,8 @@ impl Vm {
 
         let supported_msrs =
             arch::x86_64::msr::supported_guest_msrs(kvm).map_err(Error::GuestMSRs)?;
+        let nested_state_supported = kvm.check_extension_raw(KVM_CAP_NESTED_STATE.into()) > 0;
+        let readonly_mem_supported = vm_fd.check_extension(Cap::ReadonlyMem);
 
         let (sev, snp) = match tee_config.tee {
             Tee::Sev => (
@@ -533,12 +1000,78 @@ impl Vm {
             fd: vm_fd,
             supported_cpuid,
             supported_msrs,
+            nested_state_supported,
             sev,
             snp,
             tee: tee_config.tee,
+            gsi_routing: None,
+            hypercalls: None,
+            readonly_mem_supported,
+            readonly_memory: Vec::new(),
+            next_memslot: 0,
+            max_memslots: 0,
+            guest_memory_slots: Vec::new(),
         })
     }
 
+    /// Checks that guests of this Vm can run a hypervisor themselves. KVM only reports VMX/SVM
+    /// as supported when the host has nesting enabled.
+    #[cfg(target_arch = "x86_64")]
+    pub fn check_nested_support(&self) -> Result<()> {
+        let cpuid = self.supported_cpuid.as_slice();
+        let has_feature = |function: u32, bit: u32| {
+            cpuid
+                .iter()
+                .any(|e| e.function == function && e.ecx & (1 << bit) != 0)
+        };
+        // CPUID.01H:ECX.VMX[bit 5] and CPUID.80000001H:ECX.SVM[bit 2].
+        let vmx = has_feature(0x1, 5);
+        let svm = has_feature(0x8000_0001, 2);
+
+        if (vmx || svm) && self.nested_state_supported {
+            Ok(())
+        } else {
+            Err(Error::NestedVirtUnsupported)
+        }
+    }
+
+    /// Checks that the guest can read the host clock through its `ptp_kvm` driver. On x86_64 the
+    /// driver relies on kvmclock and the `KVM_HC_CLOCK_PAIRING` hypercall, which KVM only
+    /// completes while the host is using a stable TSC; on aarch64 it needs the KVM PTP service.
+    pub fn check_ptp_kvm_support(&self) -> Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let kvmclock = self.supported_cpuid.as_slice().iter().any(|e| {
+                e.function == KVM_CPUID_FEATURES && e.eax & (1 << KVM_FEATURE_CLOCKSOURCE2) != 0
+            });
+            if !kvmclock {
+                return Err(Error::PtpKvmUnsupported);
+            }
+
+            let clock = self.fd.get_clock().map_err(Error::VmGetClock)?;
+            if clock.flags & KVM_CLOCK_TSC_STABLE == 0 {
+                warn!("host TSC is not stable, the guest ptp_kvm clock won't be usable");
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        if !self.ptp_kvm_supported {
+            return Err(Error::PtpKvmUnsupported);
+        }
+        Ok(())
+    }
+
+    /// Checks that the host can give the vCPUs the optional features `vcpu_config` asks for.
+    #[cfg(target_arch = "aarch64")]
+    pub fn check_vcpu_features(&self, vcpu_config: &VcpuConfig) -> Result<()> {
+        if vcpu_config.pmu && !self.pmu_supported {
+            return Err(Error::PmuUnsupported);
+        }
+        if vcpu_config.sve && !self.sve_supported {
+            return Err(Error::SveUnsupported);
+        }
+        Ok(())
+    }
+
     /// Returns a ref to the supported `CpuId` for this Vm.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn supported_cpuid(&self) -> &CpuId {
@@ -578,7 +1111,10 @@ impl Vm {
                     .set_user_memory_region(memory_region)
                     .map_err(Error::SetUserMemoryRegion)?;
             };
+            self.guest_memory_slots.push(memory_region);
         }
+        self.next_memslot = guest_mem.num_regions() as u32;
+        self.max_memslots = kvm_max_memslots;
 
         #[cfg(target_arch = "x86_64")]
         self.fd
@@ -674,12 +1210,141 @@ impl Vm {
         self.irqchip_handle.as_ref().unwrap()
     }
 
+    /// Returns the GSI routing table KVM currently uses.
+    pub fn gsi_routing(&self) -> Vec<GsiRoute> {
+        self.gsi_routing.clone().unwrap_or_else(default_gsi_routing)
+    }
+
+    /// Validates and installs a new GSI routing table, replacing the whole current one. GSIs
+    /// left out of `routes` can no longer be raised by devices.
+    pub fn set_gsi_routing(&mut self, routes: &[GsiRoute]) -> Result<()> {
+        validate_gsi_routing(routes)?;
+
+        // kvm_irq_routing ends with a flexible array of entries, allocate room for them right
+        // after the header.
+        let header_size = std::mem::size_of::<kvm_irq_routing>();
+        let size = header_size + routes.len() * std::mem::size_of::<kvm_irq_routing_entry>();
+        let mut routing: Vec<kvm_irq_routing> = std::iter::repeat_with(kvm_irq_routing::default)
+            .take(size.div_ceil(header_size))
+            .collect();
+        routing[0].nr = routes.len() as u32;
+        // SAFETY: the vector is large enough to hold `routes.len()` entries past the header.
+        let entries = unsafe { routing[0].entries.as_mut_slice(routes.len()) };
+        for (entry, route) in entries.iter_mut().zip(routes) {
+            *entry = kvm_routing_entry(route);
+        }
+
+        self.fd
+            .set_gsi_routing(&routing[0])
+            .map_err(Error::VmSetGsiRouting)?;
+        self.gsi_routing = Some(routes.to_vec());
+        Ok(())
+    }
+
     /// Gets a reference to the kvm file descriptor owned by this VM.
     pub fn fd(&self) -> &VmFd {
         &self.fd
     }
 
-    #[allow(unused)]
+    /// Maps `size` bytes starting with `contents`, and zeroes after them, read-only in the guest
+    /// at `guest_addr`. Guest writes to the region exit to the VMM as MMIO writes.
+    pub fn map_readonly_memory(
+        &mut self,
+        guest_addr: GuestAddress,
+        size: usize,
+        contents: &[u8],
+    ) -> Result<()> {
+        if !self.readonly_mem_supported {
+            return Err(Error::ReadOnlyMemUnsupported);
+        }
+        if self.next_memslot as usize >= self.max_memslots {
+            return Err(Error::NotEnoughMemorySlots);
+        }
+
+        let region = vm_memory::mmap::MmapRegion::new(size).map_err(Error::ReadOnlyMemAlloc)?;
+        let len = contents.len().min(size);
+        // Safe because the new mapping is at least `len` bytes long.
+        unsafe { std::ptr::copy_nonoverlapping(contents.as_ptr(), region.as_ptr(), len) };
+        let memory_region = kvm_userspace_memory_region {
+            slot: self.next_memslot,
+            guest_phys_addr: guest_addr.raw_value(),
+            memory_size: size as u64,
+            userspace_addr: region.as_ptr() as u64,
+            flags: kvm_bindings::KVM_MEM_READONLY,
+        };
+        // Safe because the mapping is kept until the Vm goes away.
+        unsafe {
+            self.fd
+                .set_user_memory_region(memory_region)
+                .map_err(Error::SetUserMemoryRegion)?;
+        };
+        self.next_memslot += 1;
+        self.readonly_memory.push(region);
+        Ok(())
+    }
+
+    /// Makes KVM track the pages of the guest memory the guest writes to, see
+    /// `get_dirty_bitmap`. The read-only regions are never dirtied.
+    pub fn enable_dirty_logging(&mut self) -> Result<()> {
+        self.set_dirty_logging(true)
+    }
+
+    /// Stops tracking the pages the guest writes to.
+    pub fn disable_dirty_logging(&mut self) -> Result<()> {
+        self.set_dirty_logging(false)
+    }
+
+    // Sets or clears the dirty logging flag of the guest memory slots, keeping their other flags.
+    fn set_dirty_logging(&mut self, enabled: bool) -> Result<()> {
+        for memory_region in self.guest_memory_slots.iter_mut() {
+            let mut updated = *memory_region;
+            if enabled {
+                updated.flags |= kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES;
+            } else {
+                updated.flags &= !kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES;
+            }
+            // Safe because only the flags of an existing slot change.
+            unsafe {
+                self.fd
+                    .set_user_memory_region(updated)
+                    .map_err(Error::SetUserMemoryRegion)?;
+            };
+            *memory_region = updated;
+        }
+        Ok(())
+    }
+
+    /// Returns the ranges of guest memory the guest wrote to since dirty logging was enabled or
+    /// since the last call, with a page granularity. Writes done by the VMM itself, e.g. by the
+    /// devices, are never reported: KVM only sees the ones the vCPUs do, so the result isn't
+    /// enough to migrate a running guest on its own.
+    ///
+    /// The manual dirty log protection capability isn't enabled, so KVM_GET_DIRTY_LOG clears
+    /// the bitmap and write-protects the pages again as it returns them, and a page is only
+    /// reported again once the guest writes to it again.
+    pub fn get_dirty_bitmap(&self) -> Result<Vec<DirtyRegion>> {
+        // Safe because sysconf has no side effect.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        let mut regions = Vec::new();
+        for memory_region in &self.guest_memory_slots {
+            let bitmap = self
+                .fd
+                .get_dirty_log(memory_region.slot, memory_region.memory_size as usize)
+                .map_err(Error::GetDirtyLog)?;
+            regions.extend(dirty_regions(
+                memory_region.guest_phys_addr,
+                &bitmap,
+                page_size,
+            ));
+        }
+        Ok(regions)
+    }
+
+    /// Returns the hypercall handlers of this VM, `None` if KVM can't forward hypercalls.
+    pub fn hypercalls(&self) -> Option<&Arc<Hypercalls>> {
+        self.hypercalls.as_ref()
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Saves and returns the Kvm Vm state.
     pub fn save_state(&self) -> Result<VmState> {
@@ -747,11 +1412,11 @@ impl Vm {
 #[cfg(target_arch = "x86_64")]
 /// Structure holding VM kvm state.
 pub struct VmState {
-    pitstate: kvm_pit_state2,
-    clock: kvm_clock_data,
-    pic_master: kvm_irqchip,
-    pic_slave: kvm_irqchip,
-    ioapic: kvm_irqchip,
+    pub(crate) pitstate: kvm_pit_state2,
+    pub(crate) clock: kvm_clock_data,
+    pub(crate) pic_master: kvm_irqchip,
+    pub(crate) pic_slave: kvm_irqchip,
+    pub(crate) ioapic: kvm_irqchip,
 }
 
 /// Encapsulates configuration parameters for the guest vCPUS.
@@ -763,6 +1428,24 @@ pub struct VcpuConfig {
     pub ht_enabled: bool,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// CPUID bits forced after applying `cpu_template`.
+    #[cfg(target_arch = "x86_64")]
+    pub cpuid_overrides: Vec<CpuidOverride>,
+    /// Expose VMX/SVM so the guest can run its own hypervisor.
+    #[cfg(target_arch = "x86_64")]
+    pub nested: bool,
+    /// TSC frequency in kHz, the host's if `None`.
+    #[cfg(target_arch = "x86_64")]
+    pub tsc_khz: Option<u32>,
+    /// Topology described in the CPUID configuration, a single socket if `None`.
+    #[cfg(target_arch = "x86_64")]
+    pub cpu_topology: Option<arch::CpuTopology>,
+    /// Expose a PMUv3 so the guest can profile itself.
+    #[cfg(target_arch = "aarch64")]
+    pub pmu: bool,
+    /// Expose SVE, with the vector lengths KVM enables by default. KVM hides it otherwise.
+    #[cfg(target_arch = "aarch64")]
+    pub sve: bool,
 }
 
 // Using this for easier explicit type-casting to help IDEs interpret the code.
@@ -784,9 +1467,31 @@ pub struct Vcpu {
     cpuid: CpuId,
     #[cfg(target_arch = "x86_64")]
     msr_list: MsrList,
+    // Exit code reported when the guest halts with interrupts disabled, if watching for it.
+    #[cfg(target_arch = "x86_64")]
+    halt_exit_code: Option<u8>,
+    #[cfg(target_arch = "x86_64")]
+    nested: bool,
 
     #[cfg(target_arch = "aarch64")]
     mpidr: u64,
+    // Whether the vcpu was initialized with a PMU.
+    #[cfg(target_arch = "aarch64")]
+    pmu: bool,
+    #[cfg(target_arch = "aarch64")]
+    hypercalls: Option<Arc<Hypercalls>>,
+    // Read-only regions guest writes to fault in the guest, as `(start, size)` pairs.
+    #[cfg(target_arch = "aarch64")]
+    readonly_faults: Vec<(u64, u64)>,
+
+    // Token deciding which Vcpu may enter the guest, when the Vcpus are serialized.
+    scheduler: Option<VcpuScheduler>,
+    // When this Vcpu got the scheduler token, if it holds it.
+    slice_start: Option<Instant>,
+    // Set to when the guest first signaled its boot completion, shared with the other Vcpus.
+    boot_complete_time: Option<Arc<OnceLock<Instant>>>,
+    // Installed in the vcpu thread before it starts running.
+    seccomp_filter: Option<SeccompFilter>,
 
     // The receiving end of events channel owned by the vcpu side.
     event_receiver: Receiver<VcpuEvent>,
@@ -796,6 +1501,10 @@ pub struct Vcpu {
     response_receiver: Option<Receiver<VcpuResponse>>,
     // The transmitting end of the responses channel owned by the vcpu side.
     response_sender: Sender<VcpuResponse>,
+    // Counters updated on every exit, shared with the VcpuHandle.
+    stats: Arc<Mutex<VcpuStats>>,
+    // Breakpoints and watchpoints last installed by a debugger.
+    debug: GuestDebug,
 }
 
 impl Vcpu {
@@ -916,10 +1625,18 @@ impl Vcpu {
             io_bus,
             cpuid,
             msr_list,
+            halt_exit_code: None,
+            nested: false,
+            scheduler: None,
+            boot_complete_time: None,
+            seccomp_filter: None,
+            slice_start: None,
             event_receiver,
             event_sender: Some(event_sender),
             response_receiver: Some(response_receiver),
             response_sender,
+            stats: Arc::new(Mutex::new(VcpuStats::default())),
+            debug: GuestDebug::default(),
         })
     }
 
@@ -949,10 +1666,19 @@ impl Vcpu {
             mmio_bus: None,
             exit_evt,
             mpidr: 0,
+            pmu: false,
+            hypercalls: None,
+            readonly_faults: Vec::new(),
+            scheduler: None,
+            boot_complete_time: None,
+            seccomp_filter: None,
+            slice_start: None,
             event_receiver,
             event_sender: Some(event_sender),
             response_receiver: Some(response_receiver),
             response_sender,
+            stats: Arc::new(Mutex::new(VcpuStats::default())),
+            debug: GuestDebug::default(),
         })
     }
 
@@ -972,6 +1698,54 @@ impl Vcpu {
         self.mmio_bus = Some(mmio_bus);
     }
 
+    /// Makes the guest writes to these read-only regions, as `(start, size)` pairs, fault.
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_readonly_faults(&mut self, regions: Vec<(u64, u64)>) {
+        self.readonly_faults = regions;
+    }
+
+    /// Sets the handlers of the hypercalls this vcpu makes.
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_hypercalls(&mut self, hypercalls: Arc<Hypercalls>) {
+        self.hypercalls = Some(hypercalls);
+    }
+
+    /// Makes this vcpu take turns running guest code with the other vcpus sharing `scheduler`.
+    pub fn set_scheduler(&mut self, scheduler: VcpuScheduler) {
+        self.scheduler = Some(scheduler);
+    }
+
+    /// Makes the vcpu record when the guest first signals its boot completion in `time`.
+    pub fn set_boot_complete_time(&mut self, time: Arc<OnceLock<Instant>>) {
+        self.boot_complete_time = Some(time);
+    }
+
+    /// Restricts the system calls of the vcpu thread with `filter`, if any.
+    pub fn set_seccomp_filter(&mut self, filter: Option<SeccompFilter>) {
+        self.seccomp_filter = filter;
+    }
+
+    /// Makes this vcpu stop the VM with `exit_code` when the guest executes `hlt` with interrupts
+    /// disabled, instead of leaving it halted forever. `None` restores the default behavior.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_halt_exit_code(&mut self, exit_code: Option<u8>) {
+        self.halt_exit_code = exit_code;
+    }
+
+    // Whether the guest has interrupts masked, which makes a `hlt` final.
+    #[cfg(target_arch = "x86_64")]
+    fn interrupts_disabled(&self) -> bool {
+        matches!(self.fd.get_regs(), Ok(regs) if regs.rflags & X86_EFLAGS_IF == 0)
+    }
+
+    // With the in-kernel irqchip, KVM handles `hlt` itself and never exits to userspace, so a
+    // halted vcpu has to be detected from its state whenever `KVM_RUN` gets interrupted.
+    #[cfg(target_arch = "x86_64")]
+    fn guest_halted(&self) -> bool {
+        matches!(self.fd.get_mp_state(), Ok(state) if state.mp_state == KVM_MP_STATE_HALTED)
+            && self.interrupts_disabled()
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[allow(unused_variables)]
     /// Configures a x86_64 specific vcpu and should be called once per vcpu.
@@ -987,8 +1761,30 @@ impl Vcpu {
         kernel_start_addr: GuestAddress,
         vcpu_config: &VcpuConfig,
     ) -> Result<()> {
-        let cpuid_vm_spec = VmSpec::new(self.id, vcpu_config.vcpu_count, vcpu_config.ht_enabled)
-            .map_err(Error::CpuId)?;
+        let mut cpuid_vm_spec =
+            VmSpec::new(self.id, vcpu_config.vcpu_count, vcpu_config.ht_enabled)
+                .map_err(Error::CpuId)?;
+        cpuid_vm_spec.set_nested(vcpu_config.nested);
+        self.nested = vcpu_config.nested;
+        if let Some(topology) = vcpu_config.cpu_topology {
+            cpuid_vm_spec.set_topology(topology.threads_per_core, topology.cores_per_socket);
+        }
+
+        // Let the guest trust the TSC rather than calibrating it against slow emulated timers.
+        let tsc_khz = match vcpu_config.tsc_khz {
+            Some(tsc_khz) => {
+                self.fd.set_tsc_khz(tsc_khz).map_err(Error::VcpuSetTscKhz)?;
+                Some(tsc_khz)
+            }
+            None => self
+                .fd
+                .get_tsc_khz()
+                .map_err(|e| warn!("Failed to get the TSC frequency of vcpu {}: {e}", self.id))
+                .ok(),
+        };
+        if let Some(tsc_khz) = tsc_khz {
+            cpuid_vm_spec.set_tsc_khz(tsc_khz);
+        }
 
         filter_cpuid(&mut self.cpuid, &cpuid_vm_spec).map_err(|e| {
             error!("Failure in configuring CPUID for vcpu {}: {:?}", self.id, e);
@@ -1005,6 +1801,7 @@ impl Vcpu {
                 }
             }
         }
+        apply_cpuid_overrides(&mut self.cpuid, &vcpu_config.cpuid_overrides);
 
         self.fd
             .set_cpuid2(&self.cpuid)
@@ -1033,6 +1830,7 @@ impl Vcpu {
         vm_fd: &VmFd,
         guest_mem: &GuestMemoryMmap,
         kernel_load_addr: GuestAddress,
+        vcpu_config: &VcpuConfig,
     ) -> Result<()> {
         let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
 
@@ -1046,8 +1844,22 @@ impl Vcpu {
         if self.id > 0 {
             kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_POWER_OFF;
         }
+        // The host support for these was checked by `Vm::check_vcpu_features`.
+        if vcpu_config.pmu {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PMU_V3;
+        }
+        if vcpu_config.sve {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_SVE;
+        }
 
         self.fd.vcpu_init(&kvi).map_err(Error::VcpuArmInit)?;
+        if vcpu_config.sve {
+            // Keeps the vector lengths KVM enables by default.
+            self.fd
+                .vcpu_finalize(&(kvm_bindings::KVM_ARM_VCPU_SVE as i32))
+                .map_err(Error::VcpuArmFinalizeSve)?;
+        }
+        self.pmu = vcpu_config.pmu;
         arch::aarch64::regs::setup_regs(&self.fd, self.id, kernel_load_addr.raw_value(), guest_mem)
             .map_err(Error::REGSConfiguration)?;
 
@@ -1056,11 +1868,120 @@ impl Vcpu {
         Ok(())
     }
 
-    /// Moves the vcpu to its own thread and constructs a VcpuHandle.
-    /// The handle can be used to control the remote vcpu.
-    pub fn start_threaded(mut self) -> Result<VcpuHandle> {
-        let event_sender = self.event_sender.take().unwrap();
-        let response_receiver = self.response_receiver.take().unwrap();
+    #[cfg(target_arch = "aarch64")]
+    /// Returns whether the vcpu was configured with a PMU, which `init_pmu` must then set up.
+    pub fn has_pmu(&self) -> bool {
+        self.pmu
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Sets up the PMU of the vcpu to raise `arch::aarch64::layout::PMU_PPI` on overflows. This
+    /// needs the interrupt controller, so it must be called once it's set up.
+    pub fn init_pmu(&self) -> Result<()> {
+        let irq = (arch::aarch64::layout::PMU_PPI + 16) as i32;
+        let mut attr = kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_IRQ),
+            addr: &irq as *const i32 as u64,
+            flags: 0,
+        };
+        self.fd
+            .set_device_attr(&attr)
+            .map_err(Error::VcpuArmPmuInit)?;
+
+        attr.attr = u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_INIT);
+        attr.addr = 0;
+        self.fd
+            .set_device_attr(&attr)
+            .map_err(Error::VcpuArmPmuInit)?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Makes sure the KVM PTP hypercall service is offered to the guest. Kernels predating the
+    /// vendor hypervisor bitmap always offer it when `KVM_CAP_PTP_KVM` is reported.
+    pub fn enable_ptp_kvm(&self) -> Result<()> {
+        let mut bmap = [0u8; 8];
+        match self.fd.get_one_reg(KVM_REG_ARM_VENDOR_HYP_BMAP, &mut bmap) {
+            Ok(_) => (),
+            Err(e) if e.errno() == libc::ENOENT => return Ok(()),
+            Err(e) => return Err(Error::VcpuArmEnablePtp(e)),
+        }
+
+        let bmap = u64::from_le_bytes(bmap) | 1 << kvm_bindings::KVM_REG_ARM_VENDOR_HYP_BIT_PTP;
+        self.fd
+            .set_one_reg(KVM_REG_ARM_VENDOR_HYP_BMAP, &bmap.to_le_bytes())
+            .map_err(Error::VcpuArmEnablePtp)?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Makes the guest write at `addr`, which KVM reported as MMIO, fault if it targets one of
+    /// the `readonly_faults` regions. Returns whether it did.
+    fn fault_readonly_write(&self, addr: u64) -> bool {
+        if !self
+            .readonly_faults
+            .iter()
+            .any(|&(start, size)| addr >= start && addr - start < size)
+        {
+            return false;
+        }
+
+        let mut events = kvm_bindings::kvm_vcpu_events::default();
+        events.exception.ext_dabt_pending = 1;
+        if let Err(e) = self.fd.set_vcpu_events(&events) {
+            // Before KVM_CAP_ARM_INJECT_EXT_DABT, the best we can do is dropping the write.
+            error!("Failed to inject a data abort for the write at {addr:#x}: {e}");
+        }
+        true
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn get_core_reg(&self, reg_id: u64) -> Result<u64> {
+        let mut data = [0u8; 8];
+        self.fd
+            .get_one_reg(reg_id, &mut data)
+            .map_err(Error::VcpuArmHypercall)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn set_core_reg(&self, reg_id: u64, value: u64) -> Result<()> {
+        self.fd
+            .set_one_reg(reg_id, &value.to_le_bytes())
+            .map_err(Error::VcpuArmHypercall)?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Runs the handler of the hypercall KVM forwarded, see `crate::hypercall` for the calling
+    /// convention.
+    fn handle_hypercall(&mut self) -> Result<()> {
+        // Safe because KVM fills in `hypercall` on KVM_EXIT_HYPERCALL. On aarch64 `nr` holds the
+        // SMCCC function ID. KVM steps over both `hvc` and `smc` before exiting.
+        let function_id = unsafe { self.fd.get_kvm_run().__bindgen_anon_1.hypercall.nr as u32 };
+
+        let mut args = [0u64; 6];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = self.get_core_reg(arm64_core_reg(i as u64 + 1))?;
+        }
+        // KVM only forwards the OEM range, whose function number is the hypercall id.
+        let id = function_id & MAX_HYPERCALL_ID;
+        let ret = self
+            .hypercalls
+            .as_ref()
+            .and_then(|hypercalls| hypercalls.dispatch(id, self.id, args))
+            .unwrap_or(SMCCC_RET_NOT_SUPPORTED);
+        self.set_core_reg(arm64_core_reg(0), ret)?;
+        Ok(())
+    }
+
+    /// Moves the vcpu to its own thread and constructs a VcpuHandle.
+    /// The handle can be used to control the remote vcpu.
+    pub fn start_threaded(mut self) -> Result<VcpuHandle> {
+        let event_sender = self.event_sender.take().unwrap();
+        let response_receiver = self.response_receiver.take().unwrap();
+        let stats = self.stats.clone();
         let (init_tls_sender, init_tls_receiver) = unbounded();
         let vcpu_thread = thread::Builder::new()
             .name(format!("fc_vcpu {}", self.cpu_index()))
@@ -1068,21 +1989,30 @@ impl Vcpu {
                 self.init_thread_local_data()
                     .expect("Cannot cleanly initialize vcpu TLS.");
 
+                // The filter is in place before the guest runs its first instruction.
+                let filtered = match self.seccomp_filter.take() {
+                    Some(filter) => filter.apply().map_err(Error::VcpuSeccompFilter),
+                    None => Ok(()),
+                };
+                let run = filtered.is_ok();
                 init_tls_sender
-                    .send(true)
+                    .send(filtered)
                     .expect("Cannot notify vcpu TLS initialization.");
 
-                self.run();
+                if run {
+                    self.run();
+                }
             })
             .map_err(Error::VcpuSpawn)?;
 
         init_tls_receiver
             .recv()
-            .expect("Error waiting for TLS initialization.");
+            .expect("Error waiting for TLS initialization.")?;
 
         Ok(VcpuHandle::new(
             event_sender,
             response_receiver,
+            stats,
             vcpu_thread,
         ))
     }
@@ -1091,13 +2021,15 @@ impl Vcpu {
         if addr == MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE
             && data[0] == MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE
         {
+            if let Some(time) = &self.boot_complete_time {
+                let _ = time.set(Instant::now());
+            }
             super::super::Vmm::log_boot_time(&self.create_ts);
         }
     }
 
-    #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
-    fn save_state(&self) -> Result<VcpuState> {
+    fn save_state(&self) -> Result<VcpuKvmState> {
         /*
          * Ordering requirements:
          *
@@ -1119,6 +2051,9 @@ impl Vcpu {
          *
          * GET_MSRS requires a pre-populated data structure to do something
          * meaningful. For SET_MSRS it will then contain good data.
+         *
+         * GET_NESTED_STATE is only meaningful when VMX/SVM is exposed to the
+         * guest, and captures the state of the L2 guest it may be running.
          */
 
         // Build the list of MSRs we want to save.
@@ -1145,12 +2080,18 @@ impl Vcpu {
             .fd
             .get_vcpu_events()
             .map_err(Error::VcpuGetVcpuEvents)?;
-        Ok(VcpuState {
+        let nested_state = if self.nested {
+            Some(self.get_nested_state()?)
+        } else {
+            None
+        };
+        Ok(VcpuKvmState {
             cpuid: self.cpuid.clone(),
             msrs,
             debug_regs,
             lapic,
             mp_state,
+            nested_state,
             regs,
             sregs,
             vcpu_events,
@@ -1159,9 +2100,10 @@ impl Vcpu {
         })
     }
 
-    #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
-    fn restore_state(&self, state: VcpuState) -> Result<()> {
+    /// Restores the state returned by `save_state`, e.g. from a snapshot, into a vcpu that didn't
+    /// run yet.
+    pub(crate) fn restore_state(&self, state: VcpuKvmState) -> Result<()> {
         /*
          * Ordering requirements:
          *
@@ -1183,6 +2125,10 @@ impl Vcpu {
          *
          * SET_LAPIC must come before SET_MSRS, because the TSC deadline MSR
          * only restores successfully, when the LAPIC is correctly configured.
+         *
+         * SET_NESTED_STATE must come after SET_SREGS and SET_MSRS, as KVM
+         * validates it against CR4 and the feature control MSR, and before
+         * SET_VCPU_EVENTS, which may carry events pending for the L2 guest.
          */
         self.fd
             .set_cpuid2(&state.cpuid)
@@ -1205,12 +2151,65 @@ impl Vcpu {
             .set_lapic(&state.lapic)
             .map_err(Error::VcpuSetLapic)?;
         self.fd.set_msrs(&state.msrs).map_err(Error::VcpuSetMsrs)?;
+        if let Some(nested_state) = &state.nested_state {
+            self.set_nested_state(nested_state)?;
+        }
         self.fd
             .set_vcpu_events(&state.vcpu_events)
             .map_err(Error::VcpuSetVcpuEvents)?;
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the raw `kvm_nested_state` blob of this vcpu, header included.
+    fn get_nested_state(&self) -> Result<Vec<u8>> {
+        // Room for the header plus the largest payload KVM may produce (VMCS12 and shadow VMCS12).
+        let max_size =
+            std::mem::size_of::<kvm_nested_state>() + 2 * KVM_STATE_NESTED_VMX_VMCS_SIZE as usize;
+        let mut buf = vec![0u8; max_size];
+        buf[4..8].copy_from_slice(&(max_size as u32).to_ne_bytes());
+
+        // Safe because the kernel writes at most `size` bytes, the length of `buf`.
+        let ret = unsafe {
+            ioctl_with_mut_ptr(
+                &self.fd,
+                KVM_GET_NESTED_STATE,
+                buf.as_mut_ptr() as *mut kvm_nested_state,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::VcpuGetNestedState(kvm_ioctls::Error::last()));
+        }
+
+        let size = u32::from_ne_bytes(buf[4..8].try_into().unwrap()) as usize;
+        buf.truncate(size.clamp(std::mem::size_of::<kvm_nested_state>(), max_size));
+        Ok(buf)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Loads a blob previously returned by `get_nested_state`.
+    fn set_nested_state(&self, state: &[u8]) -> Result<()> {
+        if state.len() < std::mem::size_of::<kvm_nested_state>() {
+            return Err(Error::VcpuSetNestedState(kvm_ioctls::Error::new(
+                libc::EINVAL,
+            )));
+        }
+
+        // Safe because the kernel reads at most the `size` bytes recorded in the header, which
+        // `get_nested_state` made match the length of the blob.
+        let ret = unsafe {
+            ioctl_with_ptr(
+                &self.fd,
+                KVM_SET_NESTED_STATE,
+                state.as_ptr() as *const kvm_nested_state,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::VcpuSetNestedState(kvm_ioctls::Error::last()));
+        }
+        Ok(())
+    }
+
     /// Runs the vCPU in KVM context and handles the kvm exit reason.
     ///
     /// Returns error or enum specifying whether emulation was handled or interrupted.
@@ -1236,6 +2235,10 @@ impl Vcpu {
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    #[cfg(target_arch = "aarch64")]
+                    if self.fault_readonly_write(addr) {
+                        return Ok(VcpuEmulation::Handled);
+                    }
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         #[cfg(target_arch = "aarch64")]
                         self.check_boot_complete_signal(addr, data);
@@ -1244,19 +2247,35 @@ impl Vcpu {
                     }
                     Ok(VcpuEmulation::Handled)
                 }
+                #[cfg(target_arch = "aarch64")]
+                VcpuExit::Hypercall => {
+                    self.handle_hypercall()?;
+                    Ok(VcpuEmulation::Handled)
+                }
                 VcpuExit::Hlt => {
                     info!("Received KVM_EXIT_HLT signal");
+                    #[cfg(target_arch = "x86_64")]
+                    if self.halt_exit_code.is_some() && self.interrupts_disabled() {
+                        return Ok(VcpuEmulation::Halted);
+                    }
                     Ok(VcpuEmulation::Stopped)
                 }
                 VcpuExit::Shutdown => {
                     info!("Received KVM_EXIT_SHUTDOWN signal");
                     Ok(VcpuEmulation::Stopped)
                 }
+                // Only happens once a debugger installed breakpoints or asked for a single step.
+                VcpuExit::Debug(exit) => Ok(VcpuEmulation::DebugExit(self.watchpoint_hit(&exit))),
                 // Documentation specifies that below kvm exits are considered
                 // errors.
-                VcpuExit::FailEntry(reason, vcpu) => {
-                    error!("Received KVM_EXIT_FAIL_ENTRY signal: reason={reason}, vcpu={vcpu}");
-                    Err(Error::VcpuUnhandledKvmExit)
+                VcpuExit::FailEntry(reason, cpu) => {
+                    let failure = VcpuEntryFailure {
+                        vcpu: self.id,
+                        host_cpu: cpu,
+                        reason,
+                    };
+                    error!("Received KVM_EXIT_FAIL_ENTRY signal: {failure}");
+                    Err(Error::VcpuFailEntry(failure))
                 }
                 VcpuExit::InternalError => {
                     error!("Received KVM_EXIT_INTERNAL_ERROR signal");
@@ -1294,16 +2313,280 @@ impl Vcpu {
     /// Note that the state of the VCPU and associated VM must be setup first for this to do
     /// anything useful.
     pub fn run(&mut self) {
+        // Nothing else interrupts a halted vcpu, so kick it periodically to look for a halt.
+        #[cfg(target_arch = "x86_64")]
+        let _halt_timer = match self.halt_exit_code {
+            Some(_) => KickTimer::new(HALT_CHECK_INTERVAL)
+                .map_err(|e| error!("Failed to arm the guest halt check timer: {e}"))
+                .ok(),
+            None => None,
+        };
+        // Take the guest away from this vcpu at the end of its time slice.
+        let _slice_timer = self.scheduler.as_ref().and_then(|scheduler| {
+            KickTimer::new(scheduler.time_slice)
+                .map_err(|e| error!("Failed to arm the vcpu time slice timer: {e}"))
+                .ok()
+        });
+
         // Start running the machine state in the `Paused` state.
         StateMachine::run(self, Self::paused);
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn registers(&self) -> result::Result<VcpuRegisters, kvm_ioctls::Error> {
+        Ok(VcpuRegisters {
+            regs: self.fd.get_regs()?,
+            sregs: self.fd.get_sregs()?,
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn registers(&self) -> result::Result<VcpuRegisters, kvm_ioctls::Error> {
+        let get = |reg_id| {
+            let mut data = [0u8; 8];
+            self.fd
+                .get_one_reg(reg_id, &mut data)
+                .map(|_| u64::from_le_bytes(data))
+        };
+        let mut regs = kvm_bindings::user_pt_regs::default();
+        for (i, reg) in regs.regs.iter_mut().enumerate() {
+            *reg = get(arm64_core_reg(i as u64))?;
+        }
+        regs.sp = get(arm64_core_reg(31))?;
+        regs.pc = get(ARM64_REG_PC)?;
+        regs.pstate = get(arm64_core_reg(33))?;
+        Ok(VcpuRegisters {
+            regs,
+            sp_el1: get(ARM64_REG_SP_EL1)?,
+        })
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn state(&self) -> result::Result<VcpuState, kvm_ioctls::Error> {
+        let sregs = self.fd.get_sregs()?;
+        Ok(VcpuState {
+            regs: self.fd.get_regs()?,
+            cs: sregs.cs,
+            ds: sregs.ds,
+            es: sregs.es,
+            fs: sregs.fs,
+            gs: sregs.gs,
+            ss: sregs.ss,
+        })
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn state(&self) -> result::Result<VcpuState, kvm_ioctls::Error> {
+        Ok(VcpuState {
+            regs: self.registers()?.regs,
+        })
+    }
+
+    fn state_response(&self) -> VcpuResponse {
+        match self.state() {
+            Ok(state) => VcpuResponse::State(Box::new(state)),
+            Err(e) => VcpuResponse::RegistersFailed(e),
+        }
+    }
+
+    // Installs the hardware breakpoints, watchpoints and single-stepping requested by a debugger.
+    fn set_debug(&mut self, debug: GuestDebug) -> result::Result<(), GuestDebugError> {
+        debug.check()?;
+        // Give the debug registers back to the guest when the debugger doesn't need them.
+        let guest_debug = if debug == GuestDebug::default() {
+            kvm_guest_debug::default()
+        } else {
+            Self::guest_debug(&debug)
+        };
+        self.fd
+            .set_guest_debug(&guest_debug)
+            .map_err(GuestDebugError::SetGuestDebug)?;
+        self.debug = debug;
+        Ok(())
+    }
+
+    fn guest_debug(debug: &GuestDebug) -> kvm_guest_debug {
+        let mut guest_debug = kvm_guest_debug {
+            control: KVM_GUESTDBG_ENABLE,
+            ..Default::default()
+        };
+        if debug.single_step {
+            guest_debug.control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            guest_debug.control |= KVM_GUESTDBG_USE_HW_BP;
+            // DR0 to DR3 hold the addresses, DR7 enables each of them locally, with the kind of
+            // access (instruction by default) and length to watch from bit 16.
+            for (i, addr) in debug.hw_breakpoints.iter().enumerate() {
+                guest_debug.arch.debugreg[i] = *addr;
+                guest_debug.arch.debugreg[7] |= 1 << (i * 2);
+            }
+            for (i, watchpoint) in debug.watchpoints.iter().enumerate() {
+                let slot = debug.hw_breakpoints.len() + i;
+                let access: u64 = match watchpoint.kind {
+                    WatchpointKind::Write => 0b01,
+                    WatchpointKind::Read | WatchpointKind::Access => 0b11,
+                };
+                let len: u64 = match watchpoint.len {
+                    1 => 0b00,
+                    2 => 0b01,
+                    8 => 0b10,
+                    _ => 0b11,
+                };
+                guest_debug.arch.debugreg[slot] = watchpoint.addr;
+                guest_debug.arch.debugreg[7] |=
+                    (1 << (slot * 2)) | (access << (16 + slot * 4)) | (len << (18 + slot * 4));
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            guest_debug.control |= KVM_GUESTDBG_USE_HW;
+            // Enabled, matching all 4 bytes of the instruction at EL1 and EL0.
+            const DBGBCR_EXEC_ANY_EL: u64 = 1 | (0b11 << 1) | (0b1111 << 5);
+            for (i, addr) in debug.hw_breakpoints.iter().enumerate() {
+                guest_debug.arch.dbg_bcr[i] = DBGBCR_EXEC_ANY_EL;
+                guest_debug.arch.dbg_bvr[i] = *addr;
+            }
+            // Enabled at EL1 and EL0, for loads and/or stores of the selected bytes of the
+            // aligned word.
+            for (i, watchpoint) in debug.watchpoints.iter().enumerate() {
+                let access: u64 = match watchpoint.kind {
+                    WatchpointKind::Read => 0b01,
+                    WatchpointKind::Write => 0b10,
+                    WatchpointKind::Access => 0b11,
+                };
+                let bytes = ((1 << watchpoint.len) - 1) << (watchpoint.addr & 7);
+                guest_debug.arch.dbg_wcr[i] = 1 | (0b11 << 1) | (access << 3) | (bytes << 5);
+                guest_debug.arch.dbg_wvr[i] = watchpoint.addr & !7;
+            }
+        }
+
+        guest_debug
+    }
+
+    // Address of the watchpoint that caused a debug exit, if one did.
+    #[cfg(target_arch = "x86_64")]
+    fn watchpoint_hit(&self, exit: &kvm_debug_exit_arch) -> Option<u64> {
+        // DR6 tells which of DR0 to DR3 matched, watchpoints come after the breakpoints.
+        let slot = (0..4usize).find(|slot| exit.dr6 & (1 << slot) != 0)?;
+        self.debug
+            .watchpoints
+            .get(slot.checked_sub(self.debug.hw_breakpoints.len())?)
+            .map(|watchpoint| watchpoint.addr)
+    }
+
+    // Address of the access that caused a debug exit, if a watchpoint did.
+    #[cfg(target_arch = "aarch64")]
+    fn watchpoint_hit(&self, exit: &kvm_debug_exit_arch) -> Option<u64> {
+        // Watchpoint exceptions taken from a lower or the same exception level.
+        const ESR_ELX_EC_WATCHPT_LOW: u32 = 0x34;
+        const ESR_ELX_EC_WATCHPT_CUR: u32 = 0x35;
+        match exit.hsr >> 26 {
+            ESR_ELX_EC_WATCHPT_LOW | ESR_ELX_EC_WATCHPT_CUR => Some(exit.far),
+            _ => None,
+        }
+    }
+
+    // Copy the parts of `kvm_run` describing the last exit out of `KVM_RUN`.
+    fn run_state(&mut self) -> KvmRunSnapshot {
+        let run = self.fd.get_kvm_run();
+        let mut snapshot = KvmRunSnapshot {
+            exit_reason: run.exit_reason,
+            ..Default::default()
+        };
+
+        match run.exit_reason {
+            KVM_EXIT_IO => {
+                // SAFETY: Safe because the exit_reason (which comes from the kernel) told us
+                // which union field to use.
+                let io = unsafe { run.__bindgen_anon_1.io };
+                let data_size = io.count as usize * io.size as usize;
+                let run_start = run as *const kvm_bindings::kvm_run as *const u8;
+                // SAFETY: The data_offset is defined by the kernel to be some number of bytes
+                // into the kvm_run structure, which is fully mmap'd for the lifetime of the vCPU.
+                let data = unsafe {
+                    std::slice::from_raw_parts(run_start.add(io.data_offset as usize), data_size)
+                };
+                snapshot.io = Some(KvmIoExit {
+                    port: io.port,
+                    is_write: u32::from(io.direction) == KVM_EXIT_IO_OUT,
+                    size: io.size,
+                    count: io.count,
+                    data: data.to_vec(),
+                });
+            }
+            KVM_EXIT_MMIO => {
+                // SAFETY: Safe because the exit_reason (which comes from the kernel) told us
+                // which union field to use.
+                let mmio = unsafe { run.__bindgen_anon_1.mmio };
+                let len = std::cmp::min(mmio.len as usize, mmio.data.len());
+                snapshot.mmio = Some(KvmMmioExit {
+                    phys_addr: mmio.phys_addr,
+                    is_write: mmio.is_write != 0,
+                    data: mmio.data[..len].to_vec(),
+                });
+            }
+            _ => (),
+        }
+
+        snapshot
+    }
+
+    // Makes sure this vcpu holds the scheduler token before entering the guest, handing it over
+    // first if its time slice is used up. Returns false if the token didn't come back within a
+    // time slice, so the caller gets to check for events in the meantime.
+    fn acquire_slice(&mut self) -> bool {
+        let Some(scheduler) = &self.scheduler else {
+            return true;
+        };
+        if let Some(start) = self.slice_start {
+            if start.elapsed() < scheduler.time_slice {
+                return true;
+            }
+            self.slice_start = None;
+            scheduler.pass(self.id);
+        }
+        if !scheduler.acquire(self.id, scheduler.time_slice) {
+            return false;
+        }
+        self.slice_start = Some(Instant::now());
+        true
+    }
+
+    // Stops this vcpu from taking turns while it's paused or exited, handing the scheduler token
+    // to the next vcpu if this one holds it.
+    fn release_slice(&mut self) {
+        self.slice_start = None;
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.leave(self.id);
+        }
+    }
+
+    // Accounts for an exit out of `KVM_RUN`, which was entered at `run_start`.
+    fn account_exit(&mut self, run_start: Instant) {
+        let busy_time = run_start.elapsed();
+        let exit_reason = self.fd.get_kvm_run().exit_reason;
+        let mut stats = self.stats.lock().unwrap();
+        stats.exits += 1;
+        stats.busy_time += busy_time;
+        stats.last_exit_reason = Some(exit_reason);
+    }
+
     // This is the main loop of the `Running` state.
     fn running(&mut self) -> StateMachine<Self> {
         // This loop is here just for optimizing the emulation path.
         // No point in ticking the state machine if there are no external events.
         loop {
-            match self.run_emulation() {
+            // Not getting our turn soon enough is an interruption too.
+            if !self.acquire_slice() {
+                break;
+            }
+            let run_start = Instant::now();
+            let emulation = self.run_emulation();
+            self.account_exit(run_start);
+            match emulation {
                 // Emulation ran successfully, continue.
                 Ok(VcpuEmulation::Handled) => (),
                 // Emulation was interrupted, check external events.
@@ -1316,11 +2599,29 @@ impl Vcpu {
                 // seccomp failure because musl calls `sigprocmask` as part of `pthread_exit`.
                 // So we pause vCPU0 and send a signal to the emulation thread to stop the VMM.
                 Ok(VcpuEmulation::Stopped) => return self.exit(FC_EXIT_CODE_OK),
+                // The guest executed `hlt` with interrupts disabled and asked for it to be final.
+                #[cfg(target_arch = "x86_64")]
+                Ok(VcpuEmulation::Halted) => return self.halt(),
+                // The guest hit a breakpoint or finished a single step, wait for the debugger.
+                Ok(VcpuEmulation::DebugExit(watchpoint)) => {
+                    self.release_slice();
+                    self.response_sender
+                        .send(VcpuResponse::DebugExit { watchpoint })
+                        .expect("failed to send debug exit");
+                    return StateMachine::next(Self::paused);
+                }
+                // The vCPU can't run at all, tell the VMM why.
+                Err(Error::VcpuFailEntry(failure)) => return self.fail_entry(failure),
                 // Emulation errors lead to vCPU exit.
                 Err(_) => return self.exit(FC_EXIT_CODE_GENERIC_ERROR),
             }
         }
 
+        #[cfg(target_arch = "x86_64")]
+        if self.halt_exit_code.is_some() && self.guest_halted() {
+            return self.halt();
+        }
+
         // By default don't change state.
         let mut state = StateMachine::next(Self::running);
 
@@ -1328,7 +2629,8 @@ impl Vcpu {
         match self.event_receiver.try_recv() {
             // Running ---- Pause ----> Paused
             Ok(VcpuEvent::Pause) => {
-                // Nothing special to do.
+                // Let the other vcpus run while this one is paused.
+                self.release_slice();
                 self.response_sender
                     .send(VcpuResponse::Paused)
                     .expect("failed to send pause status");
@@ -1344,6 +2646,46 @@ impl Vcpu {
                     .send(VcpuResponse::Resumed)
                     .expect("failed to send resume status");
             }
+            #[cfg(target_arch = "x86_64")]
+            Ok(VcpuEvent::InjectNmi) => {
+                let response = match self.fd.nmi() {
+                    Ok(()) => VcpuResponse::NmiInjected,
+                    Err(e) => VcpuResponse::NmiFailed(e),
+                };
+                self.response_sender
+                    .send(response)
+                    .expect("failed to send nmi status");
+            }
+            // The run loop owns `kvm_run` while running, only paused Vcpus can be inspected.
+            Ok(
+                VcpuEvent::GetRunState
+                | VcpuEvent::GetRegisters
+                | VcpuEvent::SetDebug(_)
+                | VcpuEvent::SetWatchpoint { .. },
+            ) => {
+                self.response_sender
+                    .send(VcpuResponse::NotPaused)
+                    .expect("failed to send run state");
+            }
+            #[cfg(target_arch = "x86_64")]
+            Ok(VcpuEvent::TranslateAddress(_) | VcpuEvent::SaveState) => {
+                self.response_sender
+                    .send(VcpuResponse::NotPaused)
+                    .expect("failed to send run state");
+            }
+            // The registers are only touched by this thread, they can be read between two exits.
+            Ok(VcpuEvent::DumpState) => {
+                self.response_sender
+                    .send(self.state_response())
+                    .expect("failed to send vcpu state");
+            }
+            // MMIO exits are handled on this thread, so the bus can be swapped between two of them.
+            Ok(VcpuEvent::SetMmioBus(bus)) => {
+                self.mmio_bus = Some(bus);
+                self.response_sender
+                    .send(VcpuResponse::MmioBusSet)
+                    .expect("failed to send mmio bus status");
+            }
             // Unhandled exit of the other end.
             Err(TryRecvError::Disconnected) => {
                 // Move to 'exited' state.
@@ -1368,8 +2710,102 @@ impl Vcpu {
                 // Move to 'running' state.
                 StateMachine::next(Self::running)
             }
-            // All other events have no effect on current 'paused' state.
-            Ok(_) => StateMachine::next(Self::paused),
+            // A paused Vcpu can't take an NMI, let the sender know instead of leaving it waiting.
+            #[cfg(target_arch = "x86_64")]
+            Ok(VcpuEvent::InjectNmi) => {
+                self.response_sender
+                    .send(VcpuResponse::NotRunning)
+                    .expect("failed to send nmi status");
+                StateMachine::next(Self::paused)
+            }
+            Ok(VcpuEvent::GetRunState) => {
+                let run_state = self.run_state();
+                self.response_sender
+                    .send(VcpuResponse::RunState(run_state))
+                    .expect("failed to send run state");
+                StateMachine::next(Self::paused)
+            }
+            Ok(VcpuEvent::GetRegisters) => {
+                let response = match self.registers() {
+                    Ok(registers) => VcpuResponse::Registers(Box::new(registers)),
+                    Err(e) => VcpuResponse::RegistersFailed(e),
+                };
+                self.response_sender
+                    .send(response)
+                    .expect("failed to send registers");
+                StateMachine::next(Self::paused)
+            }
+            Ok(VcpuEvent::SetDebug(debug)) => {
+                let response = match self.set_debug(debug) {
+                    Ok(()) => VcpuResponse::DebugSet,
+                    Err(e) => VcpuResponse::DebugFailed(e),
+                };
+                self.response_sender
+                    .send(response)
+                    .expect("failed to send debug status");
+                StateMachine::next(Self::paused)
+            }
+            Ok(VcpuEvent::SetWatchpoint { addr, len, kind }) => {
+                let mut debug = self.debug.clone();
+                debug.watchpoints.push(Watchpoint { addr, len, kind });
+                let response = match self.set_debug(debug) {
+                    Ok(()) => VcpuResponse::DebugSet,
+                    Err(e) => VcpuResponse::DebugFailed(e),
+                };
+                self.response_sender
+                    .send(response)
+                    .expect("failed to send debug status");
+                StateMachine::next(Self::paused)
+            }
+            #[cfg(target_arch = "x86_64")]
+            Ok(VcpuEvent::TranslateAddress(gva)) => {
+                let gpa = self
+                    .fd
+                    .translate_gva(gva)
+                    .ok()
+                    .filter(|translation| translation.valid != 0)
+                    .map(|translation| translation.physical_address);
+                self.response_sender
+                    .send(VcpuResponse::Translated(gpa))
+                    .expect("failed to send address translation");
+                StateMachine::next(Self::paused)
+            }
+            #[cfg(target_arch = "x86_64")]
+            Ok(VcpuEvent::SaveState) => {
+                let response = match self.save_state() {
+                    Ok(state) => {
+                        VcpuResponse::SavedState(crate::snapshot::encode_vcpu_state(&state))
+                    }
+                    Err(e) => {
+                        error!("Failed to save the state of vcpu {}: {}", self.id, e);
+                        VcpuResponse::SaveStateFailed
+                    }
+                };
+                self.response_sender
+                    .send(response)
+                    .expect("failed to send vcpu state");
+                StateMachine::next(Self::paused)
+            }
+            Ok(VcpuEvent::DumpState) => {
+                self.response_sender
+                    .send(self.state_response())
+                    .expect("failed to send vcpu state");
+                StateMachine::next(Self::paused)
+            }
+            Ok(VcpuEvent::SetMmioBus(bus)) => {
+                self.mmio_bus = Some(bus);
+                self.response_sender
+                    .send(VcpuResponse::MmioBusSet)
+                    .expect("failed to send mmio bus status");
+                StateMachine::next(Self::paused)
+            }
+            // Already paused, let the sender know so it doesn't wait for it.
+            Ok(VcpuEvent::Pause) => {
+                self.response_sender
+                    .send(VcpuResponse::Paused)
+                    .expect("failed to send pause status");
+                StateMachine::next(Self::paused)
+            }
             // Unhandled exit of the other end.
             Err(_) => {
                 // Move to 'exited' state.
@@ -1378,12 +2814,33 @@ impl Vcpu {
         }
     }
 
-    #[cfg(not(test))]
     // Transition to the exited state.
     fn exit(&mut self, exit_code: u8) -> StateMachine<Self> {
+        self.release_slice();
+        self.stop(VcpuResponse::Exited(exit_code))
+    }
+
+    // Transition to the exited state, reporting that the vCPU failed to enter guest mode.
+    fn fail_entry(&mut self, failure: VcpuEntryFailure) -> StateMachine<Self> {
+        self.release_slice();
+        self.stop(VcpuResponse::EntryFailed(failure))
+    }
+
+    // Transition to the exited state, reporting that the guest halted for good.
+    #[cfg(target_arch = "x86_64")]
+    fn halt(&mut self) -> StateMachine<Self> {
+        self.release_slice();
+        info!("Guest halted with interrupts disabled");
+        let exit_code = self.halt_exit_code.unwrap_or(FC_EXIT_CODE_OK);
+        self.stop(VcpuResponse::Halted(exit_code))
+    }
+
+    #[cfg(not(test))]
+    // Report `response` to the VMM thread and stop running.
+    fn stop(&mut self, response: VcpuResponse) -> StateMachine<Self> {
         self.response_sender
-            .send(VcpuResponse::Exited(exit_code))
-            .expect("failed to send Exited status");
+            .send(response)
+            .expect("failed to send exit status");
 
         if let Err(e) = self.exit_evt.write(1) {
             error!("Failed signaling vcpu exit event: {}", e);
@@ -1396,10 +2853,9 @@ impl Vcpu {
     #[cfg(not(test))]
     // This is the main loop of the `Exited` state.
     fn exited(&mut self) -> StateMachine<Self> {
-        // Wait indefinitely.
-        // The VMM thread will kill the entire process.
-        let barrier = Barrier::new(2);
-        barrier.wait();
+        // Wait for the VMM thread to either kill the entire process or close the channel, which
+        // `VcpuHandle::join` does to let this thread finish.
+        while self.event_receiver.recv().is_ok() {}
 
         StateMachine::finish()
     }
@@ -1408,7 +2864,7 @@ impl Vcpu {
     // In tests the main/vmm thread exits without 'exit()'ing the whole process.
     // All channels get closed on the other side while this Vcpu thread is still running.
     // This Vcpu thread should just do a clean finish without reporting back to the main thread.
-    fn exit(&mut self, _: u8) -> StateMachine<Self> {
+    fn stop(&mut self, _: VcpuResponse) -> StateMachine<Self> {
         // State machine reached its end.
         StateMachine::finish()
     }
@@ -1422,17 +2878,18 @@ impl Drop for Vcpu {
 
 #[cfg(target_arch = "x86_64")]
 /// Structure holding VCPU kvm state.
-pub struct VcpuState {
-    cpuid: CpuId,
-    msrs: Msrs,
-    debug_regs: kvm_debugregs,
-    lapic: kvm_lapic_state,
-    mp_state: kvm_mp_state,
-    regs: kvm_regs,
-    sregs: kvm_sregs,
-    vcpu_events: kvm_vcpu_events,
-    xcrs: kvm_xcrs,
-    xsave: kvm_xsave,
+pub struct VcpuKvmState {
+    pub(crate) cpuid: CpuId,
+    pub(crate) msrs: Msrs,
+    pub(crate) debug_regs: kvm_debugregs,
+    pub(crate) lapic: kvm_lapic_state,
+    pub(crate) mp_state: kvm_mp_state,
+    pub(crate) nested_state: Option<Vec<u8>>,
+    pub(crate) regs: kvm_regs,
+    pub(crate) sregs: kvm_sregs,
+    pub(crate) vcpu_events: kvm_vcpu_events,
+    pub(crate) xcrs: kvm_xcrs,
+    pub(crate) xsave: kvm_xsave,
 }
 
 // Allow currently unused Pause and Exit events. These will be used by the vmm later on.
@@ -1444,10 +2901,36 @@ pub enum VcpuEvent {
     Pause,
     /// Event that should resume the Vcpu.
     Resume,
+    /// Inject a non-maskable interrupt into the running Vcpu.
+    #[cfg(target_arch = "x86_64")]
+    InjectNmi,
+    /// Take a snapshot of the `kvm_run` area of the paused Vcpu.
+    GetRunState,
+    /// Read the registers of the paused Vcpu.
+    GetRegisters,
+    /// Read the general purpose registers, program counter and segments of the Vcpu, paused or
+    /// running.
+    DumpState,
+    /// Install hardware breakpoints and watchpoints in the paused Vcpu, or make it single-step.
+    SetDebug(GuestDebug),
+    /// Add a watchpoint to the ones of the paused Vcpu.
+    SetWatchpoint {
+        addr: u64,
+        len: u64,
+        kind: WatchpointKind,
+    },
+    /// Translate a guest virtual address with the page tables of the paused Vcpu.
+    #[cfg(target_arch = "x86_64")]
+    TranslateAddress(u64),
+    /// Save the complete KVM state of the paused Vcpu, for a snapshot of the microVM.
+    #[cfg(target_arch = "x86_64")]
+    SaveState,
+    /// Replace the MMIO bus of the Vcpu, e.g. after a device was unplugged.
+    SetMmioBus(devices::Bus),
     // Serialize and Deserialize to follow after we get the support from kvm-ioctls.
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, PartialEq)]
 /// List of responses that the Vcpu reports.
 pub enum VcpuResponse {
     /// Vcpu is paused.
@@ -1456,30 +2939,416 @@ pub enum VcpuResponse {
     Resumed,
     /// Vcpu is stopped.
     Exited(u8),
+    /// The guest halted with interrupts disabled, stopping the Vcpu with the given exit code.
+    #[cfg(target_arch = "x86_64")]
+    Halted(u8),
+    /// A non-maskable interrupt was injected into the Vcpu.
+    #[cfg(target_arch = "x86_64")]
+    NmiInjected,
+    /// Injecting a non-maskable interrupt into the Vcpu failed.
+    #[cfg(target_arch = "x86_64")]
+    NmiFailed(kvm_ioctls::Error),
+    /// The Vcpu can't handle the event because it isn't running.
+    #[cfg(target_arch = "x86_64")]
+    NotRunning,
+    /// Snapshot of the `kvm_run` area of the paused Vcpu.
+    RunState(KvmRunSnapshot),
+    /// Registers of the paused Vcpu.
+    Registers(Box<VcpuRegisters>),
+    /// Reading the registers of the Vcpu failed.
+    RegistersFailed(kvm_ioctls::Error),
+    /// General purpose registers, program counter and segments of the Vcpu.
+    State(Box<VcpuState>),
+    /// The debug state of the paused Vcpu was set.
+    DebugSet,
+    /// Setting the debug state of the paused Vcpu failed.
+    DebugFailed(GuestDebugError),
+    /// The Vcpu hit a breakpoint or watchpoint or finished a single step, and is now paused.
+    DebugExit {
+        /// Address of the watchpoint that triggered, or on aarch64 of the access that triggered
+        /// it.
+        watchpoint: Option<u64>,
+    },
+    /// Guest physical address a guest virtual address maps to, if it's mapped.
+    #[cfg(target_arch = "x86_64")]
+    Translated(Option<u64>),
+    /// KVM state of the paused Vcpu, encoded as in a snapshot of the microVM.
+    #[cfg(target_arch = "x86_64")]
+    SavedState(Vec<u8>),
+    /// Saving the KVM state of the paused Vcpu failed, the error was logged.
+    #[cfg(target_arch = "x86_64")]
+    SaveStateFailed,
+    /// The MMIO bus of the Vcpu was replaced.
+    MmioBusSet,
+    /// The Vcpu can't handle the event because it isn't paused.
+    NotPaused,
+    /// Vcpu is stopped because it failed to enter guest mode.
+    EntryFailed(VcpuEntryFailure),
+}
+
+impl VcpuResponse {
+    // Whether the Vcpu sends this response on its own rather than to answer an event.
+    fn is_unsolicited(&self) -> bool {
+        match self {
+            VcpuResponse::Exited(_)
+            | VcpuResponse::EntryFailed(_)
+            | VcpuResponse::DebugExit { .. } => true,
+            #[cfg(target_arch = "x86_64")]
+            VcpuResponse::Halted(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Details of a vCPU failing to enter guest mode, as reported by a `KVM_EXIT_FAIL_ENTRY` exit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VcpuEntryFailure {
+    /// Index of the vCPU.
+    pub vcpu: u8,
+    /// Host CPU the entry was attempted on.
+    pub host_cpu: u32,
+    /// Hardware specific reason code. On Intel hosts this is the VM-exit reason, usually 33
+    /// (invalid guest state, with bit 31 set) when the vCPU registers were configured wrong.
+    pub reason: u64,
+}
+
+impl Display for VcpuEntryFailure {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "vCPU {} failed to enter guest mode on host CPU {}, hardware entry failure reason {:#x}",
+            self.vcpu, self.host_cpu, self.reason
+        )
+    }
+}
+
+/// Port I/O details of a `KVM_EXIT_IO` exit.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KvmIoExit {
+    pub port: u16,
+    pub is_write: bool,
+    /// Size of each access in bytes.
+    pub size: u8,
+    /// Number of accesses, for string instructions.
+    pub count: u32,
+    /// Data written by the guest, or returned to it, for all the accesses.
+    pub data: Vec<u8>,
+}
+
+/// MMIO details of a `KVM_EXIT_MMIO` exit.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KvmMmioExit {
+    pub phys_addr: u64,
+    pub is_write: bool,
+    /// Data written by the guest, or returned to it.
+    pub data: Vec<u8>,
+}
+
+/// Read-only copy of the `kvm_run` area shared between a Vcpu and KVM, describing the
+/// last exit out of `KVM_RUN`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KvmRunSnapshot {
+    /// One of the `KVM_EXIT_*` reasons.
+    pub exit_reason: u32,
+    /// Set when `exit_reason` is `KVM_EXIT_IO`.
+    pub io: Option<KvmIoExit>,
+    /// Set when `exit_reason` is `KVM_EXIT_MMIO`.
+    pub mmio: Option<KvmMmioExit>,
+}
+
+/// Hardware breakpoints a Vcpu supports, see `GuestDebug`. They share the 4 debug address
+/// registers with the watchpoints.
+#[cfg(target_arch = "x86_64")]
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+/// Watchpoints a Vcpu supports, see `GuestDebug`. They share the 4 debug address registers with
+/// the hardware breakpoints.
+#[cfg(target_arch = "x86_64")]
+pub const MAX_HW_WATCHPOINTS: usize = 4;
+/// Hardware breakpoints a Vcpu supports, see `GuestDebug`. The architecture guarantees at least
+/// 2.
+#[cfg(target_arch = "aarch64")]
+pub const MAX_HW_BREAKPOINTS: usize = 2;
+/// Watchpoints a Vcpu supports, see `GuestDebug`. The architecture guarantees at least 2.
+#[cfg(target_arch = "aarch64")]
+pub const MAX_HW_WATCHPOINTS: usize = 2;
+
+/// Debug state of a Vcpu, as set by a debugger.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GuestDebug {
+    /// Stop the Vcpu after every instruction.
+    pub single_step: bool,
+    /// Guest addresses to stop the Vcpu at, at most `MAX_HW_BREAKPOINTS`.
+    pub hw_breakpoints: Vec<u64>,
+    /// Guest data to stop the Vcpu on accesses to, at most `MAX_HW_WATCHPOINTS`.
+    pub watchpoints: Vec<Watchpoint>,
+}
+
+impl GuestDebug {
+    /// Checks that the debug registers of a Vcpu can hold the breakpoints and watchpoints.
+    pub fn check(&self) -> result::Result<(), GuestDebugError> {
+        let fits = self.hw_breakpoints.len() <= MAX_HW_BREAKPOINTS
+            && self.watchpoints.len() <= MAX_HW_WATCHPOINTS;
+        // Both share DR0 to DR3.
+        #[cfg(target_arch = "x86_64")]
+        let fits = fits && self.hw_breakpoints.len() + self.watchpoints.len() <= 4;
+        if !fits {
+            return Err(GuestDebugError::NoDebugRegisters);
+        }
+        self.watchpoints.iter().try_for_each(Watchpoint::check)
+    }
+}
+
+/// Accesses a watchpoint stops a Vcpu on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WatchpointKind {
+    Write,
+    /// Not supported on x86_64, which can only watch reads along with writes.
+    Read,
+    /// Reads and writes.
+    Access,
+}
+
+/// Range of guest data a Vcpu stops on accesses to, see `GuestDebug`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    /// On x86_64, 1, 2, 4 or 8 bytes, `addr` being aligned to it. On aarch64, from 1 to 8 bytes
+    /// within an aligned 8 byte word.
+    pub len: u64,
+    pub kind: WatchpointKind,
+}
+
+impl Watchpoint {
+    /// Checks that the debug registers of a Vcpu can watch this range and kind of access.
+    pub fn check(&self) -> result::Result<(), GuestDebugError> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if !matches!(self.len, 1 | 2 | 4 | 8) {
+                return Err(GuestDebugError::WatchpointLength(self.len));
+            }
+            if self.addr & (self.len - 1) != 0 {
+                return Err(GuestDebugError::WatchpointAlignment(self.addr, self.len));
+            }
+            if self.kind == WatchpointKind::Read {
+                return Err(GuestDebugError::WatchpointKind(self.kind));
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if !(1..=8).contains(&self.len) {
+                return Err(GuestDebugError::WatchpointLength(self.len));
+            }
+            if (self.addr & 7) + self.len > 8 {
+                return Err(GuestDebugError::WatchpointAlignment(self.addr, self.len));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Errors setting the debug state of a Vcpu.
+#[derive(Debug, PartialEq)]
+pub enum GuestDebugError {
+    /// There are more breakpoints or watchpoints than debug registers.
+    NoDebugRegisters,
+    /// The debug registers can't watch this many bytes.
+    WatchpointLength(u64),
+    /// The debug registers can't watch this many bytes at this address.
+    WatchpointAlignment(u64, u64),
+    /// The debug registers can't watch this kind of access.
+    WatchpointKind(WatchpointKind),
+    /// KVM_SET_GUEST_DEBUG failed.
+    SetGuestDebug(kvm_ioctls::Error),
+}
+
+impl Display for GuestDebugError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::GuestDebugError::*;
+
+        match self {
+            NoDebugRegisters => write!(f, "Not enough debug registers"),
+            WatchpointLength(len) => write!(f, "Cannot watch {len} bytes"),
+            WatchpointAlignment(addr, len) => write!(f, "Cannot watch {len} bytes at {addr:#x}"),
+            WatchpointKind(kind) => write!(f, "Cannot watch {kind:?} accesses"),
+            SetGuestDebug(e) => write!(f, "Cannot set the guest debug state: {e}"),
+        }
+    }
+}
+
+/// Registers of a paused Vcpu, as returned by `Vmm::vcpu_registers`.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VcpuRegisters {
+    pub regs: kvm_bindings::kvm_regs,
+    pub sregs: kvm_bindings::kvm_sregs,
+}
+
+/// Registers of a paused Vcpu, as returned by `Vmm::vcpu_registers`.
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VcpuRegisters {
+    /// x0 to x30, `sp` being `sp_el0`, `pc` and `pstate`.
+    pub regs: kvm_bindings::user_pt_regs,
+    pub sp_el1: u64,
+}
+
+/// Registers of a Vcpu telemetry cares about, as returned by `Vmm::vcpu_state`: a subset of
+/// `VcpuRegisters` that can also be read while the Vcpu runs.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VcpuState {
+    /// General purpose registers, `rip` and `rflags`.
+    pub regs: kvm_bindings::kvm_regs,
+    pub cs: kvm_bindings::kvm_segment,
+    pub ds: kvm_bindings::kvm_segment,
+    pub es: kvm_bindings::kvm_segment,
+    pub fs: kvm_bindings::kvm_segment,
+    pub gs: kvm_bindings::kvm_segment,
+    pub ss: kvm_bindings::kvm_segment,
+}
+
+/// Registers of a Vcpu telemetry cares about, as returned by `Vmm::vcpu_state`: a subset of
+/// `VcpuRegisters` that can also be read while the Vcpu runs.
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VcpuState {
+    /// x0 to x30, `sp` being `sp_el0`, `pc` and `pstate`.
+    pub regs: kvm_bindings::user_pt_regs,
+}
+
+/// Counters a Vcpu accumulates while it runs, see `Vmm::vcpu_stats`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VcpuStats {
+    /// Number of exits out of `KVM_RUN`, including the ones caused by a signal.
+    pub exits: u64,
+    /// Time spent in `KVM_RUN` and handling its exits, which includes the time the guest spent
+    /// halted in KVM.
+    pub busy_time: Duration,
+    /// One of the `KVM_EXIT_*` reasons, for the last exit.
+    pub last_exit_reason: Option<u32>,
+}
+
+/// Token passed round-robin between Vcpus so only one of them runs guest code at a time.
+///
+/// Each Vcpu holding the token keeps it for at least `time_slice` before handing it to the next
+/// one. Accesses to devices and guest memory are then never concurrent, which makes runs of a
+/// racy workload much easier to reproduce, at the cost of using a single host CPU. Slices are
+/// measured in host time, so the interleaving is coarse rather than exactly repeatable. The
+/// token skips the Vcpus that are paused or exited, so they don't hold up the other ones.
+#[derive(Clone)]
+pub struct VcpuScheduler {
+    state: Arc<(Mutex<SchedulerState>, Condvar)>,
+    time_slice: Duration,
+}
+
+struct SchedulerState {
+    // Id of the Vcpu holding the token.
+    owner: u8,
+    // Whether each Vcpu is taking turns, i.e. is in its running state.
+    active: Vec<bool>,
+}
+
+impl SchedulerState {
+    // Hands the token to the next active Vcpu after the owner. It stays put if there's none.
+    fn advance(&mut self) {
+        let count = self.active.len();
+        let owner = usize::from(self.owner);
+        if let Some(next) = (1..=count)
+            .map(|i| (owner + i) % count)
+            .find(|&i| self.active[i])
+        {
+            self.owner = next as u8;
+        }
+    }
+}
+
+impl VcpuScheduler {
+    pub fn new(vcpu_count: u8, time_slice: Duration) -> Self {
+        let state = SchedulerState {
+            owner: 0,
+            active: vec![false; usize::from(vcpu_count.max(1))],
+        };
+        VcpuScheduler {
+            state: Arc::new((Mutex::new(state), Condvar::new())),
+            time_slice,
+        }
+    }
+
+    // Waits up to `timeout` for Vcpu `id` to get the token, returns whether it holds it. Vcpu
+    // `id` takes turns from then on, and gets the token right away if its owner doesn't.
+    fn acquire(&self, id: u8, timeout: Duration) -> bool {
+        let (state, cond) = &*self.state;
+        let mut state = state.lock().unwrap();
+        state.active[usize::from(id)] = true;
+        let owner = usize::from(state.owner);
+        if !state.active[owner] {
+            state.owner = id;
+        }
+        let (state, _) = cond
+            .wait_timeout_while(state, timeout, |state| state.owner != id)
+            .unwrap();
+        state.owner == id
+    }
+
+    // Hands the token from Vcpu `id` to the next one.
+    fn pass(&self, id: u8) {
+        let (state, cond) = &*self.state;
+        let mut state = state.lock().unwrap();
+        if state.owner == id {
+            state.advance();
+            cond.notify_all();
+        }
+    }
+
+    // Stops Vcpu `id` from taking turns until it calls `acquire` again, handing the token over
+    // if it holds it.
+    fn leave(&self, id: u8) {
+        let (state, cond) = &*self.state;
+        let mut state = state.lock().unwrap();
+        state.active[usize::from(id)] = false;
+        if state.owner == id {
+            state.advance();
+            cond.notify_all();
+        }
+    }
 }
 
 /// Wrapper over Vcpu that hides the underlying interactions with the Vcpu thread.
 pub struct VcpuHandle {
     event_sender: Sender<VcpuEvent>,
     response_receiver: Receiver<VcpuResponse>,
+    stats: Arc<Mutex<VcpuStats>>,
     // Rust JoinHandles have to be wrapped in Option if you ever plan on 'join()'ing them.
     // We want to be able to join these threads in tests.
     vcpu_thread: Option<thread::JoinHandle<()>>,
+    // Events sent with `send_request` whose response `recv_response` hasn't received yet.
+    unanswered: AtomicUsize,
+    // Responses the vcpu sent on its own that `recv_response` received instead of an answer.
+    unsolicited: Mutex<Vec<VcpuResponse>>,
 }
 
 impl VcpuHandle {
     pub fn new(
         event_sender: Sender<VcpuEvent>,
         response_receiver: Receiver<VcpuResponse>,
+        stats: Arc<Mutex<VcpuStats>>,
         vcpu_thread: thread::JoinHandle<()>,
     ) -> Self {
         Self {
             event_sender,
             response_receiver,
+            stats,
             vcpu_thread: Some(vcpu_thread),
+            unanswered: AtomicUsize::new(0),
+            unsolicited: Mutex::new(Vec::new()),
         }
     }
 
+    /// Returns a copy of the counters of the vcpu.
+    pub fn stats(&self) -> VcpuStats {
+        self.stats.lock().unwrap().clone()
+    }
+
     pub fn send_event(&self, event: VcpuEvent) -> Result<()> {
         // Use expect() to crash if the other thread closed this channel.
         self.event_sender
@@ -1495,15 +3364,120 @@ impl VcpuHandle {
         Ok(())
     }
 
+    /// Sends `event`, which the vcpu answers with a single response, for `recv_response` to wait
+    /// for.
+    pub fn send_request(&self, event: VcpuEvent) -> Result<()> {
+        self.unanswered.fetch_add(1, Ordering::SeqCst);
+        self.send_event(event)
+    }
+
+    /// Waits up to `timeout` for the answer to the last event sent with `send_request`. The
+    /// answers to the earlier ones, which came after their own wait timed out, are dropped rather
+    /// than taken for it. Returns `None` if the vcpu didn't answer in time, or sent a response
+    /// of its own instead, e.g. because it exited, which `drain_responses` then returns.
+    pub fn recv_response(&self, timeout: Duration) -> Option<VcpuResponse> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let response = self.response_receiver.recv_deadline(deadline).ok()?;
+            if response.is_unsolicited() {
+                self.unsolicited.lock().unwrap().push(response);
+                return None;
+            }
+            let unanswered = self
+                .unanswered
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    Some(n.saturating_sub(1))
+                })
+                .unwrap();
+            if unanswered <= 1 {
+                return Some(response);
+            }
+            debug!("Dropping the late vcpu response {response:?}");
+        }
+    }
+
+    /// Returns the responses the vcpu sent on its own, such as `Exited`, along with any other one
+    /// nobody waited for.
+    pub fn drain_responses(&self) -> Vec<VcpuResponse> {
+        let mut responses = std::mem::take(&mut *self.unsolicited.lock().unwrap());
+        responses.extend(self.response_receiver.try_iter());
+        responses
+    }
+
     pub fn response_receiver(&self) -> &Receiver<VcpuResponse> {
         &self.response_receiver
     }
+
+    /// Makes the vcpu thread finish and waits for it, whatever state it's in. A running or paused
+    /// vcpu exits with `FC_EXIT_CODE_GENERIC_ERROR`, writing the exit event. Joining an already
+    /// joined vcpu does nothing.
+    pub fn join(&mut self) -> Result<()> {
+        let Some(vcpu_thread) = self.vcpu_thread.take() else {
+            return Ok(());
+        };
+        // Closing the channel is what makes the vcpu leave any state, the kick only gets it out
+        // of KVM_RUN to notice.
+        let (event_sender, _event_receiver) = unbounded();
+        drop(std::mem::replace(&mut self.event_sender, event_sender));
+        if let Err(e) = vcpu_thread.kill(sigrtmin() + VCPU_RTSIG_OFFSET) {
+            self.vcpu_thread = Some(vcpu_thread);
+            return Err(Error::SignalVcpu(e));
+        }
+        vcpu_thread.join().map_err(|_| Error::VcpuThreadPanicked)
+    }
 }
 
 enum VcpuEmulation {
     Handled,
     Interrupted,
     Stopped,
+    #[cfg(target_arch = "x86_64")]
+    Halted,
+    // With the address reported by the watchpoint that caused it, if one did.
+    DebugExit(Option<u64>),
+}
+
+/// Periodic timer sending the kick signal to the thread that created it.
+struct KickTimer(libc::timer_t);
+
+impl KickTimer {
+    fn new(interval: Duration) -> io::Result<Self> {
+        // Safe because sigevent is a plain C struct for which all zeroes is a valid value.
+        let mut sev: libc::sigevent = unsafe { std::mem::zeroed() };
+        sev.sigev_notify = SIGEV_THREAD_ID;
+        sev.sigev_signo = sigrtmin() + VCPU_RTSIG_OFFSET;
+        // Safe because gettid has no failure modes.
+        sev.sigev_notify_thread_id = unsafe { libc::gettid() };
+
+        let mut timer_id: libc::timer_t = std::ptr::null_mut();
+        // Safe because both pointers are valid for the duration of the call and we check the
+        // return value.
+        if unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut sev, &mut timer_id) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let timer = KickTimer(timer_id);
+
+        let period = libc::timespec {
+            tv_sec: interval.as_secs() as libc::time_t,
+            tv_nsec: interval.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec {
+            it_interval: period,
+            it_value: period,
+        };
+        // Safe because `timer.0` is a valid timer and we check the return value.
+        if unsafe { libc::timer_settime(timer.0, 0, &spec, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(timer)
+    }
+}
+
+impl Drop for KickTimer {
+    fn drop(&mut self) {
+        // Safe because `self.0` is a valid timer that is deleted exactly once.
+        unsafe { libc::timer_delete(self.0) };
+    }
 }
 
 #[cfg(test)]
@@ -1520,6 +3494,9 @@ mod tests {
     // In tests we need to close any pending Vcpu threads on test completion.
     impl Drop for VcpuHandle {
         fn drop(&mut self) {
+            if self.vcpu_thread.is_none() {
+                return;
+            }
             // Make sure the Vcpu is out of KVM_RUN.
             self.send_event(VcpuEvent::Pause).unwrap();
             // Close the original channel so that the Vcpu thread errors and goes to exit state.
@@ -1610,6 +3587,79 @@ mod tests {
         assert!(vm.memory_init(&gm, kvm_context.max_memslots()).is_err());
     }
 
+    #[test]
+    fn test_dirty_logging() {
+        use vm_memory::Bytes;
+
+        let kvm_context = KvmContext::new().unwrap();
+        let mut vm = Vm::new(kvm_context.fd()).expect("Cannot create new vm");
+        let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        vm.memory_init(&gm, kvm_context.max_memslots()).unwrap();
+
+        // KVM has no bitmap for slots that aren't logged.
+        assert!(vm.get_dirty_bitmap().is_err());
+        vm.enable_dirty_logging().unwrap();
+        assert!(vm
+            .guest_memory_slots
+            .iter()
+            .all(|slot| slot.flags == kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES));
+        // Nothing ran in the guest, and writes from the VMM aren't tracked.
+        gm.write_obj(1u64, GuestAddress(0)).unwrap();
+        assert_eq!(vm.get_dirty_bitmap().unwrap(), []);
+        vm.disable_dirty_logging().unwrap();
+        assert!(vm.get_dirty_bitmap().is_err());
+    }
+
+    #[test]
+    fn test_dirty_regions() {
+        assert_eq!(dirty_regions(0x1000, &[0, 0], 0x1000), []);
+        assert_eq!(
+            dirty_regions(0x1000, &[0b1101, 1 << 63, 1], 0x1000),
+            [
+                DirtyRegion {
+                    guest_addr: GuestAddress(0x1000),
+                    len: 0x1000
+                },
+                DirtyRegion {
+                    guest_addr: GuestAddress(0x3000),
+                    len: 0x2000
+                },
+                DirtyRegion {
+                    guest_addr: GuestAddress(0x1000 + 127 * 0x1000),
+                    len: 0x2000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_readonly_memory() {
+        let kvm_context = KvmContext::new().unwrap();
+        let mut vm = Vm::new(kvm_context.fd()).expect("Cannot create new vm");
+        let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        vm.memory_init(&gm, kvm_context.max_memslots()).unwrap();
+
+        vm.map_readonly_memory(GuestAddress(0x10_0000), 0x2000, &[1, 2, 3])
+            .unwrap();
+        assert_eq!(vm.next_memslot, 2);
+        let region = &vm.readonly_memory[0];
+        assert_eq!(region.size(), 0x2000);
+        // Safe because the region is 0x2000 bytes long.
+        let contents = unsafe { std::slice::from_raw_parts(region.as_ptr(), 4) };
+        assert_eq!(contents, [1, 2, 3, 0]);
+
+        // KVM refuses overlapping slots.
+        assert!(vm
+            .map_readonly_memory(GuestAddress(0x10_1000), 0x1000, &[])
+            .is_err());
+
+        vm.max_memslots = 2;
+        assert!(matches!(
+            vm.map_readonly_memory(GuestAddress(0x20_0000), 0x1000, &[]),
+            Err(Error::NotEnoughMemorySlots)
+        ));
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_setup_irqchip() {
@@ -1636,6 +3686,52 @@ mod tests {
         assert!(vm.setup_irqchip().is_err());
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_set_gsi_routing() {
+        let kvm_context = KvmContext::new().unwrap();
+        let mut vm = Vm::new(kvm_context.fd()).expect("Cannot create new vm");
+        vm.setup_irqchip().expect("Cannot setup irqchip");
+
+        let mut routes = vm.gsi_routing();
+        assert_eq!(routes.len(), 40);
+
+        let msi = GsiRoute {
+            gsi: 24,
+            destination: GsiDestination::Msi {
+                address: 0xfee0_0000,
+                data: 0x41,
+            },
+        };
+        routes.push(msi);
+        vm.set_gsi_routing(&routes).unwrap();
+        assert_eq!(vm.gsi_routing(), routes);
+
+        let irqchip = |gsi, chip, pin| GsiRoute {
+            gsi,
+            destination: GsiDestination::Irqchip { chip, pin },
+        };
+        for invalid in [
+            vec![irqchip(5, 3, 0)],
+            vec![irqchip(5, 2, 24)],
+            vec![irqchip(5, 0, 8)],
+            vec![irqchip(MAX_GSI_ROUTES as u32, 2, 5)],
+            vec![irqchip(5, 2, 5), irqchip(5, 2, 6)],
+            vec![irqchip(24, 2, 5), msi],
+        ] {
+            assert!(matches!(
+                vm.set_gsi_routing(&invalid),
+                Err(Error::InvalidGsiRoute(_))
+            ));
+        }
+        assert!(matches!(
+            vm.set_gsi_routing(&vec![msi; MAX_GSI_ROUTES + 1]),
+            Err(Error::TooManyGsiRoutes(_))
+        ));
+        // Failed calls leave the installed table alone.
+        assert_eq!(vm.gsi_routing(), routes);
+    }
+
     #[cfg(target_arch = "aarch64")]
     #[test]
     fn test_setup_irqchip() {
@@ -1665,6 +3761,10 @@ mod tests {
             vcpu_count: 1,
             ht_enabled: false,
             cpu_template: None,
+            cpuid_overrides: Vec::new(),
+            nested: false,
+            tsc_khz: None,
+            cpu_topology: None,
         };
 
         assert!(vcpu
@@ -1684,6 +3784,113 @@ mod tests {
             .is_ok());
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_configure_vcpu_cpuid_overrides() {
+        // Leaf 1 ecx bit 31 is the hypervisor bit, which KVM always reports.
+        const HYPERVISOR_BIT: u32 = 31;
+        let (_vm, mut vcpu, vm_mem) = setup_vcpu(0x10000);
+
+        let vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            ht_enabled: false,
+            cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpuid_overrides: vec![
+                CpuidOverride::clear_bit(1, CpuidRegister::Ecx, HYPERVISOR_BIT),
+                CpuidOverride {
+                    leaf: 0x8000_0001,
+                    index: Some(0),
+                    register: CpuidRegister::Edx,
+                    mask: 0xffff_ffff,
+                    value: 0,
+                },
+            ],
+            nested: false,
+            tsc_khz: None,
+            cpu_topology: None,
+        };
+        vcpu.configure_x86_64(&vm_mem, GuestAddress(0), &vcpu_config)
+            .unwrap();
+
+        let cpuid = vcpu.fd.get_cpuid2(KVM_MAX_CPUID_ENTRIES).unwrap();
+        let leaf = |function| {
+            *cpuid
+                .as_slice()
+                .iter()
+                .find(|entry| entry.function == function)
+                .unwrap()
+        };
+        assert_eq!(leaf(1).ecx & (1 << HYPERVISOR_BIT), 0);
+        assert_eq!(leaf(0x8000_0001).edx, 0);
+        // The bits left alone still come from the host and the template.
+        assert_ne!(leaf(1).edx, 0);
+    }
+
+    #[test]
+    fn test_boot_complete_time() {
+        let (_vm, mut vcpu, _vm_mem) = setup_vcpu(0x10000);
+        let time = Arc::new(OnceLock::new());
+        vcpu.set_boot_complete_time(time.clone());
+
+        // Other values or addresses aren't the boot completion signal.
+        vcpu.check_boot_complete_signal(MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE, &[0]);
+        vcpu.check_boot_complete_signal(
+            MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE + 1,
+            &[MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE],
+        );
+        assert!(time.get().is_none());
+
+        let before = Instant::now();
+        vcpu.check_boot_complete_signal(
+            MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE,
+            &[MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE],
+        );
+        let first = *time.get().unwrap();
+        assert!(first >= before);
+
+        // Only the first signal is recorded.
+        vcpu.check_boot_complete_signal(
+            MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE,
+            &[MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE],
+        );
+        assert_eq!(*time.get().unwrap(), first);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_check_ptp_kvm_support() {
+        // KVM always offers kvmclock on x86_64, only the TSC stability depends on the host.
+        let (vm, _vcpu, _vm_mem) = setup_vcpu(0x10000);
+        assert!(vm.check_ptp_kvm_support().is_ok());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_nested_state() {
+        let (vm, mut vcpu, vm_mem) = setup_vcpu(0x10000);
+        if vm.check_nested_support().is_err() {
+            // The host doesn't have nesting enabled, nothing else to check.
+            return;
+        }
+
+        let vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            ht_enabled: false,
+            cpu_template: None,
+            cpuid_overrides: Vec::new(),
+            nested: true,
+            tsc_khz: None,
+            cpu_topology: None,
+        };
+        vcpu.configure_x86_64(&vm_mem, GuestAddress(0), &vcpu_config)
+            .unwrap();
+
+        let state = vcpu.save_state().unwrap();
+        let nested_state = state.nested_state.as_ref().unwrap();
+        assert!(nested_state.len() >= std::mem::size_of::<kvm_nested_state>());
+        vcpu.restore_state(state).unwrap();
+    }
+
     #[cfg(target_arch = "aarch64")]
     #[test]
     fn test_configure_vcpu() {
@@ -1691,6 +3898,13 @@ mod tests {
         let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let mut vm = Vm::new(kvm.fd()).expect("new vm failed");
         assert!(vm.memory_init(&gm, kvm.max_memslots()).is_ok());
+        let mut vcpu_config = VcpuConfig {
+            vcpu_count: 2,
+            ht_enabled: false,
+            cpu_template: None,
+            pmu: false,
+            sve: false,
+        };
 
         // Try it for when vcpu id is 0.
         let mut vcpu = Vcpu::new_aarch64(
@@ -1702,10 +3916,14 @@ mod tests {
         .unwrap();
 
         assert!(vcpu
-            .configure_aarch64(vm.fd(), &gm, GuestAddress(0))
+            .configure_aarch64(vm.fd(), &gm, GuestAddress(0), &vcpu_config)
             .is_ok());
+        assert!(!vcpu.has_pmu());
 
-        // Try it for when vcpu id is NOT 0.
+        // Try it for when vcpu id is NOT 0, with the optional features the host supports.
+        vcpu_config.pmu = vm.pmu_supported;
+        vcpu_config.sve = vm.sve_supported;
+        assert!(vm.check_vcpu_features(&vcpu_config).is_ok());
         let mut vcpu = Vcpu::new_aarch64(
             1,
             vm.fd(),
@@ -1715,8 +3933,9 @@ mod tests {
         .unwrap();
 
         assert!(vcpu
-            .configure_aarch64(vm.fd(), &gm, GuestAddress(0))
+            .configure_aarch64(vm.fd(), &gm, GuestAddress(0), &vcpu_config)
             .is_ok());
+        assert_eq!(vcpu.has_pmu(), vm.pmu_supported);
     }
 
     #[test]
@@ -1777,6 +3996,20 @@ mod tests {
         vcpu.init_thread_local_data().unwrap_err();
     }
 
+    #[test]
+    fn test_vcpu_stats() {
+        let (_vm, mut vcpu, _mem) = setup_vcpu(0x1000);
+        let stats = vcpu.stats.clone();
+        assert_eq!(*stats.lock().unwrap(), VcpuStats::default());
+
+        vcpu.account_exit(Instant::now());
+        vcpu.account_exit(Instant::now() - Duration::from_millis(10));
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.exits, 2);
+        assert!(stats.busy_time >= Duration::from_millis(10));
+        assert!(stats.last_exit_reason.is_some());
+    }
+
     #[test]
     fn test_vcpu_kick() {
         Vcpu::register_kick_signal_handler();
@@ -1818,8 +4051,157 @@ mod tests {
         assert!(success.load(Ordering::Acquire));
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_guest_halted() {
+        let (_vm, mut vcpu, _mem) = setup_vcpu(0x1000);
+        vcpu.set_halt_exit_code(Some(3));
+
+        let mut mp_state = vcpu.fd.get_mp_state().unwrap();
+        mp_state.mp_state = KVM_MP_STATE_HALTED;
+        vcpu.fd.set_mp_state(mp_state).unwrap();
+
+        let mut regs = vcpu.fd.get_regs().unwrap();
+        regs.rflags = 0x2 | X86_EFLAGS_IF;
+        vcpu.fd.set_regs(&regs).unwrap();
+        // Idling in `hlt` with interrupts enabled isn't a halt.
+        assert!(!vcpu.guest_halted());
+
+        regs.rflags = 0x2;
+        vcpu.fd.set_regs(&regs).unwrap();
+        assert!(vcpu.guest_halted());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_kick_timer() {
+        Vcpu::register_kick_signal_handler();
+        let (vm, mut vcpu, _mem) = setup_vcpu(0x1000);
+
+        let kvm_run =
+            KvmRunWrapper::mmap_from_fd(&vcpu.fd, vm.fd.run_size()).expect("cannot mmap kvm-run");
+        let handle = std::thread::spawn(move || {
+            vcpu.init_thread_local_data().unwrap();
+            let _timer = KickTimer::new(Duration::from_millis(10)).unwrap();
+            // Loop for max 1 second to check if the timer has kicked the Vcpu.
+            for _ in 0..10 {
+                if kvm_run.as_mut_ref().immediate_exit == 1 {
+                    return true;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            false
+        });
+        assert!(handle.join().expect("failed to join thread"));
+    }
+
+    #[test]
+    fn test_vcpu_handle_responses() {
+        let (event_sender, _event_receiver) = unbounded();
+        let (response_sender, response_receiver) = unbounded();
+        let mut handle = VcpuHandle::new(
+            event_sender,
+            response_receiver,
+            Arc::default(),
+            thread::spawn(|| {}),
+        );
+        handle.vcpu_thread.take().unwrap().join().unwrap();
+        let timeout = Duration::from_millis(10);
+
+        // The answer to a request that timed out isn't taken for the one to the next request.
+        handle.unanswered.store(2, Ordering::SeqCst);
+        response_sender.send(VcpuResponse::Paused).unwrap();
+        response_sender.send(VcpuResponse::Resumed).unwrap();
+        assert_eq!(handle.recv_response(timeout), Some(VcpuResponse::Resumed));
+        assert_eq!(handle.recv_response(timeout), None);
+
+        // The exit of the vcpu ends the wait, and is still reported afterwards.
+        handle.unanswered.store(1, Ordering::SeqCst);
+        response_sender.send(VcpuResponse::Exited(3)).unwrap();
+        response_sender.send(VcpuResponse::Paused).unwrap();
+        assert_eq!(handle.recv_response(timeout), None);
+        assert_eq!(
+            handle.drain_responses(),
+            [VcpuResponse::Exited(3), VcpuResponse::Paused]
+        );
+    }
+
+    #[test]
+    fn test_vcpu_scheduler() {
+        let scheduler = VcpuScheduler::new(2, Duration::from_millis(10));
+        assert!(scheduler.acquire(0, Duration::ZERO));
+        assert!(!scheduler.acquire(1, Duration::from_millis(10)));
+        // Only the holder can hand the token over.
+        scheduler.pass(1);
+        assert!(scheduler.acquire(0, Duration::ZERO));
+
+        let other = scheduler.clone();
+        let handle = std::thread::spawn(move || other.acquire(1, Duration::from_secs(10)));
+        scheduler.pass(0);
+        assert!(handle.join().expect("failed to join thread"));
+        scheduler.pass(1);
+        assert!(scheduler.acquire(0, Duration::ZERO));
+
+        // A paused vcpu hands the token over and is skipped until it runs again.
+        scheduler.leave(0);
+        assert!(scheduler.acquire(1, Duration::ZERO));
+        scheduler.pass(1);
+        assert!(scheduler.acquire(1, Duration::ZERO));
+        scheduler.leave(1);
+        // With everyone paused, the first vcpu to run again takes the token.
+        assert!(scheduler.acquire(0, Duration::ZERO));
+    }
+
     #[test]
     fn test_vcpu_rtsig_offset() {
         assert!(validate_signal_num(sigrtmin() + VCPU_RTSIG_OFFSET).is_ok());
     }
+
+    #[test]
+    fn test_guest_debug_check() {
+        let watchpoint = |addr, len, kind| Watchpoint { addr, len, kind };
+        assert!(watchpoint(0x1000, 8, WatchpointKind::Write).check().is_ok());
+        assert!(watchpoint(0x1002, 2, WatchpointKind::Access)
+            .check()
+            .is_ok());
+        assert_eq!(
+            watchpoint(0x1000, 0, WatchpointKind::Write).check(),
+            Err(GuestDebugError::WatchpointLength(0))
+        );
+        assert_eq!(
+            watchpoint(0x1004, 8, WatchpointKind::Write).check(),
+            Err(GuestDebugError::WatchpointAlignment(0x1004, 8))
+        );
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(
+                watchpoint(0x1000, 3, WatchpointKind::Write).check(),
+                Err(GuestDebugError::WatchpointLength(3))
+            );
+            assert_eq!(
+                watchpoint(0x1000, 4, WatchpointKind::Read).check(),
+                Err(GuestDebugError::WatchpointKind(WatchpointKind::Read))
+            );
+        }
+        #[cfg(target_arch = "aarch64")]
+        assert!(watchpoint(0x1001, 3, WatchpointKind::Read).check().is_ok());
+
+        let mut debug = GuestDebug {
+            single_step: false,
+            hw_breakpoints: vec![0x2000; MAX_HW_BREAKPOINTS],
+            watchpoints: Vec::new(),
+        };
+        assert!(debug.check().is_ok());
+        debug
+            .watchpoints
+            .push(watchpoint(0x1000, 4, WatchpointKind::Write));
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(debug.check(), Err(GuestDebugError::NoDebugRegisters));
+        #[cfg(target_arch = "aarch64")]
+        assert!(debug.check().is_ok());
+        debug.hw_breakpoints.clear();
+        debug.watchpoints =
+            vec![watchpoint(0x1000, 4, WatchpointKind::Write); MAX_HW_WATCHPOINTS + 1];
+        assert_eq!(debug.check(), Err(GuestDebugError::NoDebugRegisters));
+    }
 }
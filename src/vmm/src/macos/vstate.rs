@@ -9,10 +9,11 @@ use std::cell::Cell;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
 #[cfg(not(test))]
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::super::TimestampUs;
 use super::super::{FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK};
@@ -211,7 +212,6 @@ pub struct Vcpu {
     #[cfg(target_arch = "aarch64")]
     mpidr: u64,
 
-    #[allow(unused)]
     event_receiver: Receiver<VcpuEvent>,
     // The transmitting end of the events channel which will be given to the handler.
     event_sender: Option<Sender<VcpuEvent>>,
@@ -219,6 +219,10 @@ pub struct Vcpu {
     response_receiver: Option<Receiver<VcpuResponse>>,
     // The transmitting end of the responses channel owned by the vcpu side.
     response_sender: Sender<VcpuResponse>,
+    // Whether the vcpu waits for a `Resume` event before running the guest.
+    start_paused: bool,
+    // Counters updated on every exit, shared with the VcpuHandle.
+    stats: Arc<Mutex<VcpuStats>>,
 
     intc: Arc<Mutex<Gic>>,
 }
@@ -313,6 +317,8 @@ impl Vcpu {
             event_sender: Some(event_sender),
             response_receiver: Some(response_receiver),
             response_sender,
+            start_paused: false,
+            stats: Arc::new(Mutex::new(VcpuStats::default())),
             intc,
         })
     }
@@ -336,6 +342,12 @@ impl Vcpu {
         self.boot_senders = Some(boot_senders);
     }
 
+    /// Makes the vcpu wait for a `Resume` event before it runs the guest, the way vcpus always
+    /// start on Linux.
+    pub fn set_start_paused(&mut self, start_paused: bool) {
+        self.start_paused = start_paused;
+    }
+
     /// Configures an aarch64 specific vcpu.
     ///
     /// # Arguments
@@ -355,6 +367,7 @@ impl Vcpu {
     pub fn start_threaded(mut self) -> Result<VcpuHandle> {
         let event_sender = self.event_sender.take().unwrap();
         let response_receiver = self.response_receiver.take().unwrap();
+        let stats = self.stats.clone();
         let (init_tls_sender, init_tls_receiver) = unbounded();
 
         let vcpu_thread = thread::Builder::new()
@@ -378,6 +391,7 @@ impl Vcpu {
         Ok(VcpuHandle::new(
             event_sender,
             response_receiver,
+            stats,
             vcpu_thread,
         ))
     }
@@ -468,6 +482,20 @@ impl Vcpu {
 
     /// Main loop of the vCPU thread.
     pub fn run(&mut self) {
+        if self.start_paused {
+            loop {
+                match self.event_receiver.recv() {
+                    Ok(VcpuEvent::Resume) => break,
+                    Ok(_) => (),
+                    // The VMM is gone.
+                    Err(_) => return,
+                }
+            }
+            self.response_sender
+                .send(VcpuResponse::Resumed)
+                .expect("failed to send Resumed status");
+        }
+
         let mut hvf_vcpu = HvfVcpu::new().expect("Can't create HVF vCPU");
         let hvf_vcpuid = hvf_vcpu.id();
 
@@ -488,7 +516,14 @@ impl Vcpu {
             .unwrap_or_else(|_| panic!("Can't set HVF vCPU {} initial state", hvf_vcpuid));
 
         loop {
-            match self.run_emulation(&mut hvf_vcpu) {
+            let run_start = Instant::now();
+            let emulation = self.run_emulation(&mut hvf_vcpu);
+            {
+                let mut stats = self.stats.lock().unwrap();
+                stats.exits += 1;
+                stats.busy_time += run_start.elapsed();
+            }
+            match emulation {
                 // Emulation ran successfully, continue.
                 Ok(VcpuEmulation::Handled) => (),
                 // Emulation was interrupted by a breakpoint.
@@ -578,24 +613,56 @@ pub enum VcpuResponse {
     Exited(u8),
 }
 
+impl VcpuResponse {
+    // Whether the Vcpu sends this response on its own rather than to answer an event.
+    fn is_unsolicited(&self) -> bool {
+        matches!(self, VcpuResponse::Exited(_))
+    }
+}
+
+/// Counters a Vcpu accumulates while it runs, see `Vmm::vcpu_stats`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VcpuStats {
+    /// Number of exits out of the guest.
+    pub exits: u64,
+    /// Time spent running the guest and handling its exits.
+    pub busy_time: Duration,
+    /// Reason of the last exit. HVF exit reasons aren't reported.
+    pub last_exit_reason: Option<u32>,
+}
+
 /// Wrapper over Vcpu that hides the underlying interactions with the Vcpu thread.
 pub struct VcpuHandle {
     event_sender: Sender<VcpuEvent>,
     response_receiver: Receiver<VcpuResponse>,
+    stats: Arc<Mutex<VcpuStats>>,
+    // Events sent with `send_request` whose response `recv_response` hasn't received yet.
+    unanswered: AtomicUsize,
+    // Responses the vcpu sent on its own that `recv_response` received instead of an answer.
+    unsolicited: Mutex<Vec<VcpuResponse>>,
 }
 
 impl VcpuHandle {
     pub fn new(
         event_sender: Sender<VcpuEvent>,
         response_receiver: Receiver<VcpuResponse>,
+        stats: Arc<Mutex<VcpuStats>>,
         _vcpu_thread: thread::JoinHandle<()>,
     ) -> Self {
         Self {
             event_sender,
             response_receiver,
+            stats,
+            unanswered: AtomicUsize::new(0),
+            unsolicited: Mutex::new(Vec::new()),
         }
     }
 
+    /// Returns a copy of the counters of the vcpu.
+    pub fn stats(&self) -> VcpuStats {
+        self.stats.lock().unwrap().clone()
+    }
+
     pub fn send_event(&self, event: VcpuEvent) -> Result<()> {
         // Use expect() to crash if the other thread closed this channel.
         self.event_sender
@@ -613,6 +680,46 @@ impl VcpuHandle {
         Ok(())
     }
 
+    /// Sends `event`, which the vcpu answers with a single response, for `recv_response` to wait
+    /// for.
+    pub fn send_request(&self, event: VcpuEvent) -> Result<()> {
+        self.unanswered.fetch_add(1, Ordering::SeqCst);
+        self.send_event(event)
+    }
+
+    /// Waits up to `timeout` for the answer to the last event sent with `send_request`. The
+    /// answers to the earlier ones, which came after their own wait timed out, are dropped rather
+    /// than taken for it. Returns `None` if the vcpu didn't answer in time, or sent a response
+    /// of its own instead, e.g. because it exited, which `drain_responses` then returns.
+    pub fn recv_response(&self, timeout: Duration) -> Option<VcpuResponse> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let response = self.response_receiver.recv_deadline(deadline).ok()?;
+            if response.is_unsolicited() {
+                self.unsolicited.lock().unwrap().push(response);
+                return None;
+            }
+            let unanswered = self
+                .unanswered
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    Some(n.saturating_sub(1))
+                })
+                .unwrap();
+            if unanswered <= 1 {
+                return Some(response);
+            }
+            debug!("Dropping the late vcpu response {response:?}");
+        }
+    }
+
+    /// Returns the responses the vcpu sent on its own, such as `Exited`, along with any other one
+    /// nobody waited for.
+    pub fn drain_responses(&self) -> Vec<VcpuResponse> {
+        let mut responses = std::mem::take(&mut *self.unsolicited.lock().unwrap());
+        responses.extend(self.response_receiver.try_iter());
+        responses
+    }
+
     pub fn response_receiver(&self) -> &Receiver<VcpuResponse> {
         &self.response_receiver
     }
@@ -3,11 +3,16 @@
 
 //#![deny(warnings)]
 
+use std::collections::HashMap;
 #[cfg(feature = "tee")]
 use std::fs::File;
+use std::io;
 #[cfg(feature = "tee")]
 use std::io::BufReader;
+use std::io::{Read, Seek};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[cfg(feature = "tee")]
 use serde::{Deserialize, Serialize};
@@ -15,19 +20,38 @@ use serde::{Deserialize, Serialize};
 #[cfg(feature = "tee")]
 use kbs_types::Tee;
 
+#[cfg(target_os = "linux")]
+use crate::signal_handler::{is_forwardable_signal, GuestSignalAction, GuestSignalError};
+#[cfg(not(feature = "tee"))]
+use crate::vmm_config::balloon::{balloon_target_pages, BalloonConfig, BalloonConfigError};
 #[cfg(feature = "blk")]
 use crate::vmm_config::block::{BlockBuilder, BlockConfigError, BlockDeviceConfig};
-use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
+use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError, KernelCmdlineArgs};
 #[cfg(not(feature = "tee"))]
 use crate::vmm_config::fs::*;
 #[cfg(feature = "tee")]
 use crate::vmm_config::kernel_bundle::{InitrdBundle, QbootBundle, QbootBundleError};
 use crate::vmm_config::kernel_bundle::{KernelBundle, KernelBundleError};
-use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
+use crate::vmm_config::machine_config::{
+    CpuTopology, MemoryAdvice, MemoryBackend, VmConfig, VmConfigError,
+};
 #[cfg(feature = "net")]
 use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig, NetworkInterfaceError};
+#[cfg(target_os = "linux")]
+use crate::vmm_config::readonly_memory::{
+    ReadOnlyRegionConfig, ReadOnlyRegionError, ReadOnlyWritePolicy,
+};
+#[cfg(not(feature = "tee"))]
+use crate::vmm_config::rng::RngConfig;
+use crate::vmm_config::rtc::RtcConfig;
 use crate::vmm_config::vsock::*;
 use crate::vstate::VcpuConfig;
+use crate::VmmEventsObserver;
+use arch::BootEntropy;
+use devices::{BusAccessHandler, UnhandledAccessObserver};
+#[cfg(target_os = "linux")]
+use utils::seccomp::SeccompMode;
+use vm_memory::MmapRegion;
 
 type Result<E> = std::result::Result<(), E>;
 
@@ -51,6 +75,8 @@ pub enum Error {
     VmConfig(VmConfigError),
     /// Vsock device configuration error.
     VsockDevice(VsockConfigError),
+    /// The guest can't find a pvpanic device on this architecture.
+    PvPanicUnsupported,
 }
 
 #[cfg(feature = "tee")]
@@ -88,6 +114,11 @@ pub struct VmResources {
     pub boot_config: BootSourceConfig,
     /// The parameters for the kernel bundle to be loaded in this microVM.
     pub kernel_bundle: Option<KernelBundle>,
+    /// The memory `kernel_bundle` points to, if the kernel was loaded by `set_kernel_reader`.
+    pub kernel_image: Option<Arc<MmapRegion>>,
+    /// The contents of the initrd to be loaded in this microVM.
+    #[cfg(not(feature = "tee"))]
+    pub initrd: Option<Vec<u8>>,
     /// The parameters for the qboot bundle to be loaded in this microVM.
     #[cfg(feature = "tee")]
     pub qboot_bundle: Option<QbootBundle>,
@@ -115,8 +146,71 @@ pub struct VmResources {
     pub snd_device: bool,
     /// File to send console output.
     pub console_output: Option<PathBuf>,
+    /// Number of serial console lines buffered for `Vmm::read_console_line`, if capturing.
+    pub serial_capture_lines: Option<usize>,
+    /// Size of each of the buffers of the virtio-console channel, if the guest gets one.
+    pub console_channel_capacity: Option<usize>,
+    /// Whether a guest panic stops the microVM, if the guest gets a pvpanic device.
+    pub pvpanic_stop_on_panic: Option<bool>,
     /// SMBIOS OEM Strings
     pub smbios_oem_strings: Option<Vec<String>>,
+    /// Observer for guest accesses to unregistered MMIO/PIO addresses.
+    pub unhandled_access_observer: Option<Arc<dyn UnhandledAccessObserver>>,
+    /// Embedder handlers for guest accesses to MMIO ranges, as `(base, len, handler)`.
+    pub mmio_handlers: Vec<(u64, u64, Arc<dyn BusAccessHandler>)>,
+    /// Embedder handlers for guest accesses to I/O port ranges, as `(base, len, handler)`.
+    #[cfg(target_arch = "x86_64")]
+    pub pio_handlers: Vec<(u64, u64, Arc<dyn BusAccessHandler>)>,
+    /// Observer of the lifetime of the microVM.
+    pub events_observer: Option<Arc<Mutex<dyn VmmEventsObserver + Send>>>,
+    /// Clock source for the guest RTC.
+    pub rtc_config: RtcConfig,
+    /// `madvise` hint applied to the guest memory when the microVM is built.
+    pub memory_advice: Option<MemoryAdvice>,
+    /// Where the guest memory is allocated from.
+    pub memory_backend: MemoryBackend,
+    /// Source of the early-boot randomness handed to the guest.
+    pub boot_entropy: BootEntropy,
+    /// Whether to disable KASLR in the guest kernel.
+    pub disable_kaslr: bool,
+    /// Whether to boot without any serial port.
+    pub disable_serial: bool,
+    /// Host signals forwarded to the guest as graceful shutdown requests.
+    #[cfg(target_os = "linux")]
+    pub guest_signals: Vec<(libc::c_int, GuestSignalAction)>,
+    /// Exit code to stop the VM with when the guest halts with interrupts disabled.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub halt_exit_code: Option<u8>,
+    /// Guest physical ranges carved out of RAM, as `(start, size)` pairs.
+    pub reserved_memory: Vec<(u64, u64)>,
+    /// Time slice of each vCPU when they're serialized for reproducible runs.
+    #[cfg(target_os = "linux")]
+    pub vcpu_time_slice: Option<Duration>,
+    /// Whether the guest must be able to sync its clock to the host through `ptp_kvm`.
+    #[cfg(target_os = "linux")]
+    pub ptp_kvm: bool,
+    /// Ids to register devices with instead of their default one, keyed by the latter.
+    pub device_ids: HashMap<String, String>,
+    /// Guest memory regions mapped read-only.
+    #[cfg(target_os = "linux")]
+    pub readonly_regions: Vec<ReadOnlyRegionConfig>,
+    /// Whether the vCPUs are left paused once the microVM is built.
+    pub start_paused: bool,
+    /// How long to wait for the vCPUs to acknowledge events, if not the default.
+    pub vcpu_handshake_timeout: Option<Duration>,
+    /// What happens to the vCPU and virtio-fs threads when they make a system call outside of
+    /// their allowlist, if they're restricted at all.
+    #[cfg(target_os = "linux")]
+    pub seccomp_mode: SeccompMode,
+    /// Initial state of the balloon device.
+    #[cfg(not(feature = "tee"))]
+    pub balloon: BalloonConfig,
+    /// Configuration of the virtio-rng device.
+    #[cfg(not(feature = "tee"))]
+    pub rng: RngConfig,
+    /// Number of empty virtio slots declared to the guest for devices plugged in after boot.
+    #[cfg(target_os = "linux")]
+    pub hotplug_slots: usize,
 }
 
 impl VmResources {
@@ -126,11 +220,40 @@ impl VmResources {
         // supplied by the user.
         VcpuConfig {
             vcpu_count: self.vm_config().vcpu_count.unwrap(),
-            ht_enabled: self.vm_config().ht_enabled.unwrap(),
+            ht_enabled: self
+                .vm_config()
+                .cpu_topology
+                .map_or(self.vm_config().ht_enabled.unwrap(), |topology| {
+                    topology.threads_per_core > 1
+                }),
             cpu_template: self.vm_config().cpu_template,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            cpuid_overrides: self.vm_config().cpuid_overrides.clone().unwrap_or_default(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            nested: self.vm_config().nested.unwrap(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            tsc_khz: self.vm_config().tsc_khz,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            cpu_topology: self.vm_config().cpu_topology,
+            #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+            pmu: self.vm_config().pmu.unwrap(),
+            #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+            sve: self.vm_config().sve.unwrap(),
         }
     }
 
+    /// Returns how the vCPUs are grouped into sockets, cores and threads.
+    pub fn cpu_topology(&self) -> CpuTopology {
+        // The unwraps are ok to use because the values are initialized using defaults if not
+        // supplied by the user.
+        self.vm_config.cpu_topology.unwrap_or_else(|| {
+            CpuTopology::single_socket(
+                self.vm_config.vcpu_count.unwrap(),
+                self.vm_config.ht_enabled.unwrap(),
+            )
+        })
+    }
+
     /// Returns the VmConfig.
     pub fn vm_config(&self) -> &VmConfig {
         &self.vm_config
@@ -154,15 +277,54 @@ impl VmResources {
             .vcpu_count
             .unwrap_or_else(|| self.vm_config.vcpu_count.unwrap());
 
+        let cpu_topology = machine_config.cpu_topology.or(self.vm_config.cpu_topology);
+
         // If hyperthreading is enabled or is to be enabled in this call
-        // only allow vcpu count to be 1 or even.
-        if ht_enabled && vcpu_count_value > 1 && vcpu_count_value % 2 == 1 {
+        // only allow vcpu count to be 1 or even, unless the topology says how to pair them.
+        if cpu_topology.is_none() && ht_enabled && vcpu_count_value > 1 && vcpu_count_value % 2 == 1
+        {
             return Err(VmConfigError::InvalidVcpuCount);
         }
 
+        if let Some(topology) = cpu_topology {
+            // The guest finds the socket, core and thread of an x86_64 vCPU in the bits of its
+            // APIC ID, which is its index.
+            let is_power_of_two = cfg!(not(target_arch = "x86_64"))
+                || (topology.cores_per_socket.is_power_of_two()
+                    && topology.threads_per_core.is_power_of_two());
+            if topology.vcpu_count() != vcpu_count_value as usize || !is_power_of_two {
+                return Err(VmConfigError::InvalidCpuTopology(topology));
+            }
+        }
+
+        #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+        if machine_config.nested == Some(true) {
+            return Err(VmConfigError::NestedUnsupported);
+        }
+
+        #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+        if machine_config.tsc_khz.is_some() {
+            return Err(VmConfigError::InvalidTscFrequency);
+        }
+
+        if machine_config.tsc_khz == Some(0) {
+            return Err(VmConfigError::InvalidTscFrequency);
+        }
+
+        #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+        if machine_config.cpuid_overrides.is_some() {
+            return Err(VmConfigError::CpuidOverridesUnsupported);
+        }
+
+        #[cfg(not(all(target_os = "linux", target_arch = "aarch64")))]
+        if machine_config.pmu == Some(true) || machine_config.sve == Some(true) {
+            return Err(VmConfigError::ArmVcpuFeaturesUnsupported);
+        }
+
         // Update all the fields that have a new value.
         self.vm_config.vcpu_count = Some(vcpu_count_value);
         self.vm_config.ht_enabled = Some(ht_enabled);
+        self.vm_config.cpu_topology = cpu_topology;
 
         if machine_config.mem_size_mib.is_some() {
             self.vm_config.mem_size_mib = machine_config.mem_size_mib;
@@ -172,6 +334,30 @@ impl VmResources {
             self.vm_config.cpu_template = machine_config.cpu_template;
         }
 
+        if machine_config.cpuid_overrides.is_some() {
+            self.vm_config.cpuid_overrides = machine_config.cpuid_overrides.clone();
+        }
+
+        if machine_config.mem_init.is_some() {
+            self.vm_config.mem_init = machine_config.mem_init;
+        }
+
+        if machine_config.nested.is_some() {
+            self.vm_config.nested = machine_config.nested;
+        }
+
+        if machine_config.tsc_khz.is_some() {
+            self.vm_config.tsc_khz = machine_config.tsc_khz;
+        }
+
+        if machine_config.pmu.is_some() {
+            self.vm_config.pmu = machine_config.pmu;
+        }
+
+        if machine_config.sve.is_some() {
+            self.vm_config.sve = machine_config.sve;
+        }
+
         Ok(())
     }
 
@@ -184,6 +370,12 @@ impl VmResources {
         Ok(())
     }
 
+    /// Set the structured kernel command line parameters, replacing the matching ones
+    /// from the boot source prolog.
+    pub fn set_kernel_cmdline_args(&mut self, args: KernelCmdlineArgs) {
+        self.boot_config.kernel_cmdline_args = args;
+    }
+
     pub fn kernel_bundle(&self) -> Option<&KernelBundle> {
         self.kernel_bundle.as_ref()
     }
@@ -206,6 +398,52 @@ impl VmResources {
         }
 
         self.kernel_bundle = Some(kernel_bundle);
+        self.kernel_image = None;
+        Ok(())
+    }
+
+    /// Loads the kernel from `reader`, an ELF `vmlinux` or an arm64 `Image`, instead of using a
+    /// bundle. The image is copied to memory the VM keeps for as long as it runs.
+    pub fn set_kernel_reader<R: Read + Seek>(
+        &mut self,
+        mut reader: R,
+    ) -> Result<KernelBundleError> {
+        // Where the kernel expects to find the start of the RAM.
+        #[cfg(target_arch = "aarch64")]
+        let image_base = arch::aarch64::layout::DRAM_MEM_START;
+        #[cfg(target_arch = "x86_64")]
+        let image_base = 0;
+
+        let layout = kernel::loader::kernel_layout(&mut reader, image_base)
+            .map_err(KernelBundleError::InvalidImage)?;
+        // Safe because this call just returns the page size and doesn't have any side effects.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        let guest_addr = layout.start_addr & !(page_size - 1);
+        let size = (layout.end_addr - guest_addr).next_multiple_of(page_size) as usize;
+
+        let image = MmapRegion::new(size).map_err(KernelBundleError::Allocate)?;
+        // Safe because the region was just mapped, is `size` bytes long and nothing else
+        // references it yet.
+        let buf = unsafe { std::slice::from_raw_parts_mut(image.as_ptr(), size) };
+        kernel::loader::load_kernel(&mut reader, image_base, guest_addr, buf)
+            .map_err(KernelBundleError::InvalidImage)?;
+
+        self.set_kernel_bundle(KernelBundle {
+            host_addr: image.as_ptr() as u64,
+            guest_addr,
+            entry_addr: layout.entry_addr,
+            size,
+        })?;
+        self.kernel_image = Some(Arc::new(image));
+        Ok(())
+    }
+
+    /// Reads the initrd from `reader`, to be loaded in guest memory when the VM starts.
+    #[cfg(not(feature = "tee"))]
+    pub fn set_initrd_reader<R: Read>(&mut self, mut reader: R) -> Result<io::Error> {
+        let mut initrd = Vec::new();
+        reader.read_to_end(&mut initrd)?;
+        self.initrd = Some(initrd);
         Ok(())
     }
 
@@ -263,6 +501,260 @@ impl VmResources {
         self.console_output = Some(console_output);
     }
 
+    /// Captures the guest's serial console output, keeping up to `lines` complete lines for
+    /// `Vmm::read_console_line`. The guest still has to write to the serial port, e.g. by adding
+    /// `console=ttyS0` to the kernel command line.
+    pub fn set_serial_capture(&mut self, lines: usize) {
+        self.serial_capture_lines = Some(lines);
+    }
+
+    /// Gives the guest a `krun-channel` virtio-console port, a byte stream to the host apart
+    /// from the console, read and written with `Vmm::console_read` and `Vmm::console_write`.
+    /// Each direction buffers up to `capacity` bytes before the writer has to wait.
+    pub fn set_console_channel(&mut self, capacity: usize) {
+        self.console_channel_capacity = Some(capacity);
+    }
+
+    /// Gives the guest a pvpanic device, reporting its kernel panics to
+    /// `VmmEventsObserver::on_guest_panic`. The microVM is also stopped with
+    /// `FC_EXIT_CODE_GENERIC_ERROR` if `stop_on_panic` is set, otherwise it's left to the guest
+    /// to reboot or capture a crash dump.
+    ///
+    /// Only supported on aarch64, where the device is in the device tree. x86_64 guests only find
+    /// the one at port 0x505 through ACPI, which isn't emulated, so this fails there.
+    pub fn set_pvpanic(&mut self, stop_on_panic: bool) -> Result<Error> {
+        if cfg!(target_arch = "x86_64") {
+            return Err(Error::PvPanicUnsupported);
+        }
+        self.pvpanic_stop_on_panic = Some(stop_on_panic);
+        Ok(())
+    }
+
+    /// Sets an observer to be notified about guest accesses to addresses no device claims.
+    pub fn set_unhandled_access_observer(&mut self, observer: Arc<dyn UnhandledAccessObserver>) {
+        self.unhandled_access_observer = Some(observer);
+    }
+
+    /// Sets an observer to be notified about the lifetime of the microVM, see `VmmEventsObserver`.
+    pub fn set_events_observer(&mut self, observer: Arc<Mutex<dyn VmmEventsObserver + Send>>) {
+        self.events_observer = Some(observer);
+    }
+
+    /// Routes guest accesses to the `len` bytes of MMIO space at `base` to `handler`. The range
+    /// must not overlap the guest memory or the devices libkrun creates, otherwise building the
+    /// microVM fails.
+    ///
+    /// The handler is called on the vCPU thread making the access, so with a seccomp mode other
+    /// than `SeccompMode::Off` it's restricted to the system calls of the vCPU filter, see
+    /// `set_seccomp_mode`. Handlers needing others should hand the access off to a thread of
+    /// their own.
+    pub fn add_mmio_handler(&mut self, base: u64, len: u64, handler: Arc<dyn BusAccessHandler>) {
+        self.mmio_handlers.push((base, len, handler));
+    }
+
+    /// Routes guest accesses to the `len` I/O ports at `base` to `handler`. The handlers are
+    /// registered after the legacy devices libkrun creates (the serial ports, the i8042 and the
+    /// CMOS), so building the microVM fails if the range overlaps one of them rather than the
+    /// handler taking its place.
+    ///
+    /// As for `add_mmio_handler`, the handler is called on the vCPU threads.
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_pio_handler(&mut self, base: u64, len: u64, handler: Arc<dyn BusAccessHandler>) {
+        self.pio_handlers.push((base, len, handler));
+    }
+
+    /// Sets the initial state of the balloon device.
+    #[cfg(not(feature = "tee"))]
+    pub fn set_balloon_config(&mut self, config: BalloonConfig) -> Result<BalloonConfigError> {
+        balloon_target_pages(config.target_pages)?;
+        self.balloon = config;
+        Ok(())
+    }
+
+    /// Sets the configuration of the virtio-rng device.
+    #[cfg(not(feature = "tee"))]
+    pub fn set_rng_config(&mut self, config: RngConfig) {
+        self.rng = config;
+    }
+
+    /// Sets how many empty virtio slots are declared to the guest at boot. virtio-mmio has no
+    /// hotplug notification, so the devices plugged in with `Vmm::hot_plug_net` can only take
+    /// one of these slots.
+    #[cfg(target_os = "linux")]
+    pub fn set_hotplug_slots(&mut self, count: usize) {
+        self.hotplug_slots = count;
+    }
+
+    /// Sets the clock source the guest RTC is seeded from.
+    pub fn set_rtc_config(&mut self, rtc_config: RtcConfig) {
+        self.rtc_config = rtc_config;
+    }
+
+    /// Sets the `madvise` hint to apply to the guest memory when the microVM is built.
+    pub fn set_memory_advice(&mut self, advice: MemoryAdvice) {
+        self.memory_advice = Some(advice);
+    }
+
+    /// Sets where the guest memory is allocated from.
+    pub fn set_memory_backend(&mut self, memory_backend: MemoryBackend) {
+        self.memory_backend = memory_backend;
+    }
+
+    /// Sets the source of the early-boot randomness handed to the guest.
+    pub fn set_boot_entropy(&mut self, boot_entropy: BootEntropy) {
+        self.boot_entropy = boot_entropy;
+    }
+
+    /// Sets whether KASLR should be disabled in the guest kernel.
+    pub fn set_disable_kaslr(&mut self, disable_kaslr: bool) {
+        self.disable_kaslr = disable_kaslr;
+    }
+
+    /// Checks the configuration without building the microVM, see
+    /// `builder::validate_microvm`.
+    pub fn validate(&self) -> Result<Vec<crate::builder::ConfigError>> {
+        crate::builder::validate_microvm(self)
+    }
+
+    /// Sets whether the microVM boots without any serial port, not even the ones discarding the
+    /// guest output, saving their interrupts and MMIO slot. Incompatible with
+    /// `set_serial_capture`.
+    pub fn set_disable_serial(&mut self, disable_serial: bool) {
+        self.disable_serial = disable_serial;
+    }
+
+    /// Forwards `signum` to the guest as `action` instead of letting it affect the VMM. Refused on
+    /// aarch64, where the guest has neither a keyboard controller nor a power button.
+    #[cfg(target_os = "linux")]
+    pub fn set_guest_signal_action(
+        &mut self,
+        signum: libc::c_int,
+        action: GuestSignalAction,
+    ) -> Result<GuestSignalError> {
+        if !is_forwardable_signal(signum) {
+            return Err(GuestSignalError::InvalidSignal(signum));
+        }
+        if cfg!(target_arch = "aarch64") {
+            return Err(GuestSignalError::NoShutdownDevice);
+        }
+        self.guest_signals.retain(|(s, _)| *s != signum);
+        self.guest_signals.push((signum, action));
+        Ok(())
+    }
+
+    /// Stops the VM with `exit_code` when the boot vCPU executes `hlt` with interrupts disabled.
+    /// Guests that merely idle in `hlt` keep interrupts enabled and aren't affected. `None`
+    /// disables the check, which is the default.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn set_halt_exit_code(&mut self, exit_code: Option<u8>) {
+        self.halt_exit_code = exit_code;
+    }
+
+    /// Leaves the guest physical range `start..start + size` out of the guest RAM, e.g. to keep
+    /// room for the BARs of a passthrough device, and reports it to the guest as a hole in the
+    /// e820 map (x86_64) or the DT `/memory` node (aarch64). RAM the range overlaps is lost
+    /// rather than moved elsewhere. The range must be page aligned and can't overlap other
+    /// reserved ranges. Building the microVM fails if it covers memory the VMM populates itself,
+    /// such as the kernel or, on x86_64, the boot structures in the first MiB.
+    pub fn add_reserved_memory(&mut self, start: u64, size: u64) -> Result<VmConfigError> {
+        let page_mask = arch::PAGE_SIZE as u64 - 1;
+        let valid =
+            size != 0 && (start | size) & page_mask == 0 && start.checked_add(size).is_some();
+        if !valid
+            || self
+                .reserved_memory
+                .iter()
+                .any(|&(s, sz)| start < s + sz && s < start + size)
+        {
+            return Err(VmConfigError::InvalidReservedMemory(start, size));
+        }
+        self.reserved_memory.push((start, size));
+        self.reserved_memory.sort_unstable();
+        Ok(())
+    }
+
+    /// Runs the vCPUs one at a time, round-robin, each for `time_slice` before handing over to
+    /// the next one. This makes races in the guest much easier to reproduce from one run to the
+    /// next, but the VM only ever uses one host CPU. `None` lets the vCPUs run concurrently,
+    /// which is the default.
+    #[cfg(target_os = "linux")]
+    pub fn set_vcpu_time_slice(&mut self, time_slice: Option<Duration>) {
+        self.vcpu_time_slice = time_slice;
+    }
+
+    /// Requires the host to offer the KVM PTP clock, so the guest's `ptp_kvm` driver can expose
+    /// the host clock as `/dev/ptp0` for chrony or phc2sys to discipline the guest clock with,
+    /// e.g. after the host resumes from suspend. Building the VM fails if the host lacks it.
+    #[cfg(target_os = "linux")]
+    pub fn set_ptp_kvm(&mut self, enabled: bool) {
+        self.ptp_kvm = enabled;
+    }
+
+    /// Maps `region.contents` read-only in the guest at `region.guest_addr`, handling guest writes
+    /// to it according to `region.policy`. The region must be outside of the guest RAM, see
+    /// `add_reserved_memory` to make room for it there. Building the VM fails if it overlaps
+    /// the RAM, or an MMIO device with the `FaultToGuest` policy, or if KVM lacks read-only
+    /// memory or has no memory slot left for it.
+    #[cfg(target_os = "linux")]
+    pub fn add_readonly_region(
+        &mut self,
+        region: ReadOnlyRegionConfig,
+    ) -> Result<ReadOnlyRegionError> {
+        let start = region.guest_addr;
+        let size = region.size();
+        let valid = size != 0
+            && start & (arch::PAGE_SIZE as u64 - 1) == 0
+            && start.checked_add(size).is_some()
+            && !self
+                .readonly_regions
+                .iter()
+                .any(|r| start < r.guest_addr + r.size() && r.guest_addr < start + size);
+        if !valid {
+            return Err(ReadOnlyRegionError::InvalidRegion(start));
+        }
+        if cfg!(target_arch = "x86_64")
+            && matches!(region.policy, ReadOnlyWritePolicy::FaultToGuest)
+        {
+            return Err(ReadOnlyRegionError::UnsupportedPolicy(start));
+        }
+        self.readonly_regions.push(region);
+        Ok(())
+    }
+
+    /// Leaves the vCPUs paused once the microVM is built, so the guest doesn't run until
+    /// `Vmm::resume_vcpus` is called.
+    pub fn set_start_paused(&mut self, start_paused: bool) {
+        self.start_paused = start_paused;
+    }
+
+    /// Sets how long the VMM waits for the vCPUs to acknowledge events, such as being resumed,
+    /// see `Vmm::set_vcpu_handshake_timeout`.
+    pub fn set_vcpu_handshake_timeout(&mut self, timeout: Duration) {
+        self.vcpu_handshake_timeout = Some(timeout);
+    }
+
+    /// Restricts the vCPU and virtio-fs threads to the system calls they need, each kind of
+    /// thread with its own allowlist. With `SeccompMode::Kill` the process exits with
+    /// `FC_EXIT_CODE_BAD_SYSCALL` when one of them makes another one.
+    ///
+    /// The other threads aren't filtered: the ones of the block, net, vsock, console, balloon and
+    /// rng devices, as well as the thread running the event loop, can still make any system call.
+    /// The handlers added with `add_mmio_handler` and `add_pio_handler` are called on the vCPU
+    /// threads though, so they're bound by their filter.
+    #[cfg(target_os = "linux")]
+    pub fn set_seccomp_mode(&mut self, mode: SeccompMode) {
+        self.seccomp_mode = mode;
+    }
+
+    /// Registers the device that would get `default_id` (e.g. `eth0` for the network interface
+    /// with that id, or the `block_id` of a disk) as `id`, the one `Vmm::get_bus_device` then
+    /// finds it by, so it's the same whatever devices were configured before it. Building the VM
+    /// fails if two devices end up with the same id.
+    pub fn set_device_id(&mut self, default_id: &str, id: &str) {
+        self.device_ids
+            .insert(default_id.to_string(), id.to_string());
+    }
+
     /// Sets a network device to be attached when the VM starts.
     #[cfg(feature = "net")]
     pub fn add_network_interface(
@@ -291,6 +783,12 @@ impl VmResources {
             mem_size_mib: Some(tee_config.ram_mib),
             ht_enabled: Some(false),
             cpu_template: None,
+            cpuid_overrides: None,
+            mem_init: None,
+            nested: None,
+            tsc_khz: None,
+            pmu: None,
+            sve: None,
         })
         .map_err(Error::VmConfig)?;
 
@@ -303,16 +801,30 @@ impl VmResources {
 #[cfg(test)]
 mod tests {
     use crate::resources::VmResources;
+    #[cfg(not(feature = "tee"))]
+    use crate::vmm_config::balloon::BalloonConfig;
     use crate::vmm_config::boot_source::BootSourceConfig;
-    use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
+    use crate::vmm_config::kernel_bundle::KernelBundleError;
+    use crate::vmm_config::machine_config::{
+        CpuFeaturesTemplate, CpuTopology, CpuidOverride, CpuidRegister, MemoryBackend, MemoryInit,
+        VmConfig, VmConfigError,
+    };
+    #[cfg(not(feature = "tee"))]
+    use crate::vmm_config::rng::RngConfig;
+    use crate::vmm_config::rtc::RtcConfig;
     use crate::vmm_config::vsock::tests::{default_config, TempSockFile};
     use crate::vstate::VcpuConfig;
+    use arch::BootEntropy;
+    #[cfg(target_os = "linux")]
+    use utils::seccomp::SeccompMode;
     use utils::tempfile::TempFile;
 
     fn default_boot_cfg() -> BootSourceConfig {
         BootSourceConfig {
             kernel_cmdline_prolog: None,
             kernel_cmdline_epilog: None,
+            kernel_cmdline_args: Default::default(),
+            reserved_regions: Vec::new(),
         }
     }
 
@@ -321,6 +833,9 @@ mod tests {
             vm_config: VmConfig::default(),
             boot_config: default_boot_cfg(),
             kernel_bundle: Default::default(),
+            kernel_image: None,
+            #[cfg(not(feature = "tee"))]
+            initrd: None,
             fs: Default::default(),
             vsock: Default::default(),
             #[cfg(feature = "net")]
@@ -329,7 +844,43 @@ mod tests {
             #[cfg(feature = "snd")]
             enable_snd: False,
             console_output: None,
+            serial_capture_lines: None,
+            console_channel_capacity: None,
+            pvpanic_stop_on_panic: None,
             smbios_oem_strings: None,
+            unhandled_access_observer: None,
+            mmio_handlers: Vec::new(),
+            #[cfg(target_arch = "x86_64")]
+            pio_handlers: Vec::new(),
+            events_observer: None,
+            rtc_config: RtcConfig::default(),
+            memory_advice: None,
+            memory_backend: MemoryBackend::default(),
+            boot_entropy: BootEntropy::default(),
+            disable_kaslr: false,
+            disable_serial: false,
+            #[cfg(target_os = "linux")]
+            guest_signals: Vec::new(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            halt_exit_code: None,
+            reserved_memory: Vec::new(),
+            #[cfg(target_os = "linux")]
+            vcpu_time_slice: None,
+            #[cfg(target_os = "linux")]
+            ptp_kvm: false,
+            device_ids: Default::default(),
+            #[cfg(target_os = "linux")]
+            readonly_regions: Vec::new(),
+            start_paused: false,
+            vcpu_handshake_timeout: None,
+            #[cfg(target_os = "linux")]
+            seccomp_mode: SeccompMode::Off,
+            #[cfg(not(feature = "tee"))]
+            balloon: BalloonConfig::default(),
+            #[cfg(not(feature = "tee"))]
+            rng: RngConfig::default(),
+            #[cfg(target_os = "linux")]
+            hotplug_slots: 0,
         }
     }
 
@@ -340,6 +891,18 @@ mod tests {
             vcpu_count: vm_resources.vm_config().vcpu_count.unwrap(),
             ht_enabled: vm_resources.vm_config().ht_enabled.unwrap(),
             cpu_template: vm_resources.vm_config().cpu_template,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            cpuid_overrides: Vec::new(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            nested: vm_resources.vm_config().nested.unwrap(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            tsc_khz: vm_resources.vm_config().tsc_khz,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            cpu_topology: None,
+            #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+            pmu: false,
+            #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+            sve: false,
         };
 
         let vcpu_config = vm_resources.vcpu_config();
@@ -362,6 +925,14 @@ mod tests {
             mem_size_mib: Some(512),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpuid_overrides: cfg!(all(target_os = "linux", target_arch = "x86_64"))
+                .then(|| vec![CpuidOverride::clear_bit(7, CpuidRegister::Ebx, 5)]),
+            mem_init: Some(MemoryInit::Prefault),
+            nested: Some(cfg!(all(target_os = "linux", target_arch = "x86_64"))),
+            tsc_khz: None,
+            cpu_topology: None,
+            pmu: Some(cfg!(all(target_os = "linux", target_arch = "aarch64"))),
+            sve: Some(false),
         };
 
         assert_ne!(vm_resources.vm_config, aux_vm_config);
@@ -387,6 +958,84 @@ mod tests {
             vm_resources.set_vm_config(&aux_vm_config),
             Err(VmConfigError::InvalidMemorySize)
         );
+        aux_vm_config.mem_size_mib = Some(512);
+
+        // Invalid TSC frequency.
+        aux_vm_config.tsc_khz = Some(0);
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidTscFrequency)
+        );
+        aux_vm_config.tsc_khz = None;
+
+        // CPUID overrides only apply to x86_64 Linux guests.
+        #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+        {
+            aux_vm_config.cpuid_overrides =
+                Some(vec![CpuidOverride::clear_bit(7, CpuidRegister::Ebx, 5)]);
+            assert_eq!(
+                vm_resources.set_vm_config(&aux_vm_config),
+                Err(VmConfigError::CpuidOverridesUnsupported)
+            );
+            aux_vm_config.cpuid_overrides = None;
+        }
+
+        // The guest PMU and SVE only apply to aarch64 Linux guests.
+        #[cfg(not(all(target_os = "linux", target_arch = "aarch64")))]
+        {
+            aux_vm_config.sve = Some(true);
+            assert_eq!(
+                vm_resources.set_vm_config(&aux_vm_config),
+                Err(VmConfigError::ArmVcpuFeaturesUnsupported)
+            );
+            aux_vm_config.sve = Some(false);
+        }
+
+        // The topology must account for every vCPU.
+        assert_eq!(
+            vm_resources.cpu_topology(),
+            CpuTopology::single_socket(32, true)
+        );
+        let mut topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 4,
+            threads_per_core: 2,
+        };
+        aux_vm_config.cpu_topology = Some(topology);
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidCpuTopology(topology))
+        );
+        topology.sockets = 4;
+        aux_vm_config.cpu_topology = Some(topology);
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.cpu_topology(), topology);
+
+        // The topology is kept until it's replaced, so the vCPU count must keep matching it.
+        let vcpu_count_only = VmConfig {
+            vcpu_count: Some(16),
+            ..Default::default()
+        };
+        assert_eq!(
+            vm_resources.set_vm_config(&vcpu_count_only),
+            Err(VmConfigError::InvalidCpuTopology(topology))
+        );
+
+        // x86_64 guests can only find their topology in power of 2 numbers of cores and threads.
+        let topology = CpuTopology {
+            sockets: 1,
+            cores_per_socket: 3,
+            threads_per_core: 1,
+        };
+        let odd_cores = VmConfig {
+            vcpu_count: Some(3),
+            cpu_topology: Some(topology),
+            ..Default::default()
+        };
+        assert_eq!(
+            vm_resources.set_vm_config(&odd_cores).is_ok(),
+            cfg!(not(target_arch = "x86_64"))
+        );
     }
 
     #[test]
@@ -404,4 +1053,170 @@ mod tests {
             &new_vsock_cfg.vsock_id
         );
     }
+
+    #[test]
+    fn test_set_pvpanic() {
+        let mut vm_resources = default_vm_resources();
+        let res = vm_resources.set_pvpanic(true);
+        if cfg!(target_arch = "x86_64") {
+            assert!(matches!(res, Err(super::Error::PvPanicUnsupported)));
+            assert_eq!(vm_resources.pvpanic_stop_on_panic, None);
+        } else {
+            res.unwrap();
+            assert_eq!(vm_resources.pvpanic_stop_on_panic, Some(true));
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_set_guest_signal_action() {
+        use crate::signal_handler::{GuestSignalAction, GuestSignalError};
+
+        let mut vm_resources = default_vm_resources();
+        if cfg!(target_arch = "aarch64") {
+            assert_eq!(
+                vm_resources.set_guest_signal_action(libc::SIGTERM, GuestSignalAction::PowerButton),
+                Err(GuestSignalError::NoShutdownDevice)
+            );
+            return;
+        }
+
+        vm_resources
+            .set_guest_signal_action(libc::SIGTERM, GuestSignalAction::PowerButton)
+            .unwrap();
+        vm_resources
+            .set_guest_signal_action(libc::SIGINT, GuestSignalAction::CtrlAltDel)
+            .unwrap();
+        // A new mapping for the same signal replaces the old one.
+        vm_resources
+            .set_guest_signal_action(libc::SIGTERM, GuestSignalAction::CtrlAltDel)
+            .unwrap();
+        assert_eq!(
+            vm_resources.guest_signals,
+            vec![
+                (libc::SIGINT, GuestSignalAction::CtrlAltDel),
+                (libc::SIGTERM, GuestSignalAction::CtrlAltDel),
+            ]
+        );
+
+        for signum in [
+            0,
+            libc::SIGKILL,
+            libc::SIGSEGV,
+            libc::SIGWINCH,
+            utils::signal::sigrtmin(),
+        ] {
+            assert_eq!(
+                vm_resources.set_guest_signal_action(signum, GuestSignalAction::PowerButton),
+                Err(GuestSignalError::InvalidSignal(signum))
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_reserved_memory() {
+        let mut vm_resources = default_vm_resources();
+        vm_resources
+            .add_reserved_memory(0x2000_0000, 0x1000_0000)
+            .unwrap();
+        vm_resources
+            .add_reserved_memory(0x1000_0000, 0x1000)
+            .unwrap();
+        assert_eq!(
+            vm_resources.reserved_memory,
+            vec![(0x1000_0000, 0x1000), (0x2000_0000, 0x1000_0000)]
+        );
+
+        for (start, size) in [
+            (0x4000_0000, 0),
+            (0x4000_0800, 0x1000),
+            (0x4000_0000, 0x800),
+            (!0xfff, 0x2000),
+            (0x2fff_f000, 0x2000),
+        ] {
+            assert_eq!(
+                vm_resources.add_reserved_memory(start, size),
+                Err(VmConfigError::InvalidReservedMemory(start, size))
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_kernel_reader() {
+        let mut vm_resources = default_vm_resources();
+
+        // An arm64 Image, which both architectures know how to lay out.
+        let mut image = vec![0u8; 0x40];
+        image[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        vm_resources
+            .set_kernel_reader(std::io::Cursor::new(&image))
+            .unwrap();
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+        let bundle = vm_resources.kernel_bundle().unwrap();
+        assert_eq!(bundle.guest_addr, bundle.entry_addr);
+        assert_eq!(bundle.size, page_size);
+        let image_addr = vm_resources.kernel_image.as_ref().unwrap().as_ptr();
+        assert_eq!(bundle.host_addr, image_addr as u64);
+        let loaded = unsafe { std::slice::from_raw_parts(image_addr, image.len()) };
+        assert_eq!(loaded, &image[..]);
+
+        assert!(matches!(
+            vm_resources.set_kernel_reader(std::io::Cursor::new(vec![0u8; 0x40])),
+            Err(KernelBundleError::InvalidImage(
+                kernel::loader::Error::UnknownKernelFormat
+            ))
+        ));
+
+        #[cfg(not(feature = "tee"))]
+        {
+            vm_resources.set_initrd_reader(&b"initrd"[..]).unwrap();
+            assert_eq!(vm_resources.initrd.as_deref(), Some(&b"initrd"[..]));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_add_readonly_region() {
+        use crate::vmm_config::readonly_memory::{
+            ReadOnlyRegionConfig, ReadOnlyRegionError, ReadOnlyWritePolicy,
+        };
+
+        let region = |guest_addr, len, policy| ReadOnlyRegionConfig {
+            guest_addr,
+            contents: vec![0xaa; len],
+            policy,
+        };
+        let mut vm_resources = default_vm_resources();
+        vm_resources
+            .add_readonly_region(region(
+                0x1000_0000,
+                0x1800,
+                ReadOnlyWritePolicy::IgnoreWrites,
+            ))
+            .unwrap();
+
+        for (guest_addr, len) in [(0x2000_0000, 0), (0x2000_0800, 0x1000), (0x1000_1000, 1)] {
+            assert_eq!(
+                vm_resources.add_readonly_region(region(
+                    guest_addr,
+                    len,
+                    ReadOnlyWritePolicy::IgnoreWrites
+                )),
+                Err(ReadOnlyRegionError::InvalidRegion(guest_addr))
+            );
+        }
+
+        let res = vm_resources.add_readonly_region(region(
+            0x1000_2000,
+            1,
+            ReadOnlyWritePolicy::FaultToGuest,
+        ));
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            res,
+            Err(ReadOnlyRegionError::UnsupportedPolicy(0x1000_2000))
+        );
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(res, Ok(()));
+    }
 }
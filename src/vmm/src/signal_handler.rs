@@ -1,11 +1,16 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fmt::{self, Write};
 use std::os::unix::io::RawFd;
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicUsize, Ordering};
 
-use libc::{_exit, c_int, c_void, siginfo_t, SIGBUS, SIGINT, SIGSEGV, SIGSYS, SIGWINCH};
-use utils::signal::register_signal_handler;
+use libc::{
+    _exit, c_int, c_void, siginfo_t, EINVAL, SIGBUS, SIGINT, SIGKILL, SIGSEGV, SIGSTOP, SIGSYS,
+    SIGWINCH, STDERR_FILENO,
+};
+use utils::signal::{register_signal_handler, sigrtmin};
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 // The offset of `si_syscall` (offending syscall identifier) within the siginfo structure
 // expressed as an `(u)int*`.
@@ -18,6 +23,86 @@ const SYS_SECCOMP_CODE: i32 = 1;
 
 static CONSOLE_SIGWINCH_FD: AtomicI32 = AtomicI32::new(-1);
 static CONSOLE_SIGINT_FD: AtomicI32 = AtomicI32::new(-1);
+static GUEST_SIGNAL_FD: AtomicI32 = AtomicI32::new(-1);
+// Bitmask of the forwarded signals received since the last `take_guest_signals` call.
+static PENDING_GUEST_SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+// Number of guest memory regions the faulting addresses are translated against.
+const MAX_GUEST_MEMORY_REGIONS: usize = 16;
+// Host address, size and guest physical address of the guest memory regions, copied out of the
+// `GuestMemoryMmap` since the handlers can't take a lock to look at it.
+static GUEST_MEMORY_REGIONS: [[AtomicU64; 3]; MAX_GUEST_MEMORY_REGIONS] =
+    [const { [const { AtomicU64::new(0) }; 3] }; MAX_GUEST_MEMORY_REGIONS];
+static GUEST_MEMORY_REGION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Formats a message into a fixed size buffer, truncating it, as the handlers can't allocate.
+struct MessageBuffer {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl MessageBuffer {
+    fn new() -> Self {
+        MessageBuffer {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Guest events a host signal can be translated into.
+///
+/// Only x86_64 guests can currently receive them, aarch64 guests on Linux hosts have neither
+/// a keyboard controller nor a power button.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GuestSignalAction {
+    /// Press the power button. On x86_64, which has no power button device, the guest gets a
+    /// CTRL+ALT+DEL instead.
+    PowerButton,
+    /// Send CTRL+ALT+DEL.
+    CtrlAltDel,
+}
+
+/// Errors associated with forwarding host signals to the guest.
+#[derive(Debug, Eq, PartialEq)]
+pub enum GuestSignalError {
+    /// The signal can't be caught, or is reserved by the VMM.
+    InvalidSignal(c_int),
+    /// The guest has no device to deliver the actions to, as with KVM on aarch64.
+    NoShutdownDevice,
+}
+
+impl fmt::Display for GuestSignalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::GuestSignalError::*;
+        match *self {
+            InvalidSignal(signum) => write!(f, "Signal {signum} can't be forwarded to the guest"),
+            NoShutdownDevice => write!(f, "The guest has no graceful shutdown device"),
+        }
+    }
+}
+
+impl fmt::Display for GuestSignalAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuestSignalAction::PowerButton => write!(f, "power button"),
+            GuestSignalAction::CtrlAltDel => write!(f, "CTRL+ALT+DEL"),
+        }
+    }
+}
 
 /// Signal handler for `SIGSYS`.
 ///
@@ -41,21 +126,20 @@ extern "C" fn sigsys_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_v
         "Shutting down VM after intercepting a bad syscall ({}).",
         syscall
     );
-    // Safe because we're terminating the process anyway. We don't actually do anything when
-    // running unit tests.
-    #[cfg(not(test))]
-    unsafe {
-        _exit(i32::from(super::FC_EXIT_CODE_BAD_SYSCALL))
-    };
+    // Safe because we're terminating the process anyway.
+    unsafe { _exit(i32::from(super::FC_EXIT_CODE_BAD_SYSCALL)) };
 }
 
 /// Signal handler for `SIGBUS` and `SIGSEGV`.
 ///
-/// Logs an error message and terminates the process with a specific exit code.
+/// Writes an error message with the faulting address, translated to a guest physical address
+/// when it's in the guest memory, to stderr and terminates the process with a specific exit
+/// code.
 extern "C" fn sigbus_sigsegv_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
     // Safe because we're just reading some fields from a supposedly valid argument.
     let si_signo = unsafe { (*info).si_signo };
     let si_code = unsafe { (*info).si_code };
+    let si_addr = unsafe { (*info).si_addr() } as u64;
 
     // Sanity check. The condition should never be true.
     if num != si_signo || (num != SIGBUS && num != SIGSEGV) {
@@ -63,10 +147,23 @@ extern "C" fn sigbus_sigsegv_handler(num: c_int, info: *mut siginfo_t, _unused:
         unsafe { _exit(i32::from(super::FC_EXIT_CODE_UNEXPECTED_ERROR)) };
     }
 
-    error!(
-        "Shutting down VM after intercepting signal {}, code {}.",
-        si_signo, si_code
-    );
+    // The logger may allocate or be locked by the faulting thread, so the message is written
+    // to stderr directly.
+    let mut msg = MessageBuffer::new();
+    let _ = match guest_physical_address(si_addr) {
+        Some(gpa) => writeln!(
+            msg,
+            "Shutting down VM after intercepting signal {si_signo}, code {si_code}, at guest \
+             physical address {gpa:#x} (host address {si_addr:#x})."
+        ),
+        None => writeln!(
+            msg,
+            "Shutting down VM after intercepting signal {si_signo}, code {si_code}, at host \
+             address {si_addr:#x}, outside of the guest memory."
+        ),
+    };
+    let bytes = msg.as_bytes();
+    let _ = unsafe { libc::write(STDERR_FILENO, bytes.as_ptr() as *const c_void, bytes.len()) };
 
     // Safe because we're terminating the process anyway. We don't actually do anything when
     // running unit tests.
@@ -110,6 +207,22 @@ extern "C" fn sigint_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_v
     let _ = unsafe { libc::write(console_fd, &val as *const _ as *const c_void, 8) };
 }
 
+extern "C" fn guest_signal_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
+    // Safe because we're just reading some fields from a supposedly valid argument.
+    let si_signo = unsafe { (*info).si_signo };
+
+    // Sanity check. The condition should never be true.
+    if num != si_signo || !(1..64).contains(&num) {
+        // Safe because we're terminating the process anyway.
+        unsafe { _exit(i32::from(super::FC_EXIT_CODE_UNEXPECTED_ERROR)) };
+    }
+
+    PENDING_GUEST_SIGNALS.fetch_or(1 << num, Ordering::SeqCst);
+    let val: u64 = 1;
+    let guest_signal_fd = GUEST_SIGNAL_FD.load(Ordering::Relaxed);
+    let _ = unsafe { libc::write(guest_signal_fd, &val as *const _ as *const c_void, 8) };
+}
+
 pub fn register_sigwinch_handler(console_fd: RawFd) -> utils::errno::Result<()> {
     CONSOLE_SIGWINCH_FD.store(console_fd, Ordering::Relaxed);
 
@@ -126,6 +239,81 @@ pub fn register_sigint_handler(sigint_fd: RawFd) -> utils::errno::Result<()> {
     Ok(())
 }
 
+/// Returns whether `signum` can be forwarded to the guest. Signals that can't be caught, that
+/// are already handled by `register_signal_handlers` or by the console (`SIGWINCH`), or
+/// real-time ones, which are used to kick the vCPUs, can't.
+pub fn is_forwardable_signal(signum: c_int) -> bool {
+    (1..sigrtmin()).contains(&signum)
+        && ![SIGKILL, SIGSTOP, SIGBUS, SIGSEGV, SIGSYS, SIGWINCH].contains(&signum)
+}
+
+/// Installs handlers for `signals` that flag them as pending and write to `guest_signal_fd`,
+/// so the VMM can translate them into guest events. Replaces any handler previously installed
+/// for these signals, including the console SIGINT one.
+pub fn register_guest_signal_handlers(
+    guest_signal_fd: RawFd,
+    signals: &[c_int],
+) -> utils::errno::Result<()> {
+    if let Some(&signum) = signals.iter().find(|&&s| !is_forwardable_signal(s)) {
+        error!("Signal {signum} can't be forwarded to the guest");
+        return Err(utils::errno::Error::new(EINVAL));
+    }
+
+    GUEST_SIGNAL_FD.store(guest_signal_fd, Ordering::Relaxed);
+    for &signum in signals {
+        register_signal_handler(signum, guest_signal_handler)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the forwarded signals received since the last call, in ascending order.
+pub fn take_guest_signals() -> Vec<c_int> {
+    let pending = PENDING_GUEST_SIGNALS.swap(0, Ordering::SeqCst);
+    (1..64)
+        .filter(|signum| pending & (1 << signum) != 0)
+        .collect()
+}
+
+/// Records the guest memory regions the `SIGBUS` and `SIGSEGV` handlers translate the faulting
+/// addresses against. Only the first `MAX_GUEST_MEMORY_REGIONS` regions are recorded.
+pub fn set_guest_memory_regions(guest_memory: &GuestMemoryMmap) {
+    // Hide the regions from the handlers while they're being updated.
+    GUEST_MEMORY_REGION_COUNT.store(0, Ordering::SeqCst);
+    let mut count = 0;
+    for (region, slot) in guest_memory.iter().zip(GUEST_MEMORY_REGIONS.iter()) {
+        slot[0].store(region.as_ptr() as u64, Ordering::SeqCst);
+        slot[1].store(region.len(), Ordering::SeqCst);
+        slot[2].store(region.start_addr().0, Ordering::SeqCst);
+        count += 1;
+    }
+    GUEST_MEMORY_REGION_COUNT.store(count, Ordering::SeqCst);
+}
+
+// Translates `host_addr` to a guest physical address, if it's in one of the guest memory
+// regions recorded by `set_guest_memory_regions`.
+fn guest_physical_address(host_addr: u64) -> Option<u64> {
+    let count = GUEST_MEMORY_REGION_COUNT.load(Ordering::SeqCst);
+    GUEST_MEMORY_REGIONS[..count].iter().find_map(|slot| {
+        let offset = host_addr.checked_sub(slot[0].load(Ordering::SeqCst))?;
+        (offset < slot[1].load(Ordering::SeqCst)).then(|| slot[2].load(Ordering::SeqCst) + offset)
+    })
+}
+
+/// Installs the `SIGBUS` handler, reporting the faulting address against `guest_memory` before
+/// terminating the process with `FC_EXIT_CODE_SIGBUS`, e.g. when the guest touches a page of
+/// a file backing its memory past the end of the file.
+pub fn register_sigbus_handler(guest_memory: &GuestMemoryMmap) -> utils::errno::Result<()> {
+    set_guest_memory_regions(guest_memory);
+    register_signal_handler(SIGBUS, sigbus_sigsegv_handler)
+}
+
+/// Installs the `SIGSYS` handler, terminating the process with `FC_EXIT_CODE_BAD_SYSCALL` when
+/// a thread makes a system call its seccomp filter denies.
+pub fn register_sigsys_handler() -> utils::errno::Result<()> {
+    register_signal_handler(SIGSYS, sigsys_handler)
+}
+
 /// Registers all the required signal handlers.
 ///
 /// Custom handlers are installed for: `SIGBUS`, `SIGSEGV`, `SIGSYS`.
@@ -140,3 +328,35 @@ pub fn register_signal_handlers() -> utils::errno::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_guest_physical_address() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0x1000), 0x1000),
+            (GuestAddress(0x10000), 0x2000),
+        ])
+        .unwrap();
+        set_guest_memory_regions(&guest_memory);
+
+        let second = guest_memory.find_region(GuestAddress(0x10000)).unwrap();
+        let host_addr = second.as_ptr() as u64;
+        assert_eq!(guest_physical_address(host_addr + 0x10), Some(0x10010));
+        assert_eq!(
+            guest_physical_address(host_addr + 0x2000 - 1),
+            Some(0x11fff)
+        );
+        // The mappings may be next to each other, so go past the end of the first region one.
+        let first = guest_memory.find_region(GuestAddress(0x1000)).unwrap();
+        let end = first.as_ptr() as u64 + 0x1000;
+        assert_eq!(
+            guest_physical_address(end),
+            (end == host_addr).then_some(0x10000)
+        );
+        assert_eq!(guest_physical_address(0), None);
+    }
+}
@@ -0,0 +1,949 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshots of a paused microVM, as written by `Vmm::snapshot` and restored by
+//! `builder::build_microvm_from_snapshot`.
+//!
+//! The guest RAM is written to a file of its own, in the format of `Vmm::dump_guest_memory`. The
+//! state file starts with `SNAPSHOT_MAGIC` and `SNAPSHOT_VERSION`, then holds, in order:
+//!
+//! 1. the CPUID entries KVM supports on the host, the snapshot is only restored on a host
+//!    supporting the same ones;
+//! 2. the kernel command line and the `ArchMemoryInfo` of the microVM, which must match the ones
+//!    of the microVM built to restore it;
+//! 3. the KVM state of the VM, i.e. its PIT, clock, PICs and IOAPIC, then the one of each vCPU;
+//! 4. the state of each virtio device, by type and id: the registers of its MMIO transport, its
+//!    queues and the state specific to its type.
+//!
+//! Integers are little endian. Byte strings are prefixed with their length as a `u64`, lists with
+//! their number of items as a `u32`. KVM structures are copied as they are, a snapshot is only
+//! meant to be restored by the same build of the VMM.
+//!
+//! Only single vCPU microVMs can be snapshotted for now. Of the virtio devices, the console,
+//! fs, balloon and rng ones can be snapshotted once the guest activated them, see their
+//! `VirtioDevice::snapshot` for what's left out. Any other device makes `Vmm::snapshot` fail if
+//! the guest activated it. The legacy devices, e.g. the serial ports and the CMOS, aren't saved
+//! and start over from their reset state.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::mem::{size_of, MaybeUninit};
+use std::path::Path;
+
+use arch::{ArchMemoryInfo, DeviceType};
+use devices::virtio::{DeviceSnapshot, MmioTransportState, QueueState};
+use kvm_bindings::{
+    kvm_clock_data, kvm_cpuid_entry2, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state,
+    kvm_msr_entry, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+    CpuId, Msrs,
+};
+
+use crate::vstate::{self, Vcpu, VcpuKvmState, VmState};
+use crate::Vmm;
+
+/// Magic the state file of a snapshot starts with.
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"KRUNSNAP";
+/// Version of the snapshot format, bumped on incompatible changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Errors taking or restoring a snapshot.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot read or write the snapshot files.
+    Io(io::Error),
+    /// The state file isn't a snapshot of a supported version.
+    InvalidHeader,
+    /// The state file is truncated or corrupted.
+    Malformed,
+    /// Only microVMs with a single vCPU can be snapshotted, this one has that many.
+    VcpuCount(usize),
+    /// Saving the state of the vCPU with this index failed.
+    SaveVcpu(usize),
+    /// The guest activated the device with this id, which can't be snapshotted.
+    UnsupportedDevice(String),
+    /// The host supports other CPU features than the one the snapshot was taken on.
+    CpuidMismatch,
+    /// The microVM has another kernel command line or memory layout than the snapshotted one.
+    ConfigMismatch,
+    /// The device with this id is only in the snapshot or only in the microVM.
+    DeviceMismatch(String),
+    /// Restoring the state of the device with this id failed.
+    RestoreDevice(String),
+    /// Saving or restoring the KVM state failed.
+    Kvm(vstate::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Io(e) => write!(f, "Cannot access the snapshot: {e}"),
+            InvalidHeader => write!(f, "Not a snapshot of a supported version"),
+            Malformed => write!(f, "The snapshot is truncated or corrupted"),
+            VcpuCount(count) => write!(
+                f,
+                "Only microVMs with a single vCPU can be snapshotted, not {count}"
+            ),
+            SaveVcpu(id) => write!(f, "Cannot save the state of vCPU {id}"),
+            UnsupportedDevice(id) => write!(f, "The device {id} can't be snapshotted"),
+            CpuidMismatch => write!(
+                f,
+                "The snapshot was taken on a host with other CPU features"
+            ),
+            ConfigMismatch => write!(
+                f,
+                "The snapshot was taken with another kernel command line or memory layout"
+            ),
+            DeviceMismatch(id) => write!(
+                f,
+                "The device {id} isn't both in the snapshot and in the microVM"
+            ),
+            RestoreDevice(id) => write!(f, "Cannot restore the state of the device {id}"),
+            Kvm(e) => write!(f, "Cannot save or restore the KVM state: {e}"),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// State of a virtio device in a snapshot.
+pub(crate) struct DeviceState {
+    pub device_type: u32,
+    pub id: String,
+    pub transport: MmioTransportState,
+}
+
+/// Contents of the state file of a snapshot.
+pub(crate) struct MicrovmState {
+    pub host_cpuid: Vec<kvm_cpuid_entry2>,
+    pub cmdline: String,
+    pub memory_info: ArchMemoryInfo,
+    pub vm: VmState,
+    /// The KVM state of each vCPU, as encoded by `encode_vcpu_state`.
+    pub vcpus: Vec<Vec<u8>>,
+    pub devices: Vec<DeviceState>,
+}
+
+impl MicrovmState {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder(SNAPSHOT_MAGIC.to_vec());
+        e.u32(SNAPSHOT_VERSION);
+        e.pod_list(&self.host_cpuid);
+        e.bytes(self.cmdline.as_bytes());
+        encode_memory_info(&mut e, &self.memory_info);
+        e.pod(&self.vm.pitstate);
+        e.pod(&self.vm.clock);
+        e.pod(&self.vm.pic_master);
+        e.pod(&self.vm.pic_slave);
+        e.pod(&self.vm.ioapic);
+        e.u32(self.vcpus.len() as u32);
+        for vcpu in &self.vcpus {
+            e.bytes(vcpu);
+        }
+        e.u32(self.devices.len() as u32);
+        for device in &self.devices {
+            e.u32(device.device_type);
+            e.bytes(device.id.as_bytes());
+            encode_transport(&mut e, &device.transport);
+        }
+        e.0
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < SNAPSHOT_MAGIC.len() + 4
+            || data[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC[..]
+        {
+            return Err(Error::InvalidHeader);
+        }
+        let mut d = Decoder(&data[SNAPSHOT_MAGIC.len()..]);
+        if d.u32()? != SNAPSHOT_VERSION {
+            return Err(Error::InvalidHeader);
+        }
+        let host_cpuid = d.pod_list()?;
+        let cmdline = d.string()?;
+        let memory_info = decode_memory_info(&mut d)?;
+        let vm = VmState {
+            pitstate: d.pod()?,
+            clock: d.pod()?,
+            pic_master: d.pod()?,
+            pic_slave: d.pod()?,
+            ioapic: d.pod()?,
+        };
+        let vcpus = (0..d.u32()?)
+            .map(|_| d.bytes().map(<[u8]>::to_vec))
+            .collect::<Result<_>>()?;
+        let devices = (0..d.u32()?)
+            .map(|_| {
+                Ok(DeviceState {
+                    device_type: d.u32()?,
+                    id: d.string()?,
+                    transport: decode_transport(&mut d)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        d.finish()?;
+        Ok(MicrovmState {
+            host_cpuid,
+            cmdline,
+            memory_info,
+            vm,
+            vcpus,
+            devices,
+        })
+    }
+}
+
+/// Encodes the KVM state of a vCPU for a snapshot.
+pub(crate) fn encode_vcpu_state(state: &VcpuKvmState) -> Vec<u8> {
+    let mut e = Encoder(Vec::new());
+    e.pod_list(state.cpuid.as_slice());
+    e.pod_list(state.msrs.as_slice());
+    e.pod(&state.debug_regs);
+    e.pod(&state.lapic);
+    e.pod(&state.mp_state);
+    match &state.nested_state {
+        Some(nested_state) => {
+            e.bool(true);
+            e.bytes(nested_state);
+        }
+        None => e.bool(false),
+    }
+    e.pod(&state.regs);
+    e.pod(&state.sregs);
+    e.pod(&state.vcpu_events);
+    e.pod(&state.xcrs);
+    e.pod(&state.xsave);
+    e.0
+}
+
+/// Decodes the KVM state of a vCPU encoded by `encode_vcpu_state`.
+pub(crate) fn decode_vcpu_state(data: &[u8]) -> Result<VcpuKvmState> {
+    let mut d = Decoder(data);
+    let cpuid_entries: Vec<kvm_cpuid_entry2> = d.pod_list()?;
+    let msr_entries: Vec<kvm_msr_entry> = d.pod_list()?;
+    let state = VcpuKvmState {
+        cpuid: CpuId::from_entries(&cpuid_entries).map_err(|_| Error::Malformed)?,
+        msrs: Msrs::from_entries(&msr_entries).map_err(|_| Error::Malformed)?,
+        debug_regs: d.pod()?,
+        lapic: d.pod()?,
+        mp_state: d.pod()?,
+        nested_state: if d.bool()? {
+            Some(d.bytes()?.to_vec())
+        } else {
+            None
+        },
+        regs: d.pod()?,
+        sregs: d.pod()?,
+        vcpu_events: d.pod()?,
+        xcrs: d.pod()?,
+        xsave: d.pod()?,
+    };
+    d.finish()?;
+    Ok(state)
+}
+
+/// Restores the snapshot at `state_path` and `mem_path` into a microVM that was just built, before
+/// its vCPUs are started.
+pub(crate) fn restore(
+    vmm: &mut Vmm,
+    vcpus: &[Vcpu],
+    state_path: &Path,
+    mem_path: &Path,
+) -> Result<()> {
+    let state = MicrovmState::decode(&std::fs::read(state_path).map_err(Error::Io)?)?;
+
+    if state.host_cpuid != vmm.vm.supported_cpuid().as_slice() {
+        return Err(Error::CpuidMismatch);
+    }
+    if state.cmdline != vmm.kernel_cmdline.as_str() || state.memory_info != vmm.arch_memory_info {
+        return Err(Error::ConfigMismatch);
+    }
+    if state.vcpus.len() != vcpus.len() {
+        return Err(Error::VcpuCount(vcpus.len()));
+    }
+    let virtio_devices = vmm.mmio_device_manager.virtio_devices();
+    if let Some((_, id)) = virtio_devices
+        .keys()
+        .find(|(_, id)| !state.devices.iter().any(|device| device.id == *id))
+    {
+        return Err(Error::DeviceMismatch(id.clone()));
+    }
+
+    let mut mem_file = BufReader::new(File::open(mem_path).map_err(Error::Io)?);
+    crate::load_guest_memory(&vmm.guest_memory, &vmm.arch_memory_info, &mut mem_file)
+        .map_err(Error::Io)?;
+
+    vmm.vm.restore_state(&state.vm).map_err(Error::Kvm)?;
+    for (vcpu, vcpu_state) in vcpus.iter().zip(&state.vcpus) {
+        vcpu.restore_state(decode_vcpu_state(vcpu_state)?)
+            .map_err(Error::Kvm)?;
+    }
+
+    for device in &state.devices {
+        let transport = virtio_devices
+            .get(&(DeviceType::Virtio(device.device_type), device.id.clone()))
+            .ok_or_else(|| Error::DeviceMismatch(device.id.clone()))?;
+        transport
+            .lock()
+            .unwrap()
+            .restore_state(&device.transport)
+            .map_err(|_| Error::RestoreDevice(device.id.clone()))?;
+    }
+    Ok(())
+}
+
+fn encode_memory_info(e: &mut Encoder, info: &ArchMemoryInfo) {
+    e.u64(info.ram_last_addr);
+    e.u64(info.shm_start_addr);
+    e.u64(info.shm_size);
+    e.ranges(&info.reserved_ranges);
+    e.ranges(&info.firmware_ranges);
+}
+
+fn decode_memory_info(d: &mut Decoder) -> Result<ArchMemoryInfo> {
+    Ok(ArchMemoryInfo {
+        ram_last_addr: d.u64()?,
+        shm_start_addr: d.u64()?,
+        shm_size: d.u64()?,
+        reserved_ranges: d.ranges()?,
+        firmware_ranges: d.ranges()?,
+    })
+}
+
+fn encode_transport(e: &mut Encoder, state: &MmioTransportState) {
+    e.u32(state.features_select);
+    e.u32(state.acked_features_select);
+    e.u32(state.queue_select);
+    e.u32(state.device_status);
+    e.u32(state.config_generation);
+    e.u32(state.shm_region_select);
+    e.u32(state.interrupt_status);
+    e.u64(state.acked_features);
+    e.u32(state.device.queues.len() as u32);
+    for queue in &state.device.queues {
+        e.u32(u32::from(queue.size));
+        e.bool(queue.ready);
+        e.u64(queue.desc_table);
+        e.u64(queue.avail_ring);
+        e.u64(queue.used_ring);
+        e.u32(u32::from(queue.next_avail));
+        e.u32(u32::from(queue.next_used));
+        e.bool(queue.event_idx_enabled);
+        e.bool(queue.packed);
+        e.bool(queue.avail_wrap_counter);
+        e.bool(queue.used_wrap_counter);
+    }
+    e.bytes(&state.device.data);
+}
+
+fn decode_transport(d: &mut Decoder) -> Result<MmioTransportState> {
+    Ok(MmioTransportState {
+        features_select: d.u32()?,
+        acked_features_select: d.u32()?,
+        queue_select: d.u32()?,
+        device_status: d.u32()?,
+        config_generation: d.u32()?,
+        shm_region_select: d.u32()?,
+        interrupt_status: d.u32()?,
+        acked_features: d.u64()?,
+        device: DeviceSnapshot {
+            queues: (0..d.u32()?)
+                .map(|_| {
+                    Ok(QueueState {
+                        size: d.u16()?,
+                        ready: d.bool()?,
+                        desc_table: d.u64()?,
+                        avail_ring: d.u64()?,
+                        used_ring: d.u64()?,
+                        next_avail: d.u16()?,
+                        next_used: d.u16()?,
+                        event_idx_enabled: d.bool()?,
+                        packed: d.bool()?,
+                        avail_wrap_counter: d.bool()?,
+                        used_wrap_counter: d.bool()?,
+                    })
+                })
+                .collect::<Result<_>>()?,
+            data: d.bytes()?.to_vec(),
+        },
+    })
+}
+
+/// Plain C structures of the KVM API, holding no pointers and valid for any bit pattern, which
+/// are saved as they are.
+///
+/// # Safety
+///
+/// Only implement it for types meeting these requirements.
+unsafe trait Pod: Sized {}
+
+unsafe impl Pod for kvm_clock_data {}
+unsafe impl Pod for kvm_cpuid_entry2 {}
+unsafe impl Pod for kvm_debugregs {}
+unsafe impl Pod for kvm_irqchip {}
+unsafe impl Pod for kvm_lapic_state {}
+unsafe impl Pod for kvm_mp_state {}
+unsafe impl Pod for kvm_msr_entry {}
+unsafe impl Pod for kvm_pit_state2 {}
+unsafe impl Pod for kvm_regs {}
+unsafe impl Pod for kvm_sregs {}
+unsafe impl Pod for kvm_vcpu_events {}
+unsafe impl Pod for kvm_xcrs {}
+unsafe impl Pod for kvm_xsave {}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.0.push(value as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn ranges(&mut self, ranges: &[(u64, u64)]) {
+        self.u32(ranges.len() as u32);
+        for &(start, size) in ranges {
+            self.u64(start);
+            self.u64(size);
+        }
+    }
+
+    fn pod<T: Pod>(&mut self, value: &T) {
+        // Safe because `T` is a plain C structure, see `Pod`.
+        let bytes =
+            unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn pod_list<T: Pod>(&mut self, values: &[T]) {
+        self.u32(values.len() as u32);
+        for value in values {
+            self.pod(value);
+        }
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        u16::try_from(self.u32()?).map_err(|_| Error::Malformed)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::Malformed),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = usize::try_from(self.u64()?).map_err(|_| Error::Malformed)?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| Error::Malformed)
+    }
+
+    fn ranges(&mut self) -> Result<Vec<(u64, u64)>> {
+        (0..self.u32()?)
+            .map(|_| Ok((self.u64()?, self.u64()?)))
+            .collect()
+    }
+
+    fn pod<T: Pod>(&mut self) -> Result<T> {
+        let bytes = self.take(size_of::<T>())?;
+        let mut value = MaybeUninit::<T>::uninit();
+        // Safe because `bytes` holds `size_of::<T>()` bytes to initialize `value` with, which is
+        // valid for any of them, see `Pod`.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                value.as_mut_ptr() as *mut u8,
+                bytes.len(),
+            );
+            Ok(value.assume_init())
+        }
+    }
+
+    fn pod_list<T: Pod>(&mut self) -> Result<Vec<T>> {
+        // Not preallocated, so a corrupted count can't make it allocate much more than the size
+        // of the snapshot.
+        (0..self.u32()?).map(|_| self.pod()).collect()
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Malformed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use devices::virtio::fs::fuse;
+    use devices::virtio::MmioTransport;
+    use devices::BusDevice;
+    use polly::event_manager::EventManager;
+    use utils::tempdir::TempDir;
+    use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+
+    use crate::builder::tests::{
+        test_guest_resources, wait_until, PortRecorder, TEST_GUEST_COUNTER,
+    };
+    use crate::resources::VmResources;
+    use crate::vmm_config::fs::tests::fs_config;
+
+    // Where the rings of the rng device and the buffers it fills are, in the test guest. The avail
+    // and used rings follow the descriptor table, each in its page.
+    const RNG_RINGS: u64 = 0x40_0000;
+    const BUFFERS: u64 = 0x40_3000;
+    const BUFFER_LEN: u32 = 0x100;
+    // And the ones of the request queue of the fs device, and its requests and replies.
+    const FS_RINGS: u64 = 0x50_0000;
+    const FS_REQUESTS: u64 = 0x50_3000;
+    const FS_REQUEST_LEN: u64 = 0x1000;
+    const QUEUE_SIZE: u32 = 16;
+
+    fn transport(vmm: &Mutex<Vmm>, type_id: u32) -> Arc<Mutex<MmioTransport>> {
+        let vmm = vmm.lock().unwrap();
+        vmm.mmio_device_manager
+            .virtio_devices()
+            .iter()
+            .find(|((device_type, _), _)| *device_type == DeviceType::Virtio(type_id))
+            .map(|(_, transport)| transport.clone())
+            .unwrap()
+    }
+
+    fn write_reg(transport: &Mutex<MmioTransport>, offset: u64, value: u32) {
+        transport
+            .lock()
+            .unwrap()
+            .write(0, offset, &value.to_le_bytes());
+    }
+
+    // Sets the device up with its queue `queue` at `rings`, as its guest driver would.
+    fn activate(transport: &Mutex<MmioTransport>, queue: u32, rings: u64) {
+        write_reg(transport, 0x70, 0x1);
+        write_reg(transport, 0x70, 0x3);
+        // VIRTIO_F_VERSION_1
+        write_reg(transport, 0x24, 1);
+        write_reg(transport, 0x20, 1);
+        write_reg(transport, 0x70, 0xb);
+        for (offset, value) in [
+            (0x30, queue),
+            (0x38, QUEUE_SIZE),
+            (0x80, rings as u32),
+            (0x84, 0),
+            (0x90, (rings + 0x1000) as u32),
+            (0x94, 0),
+            (0xa0, (rings + 0x2000) as u32),
+            (0xa4, 0),
+            (0x44, 1),
+        ] {
+            write_reg(transport, offset, value);
+        }
+        write_reg(transport, 0x70, 0xf);
+    }
+
+    // Makes the descriptor `index` at `rings` available, and notifies the queue `queue`.
+    fn make_available(
+        mem: &GuestMemoryMmap,
+        transport: &Mutex<MmioTransport>,
+        queue: usize,
+        rings: u64,
+        index: u16,
+    ) {
+        let avail_ring = rings + 0x1000;
+        let avail_idx: u16 = mem.read_obj(GuestAddress(avail_ring + 2)).unwrap();
+        let slot = u64::from(avail_idx % QUEUE_SIZE as u16);
+        mem.write_obj(index, GuestAddress(avail_ring + 4 + 2 * slot))
+            .unwrap();
+        mem.write_obj(avail_idx + 1, GuestAddress(avail_ring + 2))
+            .unwrap();
+        // Kick the queue the way the KVM ioeventfd would, a QueueNotify write of the
+        // index 0 doesn't signal the eventfd.
+        transport.lock().unwrap().locked_device().queue_events()[queue]
+            .write(1)
+            .unwrap();
+    }
+
+    // Sets the descriptor `index` at `rings` to `len` bytes at `addr`.
+    fn set_desc(mem: &GuestMemoryMmap, rings: u64, index: u16, addr: u64, len: u32, flags: u16) {
+        let desc = rings + 16 * u64::from(index);
+        mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+        mem.write_obj(index + 1, GuestAddress(desc + 14)).unwrap();
+    }
+
+    // Queues the buffer `index` for the rng device to fill, and notifies it.
+    fn request_entropy(mem: &GuestMemoryMmap, transport: &Mutex<MmioTransport>, index: u16) {
+        let buffer = BUFFERS + u64::from(index) * u64::from(BUFFER_LEN);
+        // VIRTQ_DESC_F_WRITE
+        set_desc(mem, RNG_RINGS, index, buffer, BUFFER_LEN, 2);
+        make_available(mem, transport, 0, RNG_RINGS, index);
+    }
+
+    // Sends the FUSE request `index` to the fs device, `opcode` on `nodeid` with `args`, its reply
+    // going to the page after the request's. Like with the Linux driver, the header of the reply
+    // has its own descriptor.
+    fn fuse_request(
+        mem: &GuestMemoryMmap,
+        transport: &Mutex<MmioTransport>,
+        index: u16,
+        opcode: u32,
+        nodeid: u64,
+        args: &[u8],
+    ) {
+        let request = FS_REQUESTS + 2 * u64::from(index) * FS_REQUEST_LEN;
+        let header = fuse::InHeader {
+            len: (size_of::<fuse::InHeader>() + args.len()) as u32,
+            opcode,
+            unique: u64::from(index),
+            nodeid,
+            ..Default::default()
+        };
+        mem.write_obj(header, GuestAddress(request)).unwrap();
+        mem.write_slice(
+            args,
+            GuestAddress(request + size_of::<fuse::InHeader>() as u64),
+        )
+        .unwrap();
+        // The readable request, then the writable reply: VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE.
+        let reply = request + FS_REQUEST_LEN;
+        let reply_header_len = size_of::<fuse::OutHeader>() as u32;
+        set_desc(mem, FS_RINGS, 3 * index, request, header.len, 1);
+        set_desc(mem, FS_RINGS, 3 * index + 1, reply, reply_header_len, 3);
+        set_desc(
+            mem,
+            FS_RINGS,
+            3 * index + 2,
+            reply + u64::from(reply_header_len),
+            FS_REQUEST_LEN as u32 - reply_header_len,
+            2,
+        );
+        make_available(mem, transport, 1, FS_RINGS, 3 * index);
+    }
+
+    // Returns the reply to the FUSE request `index`, after its header, which is checked.
+    fn fuse_reply<T: ByteValued>(mem: &GuestMemoryMmap, index: u16) -> T {
+        let reply = FS_REQUESTS + (2 * u64::from(index) + 1) * FS_REQUEST_LEN;
+        let header: fuse::OutHeader = mem.read_obj(GuestAddress(reply)).unwrap();
+        assert_eq!((header.error, header.unique), (0, u64::from(index)));
+        mem.read_obj(GuestAddress(reply + size_of::<fuse::OutHeader>() as u64))
+            .unwrap()
+    }
+
+    fn used_idx(mem: &GuestMemoryMmap, rings: u64) -> u16 {
+        mem.read_obj(GuestAddress(rings + 0x2000 + 2)).unwrap()
+    }
+
+    // Runs the event loop of `vmm` until the device used `count` buffers of the queue at `rings`.
+    fn wait_used(
+        vmm: &Arc<Mutex<Vmm>>,
+        event_manager: &mut EventManager,
+        rings: u64,
+        count: u16,
+    ) -> bool {
+        let mem = vmm.lock().unwrap().guest_memory().clone();
+        wait_until(|| {
+            Vmm::run_once(vmm, event_manager, Some(Duration::from_millis(10))).unwrap();
+            used_idx(&mem, rings) == count
+        })
+    }
+
+    // The test guest, sharing `share` in `dir` with an fs device.
+    fn resources(recorder: &Arc<PortRecorder>, dir: &TempDir) -> VmResources {
+        let mut vm_resources =
+            test_guest_resources(recorder.clone(), &dir.as_path().join("console"));
+        let share = dir.as_path().join("share");
+        vm_resources
+            .fs
+            .insert(fs_config("share", share.to_str().unwrap()))
+            .unwrap();
+        vm_resources
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let dir = TempDir::new().unwrap();
+        let state_path = dir.as_path().join("state");
+        let mem_path = dir.as_path().join("mem");
+        std::fs::create_dir(dir.as_path().join("share")).unwrap();
+        std::fs::write(dir.as_path().join("share/file"), b"hello").unwrap();
+
+        let recorder = Arc::new(PortRecorder::default());
+        let vm_resources = resources(&recorder, &dir);
+        let mut event_manager = EventManager::new().unwrap();
+        let vmm = crate::builder::build_microvm(&vm_resources, &mut event_manager, None).unwrap();
+        let mem = vmm.lock().unwrap().guest_memory().clone();
+        assert!(wait_until(|| recorder.last.lock().unwrap().is_some()));
+
+        // One request is answered before the snapshot, the other one is only queued.
+        let rng = transport(&vmm, devices::virtio::TYPE_RNG);
+        activate(&rng, 0, RNG_RINGS);
+        request_entropy(&mem, &rng, 0);
+        assert!(wait_used(&vmm, &mut event_manager, RNG_RINGS, 1));
+
+        // The guest mounts the share and opens a file on it.
+        let fs = transport(&vmm, devices::virtio::fs::TYPE_FS);
+        activate(&fs, 1, FS_RINGS);
+        let init = fuse::InitInCompat {
+            major: fuse::KERNEL_VERSION,
+            minor: fuse::KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        fuse_request(&mem, &fs, 0, 26, 0, init.as_slice());
+        fuse_request(&mem, &fs, 1, 1, fuse::ROOT_ID, b"file\0");
+        assert!(wait_used(&vmm, &mut event_manager, FS_RINGS, 2));
+        let entry: fuse::EntryOut = fuse_reply(&mem, 1);
+        let open = fuse::OpenIn {
+            flags: libc::O_RDWR as u32,
+            ..Default::default()
+        };
+        fuse_request(&mem, &fs, 2, 14, entry.nodeid, open.as_slice());
+        assert!(wait_used(&vmm, &mut event_manager, FS_RINGS, 3));
+        let opened: fuse::OpenOut = fuse_reply(&mem, 2);
+        request_entropy(&mem, &rng, 1);
+
+        let counter = {
+            let mut vmm = vmm.lock().unwrap();
+            vmm.pause_vcpus().unwrap();
+            vmm.snapshot(&state_path, &mem_path).unwrap();
+            let counter: u64 = mem.read_obj(GuestAddress(TEST_GUEST_COUNTER)).unwrap();
+            vmm.teardown().unwrap();
+            counter
+        };
+        assert!(counter > 0);
+
+        let recorder = Arc::new(PortRecorder::default());
+        let vm_resources = resources(&recorder, &dir);
+        let mut event_manager = EventManager::new().unwrap();
+        let vmm = crate::builder::build_microvm_from_snapshot(
+            &vm_resources,
+            &mut event_manager,
+            None,
+            &state_path,
+            &mem_path,
+        )
+        .unwrap();
+        let mem = vmm.lock().unwrap().guest_memory().clone();
+        let restored: u64 = mem.read_obj(GuestAddress(TEST_GUEST_COUNTER)).unwrap();
+        assert_eq!(restored, counter);
+        let buffer = |index: u64| {
+            let mut buffer = [0u8; BUFFER_LEN as usize];
+            mem.read_slice(
+                &mut buffer,
+                GuestAddress(BUFFERS + index * u64::from(BUFFER_LEN)),
+            )
+            .unwrap();
+            buffer
+        };
+        assert_ne!(buffer(0), [0; BUFFER_LEN as usize]);
+        assert_eq!(buffer(1), [0; BUFFER_LEN as usize]);
+
+        // The restored device picks up the request queued before the snapshot.
+        assert!(wait_used(&vmm, &mut event_manager, RNG_RINGS, 2));
+        assert_ne!(buffer(1), [0; BUFFER_LEN as usize]);
+        let used_id: u32 = mem
+            .read_obj(GuestAddress(RNG_RINGS + 0x2000 + 4 + 8))
+            .unwrap();
+        assert_eq!(used_id, 1);
+
+        // The guest reads the file it opened, with the inode and handle it had.
+        let fs = transport(&vmm, devices::virtio::fs::TYPE_FS);
+        let read = fuse::ReadIn {
+            fh: opened.fh,
+            size: 0x100,
+            ..Default::default()
+        };
+        fuse_request(&mem, &fs, 3, 15, entry.nodeid, read.as_slice());
+        assert!(wait_used(&vmm, &mut event_manager, FS_RINGS, 4));
+        let data: [u8; 5] = fuse_reply(&mem, 3);
+        assert_eq!(&data, b"hello");
+
+        // And the guest carries on counting from where it was, the `out` it was paused on
+        // possibly being run again.
+        vmm.lock().unwrap().resume_vcpus().unwrap();
+        assert!(wait_until(|| recorder
+            .last
+            .lock()
+            .unwrap()
+            .is_some_and(|last| u64::from(last) > counter + 100)));
+        let first = u64::from(recorder.first.lock().unwrap().unwrap());
+        assert!(
+            first == counter || first == counter + 1,
+            "{first} {counter}"
+        );
+        vmm.lock().unwrap().teardown().unwrap();
+    }
+
+    fn microvm_state() -> MicrovmState {
+        MicrovmState {
+            host_cpuid: vec![kvm_cpuid_entry2 {
+                function: 1,
+                eax: 0x806c1,
+                ..Default::default()
+            }],
+            cmdline: "console=hvc0 quiet".to_string(),
+            memory_info: ArchMemoryInfo {
+                ram_last_addr: 0x1000_0000,
+                shm_start_addr: 0x2_0000_0000,
+                shm_size: 0x10_0000,
+                reserved_ranges: vec![(0x8000, 0x1000)],
+                firmware_ranges: Vec::new(),
+            },
+            vm: VmState {
+                pitstate: Default::default(),
+                clock: kvm_clock_data {
+                    clock: 123_456,
+                    ..Default::default()
+                },
+                pic_master: Default::default(),
+                pic_slave: kvm_irqchip {
+                    chip_id: 1,
+                    ..Default::default()
+                },
+                ioapic: kvm_irqchip {
+                    chip_id: 2,
+                    ..Default::default()
+                },
+            },
+            vcpus: vec![vec![1, 2, 3]],
+            devices: vec![DeviceState {
+                device_type: 3,
+                id: "hvc0".to_string(),
+                transport: MmioTransportState {
+                    device_status: 0xf,
+                    acked_features: 1 << 32,
+                    device: DeviceSnapshot {
+                        queues: vec![QueueState {
+                            size: 256,
+                            ready: true,
+                            desc_table: 0x1000,
+                            next_avail: 0xffff,
+                            packed: true,
+                            ..Default::default()
+                        }],
+                        data: vec![1, 0, 0, 0],
+                    },
+                    ..Default::default()
+                },
+            }],
+        }
+    }
+
+    #[test]
+    fn test_microvm_state() {
+        let state = microvm_state();
+        let encoded = state.encode();
+        let decoded = MicrovmState::decode(&encoded).unwrap();
+
+        assert_eq!(decoded.host_cpuid, state.host_cpuid);
+        assert_eq!(decoded.cmdline, state.cmdline);
+        assert_eq!(decoded.memory_info, state.memory_info);
+        assert_eq!(decoded.vm.clock.clock, 123_456);
+        assert_eq!(decoded.vm.pic_slave.chip_id, 1);
+        assert_eq!(decoded.vm.ioapic.chip_id, 2);
+        assert_eq!(decoded.vcpus, state.vcpus);
+        assert_eq!(decoded.devices.len(), 1);
+        assert_eq!(decoded.devices[0].device_type, 3);
+        assert_eq!(decoded.devices[0].id, "hvc0");
+        assert_eq!(decoded.devices[0].transport, state.devices[0].transport);
+
+        // Truncated, trailing garbage, or from another version.
+        assert!(matches!(
+            MicrovmState::decode(&encoded[..encoded.len() - 1]),
+            Err(Error::Malformed)
+        ));
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(matches!(
+            MicrovmState::decode(&trailing),
+            Err(Error::Malformed)
+        ));
+        let mut other_version = encoded;
+        other_version[8] += 1;
+        assert!(matches!(
+            MicrovmState::decode(&other_version),
+            Err(Error::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn test_vcpu_state() {
+        let mut xsave: kvm_xsave = Decoder(&[0; size_of::<kvm_xsave>()]).pod().unwrap();
+        xsave.region[1023] = 0xdead;
+        let state = VcpuKvmState {
+            cpuid: CpuId::from_entries(&[kvm_cpuid_entry2 {
+                function: 0xd,
+                ..Default::default()
+            }])
+            .unwrap(),
+            msrs: Msrs::from_entries(&[kvm_msr_entry {
+                index: 0x10,
+                data: 42,
+                ..Default::default()
+            }])
+            .unwrap(),
+            debug_regs: Default::default(),
+            lapic: Default::default(),
+            mp_state: kvm_mp_state { mp_state: 3 },
+            nested_state: Some(vec![4, 5]),
+            regs: kvm_regs {
+                rip: 0xffff_ffff_8100_0000,
+                ..Default::default()
+            },
+            sregs: Default::default(),
+            vcpu_events: Default::default(),
+            xcrs: Default::default(),
+            xsave,
+        };
+
+        let decoded = decode_vcpu_state(&encode_vcpu_state(&state)).unwrap();
+        assert_eq!(decoded.cpuid.as_slice(), state.cpuid.as_slice());
+        assert_eq!(decoded.msrs.as_slice()[0].data, 42);
+        assert_eq!(decoded.mp_state.mp_state, 3);
+        assert_eq!(decoded.nested_state, Some(vec![4, 5]));
+        assert_eq!(decoded.regs.rip, state.regs.rip);
+        assert_eq!(decoded.xsave.region[1023], 0xdead);
+    }
+}
@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+
+/// Initial state of the balloon device, see `Vmm::set_balloon_target` for changing it at runtime.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BalloonConfig {
+    /// Number of 4 KiB pages the guest is asked to give up once its balloon driver loads.
+    pub target_pages: u64,
+}
+
+/// Errors associated with configuring the balloon device.
+#[derive(Debug, Eq, PartialEq)]
+pub enum BalloonConfigError {
+    /// The target doesn't fit in the 32-bit config register of the device.
+    TargetTooLarge(u64),
+}
+
+impl fmt::Display for BalloonConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BalloonConfigError::*;
+        match self {
+            TargetTooLarge(pages) => write!(f, "The balloon can't hold {pages} pages"),
+        }
+    }
+}
+
+/// Converts a target to the value of the config register of the device.
+pub fn balloon_target_pages(pages: u64) -> Result<u32, BalloonConfigError> {
+    u32::try_from(pages).map_err(|_| BalloonConfigError::TargetTooLarge(pages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balloon_target_pages() {
+        assert_eq!(balloon_target_pages(0x1_0000), Ok(0x1_0000));
+        assert_eq!(
+            balloon_target_pages(1 << 32),
+            Err(BalloonConfigError::TargetTooLarge(1 << 32))
+        );
+    }
+}
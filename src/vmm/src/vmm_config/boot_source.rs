@@ -3,6 +3,9 @@
 
 use std::fmt::{Display, Formatter, Result};
 
+use kernel::cmdline::Cmdline;
+use vm_memory::GuestAddress;
+
 /// Default guest kernel command line:
 /// - `reboot=k` shut down the guest on reboot, instead of well... rebooting;
 /// - `panic=1` on panic, reboot after 1 second;
@@ -38,6 +41,142 @@ pub struct BootSourceConfig {
     /// kernel command line is used: `reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0`.
     pub kernel_cmdline_prolog: Option<String>,
     pub kernel_cmdline_epilog: Option<String>,
+    /// Structured parameters appended after the prolog.
+    pub kernel_cmdline_args: KernelCmdlineArgs,
+    /// Guest RAM regions, as `(start, size)` pairs, a firmware needs for itself. They're reported
+    /// as reserved in the e820 map (x86_64) or as `/reserved-memory` nodes of the DT (aarch64),
+    /// so the guest OS doesn't allocate from them. They must be page aligned and can't overlap
+    /// each other, the kernel or the initrd.
+    pub reserved_regions: Vec<(GuestAddress, u64)>,
+}
+
+/// Kernel command line parameters set individually instead of as part of the prolog.
+///
+/// Setting `root`, `console` or `init`, or a key of `params`, drops any parameter with the same
+/// key from the prolog, so the value given here is the only one the guest kernel sees. A key set
+/// more than once, e.g. both as `root` and in `params`, gets its last value, in the place of the
+/// first one.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KernelCmdlineArgs {
+    /// Value for `root=`.
+    pub root: Option<String>,
+    /// Value for `console=`.
+    pub console: Option<String>,
+    /// Value for `init=`.
+    pub init: Option<String>,
+    /// Parameters inserted as `key=value`, or as `key` alone without a value, in order.
+    pub params: Vec<(String, Option<String>)>,
+    /// Additional parameters, inserted verbatim after the ones above.
+    pub extra: Vec<String>,
+}
+
+impl KernelCmdlineArgs {
+    // The keys set by `self` with their last value, in order.
+    fn params(&self) -> Vec<(&str, Option<&str>)> {
+        let fields = [
+            ("root", &self.root),
+            ("console", &self.console),
+            ("init", &self.init),
+        ]
+        .into_iter()
+        .filter_map(|(key, val)| val.as_deref().map(|v| (key, Some(v))));
+        let params = self
+            .params
+            .iter()
+            .map(|(key, val)| (key.as_str(), val.as_deref()));
+
+        let mut deduped: Vec<(&str, Option<&str>)> = Vec::new();
+        for (key, val) in fields.chain(params) {
+            match deduped.iter_mut().find(|(k, _)| *k == key) {
+                Some(param) => param.1 = val,
+                None => deduped.push((key, val)),
+            }
+        }
+        deduped
+    }
+
+    fn overrides(params: &[(&str, Option<&str>)], token: &str) -> bool {
+        params.iter().any(|(key, _)| {
+            token
+                .strip_prefix(key)
+                .is_some_and(|t| t.is_empty() || t.starts_with('='))
+        })
+    }
+
+    // Upper bound of the room the parameters of `self` take in a command line.
+    fn max_len(&self) -> usize {
+        let params: usize = self
+            .params()
+            .iter()
+            .map(|(key, val)| key.len() + val.map_or(0, |v| v.len() + 1) + 1)
+            .sum();
+        params + self.extra.iter().map(|arg| arg.len() + 1).sum::<usize>()
+    }
+
+    /// Inserts `prolog` into `cmdline`, minus the parameters overridden by `self`, followed by
+    /// the structured parameters.
+    pub fn apply(&self, prolog: &str, cmdline: &mut Cmdline) -> kernel::cmdline::Result<()> {
+        let params = self.params();
+        let mut kept = Vec::new();
+        let mut init_args = false;
+        for token in split_cmdline(prolog) {
+            // Everything after "--" belongs to init.
+            init_args |= token == "--";
+            if init_args || !Self::overrides(&params, token) {
+                kept.push(token);
+            }
+        }
+        if !kept.is_empty() {
+            cmdline.insert_str(kept.join(" "))?;
+        }
+
+        for (key, val) in params {
+            match val {
+                Some(val) => cmdline.insert(key, val)?,
+                None => cmdline.insert_flag(key)?,
+            }
+        }
+        for arg in &self.extra {
+            cmdline.insert_str(arg)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Split a kernel command line into parameters, keeping double quoted values together.
+fn split_cmdline(cmdline: &str) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    cmdline
+        .split(move |c: char| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            c.is_ascii_whitespace() && !quoted
+        })
+        .filter(|token| !token.is_empty())
+}
+
+impl BootSourceConfig {
+    /// Returns the length of the kernel command line assembled from the prolog, the structured
+    /// parameters and the epilog, nul terminator included. libkrun adds its own parameters to it
+    /// while building the microVM.
+    pub fn kernel_cmdline_len(&self) -> kernel::cmdline::Result<usize> {
+        let prolog = self
+            .kernel_cmdline_prolog
+            .as_deref()
+            .unwrap_or(DEFAULT_KERNEL_CMDLINE);
+        let epilog = self.kernel_cmdline_epilog.as_deref().unwrap_or_default();
+
+        // Leave room for everything, only invalid parameters can fail.
+        let mut cmdline =
+            Cmdline::new(prolog.len() + epilog.len() + self.kernel_cmdline_args.max_len() + 3);
+        self.kernel_cmdline_args.apply(prolog, &mut cmdline)?;
+        if !epilog.is_empty() {
+            cmdline.insert_str(epilog)?;
+        }
+        Ok(cmdline.len() + 1)
+    }
 }
 
 /// Errors associated with actions on `BootSourceConfig`.
@@ -57,3 +196,107 @@ impl Display for BootSourceConfigError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_cmdline_args() {
+        let prolog = "console=hvc0 root=/dev/vda rw KRUN_WORKDIR=\"/a  b\" -- root=x";
+
+        let mut cmdline = Cmdline::new(1024);
+        KernelCmdlineArgs::default()
+            .apply(prolog, &mut cmdline)
+            .unwrap();
+        assert_eq!(
+            cmdline.as_str(),
+            "console=hvc0 root=/dev/vda rw KRUN_WORKDIR=\"/a  b\" -- root=x"
+        );
+
+        let args = KernelCmdlineArgs {
+            root: Some("/dev/vdb".to_string()),
+            init: Some("/sbin/init".to_string()),
+            extra: vec!["quiet".to_string(), "loglevel=3".to_string()],
+            ..Default::default()
+        };
+        let mut cmdline = Cmdline::new(1024);
+        args.apply(
+            "console=hvc0 root=/dev/vda rootfstype=ext4 rw",
+            &mut cmdline,
+        )
+        .unwrap();
+        assert_eq!(
+            cmdline.as_str(),
+            "console=hvc0 rootfstype=ext4 rw root=/dev/vdb init=/sbin/init quiet loglevel=3"
+        );
+
+        let args = KernelCmdlineArgs {
+            root: Some("/dev/vdb".to_string()),
+            params: vec![
+                ("loglevel".to_string(), Some("3".to_string())),
+                ("rw".to_string(), None),
+                ("root".to_string(), Some("/dev/vdc".to_string())),
+                ("loglevel".to_string(), Some("7".to_string())),
+            ],
+            ..Default::default()
+        };
+        let mut cmdline = Cmdline::new(1024);
+        args.apply("console=hvc0 root=/dev/vda rw loglevel=1", &mut cmdline)
+            .unwrap();
+        assert_eq!(cmdline.as_str(), "console=hvc0 root=/dev/vdc loglevel=7 rw");
+
+        let args = KernelCmdlineArgs {
+            params: vec![("a b".to_string(), None)],
+            ..Default::default()
+        };
+        let mut cmdline = Cmdline::new(1024);
+        assert!(args.apply("", &mut cmdline).is_err());
+
+        let args = KernelCmdlineArgs {
+            root: Some("/dev/vda rw".to_string()),
+            ..Default::default()
+        };
+        let mut cmdline = Cmdline::new(1024);
+        assert!(args.apply("", &mut cmdline).is_err());
+
+        let args = KernelCmdlineArgs {
+            extra: vec!["a".repeat(32)],
+            ..Default::default()
+        };
+        let mut cmdline = Cmdline::new(16);
+        assert_eq!(
+            args.apply("", &mut cmdline),
+            Err(kernel::cmdline::Error::TooLarge)
+        );
+    }
+
+    #[test]
+    fn test_kernel_cmdline_len() {
+        let mut boot_config = BootSourceConfig {
+            kernel_cmdline_prolog: Some("console=hvc0 root=/dev/vda".to_string()),
+            kernel_cmdline_epilog: Some("-- /bin/sh".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            boot_config.kernel_cmdline_len(),
+            Ok("console=hvc0 root=/dev/vda -- /bin/sh".len() + 1)
+        );
+
+        boot_config.kernel_cmdline_args.params = vec![
+            ("root".to_string(), Some("/dev/vdb".to_string())),
+            ("quiet".to_string(), None),
+        ];
+        assert_eq!(
+            boot_config.kernel_cmdline_len(),
+            Ok("console=hvc0 root=/dev/vdb quiet -- /bin/sh".len() + 1)
+        );
+
+        // Far beyond any limit, it's up to the caller to check.
+        boot_config.kernel_cmdline_args.extra = vec!["a".repeat(0x20000)];
+        assert_eq!(
+            boot_config.kernel_cmdline_len(),
+            Ok("console=hvc0 root=/dev/vdb quiet -- /bin/sh".len() + 0x20002)
+        );
+    }
+}
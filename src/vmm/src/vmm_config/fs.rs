@@ -1,13 +1,18 @@
 use std::collections::VecDeque;
 use std::fmt;
+use std::num::NonZeroUsize;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{Fs, FsError};
+use devices::virtio::{
+    CachePolicy, ChainValidation, Fs, FsError, FsOpPolicy, SpecialFilePolicy, XattrMapping,
+};
 
 #[derive(Debug)]
 pub enum FsConfigError {
     /// Failed to create the fs device.
     CreateFsDevice(FsError),
+    /// Another fs device has the same tag.
+    DuplicateTag(String),
 }
 
 impl fmt::Display for FsConfigError {
@@ -15,6 +20,7 @@ impl fmt::Display for FsConfigError {
         use self::FsConfigError::*;
         match *self {
             CreateFsDevice(ref e) => write!(f, "Cannot create vsock device: {e:?}"),
+            DuplicateTag(ref tag) => write!(f, "A virtio-fs device already has the tag {tag}"),
         }
     }
 }
@@ -25,6 +31,56 @@ type Result<T> = std::result::Result<T, FsConfigError>;
 pub struct FsDeviceConfig {
     pub fs_id: String,
     pub shared_dir: String,
+    /// Whether host mount points inside `shared_dir` are exposed to the guest as submounts.
+    pub announce_submounts: bool,
+    /// Host-side prefix guest `security.` xattrs are stored under, e.g. `user.virtiofs.`.
+    pub security_xattr_prefix: Option<String>,
+    /// Rules renaming extended attributes between the guest and the host, applied before
+    /// `security_xattr_prefix`.
+    pub xattr_map: Vec<XattrMapping>,
+    /// Ioctl request numbers the guest may issue on shared files, e.g. `FS_IOC_GETFLAGS`.
+    pub allowed_ioctls: Vec<u32>,
+    /// How host device nodes inside `shared_dir` are exposed to the guest.
+    pub device_policy: SpecialFilePolicy,
+    /// How host FIFOs and Unix sockets inside `shared_dir` are exposed to the guest.
+    pub fifo_socket_policy: SpecialFilePolicy,
+    /// Whether the shared files are read and written with `O_DIRECT` on the host, falling back to
+    /// the page cache for misaligned requests.
+    pub direct_io: bool,
+    /// How the guest caches the shared files and their attributes. `CachePolicy::Auto`, the
+    /// default, only revalidates them when files are opened and after a few seconds, so changes
+    /// made to `shared_dir` on the host may take that long to show up in the guest. Use
+    /// `CachePolicy::Never` if the host modifies it while the guest runs. `CachePolicy::Always`
+    /// additionally buffers the guest writes, trading coherence with the host for throughput: host
+    /// changes can go unnoticed by the guest for as long as it has the files cached, and the guest
+    /// may later overwrite them with its buffered writes. Only use it when the guest has the
+    /// directory to itself.
+    pub cache_policy: CachePolicy,
+    /// Size of the DAX window the guest maps the shared files into instead of copying them
+    /// through the queues, a multiple of `builder::FS_DAX_WINDOW_ALIGN`. `None` disables DAX.
+    pub shm_size: Option<usize>,
+    /// FUSE operations refused with `EPERM` on this share, e.g. `FUSE_MKNOD`.
+    pub op_policy: FsOpPolicy,
+    /// How strictly the descriptor chains of guest requests are checked.
+    pub chain_validation: ChainValidation,
+    /// Whether the latency of the requests is recorded, see `Vmm::fs_stats`.
+    pub latency_stats: bool,
+    /// Number of request queues the guest may spread its requests across, each serviced by its
+    /// own thread.
+    pub num_request_queues: usize,
+    /// Size of each queue, a power of 2.
+    pub queue_size: u16,
+    /// Maximum number of requests handled at once across the request queues, the others waiting
+    /// in their queue. `None` lets each queue have one in flight.
+    pub max_in_flight_requests: Option<NonZeroUsize>,
+    /// Maximum number of host fds the inodes the guest references keep open at once, past which
+    /// the least recently used ones are closed and reopened by handle when needed. `None` doesn't
+    /// limit them. Inodes don't keep host fds open on macOS.
+    #[cfg(target_os = "linux")]
+    pub max_inodes: Option<NonZeroUsize>,
+    /// Maximum number of files the guest may have open at once, past which opens fail with
+    /// `ENFILE`. `None` doesn't limit them.
+    pub max_handles: Option<NonZeroUsize>,
 }
 
 #[derive(Default)]
@@ -39,14 +95,94 @@ impl FsBuilder {
         }
     }
 
+    /// Adds a device, whose tag must differ from the ones of the devices already added for the
+    /// guest to tell them apart.
     pub fn insert(&mut self, config: FsDeviceConfig) -> Result<()> {
+        if self
+            .list
+            .iter()
+            .any(|fs| fs.lock().unwrap().tag() == config.fs_id)
+        {
+            return Err(FsConfigError::DuplicateTag(config.fs_id));
+        }
         let fs_dev = Arc::new(Mutex::new(Self::create_fs(config)?));
         self.list.push_back(fs_dev);
         Ok(())
     }
 
     pub fn create_fs(config: FsDeviceConfig) -> Result<Fs> {
-        devices::virtio::Fs::new(config.fs_id, config.shared_dir)
-            .map_err(FsConfigError::CreateFsDevice)
+        let mut fs = devices::virtio::Fs::with_request_queues(
+            config.fs_id,
+            config.shared_dir,
+            config.num_request_queues,
+            config.queue_size,
+        )
+        .map_err(FsConfigError::CreateFsDevice)?;
+        fs.set_announce_submounts(config.announce_submounts);
+        fs.set_security_xattr_prefix(config.security_xattr_prefix);
+        fs.set_xattr_map(config.xattr_map);
+        fs.set_allowed_ioctls(config.allowed_ioctls);
+        fs.set_device_policy(config.device_policy);
+        fs.set_fifo_socket_policy(config.fifo_socket_policy);
+        fs.set_direct_io(config.direct_io);
+        fs.set_cache_policy(config.cache_policy);
+        fs.set_shm_size(config.shm_size);
+        fs.set_op_policy(config.op_policy);
+        fs.set_chain_validation(config.chain_validation);
+        fs.set_latency_stats(config.latency_stats);
+        fs.set_max_in_flight_requests(config.max_in_flight_requests);
+        #[cfg(target_os = "linux")]
+        fs.set_max_inodes(config.max_inodes);
+        fs.set_max_handles(config.max_handles);
+        Ok(fs)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use devices::virtio::{FS_NUM_REQUEST_QUEUES, FS_QUEUE_SIZE};
+
+    pub(crate) fn fs_config(tag: &str, shared_dir: &str) -> FsDeviceConfig {
+        FsDeviceConfig {
+            fs_id: tag.to_string(),
+            shared_dir: shared_dir.to_string(),
+            announce_submounts: false,
+            security_xattr_prefix: None,
+            xattr_map: Vec::new(),
+            allowed_ioctls: Vec::new(),
+            device_policy: SpecialFilePolicy::Skip,
+            fifo_socket_policy: SpecialFilePolicy::Passthrough,
+            direct_io: false,
+            cache_policy: CachePolicy::Auto,
+            shm_size: None,
+            op_policy: FsOpPolicy::default(),
+            chain_validation: ChainValidation::default(),
+            latency_stats: false,
+            num_request_queues: FS_NUM_REQUEST_QUEUES,
+            queue_size: FS_QUEUE_SIZE,
+            max_in_flight_requests: None,
+            #[cfg(target_os = "linux")]
+            max_inodes: None,
+            max_handles: None,
+        }
+    }
+
+    #[test]
+    fn test_insert_tags() {
+        let mut builder = FsBuilder::new();
+        builder.insert(fs_config("toolchain", "/opt")).unwrap();
+        builder.insert(fs_config("workspace", "/tmp")).unwrap();
+        assert!(matches!(
+            builder.insert(fs_config("workspace", "/srv")),
+            Err(FsConfigError::DuplicateTag(tag)) if tag == "workspace"
+        ));
+
+        let tags: Vec<_> = builder
+            .list
+            .iter()
+            .map(|fs| fs.lock().unwrap().tag().to_string())
+            .collect();
+        assert_eq!(tags, ["toolchain", "workspace"]);
     }
 }
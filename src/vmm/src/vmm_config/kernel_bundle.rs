@@ -21,15 +21,21 @@ pub enum KernelBundleError {
     InvalidHostAddress,
     /// Kernel size is zero or not a multiple of the page size.
     InvalidSize,
+    /// The kernel image can't be loaded.
+    InvalidImage(kernel::loader::Error),
+    /// Cannot allocate the memory the kernel image is loaded to.
+    Allocate(vm_memory::mmap::MmapRegionError),
 }
 
 impl Display for KernelBundleError {
     fn fmt(&self, f: &mut Formatter) -> Result {
         use self::KernelBundleError::*;
-        match *self {
+        match self {
             InvalidGuestAddress => write!(f, "Guest address is not page-aligned"),
             InvalidHostAddress => write!(f, "Host address is zero or not page-aligned"),
             InvalidSize => write!(f, "Kernel size is zero or not a multiple of the page size"),
+            InvalidImage(err) => write!(f, "Cannot load the kernel image: {err}"),
+            Allocate(err) => write!(f, "Cannot allocate memory for the kernel image: {err}"),
         }
     }
 }
@@ -2,6 +2,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt;
+use std::path::PathBuf;
+
+pub use arch::CpuTopology;
 
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
@@ -15,6 +18,18 @@ pub enum VmConfigError {
     InvalidVcpuCount,
     /// The memory size is invalid. The memory can only be an unsigned integer.
     InvalidMemorySize,
+    /// Nested virtualization is only supported on x86_64 Linux hosts.
+    NestedUnsupported,
+    /// The TSC frequency is zero, or set on a platform other than x86_64 Linux.
+    InvalidTscFrequency,
+    /// CPUID overrides are only supported on x86_64 Linux hosts.
+    CpuidOverridesUnsupported,
+    /// The guest PMU and SVE can only be configured on aarch64 Linux hosts.
+    ArmVcpuFeaturesUnsupported,
+    /// A reserved memory range is empty, not page aligned or overlaps another one.
+    InvalidReservedMemory(u64, u64),
+    /// The CPU topology doesn't add up to the vCPU count, or can't be presented to the guest.
+    InvalidCpuTopology(CpuTopology),
 }
 
 impl fmt::Display for VmConfigError {
@@ -27,6 +42,40 @@ impl fmt::Display for VmConfigError {
                  be 1 or an even number when hyperthreading is enabled.",
             ),
             InvalidMemorySize => write!(f, "The memory size (MiB) is invalid.",),
+            NestedUnsupported => write!(
+                f,
+                "Nested virtualization is not supported on this platform."
+            ),
+            InvalidTscFrequency => write!(
+                f,
+                "The TSC frequency can only be set on x86_64 Linux hosts, and can't be zero."
+            ),
+            CpuidOverridesUnsupported => write!(
+                f,
+                "CPUID overrides are only supported on x86_64 Linux hosts."
+            ),
+            ArmVcpuFeaturesUnsupported => write!(
+                f,
+                "The guest PMU and SVE can only be configured on aarch64 Linux hosts."
+            ),
+            InvalidReservedMemory(start, size) => write!(
+                f,
+                "The reserved memory range {start:#x}+{size:#x} is invalid: it must be \
+                 page aligned and not overlap other reserved ranges."
+            ),
+            InvalidCpuTopology(topology) => write!(
+                f,
+                "The CPU topology of {} sockets, {} cores per socket and {} threads per core is \
+                 invalid: it must have as many threads as vCPUs{}.",
+                topology.sockets,
+                topology.cores_per_socket,
+                topology.threads_per_core,
+                if cfg!(target_arch = "x86_64") {
+                    ", in power of 2 numbers of cores and threads"
+                } else {
+                    ""
+                }
+            ),
         }
     }
 }
@@ -43,6 +92,39 @@ pub struct VmConfig {
     pub ht_enabled: Option<bool>,
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// CPUID bits forced to given values after applying `cpu_template` (x86_64 Linux only), to
+    /// hide host-specific features the templates leave alone, or as a custom template of its own
+    /// without `cpu_template`.
+    pub cpuid_overrides: Option<Vec<CpuidOverride>>,
+    /// How the guest memory is initialized before boot.
+    pub mem_init: Option<MemoryInit>,
+    /// Exposes VMX/SVM to the guest so it can run its own hypervisor (x86_64 Linux only). The
+    /// host must have nesting enabled in kvm_intel/kvm_amd.
+    ///
+    /// Nested guests are noticeably slower than regular ones: every exit of the L2 guest is
+    /// first handled by the host and then reflected to the L1 hypervisor, two-dimensional
+    /// paging (EPT/NPT) has to be emulated through shadow tables, and timer and interrupt
+    /// delivery to L2 incur extra exits.
+    pub nested: Option<bool>,
+    /// The TSC frequency in kHz (x86_64 Linux only), advertised to the guest along with a stable
+    /// APIC timer rate so it can skip calibrating them. Only Intel CPUs have CPUID leaves for
+    /// these rates, on AMD hosts the guest only learns the TSC is invariant and reads the
+    /// frequency from kvmclock. `None` keeps the host's frequency. Pinning it makes runs
+    /// reproducible across hosts, or matches the host a guest is migrated from; frequencies other
+    /// than the host's need TSC scaling support from the CPU.
+    pub tsc_khz: Option<u32>,
+    /// How the vCPUs are grouped into sockets, cores and threads, overriding `ht_enabled`. `None`
+    /// puts them all in one socket.
+    pub cpu_topology: Option<CpuTopology>,
+    /// Exposes a PMUv3 to the guest (aarch64 Linux only), so `perf` can profile it with the
+    /// hardware counters. Building the microVM fails if the host doesn't provide one.
+    pub pmu: Option<bool>,
+    /// Exposes SVE to the guest (aarch64 Linux only), with the vector lengths KVM enables by
+    /// default. KVM only gives SVE to the vCPUs that ask for it, so this is an opt-in rather than
+    /// a way to disable it: leaving it off, the default, is what hides SVE from the guest, e.g.
+    /// to match a target CPU without it. Building the microVM fails if it's enabled but the host
+    /// doesn't provide it.
+    pub sve: Option<bool>,
 }
 
 impl Default for VmConfig {
@@ -52,6 +134,13 @@ impl Default for VmConfig {
             mem_size_mib: Some(128),
             ht_enabled: Some(false),
             cpu_template: None,
+            cpuid_overrides: None,
+            mem_init: None,
+            nested: Some(false),
+            tsc_khz: None,
+            cpu_topology: None,
+            pmu: Some(false),
+            sve: Some(false),
         }
     }
 }
@@ -64,8 +153,23 @@ impl fmt::Display for VmConfig {
         let cpu_template = self
             .cpu_template
             .map_or("Uninitialized".to_string(), |c| c.to_string());
+        let cpuid_overrides = self.cpuid_overrides.as_ref().map_or(0, Vec::len);
+        let mem_init = self.mem_init.unwrap_or_default().to_string();
+        let nested = self.nested.unwrap_or(false);
+        let pmu = self.pmu.unwrap_or(false);
+        let sve = self.sve.unwrap_or(false);
+        let tsc_khz = self
+            .tsc_khz
+            .map_or("Host".to_string(), |khz| khz.to_string());
+        // Sockets x cores per socket x threads per core.
+        let cpu_topology = self.cpu_topology.map_or("Single socket".to_string(), |t| {
+            format!(
+                "{}x{}x{}",
+                t.sockets, t.cores_per_socket, t.threads_per_core
+            )
+        });
 
-        write!(f, "{{ \"vcpu_count\": {vcpu_count:?}, \"mem_size_mib\": {mem_size:?},  \"ht_enabled\": {ht_enabled:?},  \"cpu_template\": {cpu_template:?} }}")
+        write!(f, "{{ \"vcpu_count\": {vcpu_count:?}, \"mem_size_mib\": {mem_size:?},  \"ht_enabled\": {ht_enabled:?},  \"cpu_template\": {cpu_template:?},  \"cpuid_overrides\": {cpuid_overrides:?},  \"mem_init\": {mem_init:?},  \"nested\": {nested:?},  \"tsc_khz\": {tsc_khz:?},  \"cpu_topology\": {cpu_topology:?},  \"pmu\": {pmu:?},  \"sve\": {sve:?} }}")
     }
 }
 
@@ -88,6 +192,191 @@ impl fmt::Display for CpuFeaturesTemplate {
     }
 }
 
+/// Register of a CPUID leaf.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CpuidRegister {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// Forces bits of a CPUID leaf seen by the guest, e.g. to clear a feature bit the host sets.
+/// Leaves and subleaves KVM doesn't report for the host are left out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CpuidOverride {
+    /// Leaf, i.e. the value of eax the guest executes CPUID with.
+    pub leaf: u32,
+    /// Subleaf, i.e. the value of ecx, or `None` for all of them.
+    pub index: Option<u32>,
+    /// Register the bits are in.
+    pub register: CpuidRegister,
+    /// Bits to force.
+    pub mask: u32,
+    /// Values of the bits set in `mask`, the other bits are ignored.
+    pub value: u32,
+}
+
+impl CpuidOverride {
+    /// Override clearing the bit with the given index of `register` in `leaf`.
+    pub fn clear_bit(leaf: u32, register: CpuidRegister, bit: u32) -> Self {
+        CpuidOverride {
+            leaf,
+            index: None,
+            register,
+            mask: 1 << bit,
+            value: 0,
+        }
+    }
+
+    /// Returns `register` with the bits of the override applied to it.
+    pub fn apply(&self, register: u32) -> u32 {
+        (register & !self.mask) | (self.value & self.mask)
+    }
+}
+
+/// How the guest memory is initialized before the guest boots.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MemoryInit {
+    /// Pages are faulted in when the guest first touches them.
+    #[default]
+    Lazy,
+    /// All pages are faulted in up front, trading startup time for predictable latency.
+    Prefault,
+    /// All pages are explicitly cleared, e.g. before restoring a snapshot into them.
+    Zeroed,
+}
+
+impl fmt::Display for MemoryInit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryInit::Lazy => write!(f, "Lazy"),
+            MemoryInit::Prefault => write!(f, "Prefault"),
+            MemoryInit::Zeroed => write!(f, "Zeroed"),
+        }
+    }
+}
+
+/// Where the guest memory is allocated from.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum MemoryBackend {
+    /// Anonymous memory, gone once the microVM exits.
+    #[default]
+    Anonymous,
+    /// The file at `path`, the guest memory regions being laid out back to back from `offset`
+    /// (a multiple of the page size) in guest address order.
+    ///
+    /// If `shared`, the file is mapped with `MAP_SHARED`: the guest writes go to the page cache of
+    /// the file, visible to any other guest or process mapping it, and persist once the microVM
+    /// exits, even abnormally. They reach the disk on the host kernel's writeback schedule, so
+    /// surviving a host crash takes syncing the file once the microVM is gone. A missing file is
+    /// created, and a file shorter than the guest memory is extended.
+    ///
+    /// Otherwise the file is mapped with `MAP_PRIVATE` and only read: guests share its pages until
+    /// they write them, which leaves them with a private copy that is discarded on exit. The file
+    /// must be large enough to back the whole guest memory.
+    ///
+    /// The file's pages are faulted in as the guest touches them, so `MemoryInit::Lazy` is the only
+    /// initialization allowed. Truncating the file while the microVM runs leaves the pages past
+    /// its new end unbacked: a vCPU touching one gets `EFAULT` from `KVM_RUN` and exits with
+    /// `FC_EXIT_CODE_GENERIC_ERROR`, and a device touching one raises `SIGBUS` in the VMM, which
+    /// then exits with `FC_EXIT_CODE_SIGBUS`.
+    FileBacked {
+        path: PathBuf,
+        offset: u64,
+        shared: bool,
+    },
+    /// Anonymous memory allocated from the host's pool of huge pages of this size, cutting down
+    /// on TLB misses.
+    ///
+    /// The pages are reserved when the microVM is built, which fails if the host doesn't have
+    /// enough of them free, see `/sys/kernel/mm/hugepages`. The head and tail of memory regions
+    /// whose guest addresses aren't aligned to the huge page size are backed by normal pages.
+    #[cfg(target_os = "linux")]
+    HugePages(HugePageSize),
+    /// A memfd, the guest memory regions being laid out back to back in guest address order,
+    /// each one starting on a page boundary.
+    ///
+    /// The memory is mapped with `MAP_SHARED`, so other processes can map the memfd returned by
+    /// `Vmm::guest_memory_fd` from the file offsets of the guest memory regions to access the
+    /// guest memory.
+    #[cfg(target_os = "linux")]
+    Memfd,
+}
+
+/// Size of the huge pages backing `MemoryBackend::HugePages`.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HugePageSize {
+    Size2M,
+    Size1G,
+}
+
+#[cfg(target_os = "linux")]
+impl HugePageSize {
+    /// Returns the page size in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+
+    /// Returns the `mmap` flags allocating pages of this size.
+    pub fn mmap_flags(&self) -> libc::c_int {
+        libc::MAP_HUGETLB
+            | match self {
+                HugePageSize::Size2M => libc::MAP_HUGE_2MB,
+                HugePageSize::Size1G => libc::MAP_HUGE_1GB,
+            }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl fmt::Display for HugePageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HugePageSize::Size2M => write!(f, "2 MiB"),
+            HugePageSize::Size1G => write!(f, "1 GiB"),
+        }
+    }
+}
+
+/// `madvise` hints that can be applied to the guest memory regions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MemoryAdvice {
+    /// Allow KSM to merge identical pages (`MADV_MERGEABLE`).
+    Mergeable,
+    /// Back the guest memory with transparent huge pages (`MADV_HUGEPAGE`).
+    HugePage,
+    /// Never back the guest memory with transparent huge pages (`MADV_NOHUGEPAGE`).
+    NoHugePage,
+}
+
+impl MemoryAdvice {
+    /// Returns the `madvise` advice value for this hint, or `None` if the host doesn't support it.
+    pub fn as_madvise(&self) -> Option<libc::c_int> {
+        #[cfg(target_os = "linux")]
+        match self {
+            MemoryAdvice::Mergeable => Some(libc::MADV_MERGEABLE),
+            MemoryAdvice::HugePage => Some(libc::MADV_HUGEPAGE),
+            MemoryAdvice::NoHugePage => Some(libc::MADV_NOHUGEPAGE),
+        }
+        #[cfg(not(target_os = "linux"))]
+        None
+    }
+}
+
+impl fmt::Display for MemoryAdvice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryAdvice::Mergeable => write!(f, "MADV_MERGEABLE"),
+            MemoryAdvice::HugePage => write!(f, "MADV_HUGEPAGE"),
+            MemoryAdvice::NoHugePage => write!(f, "MADV_NOHUGEPAGE"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,6 +387,36 @@ mod tests {
         assert_eq!(CpuFeaturesTemplate::T2.to_string(), "T2".to_string());
     }
 
+    #[test]
+    fn test_cpuid_override() {
+        let clear_avx = CpuidOverride::clear_bit(1, CpuidRegister::Ecx, 28);
+        assert_eq!(clear_avx.apply(0xffff_ffff), 0xefff_ffff);
+        assert_eq!(clear_avx.apply(0), 0);
+
+        let set_family = CpuidOverride {
+            leaf: 1,
+            index: None,
+            register: CpuidRegister::Eax,
+            mask: 0xf00,
+            value: 0xffff_f6ff,
+        };
+        assert_eq!(set_family.apply(0x806c1), 0x806c1 & !0xf00 | 0x600);
+    }
+
+    #[test]
+    fn test_display_memory_init() {
+        assert_eq!(MemoryInit::Lazy.to_string(), "Lazy");
+        assert_eq!(MemoryInit::Prefault.to_string(), "Prefault");
+        assert_eq!(MemoryInit::Zeroed.to_string(), "Zeroed");
+    }
+
+    #[test]
+    fn test_display_memory_advice() {
+        assert_eq!(MemoryAdvice::Mergeable.to_string(), "MADV_MERGEABLE");
+        assert_eq!(MemoryAdvice::HugePage.to_string(), "MADV_HUGEPAGE");
+        assert_eq!(MemoryAdvice::NoHugePage.to_string(), "MADV_NOHUGEPAGE");
+    }
+
     #[test]
     fn test_display_vm_config_error() {
         let expected_str = "The vCPU number is invalid! The vCPU number can only \
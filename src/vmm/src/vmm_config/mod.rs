@@ -5,6 +5,10 @@
 #[cfg(feature = "blk")]
 pub mod block;
 
+/// Wrapper for configuring the balloon device attached to the microVM.
+#[cfg(not(feature = "tee"))]
+pub mod balloon;
+
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
 
@@ -21,6 +25,17 @@ pub mod kernel_bundle;
 /// Wrapper for configuring the memory and CPU of the microVM.
 pub mod machine_config;
 
+/// Wrapper for configuring the read-only guest memory regions.
+#[cfg(target_os = "linux")]
+pub mod readonly_memory;
+
+/// Wrapper for configuring the virtio-rng device attached to the microVM.
+#[cfg(not(feature = "tee"))]
+pub mod rng;
+
+/// Wrapper for configuring the guest RTC.
+pub mod rtc;
+
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
 
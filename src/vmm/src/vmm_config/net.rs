@@ -6,6 +6,7 @@ use std::result;
 use std::sync::{Arc, Mutex};
 
 use devices::virtio::net::device::VirtioNetBackend;
+use devices::virtio::net::RateLimit;
 use devices::virtio::Net;
 
 pub struct NetworkInterfaceConfig {
@@ -15,6 +16,27 @@ pub struct NetworkInterfaceConfig {
     pub backend: VirtioNetBackend,
     /// MAC address.
     pub mac: [u8; 6],
+    /// Caps on the traffic the guest receives, to share the host's bandwidth between guests.
+    pub rx_rate_limit: RateLimit,
+    /// Caps on the traffic the guest sends.
+    pub tx_rate_limit: RateLimit,
+}
+
+/// Returns a MAC address for `iface_id` which only depends on `seed`, so it stays the same across
+/// boots. It's a locally administered unicast address, which won't clash with a vendor's.
+pub fn generate_mac(seed: u64, iface_id: &str) -> [u8; 6] {
+    // FNV-1a of the id, then the splitmix64 finalizer to spread the seed over all the bytes.
+    let mut h = iface_id.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    }) ^ seed;
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^= h >> 31;
+
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&h.to_be_bytes()[..6]);
+    mac[0] = (mac[0] & !0x01) | 0x02;
+    mac
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -85,7 +107,24 @@ impl NetBuilder {
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
         // Create and return the Net device
-        Net::new(cfg.iface_id, cfg.backend, cfg.mac)
-            .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let mut net = Net::new(cfg.iface_id, cfg.backend, cfg.mac)
+            .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        net.set_rate_limits(cfg.rx_rate_limit, cfg.tx_rate_limit);
+        Ok(net)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_mac() {
+        let mac = generate_mac(42, "eth0");
+        assert_eq!(mac, generate_mac(42, "eth0"));
+        assert_ne!(mac, generate_mac(43, "eth0"));
+        assert_ne!(mac, generate_mac(42, "eth1"));
+        // Unicast, locally administered.
+        assert_eq!(mac[0] & 0x03, 0x02);
     }
 }
@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Host side of the `ReadOnlyWritePolicy::Observe` policy.
+pub trait ReadOnlyWriteObserver: Send + Sync {
+    /// Called when vCPU `vcpuid` writes `data` at `addr`, the absolute guest physical address of
+    /// the write. The region is left untouched.
+    fn on_write(&self, vcpuid: u64, addr: u64, data: &[u8]);
+}
+
+/// What happens when the guest writes to a read-only region. Reads are served from the region
+/// without exiting to the VMM whatever the policy.
+#[derive(Clone)]
+pub enum ReadOnlyWritePolicy {
+    /// Drop the write, logging it.
+    IgnoreWrites,
+    /// Make the write fault in the guest, which gets an external data abort. Only supported on
+    /// aarch64, as KVM doesn't report the virtual address a page fault on x86_64 needs.
+    FaultToGuest,
+    /// Drop the write and hand it to the observer, e.g. to emulate a device window whose
+    /// registers are read from memory.
+    Observe(Arc<dyn ReadOnlyWriteObserver>),
+}
+
+impl fmt::Debug for ReadOnlyWritePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadOnlyWritePolicy::IgnoreWrites => write!(f, "IgnoreWrites"),
+            ReadOnlyWritePolicy::FaultToGuest => write!(f, "FaultToGuest"),
+            ReadOnlyWritePolicy::Observe(_) => write!(f, "Observe"),
+        }
+    }
+}
+
+/// Guest memory region the guest can read but not write.
+#[derive(Clone, Debug)]
+pub struct ReadOnlyRegionConfig {
+    /// Guest physical address the region starts at, page aligned.
+    pub guest_addr: u64,
+    /// What the guest reads from the region, padded with zeroes up to a page boundary.
+    pub contents: Vec<u8>,
+    /// How guest writes to the region are handled.
+    pub policy: ReadOnlyWritePolicy,
+}
+
+impl ReadOnlyRegionConfig {
+    /// Size of the region in the guest.
+    pub fn size(&self) -> u64 {
+        (self.contents.len() as u64).next_multiple_of(arch::PAGE_SIZE as u64)
+    }
+}
+
+/// Errors associated with configuring read-only regions.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ReadOnlyRegionError {
+    /// The region is empty, isn't page aligned, or overlaps another read-only region.
+    InvalidRegion(u64),
+    /// The write policy isn't supported on this architecture.
+    UnsupportedPolicy(u64),
+}
+
+impl fmt::Display for ReadOnlyRegionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ReadOnlyRegionError::*;
+        match self {
+            InvalidRegion(addr) => write!(f, "Invalid read-only region at {addr:#x}"),
+            UnsupportedPolicy(addr) => write!(
+                f,
+                "The write policy of the read-only region at {addr:#x} is not supported"
+            ),
+        }
+    }
+}
+
+/// Applies the `IgnoreWrites` and `Observe` policies to the writes KVM reports as MMIO exits.
+pub(crate) struct ReadOnlyWriteHandler {
+    policy: ReadOnlyWritePolicy,
+}
+
+impl ReadOnlyWriteHandler {
+    pub(crate) fn new(policy: ReadOnlyWritePolicy) -> Self {
+        ReadOnlyWriteHandler { policy }
+    }
+}
+
+impl devices::BusAccessHandler for ReadOnlyWriteHandler {
+    // Reads never exit to the VMM.
+    fn read(&self, _vcpuid: u64, _addr: u64, _data: &mut [u8]) {}
+
+    fn write(&self, vcpuid: u64, addr: u64, data: &[u8]) {
+        match &self.policy {
+            ReadOnlyWritePolicy::Observe(observer) => observer.on_write(vcpuid, addr, data),
+            _ => debug!(
+                "Dropped a {}-byte write at {addr:#x} to a read-only region",
+                data.len()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devices::BusAccessHandler;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(u64, Vec<u8>)>>);
+
+    impl ReadOnlyWriteObserver for Recorder {
+        fn on_write(&self, _vcpuid: u64, addr: u64, data: &[u8]) {
+            self.0.lock().unwrap().push((addr, data.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_write_handler() {
+        let recorder = Arc::new(Recorder::default());
+        let handler = ReadOnlyWriteHandler::new(ReadOnlyWritePolicy::Observe(recorder.clone()));
+        handler.write(0, 0x1000_0004, &[1, 2]);
+        assert_eq!(*recorder.0.lock().unwrap(), [(0x1000_0004, vec![1, 2])]);
+
+        // Dropped writes don't reach anyone.
+        ReadOnlyWriteHandler::new(ReadOnlyWritePolicy::IgnoreWrites).write(0, 0x1000_0000, &[3]);
+        assert_eq!(recorder.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_size() {
+        let region = ReadOnlyRegionConfig {
+            guest_addr: 0,
+            contents: vec![0; arch::PAGE_SIZE + 1],
+            policy: ReadOnlyWritePolicy::IgnoreWrites,
+        };
+        assert_eq!(region.size(), 2 * arch::PAGE_SIZE as u64);
+    }
+}
@@ -0,0 +1,11 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::num::NonZeroU64;
+
+/// Configuration for the virtio-rng device.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RngConfig {
+    /// Cap on the entropy handed to the guest, in bytes per second, to keep a guest from
+    /// draining a host entropy source shared with other VMs. `None` leaves it uncapped.
+    pub rate_limit: Option<NonZeroU64>,
+}
@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{mem, ptr};
+
+use utils::time::{get_time, ClockType, NANOS_PER_SECOND};
+
+/// The clock the guest RTC is seeded from when the microVM starts.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RtcBase {
+    /// Host wall clock, in UTC.
+    #[default]
+    HostUtc,
+    /// Host wall clock, adjusted to the host's local timezone.
+    HostLocal,
+    /// A fixed point in time, expressed in seconds since the Unix epoch.
+    Fixed(i64),
+}
+
+/// Configuration for the guest RTC.
+///
+/// Seeds the PL031 RTC on aarch64 and the CMOS RTC on x86_64, where the guest
+/// still reads the wallclock from kvmclock if it has it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RtcConfig {
+    /// Clock the RTC starts counting from.
+    pub base: RtcBase,
+    /// Offset in seconds applied on top of `base`. May be negative.
+    pub offset: i64,
+}
+
+impl RtcConfig {
+    /// Returns the time, in nanoseconds since the Unix epoch, the guest RTC
+    /// should report when it is created.
+    pub fn initial_time_ns(&self) -> i64 {
+        let nanos_per_second = NANOS_PER_SECOND as i64;
+        let base = match self.base {
+            RtcBase::HostUtc => get_time(ClockType::Real) as i64,
+            RtcBase::HostLocal => (get_time(ClockType::Real) as i64)
+                .saturating_add(host_utc_offset().saturating_mul(nanos_per_second)),
+            RtcBase::Fixed(ts) => ts.saturating_mul(nanos_per_second),
+        };
+        base.saturating_add(self.offset.saturating_mul(nanos_per_second))
+    }
+}
+
+/// Returns the host's current offset from UTC in seconds, or 0 if it can't be determined.
+fn host_utc_offset() -> i64 {
+    // SAFETY: `time` accepts a null pointer, and `localtime_r` only writes to the
+    // `tm` we own. Both are thread-safe.
+    unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm: libc::tm = mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtc_initial_time() {
+        let cfg = RtcConfig {
+            base: RtcBase::Fixed(1_000_000),
+            offset: -60,
+        };
+        assert_eq!(
+            cfg.initial_time_ns(),
+            (1_000_000 - 60) * NANOS_PER_SECOND as i64
+        );
+
+        let before = get_time(ClockType::Real) as i64;
+        let utc = RtcConfig::default().initial_time_ns();
+        assert!(utc >= before);
+        assert!(utc <= get_time(ClockType::Real) as i64);
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
@@ -10,11 +10,18 @@ use devices::virtio::{Vsock, VsockError};
 
 type MutexVsock = Arc<Mutex<Vsock>>;
 
+// Guest CIDs claimed by the vsock devices of this process.
+static GUEST_CIDS: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug)]
 pub enum VsockConfigError {
     /// Failed to create the vsock device.
     CreateVsockDevice(VsockError),
+    /// The CID is reserved for the hypervisor, the loopback device or the host.
+    InvalidCid(u32),
+    /// Another vsock device of this process already uses the CID.
+    CidInUse(u32),
 }
 
 impl fmt::Display for VsockConfigError {
@@ -22,12 +29,45 @@ impl fmt::Display for VsockConfigError {
         use self::VsockConfigError::*;
         match *self {
             CreateVsockDevice(ref e) => write!(f, "Cannot create vsock device: {e:?}"),
+            InvalidCid(cid) => write!(f, "Vsock guest CID {cid} is reserved"),
+            CidInUse(cid) => write!(
+                f,
+                "Vsock guest CID {cid} is already used by another microVM of this process"
+            ),
         }
     }
 }
 
 type Result<T> = std::result::Result<T, VsockConfigError>;
 
+/// Claim on a guest CID, which no other vsock device of the process can use until it's dropped.
+#[derive(Debug)]
+pub struct GuestCid(u32);
+
+impl GuestCid {
+    /// Claims `cid`, failing if it's reserved or already claimed.
+    pub fn register(cid: u32) -> Result<Self> {
+        // 0, 1 and 2 are the hypervisor, loopback and host CIDs, and -1 is VMADDR_CID_ANY.
+        if cid <= 2 || cid == u32::MAX {
+            return Err(VsockConfigError::InvalidCid(cid));
+        }
+        if !GUEST_CIDS.lock().unwrap().insert(cid) {
+            return Err(VsockConfigError::CidInUse(cid));
+        }
+        Ok(GuestCid(cid))
+    }
+
+    pub fn cid(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Drop for GuestCid {
+    fn drop(&mut self) {
+        GUEST_CIDS.lock().unwrap().remove(&self.0);
+    }
+}
+
 /// This struct represents the strongly typed equivalent of the json body
 /// from vsock related requests.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -38,12 +78,15 @@ pub struct VsockDeviceConfig {
     pub guest_cid: u32,
     /// An optional map of host to guest port mappings.
     pub host_port_map: Option<HashMap<u16, u16>>,
-    /// An optional map of guest port to host UNIX domain sockets for IPC.
+    /// An optional map of guest port to host UNIX domain sockets for IPC. A guest connection to
+    /// one of these ports is forwarded to a new connection to its socket, while connections to
+    /// the others are reset.
     pub unix_ipc_port_map: Option<HashMap<u32, PathBuf>>,
 }
 
 struct VsockWrapper {
     vsock: MutexVsock,
+    guest_cid: Arc<GuestCid>,
 }
 
 /// A builder of Vsock from 'VsockDeviceConfig'.
@@ -60,9 +103,16 @@ impl VsockBuilder {
 
     /// Inserts a Vsock in the store.
     /// If an entry already exists, it will overwrite it.
+    /// Fails if another Vsock of the process uses the same guest CID.
     pub fn insert(&mut self, cfg: VsockDeviceConfig) -> Result<()> {
+        // The entry being overwritten keeps its claim on the CID.
+        let guest_cid = match &self.inner {
+            Some(pair) if pair.guest_cid.cid() == cfg.guest_cid => pair.guest_cid.clone(),
+            _ => Arc::new(GuestCid::register(cfg.guest_cid)?),
+        };
         self.inner = Some(VsockWrapper {
             vsock: Arc::new(Mutex::new(Self::create_vsock(cfg)?)),
+            guest_cid,
         });
         Ok(())
     }
@@ -72,6 +122,11 @@ impl VsockBuilder {
         self.inner.as_ref().map(|pair| &pair.vsock)
     }
 
+    /// Provides the claim on the guest CID of the Vsock if present.
+    pub fn guest_cid(&self) -> Option<&Arc<GuestCid>> {
+        self.inner.as_ref().map(|pair| &pair.guest_cid)
+    }
+
     /// Creates a Vsock device from a VsockDeviceConfig.
     pub fn create_vsock(cfg: VsockDeviceConfig) -> Result<Vsock> {
         Vsock::new(
@@ -123,6 +178,8 @@ pub(crate) mod tests {
         let mut store = VsockBuilder::new();
         let tmp_sock_file = TempSockFile::new(TempFile::new().unwrap());
         let mut vsock_config = default_config(&tmp_sock_file);
+        // Tests run in the same process, don't use the CID of the others.
+        vsock_config.guest_cid = 100;
 
         store.insert(vsock_config.clone()).unwrap();
         let vsock = store.get().unwrap();
@@ -133,6 +190,39 @@ pub(crate) mod tests {
         store.insert(vsock_config).unwrap();
         let vsock = store.get().unwrap();
         assert_eq!(vsock.lock().unwrap().cid(), new_cid as u64);
+        // The first CID was released.
+        GuestCid::register(100).unwrap();
+    }
+
+    #[test]
+    fn test_guest_cid() {
+        assert!(matches!(
+            GuestCid::register(2),
+            Err(VsockConfigError::InvalidCid(2))
+        ));
+        assert!(matches!(
+            GuestCid::register(u32::MAX),
+            Err(VsockConfigError::InvalidCid(_))
+        ));
+
+        let tmp_sock_file = TempSockFile::new(TempFile::new().unwrap());
+        let mut vsock_config = default_config(&tmp_sock_file);
+        vsock_config.guest_cid = 200;
+        let mut store = VsockBuilder::new();
+        store.insert(vsock_config.clone()).unwrap();
+        // Overwriting the entry with the same CID is fine.
+        store.insert(vsock_config.clone()).unwrap();
+        assert_eq!(store.guest_cid().unwrap().cid(), 200);
+
+        let mut other = VsockBuilder::new();
+        assert!(matches!(
+            other.insert(vsock_config.clone()),
+            Err(VsockConfigError::CidInUse(200))
+        ));
+        assert!(other.get().is_none());
+
+        drop(store);
+        other.insert(vsock_config).unwrap();
     }
 
     #[test]
@@ -144,5 +234,9 @@ pub(crate) mod tests {
             io::Error::from_raw_os_error(0),
         ));
         let _ = format!("{}{:?}", err, err);
+        let err = InvalidCid(0);
+        let _ = format!("{}{:?}", err, err);
+        let err = CidInUse(3);
+        let _ = format!("{}{:?}", err, err);
     }
 }